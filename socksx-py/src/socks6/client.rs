@@ -38,6 +38,9 @@ impl Client {
                 .connect(destination, None, Some(options))
                 .await
                 .map_err(|e| PyOSError::new_err(e.to_string()))?;
+            let socket = socket
+                .into_tcp()
+                .map_err(|_| PyOSError::new_err("Only proxies reached over TCP are supported."))?;
 
             let gil = Python::acquire_gil();
             let py = gil.python();
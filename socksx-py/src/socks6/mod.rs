@@ -89,7 +89,7 @@ fn write_reply(
 
     pyo3_asyncio::tokio::into_coroutine(py, async move {
         let mut stream = stream.write().await;
-        socksx::socks6::write_reply(stream.deref_mut(), reply)
+        socksx::socks6::write_simple_reply(stream.deref_mut(), reply)
             .await
             .map_err(|_| PyOSError::new_err("TODO: custom errors"))?;
 
@@ -61,10 +61,10 @@ async fn connect_v5(
     let client = Socks5Client::new(proxy_addr, None).await?;
 
     // Connect to the destination.
-    let (mut outgoing, _) = client.connect(dest_addr).await?;
+    let (mut outgoing, _) = client.connect(dest_addr, None).await?;
 
     // Write a message to the destination.
-    outgoing.write(String::from("Hello, world!\n").as_bytes()).await?;
+    outgoing.write_all(String::from("Hello, world!\n").as_bytes()).await?;
 
     Ok(())
 }
@@ -81,7 +81,7 @@ async fn connect_v6(
     let (mut outgoing, _) = client.connect(dest_addr, None, None).await?;
 
     // Write a message to the destination.
-    outgoing.write(String::from("Hello, world!\n").as_bytes()).await?;
+    outgoing.write_all(String::from("Hello, world!\n").as_bytes()).await?;
 
     Ok(())
 }
@@ -26,6 +26,7 @@ struct Arguments {
 
 /***** ENTRYPOINT *****/
 // iptables -t nat -A OUTPUT ! -d $PROXY_HOST/32 -o eth0 -p tcp -m tcp -j REDIRECT --to-ports 42000
+// Or, with the `transparent` feature, let `socksx::transparent::RedirectRules` install (and remove) the rules.
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Arguments::parse();
@@ -64,7 +65,8 @@ async fn redirect_v5(
     let mut incoming = incoming;
 
     let dst_addr = socksx::get_original_dst(&incoming)?.to_string();
-    let (mut outgoing, _) = client.connect(dst_addr).await?;
+    let initial_data = socksx::try_read_initial_data(&mut incoming).await?;
+    let (mut outgoing, _) = client.connect(dst_addr, initial_data).await?;
 
     socksx::copy_bidirectional(&mut incoming, &mut outgoing).await?;
 
@@ -0,0 +1,9 @@
+#![no_main]
+// Parses arbitrary bytes as SOCKS6 requests, and checks that the requests that parse survive a roundtrip.
+use libfuzzer_sys::fuzz_target;
+use socksx::wire::fuzz;
+
+fuzz_target!(|bytes: &[u8]| {
+    fuzz::fuzz_parse_request(bytes);
+    assert!(fuzz::fuzz_roundtrip_request(bytes));
+});
@@ -0,0 +1,8 @@
+#![no_main]
+// Checks that options, parsed from arbitrary bytes or described by them, survive a roundtrip.
+use libfuzzer_sys::fuzz_target;
+use socksx::wire::fuzz;
+
+fuzz_target!(|bytes: &[u8]| {
+    assert!(fuzz::fuzz_roundtrip_option(bytes));
+});
@@ -1,11 +1,17 @@
+use std::borrow::Cow;
 use std::convert::{TryFrom, TryInto};
-use std::net::{IpAddr, SocketAddr};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr, SocketAddrV6};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-use anyhow::Result;
-use tokio::io::{AsyncRead, AsyncReadExt};
-use url::Url;
+use anyhow::{Context, Result};
+use bytes::BytesMut;
+use tokio::io::AsyncRead;
+use url::{Host, Url};
 
-use crate::{constants::*, Credentials};
+use crate::{constants::*, wire, Credentials, SocksError};
 
 /// Represents a SOCKS proxy address.
 #[derive(Clone, Debug, PartialEq)]
@@ -41,13 +47,62 @@ impl ProxyAddress {
     pub fn root() -> Self {
         ProxyAddress::new(6, String::from("root"), 1080, None)
     }
+
+    /// Returns the host and port of the proxy as `host:port`.
+    pub fn host_port(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    /// Parses a list of proxy addresses, e.g., `socks6://a:1080,socks5://b:1080`.
+    ///
+    /// Whitespace around each entry is ignored, but empty entries are rejected.
+    ///
+    /// # Parameters
+    /// - `list`: The proxy addresses, separated by the delimiter.
+    /// - `delimiter`: The character that separates the entries.
+    ///
+    /// # Returns
+    /// The proxy addresses in order, or an error that names the position of the first invalid entry.
+    pub fn parse_list(
+        list: &str,
+        delimiter: char,
+    ) -> Result<Vec<ProxyAddress>> {
+        list.split(delimiter)
+            .map(str::trim)
+            .enumerate()
+            .map(|(i, entry)| {
+                ensure!(!entry.is_empty(), "Empty proxy address at position {} of the list", i + 1);
+
+                ProxyAddress::try_from(entry.to_string())
+                    .with_context(|| format!("Invalid proxy address at position {} of the list: {}", i + 1, entry))
+            })
+            .collect()
+    }
+
+    /// Checks that the proxy speaks the given SOCKS version.
+    pub(crate) fn ensure_version(
+        &self,
+        socks_version: u8,
+    ) -> Result<()> {
+        ensure!(
+            self.socks_version == socks_version,
+            "Expected a SOCKS{} proxy, but got: {}",
+            socks_version,
+            self
+        );
+
+        Ok(())
+    }
 }
 
 
-impl ToString for ProxyAddress {
-    // Converts the `ProxyAddress` to a string representation.
-    fn to_string(&self) -> String {
-        format!("socks{}://{}:{}", self.socks_version, self.host, self.port)
+impl fmt::Display for ProxyAddress {
+    // Formats the `ProxyAddress` as a string representation.
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        write!(f, "socks{}://{}:{}", self.socks_version, self.host, self.port)
     }
 }
 
@@ -87,71 +142,200 @@ impl TryFrom<String> for ProxyAddress {
     }
 }
 
+/// A chain of proxy addresses, parsed from a comma-separated list.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChainSpec(pub Vec<ProxyAddress>);
+
+impl FromStr for ChainSpec {
+    type Err = anyhow::Error;
+
+    // Parses a comma-separated list of proxy addresses.
+    fn from_str(list: &str) -> Result<Self> {
+        ProxyAddress::parse_list(list, ',').map(ChainSpec)
+    }
+}
+
+impl Deref for ChainSpec {
+    type Target = [ProxyAddress];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl From<ChainSpec> for Vec<ProxyAddress> {
+    fn from(chain: ChainSpec) -> Self {
+        chain.0
+    }
+}
+
 /// Represents a network address, which could be either a domain name or an IP address.
 #[derive(Clone, Debug, PartialEq)]
 pub enum Address {
     /// An address represented by a domain name.
     Domainname { host: String, port: u16 },
-    /// An address represented by an IP address.
+    /// An address represented by an IP address. An IPv6 address may carry the scope (zone) ID of a link-local
+    /// address, which only means something on the host that it was given on, see `ScopePolicy`.
     Ip(SocketAddr),
+    /// The path of a Unix socket on the host of the proxy.
+    ///
+    /// This is a socksx extension, encoded with `SOCKS_ATYP_UNIX`, which other proxies don't understand.
+    Unix(PathBuf),
 }
 
 
 impl Address {
     /// Creates a new `Address` instance.
+    ///
+    /// Domain names are normalized like `try_new` does, and kept as given, with a warning, if they don't follow the
+    /// IDNA rules. So are IPv6 addresses with a zone ID that doesn't name an interface. Use `try_new` to refuse
+    /// them instead.
     pub fn new<S: Into<String>>(
         host: S,
         port: u16,
     ) -> Self {
         let host = host.into();
 
-        if let Ok(host) = host.parse::<IpAddr>() {
-            Address::Ip(SocketAddr::new(host, port))
+        if let Some(Ok(addr)) = parse_ip(&host, port) {
+            Address::Ip(addr)
         } else {
+            let host = normalize_domain(&host).unwrap_or_else(|e| {
+                warn!("Keeping domain name {} as given: {}", host, e);
+                host
+            });
             Address::Domainname { host, port }
         }
     }
 
+    /// Creates a new `Address` instance, encoding a domain name with non-ASCII characters as punycode
+    /// (see `normalize_domain`).
+    ///
+    /// An IPv6 address may have a zone ID, either the index or the name of an interface, e.g., `fe80::1%2` or
+    /// `fe80::1%eth0`.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the address, or `SocksError::InvalidDomain` if the domain name doesn't follow the
+    /// IDNA rules, or an error if the zone ID doesn't name an interface.
+    pub fn try_new<S: Into<String>>(
+        host: S,
+        port: u16,
+    ) -> Result<Self> {
+        let host = host.into();
+
+        if let Some(addr) = parse_ip(&host, port) {
+            Ok(Address::Ip(addr?))
+        } else {
+            let host = normalize_domain(&host)?;
+            Ok(Address::Domainname { host, port })
+        }
+    }
+
+    /// Creates an `Address` for the Unix socket at the given path.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the address, or an error if the path is empty or longer than 255 bytes.
+    pub fn unix<P: Into<PathBuf>>(path: P) -> Result<Self> {
+        let path = path.into();
+        let length = unix_path_bytes(&path).len();
+        ensure!(length > 0 && length <= u8::MAX as usize, "Invalid length for a Unix socket path: {}", length);
+
+        Ok(Address::Unix(path))
+    }
+
+    /// Returns the scope (zone) ID of a link-local IPv6 address, if it has one.
+    pub fn scope_id(&self) -> Option<u32> {
+        match self {
+            Address::Ip(SocketAddr::V6(addr)) if addr.scope_id() != 0 => Some(addr.scope_id()),
+            _ => None,
+        }
+    }
+
+    /// Returns the address without its scope ID, if it has one.
+    pub fn without_scope(&self) -> Address {
+        match self {
+            Address::Ip(SocketAddr::V6(addr)) => {
+                Address::Ip(SocketAddrV6::new(*addr.ip(), addr.port(), addr.flowinfo(), 0).into())
+            }
+            address => address.clone(),
+        }
+    }
+
     /// Converts the `Address` into a byte sequence compatible with the SOCKS protocol.
+    ///
+    /// The scope ID of an IPv6 address is left out, as the protocol has no room for it.
     pub fn as_socks_bytes(&self) -> Vec<u8> {
-        let mut bytes = vec![];
+        let mut bytes = Vec::with_capacity(self.encoded_len());
+        self.write_socks_bytes(&mut bytes);
+
+        bytes
+    }
+
+    /// Returns the number of bytes `write_socks_bytes` appends for this address.
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            Address::Ip(SocketAddr::V4(_)) => 1 + 4 + 2,
+            Address::Ip(SocketAddr::V6(_)) => 1 + 16 + 2,
+            Address::Domainname { host, .. } => 1 + 1 + host.len() + 2,
+            Address::Unix(path) => 1 + 1 + unix_path_bytes(path).len(),
+        }
+    }
 
+    /// Appends the SOCKS representation of the `Address` to the buffer.
+    pub fn write_socks_bytes(
+        &self,
+        bytes: &mut Vec<u8>,
+    ) {
         match self {
             Address::Ip(dst_addr) => {
                 match dst_addr.ip() {
                     IpAddr::V4(host) => {
                         bytes.push(SOCKS_ATYP_IPV4);
-                        bytes.extend(host.octets().iter());
+                        bytes.extend_from_slice(&host.octets());
                     }
                     IpAddr::V6(host) => {
                         bytes.push(SOCKS_ATYP_IPV6);
-                        bytes.extend(host.octets().iter());
+                        bytes.extend_from_slice(&host.octets());
                     }
                 }
 
-                bytes.extend(dst_addr.port().to_be_bytes().iter())
+                bytes.extend_from_slice(&dst_addr.port().to_be_bytes())
             }
             Address::Domainname { host, port } => {
                 bytes.push(SOCKS_ATYP_DOMAINNAME);
 
                 let host = host.as_bytes();
                 bytes.push(host.len() as u8);
-                bytes.extend(host);
+                bytes.extend_from_slice(host);
 
-                bytes.extend(port.to_be_bytes().iter());
+                bytes.extend_from_slice(&port.to_be_bytes());
             }
-        }
+            Address::Unix(path) => {
+                bytes.push(SOCKS_ATYP_UNIX);
 
-        bytes
+                let path = unix_path_bytes(path);
+                bytes.push(path.len() as u8);
+                bytes.extend_from_slice(&path);
+            }
+        }
     }
 }
 
-impl ToString for Address {
-    // Converts the `Address` to a string representation.
-    fn to_string(&self) -> String {
+impl fmt::Display for Address {
+    // Formats the `Address` as a string representation, with the zone ID of a scoped IPv6 address as the name of its
+    // interface, if it has one.
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
         match self {
-            Address::Domainname { host, port } => format!("{}:{}", host, port),
-            Address::Ip(socket_addr) => socket_addr.to_string(),
+            Address::Domainname { host, port } => write!(f, "{}:{}", display_domain(host), port),
+            Address::Ip(SocketAddr::V6(addr)) if addr.scope_id() != 0 => {
+                write!(f, "[{}%{}]:{}", addr.ip(), zone_name(addr.scope_id()), addr.port())
+            }
+            Address::Ip(socket_addr) => write!(f, "{}", socket_addr),
+            Address::Unix(path) => write!(f, "unix:{}", path.display()),
         }
     }
 }
@@ -161,7 +345,7 @@ impl TryFrom<SocketAddr> for Address {
     type Error = anyhow::Error;
 
     fn try_from(addr: SocketAddr) -> Result<Self, Self::Error> {
-        addr.to_string().try_into()
+        Ok(Address::Ip(addr))
     }
 }
 
@@ -170,11 +354,71 @@ impl TryFrom<String> for Address {
     type Error = anyhow::Error;
 
     fn try_from(addr: String) -> Result<Self> {
-        if let Some((host, port)) = addr.split_once(':') {
-            Ok(Address::new(host, port.parse()?))
-        } else {
-            bail!("Address doesn't seperate host and port by ':'.")
+        addr.as_str().try_into()
+    }
+}
+
+/// Tries to convert a `&str` into an `Address`, either as "host:port" or as a full URL. IPv6 addresses are put
+/// in brackets, with an optional zone ID, e.g., "[fe80::1%eth0]:80".
+impl TryFrom<&str> for Address {
+    type Error = anyhow::Error;
+
+    fn try_from(addr: &str) -> Result<Self> {
+        if addr.contains("://") {
+            return Url::parse(addr)?.try_into();
         }
+
+        let (host, port) = match addr.rsplit_once(':') {
+            Some(host_port) => host_port,
+            None => bail!("Address doesn't seperate host and port by ':'."),
+        };
+        let host = match host.strip_prefix('[').and_then(|host| host.strip_suffix(']')) {
+            Some(host) => host,
+            None if host.contains(':') => bail!("IPv6 address isn't put in brackets: {}", addr),
+            None => host,
+        };
+
+        Address::try_new(host, port.parse()?)
+    }
+}
+
+/// Parses an `Address` from "host:port", like `TryFrom<&str>`.
+impl FromStr for Address {
+    type Err = anyhow::Error;
+
+    fn from_str(addr: &str) -> Result<Self> {
+        Address::try_from(addr)
+    }
+}
+
+/// Tries to convert a `Url` into an `Address`, using the default port of the scheme if none is given.
+impl TryFrom<&Url> for Address {
+    type Error = anyhow::Error;
+
+    fn try_from(url: &Url) -> Result<Self> {
+        let default_port = match url.scheme() {
+            "http" | "ws" => 80,
+            "https" | "wss" => 443,
+            scheme => bail!("Unsupported URL scheme for a destination: {}", scheme),
+        };
+
+        let host = match url.host() {
+            Some(Host::Domain(domain)) => domain.to_string(),
+            Some(Host::Ipv4(ip)) => ip.to_string(),
+            Some(Host::Ipv6(ip)) => ip.to_string(),
+            None => bail!("Missing host in destination URL: {}", url),
+        };
+
+        Ok(Address::new(host, url.port().unwrap_or(default_port)))
+    }
+}
+
+/// Tries to convert a `Url` into an `Address`, using the default port of the scheme if none is given.
+impl TryFrom<Url> for Address {
+    type Error = anyhow::Error;
+
+    fn try_from(url: Url) -> Result<Self> {
+        Address::try_from(&url)
     }
 }
 
@@ -183,51 +427,354 @@ impl TryFrom<&ProxyAddress> for Address {
     type Error = anyhow::Error;
 
     fn try_from(addr: &ProxyAddress) -> Result<Self> {
-        format!("{}:{}", addr.host, addr.port).try_into()
+        addr.host_port().try_into()
     }
 }
 
-/// Reads the destination address from a stream and returns it as an `Address`.
-pub async fn read_address<S>(stream: &mut S) -> Result<Address>
-where
-    S: AsyncRead + Unpin,
-{
-    // Read address type.
-    let mut address_type = [0; 1];
-    stream.read_exact(&mut address_type).await?;
+/// What a client does with the scope ID of a link-local IPv6 destination when it sends the destination to a
+/// proxy. The SOCKS protocols have no room for the scope ID, which wouldn't mean anything on the host of the
+/// proxy anyway.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ScopePolicy {
+    /// Sends the address without its scope ID, and logs a warning.
+    #[default]
+    Strip,
+    /// Refuses to send the address, so that the request fails.
+    Reject,
+}
+
+impl ScopePolicy {
+    // Returns the destination as it's sent to the proxy.
+    pub(crate) fn apply(
+        self,
+        destination: Address,
+    ) -> Result<Address> {
+        let Some(scope_id) = destination.scope_id() else {
+            return Ok(destination);
+        };
+
+        match self {
+            ScopePolicy::Strip => {
+                warn!("Leaving out scope ID {} of {}, which can't be sent to the proxy.", scope_id, destination);
+                Ok(destination.without_scope())
+            }
+            ScopePolicy::Reject => bail!("Can't send the scope ID of {} to the proxy.", destination),
+        }
+    }
+}
+
+/// A range of IP addresses, e.g., `10.0.0.0/8` or `fe80::/10`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNetwork {
+    /// Creates the network of the addresses that share the first `prefix` bits with the address.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the network, or an error if the prefix is longer than the address.
+    pub fn new(
+        addr: IpAddr,
+        prefix: u8,
+    ) -> Result<Self> {
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        ensure!(prefix <= max, "Invalid prefix length for {}: {}", addr, prefix);
+
+        Ok(Self { addr, prefix })
+    }
+
+    /// Returns the address the network was created with.
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    /// Returns the prefix length.
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    /// Returns whether the address is in the network. IPv4 addresses are never in IPv6 networks, nor the
+    /// other way around, except that IPv4-mapped IPv6 addresses are compared as the IPv4 address they map.
+    pub fn contains(
+        &self,
+        addr: IpAddr,
+    ) -> bool {
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+            addr => addr,
+        };
+
+        match (self.addr, addr) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
 
-    let dst_addr = match address_type[0] {
-        SOCKS_ATYP_IPV4 => {
-            let mut dst_addr = [0; 4];
-            stream.read_exact(&mut dst_addr).await?;
+impl From<IpAddr> for IpNetwork {
+    fn from(addr: IpAddr) -> Self {
+        let prefix = if addr.is_ipv4() { 32 } else { 128 };
+        Self { addr, prefix }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = anyhow::Error;
+
+    // Parses an address with an optional prefix length, e.g., `10.0.0.0/8`, `::1`, or `[fe80::]/10`.
+    fn from_str(network: &str) -> Result<Self> {
+        let (addr, prefix) = match network.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (network, None),
+        };
+        let addr = addr.strip_prefix('[').and_then(|a| a.strip_suffix(']')).unwrap_or(addr);
+        let addr: IpAddr = addr.parse().with_context(|| format!("Invalid IP address: {}", addr))?;
 
-            IpAddr::from(dst_addr).to_string()
+        match prefix {
+            Some(prefix) => {
+                let prefix = prefix.parse().with_context(|| format!("Invalid prefix length: {}", prefix))?;
+                Self::new(addr, prefix)
+            }
+            None => Ok(Self::from(addr)),
         }
-        SOCKS_ATYP_IPV6 => {
-            let mut dst_addr = [0; 16];
-            stream.read_exact(&mut dst_addr).await?;
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Encodes a domain name with non-ASCII characters as punycode, following the IDNA rules (UTS #46), so that it
+/// can be serialized and resolved, e.g., `bücher.example` as `xn--bcher-kva.example`. Domain names that are
+/// ASCII already are returned as they are.
+///
+/// Without the `idna` feature, every domain name is returned as it is.
+///
+/// # Returns
+///
+/// A `Result` containing the domain name, or `SocksError::InvalidDomain` if it doesn't follow the IDNA rules.
+pub fn normalize_domain(host: &str) -> Result<String, SocksError> {
+    #[cfg(feature = "idna")]
+    if !host.is_ascii() {
+        return idna::domain_to_ascii(host).map_err(|_| SocksError::InvalidDomain(host.to_string()));
+    }
+
+    Ok(host.to_string())
+}
 
-            IpAddr::from(dst_addr).to_string()
+// Parses an IP address, with a zone ID for IPv6 addresses, into a socket address. Returns `None` if the host isn't
+// an IP address, i.e., is a domain name.
+fn parse_ip(
+    host: &str,
+    port: u16,
+) -> Option<Result<SocketAddr>> {
+    let (ip, zone) = match host.split_once('%') {
+        Some((ip, zone)) => (ip, Some(zone)),
+        None => (host, None),
+    };
+
+    let addr = match (ip.parse().ok()?, zone) {
+        (ip, None) => Ok(SocketAddr::new(ip, port)),
+        (IpAddr::V6(ip), Some(zone)) => {
+            parse_zone(zone).map(|scope_id| SocketAddrV6::new(ip, port, 0, scope_id).into())
         }
-        SOCKS_ATYP_DOMAINNAME => {
-            let mut length = [0; 1];
-            stream.read_exact(&mut length).await?;
+        (IpAddr::V4(_), Some(_)) => Err(anyhow!("IPv4 address can't have a zone ID: {}", host)),
+    };
+
+    Some(addr)
+}
+
+// Returns the scope ID of a zone, given as the index or the name of an interface.
+fn parse_zone(zone: &str) -> Result<u32> {
+    if let Ok(index) = zone.parse() {
+        return Ok(index);
+    }
+
+    interface_index(zone)
+}
+
+// Returns the index of the interface with the given name.
+#[cfg(unix)]
+fn interface_index(name: &str) -> Result<u32> {
+    nix::net::if_::if_nametoindex(name).with_context(|| format!("Unknown interface in zone ID: {}", name))
+}
+
+// Returns an error, as interface names can't be looked up on other platforms.
+#[cfg(not(unix))]
+fn interface_index(name: &str) -> Result<u32> {
+    bail!("Zone IDs must be interface indices on this platform: {}", name)
+}
+
+// Returns the zone ID of a scope ID for display: the name of its interface, or the index if it has no name.
+#[cfg(unix)]
+fn zone_name(scope_id: u32) -> String {
+    match nix::net::if_::if_indextoname(scope_id).map(|name| name.into_string()) {
+        Ok(Ok(name)) if !name.is_empty() => name,
+        _ => scope_id.to_string(),
+    }
+}
 
-            let mut dst_addr = vec![0; length[0] as usize];
-            stream.read_exact(&mut dst_addr).await?;
+// Returns the index as the zone ID, as interface names can't be looked up on other platforms.
+#[cfg(not(unix))]
+fn zone_name(scope_id: u32) -> String {
+    scope_id.to_string()
+}
 
-            String::from_utf8_lossy(&dst_addr[..]).to_string()
+// Returns the Unicode form of a domain name with punycode labels, for display. Only the labels that pass
+// `is_displayable` are decoded, so that a name can't pass for another in logs, e.g., `xn--pple-43d.example`, with a
+// Cyrillic `а`, for `apple.example`. Without the `idna` feature, the name is returned as it is.
+fn display_domain(host: &str) -> Cow<'_, str> {
+    #[cfg(feature = "idna")]
+    if host.contains("xn--") {
+        let uts46 = idna::uts46::Uts46::new();
+        let (unicode, decoded) = uts46.to_user_interface(
+            host.as_bytes(),
+            idna::uts46::AsciiDenyList::EMPTY,
+            idna::uts46::Hyphens::Allow,
+            |label, _, _| is_displayable(label),
+        );
+        if decoded.is_ok() {
+            return unicode;
         }
-        _ => unreachable!(),
+    }
+
+    host.into()
+}
+
+// The scripts that domain labels are displayed in, as far as `is_displayable` tells them apart.
+#[cfg(feature = "idna")]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Script {
+    Latin,
+    Greek,
+    Cyrillic,
+    Armenian,
+    Hebrew,
+    Arabic,
+    Devanagari,
+    Thai,
+    Georgian,
+    // Han, Hiragana, Katakana, Bopomofo and Hangul, which Chinese, Japanese and Korean names mix.
+    Cjk,
+}
+
+// Returns the script of a character, `None` for digits, hyphens and combining marks, which go with any script, or an
+// error for characters of other scripts, which aren't displayed.
+#[cfg(feature = "idna")]
+fn script_of(c: char) -> Result<Option<Script>, ()> {
+    let script = match c {
+        '0'..='9' | '-' | '\u{0300}'..='\u{036F}' => return Ok(None),
+        'a'..='z' | '\u{00DF}'..='\u{00F6}' | '\u{00F8}'..='\u{024F}' | '\u{1E00}'..='\u{1EFF}' => Script::Latin,
+        '\u{0370}'..='\u{03FF}' | '\u{1F00}'..='\u{1FFF}' => Script::Greek,
+        '\u{0400}'..='\u{052F}' => Script::Cyrillic,
+        '\u{0530}'..='\u{058F}' => Script::Armenian,
+        '\u{0590}'..='\u{05FF}' => Script::Hebrew,
+        '\u{0600}'..='\u{06FF}' | '\u{0750}'..='\u{077F}' => Script::Arabic,
+        '\u{0900}'..='\u{097F}' => Script::Devanagari,
+        '\u{0E00}'..='\u{0E7F}' => Script::Thai,
+        '\u{10A0}'..='\u{10FF}' => Script::Georgian,
+        '\u{1100}'..='\u{11FF}'
+        | '\u{3040}'..='\u{312F}'
+        | '\u{3400}'..='\u{4DBF}'
+        | '\u{4E00}'..='\u{9FFF}'
+        | '\u{AC00}'..='\u{D7AF}' => Script::Cjk,
+        _ => return Err(()),
     };
 
-    // Read destination port.
-    let mut dst_port = [0; 2];
-    stream.read_exact(&mut dst_port).await?;
+    Ok(Some(script))
+}
+
+// Cyrillic and Greek letters that look like Latin ones, of which whole-script confusable labels consist.
+#[cfg(feature = "idna")]
+const LATIN_LOOKALIKES: &str = "асеорхуіјѕһԁԛԝӏүαικνορτυχ";
+
+// Decides whether a decoded label is displayed as Unicode, following the restriction levels of UTS #39 loosely: its
+// characters are of a single script, or of Latin and the CJK scripts, and it doesn't consist of Cyrillic or Greek
+// letters that all look like Latin ones.
+#[cfg(feature = "idna")]
+fn is_displayable(label: &[char]) -> bool {
+    let mut scripts = vec![];
+    for &c in label {
+        match script_of(c) {
+            Ok(Some(script)) if !scripts.contains(&script) => scripts.push(script),
+            Ok(_) => {}
+            Err(()) => return false,
+        }
+    }
+
+    match scripts[..] {
+        [Script::Cyrillic] | [Script::Greek] => {
+            !label.iter().all(|c| !c.is_alphabetic() || LATIN_LOOKALIKES.contains(*c))
+        }
+        [_] => true,
+        [Script::Latin, Script::Cjk] | [Script::Cjk, Script::Latin] => true,
+        _ => false,
+    }
+}
+
+// Returns the bytes of a Unix socket path, as they are encoded on the wire.
+#[cfg(unix)]
+pub(crate) fn unix_path_bytes(path: &Path) -> Cow<'_, [u8]> {
+    use std::os::unix::ffi::OsStrExt;
+
+    path.as_os_str().as_bytes().into()
+}
+
+// Returns the bytes of a Unix socket path, which is assumed to be Unicode on other platforms.
+#[cfg(not(unix))]
+pub(crate) fn unix_path_bytes(path: &Path) -> Cow<'_, [u8]> {
+    match path.to_string_lossy() {
+        Cow::Borrowed(path) => path.as_bytes().into(),
+        Cow::Owned(path) => path.into_bytes().into(),
+    }
+}
+
+// Returns the Unix socket path encoded on the wire.
+#[cfg(unix)]
+pub(crate) fn unix_path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+
+    std::ffi::OsStr::from_bytes(bytes).into()
+}
+
+// Returns the Unix socket path encoded on the wire, which is assumed to be Unicode on other platforms.
+#[cfg(not(unix))]
+pub(crate) fn unix_path_from_bytes(bytes: &[u8]) -> PathBuf {
+    String::from_utf8_lossy(bytes).into_owned().into()
+}
 
-    let dst_port = ((dst_port[0] as u16) << 8) | dst_port[1] as u16;
+/// Reads the destination address from a stream and returns it as an `Address`.
+pub async fn read_address<S>(stream: &mut S) -> Result<Address>
+where
+    S: AsyncRead + Unpin + ?Sized,
+{
+    read_address_buf(stream, &mut BytesMut::new()).await
+}
 
-    Ok(Address::new(dst_addr, dst_port))
+/// Reads the destination address from a stream, using `scratch` as the intermediate buffer.
+pub async fn read_address_buf<S>(
+    stream: &mut S,
+    scratch: &mut BytesMut,
+) -> Result<Address>
+where
+    S: AsyncRead + Unpin + ?Sized,
+{
+    wire::read_message(stream, scratch, wire::parse_address).await
 }
 
 #[cfg(test)]
@@ -290,6 +837,50 @@ mod tests {
         Ok(())
     }
 
+    // Tests parsing lists of proxy addresses, including credentials and IPv6 hosts.
+    #[test]
+    fn test_proxy_address_parse_list() -> Result<()> {
+        let user = Some(Credentials::new("user", "secret"));
+        let cases = vec![
+            ("socks6://a:1080", vec![ProxyAddress::new(6, String::from("a"), 1080, None)]),
+            (
+                " socks6://a:1080 , socks5://b:1081",
+                vec![
+                    ProxyAddress::new(6, String::from("a"), 1080, None),
+                    ProxyAddress::new(5, String::from("b"), 1081, None),
+                ],
+            ),
+            (
+                "socks6://user:secret@a:1080,socks6://[::1]:1080",
+                vec![
+                    ProxyAddress::new(6, String::from("a"), 1080, user),
+                    ProxyAddress::new(6, String::from("[::1]"), 1080, None),
+                ],
+            ),
+        ];
+        for (list, expected) in cases {
+            assert_eq!(ProxyAddress::parse_list(list, ',')?, expected, "{}", list);
+        }
+
+        let cases = vec![
+            ("", "position 1"),
+            ("socks6://a:1080,", "position 2"),
+            ("socks6://a:1080,,socks6://b:1080", "position 2"),
+            ("socks6://a:1080,http://b:80", "position 2"),
+            ("socks6://a", "position 1"),
+            ("socks6://[::1:1080", "position 1"),
+        ];
+        for (list, position) in cases {
+            let error = ProxyAddress::parse_list(list, ',').unwrap_err();
+            assert!(error.to_string().contains(position), "{}: {}", list, error);
+        }
+
+        let chain: ChainSpec = "socks6://a:1080, socks5://b:1080".parse()?;
+        assert_eq!(chain.len(), 2);
+        assert_eq!(Vec::from(chain), ProxyAddress::parse_list("socks6://a:1080;socks5://b:1080", ';')?);
+        Ok(())
+    }
+
     #[test]
     fn test_proxy_address_try_from_invalid_string() {
         let proxy_str = "invalid://localhost:1080".to_string();
@@ -346,5 +937,140 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_address_try_from_url() -> Result<()> {
+        for (url, expected) in [
+            ("http://example.com/path", Address::new("example.com", 80)),
+            ("https://example.com", Address::new("example.com", 443)),
+            ("ws://example.com/socket", Address::new("example.com", 80)),
+            ("wss://example.com/socket", Address::new("example.com", 443)),
+            ("https://example.com:8443/", Address::new("example.com", 8443)),
+            ("http://192.168.1.1", Address::new("192.168.1.1", 80)),
+            ("https://[::1]", Address::new("::1", 443)),
+        ] {
+            let address: Address = (&Url::parse(url)?).try_into()?;
+            assert_eq!(address, expected, "{}", url);
+
+            let address: Address = url.try_into()?;
+            assert_eq!(address, expected, "{}", url);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_address_try_from_invalid_url() {
+        let unsupported: Result<Address> = "ftp://example.com".try_into();
+        assert!(unsupported.unwrap_err().to_string().contains("Unsupported URL scheme"));
+
+        let invalid: Result<Address> = "http://".try_into();
+        assert!(invalid.is_err());
+    }
+
+    #[test]
+    fn test_address_try_from_str() -> Result<()> {
+        let address: Address = "localhost:8000".try_into()?;
+        assert_eq!(address, Address::new("localhost", 8000));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "idna")]
+    fn test_address_idna() -> Result<()> {
+        let address: Address = "Bücher.example:443".try_into()?;
+        assert_eq!(address, Address::new("xn--bcher-kva.example", 443));
+        assert_eq!(address, Address::new("bücher.example", 443));
+        assert_eq!(address.to_string(), "bücher.example:443");
+        assert_eq!(&address.as_socks_bytes()[2..23], b"xn--bcher-kva.example");
+
+        // The same normalization applies to domain names received from a peer.
+        let host = "bücher.example".as_bytes();
+        let mut bytes = vec![SOCKS_ATYP_DOMAINNAME, host.len() as u8];
+        bytes.extend_from_slice(host);
+        bytes.extend_from_slice(&443u16.to_be_bytes());
+        assert_eq!(wire::parse_address(&bytes)?, wire::Parsed::Complete(address, bytes.len()));
+
+        let invalid: Result<Address> = "ab\u{200D}.example:443".try_into();
+        assert!(matches!(invalid.unwrap_err().downcast_ref(), Some(SocksError::InvalidDomain(_))));
+        Ok(())
+    }
+
+    // Tests that only the labels of a domain name in a single script, or in Latin and the CJK scripts, are displayed
+    // as Unicode, and not the ones that mix scripts or consist of Cyrillic letters that look like Latin ones.
+    #[test]
+    #[cfg(feature = "idna")]
+    fn test_display_domain() {
+        let displayed = |host: &str| Address::new(host, 443).to_string();
+        assert_eq!(displayed("пример.example"), "пример.example:443");
+        assert_eq!(displayed("日本語とカタカナ.example"), "日本語とカタカナ.example:443");
+        assert_eq!(displayed("abc中文.example"), "abc中文.example:443");
+        assert_eq!(displayed("bücher.пример"), "bücher.пример:443");
+
+        // A Cyrillic `а` among Latin letters, and a label of Cyrillic letters that all look like Latin ones.
+        assert_eq!(displayed("\u{0430}pple.example"), "xn--pple-43d.example:443");
+        assert_eq!(displayed("\u{0430}\u{0440}\u{0440}\u{04CF}\u{0435}.example"), "xn--80ak6aa92e.example:443");
+        assert_eq!(displayed("\u{03BF}\u{03C1}.example"), "xn--0xae.example:443");
+    }
+
+    // Tests that IPv6 addresses with a zone ID parse, by interface index or name, that they display the name of the
+    // interface, and that they round-trip through their string form.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_address_scope_id() -> Result<()> {
+        let loopback = nix::net::if_::if_nametoindex("lo")?;
+        let address: Address = format!("[fe80::1%{}]:80", loopback).parse()?;
+        assert_eq!(address, Address::Ip(format!("[fe80::1%{}]:80", loopback).parse()?));
+        assert_eq!(address.scope_id(), Some(loopback));
+        assert_eq!(address.to_string(), "[fe80::1%lo]:80");
+        assert_eq!(address.to_string().parse::<Address>()?, address);
+        assert_eq!(Address::try_new("fe80::1%lo", 80)?, address);
+        assert_eq!(address.without_scope(), "[fe80::1]:80".parse()?);
+        assert_eq!(address.without_scope().scope_id(), None);
+        assert_eq!(address.as_socks_bytes(), address.without_scope().as_socks_bytes());
+
+        // Interfaces that don't exist are displayed by their index.
+        let unnamed: Address = "[fe80::1%4000000]:80".parse()?;
+        assert_eq!(unnamed.scope_id(), Some(4000000));
+        assert_eq!(unnamed.to_string(), "[fe80::1%4000000]:80");
+        assert_eq!("[fe80::1]:80".parse::<Address>()?, Address::new("fe80::1", 80));
+
+        assert!(Address::try_new("fe80::1%no-such-interface", 80).is_err());
+        assert!(Address::try_new("192.0.2.1%3", 80).is_err());
+        assert!("fe80::1:80".parse::<Address>().is_err());
+        Ok(())
+    }
+
+    // Tests that the scope policy strips or rejects scope IDs, and leaves other addresses alone.
+    #[test]
+    fn test_scope_policy() -> Result<()> {
+        let scoped: Address = "[fe80::1%3]:80".parse()?;
+        assert_eq!(ScopePolicy::Strip.apply(scoped.clone())?, scoped.without_scope());
+        assert!(ScopePolicy::Reject.apply(scoped).is_err());
+
+        let unscoped: Address = "[fe80::1]:80".parse()?;
+        assert_eq!(ScopePolicy::Reject.apply(unscoped.clone())?, unscoped);
+        Ok(())
+    }
+
+    // Tests which addresses networks of either family contain, and which networks parse.
+    #[test]
+    fn test_ip_network() -> Result<()> {
+        let private: IpNetwork = "10.0.0.0/8".parse()?;
+        assert!(private.contains("10.1.2.3".parse()?));
+        assert!(private.contains("::ffff:10.1.2.3".parse()?));
+        assert!(!private.contains("11.0.0.1".parse()?));
+
+        let link_local: IpNetwork = "[fe80::]/10".parse()?;
+        assert!(link_local.contains("fe80::1".parse()?));
+        assert!(!link_local.contains("169.254.0.1".parse()?));
+
+        let all: IpNetwork = "0.0.0.0/0".parse()?;
+        assert!(all.contains("192.0.2.1".parse()?));
+        assert_eq!("::1".parse::<IpNetwork>()?.to_string(), "::1/128");
+
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+        assert!("example.com/8".parse::<IpNetwork>().is_err());
+        Ok(())
+    }
+
     // TODO: Add tests for `read_address` function once we have a way to mock the `AsyncRead`.
 }
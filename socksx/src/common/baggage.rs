@@ -0,0 +1,274 @@
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+
+use anyhow::Result;
+
+use crate::socks6::options::{MetadataOption, SocksOption};
+
+/// The SOCKS6 metadata keys reserved for baggage, below the keys of the connection ID and the chain.
+///
+/// Handlers pass these keys on to the next link of a chain unchanged, unlike the chain keys, which each link
+/// emits again.
+pub const BAGGAGE_METADATA_KEYS: RangeInclusive<u16> = 900..=963;
+
+/// The most bytes a single baggage entry may take, its name, `=`, and value combined.
+pub const MAX_BAGGAGE_ENTRY_LEN: usize = 256;
+
+/// The most bytes all baggage entries of a request may take together.
+pub const MAX_BAGGAGE_LEN: usize = 2048;
+
+/// Named values, e.g., a trace ID, that travel along with a request through every link of a chain.
+///
+/// Each entry is sent as a metadata option under one of the `BAGGAGE_METADATA_KEYS`, in the order the entries
+/// were added, with `name=value` as its value. Entries are bounded by `MAX_BAGGAGE_ENTRY_LEN` each, and by
+/// `MAX_BAGGAGE_LEN` together.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Baggage {
+    entries: Vec<(String, String)>,
+}
+
+impl Baggage {
+    /// Creates baggage without entries.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an entry, or replaces the value of the entry with the same name.
+    ///
+    /// # Errors
+    /// If the name is empty or contains `=`, or the entry doesn't fit within the limits.
+    pub fn with_entry<N, V>(
+        mut self,
+        name: N,
+        value: V,
+    ) -> Result<Self>
+    where
+        N: Into<String>,
+        V: Into<String>,
+    {
+        let name = name.into();
+        let value = value.into();
+        ensure!(!name.is_empty() && !name.contains('='), "Invalid baggage name: {:?}", name);
+        ensure!(
+            entry_len(&name, &value) <= MAX_BAGGAGE_ENTRY_LEN,
+            "Baggage entry {} is too large: {} bytes",
+            name,
+            entry_len(&name, &value)
+        );
+
+        match self.entries.iter_mut().find(|(n, _)| n == &name) {
+            Some(entry) => entry.1 = value,
+            None => {
+                ensure!(self.entries.len() < BAGGAGE_METADATA_KEYS.len(), "Too many baggage entries");
+                self.entries.push((name, value));
+            }
+        }
+        ensure!(
+            self.encoded_len() <= MAX_BAGGAGE_LEN,
+            "Baggage is too large: {} bytes",
+            self.encoded_len()
+        );
+
+        Ok(self)
+    }
+
+    /// Returns the value of the entry with the given name, if there is one.
+    pub fn get(
+        &self,
+        name: &str,
+    ) -> Option<&str> {
+        self.entries.iter().find(|(n, _)| n == name).map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the entries, as names and values, in the order they are sent.
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    /// Returns the number of entries.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns whether there are no entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the number of bytes the entries take together, as counted against `MAX_BAGGAGE_LEN`.
+    pub fn encoded_len(&self) -> usize {
+        self.entries.iter().map(|(name, value)| entry_len(name, value)).sum()
+    }
+
+    /// Returns the entries as metadata, by key.
+    pub fn to_metadata(&self) -> HashMap<u16, String> {
+        BAGGAGE_METADATA_KEYS
+            .zip(&self.entries)
+            .map(|(key, (name, value))| (key, format!("{}={}", name, value)))
+            .collect()
+    }
+
+    /// Returns the entries as metadata options, ordered by key.
+    pub fn to_options(&self) -> Vec<SocksOption> {
+        let mut options: Vec<_> = self.to_metadata().into_iter().collect();
+        options.sort_by_key(|(key, _)| *key);
+
+        options.into_iter().map(|(key, value)| MetadataOption::new(key, value).wrap()).collect()
+    }
+
+    /// Collects the baggage from the metadata of a request, in the order of the keys.
+    ///
+    /// Values without a name are skipped, and so are the entries that don't fit within the limits, so that
+    /// the baggage stays bounded even if the request wasn't validated.
+    pub fn from_metadata(metadata: &HashMap<u16, String>) -> Self {
+        let mut baggage = Self::new();
+        for key in BAGGAGE_METADATA_KEYS {
+            let Some((name, value)) = metadata.get(&key).and_then(|entry| entry.split_once('=')) else {
+                continue;
+            };
+            if name.is_empty() || baggage.get(name).is_some() {
+                continue;
+            }
+            if entry_len(name, value) > MAX_BAGGAGE_ENTRY_LEN
+                || baggage.encoded_len() + entry_len(name, value) > MAX_BAGGAGE_LEN
+            {
+                continue;
+            }
+
+            baggage.entries.push((name.to_string(), value.to_string()));
+        }
+
+        baggage
+    }
+}
+
+/// Returns whether the baggage in the metadata of a request exceeds the limits, either in a single entry, or
+/// all entries together.
+pub fn is_oversized(metadata: &HashMap<u16, String>) -> bool {
+    let mut total = 0;
+    for key in BAGGAGE_METADATA_KEYS {
+        if let Some(entry) = metadata.get(&key) {
+            if entry.len() > MAX_BAGGAGE_ENTRY_LEN {
+                return true;
+            }
+            total += entry.len();
+        }
+    }
+
+    total > MAX_BAGGAGE_LEN
+}
+
+// Returns the number of bytes an entry takes, as it is sent.
+fn entry_len(
+    name: &str,
+    value: &str,
+) -> usize {
+    name.len() + 1 + value.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::addresses::ProxyAddress;
+    use crate::session::{SessionHooks, SessionInfo};
+    use crate::test_util::MockSocksServer;
+    use crate::{Mirror, Socks6Client, Socks6Handler, SocksHandler};
+
+    // Tests that entries keep their order, replace entries of the same name, and round-trip through metadata.
+    #[test]
+    fn test_entries() -> Result<()> {
+        let baggage = Baggage::new()
+            .with_entry("traceparent", "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01")?
+            .with_entry("tenant", "a")?
+            .with_entry("tenant", "b=c")?;
+        assert_eq!(baggage.len(), 2);
+        assert_eq!(baggage.get("tenant"), Some("b=c"));
+
+        let metadata = baggage.to_metadata();
+        assert_eq!(metadata.get(&901), Some(&String::from("tenant=b=c")));
+        assert_eq!(Baggage::from_metadata(&metadata), baggage);
+        assert!(!is_oversized(&metadata));
+
+        Ok(())
+    }
+
+    // Tests that invalid names and entries beyond the limits are refused when added, and skipped when received.
+    #[test]
+    fn test_limits() -> Result<()> {
+        assert!(Baggage::new().with_entry("", "value").is_err());
+        assert!(Baggage::new().with_entry("a=b", "value").is_err());
+        assert!(Baggage::new().with_entry("large", "x".repeat(MAX_BAGGAGE_ENTRY_LEN)).is_err());
+
+        let mut baggage = Baggage::new();
+        for i in 0..(MAX_BAGGAGE_LEN / MAX_BAGGAGE_ENTRY_LEN) {
+            baggage = baggage.with_entry(format!("{:02}", i), "x".repeat(MAX_BAGGAGE_ENTRY_LEN - 3))?;
+        }
+        assert_eq!(baggage.encoded_len(), MAX_BAGGAGE_LEN);
+        assert!(baggage.clone().with_entry("more", "x").is_err());
+
+        let mut metadata = baggage.to_metadata();
+        metadata.insert(950, String::from("more=x"));
+        metadata.insert(951, format!("large={}", "x".repeat(MAX_BAGGAGE_ENTRY_LEN)));
+        metadata.insert(952, String::from("unnamed"));
+        assert!(is_oversized(&metadata));
+        assert_eq!(Baggage::from_metadata(&metadata), baggage);
+
+        Ok(())
+    }
+
+    // Hooks that pass on the baggage of every session.
+    struct BaggageHooks {
+        received: Mutex<Vec<Baggage>>,
+    }
+
+    impl SessionHooks for BaggageHooks {
+        fn mirror(
+            &self,
+            session: &SessionInfo,
+        ) -> Option<Mirror> {
+            self.received.lock().unwrap().push(session.baggage.clone());
+            None
+        }
+    }
+
+    // Tests that a handler exposes the baggage to its hooks, and passes it on to the next link unchanged.
+    #[tokio::test]
+    async fn test_forward_baggage() -> Result<()> {
+        let next = MockSocksServer::socks6();
+        let hooks = Arc::new(BaggageHooks {
+            received: Mutex::new(vec![]),
+        });
+        let handler = Socks6Handler::new(vec![ProxyAddress::new(6, String::from("next"), 1080, None)])
+            .with_connector(Arc::new(next.clone()))
+            .with_hooks(hooks.clone());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            handler.accept_request(&mut stream).await
+        });
+
+        let baggage = Baggage::new().with_entry("trace-id", "4bf92f3577b34da6")?;
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+        client.connect_with_baggage("192.0.2.1:80", None, None, &baggage).await?;
+
+        assert_eq!(hooks.received.lock().unwrap().clone(), vec![baggage.clone()]);
+        let forwarded: HashMap<u16, String> = next.recordings()[0]
+            .options
+            .iter()
+            .filter_map(|option| match option {
+                SocksOption::Metadata(m) => Some((m.key, m.value.clone())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(forwarded.get(&900), baggage.to_metadata().get(&900));
+        assert_eq!(Baggage::from_metadata(&forwarded), baggage);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,496 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time;
+
+use crate::session::ConnectionId;
+
+tokio::task_local! {
+    // The limit that the tunnels relayed by the current task share, if any.
+    static LIMIT: BandwidthLimit;
+}
+
+/// How often a `BandwidthLimit` distributes its budget over the tunnels by default.
+pub const DEFAULT_BANDWIDTH_INTERVAL: Duration = Duration::from_millis(100);
+
+// The least a tunnel is allowed per interval, unless its equal share is less, so that a tunnel that was idle can
+// relay a few reads right away.
+const MIN_ALLOWANCE: u64 = 16 * 1024;
+
+/// The rate that a tunnel relayed at in the last interval of a `BandwidthLimit`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TunnelRate {
+    /// The connection ID of the session of the tunnel.
+    pub id: ConnectionId,
+    /// The bytes relayed per second, in both directions combined.
+    pub bytes_per_second: u64,
+}
+
+// The allowance of a tunnel for the current interval, which its streams take from without a lock.
+#[derive(Debug)]
+pub(crate) struct Quota {
+    id: ConnectionId,
+    allowance: AtomicU64,
+    used: AtomicU64,
+    // Whether the tunnel ran out of allowance in the current interval.
+    starved: AtomicBool,
+    rate: AtomicU64,
+    // The streams that wait for allowance, to be woken once the next interval starts.
+    waiting: Mutex<[Option<Waker>; 2]>,
+}
+
+impl Quota {
+    // Takes the bytes that were read from the allowance.
+    fn take(
+        &self,
+        length: u64,
+    ) {
+        let _ = self.allowance.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |a| Some(a.saturating_sub(length)));
+        self.used.fetch_add(length, Ordering::Relaxed);
+    }
+
+    // Grants the allowance of the next interval, and wakes the streams waiting for it.
+    fn grant(
+        &self,
+        allowance: u64,
+    ) {
+        self.allowance.store(allowance, Ordering::Relaxed);
+        for waker in self.waiting.lock().unwrap().iter_mut().filter_map(Option::take) {
+            waker.wake();
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    quotas: Vec<Arc<Quota>>,
+    coordinating: bool,
+}
+
+/// A number of bytes per second that the tunnels of a server relay at, together.
+///
+/// Set with `SocksServer::with_bandwidth_limit`. The limit is shared fairly: every interval, a coordinator task
+/// gives each tunnel an equal share of the interval's budget, and lends what the tunnels that relay less than their
+/// share leave unused to the tunnels that ran out of theirs. So a bulk transfer gets whatever the interactive
+/// sessions beside it don't use, without delaying them. The tunnels take from their allowances without a lock.
+///
+/// Bytes are counted in both directions combined. Clones share the same limit, so that a limit can cover several
+/// servers.
+#[derive(Clone, Debug)]
+pub struct BandwidthLimit {
+    bytes_per_second: u64,
+    interval: Duration,
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl BandwidthLimit {
+    /// Creates a limit of the given number of bytes per second, distributed every `DEFAULT_BANDWIDTH_INTERVAL`.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the limit, or an error if the rate is zero.
+    pub fn new(bytes_per_second: u64) -> Result<Self> {
+        ensure!(bytes_per_second > 0, "The bandwidth limit must be positive.");
+
+        Ok(Self {
+            bytes_per_second,
+            interval: DEFAULT_BANDWIDTH_INTERVAL,
+            registry: Arc::new(Mutex::new(Registry::default())),
+        })
+    }
+
+    /// Sets how often the budget is distributed. Shorter intervals follow changes in demand more closely, at the
+    /// cost of waking the coordinator more often.
+    pub fn with_interval(
+        mut self,
+        interval: Duration,
+    ) -> Self {
+        self.interval = interval.max(Duration::from_millis(1));
+        self
+    }
+
+    /// Returns the number of bytes per second.
+    pub fn bytes_per_second(&self) -> u64 {
+        self.bytes_per_second
+    }
+
+    /// Returns the interval the budget is distributed in.
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Returns the rates that the tunnels relayed at in the last interval, in the order they started.
+    pub fn rates(&self) -> Vec<TunnelRate> {
+        let registry = self.registry.lock().unwrap();
+        registry
+            .quotas
+            .iter()
+            .map(|quota| TunnelRate {
+                id: quota.id,
+                bytes_per_second: quota.rate.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// Runs the future with the limit as the one that its tunnels share.
+    pub(crate) async fn scope<F: Future>(
+        self,
+        future: F,
+    ) -> F::Output {
+        LIMIT.scope(self, future).await
+    }
+
+    // Returns the bytes of an interval's budget.
+    fn budget(&self) -> u64 {
+        ((self.bytes_per_second as f64 * self.interval.as_secs_f64()) as u64).max(1)
+    }
+
+    // Adds a tunnel, starting the coordinator if it isn't running. Until the next interval, the tunnel may relay the
+    // least allowance.
+    fn join(
+        &self,
+        id: ConnectionId,
+    ) -> Share {
+        let mut registry = self.registry.lock().unwrap();
+        let allowance = (self.budget() / (registry.quotas.len() as u64 + 1)).min(MIN_ALLOWANCE);
+        let quota = Arc::new(Quota {
+            id,
+            allowance: AtomicU64::new(allowance),
+            used: AtomicU64::new(0),
+            starved: AtomicBool::new(false),
+            rate: AtomicU64::new(0),
+            waiting: Mutex::new([None, None]),
+        });
+        registry.quotas.push(Arc::clone(&quota));
+
+        if !registry.coordinating {
+            registry.coordinating = true;
+            tokio::spawn(coordinate(Arc::downgrade(&self.registry), self.budget(), self.interval));
+        }
+
+        Share {
+            registry: Arc::clone(&self.registry),
+            quota,
+        }
+    }
+}
+
+// A tunnel's part in the limit of its task, which it leaves when dropped.
+pub(crate) struct Share {
+    registry: Arc<Mutex<Registry>>,
+    quota: Arc<Quota>,
+}
+
+impl Share {
+    // Joins the limit of the current task, if any, for the tunnel of the session.
+    pub(crate) fn current(id: ConnectionId) -> Option<Self> {
+        LIMIT.try_with(|limit| limit.join(id)).ok()
+    }
+
+    pub(crate) fn quota(&self) -> Arc<Quota> {
+        Arc::clone(&self.quota)
+    }
+}
+
+impl Drop for Share {
+    fn drop(&mut self) {
+        let mut registry = self.registry.lock().unwrap();
+        registry.quotas.retain(|quota| !Arc::ptr_eq(quota, &self.quota));
+    }
+}
+
+// Distributes the budget every interval for as long as there are tunnels, and the limit exists.
+async fn coordinate(
+    registry: Weak<Mutex<Registry>>,
+    budget: u64,
+    interval: Duration,
+) {
+    let mut cursor = 0;
+    loop {
+        time::sleep(interval).await;
+        let Some(registry) = registry.upgrade() else {
+            return;
+        };
+
+        let mut registry = registry.lock().unwrap();
+        if registry.quotas.is_empty() {
+            registry.coordinating = false;
+            return;
+        }
+        cursor = distribute(&registry.quotas, budget, interval, cursor);
+    }
+}
+
+// Grants the allowances of the next interval. Tunnels that didn't run out get twice what they used, between the
+// least allowance and their equal share. What that leaves of the budget is split between the tunnels that did run
+// out, or between all tunnels if none did, so that the allowances add up to the budget.
+//
+// The bytes that don't split evenly go to one tunnel each, taking turns from the cursor on, so that every tunnel gets
+// some even if there are more of them than bytes in the budget. Returns the cursor for the next interval.
+fn distribute(
+    quotas: &[Arc<Quota>],
+    budget: u64,
+    interval: Duration,
+    cursor: u64,
+) -> u64 {
+    let share = budget / quotas.len() as u64;
+    let mut spare = budget;
+    let mut starved = 0;
+    let allowances: Vec<_> = quotas
+        .iter()
+        .map(|quota| {
+            let used = quota.used.swap(0, Ordering::Relaxed);
+            quota.rate.store((used as f64 / interval.as_secs_f64()) as u64, Ordering::Relaxed);
+            if quota.starved.swap(false, Ordering::Relaxed) {
+                starved += 1;
+                return None;
+            }
+
+            let allowance = used.saturating_mul(2).clamp(MIN_ALLOWANCE.min(share), share);
+            spare -= allowance;
+            Some(allowance)
+        })
+        .collect();
+
+    let borrowers = if starved > 0 { starved } else { quotas.len() as u64 };
+    let (lent, remainder) = (spare / borrowers, spare % borrowers);
+    let mut borrower = 0;
+    let mut lend = || {
+        let turn = (borrower + borrowers - cursor % borrowers) % borrowers;
+        borrower += 1;
+        lent + u64::from(turn < remainder)
+    };
+    for (quota, allowance) in quotas.iter().zip(allowances) {
+        let allowance = match allowance {
+            None => lend(),
+            Some(allowance) if starved == 0 => allowance + lend(),
+            Some(allowance) => allowance,
+        };
+        quota.grant(allowance);
+    }
+
+    (cursor + remainder) % borrowers
+}
+
+// A stream of a tunnel that reads no more than the allowance of its tunnel, and waits for the next interval once
+// that is used up. Without a quota, it reads like the stream it wraps.
+pub(crate) struct Throttled<S> {
+    inner: S,
+    quota: Option<Arc<Quota>>,
+    slot: usize,
+}
+
+impl<S> Throttled<S> {
+    pub(crate) fn new(
+        inner: S,
+        quota: Option<Arc<Quota>>,
+        slot: usize,
+    ) -> Self {
+        Self { inner, quota, slot }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let Some(quota) = &this.quota else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+
+        let mut allowance = quota.allowance.load(Ordering::Relaxed);
+        if allowance == 0 {
+            quota.starved.store(true, Ordering::Relaxed);
+            quota.waiting.lock().unwrap()[this.slot] = Some(cx.waker().clone());
+
+            // The next interval may have started in the meantime.
+            allowance = quota.allowance.load(Ordering::Relaxed);
+            if allowance == 0 {
+                return Poll::Pending;
+            }
+        }
+
+        let read = if allowance < buf.remaining() as u64 {
+            // Reads into the front of the buffer, which is only zeroed the first time it is used.
+            buf.initialize_unfilled_to(allowance as usize);
+            let mut limited = buf.take(allowance as usize);
+            let read = Pin::new(&mut this.inner).poll_read(cx, &mut limited);
+            let length = limited.filled().len();
+            buf.advance(length);
+            read.map_ok(|_| length)
+        } else {
+            let filled = buf.filled().len();
+            Pin::new(&mut this.inner).poll_read(cx, buf).map_ok(|_| buf.filled().len() - filled)
+        };
+
+        read.map_ok(|length| quota.take(length as u64))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::time::Instant;
+
+    use crate::session::{self, SessionLimits};
+
+    use super::*;
+
+    // Starts a relay that shares the limit, returning the ends of its source and destination.
+    fn start_tunnel(limit: &BandwidthLimit) -> (ConnectionId, tokio::io::DuplexStream, tokio::io::DuplexStream) {
+        let id = ConnectionId::generate();
+        let (client, mut source) = tokio::io::duplex(64 * 1024);
+        let (mut destination, server) = tokio::io::duplex(64 * 1024);
+        let relay = async move {
+            let limits = SessionLimits::none();
+            session::relay(id, &mut source, &mut destination, limits, future::pending()).await.1
+        };
+        tokio::spawn(limit.clone().scope(relay));
+
+        (id, client, server)
+    }
+
+    // Tests that a greedy tunnel gets the bandwidth that a trickling tunnel leaves unused, without delaying it.
+    #[tokio::test(start_paused = true)]
+    async fn test_greedy_and_trickle() -> Result<()> {
+        let limit = BandwidthLimit::new(1_000_000)?;
+        let (greedy, mut greedy_client, mut greedy_server) = start_tunnel(&limit);
+        let (trickle, mut trickle_client, mut trickle_server) = start_tunnel(&limit);
+
+        tokio::spawn(async move {
+            let chunk = vec![0; 16 * 1024];
+            while greedy_client.write_all(&chunk).await.is_ok() {}
+        });
+        let received = tokio::spawn(async move {
+            let mut buffer = vec![0; 64 * 1024];
+            let mut received = 0;
+            let started = Instant::now();
+            while started.elapsed() < Duration::from_secs(2) {
+                received += greedy_server.read(&mut buffer).await.unwrap();
+            }
+            (received, greedy_server)
+        });
+
+        // Every message of the trickling tunnel arrives without waiting for the next interval.
+        for _ in 0..200 {
+            let sent = Instant::now();
+            trickle_client.write_all(&[1; 100]).await?;
+            trickle_server.read_exact(&mut [0; 100]).await?;
+            assert!(sent.elapsed() < Duration::from_millis(1));
+            time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // The greedy tunnel relays what the limit leaves, but for the least allowance the trickling tunnel keeps.
+        let (received, _greedy_server) = received.await?;
+        assert!((1_600_000..=2_000_000).contains(&received), "{}", received);
+
+        let rates = limit.rates();
+        assert_eq!(rates.iter().map(|rate| rate.id).collect::<Vec<_>>(), vec![greedy, trickle]);
+        assert!((800_000..=1_000_000).contains(&rates[0].bytes_per_second), "{:?}", rates);
+        assert!((5_000..=20_000).contains(&rates[1].bytes_per_second), "{:?}", rates);
+        Ok(())
+    }
+
+    // Tests that the allowances add up to the budget, and that the tunnels that ran out borrow the spare.
+    #[test]
+    fn test_distribute() {
+        let quota = |used, starved| {
+            Arc::new(Quota {
+                id: ConnectionId::generate(),
+                allowance: AtomicU64::new(0),
+                used: AtomicU64::new(used),
+                starved: AtomicBool::new(starved),
+                rate: AtomicU64::new(0),
+                waiting: Mutex::new([None, None]),
+            })
+        };
+        let allowances = |quotas: &[Arc<Quota>]| -> Vec<u64> {
+            quotas.iter().map(|quota| quota.allowance.load(Ordering::Relaxed)).collect()
+        };
+
+        // An idle tunnel keeps the least allowance, and one that used a little gets twice as much.
+        let quotas = [quota(100_000, true), quota(0, false), quota(10_000, false)];
+        distribute(&quotas, 100_000, Duration::from_millis(100), 0);
+        assert_eq!(allowances(&quotas), vec![100_000 - 16_384 - 20_000, 16_384, 20_000]);
+        assert_eq!(quotas[0].rate.load(Ordering::Relaxed), 1_000_000);
+
+        // Without starved tunnels, the spare is split between all of them, up to the budget.
+        distribute(&quotas, 90_000, Duration::from_millis(100), 0);
+        assert_eq!(allowances(&quotas), vec![30_000, 30_000, 30_000]);
+        assert!(!quotas[0].starved.load(Ordering::Relaxed));
+
+        // The bytes that don't split evenly go to the tunnels in turn.
+        let cursor = distribute(&quotas, 90_002, Duration::from_millis(100), 2);
+        assert_eq!(allowances(&quotas), vec![30_001, 30_000, 30_001]);
+        assert_eq!(cursor, 1);
+    }
+
+    // Tests that with more tunnels than bytes in the budget, every tunnel gets a byte within a few intervals, and the
+    // allowances still add up to the budget.
+    #[test]
+    fn test_distribute_more_tunnels_than_budget() {
+        let quotas: Vec<_> = (0..1001)
+            .map(|_| {
+                Arc::new(Quota {
+                    id: ConnectionId::generate(),
+                    allowance: AtomicU64::new(0),
+                    used: AtomicU64::new(0),
+                    starved: AtomicBool::new(true),
+                    rate: AtomicU64::new(0),
+                    waiting: Mutex::new([None, None]),
+                })
+            })
+            .collect();
+
+        let mut granted = vec![0; quotas.len()];
+        let mut cursor = 0;
+        for _ in 0..2 {
+            cursor = distribute(&quotas, 1000, Duration::from_millis(100), cursor);
+            let allowances: Vec<_> = quotas.iter().map(|quota| quota.allowance.load(Ordering::Relaxed)).collect();
+            assert_eq!(allowances.iter().sum::<u64>(), 1000);
+            for (granted, allowance) in granted.iter_mut().zip(allowances) {
+                *granted += allowance;
+            }
+            for quota in &quotas {
+                quota.starved.store(true, Ordering::Relaxed);
+            }
+        }
+        assert!(granted.iter().all(|&granted| granted > 0), "{:?}", granted);
+    }
+}
@@ -0,0 +1,155 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use crate::SocksError;
+
+tokio::task_local! {
+    // The budget that the handshakes of the current task are charged to, if any.
+    static BUDGET: MemoryBudget;
+}
+
+#[derive(Debug)]
+struct Inner {
+    limit: usize,
+    used: AtomicUsize,
+}
+
+/// A number of bytes that the handshakes of all connections of a server may buffer at once, e.g., to keep many
+/// clients that send large option blocks or initial data from exhausting the memory of the server together.
+///
+/// Set with `SocksServer::with_memory_budget`. A request whose handshake would exceed the budget is refused with
+/// a general failure reply. Clones share the same budget, so that a budget can cover several servers.
+///
+/// Only the SOCKS6 handshake is charged, i.e., its request, the answers of authentication rounds, and its initial
+/// data: a SOCKS5 handshake buffers a few hundred bytes at most.
+#[derive(Clone, Debug)]
+pub struct MemoryBudget {
+    inner: Arc<Inner>,
+}
+
+impl MemoryBudget {
+    /// Creates a budget of the given number of bytes.
+    pub fn new(limit: usize) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                limit,
+                used: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Returns the number of bytes of the budget.
+    pub fn limit(&self) -> usize {
+        self.inner.limit
+    }
+
+    /// Returns the number of bytes that handshakes buffer at the moment.
+    pub fn used(&self) -> usize {
+        self.inner.used.load(Ordering::Relaxed)
+    }
+
+    /// Runs the future with the budget as the one that its handshakes are charged to.
+    pub(crate) async fn scope<F: Future>(
+        self,
+        future: F,
+    ) -> F::Output {
+        BUDGET.scope(self, future).await
+    }
+
+    // Takes the bytes from the budget, unless that would exceed it.
+    fn reserve(
+        &self,
+        bytes: usize,
+    ) -> bool {
+        let limit = self.inner.limit;
+        let reserved = self.inner.used.fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+            used.checked_add(bytes).filter(|&total| total <= limit)
+        });
+
+        reserved.is_ok()
+    }
+
+    // Returns the bytes to the budget.
+    fn release(
+        &self,
+        bytes: usize,
+    ) {
+        self.inner.used.fetch_sub(bytes, Ordering::AcqRel);
+    }
+}
+
+// The bytes that a handshake buffers, charged to the budget of the task it runs in, and returned to it when the
+// charge is dropped. Outside of a budget, e.g., in a handshake that a server without a budget serves, charging
+// always succeeds.
+#[derive(Debug, Default)]
+pub(crate) struct Charge {
+    budget: Option<MemoryBudget>,
+    bytes: usize,
+}
+
+impl Charge {
+    // Creates a charge against the budget of the current task, if any.
+    pub(crate) fn current() -> Self {
+        Self {
+            budget: BUDGET.try_with(Clone::clone).ok(),
+            bytes: 0,
+        }
+    }
+
+    // Charges more bytes, failing with `SocksError::MemoryBudgetExhausted` if the budget can't cover them.
+    pub(crate) fn add(
+        &mut self,
+        bytes: usize,
+    ) -> Result<()> {
+        if let Some(budget) = &self.budget {
+            if !budget.reserve(bytes) {
+                bail!(SocksError::MemoryBudgetExhausted(budget.limit()));
+            }
+        }
+        self.bytes += bytes;
+
+        Ok(())
+    }
+}
+
+impl Drop for Charge {
+    fn drop(&mut self) {
+        if let Some(budget) = &self.budget {
+            budget.release(self.bytes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Tests that charges are refused beyond the limit, and that dropping them returns their bytes.
+    #[tokio::test]
+    async fn test_charge() {
+        let budget = MemoryBudget::new(100);
+        let scoped = budget.clone().scope(async {
+            let mut first = Charge::current();
+            first.add(60)?;
+            let mut second = Charge::current();
+            let error = second.add(50).unwrap_err();
+            assert!(matches!(error.downcast_ref(), Some(SocksError::MemoryBudgetExhausted(100))));
+            second.add(40)?;
+            assert_eq!(budget.used(), 100);
+
+            drop(first);
+            assert_eq!(budget.used(), 40);
+            Ok::<_, anyhow::Error>(())
+        });
+        scoped.await.unwrap();
+        assert_eq!(budget.used(), 0);
+
+        // Outside of the scope, nothing is charged to the budget.
+        let mut unbudgeted = Charge::current();
+        unbudgeted.add(1000).unwrap();
+        assert_eq!(budget.used(), 0);
+    }
+}
@@ -0,0 +1,374 @@
+use std::env;
+
+use anyhow::{Context, Result};
+use tokio::io::AsyncWriteExt;
+
+use crate::addresses::{normalize_domain, IpNetwork};
+use crate::dialer::{AddressFamilyPreference, Dialer, Keepalive};
+use crate::guard::DestinationGuard;
+use crate::stream::SocksStream;
+use crate::{Address, SocksError};
+
+/// The environment variables a bypass list is read from, in the order curl consults them.
+pub const NO_PROXY_VARS: [&str; 2] = ["no_proxy", "NO_PROXY"];
+
+/// How a client reached a destination.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Route {
+    /// Through the proxy.
+    Proxied,
+    /// Directly, as the destination is on the client's bypass list.
+    Direct,
+}
+
+/// A rule of a `BypassList`, deciding which destinations are connected to directly.
+///
+/// Domain rules never match IP destinations, nor the other way around, as no names are resolved to decide.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum BypassRule {
+    /// Matches every destination, written as `*`.
+    All,
+    /// Matches the IP destinations within the network, written as an address with an optional prefix length.
+    Network(IpNetwork),
+    /// Matches the domain destinations with exactly this host, ignoring case.
+    Host(String),
+    /// Matches the domain destinations with this host, or a subdomain of it, ignoring case.
+    ///
+    /// This is how names in a list are read, with or without a leading dot, like curl does.
+    Domain(String),
+}
+
+impl BypassRule {
+    /// Returns whether the rule applies to the destination. Unix socket destinations are never matched.
+    pub fn matches(
+        &self,
+        destination: &Address,
+    ) -> bool {
+        match (self, destination) {
+            (_, Address::Unix(_)) => false,
+            (BypassRule::All, _) => true,
+            (BypassRule::Network(network), Address::Ip(addr)) => network.contains(addr.ip()),
+            (BypassRule::Host(name), Address::Domainname { host, .. }) => normalize_name(host) == *name,
+            (BypassRule::Domain(name), Address::Domainname { host, .. }) => domain_matches(name, host),
+            _ => false,
+        }
+    }
+}
+
+/// The destinations a client connects to directly instead of through the proxy, e.g., localhost and the
+/// addresses of its own cluster.
+///
+/// Lists are written like curl's `NO_PROXY`: entries separated by commas or whitespace, each either `*`, an IP
+/// address with an optional prefix length, or a name that matches itself and its subdomains.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct BypassList {
+    rules: Vec<BypassRule>,
+}
+
+impl BypassList {
+    /// Creates a list without rules, which bypasses the proxy for no destination.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parses a list, e.g., `localhost,.cluster.local,10.0.0.0/8`.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the list, or an error that names the position of the first invalid entry.
+    pub fn parse(list: &str) -> Result<Self> {
+        let rules = entries(list)
+            .enumerate()
+            .map(|(i, entry)| {
+                parse_rule(entry).with_context(|| format!("Invalid entry at position {} of the list: {}", i + 1, entry))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self { rules })
+    }
+
+    /// Reads the list from `no_proxy`, or `NO_PROXY` if that isn't set, skipping invalid entries.
+    pub fn from_env() -> Self {
+        Self::from_vars(|name| env::var(name).ok())
+    }
+
+    // Reads the list from the first of the variables that is set, with the given lookup.
+    fn from_vars<F>(lookup: F) -> Self
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let Some(list) = NO_PROXY_VARS.iter().find_map(|name| lookup(name)) else {
+            return Self::new();
+        };
+
+        let rules = entries(&list)
+            .filter_map(|entry| match parse_rule(entry) {
+                Ok(rule) => Some(rule),
+                Err(e) => {
+                    warn!("Ignoring invalid entry of the bypass list: {}: {:#}", entry, e);
+                    None
+                }
+            })
+            .collect();
+
+        Self { rules }
+    }
+
+    /// Adds a rule, e.g., a `BypassRule::Host` that doesn't match subdomains.
+    pub fn with_rule(
+        mut self,
+        rule: BypassRule,
+    ) -> Self {
+        let rule = match rule {
+            BypassRule::Host(name) => BypassRule::Host(normalize_name(&name)),
+            BypassRule::Domain(name) => BypassRule::Domain(normalize_name(name.trim_start_matches('.'))),
+            rule => rule,
+        };
+        self.rules.push(rule);
+
+        self
+    }
+
+    /// Returns the rules, in the order they were added.
+    pub fn rules(&self) -> &[BypassRule] {
+        &self.rules
+    }
+
+    /// Returns whether the list has no rules.
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Returns whether any rule matches the destination.
+    pub fn matches(
+        &self,
+        destination: &Address,
+    ) -> bool {
+        self.rules.iter().any(|rule| rule.matches(destination))
+    }
+
+    /// Returns how a client with this list reaches the destination.
+    pub fn route(
+        &self,
+        destination: &Address,
+    ) -> Route {
+        if self.matches(destination) {
+            Route::Direct
+        } else {
+            Route::Proxied
+        }
+    }
+}
+
+/// Connects to the destination directly, as the clients do for the destinations on their bypass list, and writes
+/// the initial data. The guard checks the addresses a domain name resolves to, as it does for the proxy.
+///
+/// # Returns
+///
+/// A `Result` containing the stream, and its local address in place of the address a proxy would have bound.
+pub(crate) async fn connect_direct(
+    destination: &Address,
+    guard: &DestinationGuard,
+    family_preference: AddressFamilyPreference,
+    keepalive: Option<Keepalive>,
+    mark: Option<u32>,
+    initial_data: Option<Vec<u8>>,
+) -> Result<(SocksStream, Address)> {
+    let mut dialer = Dialer::default();
+    dialer.set_guard(guard.clone());
+    dialer.set_family_preference(family_preference);
+    dialer.set_keepalive(keepalive);
+    dialer.set_mark(mark);
+
+    debug!("Connecting to {} directly, bypassing the proxy.", destination);
+    let (mut stream, _) = dialer.connect(destination).await?;
+    if let Some(initial_data) = initial_data {
+        stream.write_all(&initial_data).await.map_err(SocksError::InitialDataWrite)?;
+    }
+
+    let binding = Address::Ip(stream.local_addr()?);
+    Ok((SocksStream::Tcp(stream), binding))
+}
+
+// Splits a list into its entries, skipping empty ones.
+fn entries(list: &str) -> impl Iterator<Item = &str> {
+    list.split(|c: char| c == ',' || c.is_whitespace()).filter(|entry| !entry.is_empty())
+}
+
+// Parses an entry of a list into a rule.
+fn parse_rule(entry: &str) -> Result<BypassRule> {
+    if entry == "*" {
+        return Ok(BypassRule::All);
+    }
+
+    // Anything that starts like an IP address, or contains a prefix length, must be a valid network.
+    let looks_like_ip = entry.starts_with('[') || entry.contains('/') || entry.parse::<std::net::IpAddr>().is_ok();
+    if looks_like_ip {
+        return entry.parse().map(BypassRule::Network);
+    }
+
+    let name = normalize_name(entry.trim_start_matches('.'));
+    ensure!(!name.is_empty(), "Empty name");
+    Ok(BypassRule::Domain(name))
+}
+
+// Returns whether the host is the normalized name, or a subdomain of it.
+pub(crate) fn domain_matches(
+    name: &str,
+    host: &str,
+) -> bool {
+    let host = normalize_name(host);
+    host == name || host.strip_suffix(name).is_some_and(|sub| sub.ends_with('.'))
+}
+
+// Lowercases a name and strips a trailing dot, so that the forms of a name compare equal.
+pub(crate) fn normalize_name(name: &str) -> String {
+    let name = name.trim_end_matches('.');
+    normalize_domain(name).unwrap_or_else(|_| name.to_string()).to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::test_util::MockSocksServer;
+    use crate::{Socks5Client, Socks6Client};
+
+    // Tests which destinations the entries of a list match.
+    #[test]
+    fn test_matches() -> Result<()> {
+        let list = BypassList::parse("localhost, .cluster.local example.com. 10.0.0.0/8,[::1],169.254.0.0/16")?;
+        assert_eq!(list.rules().len(), 6);
+
+        let bypassed = [
+            "localhost",
+            "LocalHost.",
+            "api.svc.cluster.local",
+            "cluster.local",
+            "example.com",
+            "www.example.com",
+            "10.1.2.3",
+            "::1",
+            "169.254.169.254",
+        ];
+        for host in bypassed {
+            assert_eq!(list.route(&Address::new(host, 80)), Route::Direct, "{}", host);
+        }
+
+        let proxied = ["notexample.com", "example.org", "11.0.0.1", "::2", "127.0.0.1"];
+        for host in proxied {
+            assert_eq!(list.route(&Address::new(host, 80)), Route::Proxied, "{}", host);
+        }
+
+        let exact = BypassList::new().with_rule(BypassRule::Host(String::from("Example.com")));
+        assert!(exact.matches(&Address::new("example.com", 80)));
+        assert!(!exact.matches(&Address::new("www.example.com", 80)));
+
+        let all = BypassList::parse("*")?;
+        assert!(all.matches(&Address::new("192.0.2.1", 80)) && all.matches(&Address::new("example.com", 80)));
+        assert!(!all.matches(&Address::unix("/tmp/socket")?));
+
+        assert!(BypassList::parse("10.0.0.0/33").unwrap_err().to_string().contains("position 1"));
+        assert!(BypassList::new().is_empty());
+        Ok(())
+    }
+
+    // Tests that the list is read from the lowercase variable first, skipping invalid entries.
+    #[test]
+    fn test_from_vars() {
+        let list = BypassList::from_vars(|name| match name {
+            "no_proxy" => Some(String::from("localhost,10.0.0.0/99")),
+            _ => Some(String::from("*")),
+        });
+        assert_eq!(list.rules(), &[BypassRule::Domain(String::from("localhost"))]);
+
+        let list = BypassList::from_vars(|name| (name == "NO_PROXY").then(|| String::from("*")));
+        assert_eq!(list.rules(), &[BypassRule::All]);
+        assert!(BypassList::from_vars(|_| None).is_empty());
+    }
+
+    // Tests that both clients connect to bypassed destinations directly, and to the others through the proxy, and
+    // describe the direct tunnel as such.
+    #[tokio::test]
+    async fn test_clients_bypass() -> Result<()> {
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let destination_addr = destination.local_addr()?;
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = destination.accept().await?;
+                let mut received = [0; 5];
+                stream.read_exact(&mut received).await?;
+                stream.write_all(&received).await?;
+            }
+            #[allow(unreachable_code)]
+            Ok::<_, anyhow::Error>(())
+        });
+        let bypass = BypassList::parse("127.0.0.0/8")?;
+
+        let proxy = MockSocksServer::socks5();
+        let proxy_addr = proxy.bind().await?;
+        let client = Socks5Client::new(proxy_addr.to_string(), None).await?.with_bypass(bypass.clone());
+        let (mut stream, binding, route) = client.connect_routed(destination_addr, Some(b"hello".to_vec())).await?;
+        let mut echoed = [0; 5];
+        stream.read_exact(&mut echoed).await?;
+        assert_eq!((&echoed, route), (b"hello", Route::Direct));
+        assert_eq!(binding, Address::Ip(stream.local_addr()?));
+        let (_, _, route) = client.connect_routed("192.0.2.1:80", None).await?;
+        assert_eq!(route, Route::Proxied);
+        assert_eq!(proxy.recordings().len(), 1);
+
+        let proxy = MockSocksServer::socks6();
+        let proxy_addr = proxy.bind().await?;
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?.with_bypass(bypass);
+        let (mut stream, info) = client.connect_with_info(destination_addr, Some(b"hello".to_vec()), None).await?;
+        stream.read_exact(&mut echoed).await?;
+        assert_eq!(&echoed, b"hello");
+        assert_eq!((info.proxy, info.route), (Some(destination_addr), Route::Direct));
+        assert_eq!(info.binding, Address::Ip(stream.local_addr()?));
+        let (_, _, route) = client.connect_routed("192.0.2.1:80", None, None).await?;
+        assert_eq!(route, Route::Proxied);
+        assert_eq!(proxy.recordings().len(), 1);
+
+        Ok(())
+    }
+
+    // Tests that the guard of the clients checks the addresses a bypassed domain name resolves to, and refuses the
+    // name if they are blocked, in each of the ways to connect.
+    #[tokio::test]
+    async fn test_bypass_guarded() -> Result<()> {
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let port = destination.local_addr()?.port();
+        let bypass = BypassList::parse("localhost")?;
+        let guard = DestinationGuard::permissive()
+            .with_blocked("127.0.0.0/8".parse()?)
+            .with_blocked("[::1]".parse()?);
+        let refused = |result: Result<()>| {
+            let error = result.unwrap_err();
+            assert!(matches!(error.downcast_ref(), Some(SocksError::DestinationBlocked(_))), "{:?}", error);
+        };
+
+        let proxy = MockSocksServer::socks5();
+        let proxy_addr = proxy.bind().await?;
+        let client = Socks5Client::new(proxy_addr.to_string(), None)
+            .await?
+            .with_bypass(bypass.clone())
+            .with_destination_guard(guard.clone());
+        refused(client.connect_routed(format!("localhost:{}", port), None).await.map(drop));
+        refused(client.connect_with_timings(format!("localhost:{}", port), None).await.map(drop));
+
+        let proxy = MockSocksServer::socks6();
+        let proxy_addr = proxy.bind().await?;
+        let client = Socks6Client::new(proxy_addr.to_string(), None)
+            .await?
+            .with_bypass(bypass)
+            .with_destination_guard(guard);
+        refused(client.connect(format!("localhost:{}", port), None, None).await.map(drop));
+        refused(client.connect_routed(format!("localhost:{}", port), None, None).await.map(drop));
+        refused(client.connect_with_timings(format!("localhost:{}", port), None, None).await.map(drop));
+        assert!(proxy.recordings().is_empty());
+
+        Ok(())
+    }
+}
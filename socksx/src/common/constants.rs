@@ -1,12 +1,16 @@
+/// SOCKS protocol version 4 identifier.
+pub const SOCKS_VER_4: u8 = 0x04u8;
 /// SOCKS protocol version 5 identifier.
 pub const SOCKS_VER_5: u8 = 0x05u8;
-/// SOCKS protocol version 6 identifier.
-pub const SOCKS_VER_6: u8 = 0x06u8;
+/// SOCKS protocol version 6 identifier, as used by the default draft revision.
+pub const SOCKS_VER_6: u8 = SOCKS6_DRAFT_11.version;
 
 /// Version identifier for SOCKS authentication.
 pub const SOCKS_AUTH_VER: u8 = 0x01u8;
 /// Code for no authentication required.
 pub const SOCKS_AUTH_NOT_REQUIRED: u8 = 0x00u8;
+/// Code for GSSAPI authentication (RFC 1961).
+pub const SOCKS_AUTH_GSSAPI: u8 = 0x01u8;
 /// Code for username/password authentication.
 pub const SOCKS_AUTH_USERNAME_PASSWORD: u8 = 0x02u8;
 /// Code for no acceptable authentication methods.
@@ -16,14 +20,91 @@ pub const SOCKS_AUTH_SUCCESS: u8 = 0x00u8;
 /// Code for failed authentication.
 pub const SOCKS_AUTH_FAILED: u8 = 0x01u8;
 
+/// Version identifier for GSSAPI sub-negotiation messages (RFC 1961).
+pub const SOCKS_GSSAPI_VER: u8 = 0x01u8;
+/// GSSAPI message type for a token of the security context establishment.
+pub const SOCKS_GSSAPI_AUTHENTICATION: u8 = 0x01u8;
+/// GSSAPI message type for the per-message protection level negotiation.
+pub const SOCKS_GSSAPI_PROTECTION: u8 = 0x02u8;
+/// GSSAPI message type for aborting the sub-negotiation, which carries no token.
+pub const SOCKS_GSSAPI_ABORT: u8 = 0xFFu8;
+/// GSSAPI protection level for no per-message protection, which isn't part of RFC 1961, but is used by
+/// implementations such as Dante for "clear" traffic.
+pub const SOCKS_GSSAPI_PROTECTION_NONE: u8 = 0x00u8;
+
 /// Option kind for stack in SOCKS protocol.
-pub const SOCKS_OKIND_STACK: u16 = 0x01u16;
+pub const SOCKS_OKIND_STACK: u16 = SOCKS6_DRAFT_11.okind_stack;
 /// Option kind for advertising authentication methods.
-pub const SOCKS_OKIND_AUTH_METH_ADV: u16 = 0x02u16;
+pub const SOCKS_OKIND_AUTH_METH_ADV: u16 = SOCKS6_DRAFT_11.okind_auth_meth_adv;
 /// Option kind for selecting authentication methods.
-pub const SOCKS_OKIND_AUTH_METH_SEL: u16 = 0x03u16;
+pub const SOCKS_OKIND_AUTH_METH_SEL: u16 = SOCKS6_DRAFT_11.okind_auth_meth_sel;
 /// Option kind for authentication data.
-pub const SOCKS_OKIND_AUTH_DATA: u16 = 0x04u16;
+pub const SOCKS_OKIND_AUTH_DATA: u16 = SOCKS6_DRAFT_11.okind_auth_data;
+/// Option kind for metadata, which isn't part of the draft.
+pub const SOCKS_OKIND_METADATA: u16 = SOCKS6_DRAFT_11.okind_metadata;
+/// Option kind for packed metadata, a socksx extension that carries several metadata entries in one option, in
+/// every draft revision.
+pub const SOCKS_OKIND_PACKED_METADATA: u16 = 0xFDE9u16;
+/// Option kind for advertising the schemes that compressed metadata can be sent with, a socksx extension, in
+/// every draft revision.
+pub const SOCKS_OKIND_METADATA_COMPRESSION: u16 = 0xFDEAu16;
+/// Option kind for method-specific data that accompanies the authentication method advertisement, a socksx
+/// extension, in every draft revision.
+pub const SOCKS_OKIND_AUTH_METHOD_DATA: u16 = 0xFDEBu16;
+/// Metadata compression scheme for deflate (RFC 1951).
+pub const SOCKS_METADATA_COMPRESSION_DEFLATE: u8 = 0x01u8;
+
+/// Stack option leg for the connection between the client and the proxy.
+pub const SOCKS_STACK_LEG_CLIENT_PROXY: u8 = 0x01u8;
+/// Stack option leg for the connection between the proxy and the destination.
+pub const SOCKS_STACK_LEG_PROXY_REMOTE: u8 = 0x02u8;
+/// Stack option leg for both connections.
+pub const SOCKS_STACK_LEG_BOTH: u8 = 0x03u8;
+/// Stack option level for IP, regardless of the version.
+pub const SOCKS_STACK_LEVEL_IP: u8 = 0x01u8;
+/// Stack option code for the TTL, or hop limit, of the IP level.
+pub const SOCKS_STACK_CODE_TTL: u8 = 0x03u8;
+
+/// The wire values that differ between revisions of the SOCKS6 draft.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Socks6DraftConstants {
+    /// SOCKS protocol version identifier.
+    pub version: u8,
+    /// Option kind for stack.
+    pub okind_stack: u16,
+    /// Option kind for advertising authentication methods.
+    pub okind_auth_meth_adv: u16,
+    /// Option kind for selecting authentication methods.
+    pub okind_auth_meth_sel: u16,
+    /// Option kind for authentication data.
+    pub okind_auth_data: u16,
+    /// Option kind for metadata.
+    pub okind_metadata: u16,
+}
+
+/// Wire values of revision 11 of the SOCKS6 draft.
+pub const SOCKS6_DRAFT_11: Socks6DraftConstants = Socks6DraftConstants {
+    version: 0x06u8,
+    okind_stack: 0x01u16,
+    okind_auth_meth_adv: 0x02u16,
+    okind_auth_meth_sel: 0x03u16,
+    okind_auth_data: 0x04u16,
+    okind_metadata: 0xFDE8u16,
+};
+
+/// Wire values of `Socks6Draft::Draft13`, which renumbers the authentication options to 0x10 through 0x12.
+///
+/// Unlike the values of revision 11, these aren't cited from a section of the draft text, so check them against the
+/// server before relying on this mode. Only the version byte and the option kinds are covered: messages, replies
+/// included, keep the layout of revision 11.
+pub const SOCKS6_DRAFT_13: Socks6DraftConstants = Socks6DraftConstants {
+    version: 0x06u8,
+    okind_stack: 0x01u16,
+    okind_auth_meth_adv: 0x10u16,
+    okind_auth_meth_sel: 0x11u16,
+    okind_auth_data: 0x12u16,
+    okind_metadata: 0xFDE8u16,
+};
 
 /// Command code for no operation.
 pub const SOCKS_CMD_NOOP: u8 = 0x00u8;
@@ -45,6 +126,27 @@ pub const SOCKS_ATYP_IPV4: u8 = 0x01u8;
 pub const SOCKS_ATYP_DOMAINNAME: u8 = 0x03u8;
 /// Address type identifier for IPv6 addresses.
 pub const SOCKS_ATYP_IPV6: u8 = 0x04u8;
+/// Address type identifier for Unix socket paths. This is a socksx extension that no SOCKS version defines,
+/// encoded as a length byte and the path, without a port. Handlers refuse it unless configured otherwise.
+pub const SOCKS_ATYP_UNIX: u8 = 0xF0u8;
 
 /// Reply code for succeeded operation.
 pub const SOCKS_REP_SUCCEEDED: u8 = 0x00u8;
+/// Reply code for a general server failure.
+pub const SOCKS_REP_GENERAL_FAILURE: u8 = 0x01u8;
+/// Reply code for a connection not allowed by the ruleset.
+pub const SOCKS_REP_CONNECTION_NOT_ALLOWED: u8 = 0x02u8;
+/// Reply code for an unreachable network.
+pub const SOCKS_REP_NETWORK_UNREACHABLE: u8 = 0x03u8;
+/// Reply code for an unreachable host.
+pub const SOCKS_REP_HOST_UNREACHABLE: u8 = 0x04u8;
+/// Reply code for a refused connection.
+pub const SOCKS_REP_CONNECTION_REFUSED: u8 = 0x05u8;
+/// Reply code for an expired TTL.
+pub const SOCKS_REP_TTL_EXPIRED: u8 = 0x06u8;
+/// Reply code for an unsupported command.
+pub const SOCKS_REP_COMMAND_NOT_SUPPORTED: u8 = 0x07u8;
+/// Reply code for an unsupported address type.
+pub const SOCKS_REP_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08u8;
+/// Reply code for a connection attempt that timed out.
+pub const SOCKS_REP_CONNECTION_ATTEMPT_TIMEOUT: u8 = 0x09u8;
@@ -1,5 +1,15 @@
+use std::env::{self, VarError};
+use std::fmt;
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::sync::OnceCell;
+
 /// Represents the username and password credentials for SOCKS authentication.
-#[derive(Clone, Debug, PartialEq)]
+///
+/// The password is left out of the `Debug` output, so that it doesn't end up in logs.
+#[derive(Clone, PartialEq)]
 pub struct Credentials {
     /// The username as a byte vector.
     pub username: Vec<u8>,
@@ -24,6 +34,55 @@ impl Credentials {
         Credentials { username, password }
     }
 
+    /// Reads the credentials from the environment variables `<prefix>_USERNAME` and `<prefix>_PASSWORD`, e.g.,
+    /// `PROXY_USERNAME` and `PROXY_PASSWORD` for the prefix `PROXY`.
+    ///
+    /// # Returns
+    ///
+    /// Returns the credentials, or an error naming the variables if either of them isn't set or isn't valid
+    /// unicode. Errors never contain the values.
+    pub fn from_env(prefix: &str) -> Result<Self> {
+        Self::from_vars(prefix, env_var)
+    }
+
+    // Reads the credentials from the variables with the prefix, with the given lookup.
+    fn from_vars(
+        prefix: &str,
+        lookup: VarLookup,
+    ) -> Result<Self> {
+        let username_var = format!("{}_USERNAME", prefix);
+        let password_var = format!("{}_PASSWORD", prefix);
+
+        match (read_var(&username_var, lookup)?, read_var(&password_var, lookup)?) {
+            (Some(username), Some(password)) => Ok(Credentials::new(username, password)),
+            (None, None) => bail!("Neither {} nor {} is set.", username_var, password_var),
+            (Some(_), None) => bail!("{} is set, but {} isn't.", username_var, password_var),
+            (None, Some(_)) => bail!("{} is set, but {} isn't.", password_var, username_var),
+        }
+    }
+
+    /// Reads the password of the user from the keyring of the OS, e.g., the Keychain on macOS, the Credential
+    /// Manager on Windows, or the kernel keyring on Linux.
+    ///
+    /// The keyring may block, e.g., to ask for permission, so async code should read it on the blocking thread
+    /// pool, like `CredentialSource::load` does.
+    ///
+    /// # Parameters
+    ///
+    /// * `service`: The service the password is stored for.
+    /// * `user`: The user the password is stored for, which is the username of the credentials.
+    #[cfg(feature = "keyring")]
+    pub fn from_keyring(
+        service: &str,
+        user: &str,
+    ) -> Result<Self> {
+        // Errors are formatted here, since the secrets of some keyring errors are part of their debug output.
+        let failed = |e: keyring::Error| anyhow!("Failed to read the password of {} for {}: {}", user, service, e);
+        let password = keyring::Entry::new(service, user).and_then(|entry| entry.get_secret()).map_err(failed)?;
+
+        Ok(Credentials::new(user.as_bytes().to_vec(), password))
+    }
+
     /// Converts the `Credentials` into a byte sequence compatible with the SOCKS authentication protocol.
     ///
     /// # Returns
@@ -42,6 +101,165 @@ impl Credentials {
     }
 }
 
+impl fmt::Debug for Credentials {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &String::from_utf8_lossy(&self.username))
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+// Looks up an environment variable, which is `env_var` outside of tests.
+type VarLookup = fn(&str) -> Result<String, VarError>;
+
+fn env_var(name: &str) -> Result<String, VarError> {
+    env::var(name)
+}
+
+// Reads an environment variable, if it is set, leaving its value out of the error if it isn't unicode.
+fn read_var(
+    name: &str,
+    lookup: VarLookup,
+) -> Result<Option<String>> {
+    match lookup(name) {
+        Ok(value) => Ok(Some(value)),
+        Err(VarError::NotPresent) => Ok(None),
+        Err(VarError::NotUnicode(_)) => bail!("{} isn't valid unicode.", name),
+    }
+}
+
+/// Where the credentials of a client come from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CredentialSource {
+    /// Credentials that are known up front.
+    Fixed(Credentials),
+    /// The environment variables with the prefix, see `Credentials::from_env`.
+    Env(String),
+    /// The password of the user in the keyring of the OS, see `Credentials::from_keyring`.
+    #[cfg(feature = "keyring")]
+    Keyring {
+        /// The service the password is stored for.
+        service: String,
+        /// The user the password is stored for.
+        user: String,
+    },
+}
+
+impl CredentialSource {
+    /// Reads the credentials from the source, reading the keyring on the blocking thread pool.
+    pub async fn load(&self) -> Result<Credentials> {
+        self.load_with(env_var).await
+    }
+
+    // Reads the credentials from the source, looking up environment variables with the given lookup.
+    async fn load_with(
+        &self,
+        lookup: VarLookup,
+    ) -> Result<Credentials> {
+        match self {
+            CredentialSource::Fixed(credentials) => Ok(credentials.clone()),
+            CredentialSource::Env(prefix) => Credentials::from_vars(prefix, lookup),
+            #[cfg(feature = "keyring")]
+            CredentialSource::Keyring { service, user } => {
+                let (service, user) = (service.clone(), user.clone());
+                tokio::task::spawn_blocking(move || Credentials::from_keyring(&service, &user)).await?
+            }
+        }
+    }
+}
+
+/// Credentials that are read from their source when they're first needed, e.g., on the first connect of a
+/// client, and kept from then on, so that a client can be built before they are available.
+///
+/// Clones share the credentials once they are read. A source that fails is read again the next time.
+#[derive(Clone, Debug)]
+pub struct DeferredCredentials {
+    source: CredentialSource,
+    read: Arc<OnceCell<Credentials>>,
+    lookup: VarLookup,
+}
+
+impl DeferredCredentials {
+    /// Creates credentials that are read from the source when they're first needed.
+    pub fn new(source: CredentialSource) -> Self {
+        Self {
+            source,
+            read: Arc::new(OnceCell::new()),
+            lookup: env_var,
+        }
+    }
+
+    // Looks up environment variables with the lookup instead of `env::var`, so tests don't have to set them.
+    #[cfg(test)]
+    pub(crate) fn with_lookup(
+        mut self,
+        lookup: VarLookup,
+    ) -> Self {
+        self.lookup = lookup;
+        self
+    }
+
+    /// Returns the credentials, reading them from the source unless that was done already.
+    pub async fn get(&self) -> Result<&Credentials> {
+        self.read.get_or_try_init(|| self.source.load_with(self.lookup)).await
+    }
+}
+
+impl From<Credentials> for DeferredCredentials {
+    fn from(credentials: Credentials) -> Self {
+        Self {
+            read: Arc::new(OnceCell::new_with(Some(credentials.clone()))),
+            source: CredentialSource::Fixed(credentials),
+            lookup: env_var,
+        }
+    }
+}
+
+/// Decides whether a username and password are valid, for the username/password authentication of the handlers,
+/// e.g., by looking them up in a `passwd::PasswordFileAuthenticator`.
+#[async_trait]
+pub trait PasswordVerifier {
+    /// Returns whether the password is the right one for the username.
+    async fn verify(
+        &self,
+        username: &[u8],
+        password: &[u8],
+    ) -> bool;
+}
+
+/// Accepts only these credentials, comparing them in constant time.
+#[async_trait]
+impl PasswordVerifier for Credentials {
+    async fn verify(
+        &self,
+        username: &[u8],
+        password: &[u8],
+    ) -> bool {
+        // Both are compared, so the time taken doesn't tell which of them is wrong.
+        let username_matches = constant_time_eq(&self.username, username);
+        let password_matches = constant_time_eq(&self.password, password);
+
+        username_matches & password_matches
+    }
+}
+
+/// Compares two byte strings in time that only depends on their lengths, not on where they differ.
+pub(crate) fn constant_time_eq(
+    a: &[u8],
+    b: &[u8],
+) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    let difference = a.iter().zip(b).fold(0u8, |difference, (x, y)| difference | (x ^ y));
+    std::hint::black_box(difference) == 0
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -59,4 +277,62 @@ mod tests {
         let socks_bytes = credentials.as_socks_bytes();
         assert_eq!(socks_bytes, vec![8, 117, 115, 101, 114, 110, 97, 109, 101, 8, 112, 97, 115, 115, 119, 111, 114, 100]);
     }
+
+    // Tests reading credentials from the environment, and that partial configurations fail without revealing the
+    // password.
+    #[test]
+    fn test_credentials_from_env() -> Result<()> {
+        let lookup = |name: &str| match name {
+            "PROXY_USERNAME" => Ok("user".to_string()),
+            "PROXY_PASSWORD" | "PARTIAL_PASSWORD" => Ok("secret".to_string()),
+            "INVALID_USERNAME" => Err(VarError::NotUnicode("user\u{fffd}".into())),
+            _ => Err(VarError::NotPresent),
+        };
+        assert_eq!(Credentials::from_vars("PROXY", lookup)?, Credentials::new("user", "secret"));
+
+        let error = Credentials::from_vars("UNSET", lookup).unwrap_err();
+        assert_eq!(error.to_string(), "Neither UNSET_USERNAME nor UNSET_PASSWORD is set.");
+
+        let error = Credentials::from_vars("PARTIAL", lookup).unwrap_err();
+        assert_eq!(error.to_string(), "PARTIAL_PASSWORD is set, but PARTIAL_USERNAME isn't.");
+
+        let error = Credentials::from_vars("INVALID", lookup).unwrap_err();
+        assert_eq!(error.to_string(), "INVALID_USERNAME isn't valid unicode.");
+
+        let debug = format!("{:?}", Credentials::from_vars("PROXY", lookup)?);
+        assert_eq!(debug, r#"Credentials { username: "user", password: "<redacted>" }"#);
+        Ok(())
+    }
+
+    // Tests that deferred credentials are read when they're first needed, again after a failure, and not after
+    // they were read.
+    #[tokio::test]
+    async fn test_deferred_credentials() -> Result<()> {
+        static PASSWORD: std::sync::Mutex<Option<&str>> = std::sync::Mutex::new(None);
+        let lookup = |name: &str| match (name, *PASSWORD.lock().unwrap()) {
+            ("PROXY_USERNAME", Some(_)) => Ok("user".to_string()),
+            ("PROXY_PASSWORD", Some(password)) => Ok(password.to_string()),
+            _ => Err(VarError::NotPresent),
+        };
+
+        let credentials = DeferredCredentials::new(CredentialSource::Env("PROXY".to_string())).with_lookup(lookup);
+        assert!(credentials.get().await.is_err());
+
+        *PASSWORD.lock().unwrap() = Some("secret");
+        let shared = credentials.clone();
+        assert_eq!(credentials.get().await?, &Credentials::new("user", "secret"));
+
+        *PASSWORD.lock().unwrap() = Some("changed");
+        assert_eq!(shared.get().await?, &Credentials::new("user", "secret"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_credentials_verify() {
+        let credentials = Credentials::new("user", "secret");
+        assert!(credentials.verify(b"user", b"secret").await);
+        assert!(!credentials.verify(b"user", b"secreT").await);
+        assert!(!credentials.verify(b"user", b"secret2").await);
+        assert!(!credentials.verify(b"other", b"secret").await);
+    }
 }
@@ -0,0 +1,1367 @@
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{ensure, Result};
+use async_trait::async_trait;
+use futures::stream::{FuturesUnordered, StreamExt};
+use socket2::{SockRef, TcpKeepalive};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::time::{self, Instant};
+
+use crate::guard::DestinationGuard;
+use crate::interface::AsyncStream;
+use crate::{constants::SOCKS_ATYP_UNIX, Address, SocksError};
+use crate::resolver::{Resolver, SystemResolver};
+use crate::stream::SocksStream;
+
+/// Delay between two consecutive connection attempts, as recommended by RFC 8305.
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// How long the handlers wait for an outbound connect by default, including resolution and every attempt.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Policy that determines which address families are used for outbound connects, and in which order.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum AddressFamilyPreference {
+    /// Use the candidates in the order returned by DNS.
+    #[default]
+    DnsOrder,
+    /// Try IPv4 candidates first, then IPv6 candidates.
+    PreferIpv4,
+    /// Try IPv6 candidates first, then IPv4 candidates.
+    PreferIpv6,
+    /// Only use IPv4 candidates.
+    Ipv4Only,
+    /// Only use IPv6 candidates.
+    Ipv6Only,
+}
+
+impl AddressFamilyPreference {
+    /// Filters and reorders the candidates according to the preference.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` containing the candidates, or `SocksError::AddressFamilyNotAvailable` when
+    /// none of the candidates belong to the required address family.
+    pub fn apply(
+        self,
+        mut candidates: Vec<SocketAddr>,
+    ) -> Result<Vec<SocketAddr>> {
+        use AddressFamilyPreference::*;
+
+        match self {
+            DnsOrder => {}
+            // Sorting is stable, so the DNS order is preserved within each family.
+            PreferIpv4 => candidates.sort_by_key(|c| c.is_ipv6()),
+            PreferIpv6 => candidates.sort_by_key(|c| c.is_ipv4()),
+            Ipv4Only => {
+                candidates.retain(|c| c.is_ipv4());
+                ensure!(!candidates.is_empty(), SocksError::AddressFamilyNotAvailable("IPv4"));
+            }
+            Ipv6Only => {
+                candidates.retain(|c| c.is_ipv6());
+                ensure!(!candidates.is_empty(), SocksError::AddressFamilyNotAvailable("IPv6"));
+            }
+        }
+
+        Ok(candidates)
+    }
+}
+
+impl FromStr for AddressFamilyPreference {
+    type Err = anyhow::Error;
+
+    // Parses the kebab-case name of a preference, e.g., `prefer-ipv6`.
+    fn from_str(preference: &str) -> Result<Self> {
+        use AddressFamilyPreference::*;
+
+        match preference {
+            "dns-order" => Ok(DnsOrder),
+            "prefer-ipv4" => Ok(PreferIpv4),
+            "prefer-ipv6" => Ok(PreferIpv6),
+            "ipv4-only" => Ok(Ipv4Only),
+            "ipv6-only" => Ok(Ipv6Only),
+            _ => bail!("Unrecognized address family preference: {}", preference),
+        }
+    }
+}
+
+/// Describes the connection attempt that won the race.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ConnectInfo {
+    /// The address that was connected to.
+    pub addr: SocketAddr,
+    /// The number of connection attempts that were started, including the winning one.
+    pub attempts: usize,
+}
+
+impl ConnectInfo {
+    /// Returns `true` if the winning connection uses IPv6.
+    pub fn is_ipv6(&self) -> bool {
+        self.addr.is_ipv6()
+    }
+}
+
+/// The addresses of both ends of a connection, as far as they are known, e.g., not for in-memory streams.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ConnectionAddrs {
+    /// The address of the remote end.
+    pub peer: Option<SocketAddr>,
+    /// The address of the local end.
+    pub local: Option<SocketAddr>,
+    /// The connection attempt that won the race, if the connection was made by racing attempts, like `Dialer`
+    /// does.
+    pub connect_info: Option<ConnectInfo>,
+}
+
+impl ConnectionAddrs {
+    /// Returns the addresses of a TCP connection.
+    pub fn of(stream: &TcpStream) -> Self {
+        Self {
+            peer: stream.peer_addr().ok(),
+            local: stream.local_addr().ok(),
+            connect_info: None,
+        }
+    }
+}
+
+/// TCP keepalive settings, to notice tunnels whose peer disappeared without closing the connection, e.g.,
+/// behind a NAT that dropped its mapping.
+///
+/// The interval and number of probes are only applied on platforms that allow configuring them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Keepalive {
+    /// How long the connection is idle before the first probe is sent.
+    pub idle: Duration,
+    /// The time between two probes.
+    pub interval: Option<Duration>,
+    /// The number of unanswered probes after which the peer is considered dead.
+    pub retries: Option<u32>,
+}
+
+impl Keepalive {
+    /// Creates keepalive settings that send the first probe after the given idle time.
+    pub fn new(idle: Duration) -> Self {
+        Self {
+            idle,
+            interval: None,
+            retries: None,
+        }
+    }
+
+    /// Sets the time between two probes.
+    pub fn with_interval(
+        mut self,
+        interval: Duration,
+    ) -> Self {
+        self.interval = Some(interval);
+        self
+    }
+
+    /// Sets the number of unanswered probes after which the peer is considered dead.
+    pub fn with_retries(
+        mut self,
+        retries: u32,
+    ) -> Self {
+        self.retries = Some(retries);
+        self
+    }
+
+    /// Enables keepalive on the stream with these settings.
+    pub fn apply(
+        &self,
+        stream: &TcpStream,
+    ) -> Result<()> {
+        #[allow(unused_mut)]
+        let mut keepalive = TcpKeepalive::new().with_time(self.idle);
+
+        #[cfg(any(
+            target_os = "android",
+            target_os = "dragonfly",
+            target_os = "freebsd",
+            target_os = "illumos",
+            target_os = "ios",
+            target_os = "linux",
+            target_os = "macos",
+            target_os = "netbsd",
+            target_os = "windows",
+        ))]
+        {
+            if let Some(interval) = self.interval {
+                keepalive = keepalive.with_interval(interval);
+            }
+            if let Some(retries) = self.retries {
+                keepalive = keepalive.with_retries(retries);
+            }
+        }
+
+        SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+
+        Ok(())
+    }
+}
+
+/// Establishes outbound connections to destinations on behalf of the handlers.
+#[derive(Clone)]
+pub struct Dialer {
+    resolver: Arc<dyn Resolver + Send + Sync>,
+    family_preference: AddressFamilyPreference,
+    keepalive: Option<Keepalive>,
+    guard: DestinationGuard,
+    socket: SocketConfig,
+}
+
+/// The settings of the sockets that outbound connections are made from.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SocketConfig {
+    /// The firewall mark (SO_MARK), see `set_mark`.
+    pub mark: Option<u32>,
+    /// The TTL (IP_TTL) of the packets, or their hop limit (IPV6_UNICAST_HOPS) over IPv6, see `set_ttl`.
+    pub ttl: Option<u8>,
+}
+
+impl SocketConfig {
+    // Returns whether nothing is set, so that sockets can be created by `TcpStream::connect`.
+    fn is_default(&self) -> bool {
+        self.mark.is_none() && self.ttl.is_none()
+    }
+
+    // Applies the settings to a socket for the address.
+    fn apply(
+        &self,
+        socket: &TcpSocket,
+        addr: SocketAddr,
+    ) -> io::Result<()> {
+        if let Some(mark) = self.mark {
+            set_mark(socket, mark)?;
+        }
+        if let Some(ttl) = self.ttl {
+            set_ttl(socket, addr, ttl)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Dialer {
+    fn default() -> Self {
+        Self::new(Arc::new(SystemResolver))
+    }
+}
+
+impl Dialer {
+    /// Creates a new `Dialer` that resolves domain names with the given resolver.
+    pub fn new(resolver: Arc<dyn Resolver + Send + Sync>) -> Self {
+        Self {
+            resolver,
+            family_preference: AddressFamilyPreference::default(),
+            keepalive: None,
+            guard: DestinationGuard::permissive(),
+            socket: SocketConfig::default(),
+        }
+    }
+
+    /// Returns the policy that determines which addresses are used, and in which order.
+    pub fn family_preference(&self) -> AddressFamilyPreference {
+        self.family_preference
+    }
+
+    /// Sets the policy that determines which addresses are used, and in which order.
+    pub fn set_family_preference(
+        &mut self,
+        family_preference: AddressFamilyPreference,
+    ) {
+        self.family_preference = family_preference;
+    }
+
+    /// Sets the resolver used for domain names.
+    pub fn set_resolver(
+        &mut self,
+        resolver: Arc<dyn Resolver + Send + Sync>,
+    ) {
+        self.resolver = resolver;
+    }
+
+    /// Sets the keepalive settings applied to every established connection.
+    pub fn set_keepalive(
+        &mut self,
+        keepalive: Option<Keepalive>,
+    ) {
+        self.keepalive = keepalive;
+    }
+
+    /// Sets the guard that decides which destinations may be connected to. It checks every resolution of a
+    /// domain name right before the resolved addresses are connected to, so a name can't resolve to a blocked
+    /// address the second time around. Permissive by default.
+    pub fn set_guard(
+        &mut self,
+        guard: DestinationGuard,
+    ) {
+        self.guard = guard;
+    }
+
+    /// Sets the firewall mark (SO_MARK) of the sockets connections are made from, see `set_mark`.
+    pub fn set_mark(
+        &mut self,
+        mark: Option<u32>,
+    ) {
+        self.socket.mark = mark;
+    }
+
+    /// Sets the TTL, or hop limit over IPv6, of the packets of the connections, see `set_ttl`. The system default
+    /// is used without one, or with a zero TTL, which the OS would reject.
+    pub fn set_ttl(
+        &mut self,
+        ttl: Option<u8>,
+    ) {
+        self.socket.ttl = ttl.filter(|&ttl| ttl > 0);
+    }
+
+    /// Returns the settings of the sockets connections are made from.
+    pub fn socket_config(&self) -> SocketConfig {
+        self.socket
+    }
+
+    /// Resolves an `Address` into all of its candidate socket addresses, in DNS order.
+    pub async fn resolve(
+        &self,
+        address: &Address,
+    ) -> Result<Vec<SocketAddr>> {
+        match address {
+            Address::Ip(addr) => Ok(vec![*addr]),
+            Address::Domainname { host, port } => {
+                let addresses = self.resolver.resolve(host).await?;
+                Ok(addresses.into_iter().map(|ip| SocketAddr::new(ip, *port)).collect())
+            }
+            Address::Unix(_) => Err(SocksError::UnsupportedAddressType(SOCKS_ATYP_UNIX).into()),
+        }
+    }
+
+    /// Resolves a domain name into the address a connect would try first, e.g., to hand on a resolved destination
+    /// instead of the name. The candidates are checked by the guard first. Other addresses are returned as is.
+    pub async fn resolve_preferred(
+        &self,
+        address: &Address,
+    ) -> Result<Address> {
+        if !matches!(address, Address::Domainname { .. }) {
+            return Ok(address.clone());
+        }
+
+        let candidates = self.resolve(address).await?;
+        let resolved: Vec<_> = candidates.iter().map(SocketAddr::ip).collect();
+        self.guard.check_resolved(address, &resolved)?;
+        let preferred = interleave(self.family_preference.apply(candidates)?).into_iter().next();
+        let preferred = preferred.ok_or_else(|| anyhow!("{} didn't resolve to an address", address))?;
+
+        Ok(Address::Ip(preferred))
+    }
+
+    /// Resolves the address and connects to it using `connect_happy_eyeballs`.
+    ///
+    /// The family preference determines which candidates are used, and which family leads the race. The
+    /// candidates are checked by the guard first.
+    pub async fn connect(
+        &self,
+        address: &Address,
+    ) -> Result<(TcpStream, ConnectInfo)> {
+        self.guard.check_resolved(address, &[])?;
+        let candidates = self.resolve(address).await?;
+        let resolved: Vec<_> = candidates.iter().map(SocketAddr::ip).collect();
+        self.guard.check_resolved(address, &resolved)?;
+        let candidates = interleave(self.family_preference.apply(candidates)?);
+        let (stream, info) = race(candidates, CONNECTION_ATTEMPT_DELAY, self.socket).await?;
+
+        if let Some(keepalive) = &self.keepalive {
+            keepalive.apply(&stream)?;
+        }
+
+        Ok((stream, info))
+    }
+}
+
+/// Establishes the outbound connections of a handler, to destinations and to the next proxy in a chain.
+///
+/// The handlers use a `Dialer` by default. A custom connector can, e.g., tunnel connections
+/// through another transport, or hand out in-memory streams in tests.
+#[async_trait]
+pub trait Connector {
+    /// Connects to the given address.
+    ///
+    /// # Parameters
+    ///
+    /// * `address`: The destination, or the next proxy.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` containing the connected stream or an error.
+    async fn connect(
+        &self,
+        address: &Address,
+    ) -> Result<Box<dyn AsyncStream>>;
+
+    /// Connects to the given address like `connect`, and returns the addresses of the connection as well.
+    ///
+    /// By default, the addresses are unknown. Connectors that know them, like `Dialer`, return them for the
+    /// `TunnelInfo` of the session.
+    async fn connect_with_addrs(
+        &self,
+        address: &Address,
+    ) -> Result<(Box<dyn AsyncStream>, ConnectionAddrs)> {
+        Ok((self.connect(address).await?, ConnectionAddrs::default()))
+    }
+}
+
+#[async_trait]
+impl Connector for Dialer {
+    async fn connect(
+        &self,
+        address: &Address,
+    ) -> Result<Box<dyn AsyncStream>> {
+        let (stream, _) = self.connect_with_addrs(address).await?;
+        Ok(stream)
+    }
+
+    async fn connect_with_addrs(
+        &self,
+        address: &Address,
+    ) -> Result<(Box<dyn AsyncStream>, ConnectionAddrs)> {
+        if let Address::Unix(path) = address {
+            self.guard.check_resolved(address, &[])?;
+            return Ok((Box::new(connect_unix(path).await?), ConnectionAddrs::default()));
+        }
+
+        let (stream, info) = Dialer::connect(self, address).await?;
+        debug!("Connected to {} after {} attempt(s).", info.addr, info.attempts);
+        let addrs = ConnectionAddrs {
+            connect_info: Some(info),
+            ..ConnectionAddrs::of(&stream)
+        };
+
+        Ok((Box::new(stream), addrs))
+    }
+}
+
+/// Connects to the Unix socket at the path.
+#[cfg(unix)]
+async fn connect_unix(path: &Path) -> Result<SocksStream> {
+    let stream = tokio::net::UnixStream::connect(path).await?;
+    debug!("Connected to Unix socket {}.", path.display());
+
+    Ok(SocksStream::Unix(stream))
+}
+
+/// Fails, as Unix sockets are only supported on Unix platforms.
+#[cfg(not(unix))]
+async fn connect_unix(_path: &Path) -> Result<SocksStream> {
+    Err(SocksError::UnsupportedAddressType(SOCKS_ATYP_UNIX).into())
+}
+
+/// Connects to the address with the connector, giving up once the timeout has passed.
+///
+/// The timeout covers the connect as a whole: for a `Dialer`, the resolution and every attempt combined.
+/// Expiry fails with `SocksError::ConnectTimeout`.
+pub(crate) async fn connect_within(
+    connector: &(dyn Connector + Send + Sync),
+    address: &Address,
+    timeout: Duration,
+) -> Result<(Box<dyn AsyncStream>, ConnectionAddrs)> {
+    match time::timeout(timeout, connector.connect_with_addrs(address)).await {
+        Ok(connected) => connected,
+        Err(_) => Err(SocksError::ConnectTimeout(timeout).into()),
+    }
+}
+
+/// Interleaves the candidates by address family, starting with the family of the first candidate.
+///
+/// The relative order of candidates within a family is preserved (RFC 8305, section 4).
+pub fn interleave(candidates: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let leading_ipv6 = match candidates.first() {
+        Some(first) => first.is_ipv6(),
+        None => return candidates,
+    };
+
+    let (mut leading, mut trailing): (Vec<_>, Vec<_>) =
+        candidates.into_iter().partition(|c| c.is_ipv6() == leading_ipv6);
+    let mut leading = leading.drain(..);
+    let mut trailing = trailing.drain(..);
+
+    let mut interleaved = vec![];
+    loop {
+        match (leading.next(), trailing.next()) {
+            (None, None) => break,
+            (a, b) => interleaved.extend(a.into_iter().chain(b)),
+        }
+    }
+
+    interleaved
+}
+
+/// Policy that determines when a client resolves the proxy's hostname again.
+///
+/// The default resolves the hostname once, when the client is created.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ReResolution {
+    /// Resolve again when the addresses are older than this interval.
+    pub interval: Option<Duration>,
+    /// Resolve again after this many consecutive failures to connect to the proxy.
+    pub after_failures: Option<u32>,
+}
+
+// The addresses of a proxy, and the bookkeeping needed to decide when to resolve them again.
+struct EndpointState {
+    addrs: Vec<SocketAddr>,
+    resolved_at: Instant,
+    failures: u32,
+    current: Option<SocketAddr>,
+    unhealthy: Vec<SocketAddr>,
+}
+
+// How a proxy is reached: over TCP, at the addresses its hostname resolves to, or at a Unix socket.
+#[derive(Clone, Debug, PartialEq)]
+enum EndpointKind {
+    Tcp,
+    Unix(PathBuf),
+}
+
+/// The address of a proxy as given by the user, together with its resolved addresses.
+///
+/// An address of the form `unix:/path/to/socket` is of a proxy that listens on a Unix socket, which has no
+/// addresses to resolve.
+///
+/// Clones share the resolved addresses, so a re-resolution is visible to all of them.
+#[derive(Clone)]
+pub(crate) struct ProxyEndpoint {
+    host: String,
+    kind: EndpointKind,
+    resolver: Arc<dyn Resolver + Send + Sync>,
+    re_resolution: ReResolution,
+    attempt_delay: Duration,
+    mark: Option<u32>,
+    state: Arc<Mutex<EndpointState>>,
+}
+
+impl ProxyEndpoint {
+    /// Resolves the proxy address, given as `host:port`, using the system resolver.
+    pub(crate) async fn resolve<S: Into<String>>(host: S) -> Result<Self> {
+        Self::resolve_with(host, Arc::new(SystemResolver)).await
+    }
+
+    /// Resolves the proxy address, given as `host:port`, using the given resolver.
+    pub(crate) async fn resolve_with<S: Into<String>>(
+        host: S,
+        resolver: Arc<dyn Resolver + Send + Sync>,
+    ) -> Result<Self> {
+        let host = host.into();
+        let (kind, addrs) = match host.strip_prefix("unix:") {
+            Some(path) => (EndpointKind::Unix(PathBuf::from(path)), vec![]),
+            None => (EndpointKind::Tcp, lookup(&host, resolver.as_ref()).await?),
+        };
+
+        Ok(Self {
+            host,
+            kind,
+            resolver,
+            re_resolution: ReResolution::default(),
+            attempt_delay: CONNECTION_ATTEMPT_DELAY,
+            mark: None,
+            state: Arc::new(Mutex::new(EndpointState {
+                addrs,
+                resolved_at: Instant::now(),
+                failures: 0,
+                current: None,
+                unhealthy: vec![],
+            })),
+        })
+    }
+
+    /// Creates an endpoint without addresses, for clients that are only given streams to the proxy.
+    pub(crate) fn unresolved() -> Self {
+        Self {
+            host: String::new(),
+            kind: EndpointKind::Tcp,
+            resolver: Arc::new(SystemResolver),
+            re_resolution: ReResolution::default(),
+            attempt_delay: CONNECTION_ATTEMPT_DELAY,
+            mark: None,
+            state: Arc::new(Mutex::new(EndpointState {
+                addrs: vec![],
+                resolved_at: Instant::now(),
+                failures: 0,
+                current: None,
+                unhealthy: vec![],
+            })),
+        }
+    }
+
+    /// Returns the hostname of the proxy, without the port, e.g., to verify its TLS certificate for.
+    #[cfg_attr(not(feature = "tls"), allow(dead_code))]
+    pub(crate) fn hostname(&self) -> &str {
+        self.host.rsplit_once(':').map_or(self.host.as_str(), |(host, _)| host)
+    }
+
+    /// Sets the policy that determines when the hostname is resolved again.
+    pub(crate) fn set_re_resolution(
+        &mut self,
+        re_resolution: ReResolution,
+    ) {
+        self.re_resolution = re_resolution;
+    }
+
+    /// Sets how long an attempt to connect to one address has a head start before the next address, of the
+    /// other family if there is one, is tried as well.
+    pub(crate) fn set_attempt_delay(
+        &mut self,
+        delay: Duration,
+    ) {
+        self.attempt_delay = delay;
+    }
+
+    /// Sets the firewall mark (SO_MARK) of the sockets the proxy is connected from, see `set_mark`.
+    pub(crate) fn set_mark(
+        &mut self,
+        mark: Option<u32>,
+    ) {
+        self.mark = mark;
+    }
+
+    /// Returns the firewall mark of the sockets the proxy is connected from, if any.
+    pub(crate) fn mark(&self) -> Option<u32> {
+        self.mark
+    }
+
+    // Returns the settings of the sockets the proxy is connected from.
+    fn socket_config(&self) -> SocketConfig {
+        SocketConfig {
+            mark: self.mark,
+            ttl: None,
+        }
+    }
+
+    /// Returns the path of the Unix socket the proxy listens on, if it does.
+    pub(crate) fn unix_path(&self) -> Option<&Path> {
+        match &self.kind {
+            EndpointKind::Unix(path) => Some(path),
+            EndpointKind::Tcp => None,
+        }
+    }
+
+    /// Returns the currently known addresses of the proxy.
+    pub(crate) fn addrs(&self) -> Vec<SocketAddr> {
+        self.state.lock().unwrap().addrs.clone()
+    }
+
+    /// Returns the address used for the most recent successful connection, if any.
+    pub(crate) fn current_addr(&self) -> Option<SocketAddr> {
+        self.state.lock().unwrap().current
+    }
+
+    /// Records whether the address passed its last health check. Addresses that didn't are skipped by `connect`,
+    /// unless no other address is left.
+    pub(crate) fn set_healthy(
+        &self,
+        addr: SocketAddr,
+        healthy: bool,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        state.unhealthy.retain(|unhealthy| *unhealthy != addr);
+        if !healthy {
+            state.unhealthy.push(addr);
+        }
+    }
+
+    /// Connects to the given address of the proxy, e.g., to check its health.
+    pub(crate) async fn connect_to(
+        &self,
+        addr: SocketAddr,
+    ) -> Result<TcpStream> {
+        Ok(connect_with(addr, self.socket_config()).await?)
+    }
+
+    /// Connects to the proxy like `connect_stream`, resolving its hostname again first if the policy says so.
+    pub(crate) async fn connect(
+        &self,
+        preference: AddressFamilyPreference,
+    ) -> Result<(SocksStream, Option<ConnectInfo>)> {
+        self.refresh_if_due().await;
+        self.connect_stream(preference).await
+    }
+
+    /// Connects to the proxy over TCP, with the addresses it has, without resolving its hostname again.
+    ///
+    /// The addresses are raced like `connect_candidates` does, with the endpoint's attempt delay. Addresses that
+    /// failed their last health check are skipped, as long as others are left.
+    pub(crate) async fn connect_resolved(
+        &self,
+        preference: AddressFamilyPreference,
+    ) -> Result<(TcpStream, ConnectInfo)> {
+        ensure!(!self.host.is_empty(), "No proxy address is known, streams to the proxy must be given.");
+        let connected = match preference.apply(self.addrs()) {
+            Ok(candidates) => {
+                let candidates = interleave(self.skip_unhealthy(candidates));
+                race(candidates, self.attempt_delay, self.socket_config()).await
+            }
+            Err(e) => Err(e),
+        };
+        match connected {
+            Ok((stream, info)) => {
+                debug!("Connected to proxy {} at {} after {} attempt(s).", self.host, info.addr, info.attempts);
+                let mut state = self.state.lock().unwrap();
+                state.failures = 0;
+                state.current = Some(info.addr);
+                state.unhealthy.retain(|unhealthy| *unhealthy != info.addr);
+
+                Ok((stream, info))
+            }
+            Err(e) => {
+                self.state.lock().unwrap().failures += 1;
+                Err(e)
+            }
+        }
+    }
+
+    /// Connects to the proxy like `connect_resolved`, or to its Unix socket if it listens on one, in which case
+    /// there's no `ConnectInfo` to return.
+    pub(crate) async fn connect_stream(
+        &self,
+        preference: AddressFamilyPreference,
+    ) -> Result<(SocksStream, Option<ConnectInfo>)> {
+        if let EndpointKind::Unix(path) = &self.kind {
+            return Ok((connect_unix(path).await?, None));
+        }
+
+        let (stream, info) = self.connect_resolved(preference).await?;
+        Ok((SocksStream::Tcp(stream), Some(info)))
+    }
+
+    // Leaves out the candidates that failed their last health check, unless that leaves none.
+    fn skip_unhealthy(
+        &self,
+        candidates: Vec<SocketAddr>,
+    ) -> Vec<SocketAddr> {
+        let state = self.state.lock().unwrap();
+        let healthy: Vec<_> = candidates.iter().copied().filter(|c| !state.unhealthy.contains(c)).collect();
+
+        if healthy.is_empty() { candidates } else { healthy }
+    }
+
+    /// Resolves the hostname again if the policy says so, returning how long that took if it did.
+    pub(crate) async fn refresh_if_due(&self) -> Option<Duration> {
+        if !self.is_due() {
+            return None;
+        }
+
+        let started = Instant::now();
+        self.refresh().await;
+        Some(started.elapsed())
+    }
+
+    // Determines whether the hostname should be resolved again.
+    fn is_due(&self) -> bool {
+        if self.kind != EndpointKind::Tcp {
+            return false;
+        }
+
+        let state = self.state.lock().unwrap();
+
+        let expired = self.re_resolution.interval.is_some_and(|i| state.resolved_at.elapsed() >= i);
+        let failing = self.re_resolution.after_failures.is_some_and(|n| state.failures >= n);
+
+        expired || failing
+    }
+
+    // Resolves the hostname again, keeping the previous addresses if that fails.
+    async fn refresh(&self) {
+        match lookup(&self.host, self.resolver.as_ref()).await {
+            Ok(addrs) => {
+                let mut state = self.state.lock().unwrap();
+                if state.addrs != addrs {
+                    info!("Proxy {} moved from {:?} to {:?}.", self.host, state.addrs, addrs);
+                }
+
+                state.addrs = addrs;
+                state.resolved_at = Instant::now();
+                state.failures = 0;
+            }
+            Err(e) => warn!("Failed to resolve proxy {} again: {}", self.host, e),
+        }
+    }
+}
+
+// Resolves a `host:port` string, accepting IP literals as-is.
+async fn lookup(
+    host: &str,
+    resolver: &(dyn Resolver + Send + Sync),
+) -> Result<Vec<SocketAddr>> {
+    // IP addresses, with the zone ID of a link-local IPv6 address, aren't looked up.
+    if let Ok(Address::Ip(addr)) = host.parse() {
+        return Ok(vec![addr]);
+    }
+
+    let (name, port) = match host.rsplit_once(':') {
+        Some((name, port)) => (name, port.parse()?),
+        None => bail!("Address doesn't seperate host and port by ':'."),
+    };
+
+    let addresses = resolver.resolve(name).await?;
+    Ok(addresses.into_iter().map(|ip| SocketAddr::new(ip, port)).collect())
+}
+
+/// Connects to the first candidate that accepts, racing the attempts as described by RFC 8305.
+///
+/// Candidates are tried in the given order. A new attempt is started once `delay` passed since the
+/// previous one started without a successful connection, or as soon as an attempt fails. The first
+/// attempt to succeed wins, and all attempts that are still in progress are cancelled.
+///
+/// # Parameters
+///
+/// * `candidates`: The addresses to connect to, in order of preference.
+/// * `delay`: The delay before starting the next attempt.
+///
+/// # Returns
+///
+/// Returns a `Result` containing the connected `TcpStream` and details about the winning attempt,
+/// or the error of the last failed attempt.
+pub async fn connect_happy_eyeballs(
+    candidates: Vec<SocketAddr>,
+    delay: Duration,
+) -> Result<(TcpStream, ConnectInfo)> {
+    race(candidates, delay, SocketConfig::default()).await
+}
+
+// Races the candidates like `connect_happy_eyeballs`, from sockets with the settings.
+async fn race(
+    candidates: Vec<SocketAddr>,
+    delay: Duration,
+    socket: SocketConfig,
+) -> Result<(TcpStream, ConnectInfo)> {
+    let mut candidates = candidates.into_iter().peekable();
+    let mut in_flight = FuturesUnordered::new();
+    let mut attempts = 0;
+    let mut last_error = None;
+    let mut next_attempt = Instant::now();
+
+    loop {
+        // Start the next attempt once it is due, which is right away after a failure (RFC 8305, section 5).
+        if Instant::now() >= next_attempt {
+            if let Some(addr) = candidates.next() {
+                attempts += 1;
+                in_flight.push(connect_one(addr, socket));
+                next_attempt = Instant::now() + delay;
+            }
+        }
+        if in_flight.is_empty() {
+            break;
+        }
+
+        let pending = candidates.peek().is_some();
+        tokio::select! {
+            Some((addr, result)) = in_flight.next() => match result {
+                Ok(stream) => return Ok((stream, ConnectInfo { addr, attempts })),
+                Err(e) => {
+                    debug!("Connection attempt to {} failed: {}", addr, e);
+                    last_error = Some(e);
+                    next_attempt = Instant::now();
+                }
+            },
+            _ = time::sleep_until(next_attempt), if pending => {}
+        }
+    }
+
+    match last_error {
+        Some(e) => Err(e.into()),
+        None => bail!("No candidate addresses to connect to."),
+    }
+}
+
+/// Connects to already resolved candidates using `connect_happy_eyeballs`.
+///
+/// The preference determines which candidates are used, and which family leads the race.
+pub async fn connect_candidates(
+    candidates: Vec<SocketAddr>,
+    preference: AddressFamilyPreference,
+) -> Result<(TcpStream, ConnectInfo)> {
+    let candidates = interleave(preference.apply(candidates)?);
+
+    connect_happy_eyeballs(candidates, CONNECTION_ATTEMPT_DELAY).await
+}
+
+/// Sets the firewall mark (SO_MARK) of the socket, which packet filter rules can match on, e.g., to exempt the
+/// proxy's own connections from the rules that redirect connections to it.
+///
+/// # Returns
+///
+/// An error if the mark can't be set: it requires the CAP_NET_ADMIN capability, and is only supported on Linux.
+pub fn set_mark(
+    socket: &TcpSocket,
+    mark: u32,
+) -> io::Result<()> {
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    {
+        SockRef::from(socket).set_mark(mark).map_err(|e| match e.kind() {
+            io::ErrorKind::PermissionDenied => io::Error::new(
+                e.kind(),
+                format!("Failed to set socket mark {} ({}), the process needs the CAP_NET_ADMIN capability", mark, e),
+            ),
+            _ => e,
+        })
+    }
+
+    #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+    {
+        let _ = (socket, mark);
+        Err(io::Error::new(io::ErrorKind::Unsupported, "Socket marks are only supported on Linux"))
+    }
+}
+
+/// Sets the TTL of the packets of the socket, or their hop limit if the address is an IPv6 address. A zero TTL
+/// isn't valid, and fails.
+pub fn set_ttl(
+    socket: &TcpSocket,
+    addr: SocketAddr,
+    ttl: u8,
+) -> io::Result<()> {
+    match addr {
+        SocketAddr::V4(_) => SockRef::from(socket).set_ttl_v4(ttl.into()),
+        SocketAddr::V6(_) => SockRef::from(socket).set_unicast_hops_v6(ttl.into()),
+    }
+}
+
+// Single connection attempt that remembers which address it was for.
+async fn connect_one(
+    addr: SocketAddr,
+    socket: SocketConfig,
+) -> (SocketAddr, io::Result<TcpStream>) {
+    (addr, connect_with(addr, socket).await)
+}
+
+// Connects to the address from a socket with the settings.
+async fn connect_with(
+    addr: SocketAddr,
+    config: SocketConfig,
+) -> io::Result<TcpStream> {
+    if config.is_default() {
+        return TcpStream::connect(addr).await;
+    }
+
+    let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+    config.apply(&socket, addr)?;
+    socket.connect(addr).await
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    // Tests that families are interleaved, starting with the family of the first candidate.
+    #[test]
+    fn test_interleave() {
+        let candidates: Vec<SocketAddr> = vec![
+            "[::1]:1".parse().unwrap(),
+            "[::2]:1".parse().unwrap(),
+            "[::3]:1".parse().unwrap(),
+            "127.0.0.1:1".parse().unwrap(),
+        ];
+
+        let interleaved: Vec<String> = interleave(candidates).iter().map(|c| c.to_string()).collect();
+        assert_eq!(interleaved, vec!["[::1]:1", "127.0.0.1:1", "[::2]:1", "[::3]:1"]);
+    }
+
+    fn candidates() -> Vec<SocketAddr> {
+        vec![
+            "[::1]:1".parse().unwrap(),
+            "127.0.0.1:1".parse().unwrap(),
+            "[::2]:1".parse().unwrap(),
+            "127.0.0.2:1".parse().unwrap(),
+        ]
+    }
+
+    // Tests that the preferred family is moved to the front, preserving the DNS order.
+    #[test]
+    fn test_preference_reorders() -> Result<()> {
+        let ordered: Vec<String> = AddressFamilyPreference::PreferIpv4
+            .apply(candidates())?
+            .iter()
+            .map(|c| c.to_string())
+            .collect();
+        assert_eq!(ordered, vec!["127.0.0.1:1", "127.0.0.2:1", "[::1]:1", "[::2]:1"]);
+
+        let ordered = AddressFamilyPreference::PreferIpv6.apply(candidates())?;
+        assert!(ordered[0].is_ipv6() && ordered[1].is_ipv6());
+
+        assert_eq!(AddressFamilyPreference::DnsOrder.apply(candidates())?, candidates());
+
+        Ok(())
+    }
+
+    // Tests that the "only" preferences filter, and fail when no candidate remains.
+    #[test]
+    fn test_preference_filters() -> Result<()> {
+        let filtered = AddressFamilyPreference::Ipv6Only.apply(candidates())?;
+        assert!(filtered.iter().all(|c| c.is_ipv6()));
+        assert_eq!(filtered.len(), 2);
+
+        let ipv4: Vec<SocketAddr> = vec!["127.0.0.1:1".parse()?];
+        let error = AddressFamilyPreference::Ipv6Only.apply(ipv4).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<SocksError>(),
+            Some(SocksError::AddressFamilyNotAvailable(_))
+        ));
+
+        Ok(())
+    }
+
+    // Tests parsing preferences from their names.
+    #[test]
+    fn test_preference_from_str() {
+        assert_eq!("ipv4-only".parse::<AddressFamilyPreference>().unwrap(), AddressFamilyPreference::Ipv4Only);
+        assert!("ipv5-only".parse::<AddressFamilyPreference>().is_err());
+    }
+
+    // Tests that the dialer resolves domain names through its resolver.
+    #[tokio::test]
+    async fn test_dialer_uses_resolver() -> Result<()> {
+        struct StubResolver;
+
+        #[async_trait::async_trait]
+        impl Resolver for StubResolver {
+            async fn resolve(
+                &self,
+                _host: &str,
+            ) -> Result<Vec<std::net::IpAddr>> {
+                Ok(vec!["127.0.0.1".parse()?])
+            }
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+
+        let dialer = Dialer::new(Arc::new(StubResolver));
+        let (_, info) = dialer.connect(&Address::new("stub.invalid", port)).await?;
+        assert_eq!(info.addr, listener.local_addr()?);
+
+        Ok(())
+    }
+
+    // Tests that the scope ID of a link-local destination, or of the proxy, ends up in the address connected to.
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_scope_id_kept() -> Result<()> {
+        let loopback = nix::net::if_::if_nametoindex("lo")?;
+        let scoped = |port| SocketAddr::V6(std::net::SocketAddrV6::new("fe80::1".parse().unwrap(), port, 0, loopback));
+
+        let destination = Address::try_new("fe80::1%lo", 80)?;
+        let candidates = Dialer::new(Arc::new(SystemResolver)).resolve(&destination).await?;
+        assert_eq!(candidates, vec![scoped(80)]);
+
+        let proxy = lookup("[fe80::1%lo]:1080", &SystemResolver).await?;
+        assert_eq!(proxy, vec![scoped(1080)]);
+
+        Ok(())
+    }
+
+    // Resolver that answers with the address currently stored in it.
+    struct MovingResolver {
+        target: Mutex<std::net::IpAddr>,
+        lookups: std::sync::atomic::AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl Resolver for MovingResolver {
+        async fn resolve(
+            &self,
+            _host: &str,
+        ) -> Result<Vec<std::net::IpAddr>> {
+            self.lookups.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(vec![*self.target.lock().unwrap()])
+        }
+    }
+
+    // Tests that the proxy is resolved again after consecutive connect failures.
+    #[tokio::test]
+    async fn test_re_resolution_after_failures() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+
+        // Nothing listens on the initial address.
+        let resolver = Arc::new(MovingResolver {
+            target: Mutex::new("127.0.0.2".parse()?),
+            lookups: Default::default(),
+        });
+
+        let mut endpoint = ProxyEndpoint::resolve_with(format!("proxy.invalid:{}", port), resolver.clone()).await?;
+        endpoint.set_re_resolution(ReResolution {
+            interval: None,
+            after_failures: Some(1),
+        });
+
+        // The address moves after the first failure.
+        assert!(endpoint.connect(AddressFamilyPreference::default()).await.is_err());
+        *resolver.target.lock().unwrap() = "127.0.0.1".parse()?;
+
+        endpoint.connect(AddressFamilyPreference::default()).await?;
+        assert_eq!(endpoint.current_addr(), Some(listener.local_addr()?));
+        assert_eq!(resolver.lookups.load(std::sync::atomic::Ordering::SeqCst), 2);
+
+        Ok(())
+    }
+
+    // Tests that addresses that failed their health check are skipped, unless no other address is left.
+    #[tokio::test]
+    async fn test_unhealthy_addresses_skipped() -> Result<()> {
+        struct TwoAddressResolver;
+
+        #[async_trait::async_trait]
+        impl Resolver for TwoAddressResolver {
+            async fn resolve(
+                &self,
+                _host: &str,
+            ) -> Result<Vec<std::net::IpAddr>> {
+                Ok(vec!["127.0.0.1".parse()?, "127.0.0.2".parse()?])
+            }
+        }
+
+        let first = TcpListener::bind("127.0.0.1:0").await?;
+        let port = first.local_addr()?.port();
+        let second = TcpListener::bind(("127.0.0.2", port)).await?;
+
+        let resolver = Arc::new(TwoAddressResolver);
+        let endpoint = ProxyEndpoint::resolve_with(format!("proxy.invalid:{}", port), resolver).await?;
+        let (_, info) = endpoint.connect_resolved(AddressFamilyPreference::default()).await?;
+        assert_eq!(info.addr, first.local_addr()?);
+
+        endpoint.set_healthy(first.local_addr()?, false);
+        let (_, info) = endpoint.connect_resolved(AddressFamilyPreference::default()).await?;
+        assert_eq!(info.addr, second.local_addr()?);
+
+        endpoint.set_healthy(second.local_addr()?, false);
+        let (_, info) = endpoint.connect_resolved(AddressFamilyPreference::default()).await?;
+        assert_eq!(info.addr, first.local_addr()?);
+
+        Ok(())
+    }
+
+    // Tests that the proxy is resolved again once the interval has passed, and not before.
+    #[tokio::test(start_paused = true)]
+    async fn test_re_resolution_interval() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+
+        let resolver = Arc::new(MovingResolver {
+            target: Mutex::new("127.0.0.1".parse()?),
+            lookups: Default::default(),
+        });
+
+        let mut endpoint = ProxyEndpoint::resolve_with(format!("proxy.invalid:{}", port), resolver.clone()).await?;
+        endpoint.set_re_resolution(ReResolution {
+            interval: Some(Duration::from_secs(30)),
+            after_failures: None,
+        });
+
+        endpoint.connect(AddressFamilyPreference::default()).await?;
+        assert_eq!(resolver.lookups.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        time::advance(Duration::from_secs(31)).await;
+        endpoint.connect(AddressFamilyPreference::default()).await?;
+        assert_eq!(resolver.lookups.load(std::sync::atomic::Ordering::SeqCst), 2);
+
+        Ok(())
+    }
+
+    // Tests that a proxy preferring IPv6 falls back to IPv4 when its IPv6 address can't be reached.
+    #[tokio::test]
+    async fn test_proxy_prefers_ipv6_with_fallback() -> Result<()> {
+        struct DualStackResolver;
+
+        #[async_trait::async_trait]
+        impl Resolver for DualStackResolver {
+            async fn resolve(
+                &self,
+                _host: &str,
+            ) -> Result<Vec<std::net::IpAddr>> {
+                // The IPv6 address is reserved for documentation, and never answers.
+                Ok(vec!["127.0.0.1".parse()?, "2001:db8::1".parse()?])
+            }
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+
+        let resolver = Arc::new(DualStackResolver);
+        let mut endpoint = ProxyEndpoint::resolve_with(format!("proxy.invalid:{}", port), resolver).await?;
+        endpoint.set_attempt_delay(Duration::from_millis(50));
+
+        let started = Instant::now();
+        let (_, info) = endpoint.connect_resolved(AddressFamilyPreference::PreferIpv6).await?;
+        assert_eq!(info.addr, listener.local_addr()?);
+        assert_eq!(info.attempts, 2);
+        assert_eq!(endpoint.current_addr(), Some(listener.local_addr()?));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        Ok(())
+    }
+
+    // Tests that the dialer connects from sockets with its mark, or fails pointing at the missing capability.
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_dialer_mark() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let mut dialer = Dialer::default();
+        dialer.set_mark(Some(42));
+
+        match dialer.connect(&Address::Ip(listener.local_addr()?)).await {
+            Ok((stream, _)) => assert_eq!(SockRef::from(&stream).mark()?, 42),
+            Err(e) => assert!(format!("{:#}", e).contains("CAP_NET_ADMIN"), "{:#}", e),
+        }
+
+        Ok(())
+    }
+
+    // Tests that the dialer connects from sockets with its TTL, over IPv4, or its hop limit, over IPv6.
+    #[tokio::test]
+    async fn test_dialer_ttl() -> Result<()> {
+        let mut dialer = Dialer::default();
+        dialer.set_ttl(Some(17));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let (stream, _) = dialer.connect(&Address::Ip(listener.local_addr()?)).await?;
+        assert_eq!(SockRef::from(&stream).ttl_v4()?, 17);
+
+        // A zero TTL leaves the system default, rather than failing every connect.
+        let mut default = Dialer::default();
+        default.set_ttl(Some(0));
+        assert_eq!(default.socket_config(), SocketConfig::default());
+        default.connect(&Address::Ip(listener.local_addr()?)).await?;
+
+        // Not every host has IPv6 loopback.
+        if let Ok(listener) = TcpListener::bind("[::1]:0").await {
+            let (stream, _) = dialer.connect(&Address::Ip(listener.local_addr()?)).await?;
+            assert_eq!(SockRef::from(&stream).unicast_hops_v6()?, 17);
+        }
+
+        Ok(())
+    }
+
+    // Tests that a single candidate connects on the first attempt.
+    #[tokio::test]
+    async fn test_connect_single_candidate() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let (_, info) = connect_happy_eyeballs(vec![addr], CONNECTION_ATTEMPT_DELAY).await?;
+        assert_eq!(info, ConnectInfo { addr, attempts: 1 });
+
+        Ok(())
+    }
+
+    // Tests that a refused candidate falls through to the next one without waiting for the delay.
+    #[tokio::test]
+    async fn test_connect_falls_back_on_failure() -> Result<()> {
+        let closed = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let (_, info) = connect_happy_eyeballs(vec![closed, addr], Duration::from_secs(60)).await?;
+        assert_eq!(info, ConnectInfo { addr, attempts: 2 });
+
+        Ok(())
+    }
+
+    // Tests that a stalled candidate is raced by the next one after the delay.
+    #[tokio::test]
+    async fn test_connect_races_stalled_candidate() -> Result<()> {
+        // TEST-NET-1 is not routable, so the attempt stalls.
+        let stalled: SocketAddr = "192.0.2.1:9".parse()?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let (_, info) = connect_happy_eyeballs(vec![stalled, addr], Duration::from_millis(50)).await?;
+        assert_eq!(info.addr, addr);
+        assert_eq!(info.attempts, 2);
+
+        Ok(())
+    }
+
+    // Tests that a failure starts the next attempt right away while an earlier attempt is still in progress.
+    #[tokio::test]
+    async fn test_connect_failure_skips_delay() -> Result<()> {
+        let stalled: SocketAddr = "192.0.2.1:9".parse()?;
+        let closed = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let started = Instant::now();
+        let delay = Duration::from_secs(1);
+        let (_, info) = connect_happy_eyeballs(vec![stalled, closed, addr], delay).await?;
+        assert_eq!(info.addr, addr);
+        assert_eq!(info.attempts, 3);
+        assert!(started.elapsed() < delay * 2, "{:?}", started.elapsed());
+
+        Ok(())
+    }
+
+    // Tests that the error of the last attempt is returned when all candidates fail.
+    #[tokio::test]
+    async fn test_connect_all_fail() -> Result<()> {
+        let closed = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+
+        assert!(connect_happy_eyeballs(vec![closed], CONNECTION_ATTEMPT_DELAY).await.is_err());
+        assert!(connect_happy_eyeballs(vec![], CONNECTION_ATTEMPT_DELAY).await.is_err());
+
+        Ok(())
+    }
+
+    // Tests that the dialer connects to Unix socket destinations, which can't be resolved.
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_connect_unix() -> Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let path = std::env::temp_dir().join(format!("socksx-dialer-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path)?;
+
+        let dialer = Dialer::default();
+        let address = Address::unix(&path)?;
+        assert!(dialer.resolve(&address).await.is_err());
+
+        let mut stream = Connector::connect(&dialer, &address).await?;
+        let (mut accepted, _) = listener.accept().await?;
+        stream.write_all(b"ping").await?;
+        let mut received = [0; 4];
+        accepted.read_exact(&mut received).await?;
+        assert_eq!(&received, b"ping");
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    // Tests that keepalive is enabled on a stream with the configured settings.
+    #[tokio::test]
+    async fn test_keepalive_apply() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let stream = TcpStream::connect(listener.local_addr()?).await?;
+        assert!(!SockRef::from(&stream).keepalive()?);
+
+        let keepalive = Keepalive::new(Duration::from_secs(30))
+            .with_interval(Duration::from_secs(5))
+            .with_retries(3);
+        keepalive.apply(&stream)?;
+        assert!(SockRef::from(&stream).keepalive()?);
+
+        #[cfg(target_os = "linux")]
+        {
+            let socket = SockRef::from(&stream);
+            assert_eq!(socket.tcp_keepalive_time()?, Duration::from_secs(30));
+            assert_eq!(socket.tcp_keepalive_interval()?, Duration::from_secs(5));
+            assert_eq!(socket.tcp_keepalive_retries()?, 3);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,371 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::constants::*;
+use crate::dialer::ConnectionAddrs;
+use crate::interface::AsyncStream;
+use crate::policy::Policy;
+use crate::util::Rewound;
+use crate::{Socks5Handler, Socks6Handler, SocksHandler};
+
+type Handler = Arc<dyn SocksHandler + Send + Sync>;
+
+// The handler of a SOCKS version, and whether clients of that version are served.
+#[derive(Clone)]
+struct Slot {
+    handler: Option<Handler>,
+    enabled: bool,
+}
+
+impl Slot {
+    fn new(handler: Option<Handler>) -> Self {
+        Self {
+            enabled: handler.is_some(),
+            handler,
+        }
+    }
+
+    // Returns the handler, if clients of the version are served.
+    fn handler(&self) -> Option<&Handler> {
+        self.handler.as_ref().filter(|_| self.enabled)
+    }
+}
+
+/// Serves SOCKS4, SOCKS5, and SOCKS6 clients on a single port, by handing each connection to the handler of the
+/// SOCKS version its first byte names.
+///
+/// The first byte is replayed to the handler, which reads the request as if it came straight from the client.
+/// Connections whose first byte names no version, or a version that isn't enabled, are closed without a reply,
+/// and counted, see `unrecognized`.
+///
+/// SOCKS5 and SOCKS6 are served by default handlers unless configured otherwise. The crate has no SOCKS4
+/// handler, so SOCKS4 clients are only served with a handler set by `with_socks4`.
+///
+/// The dispatcher is a `SocksHandler` itself, so it can serve a single connection, or every connection of a
+/// `SocksServer`.
+#[derive(Clone)]
+pub struct ProtocolDispatcher {
+    socks4: Slot,
+    socks5: Slot,
+    socks6: Slot,
+    unrecognized: Arc<AtomicU64>,
+}
+
+impl Default for ProtocolDispatcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProtocolDispatcher {
+    /// Creates a dispatcher that serves SOCKS5 and SOCKS6 clients with default handlers.
+    pub fn new() -> Self {
+        Self {
+            socks4: Slot::new(None),
+            socks5: Slot::new(Some(Arc::new(Socks5Handler::default()))),
+            socks6: Slot::new(Some(Arc::new(Socks6Handler::default()))),
+            unrecognized: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Serves SOCKS4 clients with the handler.
+    pub fn with_socks4(
+        mut self,
+        handler: Arc<dyn SocksHandler + Send + Sync>,
+    ) -> Self {
+        self.socks4 = Slot::new(Some(handler));
+        self
+    }
+
+    /// Serves SOCKS5 clients with the handler, instead of a default `Socks5Handler`.
+    pub fn with_socks5(
+        mut self,
+        handler: Arc<dyn SocksHandler + Send + Sync>,
+    ) -> Self {
+        self.socks5 = Slot::new(Some(handler));
+        self
+    }
+
+    /// Serves SOCKS6 clients with the handler, instead of a default `Socks6Handler`.
+    pub fn with_socks6(
+        mut self,
+        handler: Arc<dyn SocksHandler + Send + Sync>,
+    ) -> Self {
+        self.socks6 = Slot::new(Some(handler));
+        self
+    }
+
+    /// Sets whether clients of the SOCKS version (4, 5, or 6) are served. A version can only be enabled once it
+    /// has a handler.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the dispatcher, or an error if the version is unknown, or has no handler.
+    pub fn with_version_enabled(
+        mut self,
+        version: u8,
+        enabled: bool,
+    ) -> Result<Self> {
+        let slot = self.slot_mut(version).ok_or_else(|| anyhow!("Unsupported SOCKS version: {}", version))?;
+        ensure!(!enabled || slot.handler.is_some(), "No handler for SOCKS{} clients", version);
+        slot.enabled = enabled;
+
+        Ok(self)
+    }
+
+    /// Returns whether clients of the SOCKS version are served.
+    pub fn is_enabled(
+        &self,
+        version: u8,
+    ) -> bool {
+        self.slot(version).and_then(Slot::handler).is_some()
+    }
+
+    /// Returns the number of connections that were closed, as their first byte named no enabled SOCKS version.
+    pub fn unrecognized(&self) -> u64 {
+        self.unrecognized.load(Ordering::Relaxed)
+    }
+
+    fn slot(
+        &self,
+        version: u8,
+    ) -> Option<&Slot> {
+        match version {
+            SOCKS_VER_4 => Some(&self.socks4),
+            SOCKS_VER_5 => Some(&self.socks5),
+            SOCKS_VER_6 => Some(&self.socks6),
+            _ => None,
+        }
+    }
+
+    fn slot_mut(
+        &mut self,
+        version: u8,
+    ) -> Option<&mut Slot> {
+        match version {
+            SOCKS_VER_4 => Some(&mut self.socks4),
+            SOCKS_VER_5 => Some(&mut self.socks5),
+            SOCKS_VER_6 => Some(&mut self.socks6),
+            _ => None,
+        }
+    }
+
+    // Reads the first byte of the source, and returns the handler of the version it names, with the source
+    // rewound to before that byte. Sources of other versions are closed.
+    async fn route<'a>(
+        &self,
+        source: &'a mut dyn AsyncStream,
+    ) -> Result<(Handler, Rewound<&'a mut dyn AsyncStream>)> {
+        let version = source.read_u8().await?;
+        match self.slot(version).and_then(Slot::handler) {
+            Some(handler) => {
+                debug!("Handing a SOCKS{} client over to its handler.", version);
+                Ok((Arc::clone(handler), Rewound::new(vec![version], source)))
+            }
+            None => {
+                self.unrecognized.fetch_add(1, Ordering::Relaxed);
+                source.shutdown().await?;
+                bail!("Unrecognized SOCKS version of a client: {:#04x}", version)
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl SocksHandler for ProtocolDispatcher {
+    async fn accept_request(
+        &self,
+        source: &mut dyn AsyncStream,
+    ) -> Result<()> {
+        let (handler, mut source) = self.route(source).await?;
+        handler.accept_request(&mut source).await
+    }
+
+    async fn accept_request_as(
+        &self,
+        source: &mut dyn AsyncStream,
+        identity: Option<String>,
+    ) -> Result<()> {
+        let (handler, mut source) = self.route(source).await?;
+        handler.accept_request_as(&mut source, identity).await
+    }
+
+    async fn accept_request_from(
+        &self,
+        source: &mut dyn AsyncStream,
+        identity: Option<String>,
+        addrs: ConnectionAddrs,
+    ) -> Result<()> {
+        let (handler, mut source) = self.route(source).await?;
+        handler.accept_request_from(&mut source, identity, addrs).await
+    }
+
+    async fn refuse_request(
+        &self,
+        source: &mut dyn AsyncStream,
+    ) -> Result<()> {
+        let (handler, mut source) = self.route(source).await?;
+        handler.refuse_request(&mut source).await
+    }
+
+    async fn refuse_overloaded(
+        &self,
+        source: &mut dyn AsyncStream,
+    ) -> Result<()> {
+        let (handler, mut source) = self.route(source).await?;
+        handler.refuse_overloaded(&mut source).await
+    }
+
+    async fn setup(
+        &self,
+        source: &mut dyn AsyncStream,
+    ) -> Result<Box<dyn AsyncStream>> {
+        let (handler, mut source) = self.route(source).await?;
+        let mut destination = handler.setup(&mut source).await?;
+        destination.write_all(source.remaining()).await?;
+
+        Ok(destination)
+    }
+
+    // Replaces the policy of every handler, failing if one of them doesn't support policies.
+    fn update_policy(
+        &self,
+        policy: Policy,
+    ) -> Result<()> {
+        for slot in [&self.socks4, &self.socks5, &self.socks6] {
+            if let Some(handler) = slot.handler() {
+                handler.update_policy(policy.clone())?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::{Socks5Client, Socks6Client, SocksServer};
+
+    // Handler that answers a SOCKS4 CONNECT request with a grant, and connects to the destination, without any
+    // of the rest of the protocol.
+    struct Socks4Handler;
+
+    #[async_trait]
+    impl SocksHandler for Socks4Handler {
+        async fn accept_request(
+            &self,
+            source: &mut dyn AsyncStream,
+        ) -> Result<()> {
+            let mut destination = self.setup(source).await?;
+            tokio::io::copy_bidirectional(source, &mut destination).await?;
+            Ok(())
+        }
+
+        async fn refuse_request(
+            &self,
+            _source: &mut dyn AsyncStream,
+        ) -> Result<()> {
+            Ok(())
+        }
+
+        async fn setup(
+            &self,
+            source: &mut dyn AsyncStream,
+        ) -> Result<Box<dyn AsyncStream>> {
+            let mut request = [0; 8];
+            source.read_exact(&mut request).await?;
+            ensure!(request[..2] == [SOCKS_VER_4, SOCKS_CMD_CONNECT], "Not a SOCKS4 CONNECT: {:?}", request);
+            while source.read_u8().await? != 0 {}
+
+            let port = u16::from_be_bytes([request[2], request[3]]);
+            let ip = std::net::Ipv4Addr::new(request[4], request[5], request[6], request[7]);
+            let destination = TcpStream::connect((ip, port)).await?;
+            source.write_all(&[0x00, 0x5a, 0, 0, 0, 0, 0, 0]).await?;
+
+            Ok(Box::new(destination))
+        }
+    }
+
+    // Binds a destination that echoes what it receives.
+    async fn echo() -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = stream.split();
+                    tokio::io::copy(&mut reader, &mut writer).await
+                });
+            }
+        });
+
+        Ok(addr)
+    }
+
+    // Tests that SOCKS4, SOCKS5, and SOCKS6 clients are served on the same port, each by its own handler.
+    #[tokio::test]
+    async fn test_dispatch() -> Result<()> {
+        let destination = echo().await?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = listener.local_addr()?.to_string();
+        let dispatcher = ProtocolDispatcher::new().with_socks4(Arc::new(Socks4Handler));
+        let server = SocksServer::new(listener, Arc::new(dispatcher.clone()));
+        tokio::spawn(async move { server.run().await });
+
+        let mut socks4 = TcpStream::connect(&proxy_addr).await?;
+        let port = destination.port().to_be_bytes();
+        socks4.write_all(&[SOCKS_VER_4, SOCKS_CMD_CONNECT, port[0], port[1], 127, 0, 0, 1, b'u', 0]).await?;
+        let mut reply = [0; 8];
+        socks4.read_exact(&mut reply).await?;
+        assert_eq!(reply[1], 0x5a);
+
+        let socks5 = Socks5Client::new(proxy_addr.clone(), None).await?;
+        let (socks5, _) = socks5.connect(destination.to_string(), None).await?;
+
+        let socks6 = Socks6Client::new(proxy_addr.clone(), None).await?;
+        let (socks6, _) = socks6.connect(destination.to_string(), None, None).await?;
+
+        for (name, mut stream) in [("SOCKS4", socks4.into()), ("SOCKS5", socks5), ("SOCKS6", socks6)] {
+            stream.write_all(name.as_bytes()).await?;
+            let mut echoed = vec![0; name.len()];
+            stream.read_exact(&mut echoed).await?;
+            assert_eq!(echoed, name.as_bytes());
+        }
+        assert_eq!(dispatcher.unrecognized(), 0);
+
+        Ok(())
+    }
+
+    // Tests that clients of an unknown or disabled version are closed and counted, and that versions can only be
+    // enabled with a handler.
+    #[tokio::test]
+    async fn test_unrecognized() -> Result<()> {
+        let dispatcher = ProtocolDispatcher::new().with_version_enabled(SOCKS_VER_6, false)?;
+        assert!(dispatcher.is_enabled(SOCKS_VER_5));
+        assert!(!dispatcher.is_enabled(SOCKS_VER_6));
+        assert!(ProtocolDispatcher::new().with_version_enabled(SOCKS_VER_4, true).is_err());
+        assert!(ProtocolDispatcher::new().with_version_enabled(0x07, false).is_err());
+
+        for first in [b'G', SOCKS_VER_4, SOCKS_VER_6] {
+            let (mut client, mut source) = tokio::io::duplex(1024);
+            client.write_all(&[first, 0x01, 0x00]).await?;
+            assert!(dispatcher.accept_request(&mut source).await.is_err());
+
+            let mut received = vec![];
+            client.read_to_end(&mut received).await?;
+            assert!(received.is_empty());
+        }
+        assert_eq!(dispatcher.unrecognized(), 3);
+
+        Ok(())
+    }
+}
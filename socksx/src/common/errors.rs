@@ -0,0 +1,176 @@
+use std::io::{self, ErrorKind};
+use std::time::Duration;
+
+use thiserror::Error;
+
+use crate::constants::*;
+use crate::socks6::{AuthFailureReason, ChainFailure};
+use crate::udp::UdpRefusal;
+
+/// Errors that can be distinguished by callers of the SOCKS clients and handlers.
+///
+/// Functions in this crate return `anyhow::Result`, so these are usually retrieved with
+/// `anyhow::Error::downcast_ref::<SocksError>()`.
+#[derive(Debug, Error)]
+pub enum SocksError {
+    /// The tunnel was established, but writing the initial data through it failed.
+    #[error("Failed to write initial data through the established tunnel: {0}")]
+    InitialDataWrite(#[source] io::Error),
+    /// None of the resolved addresses belong to the address family required by the policy.
+    #[error("No resolved address matches the required address family ({0}).")]
+    AddressFamilyNotAvailable(&'static str),
+    /// The request carries a command that isn't supported.
+    #[error("Command not supported: {0}")]
+    CommandNotSupported(u8),
+    /// The request carries a command byte that doesn't correspond to any command.
+    #[error("Unknown command: {0}")]
+    UnknownCommand(u8),
+    /// The proxy rejected the client's authentication, or the other way around, with the reason the SOCKS6 proxy
+    /// reported, if any.
+    #[error("Authentication failed{}.", .0.map(|reason| format!(": {}", reason)).unwrap_or_default())]
+    AuthenticationFailed(Option<AuthFailureReason>),
+    /// The proxy doesn't accept any of the authentication methods the client offered.
+    #[error("No acceptable authentication method.")]
+    NoAcceptableAuthMethod,
+    /// The client speaks a different SOCKS version than the handler.
+    #[error("Client uses a different SOCKS version: {0}")]
+    VersionMismatch(u8),
+    /// The operation isn't supported on this platform.
+    #[error("Not supported on this platform: {0}")]
+    Unsupported(&'static str),
+    /// An address carries an address type (ATYP) that isn't supported.
+    #[error("Unsupported address type: {0}")]
+    UnsupportedAddressType(u8),
+    /// An established tunnel broke because the peer stopped responding, e.g., to keepalive probes.
+    #[error("Peer stopped responding: {0}")]
+    PeerDead(#[source] io::Error),
+    /// The peer used a username/password sub-negotiation version byte that the policy doesn't accept.
+    #[error("Unexpected username/password sub-negotiation version: {0:#04x}")]
+    AuthVersionMismatch(u8),
+    /// The outbound connect, including the resolution of the destination, didn't complete in time.
+    #[error("Connect timed out after {0:?}.")]
+    ConnectTimeout(Duration),
+    /// The HTTP proxy demands authentication, or rejected the credentials (407).
+    #[error("HTTP proxy authentication required.")]
+    HttpProxyAuthenticationRequired,
+    /// The HTTP proxy answered the CONNECT request with an unsuccessful status other than 407.
+    #[error("HTTP proxy refused to connect: {0}")]
+    HttpConnectFailed(u16),
+    /// A link further down a chain failed to reach the next link, or the destination, and reported where.
+    #[error("Chain failed: {0}")]
+    ChainFailed(ChainFailure),
+    /// A domain name with non-ASCII characters that doesn't follow the IDNA rules.
+    #[error("Invalid internationalized domain name: {0}")]
+    InvalidDomain(String),
+    /// The policy of the handler doesn't allow the session.
+    #[error("Connection to {0} not allowed by policy.")]
+    ConnectionNotAllowed(String),
+    /// The TLS handshake with the peer failed, e.g., because it rejected the client certificate, as opposed to
+    /// a failure of the SOCKS handshake.
+    #[error("TLS handshake failed: {0}")]
+    TlsHandshakeFailed(String),
+    /// The proxy answered the request with an unsuccessful reply, carrying its reply code.
+    #[error("CONNECT operation failed with reply code {0:#04x}.")]
+    OperationFailed(u8),
+    /// The destination guard blocks the destination, e.g., because it is an internal address.
+    #[error("Destination {0} blocked by the destination guard.")]
+    DestinationBlocked(String),
+    /// The client's SOCKS5 method negotiation is malformed.
+    #[error("Malformed method negotiation: {0}")]
+    MalformedNegotiation(NegotiationError),
+    /// Buffering the handshake would exceed the memory budget of the server, of the given number of bytes.
+    #[error("Handshake exceeds the memory budget of {0} bytes.")]
+    MemoryBudgetExhausted(usize),
+    /// The limits of the UDP relay of the handler don't allow another UDP association.
+    #[error("UDP association refused: {0}.")]
+    UdpAssociationRefused(UdpRefusal),
+}
+
+/// A way in which the method negotiation of a SOCKS5 client can be malformed.
+#[derive(Clone, Copy, Debug, Error, Eq, PartialEq)]
+pub enum NegotiationError {
+    /// The client offered no methods at all (NMETHODS is zero).
+    #[error("no methods offered")]
+    NoMethods,
+    /// The client sent fewer methods than it declared, and stopped sending before the rest arrived.
+    #[error("{received} of {declared} methods received")]
+    TruncatedMethods {
+        /// The number of methods the client declared (NMETHODS).
+        declared: u8,
+        /// The number of methods that arrived.
+        received: usize,
+    },
+    /// The client offered the same method more than once.
+    #[error("method {0:#04x} offered more than once")]
+    DuplicateMethod(u8),
+}
+
+/// A byte that doesn't correspond to any value of the type it was converted into.
+#[derive(Clone, Copy, Debug, Error, Eq, PartialEq)]
+#[error("Unknown {kind}: {value}")]
+pub struct UnknownValue {
+    /// What the byte was supposed to be, e.g., "SOCKS6 command".
+    pub kind: &'static str,
+    /// The byte itself.
+    pub value: u8,
+}
+
+/// Determines the reply code that best describes why an operation failed.
+///
+/// The SOCKS5 and SOCKS6 reply codes share the same values, so this is used by both handlers.
+pub(crate) fn reply_code(error: &anyhow::Error) -> u8 {
+    if let Some(error) = error.downcast_ref::<SocksError>() {
+        return match error {
+            SocksError::AddressFamilyNotAvailable(_) => SOCKS_REP_ADDRESS_TYPE_NOT_SUPPORTED,
+            SocksError::ChainFailed(failure) => failure.reply,
+            SocksError::OperationFailed(reply) => *reply,
+            SocksError::ConnectTimeout(_) => SOCKS_REP_CONNECTION_ATTEMPT_TIMEOUT,
+            SocksError::ConnectionNotAllowed(_)
+            | SocksError::DestinationBlocked(_)
+            | SocksError::UdpAssociationRefused(_) => SOCKS_REP_CONNECTION_NOT_ALLOWED,
+            SocksError::InitialDataWrite(_) | SocksError::PeerDead(_) => SOCKS_REP_GENERAL_FAILURE,
+            SocksError::CommandNotSupported(_) | SocksError::UnknownCommand(_) => SOCKS_REP_COMMAND_NOT_SUPPORTED,
+            SocksError::UnsupportedAddressType(_) => SOCKS_REP_ADDRESS_TYPE_NOT_SUPPORTED,
+            SocksError::VersionMismatch(_)
+            | SocksError::AuthVersionMismatch(_)
+            | SocksError::InvalidDomain(_)
+            | SocksError::HttpProxyAuthenticationRequired
+            | SocksError::HttpConnectFailed(_)
+            | SocksError::AuthenticationFailed(_)
+            | SocksError::NoAcceptableAuthMethod
+            | SocksError::TlsHandshakeFailed(_)
+            | SocksError::MalformedNegotiation(_)
+            | SocksError::MemoryBudgetExhausted(_)
+            | SocksError::Unsupported(_) => SOCKS_REP_GENERAL_FAILURE,
+        };
+    }
+
+    match error.downcast_ref::<io::Error>().map(io::Error::kind) {
+        Some(ErrorKind::ConnectionRefused) => SOCKS_REP_CONNECTION_REFUSED,
+        Some(ErrorKind::HostUnreachable) => SOCKS_REP_HOST_UNREACHABLE,
+        Some(ErrorKind::NetworkUnreachable) => SOCKS_REP_NETWORK_UNREACHABLE,
+        Some(ErrorKind::TimedOut) => SOCKS_REP_CONNECTION_ATTEMPT_TIMEOUT,
+        _ => SOCKS_REP_GENERAL_FAILURE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Tests that errors are mapped onto the matching reply codes.
+    #[test]
+    fn test_reply_code() {
+        let error = anyhow::Error::from(SocksError::AddressFamilyNotAvailable("IPv6"));
+        assert_eq!(reply_code(&error), SOCKS_REP_ADDRESS_TYPE_NOT_SUPPORTED);
+
+        let error = anyhow::Error::from(SocksError::CommandNotSupported(0x09));
+        assert_eq!(reply_code(&error), SOCKS_REP_COMMAND_NOT_SUPPORTED);
+
+        let error = anyhow::Error::from(io::Error::from(ErrorKind::ConnectionRefused));
+        assert_eq!(reply_code(&error), SOCKS_REP_CONNECTION_REFUSED);
+
+        let error = anyhow!("Something else went wrong.");
+        assert_eq!(reply_code(&error), SOCKS_REP_GENERAL_FAILURE);
+    }
+}
@@ -0,0 +1,463 @@
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use crate::addresses::IpNetwork;
+use crate::bypass::{domain_matches, normalize_name};
+use crate::resolver::Resolver;
+use crate::{util, Address, SocksError};
+
+/// The networks `DestinationGuard::internal` blocks: loopback, RFC 1918, link-local, CGNAT, IPv6 unique local,
+/// and unspecified addresses, which include the metadata endpoints of the cloud providers, e.g.,
+/// 169.254.169.254, fd00:ec2::254, and 100.100.100.200.
+pub const INTERNAL_NETWORKS: [&str; 11] = [
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "::/128",
+    "::1/128",
+    "fc00::/7",
+    "fe80::/10",
+];
+
+/// The networks `DestinationGuard::host_local` blocks: loopback, link-local, and unspecified addresses, and the
+/// metadata endpoints of the cloud providers outside of those, i.e., fd00:ec2::254 and 100.100.100.200.
+pub const HOST_LOCAL_NETWORKS: [&str; 8] = [
+    "0.0.0.0/8",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "100.100.100.200/32",
+    "::/128",
+    "::1/128",
+    "fe80::/10",
+    "fd00:ec2::254/128",
+];
+
+/// The names `DestinationGuard::internal` and `DestinationGuard::host_local` block, along with their subdomains,
+/// as they resolve to internal addresses without asking a DNS server.
+pub const INTERNAL_HOSTS: [&str; 2] = ["localhost", "metadata.google.internal"];
+
+/// Decides which destinations may be connected to, e.g., to keep user-supplied URLs from reaching internal
+/// infrastructure through the proxy.
+///
+/// A destination is blocked if it is an IP address in one of the blocked networks, or a domain name that is, or
+/// is a subdomain of, one of the blocked hosts. Allowed networks and hosts are exceptions to the blocked ones.
+///
+/// Domain names are resolved by the proxy, out of the guard's sight, unless the guard resolves them locally
+/// (`with_local_resolution`). Unix socket destinations are blocked as soon as anything is.
+#[derive(Clone, Default)]
+pub struct DestinationGuard {
+    blocked: Vec<IpNetwork>,
+    allowed: Vec<IpNetwork>,
+    blocked_hosts: Vec<String>,
+    allowed_hosts: Vec<String>,
+    resolver: Option<Arc<dyn Resolver + Send + Sync>>,
+}
+
+impl DestinationGuard {
+    /// Creates a guard that blocks nothing, which is what the clients use by default.
+    pub fn permissive() -> Self {
+        Self::default()
+    }
+
+    /// Creates a guard that blocks the `INTERNAL_NETWORKS` and the `INTERNAL_HOSTS`.
+    pub fn internal() -> Self {
+        Self::blocking(&INTERNAL_NETWORKS)
+    }
+
+    /// Creates a guard that blocks the `HOST_LOCAL_NETWORKS` and the `INTERNAL_HOSTS`, i.e., the destinations on
+    /// the host of a proxy rather than on its private networks. Add the listener with `with_listener`.
+    pub fn host_local() -> Self {
+        Self::blocking(&HOST_LOCAL_NETWORKS)
+    }
+
+    // Creates a guard that blocks the networks and the `INTERNAL_HOSTS`.
+    fn blocking(networks: &[&str]) -> Self {
+        let guard = networks
+            .iter()
+            .map(|network| network.parse().expect("Preset networks are valid"))
+            .fold(Self::permissive(), Self::with_blocked);
+
+        INTERNAL_HOSTS.iter().fold(guard, |guard, host| guard.with_blocked_host(*host))
+    }
+
+    /// Blocks the addresses in the network.
+    pub fn with_blocked(
+        mut self,
+        network: IpNetwork,
+    ) -> Self {
+        self.blocked.push(network);
+        self
+    }
+
+    /// Blocks the addresses a listener can be reached at, so the proxy can't be made to connect to itself: the
+    /// address it is bound to, or the addresses of the host's interfaces if it is bound to an unspecified address.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the guard, or an error if the interface addresses can't be listed.
+    pub fn with_listener(
+        self,
+        listener: SocketAddr,
+    ) -> Result<Self> {
+        let addrs = if listener.ip().is_unspecified() {
+            util::interface_addrs()?
+        } else {
+            vec![listener.ip()]
+        };
+
+        Ok(addrs.into_iter().map(IpNetwork::from).fold(self, Self::with_blocked))
+    }
+
+    /// Allows the addresses in the network, even if a blocked network contains them.
+    pub fn with_allowed(
+        mut self,
+        network: IpNetwork,
+    ) -> Self {
+        self.allowed.push(network);
+        self
+    }
+
+    /// Blocks the domain name and its subdomains, ignoring case.
+    pub fn with_blocked_host<S: AsRef<str>>(
+        mut self,
+        host: S,
+    ) -> Self {
+        self.blocked_hosts.push(normalize_name(host.as_ref().trim_start_matches('.')));
+        self
+    }
+
+    /// Allows the domain name and its subdomains, ignoring case, even if a blocked host matches them. Their
+    /// resolved addresses aren't checked either.
+    pub fn with_allowed_host<S: AsRef<str>>(
+        mut self,
+        host: S,
+    ) -> Self {
+        self.allowed_hosts.push(normalize_name(host.as_ref().trim_start_matches('.')));
+        self
+    }
+
+    /// Resolves domain names with the resolver before they are connected to, blocking those that resolve to a
+    /// blocked address.
+    ///
+    /// The destination is then replaced by the first resolved address, so the proxy can't be made to connect
+    /// elsewhere by resolving the name differently, e.g., through DNS rebinding.
+    pub fn with_local_resolution(
+        mut self,
+        resolver: Arc<dyn Resolver + Send + Sync>,
+    ) -> Self {
+        self.resolver = Some(resolver);
+        self
+    }
+
+    /// Returns whether the guard blocks nothing.
+    pub fn is_permissive(&self) -> bool {
+        self.blocked.is_empty() && self.blocked_hosts.is_empty()
+    }
+
+    /// Returns whether the address is blocked.
+    pub fn is_blocked(
+        &self,
+        addr: IpAddr,
+    ) -> bool {
+        self.blocked.iter().any(|network| network.contains(addr))
+            && !self.allowed.iter().any(|network| network.contains(addr))
+    }
+
+    /// Checks the destination, resolving it first if the guard resolves domain names locally.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the destination to connect to, which is the first resolved address of a resolved
+    /// domain name, or `SocksError::DestinationBlocked` if the destination is blocked.
+    pub async fn check(
+        &self,
+        destination: &Address,
+    ) -> Result<Address> {
+        self.check_resolved(destination, &[])?;
+
+        match (destination, &self.resolver) {
+            (Address::Domainname { host, port }, Some(resolver))
+                if !self.is_permissive() && !self.is_allowed_host(host) =>
+            {
+                let addresses = resolver.resolve(host).await?;
+                self.check_resolved(destination, &addresses)?;
+                ensure!(!addresses.is_empty(), "No addresses found for {}", host);
+
+                Ok(Address::Ip(SocketAddr::new(addresses[0], *port)))
+            }
+            _ => Ok(destination.clone()),
+        }
+    }
+
+    /// Checks the destination along with the addresses its domain name resolved to, as the handlers do right
+    /// before connecting to them, so that every resolution is checked.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating whether the destination may be connected to, with `SocksError::DestinationBlocked`
+    /// if it is blocked, or if any of the resolved addresses is.
+    pub fn check_resolved(
+        &self,
+        destination: &Address,
+        resolved: &[IpAddr],
+    ) -> Result<()> {
+        if self.is_permissive() {
+            return Ok(());
+        }
+
+        match destination {
+            Address::Ip(addr) if self.is_blocked(addr.ip()) => Err(blocked(destination, None)),
+            Address::Ip(_) => Ok(()),
+            Address::Domainname { host, .. } if self.is_allowed_host(host) => Ok(()),
+            Address::Domainname { host, .. } if self.blocked_hosts.iter().any(|name| domain_matches(name, host)) => {
+                Err(blocked(destination, None))
+            }
+            Address::Domainname { .. } => match resolved.iter().find(|addr| self.is_blocked(**addr)) {
+                Some(addr) => Err(blocked(destination, Some(*addr))),
+                None => Ok(()),
+            },
+            Address::Unix(_) => Err(blocked(destination, None)),
+        }
+    }
+
+    // Returns whether the domain name is one of the allowed hosts, or a subdomain of one.
+    fn is_allowed_host(
+        &self,
+        host: &str,
+    ) -> bool {
+        self.allowed_hosts.iter().any(|name| domain_matches(name, host))
+    }
+}
+
+impl fmt::Debug for DestinationGuard {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        f.debug_struct("DestinationGuard")
+            .field("blocked", &self.blocked)
+            .field("allowed", &self.allowed)
+            .field("blocked_hosts", &self.blocked_hosts)
+            .field("allowed_hosts", &self.allowed_hosts)
+            .field("local_resolution", &self.resolver.is_some())
+            .finish()
+    }
+}
+
+// Returns the error for a blocked destination, naming the address it resolved to, if any.
+fn blocked(
+    destination: &Address,
+    resolved: Option<IpAddr>,
+) -> anyhow::Error {
+    let destination = match resolved {
+        Some(addr) => format!("{} ({})", destination, addr),
+        None => destination.to_string(),
+    };
+
+    SocksError::DestinationBlocked(destination).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use async_trait::async_trait;
+    use bytes::BytesMut;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::constants::*;
+    use crate::socks6::{Socks6Command, Socks6Request};
+    use crate::test_util::MockSocksServer;
+    use crate::{wire, Socks5Client, Socks5Handler, Socks6Client, Socks6Handler, SocksHandler};
+
+    // Resolver that answers every name with the same addresses.
+    struct StaticResolver(Vec<IpAddr>);
+
+    #[async_trait]
+    impl Resolver for StaticResolver {
+        async fn resolve(
+            &self,
+            _host: &str,
+        ) -> Result<Vec<IpAddr>> {
+            Ok(self.0.clone())
+        }
+    }
+
+    // Resolver that answers with each of its addresses in turn, like a DNS server rebinding a name.
+    struct RebindingResolver {
+        addresses: Vec<IpAddr>,
+        resolutions: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl Resolver for RebindingResolver {
+        async fn resolve(
+            &self,
+            _host: &str,
+        ) -> Result<Vec<IpAddr>> {
+            let i = self.resolutions.fetch_add(1, Ordering::SeqCst);
+            Ok(vec![self.addresses[i % self.addresses.len()]])
+        }
+    }
+
+    // Tests which destinations the internal guard blocks, and that the allowed networks and hosts are exceptions.
+    #[tokio::test]
+    async fn test_internal() -> Result<()> {
+        let guard = DestinationGuard::internal()
+            .with_allowed("10.1.0.0/16".parse()?)
+            .with_allowed_host("localhost.example")
+            .with_blocked_host("internal.example.com");
+
+        let blocked = [
+            "127.0.0.1",
+            "10.0.0.1",
+            "172.16.5.4",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::ffff:127.0.0.1",
+            "fe80::1",
+            "fd00:ec2::254",
+            "localhost",
+            "Metadata.Google.Internal.",
+            "db.internal.example.com",
+        ];
+        for host in blocked {
+            let error = guard.check(&Address::new(host, 80)).await.unwrap_err();
+            assert!(matches!(error.downcast_ref(), Some(SocksError::DestinationBlocked(_))), "{}", host);
+        }
+
+        for host in ["192.0.2.1", "2001:db8::1", "10.1.2.3", "example.com", "localhost.example"] {
+            assert_eq!(guard.check(&Address::new(host, 80)).await?, Address::new(host, 80));
+        }
+        assert!(guard.check(&Address::unix("/run/docker.sock")?).await.is_err());
+        assert!(DestinationGuard::permissive().check(&Address::new("127.0.0.1", 80)).await.is_ok());
+
+        Ok(())
+    }
+
+    // Tests that a resolving guard blocks names that resolve to a blocked address, and pins the others.
+    #[tokio::test]
+    async fn test_local_resolution() -> Result<()> {
+        let rebinding = StaticResolver(vec!["192.0.2.1".parse()?, "127.0.0.1".parse()?]);
+        let guard = DestinationGuard::internal().with_local_resolution(Arc::new(rebinding));
+        let error = guard.check(&Address::new("rebind.example", 80)).await.unwrap_err();
+        assert_eq!(error.to_string(), "Destination rebind.example:80 (127.0.0.1) blocked by the destination guard.");
+
+        let public = StaticResolver(vec!["192.0.2.1".parse()?]);
+        let guard = DestinationGuard::internal().with_local_resolution(Arc::new(public));
+        assert_eq!(guard.check(&Address::new("example.com", 443)).await?, Address::new("192.0.2.1", 443));
+
+        Ok(())
+    }
+
+    // Tests which destinations the host-local guard blocks, including the addresses of the listener.
+    #[tokio::test]
+    async fn test_host_local() -> Result<()> {
+        let guard = DestinationGuard::host_local().with_listener("192.0.2.10:1080".parse()?)?;
+
+        let blocked = ["127.0.0.1", "::1", "169.254.169.254", "100.100.100.200", "fd00:ec2::254", "0.0.0.0"];
+        for host in blocked.iter().chain(&["192.0.2.10", "localhost"]) {
+            assert!(guard.check(&Address::new(*host, 80)).await.is_err(), "{}", host);
+        }
+        for host in ["10.0.0.1", "192.168.1.1", "192.0.2.11", "example.com"] {
+            assert!(guard.check(&Address::new(host, 80)).await.is_ok(), "{}", host);
+        }
+
+        let resolved = ["192.0.2.1".parse()?, "127.0.0.1".parse()?];
+        let error = guard.check_resolved(&Address::new("example.com", 80), &resolved).unwrap_err();
+        assert_eq!(error.to_string(), "Destination example.com:80 (127.0.0.1) blocked by the destination guard.");
+        assert!(guard.check_resolved(&Address::new("example.com", 80), &resolved[..1]).is_ok());
+
+        Ok(())
+    }
+
+    // Tests that the handlers check every resolution of a destination, so a name that is rebound to a blocked
+    // address after a first, allowed connect is refused with a ConnectionNotAllowed reply.
+    #[tokio::test]
+    async fn test_handlers_rebinding() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        tokio::spawn(async move {
+            loop {
+                let _ = listener.accept().await;
+            }
+        });
+        let resolver = || {
+            Arc::new(RebindingResolver {
+                addresses: vec!["127.0.0.1".parse().unwrap(), "169.254.169.254".parse().unwrap()],
+                resolutions: AtomicUsize::new(0),
+            })
+        };
+        let guard = DestinationGuard::permissive().with_blocked("169.254.169.254/32".parse()?);
+
+        let handler = Socks5Handler::default().with_resolver(resolver()).with_destination_guard(guard.clone());
+        let mut request = vec![SOCKS_VER_5, 0x01, SOCKS_AUTH_NOT_REQUIRED];
+        request.extend_from_slice(&[SOCKS_VER_5, SOCKS_CMD_CONNECT, SOCKS_RSV, SOCKS_ATYP_DOMAINNAME, 14]);
+        request.extend_from_slice(b"rebind.example");
+        request.extend_from_slice(&port.to_be_bytes());
+        for expected in [SOCKS_REP_SUCCEEDED, SOCKS_REP_CONNECTION_NOT_ALLOWED] {
+            let (mut client, mut source) = tokio::io::duplex(4096);
+            let handler = handler.clone();
+            tokio::spawn(async move { handler.accept_request(&mut source).await });
+            client.write_all(&request).await?;
+
+            let mut reply = [0; 12];
+            client.read_exact(&mut reply).await?;
+            assert_eq!(reply[3], expected);
+        }
+
+        let handler = Socks6Handler::default().with_resolver(resolver()).with_destination_guard(guard);
+        let destination = Address::new("rebind.example", port);
+        for expected in [SOCKS_REP_SUCCEEDED, SOCKS_REP_CONNECTION_NOT_ALLOWED] {
+            let (mut client, mut source) = tokio::io::duplex(4096);
+            let handler = handler.clone();
+            tokio::spawn(async move { handler.accept_request(&mut source).await });
+            let request = Socks6Request::new(Socks6Command::Connect, destination.clone(), 0, vec![], None);
+            client.write_all(&request.into_socks_bytes()).await?;
+
+            let mut scratch = BytesMut::new();
+            wire::read_message(&mut client, &mut scratch, wire::parse_socks6_auth_reply).await?;
+            let (reply_code, _, _) = wire::read_message(&mut client, &mut scratch, wire::parse_socks6_reply).await?;
+            assert_eq!(reply_code, expected);
+        }
+
+        Ok(())
+    }
+
+    // Tests that the clients refuse blocked destinations without contacting the proxy.
+    #[tokio::test]
+    async fn test_clients_guard() -> Result<()> {
+        let proxy = MockSocksServer::socks5();
+        let proxy_addr = proxy.bind().await?;
+        let client = Socks5Client::new(proxy_addr.to_string(), None)
+            .await?
+            .with_destination_guard(DestinationGuard::internal());
+        let error = client.connect("169.254.169.254:80", None).await.unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(SocksError::DestinationBlocked(_))));
+        client.connect("192.0.2.1:80", None).await?;
+        assert_eq!(proxy.recordings().len(), 1);
+
+        let proxy = MockSocksServer::socks6();
+        let proxy_addr = proxy.bind().await?;
+        let client = Socks6Client::new(proxy_addr.to_string(), None)
+            .await?
+            .with_destination_guard(DestinationGuard::internal());
+        assert!(client.connect("localhost:80", None, None).await.is_err());
+        client.connect("192.0.2.1:80", None, None).await?;
+        assert_eq!(proxy.recordings().len(), 1);
+
+        Ok(())
+    }
+}
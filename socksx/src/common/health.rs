@@ -0,0 +1,121 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::task::JoinHandle;
+use tokio::time;
+
+use crate::addresses::Address;
+use crate::dialer::ProxyEndpoint;
+
+/// How a health check found out that the proxy is alive.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HealthProbe {
+    /// The proxy authenticated the client and answered a NOOP request (SOCKS6 only).
+    NoOp,
+    /// The proxy authenticated the client and connected to the canary destination, which was closed right away.
+    Canary,
+}
+
+/// The outcome of a successful health check.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HealthReport {
+    /// How long the check took, from connecting to the proxy until its operation reply.
+    pub latency: Duration,
+    /// The address of the proxy that was checked, or `None` if the proxy listens on a Unix socket.
+    pub proxy: Option<SocketAddr>,
+    /// How the proxy was checked.
+    pub probe: HealthProbe,
+    /// The address the proxy announced in its operation reply.
+    pub binding: Address,
+}
+
+/// The health of a proxy, as last determined by a `HealthMonitor`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum HealthStatus {
+    /// The proxy hasn't been checked yet.
+    #[default]
+    Unknown,
+    /// At least one address of the proxy passed its last check, which is reported.
+    Healthy(HealthReport),
+    /// No address of the proxy passed its last check, for the given reason.
+    Unhealthy(String),
+}
+
+impl HealthStatus {
+    /// Returns whether the proxy passed its last check.
+    pub fn is_healthy(&self) -> bool {
+        matches!(self, HealthStatus::Healthy(_))
+    }
+}
+
+/// Checks the health of a proxy in the background, until it is dropped.
+///
+/// Created by `spawn_health_monitor` on the clients, which read the status with `health`. Every address of the
+/// proxy is checked on its own, and addresses that fail their check are skipped by the connects of the client
+/// while other addresses pass theirs.
+#[derive(Debug)]
+pub struct HealthMonitor {
+    task: JoinHandle<()>,
+}
+
+impl Drop for HealthMonitor {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Starts checking every address of the proxy with the check, once per interval, keeping the status up to date.
+///
+/// A proxy that listens on a Unix socket has no addresses, and is checked at its socket with `None` instead. A check
+/// that takes longer than the interval fails.
+pub(crate) fn spawn_monitor<F, C>(
+    interval: Duration,
+    proxy: ProxyEndpoint,
+    status: Arc<Mutex<HealthStatus>>,
+    check: F,
+) -> HealthMonitor
+where
+    F: Fn(Option<SocketAddr>) -> C + Send + 'static,
+    C: Future<Output = Result<HealthReport>> + Send,
+{
+    let task = tokio::spawn(async move {
+        loop {
+            let mut healthy = None;
+            let mut failure = String::from("The proxy has no addresses");
+            let targets: Vec<_> = match proxy.unix_path() {
+                Some(path) => vec![(None, path.display().to_string())],
+                None => proxy.addrs().into_iter().map(|addr| (Some(addr), addr.to_string())).collect(),
+            };
+            for (addr, name) in targets {
+                let checked = match time::timeout(interval, check(addr)).await {
+                    Ok(checked) => checked,
+                    Err(_) => Err(anyhow!("Health check timed out after {:?}", interval)),
+                };
+                if let Some(addr) = addr {
+                    proxy.set_healthy(addr, checked.is_ok());
+                }
+
+                match checked {
+                    Ok(report) => {
+                        healthy.get_or_insert(report);
+                    }
+                    Err(e) => {
+                        debug!("Health check of proxy {} failed: {:#}", name, e);
+                        failure = format!("{}: {:#}", name, e);
+                    }
+                }
+            }
+
+            *status.lock().unwrap() = match healthy {
+                Some(report) => HealthStatus::Healthy(report),
+                None => HealthStatus::Unhealthy(failure),
+            };
+            time::sleep(interval).await;
+        }
+    });
+
+    HealthMonitor { task }
+}
@@ -1,6 +1,23 @@
-use anyhow::Result;
+use std::convert::TryFrom;
+use std::env;
+
+use anyhow::{Context, Result};
 use async_trait::async_trait;
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::constants::*;
+use crate::dialer::ConnectionAddrs;
+use crate::policy::Policy;
+use crate::{Address, ProxyAddress, Socks5Client, Socks6Client, SocksStream};
+use crate::bypass::{BypassList, Route};
+
+/// The environment variables `client_from_env` reads the proxy address from, in the order curl consults them.
+pub const ALL_PROXY_VARS: [&str; 2] = ["all_proxy", "ALL_PROXY"];
+
+/// A bidirectional byte stream, such as a `TcpStream`, that the handlers can serve and connect with.
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
 
 /// An asynchronous trait defining the core functionalities required for handling SOCKS requests.
 #[async_trait]
@@ -9,41 +26,226 @@ pub trait SocksHandler {
     ///
     /// # Parameters
     ///
-    /// * `source`: A mutable reference to the source `TcpStream` from which the request originates.
+    /// * `source`: A mutable reference to the source stream from which the request originates.
     ///
     /// # Returns
     ///
     /// Returns `Result<()>` indicating the success or failure of the operation.
     async fn accept_request(
         &self,
-        source: &mut TcpStream,
+        source: &mut dyn AsyncStream,
     ) -> Result<()>;
 
+    /// Accepts a SOCKS request from a client that its transport authenticated already, e.g., with a TLS client
+    /// certificate, like `accept_request`.
+    ///
+    /// The identity is the one the session hooks see, unless the client authenticates within SOCKS as well.
+    /// Handlers without identities ignore it.
+    ///
+    /// # Parameters
+    ///
+    /// * `source`: A mutable reference to the source stream from which the request originates.
+    /// * `identity`: The identity the transport authenticated the client as, if any.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Result<()>` indicating the success or failure of the operation.
+    async fn accept_request_as(
+        &self,
+        source: &mut dyn AsyncStream,
+        _identity: Option<String>,
+    ) -> Result<()> {
+        self.accept_request(source).await
+    }
+
+    /// Accepts a SOCKS request from a client like `accept_request_as`, with the addresses of its connection, which
+    /// the session hooks see in the `TunnelInfo` of the session.
+    ///
+    /// # Parameters
+    ///
+    /// * `source`: A mutable reference to the source stream from which the request originates.
+    /// * `identity`: The identity the transport authenticated the client as, if any.
+    /// * `addrs`: The addresses of the connection of the client: its own as the peer, and the local one.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Result<()>` indicating the success or failure of the operation.
+    async fn accept_request_from(
+        &self,
+        source: &mut dyn AsyncStream,
+        identity: Option<String>,
+        _addrs: ConnectionAddrs,
+    ) -> Result<()> {
+        self.accept_request_as(source, identity).await
+    }
+
     /// Refuses a SOCKS request from a client.
     ///
     /// # Parameters
     ///
-    /// * `source`: A reference to the source `TcpStream` from which the request originates.
+    /// * `source`: A reference to the source stream from which the request originates.
     ///
     /// # Returns
     ///
     /// Returns `Result<()>` indicating the success or failure of the operation.
     async fn refuse_request(
         &self,
-        source: &mut TcpStream,
+        source: &mut dyn AsyncStream,
     ) -> Result<()>;
 
+    /// Refuses a SOCKS request from a client with a general failure, as the server has no room for it.
+    ///
+    /// By default, the request is refused like `refuse_request` refuses it.
+    ///
+    /// # Parameters
+    ///
+    /// * `source`: A reference to the source stream from which the request originates.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Result<()>` indicating the success or failure of the operation.
+    async fn refuse_overloaded(
+        &self,
+        source: &mut dyn AsyncStream,
+    ) -> Result<()> {
+        self.refuse_request(source).await
+    }
+
     /// Sets up the SOCKS connection for a given source.
     ///
     /// # Parameters
     ///
-    /// * `source`: A mutable reference to the source `TcpStream`.
+    /// * `source`: A mutable reference to the source stream.
     ///
     /// # Returns
     ///
-    /// Returns a `Result<TcpStream>` containing the prepared `TcpStream` or an error.
+    /// Returns a `Result` containing the stream to the destination or an error.
     async fn setup(
         &self,
-        source: &mut TcpStream,
-    ) -> Result<TcpStream>;
+        source: &mut dyn AsyncStream,
+    ) -> Result<Box<dyn AsyncStream>>;
+
+    /// Replaces the policy that decides which sessions are allowed, for the requests accepted from now on.
+    ///
+    /// # Parameters
+    ///
+    /// * `policy`: The new policy.
+    ///
+    /// # Returns
+    ///
+    /// Returns an error if the handler doesn't support policies.
+    fn update_policy(
+        &self,
+        _policy: Policy,
+    ) -> Result<()> {
+        bail!("This handler doesn't support policies.")
+    }
+}
+
+/// An asynchronous trait that allows version-generic code to use SOCKS5 and SOCKS6 clients interchangeably.
+#[async_trait]
+pub trait SocksClient {
+    /// Connects to a destination through the proxy.
+    ///
+    /// # Parameters
+    ///
+    /// * `destination`: The address of the destination.
+    /// * `initial_data`: Optional data that must be delivered to the destination before anything else.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` containing a tuple with the `SocksStream` to the destination and the bound `Address`.
+    async fn connect(
+        &self,
+        destination: Address,
+        initial_data: Option<Vec<u8>>,
+    ) -> Result<(SocksStream, Address)>;
+
+    /// Connects to a destination through the proxy, or directly if the client bypasses the proxy for it.
+    ///
+    /// # Parameters
+    ///
+    /// * `destination`: The address of the destination.
+    /// * `initial_data`: Optional data that must be delivered to the destination before anything else.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` containing a tuple with the `SocksStream` to the destination, the bound `Address`, and
+    /// whether the proxy was used. Clients without a bypass list always use the proxy.
+    async fn connect_routed(
+        &self,
+        destination: Address,
+        initial_data: Option<Vec<u8>>,
+    ) -> Result<(SocksStream, Address, Route)> {
+        let (stream, binding) = self.connect(destination, initial_data).await?;
+        Ok((stream, binding, Route::Proxied))
+    }
+
+    /// Returns a copy of the client that sets the firewall mark (SO_MARK) on the sockets it connects.
+    ///
+    /// # Parameters
+    ///
+    /// * `mark`: The firewall mark.
+    ///
+    /// # Returns
+    ///
+    /// Returns the marked client, or `None` if the client can't set a mark, which is the default.
+    fn marked(
+        &self,
+        _mark: u32,
+    ) -> Option<Box<dyn SocksClient + Send + Sync>> {
+        None
+    }
+}
+
+/// Creates a client for the proxy, of the SOCKS version the proxy address carries.
+///
+/// # Parameters
+///
+/// * `proxy_addr`: The address of the proxy, including its version and credentials.
+///
+/// # Returns
+///
+/// Returns a `Result` containing the client, or an error if the SOCKS version isn't supported.
+pub async fn client_from_proxy_addr(proxy_addr: ProxyAddress) -> Result<Box<dyn SocksClient + Send + Sync>> {
+    client_with_bypass(proxy_addr, BypassList::new()).await
+}
+
+/// Creates a client for the proxy, of the SOCKS version the proxy address carries, that connects to the
+/// destinations on the bypass list directly.
+///
+/// # Parameters
+///
+/// * `proxy_addr`: The address of the proxy, including its version and credentials.
+/// * `bypass`: The destinations that are reached without the proxy.
+///
+/// # Returns
+///
+/// Returns a `Result` containing the client, or an error if the SOCKS version isn't supported.
+pub async fn client_with_bypass(
+    proxy_addr: ProxyAddress,
+    bypass: BypassList,
+) -> Result<Box<dyn SocksClient + Send + Sync>> {
+    match proxy_addr.socks_version {
+        SOCKS_VER_5 => Ok(Box::new(Socks5Client::from_proxy_addr(proxy_addr).await?.with_bypass(bypass))),
+        SOCKS_VER_6 => Ok(Box::new(Socks6Client::from_proxy_addr(proxy_addr).await?.with_bypass(bypass))),
+        version => bail!("Unsupported SOCKS version: {}", version),
+    }
+}
+
+/// Creates a client for the proxy in `all_proxy`, or `ALL_PROXY` if that isn't set, e.g., `socks5://proxy:1080`.
+///
+/// The client connects to the destinations in `no_proxy`, or `NO_PROXY`, directly (see `BypassList::from_env`).
+///
+/// # Returns
+///
+/// Returns a `Result` containing the client, `None` if neither variable is set, or an error if the proxy address
+/// isn't a valid SOCKS5 or SOCKS6 URL.
+pub async fn client_from_env() -> Result<Option<Box<dyn SocksClient + Send + Sync>>> {
+    let Some(proxy_addr) = ALL_PROXY_VARS.iter().find_map(|name| env::var(name).ok()) else {
+        return Ok(None);
+    };
+    let proxy_addr = ProxyAddress::try_from(proxy_addr).context("Invalid proxy address in the environment")?;
+
+    client_with_bypass(proxy_addr, BypassList::from_env()).await.map(Some)
 }
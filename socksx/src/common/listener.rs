@@ -0,0 +1,406 @@
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use anyhow::Result;
+use futures::stream::{FuturesUnordered, Stream, StreamExt};
+use num_traits::FromPrimitive;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+
+use crate::addresses::Address;
+use crate::constants::*;
+use crate::dialer::ConnectionAddrs;
+use crate::session::{CloseSummary, ConnectionId};
+use crate::socks5::{Socks5Handler, Socks5Reply, Socks5Request};
+use crate::socks6::{Socks6Handler, Socks6Reply, Socks6Request};
+
+/// How many handshakes a `SocksListener` runs at once by default.
+pub const DEFAULT_MAX_HANDSHAKES: usize = 64;
+
+/// A request read by a `SocksListener`, of the SOCKS version of its handler.
+#[derive(Clone, Debug)]
+pub enum IncomingRequest {
+    /// A request read by a `Socks5Handler`.
+    Socks5(Socks5Request),
+    /// A request read by a `Socks6Handler`.
+    Socks6(Socks6Request),
+}
+
+impl IncomingRequest {
+    /// Returns the SOCKS version of the request.
+    pub fn version(&self) -> u8 {
+        match self {
+            IncomingRequest::Socks5(_) => SOCKS_VER_5,
+            IncomingRequest::Socks6(_) => SOCKS_VER_6,
+        }
+    }
+
+    /// Returns the destination the source asked for.
+    pub fn destination(&self) -> &Address {
+        match self {
+            IncomingRequest::Socks5(request) => &request.destination,
+            IncomingRequest::Socks6(request) => &request.destination,
+        }
+    }
+}
+
+// What the consumer of a listener decided on a session, with where the outcome goes.
+enum Decision {
+    Accept(oneshot::Sender<Result<CloseSummary>>),
+    Reject(u8, oneshot::Sender<Result<()>>),
+    TakeOver(oneshot::Sender<Result<(TcpStream, Vec<u8>)>>),
+}
+
+/// A session whose source completed its handshake with a `SocksListener`, and waits for the reply to its request.
+///
+/// Exactly one of `accept`, `reject`, and `take_over` decides on the session. A session that is dropped without
+/// a decision is closed without a reply.
+pub struct IncomingSession {
+    id: ConnectionId,
+    request: IncomingRequest,
+    addrs: ConnectionAddrs,
+    decision: oneshot::Sender<Decision>,
+}
+
+impl IncomingSession {
+    /// Returns the connection ID of the session.
+    pub fn id(&self) -> ConnectionId {
+        self.id
+    }
+
+    /// Returns the request of the source.
+    pub fn request(&self) -> &IncomingRequest {
+        &self.request
+    }
+
+    /// Returns the address of the source.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.addrs.peer
+    }
+
+    /// Returns the local address the source connected to.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.addrs.local
+    }
+
+    /// Connects to the destination like the handler would, and relays between it and the source until either
+    /// closes the connection.
+    ///
+    /// The relay runs in a task of its own, so it isn't cut short if the returned future is dropped.
+    ///
+    /// # Returns
+    /// A `Result` containing how the relay ended.
+    pub async fn accept(self) -> Result<CloseSummary> {
+        self.decide(Decision::Accept).await
+    }
+
+    /// Refuses the request with the reply code, e.g., `SOCKS_REP_CONNECTION_NOT_ALLOWED`, which must be one of
+    /// the SOCKS version of the request.
+    pub async fn reject(
+        self,
+        code: u8,
+    ) -> Result<()> {
+        let known = match self.request {
+            IncomingRequest::Socks5(_) => Socks5Reply::from_u8(code).is_some(),
+            IncomingRequest::Socks6(_) => Socks6Reply::from_u8(code).is_some(),
+        };
+        ensure!(known, "Unknown SOCKS{} reply code: {}", self.request.version(), code);
+
+        self.decide(|outcome| Decision::Reject(code, outcome)).await
+    }
+
+    /// Leaves the request to the caller, which sends the operation reply and serves the source itself, like
+    /// the `take_over` of the pending sessions of the handlers.
+    ///
+    /// # Returns
+    /// A `Result` containing the stream of the source, and the bytes the source sent after its request, which
+    /// the handler read already and which come before anything read from the stream.
+    pub async fn take_over(self) -> Result<(TcpStream, Vec<u8>)> {
+        self.decide(Decision::TakeOver).await
+    }
+
+    // Hands the decision to the task of the session, and waits for its outcome.
+    async fn decide<T>(
+        self,
+        decision: impl FnOnce(oneshot::Sender<Result<T>>) -> Decision,
+    ) -> Result<T> {
+        let id = self.id;
+        let (outcome, outcome_rx) = oneshot::channel();
+        if self.decision.send(decision(outcome)).is_err() {
+            bail!("[{}] The session ended before a decision was made.", id);
+        }
+
+        outcome_rx.await.unwrap_or_else(|_| Err(anyhow!("[{}] The session ended without an outcome.", id)))
+    }
+}
+
+// The handler a listener completes the handshakes with.
+#[derive(Clone)]
+enum Handler {
+    Socks5(Arc<Socks5Handler>),
+    Socks6(Arc<Socks6Handler>),
+}
+
+/// Accepts connections on a listener, and yields the sessions whose source completed its handshake with a
+/// handler, so that the caller decides on each, e.g., in an actor instead of a callback.
+///
+/// The handshakes run in tasks of their own, a limited number at a time. Connections are only accepted while
+/// the stream is polled, so a consumer that stops polling leaves new connections in the kernel's backlog.
+/// Connections whose handshake fails are yielded as errors, which don't end the stream.
+pub struct SocksListener {
+    listener: TcpListener,
+    handler: Handler,
+    max_handshakes: usize,
+    handshakes: FuturesUnordered<oneshot::Receiver<Result<IncomingSession>>>,
+}
+
+impl SocksListener {
+    /// Creates a listener whose sources complete their handshake with the SOCKS5 handler.
+    pub fn socks5(
+        listener: TcpListener,
+        handler: Socks5Handler,
+    ) -> Self {
+        Self::new(listener, Handler::Socks5(Arc::new(handler)))
+    }
+
+    /// Creates a listener whose sources complete their handshake with the SOCKS6 handler.
+    pub fn socks6(
+        listener: TcpListener,
+        handler: Socks6Handler,
+    ) -> Self {
+        Self::new(listener, Handler::Socks6(Arc::new(handler)))
+    }
+
+    // Creates a listener with the default limit on concurrent handshakes.
+    fn new(
+        listener: TcpListener,
+        handler: Handler,
+    ) -> Self {
+        Self {
+            listener,
+            handler,
+            max_handshakes: DEFAULT_MAX_HANDSHAKES,
+            handshakes: FuturesUnordered::new(),
+        }
+    }
+
+    /// Sets how many handshakes run at once, including those of sessions that weren't yielded yet.
+    pub fn with_max_handshakes(
+        mut self,
+        max_handshakes: usize,
+    ) -> Self {
+        self.max_handshakes = max_handshakes.max(1);
+        self
+    }
+
+    /// Returns the local address of the listener.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    // Starts the handshake with a source in a task of its own, which reports the session once it's done.
+    fn start_handshake(
+        &mut self,
+        source: TcpStream,
+    ) {
+        let (incoming, incoming_rx) = oneshot::channel();
+        match self.handler.clone() {
+            Handler::Socks5(handler) => tokio::spawn(serve_socks5(handler, source, incoming)),
+            Handler::Socks6(handler) => tokio::spawn(serve_socks6(handler, source, incoming)),
+        };
+
+        self.handshakes.push(incoming_rx);
+    }
+}
+
+impl Stream for SocksListener {
+    type Item = Result<IncomingSession>;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if this.handshakes.len() < this.max_handshakes {
+                match this.listener.poll_accept(cx) {
+                    Poll::Ready(Ok((source, _))) => {
+                        this.start_handshake(source);
+                        continue;
+                    }
+                    Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e.into()))),
+                    Poll::Pending => {}
+                }
+            }
+
+            // Without handshakes, the listener is pending, which wakes the stream.
+            return match this.handshakes.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(session))) => Poll::Ready(Some(session)),
+                Poll::Ready(Some(Err(_))) => continue,
+                Poll::Ready(None) | Poll::Pending => Poll::Pending,
+            };
+        }
+    }
+}
+
+// Hands the session to the consumer of the listener, and waits for its decision, if any.
+async fn announce(
+    id: ConnectionId,
+    request: IncomingRequest,
+    addrs: ConnectionAddrs,
+    incoming: oneshot::Sender<Result<IncomingSession>>,
+) -> Option<Decision> {
+    let (decision, decision_rx) = oneshot::channel();
+    let session = IncomingSession {
+        id,
+        request,
+        addrs,
+        decision,
+    };
+    incoming.send(Ok(session)).ok()?;
+
+    let decision = decision_rx.await.ok();
+    if decision.is_none() {
+        debug!("[{}] The session was dropped without a decision, closing the connection.", id);
+    }
+
+    decision
+}
+
+// Completes the handshake of a source with the SOCKS5 handler, and carries out the decision on its session.
+async fn serve_socks5(
+    handler: Arc<Socks5Handler>,
+    mut source: TcpStream,
+    incoming: oneshot::Sender<Result<IncomingSession>>,
+) {
+    let addrs = ConnectionAddrs::of(&source);
+    let (pending, request) = match handler.accept(&mut source).await {
+        Ok(accepted) => accepted,
+        Err(e) => {
+            let _ = incoming.send(Err(e));
+            return;
+        }
+    };
+    let pending = pending.with_source_addrs(addrs);
+
+    let Some(decision) = announce(pending.id(), IncomingRequest::Socks5(request), addrs, incoming).await else {
+        return;
+    };
+    match decision {
+        Decision::Accept(outcome) => {
+            let _ = outcome.send(pending.proxy_to_destination().await);
+        }
+        Decision::Reject(code, outcome) => {
+            let reply = Socks5Reply::from_u8(code).unwrap_or(Socks5Reply::GeneralFailure);
+            let _ = outcome.send(pending.reject(reply).await);
+        }
+        Decision::TakeOver(outcome) => {
+            let taken = pending.take_over().await;
+            let _ = outcome.send(taken.map(|read_ahead| (source, read_ahead)));
+        }
+    }
+}
+
+// Completes the handshake of a source with the SOCKS6 handler, and carries out the decision on its session.
+async fn serve_socks6(
+    handler: Arc<Socks6Handler>,
+    mut source: TcpStream,
+    incoming: oneshot::Sender<Result<IncomingSession>>,
+) {
+    let addrs = ConnectionAddrs::of(&source);
+    let (pending, request) = match handler.accept(&mut source).await {
+        Ok(accepted) => accepted,
+        Err(e) => {
+            let _ = incoming.send(Err(e));
+            return;
+        }
+    };
+    let pending = pending.with_source_addrs(addrs);
+
+    let Some(decision) = announce(pending.id(), IncomingRequest::Socks6(request), addrs, incoming).await else {
+        return;
+    };
+    match decision {
+        Decision::Accept(outcome) => {
+            let _ = outcome.send(pending.proxy_to_destination().await);
+        }
+        Decision::Reject(code, outcome) => {
+            let reply = Socks6Reply::from_u8(code).unwrap_or(Socks6Reply::GeneralFailure);
+            let _ = outcome.send(pending.reject(reply).await);
+        }
+        Decision::TakeOver(outcome) => {
+            let taken = pending.take_over().await;
+            let _ = outcome.send(taken.map(|read_ahead| (source, read_ahead)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::socks5;
+    use crate::test_util::MockConnector;
+    use crate::{Socks5Client, SocksError};
+
+    // Tests consuming sessions concurrently, accepting, rejecting, and taking over one each, depending on the port
+    // of their destination.
+    #[tokio::test]
+    async fn test_listener_sessions() -> Result<()> {
+        let connector = MockConnector::new();
+        let handler = Socks5Handler::default().with_connector(Arc::new(connector.clone()));
+        let listener = SocksListener::socks5(TcpListener::bind("127.0.0.1:0").await?, handler);
+        let client = Socks5Client::new(listener.local_addr()?.to_string(), None).await?;
+
+        let decisions = tokio::spawn(async move {
+            let decide = |session: Result<IncomingSession>| async move {
+                let session = session?;
+                assert!(session.peer_addr().is_some());
+                match session.request().destination().to_string().as_str() {
+                    "192.0.2.1:1" => session.accept().await.map(|_| "accepted"),
+                    "192.0.2.1:2" => session.reject(SOCKS_REP_CONNECTION_NOT_ALLOWED).await.map(|_| "rejected"),
+                    _ => {
+                        let (mut source, read_ahead) = session.take_over().await?;
+                        assert!(read_ahead.is_empty());
+                        socks5::write_reply(&mut source, Socks5Reply::Success).await?;
+                        source.write_all(b"taken").await?;
+                        Ok("taken over")
+                    }
+                }
+            };
+            let decisions: Vec<Result<&str>> = listener.take(3).map(decide).buffer_unordered(3).collect().await;
+            decisions.into_iter().collect::<Result<Vec<_>>>()
+        });
+
+        let (accepted, rejected, taken) = tokio::join!(
+            client.connect("192.0.2.1:1".to_string(), None),
+            client.connect("192.0.2.1:2".to_string(), None),
+            client.connect("192.0.2.1:3".to_string(), None),
+        );
+
+        let (mut stream, _) = accepted?;
+        stream.write_all(b"hello").await?;
+        let mut echoed = [0; 5];
+        stream.read_exact(&mut echoed).await?;
+        assert_eq!(&echoed, b"hello");
+        drop(stream);
+
+        let error = rejected.unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(SocksError::OperationFailed(SOCKS_REP_CONNECTION_NOT_ALLOWED))));
+
+        let (mut stream, _) = taken?;
+        let mut received = vec![];
+        stream.read_to_end(&mut received).await?;
+        assert_eq!(received, b"taken");
+
+        let mut decisions = decisions.await??;
+        decisions.sort_unstable();
+        assert_eq!(decisions, vec!["accepted", "rejected", "taken over"]);
+        assert_eq!(connector.destinations().len(), 1);
+
+        Ok(())
+    }
+}
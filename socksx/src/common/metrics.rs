@@ -0,0 +1,474 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::session::CloseSummary;
+use crate::socks6::{Diagnostic, ValidationError};
+use crate::timings::HandshakeTimings;
+use crate::SocksError;
+
+/// Counts the handshakes that were started, by `role` and `version`.
+pub const HANDSHAKES_STARTED: &str = "socksx_handshakes_started";
+/// Counts the handshakes that completed with a successful operation reply, by `role` and `version`.
+pub const HANDSHAKES_SUCCEEDED: &str = "socksx_handshakes_succeeded";
+/// Counts the handshakes that failed, by `role`, `version`, and `cause` (see `failure_cause`).
+pub const HANDSHAKES_FAILED: &str = "socksx_handshakes_failed";
+/// Counts the operation replies a handler sent, by `role`, `version`, and reply `code`.
+pub const REPLIES_SENT: &str = "socksx_replies_sent";
+/// Counts the operation replies a client received, by `role`, `version`, and reply `code`.
+pub const REPLIES_RECEIVED: &str = "socksx_replies_received";
+/// Counts the bytes relayed through tunnels, by `role`, `version`, and `direction` (`sent` from the source to
+/// the destination, or `received`).
+pub const BYTES_RELAYED: &str = "socksx_bytes_relayed";
+/// Observes how long tunnels lasted, by `role`, `version`, and the `reason` they were closed for.
+pub const TUNNEL_DURATION: &str = "socksx_tunnel_duration";
+/// Observes how long the phases of the connects of a client took, by `role`, `version`, and `phase` (see
+/// `HandshakeTimings::phases`).
+pub const HANDSHAKE_PHASE_DURATION: &str = "socksx_handshake_phase_duration";
+/// The number of sessions a server is running.
+pub const SESSIONS_ACTIVE: &str = "socksx_sessions_active";
+/// The number of accepted connections waiting for a session of a server to finish.
+pub const CONNECTIONS_QUEUED: &str = "socksx_connections_queued";
+/// Counts the connections a server accepted faster than its accept rate, by the `action` taken: `delayed` or
+/// `closed`.
+pub const CONNECTIONS_RATE_LIMITED: &str = "socksx_connections_rate_limited";
+/// Counts the connections a server or a transparent proxy closed as soon as it accepted them, as their source
+/// address isn't allowed by its `SourceFilter`.
+pub const CONNECTIONS_SOURCE_DENIED: &str = "socksx_connections_source_denied";
+/// Counts the UDP associations a handler refused, by the `reason` they were refused for: `client_limit`,
+/// `total_limit`, or `unspecified_client`.
+pub const UDP_ASSOCIATIONS_REFUSED: &str = "socksx_udp_associations_refused";
+/// Counts the datagrams a UDP association dropped, by the `reason` they were dropped for: `oversized`,
+/// `fragmented`, `malformed`, `blocked`, `unreachable`, or `unknown_source`.
+pub const UDP_DATAGRAMS_DROPPED: &str = "socksx_udp_datagrams_dropped";
+/// Counts the connections a transparent proxy accepted, by the `decision` it made for them: `proxied`,
+/// `bypassed`, `dropped`, or `looped`.
+pub const TRANSPARENT_DECISIONS: &str = "socksx_transparent_decisions";
+
+/// Receives the measurements of the handlers, clients, and servers, e.g., to export them to statsd or Prometheus.
+///
+/// The names are the constants of this module, and the labels are pairs of a label name and its value. The
+/// methods are called while sessions are served, so they should be cheap and must not block.
+pub trait Metrics {
+    /// Adds the value to a counter.
+    fn increment_counter(
+        &self,
+        name: &str,
+        labels: &[(&str, &str)],
+        value: u64,
+    );
+
+    /// Records an observation of a duration.
+    fn observe_duration(
+        &self,
+        name: &str,
+        labels: &[(&str, &str)],
+        duration: Duration,
+    );
+
+    /// Sets a gauge to the value.
+    fn set_gauge(
+        &self,
+        name: &str,
+        labels: &[(&str, &str)],
+        value: i64,
+    );
+}
+
+/// Metrics that discard every measurement, which is what the handlers, clients, and servers use by default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoopMetrics;
+
+impl Metrics for NoopMetrics {
+    fn increment_counter(
+        &self,
+        _name: &str,
+        _labels: &[(&str, &str)],
+        _value: u64,
+    ) {
+    }
+
+    fn observe_duration(
+        &self,
+        _name: &str,
+        _labels: &[(&str, &str)],
+        _duration: Duration,
+    ) {
+    }
+
+    fn set_gauge(
+        &self,
+        _name: &str,
+        _labels: &[(&str, &str)],
+        _value: i64,
+    ) {
+    }
+}
+
+/// Metrics that are kept in memory, e.g., to check them in tests.
+///
+/// Measurements are looked up by name and labels, in any order of the labels.
+#[derive(Debug, Default)]
+pub struct InMemoryMetrics {
+    counters: Mutex<HashMap<String, u64>>,
+    durations: Mutex<HashMap<String, Vec<Duration>>>,
+    gauges: Mutex<HashMap<String, i64>>,
+}
+
+impl InMemoryMetrics {
+    /// Creates metrics without any measurements.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the value of a counter, which is 0 if it was never incremented.
+    pub fn counter(
+        &self,
+        name: &str,
+        labels: &[(&str, &str)],
+    ) -> u64 {
+        let counters = self.counters.lock().unwrap();
+        counters.get(&key(name, labels)).copied().unwrap_or(0)
+    }
+
+    /// Returns the sum of a counter over all of its labels.
+    pub fn counter_total(
+        &self,
+        name: &str,
+    ) -> u64 {
+        let counters = self.counters.lock().unwrap();
+        counters
+            .iter()
+            .filter(|(key, _)| key.split('{').next() == Some(name))
+            .map(|(_, value)| value)
+            .sum()
+    }
+
+    /// Returns the observations of a duration, in the order they were recorded.
+    pub fn durations(
+        &self,
+        name: &str,
+        labels: &[(&str, &str)],
+    ) -> Vec<Duration> {
+        let durations = self.durations.lock().unwrap();
+        durations.get(&key(name, labels)).cloned().unwrap_or_default()
+    }
+
+    /// Returns the value of a gauge, if it was ever set.
+    pub fn gauge(
+        &self,
+        name: &str,
+        labels: &[(&str, &str)],
+    ) -> Option<i64> {
+        self.gauges.lock().unwrap().get(&key(name, labels)).copied()
+    }
+}
+
+impl Metrics for InMemoryMetrics {
+    fn increment_counter(
+        &self,
+        name: &str,
+        labels: &[(&str, &str)],
+        value: u64,
+    ) {
+        *self.counters.lock().unwrap().entry(key(name, labels)).or_default() += value;
+    }
+
+    fn observe_duration(
+        &self,
+        name: &str,
+        labels: &[(&str, &str)],
+        duration: Duration,
+    ) {
+        self.durations.lock().unwrap().entry(key(name, labels)).or_default().push(duration);
+    }
+
+    fn set_gauge(
+        &self,
+        name: &str,
+        labels: &[(&str, &str)],
+        value: i64,
+    ) {
+        self.gauges.lock().unwrap().insert(key(name, labels), value);
+    }
+}
+
+// Identifies a measurement by its name and its labels, sorted by label name, e.g., `name{a="1",b="2"}`.
+fn key(
+    name: &str,
+    labels: &[(&str, &str)],
+) -> String {
+    let mut labels = labels.to_vec();
+    labels.sort_unstable();
+
+    let labels: Vec<String> = labels.iter().map(|(label, value)| format!("{}=\"{}\"", label, value)).collect();
+    format!("{}{{{}}}", name, labels.join(","))
+}
+
+/// Returns the `cause` label of a failed handshake: `version_mismatch`, `authentication`, `unsupported`,
+/// `invalid_request`, `not_allowed`, `connect_timeout`, `connect`, `operation_failed`, `tls`, `io`, or `other`.
+pub fn failure_cause(error: &anyhow::Error) -> &'static str {
+    if let Some(error) = error.downcast_ref::<SocksError>() {
+        return match error {
+            SocksError::VersionMismatch(_) => "version_mismatch",
+            SocksError::AuthenticationFailed(_)
+            | SocksError::NoAcceptableAuthMethod
+            | SocksError::AuthVersionMismatch(_)
+            | SocksError::HttpProxyAuthenticationRequired => "authentication",
+            SocksError::CommandNotSupported(_)
+            | SocksError::UnknownCommand(_)
+            | SocksError::UnsupportedAddressType(_)
+            | SocksError::Unsupported(_) => "unsupported",
+            SocksError::InvalidDomain(_) | SocksError::MalformedNegotiation(_) => "invalid_request",
+            SocksError::ConnectionNotAllowed(_)
+            | SocksError::DestinationBlocked(_)
+            | SocksError::UdpAssociationRefused(_) => "not_allowed",
+            SocksError::ConnectTimeout(_) => "connect_timeout",
+            SocksError::AddressFamilyNotAvailable(_)
+            | SocksError::ChainFailed(_)
+            | SocksError::HttpConnectFailed(_) => "connect",
+            SocksError::InitialDataWrite(_) | SocksError::PeerDead(_) => "io",
+            SocksError::TlsHandshakeFailed(_) => "tls",
+            SocksError::OperationFailed(_) => "operation_failed",
+            SocksError::MemoryBudgetExhausted(_) => "memory_budget",
+        };
+    }
+    if error.is::<ValidationError>() || error.is::<Diagnostic>() {
+        return "invalid_request";
+    }
+
+    match error.downcast_ref::<io::Error>().map(io::Error::kind) {
+        Some(io::ErrorKind::ConnectionRefused | io::ErrorKind::HostUnreachable | io::ErrorKind::NetworkUnreachable) => {
+            "connect"
+        }
+        Some(io::ErrorKind::TimedOut) => "connect_timeout",
+        Some(_) => "io",
+        None => "other",
+    }
+}
+
+// Takes the measurements of one side of the handshakes of a SOCKS version, which label all of them.
+#[derive(Clone, Copy)]
+pub(crate) struct Recorder<'a> {
+    metrics: &'a (dyn Metrics + Send + Sync),
+    role: &'static str,
+    version: &'static str,
+}
+
+impl<'a> Recorder<'a> {
+    pub(crate) fn handler(
+        metrics: &'a (dyn Metrics + Send + Sync),
+        version: &'static str,
+    ) -> Self {
+        Self {
+            metrics,
+            role: "handler",
+            version,
+        }
+    }
+
+    pub(crate) fn client(
+        metrics: &'a (dyn Metrics + Send + Sync),
+        version: &'static str,
+    ) -> Self {
+        Self {
+            metrics,
+            role: "client",
+            version,
+        }
+    }
+
+    pub(crate) fn started(self) {
+        self.count(HANDSHAKES_STARTED, &[], 1);
+    }
+
+    pub(crate) fn succeeded(self) {
+        self.count(HANDSHAKES_SUCCEEDED, &[], 1);
+    }
+
+    pub(crate) fn failed(
+        self,
+        error: &anyhow::Error,
+    ) {
+        self.count(HANDSHAKES_FAILED, &[("cause", failure_cause(error))], 1);
+    }
+
+    // Counts a handshake the caller of a handler decided to reject.
+    pub(crate) fn rejected(self) {
+        self.count(HANDSHAKES_FAILED, &[("cause", "rejected")], 1);
+    }
+
+    pub(crate) fn reply_sent(
+        self,
+        code: u8,
+    ) {
+        self.count(REPLIES_SENT, &[("code", &code.to_string())], 1);
+    }
+
+    pub(crate) fn reply_received(
+        self,
+        code: u8,
+    ) {
+        self.count(REPLIES_RECEIVED, &[("code", &code.to_string())], 1);
+    }
+
+    // Records the bytes a tunnel relayed, and how long it lasted.
+    pub(crate) fn closed(
+        self,
+        summary: &CloseSummary,
+    ) {
+        self.count(BYTES_RELAYED, &[("direction", "sent")], summary.sent);
+        self.count(BYTES_RELAYED, &[("direction", "received")], summary.received);
+
+        let reason = format!("{:?}", summary.reason);
+        let labels = [("role", self.role), ("version", self.version), ("reason", reason.as_str())];
+        self.metrics.observe_duration(TUNNEL_DURATION, &labels, summary.duration);
+    }
+
+    // Counts a UDP association that was refused for the given reason.
+    pub(crate) fn udp_refused(
+        self,
+        reason: &str,
+    ) {
+        self.count(UDP_ASSOCIATIONS_REFUSED, &[("reason", reason)], 1);
+    }
+
+    // Counts a datagram that a UDP association dropped for the given reason.
+    pub(crate) fn udp_dropped(
+        self,
+        reason: &str,
+    ) {
+        self.count(UDP_DATAGRAMS_DROPPED, &[("reason", reason)], 1);
+    }
+
+    // Records how long each phase of a successful connect took.
+    pub(crate) fn timings(
+        self,
+        timings: &HandshakeTimings,
+    ) {
+        for (phase, duration) in timings.phases() {
+            let labels = [("role", self.role), ("version", self.version), ("phase", phase)];
+            self.metrics.observe_duration(HANDSHAKE_PHASE_DURATION, &labels, duration);
+        }
+    }
+
+    fn count(
+        self,
+        name: &str,
+        labels: &[(&str, &str)],
+        value: u64,
+    ) {
+        let mut all = vec![("role", self.role), ("version", self.version)];
+        all.extend_from_slice(labels);
+        self.metrics.increment_counter(name, &all, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use anyhow::Result;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::constants::*;
+    use crate::test_util::{Harness, MockConnector, MockSocksServer};
+    use crate::{Socks5Client, Socks5Handler, Socks6Client, Socks6Handler};
+
+    // Tests that measurements are looked up regardless of the order of their labels.
+    #[test]
+    fn test_in_memory_metrics() {
+        let metrics = InMemoryMetrics::new();
+        metrics.increment_counter("requests", &[("a", "1"), ("b", "2")], 2);
+        metrics.increment_counter("requests", &[("b", "2"), ("a", "1")], 3);
+        metrics.increment_counter("requests", &[("a", "2")], 1);
+        metrics.set_gauge("open", &[], 4);
+        metrics.observe_duration("duration", &[], Duration::from_secs(1));
+
+        assert_eq!(metrics.counter("requests", &[("a", "1"), ("b", "2")]), 5);
+        assert_eq!(metrics.counter("requests", &[("a", "3")]), 0);
+        assert_eq!(metrics.counter_total("requests"), 6);
+        assert_eq!(metrics.gauge("open", &[]), Some(4));
+        assert_eq!(metrics.durations("duration", &[]), vec![Duration::from_secs(1)]);
+    }
+
+    // Tests that a handler and a client of both versions count the handshake, the reply, and the relayed bytes.
+    #[tokio::test]
+    async fn test_handshake_metrics() -> Result<()> {
+        let handler_metrics = Arc::new(InMemoryMetrics::new());
+        let client_metrics = Arc::new(InMemoryMetrics::new());
+
+        let harness = Harness::socks6(Socks6Handler::default().with_metrics(handler_metrics.clone()));
+        let client = Socks6Client::for_streams(None).with_metrics(client_metrics.clone());
+        let (mut tunnel, _) = harness.connect_socks6(&client, "example.com:80", None, None).await?;
+        tunnel.write_all(b"ping").await?;
+        tunnel.read_exact(&mut [0; 4]).await?;
+        drop(tunnel);
+
+        // The SOCKS5 handler is driven by hand, and the SOCKS5 client talks to a scripted proxy.
+        let harness = Harness::socks5(Socks5Handler::default().with_metrics(handler_metrics.clone()));
+        let mut tunnel = harness.stream();
+        tunnel.write_all(&[SOCKS_VER_5, 1, SOCKS_AUTH_NOT_REQUIRED]).await?;
+        tunnel.read_exact(&mut [0; 2]).await?;
+        tunnel.write_all(&[SOCKS_VER_5, SOCKS_CMD_CONNECT, 0, SOCKS_ATYP_IPV4, 192, 0, 2, 1, 0, 80]).await?;
+        tunnel.read_exact(&mut [0; 10]).await?;
+        tunnel.write_all(b"ping").await?;
+        tunnel.read_exact(&mut [0; 4]).await?;
+        drop(tunnel);
+
+        let proxy_addr = MockSocksServer::socks5().bind().await?;
+        let client = Socks5Client::new(proxy_addr.to_string(), None).await?.with_metrics(client_metrics.clone());
+        client.connect("192.0.2.1:80".to_string(), None).await?;
+
+        let success = SOCKS_REP_SUCCEEDED.to_string();
+        for version in ["5", "6"] {
+            let handler = [("role", "handler"), ("version", version)];
+            let client = [("role", "client"), ("version", version)];
+            assert_eq!(handler_metrics.counter(HANDSHAKES_STARTED, &handler), 1);
+            assert_eq!(handler_metrics.counter(HANDSHAKES_SUCCEEDED, &handler), 1);
+            assert_eq!(client_metrics.counter(HANDSHAKES_SUCCEEDED, &client), 1);
+            assert_eq!(handler_metrics.counter(REPLIES_SENT, &[handler[0], handler[1], ("code", &success)]), 1);
+            assert_eq!(client_metrics.counter(REPLIES_RECEIVED, &[client[0], client[1], ("code", &success)]), 1);
+        }
+
+        // The relays end once the tunnels are dropped.
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while handler_metrics.counter_total(BYTES_RELAYED) < 16 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await?;
+        let labels = [("role", "handler"), ("version", "6"), ("direction", "received")];
+        assert_eq!(handler_metrics.counter(BYTES_RELAYED, &labels), 4);
+
+        Ok(())
+    }
+
+    // Tests that a handshake that fails is counted with its cause on both sides.
+    #[tokio::test]
+    async fn test_handshake_failure_metrics() -> Result<()> {
+        let handler_metrics = Arc::new(InMemoryMetrics::new());
+        let client_metrics = Arc::new(InMemoryMetrics::new());
+
+        let connector = MockConnector::new().with_error(io::ErrorKind::ConnectionRefused);
+        let harness = Harness::with_connector_socks6(
+            Socks6Handler::default().with_metrics(handler_metrics.clone()),
+            connector,
+        );
+        let client = Socks6Client::for_streams(None).with_metrics(client_metrics.clone());
+        assert!(harness.connect_socks6(&client, "example.com:80", None, None).await.is_err());
+
+        let refused = SOCKS_REP_CONNECTION_REFUSED.to_string();
+        let handler = [("role", "handler"), ("version", "6")];
+        let client = [("role", "client"), ("version", "6")];
+        assert_eq!(handler_metrics.counter(HANDSHAKES_FAILED, &[handler[0], handler[1], ("cause", "connect")]), 1);
+        assert_eq!(handler_metrics.counter(REPLIES_SENT, &[handler[0], handler[1], ("code", &refused)]), 1);
+        assert_eq!(client_metrics.counter(REPLIES_RECEIVED, &[client[0], client[1], ("code", &refused)]), 1);
+        assert_eq!(client_metrics.counter_total(HANDSHAKES_FAILED), 1);
+        assert_eq!(client_metrics.counter_total(HANDSHAKES_SUCCEEDED), 0);
+
+        Ok(())
+    }
+}
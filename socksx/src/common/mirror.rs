@@ -0,0 +1,349 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::channel::mpsc;
+use futures::StreamExt;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::session::ConnectionId;
+
+/// The number of chunks a mirror buffers for its sink, by default.
+pub const DEFAULT_MIRROR_CAPACITY: usize = 64;
+
+/// The direction in which a chunk was relayed.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+    /// From the source to the destination.
+    Upstream = 0x00,
+    /// From the destination to the source.
+    Downstream = 0x01,
+}
+
+/// What a mirror does with a chunk when its sink can't keep up.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum MirrorPolicy {
+    /// Leave the chunk out of the capture, so the tunnel never waits for the sink.
+    #[default]
+    Discard,
+    /// Hold up the tunnel until the sink has room for the chunk.
+    Backpressure,
+}
+
+/// A sink that receives a copy of every chunk relayed in a session, e.g., a file to debug a protocol with.
+///
+/// The chunks of both directions are written to the sink in the order they are relayed, each as a frame
+/// of a direction byte (see `Direction`), the length of the chunk as a big-endian `u32`, and the chunk
+/// itself. The sink is shut down once the session ends.
+pub struct Mirror {
+    sink: Box<dyn AsyncWrite + Send + Unpin>,
+    policy: MirrorPolicy,
+    capacity: usize,
+}
+
+impl Mirror {
+    /// Creates a mirror that writes to the sink, discarding chunks it can't keep up with.
+    pub fn new<W: AsyncWrite + Send + Unpin + 'static>(sink: W) -> Self {
+        Self {
+            sink: Box::new(sink),
+            policy: MirrorPolicy::default(),
+            capacity: DEFAULT_MIRROR_CAPACITY,
+        }
+    }
+
+    /// Sets what happens to chunks when the sink can't keep up.
+    pub fn with_policy(
+        mut self,
+        policy: MirrorPolicy,
+    ) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Sets how many chunks are buffered for the sink, before the policy applies.
+    pub fn with_capacity(
+        mut self,
+        capacity: usize,
+    ) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    // Starts writing to the sink in a task of its own, returning the tap the relayed streams feed it with.
+    pub(crate) fn start(
+        self,
+        id: ConnectionId,
+    ) -> Tap {
+        let Mirror {
+            mut sink,
+            policy,
+            capacity,
+        } = self;
+
+        // The channel holds a chunk for its sender beyond its buffer.
+        let (frames, mut receiver) = mpsc::channel::<Bytes>(capacity.saturating_sub(1));
+        let discarded = Arc::new(AtomicU64::new(0));
+
+        let tap = Tap {
+            frames,
+            policy,
+            discarded: Arc::clone(&discarded),
+        };
+        tokio::spawn(async move {
+            while let Some(frame) = receiver.next().await {
+                if let Err(e) = sink.write_all(&frame).await {
+                    warn!("[{}] Stopped mirroring the session: {:?}", id, e);
+                    return;
+                }
+            }
+            if let Err(e) = sink.shutdown().await {
+                warn!("[{}] Failed to close the mirror: {:?}", id, e);
+            }
+
+            let discarded = discarded.load(Ordering::Relaxed);
+            if discarded > 0 {
+                debug!("[{}] Left {} bytes out of the mirror, as it couldn't keep up.", id, discarded);
+            }
+        });
+
+        tap
+    }
+}
+
+// The sending end of a started mirror.
+pub(crate) struct Tap {
+    frames: mpsc::Sender<Bytes>,
+    policy: MirrorPolicy,
+    discarded: Arc<AtomicU64>,
+}
+
+impl Tap {
+    // Waits for room in the channel, if the policy calls for it. A sink that failed never holds up the tunnel.
+    fn poll_room(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<()> {
+        if self.policy == MirrorPolicy::Backpressure {
+            let _ = futures::ready!(self.frames.poll_ready(cx));
+        }
+
+        Poll::Ready(())
+    }
+
+    fn send(
+        &mut self,
+        direction: Direction,
+        chunk: &[u8],
+    ) {
+        let mut frame = BytesMut::with_capacity(1 + 4 + chunk.len());
+        frame.put_u8(direction as u8);
+        frame.put_u32(chunk.len() as u32);
+        frame.put_slice(chunk);
+
+        if let Err(e) = self.frames.try_send(frame.freeze()) {
+            if e.is_full() {
+                self.discarded.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// A stream to the destination of a session, whose writes are copied to a mirror as relayed upstream, and
+/// whose reads as relayed downstream.
+pub(crate) struct MirroredStream<S> {
+    inner: S,
+    tap: Tap,
+}
+
+impl<S> MirroredStream<S> {
+    pub(crate) fn new(
+        inner: S,
+        tap: Tap,
+    ) -> Self {
+        Self { inner, tap }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MirroredStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        futures::ready!(this.tap.poll_room(cx));
+
+        let filled = buf.filled().len();
+        futures::ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if buf.filled().len() > filled {
+            this.tap.send(Direction::Downstream, &buf.filled()[filled..]);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MirroredStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        futures::ready!(this.tap.poll_room(cx));
+
+        let written = futures::ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        if written > 0 {
+            this.tap.send(Direction::Upstream, &buf[..written]);
+        }
+
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use tokio::io::{AsyncReadExt, DuplexStream};
+
+    use crate::session::{SessionHooks, SessionInfo};
+    use crate::test_util::Harness;
+    use crate::{Address, Socks6Client, Socks6Handler};
+
+    use super::*;
+
+    // Hooks that mirror the sessions to port 443, keeping the other end of each sink.
+    #[derive(Default)]
+    struct HttpsOnly {
+        captures: Mutex<Vec<DuplexStream>>,
+    }
+
+    impl SessionHooks for HttpsOnly {
+        fn mirror(
+            &self,
+            session: &SessionInfo,
+        ) -> Option<Mirror> {
+            if !matches!(session.destination, Address::Domainname { port: 443, .. }) {
+                return None;
+            }
+
+            let (sink, capture) = tokio::io::duplex(64 * 1024);
+            self.captures.lock().unwrap().push(capture);
+            Some(Mirror::new(sink))
+        }
+    }
+
+    // Reads the frames of a capture until the sink is closed, concatenating the chunks of each direction.
+    async fn read_capture(capture: &mut DuplexStream) -> Result<(Vec<u8>, Vec<u8>)> {
+        let mut bytes = vec![];
+        capture.read_to_end(&mut bytes).await?;
+
+        let (mut upstream, mut downstream) = (vec![], vec![]);
+        let mut frames = &bytes[..];
+        while !frames.is_empty() {
+            let length = u32::from_be_bytes(frames[1..5].try_into()?) as usize;
+            let chunk = &frames[5..5 + length];
+            match frames[0] {
+                0x00 => upstream.extend_from_slice(chunk),
+                0x01 => downstream.extend_from_slice(chunk),
+                direction => panic!("Unknown direction: {}", direction),
+            }
+            frames = &frames[5 + length..];
+        }
+
+        Ok((upstream, downstream))
+    }
+
+    // Tests that only the sessions the hooks select are mirrored, initial data included.
+    #[tokio::test]
+    async fn test_mirror_selected_sessions() -> Result<()> {
+        let hooks = Arc::new(HttpsOnly::default());
+        let harness = Harness::socks6(Socks6Handler::default().with_hooks(hooks.clone()));
+        let client = Socks6Client::for_streams(None);
+
+        for destination in ["example.com:80", "example.com:443"] {
+            let (mut stream, _) = harness
+                .connect_socks6(&client, destination.to_string(), Some(b"hello".to_vec()), None)
+                .await?;
+            stream.write_all(b" world").await?;
+
+            let mut echoed = [0; 11];
+            stream.read_exact(&mut echoed).await?;
+        }
+
+        let mut capture = hooks.captures.lock().unwrap().pop().unwrap();
+        assert!(hooks.captures.lock().unwrap().is_empty());
+
+        let (upstream, downstream) = read_capture(&mut capture).await?;
+        assert_eq!(upstream, b"hello world");
+        assert_eq!(downstream, b"hello world");
+
+        Ok(())
+    }
+
+    // Writes 512 bytes through a mirrored stream whose sink stalls until the writes are done, or until a
+    // while has passed, returning whether the writes were held up, and how many bytes were captured.
+    async fn capture_stalled(policy: MirrorPolicy) -> Result<(bool, usize)> {
+        let (sink, mut capture) = tokio::io::duplex(64);
+        let tap = Mirror::new(sink).with_policy(policy).with_capacity(4).start(ConnectionId::generate());
+
+        let (inner, _peer) = tokio::io::duplex(64 * 1024);
+        let mut stream = MirroredStream::new(inner, tap);
+        let writing = tokio::spawn(async move {
+            for _ in 0..32 {
+                stream.write_all(&[0xAB; 16]).await?;
+            }
+            Ok::<_, io::Error>(())
+        });
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let held_up = !writing.is_finished();
+
+        let (upstream, _) = read_capture(&mut capture).await?;
+        writing.await??;
+
+        Ok((held_up, upstream.len()))
+    }
+
+    // Tests that a stalled sink loses chunks by default, without holding up the tunnel.
+    #[tokio::test]
+    async fn test_discard_policy() -> Result<()> {
+        let (held_up, captured) = capture_stalled(MirrorPolicy::Discard).await?;
+        assert!(!held_up);
+        assert!(captured < 512, "captured {} bytes", captured);
+
+        Ok(())
+    }
+
+    // Tests that a stalled sink holds up the tunnel with backpressure, and loses nothing.
+    #[tokio::test]
+    async fn test_backpressure_policy() -> Result<()> {
+        let (held_up, captured) = capture_stalled(MirrorPolicy::Backpressure).await?;
+        assert!(held_up);
+        assert_eq!(captured, 512);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,530 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+
+use crate::credentials::{constant_time_eq, PasswordVerifier};
+use crate::socks6::auth::{self, AuthOutcome, AuthRequest, Authenticator};
+use crate::socks6::options::AuthMethod;
+
+/// A password hashing scheme the hashes of a password file are made with, e.g., bcrypt or argon2id.
+///
+/// Schemes wrap a hashing library, so the password file doesn't tie the proxy to one of them. The `bcrypt` and
+/// `argon2` features provide `Bcrypt` and `Argon2id`.
+pub trait HashScheme {
+    /// Returns whether the hash was made with this scheme, typically judging by its prefix, e.g., `$2b$` for
+    /// bcrypt or `$argon2id$` for argon2id.
+    fn recognizes(
+        &self,
+        hash: &str,
+    ) -> bool;
+
+    /// Hashes the password with the parameters and salt of the stored hash, encoded like it, so that the result
+    /// equals the stored hash if, and only if, the password is the right one.
+    fn rehash(
+        &self,
+        password: &[u8],
+        stored: &str,
+    ) -> Result<String>;
+}
+
+/// The bcrypt scheme, for hashes with any of the `$2a$`, `$2b$`, `$2x$`, and `$2y$` prefixes, e.g., those of
+/// `htpasswd -B`. Note that bcrypt only hashes the first 72 bytes of a password.
+#[cfg(feature = "bcrypt")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Bcrypt;
+
+#[cfg(feature = "bcrypt")]
+impl HashScheme for Bcrypt {
+    fn recognizes(
+        &self,
+        hash: &str,
+    ) -> bool {
+        ["$2a$", "$2b$", "$2x$", "$2y$"].iter().any(|prefix| hash.starts_with(prefix))
+    }
+
+    fn rehash(
+        &self,
+        password: &[u8],
+        stored: &str,
+    ) -> Result<String> {
+        use std::convert::TryFrom;
+
+        use base64::Engine;
+        use bcrypt::Version;
+
+        let version = match stored.get(1..3) {
+            Some("2a") => Version::TwoA,
+            Some("2x") => Version::TwoX,
+            Some("2y") => Version::TwoY,
+            _ => Version::TwoB,
+        };
+        let parts: bcrypt::HashParts = stored.parse()?;
+        let salt = bcrypt::BASE_64.decode(parts.get_salt())?;
+        let salt = <[u8; 16]>::try_from(salt).map_err(|salt| anyhow!("Invalid salt length: {}", salt.len()))?;
+
+        Ok(bcrypt::hash_with_salt(password, parts.get_cost(), salt)?.format_for_version(version))
+    }
+}
+
+/// The argon2id scheme, for hashes in the PHC string format with the `$argon2id$` prefix, e.g.,
+/// `$argon2id$v=19$m=19456,t=2,p=1$<salt>$<hash>`.
+#[cfg(feature = "argon2")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Argon2id;
+
+#[cfg(feature = "argon2")]
+impl HashScheme for Argon2id {
+    fn recognizes(
+        &self,
+        hash: &str,
+    ) -> bool {
+        hash.starts_with("$argon2id$")
+    }
+
+    fn rehash(
+        &self,
+        password: &[u8],
+        stored: &str,
+    ) -> Result<String> {
+        use std::convert::TryFrom;
+
+        use argon2::{Argon2, Params, PasswordHash, PasswordHasher};
+
+        let parsed = PasswordHash::new(stored)?;
+        let salt = parsed.salt.context("Missing salt")?;
+        let params = Params::try_from(&parsed)?;
+        let rehashed = Argon2::default().hash_password_customized(
+            password,
+            Some(parsed.algorithm),
+            parsed.version,
+            params,
+            salt,
+        )?;
+
+        Ok(rehashed.to_string())
+    }
+}
+
+// The hash of a user's password, along with the scheme it was made with.
+#[derive(Clone)]
+struct Entry {
+    hash: String,
+    scheme: Arc<dyn HashScheme + Send + Sync>,
+}
+
+/// Authenticates clients with the usernames and password hashes of a file, in the format of htpasswd: a
+/// `username:hash` line per user. Empty lines, and lines starting with `#`, are skipped.
+///
+/// It serves as the `Authenticator` of a `Socks6Handler`, and as the `PasswordVerifier` of a `Socks5Handler`.
+/// Hashes are compared in constant time, and the passwords of unknown users are hashed like those of the first
+/// user in the file, so the time it takes to reject them doesn't reveal which usernames exist. The file is read
+/// when the authenticator is loaded, and again on `reload`, which clones see as well.
+#[derive(Clone)]
+pub struct PasswordFileAuthenticator {
+    path: PathBuf,
+    schemes: Vec<Arc<dyn HashScheme + Send + Sync>>,
+    users: Arc<RwLock<Users>>,
+}
+
+// The entries of a password file by username, and the entry that the passwords of unknown users are hashed with.
+#[derive(Default)]
+struct Users {
+    entries: HashMap<String, Entry>,
+    dummy: Option<Entry>,
+}
+
+impl PasswordFileAuthenticator {
+    /// Loads the password file at the path, whose hashes are made with any of the schemes.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the authenticator, or an error that names the line number of every malformed line,
+    /// e.g., one without a `:`, or with a hash none of the schemes recognizes.
+    pub fn load<P: Into<PathBuf>>(
+        path: P,
+        schemes: Vec<Arc<dyn HashScheme + Send + Sync>>,
+    ) -> Result<Self> {
+        let authenticator = Self {
+            path: path.into(),
+            schemes,
+            users: Arc::default(),
+        };
+        authenticator.reload()?;
+
+        Ok(authenticator)
+    }
+
+    /// Returns the path of the password file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Reads the password file again, e.g., after users have been added.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the number of users, or an error like `load`, in which case the users read before
+    /// are kept.
+    pub fn reload(&self) -> Result<usize> {
+        let contents = fs::read_to_string(&self.path)
+            .with_context(|| format!("Failed to read password file {}", self.path.display()))?;
+        let users = parse(&contents, &self.schemes)
+            .with_context(|| format!("Malformed password file {}", self.path.display()))?;
+
+        let count = users.entries.len();
+        *self.users.write().expect("Password file lock poisoned") = users;
+        info!("Loaded {} user(s) from {}.", count, self.path.display());
+
+        Ok(count)
+    }
+
+    /// Returns the number of users.
+    pub fn len(&self) -> usize {
+        self.users.read().expect("Password file lock poisoned").entries.len()
+    }
+
+    /// Returns whether the file has no users, in which case no client is authenticated.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Returns the entry of the user, and whether the user is known: if the username isn't valid UTF-8 or in the
+    // file, the dummy entry is returned instead, if the file has any users.
+    fn entry(
+        &self,
+        username: &[u8],
+    ) -> Option<(Entry, bool)> {
+        let users = self.users.read().expect("Password file lock poisoned");
+        let entry = std::str::from_utf8(username).ok().and_then(|username| users.entries.get(username));
+        match entry {
+            Some(entry) => Some((entry.clone(), true)),
+            None => users.dummy.clone().map(|dummy| (dummy, false)),
+        }
+    }
+}
+
+impl fmt::Debug for PasswordFileAuthenticator {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        f.debug_struct("PasswordFileAuthenticator")
+            .field("path", &self.path)
+            .field("users", &self.len())
+            .finish()
+    }
+}
+
+#[async_trait]
+impl PasswordVerifier for PasswordFileAuthenticator {
+    async fn verify(
+        &self,
+        username: &[u8],
+        password: &[u8],
+    ) -> bool {
+        let Some((Entry { hash, scheme }, known)) = self.entry(username) else {
+            return false;
+        };
+
+        // Password hashes are slow by design, so they are computed off the runtime's threads.
+        let password = password.to_vec();
+        let rehashed = tokio::task::spawn_blocking(move || {
+            let rehashed = scheme.rehash(&password, &hash)?;
+            Ok::<_, anyhow::Error>(constant_time_eq(rehashed.as_bytes(), hash.as_bytes()))
+        });
+
+        match rehashed.await {
+            Ok(Ok(matches)) => matches && known,
+            Ok(Err(e)) => {
+                warn!("Failed to hash the password of {}: {:#}", String::from_utf8_lossy(username), e);
+                false
+            }
+            Err(e) => {
+                warn!("Failed to hash the password of {}: {}", String::from_utf8_lossy(username), e);
+                false
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Authenticator for PasswordFileAuthenticator {
+    fn methods(&self) -> Vec<AuthMethod> {
+        vec![AuthMethod::UsernamePassword]
+    }
+
+    async fn authenticate(
+        &self,
+        request: AuthRequest<'_>,
+    ) -> AuthOutcome {
+        auth::authenticate_user_pass(self, request).await
+    }
+}
+
+// Parses the lines of a password file, collecting the line numbers of all malformed lines. The entry of the first
+// user is the dummy entry.
+fn parse(
+    contents: &str,
+    schemes: &[Arc<dyn HashScheme + Send + Sync>],
+) -> Result<Users> {
+    let mut users = Users::default();
+    let mut malformed = vec![];
+
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match parse_line(line, schemes) {
+            Ok((username, _)) if users.entries.contains_key(&username) => {
+                malformed.push(format!("line {}: duplicate user {}", i + 1, username))
+            }
+            Ok((username, entry)) => {
+                users.dummy.get_or_insert_with(|| entry.clone());
+                users.entries.insert(username, entry);
+            }
+            Err(e) => malformed.push(format!("line {}: {}", i + 1, e)),
+        }
+    }
+
+    ensure!(malformed.is_empty(), "{}", malformed.join(", "));
+    Ok(users)
+}
+
+// Parses a `username:hash` line.
+fn parse_line(
+    line: &str,
+    schemes: &[Arc<dyn HashScheme + Send + Sync>],
+) -> Result<(String, Entry)> {
+    let (username, hash) = line.split_once(':').context("missing ':' between username and hash")?;
+    ensure!(!username.is_empty(), "empty username");
+    ensure!(username.len() <= 255, "username longer than 255 bytes");
+
+    let scheme = schemes
+        .iter()
+        .find(|scheme| scheme.recognizes(hash))
+        .with_context(|| format!("unsupported hash scheme for user {}", username))?;
+    let entry = Entry {
+        hash: hash.to_string(),
+        scheme: Arc::clone(scheme),
+    };
+
+    Ok((username.to_string(), entry))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Write;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::constants::*;
+    use crate::socks5::Socks5Reply;
+    use crate::test_util::Harness;
+    use crate::{Credentials, Socks5Handler, Socks6Client, Socks6Handler};
+
+    const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/common/testdata/htpasswd");
+    const MALFORMED_FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/common/testdata/htpasswd-malformed");
+    #[cfg(feature = "bcrypt")]
+    const BCRYPT_FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/common/testdata/htpasswd-bcrypt");
+    #[cfg(feature = "argon2")]
+    const ARGON2ID_FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/common/testdata/htpasswd-argon2id");
+
+    // Scheme of the fixtures, which hashes into `$test$<salt>$<hex of salt and password>`. It isn't a hash at all,
+    // but it does take its parameters from the stored hash, like real schemes.
+    struct TestScheme;
+
+    impl HashScheme for TestScheme {
+        fn recognizes(
+            &self,
+            hash: &str,
+        ) -> bool {
+            hash.starts_with("$test$")
+        }
+
+        fn rehash(
+            &self,
+            password: &[u8],
+            stored: &str,
+        ) -> Result<String> {
+            let salt = stored.split('$').nth(2).context("Missing salt")?;
+            let mut hash = format!("$test${}$", salt);
+            for byte in salt.as_bytes().iter().chain(password) {
+                write!(hash, "{:02x}", byte)?;
+            }
+
+            Ok(hash)
+        }
+    }
+
+    // Test scheme that counts the passwords it hashed.
+    #[derive(Default)]
+    struct CountingScheme {
+        rehashed: AtomicUsize,
+    }
+
+    impl HashScheme for CountingScheme {
+        fn recognizes(
+            &self,
+            hash: &str,
+        ) -> bool {
+            TestScheme.recognizes(hash)
+        }
+
+        fn rehash(
+            &self,
+            password: &[u8],
+            stored: &str,
+        ) -> Result<String> {
+            self.rehashed.fetch_add(1, Ordering::Relaxed);
+            TestScheme.rehash(password, stored)
+        }
+    }
+
+    fn load(path: &str) -> Result<PasswordFileAuthenticator> {
+        PasswordFileAuthenticator::load(path, vec![Arc::new(TestScheme)])
+    }
+
+    // Tests that the right passwords are accepted, and wrong passwords and unknown users rejected.
+    #[tokio::test]
+    async fn test_verify() -> Result<()> {
+        let authenticator = load(FIXTURE)?;
+        assert_eq!(authenticator.len(), 2);
+
+        assert!(authenticator.verify(b"alice", b"wonderland").await);
+        assert!(authenticator.verify(b"bob", b"builder").await);
+        assert!(!authenticator.verify(b"alice", b"builder").await);
+        assert!(!authenticator.verify(b"alice", b"").await);
+        assert!(!authenticator.verify(b"carol", b"wonderland").await);
+        assert!(!authenticator.verify(b"\xff", b"wonderland").await);
+
+        Ok(())
+    }
+
+    // Tests that the passwords of a file with bcrypt hashes are verified, whatever the version of the prefix.
+    #[cfg(feature = "bcrypt")]
+    #[tokio::test]
+    async fn test_bcrypt() -> Result<()> {
+        let authenticator = PasswordFileAuthenticator::load(BCRYPT_FIXTURE, vec![Arc::new(Bcrypt)])?;
+        assert_eq!(authenticator.len(), 2);
+
+        assert!(authenticator.verify(b"alice", b"wonderland").await);
+        assert!(authenticator.verify(b"bob", b"builder").await);
+        assert!(!authenticator.verify(b"alice", b"builder").await);
+        assert!(!authenticator.verify(b"bob", b"Builder").await);
+        assert!(!Bcrypt.recognizes("$argon2id$v=19$m=64,t=1,p=1$c2FsdA$aGFzaA"));
+
+        Ok(())
+    }
+
+    // Tests that the passwords of a file with argon2id hashes are verified, with the parameters of each hash.
+    #[cfg(feature = "argon2")]
+    #[tokio::test]
+    async fn test_argon2id() -> Result<()> {
+        let authenticator = PasswordFileAuthenticator::load(ARGON2ID_FIXTURE, vec![Arc::new(Argon2id)])?;
+        assert_eq!(authenticator.len(), 2);
+
+        assert!(authenticator.verify(b"alice", b"wonderland").await);
+        assert!(authenticator.verify(b"bob", b"builder").await);
+        assert!(!authenticator.verify(b"alice", b"builder").await);
+        assert!(!authenticator.verify(b"bob", b"").await);
+        assert!(!Argon2id.recognizes("$argon2i$v=19$m=64,t=1,p=1$c2FsdA$aGFzaA"));
+
+        Ok(())
+    }
+
+    // Tests that the passwords of unknown users are hashed like those of known users, and never accepted, even if
+    // they are the password of the dummy entry.
+    #[tokio::test]
+    async fn test_unknown_users_hashed() -> Result<()> {
+        let scheme = Arc::new(CountingScheme::default());
+        let authenticator = PasswordFileAuthenticator::load(FIXTURE, vec![scheme.clone()])?;
+
+        assert!(!authenticator.verify(b"carol", b"wonderland").await);
+        assert!(!authenticator.verify(b"\xff", b"builder").await);
+        assert_eq!(scheme.rehashed.load(Ordering::Relaxed), 2);
+        assert!(authenticator.verify(b"alice", b"wonderland").await);
+        assert_eq!(scheme.rehashed.load(Ordering::Relaxed), 3);
+
+        Ok(())
+    }
+
+    // Tests that loading a file with malformed lines fails, naming the line number of each.
+    #[test]
+    fn test_malformed_lines() {
+        let error = format!("{:#}", load(MALFORMED_FIXTURE).unwrap_err());
+        assert!(error.contains("line 3: missing ':'"), "{}", error);
+        assert!(error.contains("line 4: empty username"), "{}", error);
+        assert!(error.contains("line 5: unsupported hash scheme for user carol"), "{}", error);
+        assert!(error.contains("line 6: duplicate user alice"), "{}", error);
+        assert!(!error.contains("line 2"), "{}", error);
+
+        assert!(load("/nonexistent/htpasswd").is_err());
+    }
+
+    // Tests that reloading picks up changes to the file, and keeps the users when the file has become malformed.
+    #[tokio::test]
+    async fn test_reload() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("socksx-htpasswd-{}", std::process::id()));
+        fs::copy(FIXTURE, &path)?;
+        let authenticator = PasswordFileAuthenticator::load(&path, vec![Arc::new(TestScheme)])?;
+        let clone = authenticator.clone();
+
+        fs::write(&path, "bob:$test$salt$73616c7472656275696c74\n")?;
+        assert_eq!(authenticator.reload()?, 1);
+        assert!(clone.verify(b"bob", b"rebuilt").await);
+        assert!(!clone.verify(b"bob", b"builder").await);
+        assert!(!clone.verify(b"alice", b"wonderland").await);
+
+        fs::write(&path, "bob\n")?;
+        assert!(authenticator.reload().is_err());
+        assert!(clone.verify(b"bob", b"rebuilt").await);
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    // Tests that both handlers authenticate their clients against the file.
+    #[tokio::test]
+    async fn test_handlers() -> Result<()> {
+        let authenticator = Arc::new(load(FIXTURE)?);
+
+        let handler = Socks5Handler::default().with_password_verifier(authenticator.clone());
+        for (password, status) in [(&b"wonderland"[..], SOCKS_AUTH_SUCCESS), (b"builder", SOCKS_AUTH_FAILED)] {
+            let mut handshake = vec![SOCKS_VER_5, 0x01, SOCKS_AUTH_USERNAME_PASSWORD, SOCKS_AUTH_VER, 0x05];
+            handshake.extend_from_slice(b"alice");
+            handshake.push(password.len() as u8);
+            handshake.extend_from_slice(password);
+            let request = [SOCKS_VER_5, SOCKS_CMD_CONNECT, SOCKS_RSV, SOCKS_ATYP_IPV4, 192, 0, 2, 1, 0, 80];
+            handshake.extend_from_slice(&request);
+
+            let (mut client, mut source) = tokio::io::duplex(4096);
+            client.write_all(&handshake).await?;
+            match handler.accept(&mut source).await {
+                Ok((session, _)) => session.reject(Socks5Reply::ConnectionNotAllowed).await?,
+                Err(_) => assert_eq!(status, SOCKS_AUTH_FAILED),
+            }
+
+            let mut replies = [0; 4];
+            client.read_exact(&mut replies).await?;
+            assert_eq!(replies[3], status);
+        }
+
+        let harness = Harness::socks6(Socks6Handler::default().with_authenticator(authenticator));
+        let client = Socks6Client::for_streams(Some(Credentials::new("bob", "builder")));
+        harness.connect_socks6(&client, "192.0.2.1:80", None, None).await?;
+        let client = Socks6Client::for_streams(Some(Credentials::new("bob", "wonderland")));
+        assert!(harness.connect_socks6(&client, "192.0.2.1:80", None, None).await.is_err());
+        assert_eq!(harness.connector().destinations().len(), 1);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,323 @@
+use std::future::{self, Future};
+use std::sync::Arc;
+
+use tokio::sync::watch;
+
+use crate::addresses::normalize_domain;
+use crate::session::SessionInfo;
+use crate::Address;
+
+/// What a policy decides for a session.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Action {
+    /// Let the session through.
+    Allow,
+    /// Refuse the request, with a ConnectionNotAllowed reply.
+    Deny,
+}
+
+/// A rule of a policy, which applies its action to the sessions it matches.
+///
+/// A rule without conditions matches every session.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Rule {
+    action: Action,
+    host: Option<String>,
+    port: Option<u16>,
+    identity: Option<String>,
+}
+
+impl Rule {
+    /// Creates a rule that allows the sessions it matches.
+    pub fn allow() -> Self {
+        Self::new(Action::Allow)
+    }
+
+    /// Creates a rule that denies the sessions it matches.
+    pub fn deny() -> Self {
+        Self::new(Action::Deny)
+    }
+
+    fn new(action: Action) -> Self {
+        Self {
+            action,
+            host: None,
+            port: None,
+            identity: None,
+        }
+    }
+
+    /// Only matches destinations with the given host, ignoring case. A host of the form `*.example.com`
+    /// matches the subdomains of `example.com`, and an IP address matches destinations given as that address.
+    /// Internationalized domain names match in either form, as destinations are normalized to punycode.
+    ///
+    /// Destinations are matched as requested, before they are resolved, so a destination given as a name only
+    /// matches rules for that name, regardless of the `ResolutionPolicy` of the handler.
+    pub fn for_host<S: Into<String>>(
+        mut self,
+        host: S,
+    ) -> Self {
+        let host = host.into();
+        let (wildcard, domain) = match host.strip_prefix("*.") {
+            Some(domain) => ("*.", domain),
+            None => ("", host.as_str()),
+        };
+        let domain = normalize_domain(domain).unwrap_or_else(|_| domain.to_string());
+
+        self.host = Some(format!("{}{}", wildcard, domain.to_ascii_lowercase()));
+        self
+    }
+
+    /// Only matches destinations with the given port.
+    pub fn for_port(
+        mut self,
+        port: u16,
+    ) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    /// Only matches sessions whose source authenticated with the given name.
+    pub fn for_identity<S: Into<String>>(
+        mut self,
+        identity: S,
+    ) -> Self {
+        self.identity = Some(identity.into());
+        self
+    }
+
+    /// Returns whether the rule applies to the session.
+    ///
+    /// Unix socket destinations only match rules without conditions on the host and port.
+    pub fn matches(
+        &self,
+        session: &SessionInfo,
+    ) -> bool {
+        let identity_matches = self.identity.as_ref().is_none_or(|i| session.identity.as_ref() == Some(i));
+        let (host, port) = match &session.destination {
+            Address::Domainname { host, port } => (host.to_ascii_lowercase(), *port),
+            Address::Ip(addr) => (addr.ip().to_string(), addr.port()),
+            // Unix socket paths have neither a host nor a port to match.
+            Address::Unix(_) => return self.host.is_none() && self.port.is_none() && identity_matches,
+        };
+
+        let host_matches = match &self.host {
+            Some(pattern) => match pattern.strip_prefix("*.") {
+                Some(domain) => host.strip_suffix(domain).is_some_and(|sub| sub.ends_with('.')),
+                None => &host == pattern,
+            },
+            None => true,
+        };
+
+        host_matches && self.port.is_none_or(|p| p == port) && identity_matches
+    }
+}
+
+/// Decides which sessions a handler lets through, e.g., an allowlist of destinations.
+///
+/// The action of the first rule that matches a session applies, or the default action if none does.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Policy {
+    rules: Vec<Rule>,
+    default: Action,
+}
+
+impl Policy {
+    /// Creates a policy that allows every session, unless a rule denies it.
+    pub fn allow_all() -> Self {
+        Self {
+            rules: vec![],
+            default: Action::Allow,
+        }
+    }
+
+    /// Creates a policy that denies every session, unless a rule allows it.
+    pub fn deny_all() -> Self {
+        Self {
+            rules: vec![],
+            default: Action::Deny,
+        }
+    }
+
+    /// Adds a rule, after the rules added before it.
+    pub fn with_rule(
+        mut self,
+        rule: Rule,
+    ) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Returns the action for the session.
+    pub fn decide(
+        &self,
+        session: &SessionInfo,
+    ) -> Action {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(session))
+            .map_or(self.default, |rule| rule.action)
+    }
+
+    /// Returns whether the session is allowed.
+    pub fn allows(
+        &self,
+        session: &SessionInfo,
+    ) -> bool {
+        self.decide(session) == Action::Allow
+    }
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self::allow_all()
+    }
+}
+
+// The policy of a handler, which can be replaced while the handler serves sessions. Clones of a handler share it.
+#[derive(Clone)]
+pub(crate) struct SharedPolicy {
+    policy: Arc<watch::Sender<Arc<Policy>>>,
+}
+
+impl SharedPolicy {
+    pub(crate) fn new(policy: Policy) -> Self {
+        Self {
+            policy: Arc::new(watch::Sender::new(Arc::new(policy))),
+        }
+    }
+
+    // Returns the policy for new requests.
+    pub(crate) fn current(&self) -> Arc<Policy> {
+        Arc::clone(&self.policy.borrow())
+    }
+
+    pub(crate) fn update(
+        &self,
+        policy: Policy,
+    ) {
+        self.policy.send_replace(Arc::new(policy));
+    }
+
+    // Completes once the session is no longer allowed, if established sessions are re-evaluated against
+    // policy updates. Otherwise, a session keeps the decision it was accepted with, and this never completes.
+    pub(crate) fn revoked(
+        &self,
+        session: SessionInfo,
+        reevaluate: bool,
+    ) -> impl Future<Output = ()> + Send + 'static {
+        let mut policies = self.policy.subscribe();
+
+        async move {
+            if reevaluate {
+                // The policy may have been updated since the session was accepted.
+                while policies.borrow_and_update().allows(&session) {
+                    if policies.changed().await.is_err() {
+                        break;
+                    }
+                }
+                if !policies.borrow().allows(&session) {
+                    return;
+                }
+            }
+
+            future::pending().await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::session::ConnectionId;
+    use crate::test_util::Harness;
+    use crate::{Baggage, Socks6Client, Socks6Handler};
+
+    use super::*;
+
+    fn session(
+        destination: &str,
+        identity: Option<&str>,
+    ) -> SessionInfo {
+        SessionInfo {
+            id: ConnectionId::generate(),
+            destination: Address::try_from(destination).unwrap(),
+            identity: identity.map(String::from),
+            baggage: Baggage::new(),
+        }
+    }
+
+    // Tests that the first matching rule decides, and the default applies otherwise.
+    #[test]
+    fn test_policy_rules() {
+        let policy = Policy::deny_all()
+            .with_rule(Rule::deny().for_host("internal.example.com"))
+            .with_rule(Rule::allow().for_host("*.example.com").for_port(443))
+            .with_rule(Rule::allow().for_identity("admin"))
+            .with_rule(Rule::allow().for_host("192.0.2.1"));
+
+        assert!(policy.allows(&session("www.Example.com:443", None)));
+        assert!(policy.allows(&session("192.0.2.1:22", None)));
+        assert!(policy.allows(&session("internal.example.org:80", Some("admin"))));
+        assert!(!policy.allows(&session("internal.example.com:443", Some("admin"))));
+        assert!(!policy.allows(&session("www.example.com:80", None)));
+        assert!(!policy.allows(&session("example.com:443", None)));
+        assert!(!policy.allows(&session("notexample.com:443", None)));
+        assert!(!policy.allows(&session("internal.example.org:80", Some("guest"))));
+
+        // Internationalized domain names match in either form.
+        if !cfg!(feature = "idna") {
+            return;
+        }
+        let policy = Policy::deny_all().with_rule(Rule::allow().for_host("*.bücher.example"));
+        assert!(policy.allows(&session("www.xn--bcher-kva.example:443", None)));
+        assert!(policy.allows(&session("www.Bücher.example:443", None)));
+    }
+
+    // Opens a tunnel through the harness, and checks that it echoes.
+    async fn open_tunnel(
+        harness: &Harness,
+        client: &Socks6Client,
+    ) -> Result<tokio::io::DuplexStream> {
+        let (mut tunnel, _) = harness.connect_socks6(client, "example.com:80", None, None).await?;
+        tunnel.write_all(b"ping").await?;
+        let mut echoed = [0; 4];
+        tunnel.read_exact(&mut echoed).await?;
+        assert_eq!(&echoed, b"ping");
+
+        Ok(tunnel)
+    }
+
+    // Tests that an updated policy applies to new requests, and to established tunnels only if re-evaluated.
+    #[tokio::test]
+    async fn test_update_policy() -> Result<()> {
+        for reevaluate in [false, true] {
+            let handler = Socks6Handler::default()
+                .with_policy(Policy::allow_all())
+                .with_policy_reevaluation(reevaluate);
+            let harness = Harness::socks6(handler);
+            let client = Socks6Client::for_streams(None);
+
+            let mut tunnel = open_tunnel(&harness, &client).await?;
+            harness.handler().update_policy(Policy::deny_all())?;
+            assert!(harness.connect_socks6(&client, "example.com:80", None, None).await.is_err());
+
+            if reevaluate {
+                let closed = tokio::time::timeout(Duration::from_secs(5), tunnel.read(&mut [0; 1])).await??;
+                assert_eq!(closed, 0);
+            } else {
+                tunnel.write_all(b"pong").await?;
+                let mut echoed = [0; 4];
+                tunnel.read_exact(&mut echoed).await?;
+                assert_eq!(&echoed, b"pong");
+            }
+        }
+
+        Ok(())
+    }
+}
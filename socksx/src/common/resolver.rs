@@ -0,0 +1,370 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::net;
+use tokio::sync::OnceCell;
+use tokio::time::Instant;
+
+use crate::constants::SOCKS_ATYP_DOMAINNAME;
+use crate::dialer::Dialer;
+use crate::{Address, SocksError};
+
+/// An asynchronous trait for resolving domain names into IP addresses.
+#[async_trait]
+pub trait Resolver {
+    /// Resolves a domain name into its IP addresses, in DNS order.
+    ///
+    /// # Parameters
+    ///
+    /// * `host`: The domain name to resolve.
+    ///
+    /// # Returns
+    ///
+    /// Returns a `Result` containing the non-empty list of IP addresses or an error.
+    async fn resolve(
+        &self,
+        host: &str,
+    ) -> Result<Vec<IpAddr>>;
+}
+
+/// Resolves domain names using the resolver of the operating system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemResolver;
+
+#[async_trait]
+impl Resolver for SystemResolver {
+    async fn resolve(
+        &self,
+        host: &str,
+    ) -> Result<Vec<IpAddr>> {
+        let addresses: Vec<IpAddr> = net::lookup_host((host, 0)).await?.map(|a| a.ip()).collect();
+        ensure!(!addresses.is_empty(), "Domain name didn't resolve to an IP address.");
+
+        Ok(addresses)
+    }
+}
+
+/// Where a handler resolves the domain name destinations of its requests: at its own hop, or further upstream,
+/// i.e., at the next link of a chain or through a custom connector (`with_connector`).
+///
+/// The policy of a handler (`with_policy`) always decides on the destination as requested, before anything is
+/// resolved, so a rule for a host sees the name, never the addresses it resolves to. The addresses are only checked
+/// by the `DestinationGuard`, where they are resolved at this hop.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ResolutionPolicy {
+    /// Resolves names at this hop. Upstream is handed the address a connect would try first, instead of the name.
+    ResolveLocal,
+    /// Passes names on unresolved to the next link of a chain, so that the last link resolves them, and resolves
+    /// them at this hop otherwise. A custom connector is handed the name as well.
+    #[default]
+    PassThroughWhenChaining,
+    /// Never resolves names at this hop. They are passed on unresolved upstream, and refused with
+    /// `SocksError::UnsupportedAddressType` where there is no next link or custom connector to take them.
+    AlwaysPassThrough,
+}
+
+impl ResolutionPolicy {
+    /// Returns the destination to hand on: upstream, if there is an upstream, or to the default connector, which
+    /// resolves a name itself. A resolution at this hop that doesn't complete in time fails with
+    /// `SocksError::ConnectTimeout`.
+    pub(crate) async fn destination_for(
+        self,
+        destination: &Address,
+        upstream: bool,
+        dialer: &Dialer,
+        timeout: Duration,
+    ) -> Result<Address> {
+        if !matches!(destination, Address::Domainname { .. }) {
+            return Ok(destination.clone());
+        }
+
+        match self {
+            ResolutionPolicy::ResolveLocal if upstream => {
+                match tokio::time::timeout(timeout, dialer.resolve_preferred(destination)).await {
+                    Ok(resolved) => resolved,
+                    Err(_) => Err(SocksError::ConnectTimeout(timeout).into()),
+                }
+            }
+            ResolutionPolicy::AlwaysPassThrough if !upstream => {
+                Err(SocksError::UnsupportedAddressType(SOCKS_ATYP_DOMAINNAME).into())
+            }
+            _ => Ok(destination.clone()),
+        }
+    }
+}
+
+/// Counters that describe the effectiveness of a `CachingResolver`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    /// The number of resolutions answered from the cache, including shared in-flight lookups.
+    pub hits: u64,
+    /// The number of resolutions that required a lookup by the inner resolver.
+    pub misses: u64,
+}
+
+// The outcome of a lookup, shared by every caller that asked for the same name.
+struct Resolution {
+    result: std::result::Result<Vec<IpAddr>, String>,
+    resolved_at: Instant,
+}
+
+struct Entry {
+    resolution: Arc<OnceCell<Resolution>>,
+    last_used: u64,
+}
+
+/// A resolver that caches the positive and negative results of an inner resolver.
+///
+/// The real TTLs of the records are not visible through the `Resolver` trait, so entries expire
+/// after a configured maximum. Concurrent resolutions of the same name share a single lookup, and
+/// the least recently used entry is evicted when the cache is full.
+pub struct CachingResolver<R> {
+    inner: R,
+    capacity: usize,
+    max_ttl: Duration,
+    negative_ttl: Duration,
+    entries: Mutex<HashMap<String, Entry>>,
+    clock: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<R: Resolver> CachingResolver<R> {
+    /// Creates a new `CachingResolver` in front of the given resolver.
+    ///
+    /// # Parameters
+    ///
+    /// * `inner`: The resolver used on cache misses.
+    /// * `capacity`: The maximum number of cached names.
+    /// * `max_ttl`: How long successful resolutions are cached.
+    /// * `negative_ttl`: How long failed resolutions are cached.
+    pub fn new(
+        inner: R,
+        capacity: usize,
+        max_ttl: Duration,
+        negative_ttl: Duration,
+    ) -> Self {
+        Self {
+            inner,
+            capacity,
+            max_ttl,
+            negative_ttl,
+            entries: Mutex::new(HashMap::new()),
+            clock: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the hit and miss counters of the cache.
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Returns the number of names currently in the cache.
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    /// Returns `true` if the cache holds no names.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Returns the shared resolution cell for the name, replacing it if it has expired.
+    fn entry(
+        &self,
+        host: &str,
+    ) -> Arc<OnceCell<Resolution>> {
+        let now = self.clock.fetch_add(1, Ordering::Relaxed);
+        let mut entries = self.entries.lock().unwrap();
+
+        if let Some(entry) = entries.get_mut(host) {
+            let expired = entry.resolution.get().is_some_and(|r| {
+                let ttl = if r.result.is_ok() { self.max_ttl } else { self.negative_ttl };
+                r.resolved_at.elapsed() >= ttl
+            });
+
+            if !expired {
+                entry.last_used = now;
+                return Arc::clone(&entry.resolution);
+            }
+        } else if entries.len() >= self.capacity {
+            let least_recently_used = entries.iter().min_by_key(|(_, e)| e.last_used).map(|(h, _)| h.clone());
+            if let Some(host) = least_recently_used {
+                entries.remove(&host);
+            }
+        }
+
+        let resolution = Arc::new(OnceCell::new());
+        entries.insert(
+            host.to_string(),
+            Entry {
+                resolution: Arc::clone(&resolution),
+                last_used: now,
+            },
+        );
+
+        resolution
+    }
+}
+
+#[async_trait]
+impl<R: Resolver + Send + Sync> Resolver for CachingResolver<R> {
+    async fn resolve(
+        &self,
+        host: &str,
+    ) -> Result<Vec<IpAddr>> {
+        let resolution = self.entry(host);
+
+        let mut missed = false;
+        let resolution = resolution
+            .get_or_init(|| async {
+                missed = true;
+                Resolution {
+                    result: self.inner.resolve(host).await.map_err(|e| e.to_string()),
+                    resolved_at: Instant::now(),
+                }
+            })
+            .await;
+
+        if missed {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        }
+
+        match &resolution.result {
+            Ok(addresses) => Ok(addresses.clone()),
+            Err(e) => bail!("{}", e),
+        }
+    }
+}
+
+#[async_trait]
+impl<R: Resolver + Send + Sync + ?Sized> Resolver for Arc<R> {
+    async fn resolve(
+        &self,
+        host: &str,
+    ) -> Result<Vec<IpAddr>> {
+        (**self).resolve(host).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Resolver that counts its lookups, and fails for names starting with "bad".
+    #[derive(Default)]
+    struct CountingResolver {
+        lookups: AtomicU64,
+    }
+
+    #[async_trait]
+    impl Resolver for CountingResolver {
+        async fn resolve(
+            &self,
+            host: &str,
+        ) -> Result<Vec<IpAddr>> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+
+            ensure!(!host.starts_with("bad"), "No such host: {}", host);
+            Ok(vec!["192.0.2.1".parse()?])
+        }
+    }
+
+    fn caching_resolver(capacity: usize) -> CachingResolver<CountingResolver> {
+        CachingResolver::new(
+            CountingResolver::default(),
+            capacity,
+            Duration::from_secs(60),
+            Duration::from_secs(5),
+        )
+    }
+
+    // Tests that repeated resolutions are answered from the cache.
+    #[tokio::test]
+    async fn test_positive_caching() -> Result<()> {
+        let resolver = caching_resolver(8);
+
+        resolver.resolve("example.com").await?;
+        resolver.resolve("example.com").await?;
+
+        assert_eq!(resolver.inner.lookups.load(Ordering::SeqCst), 1);
+        assert_eq!(resolver.stats(), CacheStats { hits: 1, misses: 1 });
+        Ok(())
+    }
+
+    // Tests that failures are cached as well.
+    #[tokio::test]
+    async fn test_negative_caching() {
+        let resolver = caching_resolver(8);
+
+        assert!(resolver.resolve("bad.example.com").await.is_err());
+        assert!(resolver.resolve("bad.example.com").await.is_err());
+
+        assert_eq!(resolver.inner.lookups.load(Ordering::SeqCst), 1);
+    }
+
+    // Tests that entries expire after the configured TTL.
+    #[tokio::test(start_paused = true)]
+    async fn test_expiry() -> Result<()> {
+        let resolver = caching_resolver(8);
+
+        resolver.resolve("example.com").await?;
+        tokio::time::advance(Duration::from_secs(61)).await;
+        resolver.resolve("example.com").await?;
+
+        assert_eq!(resolver.inner.lookups.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+
+    // Tests that the least recently used entry is evicted when the cache is full.
+    #[tokio::test]
+    async fn test_lru_eviction() -> Result<()> {
+        let resolver = caching_resolver(2);
+
+        resolver.resolve("a.example.com").await?;
+        resolver.resolve("b.example.com").await?;
+        resolver.resolve("a.example.com").await?;
+        resolver.resolve("c.example.com").await?;
+        assert_eq!(resolver.len(), 2);
+
+        // "b" was least recently used, so it has to be looked up again.
+        resolver.resolve("a.example.com").await?;
+        resolver.resolve("b.example.com").await?;
+        assert_eq!(resolver.inner.lookups.load(Ordering::SeqCst), 4);
+        Ok(())
+    }
+
+    // Tests that concurrent resolutions of the same name share a single lookup.
+    #[tokio::test]
+    async fn test_stampede_protection() -> Result<()> {
+        let resolver = Arc::new(caching_resolver(8));
+
+        let lookups: Vec<_> = (0..10)
+            .map(|_| {
+                let resolver = Arc::clone(&resolver);
+                tokio::spawn(async move { resolver.resolve("example.com").await })
+            })
+            .collect();
+
+        for lookup in lookups {
+            lookup.await??;
+        }
+
+        assert_eq!(resolver.inner.lookups.load(Ordering::SeqCst), 1);
+        assert_eq!(resolver.stats(), CacheStats { hits: 9, misses: 1 });
+        Ok(())
+    }
+}
@@ -0,0 +1,302 @@
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use thiserror::Error;
+
+use crate::constants::*;
+use crate::SocksError;
+
+/// The longest a client waits between two attempts, regardless of the backoff base and the number of attempts.
+pub const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Decides whether, and how often, the clients try again when a connect fails, e.g., while the proxy restarts.
+///
+/// Attempts are separated by an exponential backoff: the base before the second attempt, twice the base
+/// before the third, and so on, up to `MAX_BACKOFF`. Each delay is varied randomly by the jitter, a fraction of
+/// the delay, so that clients that failed together don't retry together.
+///
+/// Whether a failure is retried is decided by a predicate over the error, `is_transient` by default.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    backoff_base: Duration,
+    jitter: f64,
+    retryable: Arc<dyn Fn(&anyhow::Error) -> bool + Send + Sync>,
+}
+
+impl RetryPolicy {
+    /// Creates a policy that makes at most the given number of attempts, with a backoff base of 100ms and a
+    /// jitter of 20%, retrying transient failures.
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            backoff_base: Duration::from_millis(100),
+            jitter: 0.2,
+            retryable: Arc::new(is_transient),
+        }
+    }
+
+    /// Creates a policy that makes a single attempt, which is what the clients do by default.
+    pub fn never() -> Self {
+        Self::new(1)
+    }
+
+    /// Sets the delay before the second attempt, which doubles before every attempt after it.
+    pub fn with_backoff(
+        mut self,
+        base: Duration,
+    ) -> Self {
+        self.backoff_base = base;
+        self
+    }
+
+    /// Sets the fraction of each delay it is randomly lengthened or shortened by, between 0 and 1.
+    pub fn with_jitter(
+        mut self,
+        jitter: f64,
+    ) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Sets the predicate that decides which failures are retried, instead of `is_transient`.
+    pub fn with_predicate<F>(
+        mut self,
+        retryable: F,
+    ) -> Self
+    where
+        F: Fn(&anyhow::Error) -> bool + Send + Sync + 'static,
+    {
+        self.retryable = Arc::new(retryable);
+        self
+    }
+
+    /// Returns the most attempts the policy makes.
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// Returns whether the policy retries the failure.
+    pub fn is_retryable(
+        &self,
+        error: &anyhow::Error,
+    ) -> bool {
+        (self.retryable)(error)
+    }
+
+    /// Returns the delay after the given failed attempt, counted from 1, before the jitter is applied.
+    pub fn backoff(
+        &self,
+        attempt: u32,
+    ) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.backoff_base.saturating_mul(factor).min(MAX_BACKOFF)
+    }
+
+    // Varies the delay by up to the jitter, in either direction.
+    fn jittered(
+        &self,
+        delay: Duration,
+    ) -> Duration {
+        let random = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+        delay.mul_f64(1.0 + self.jitter * (2.0 * random - 1.0))
+    }
+
+    /// Runs the operation until it succeeds, fails with an error that isn't retried, or the attempts run out.
+    ///
+    /// The operation is given the number of the attempt, counted from 1. Unless the operation succeeds, the
+    /// error of the last attempt is returned, with a `RetryError` as its context that reports the number of
+    /// attempts that were made if the policy allows more than one.
+    pub async fn run<T, F, Fut>(
+        &self,
+        mut operation: F,
+    ) -> Result<T>
+    where
+        F: FnMut(u32) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            let error = match operation(attempt).await {
+                Ok(value) => return Ok(value),
+                Err(error) => error,
+            };
+            if self.max_attempts == 1 {
+                return Err(error);
+            }
+            if attempt >= self.max_attempts || !self.is_retryable(&error) {
+                let message = error.to_string();
+                return Err(error.context(RetryError {
+                    attempts: attempt,
+                    message,
+                }));
+            }
+
+            let delay = self.jittered(self.backoff(attempt));
+            debug!("Attempt {} failed, trying again in {:?}: {}", attempt, delay, error);
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::never()
+    }
+}
+
+impl fmt::Debug for RetryPolicy {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("backoff_base", &self.backoff_base)
+            .field("jitter", &self.jitter)
+            .finish()
+    }
+}
+
+/// The context of the error of the last attempt of a `RetryPolicy`, reporting how many attempts were made.
+///
+/// The error itself, e.g., a `SocksError`, can still be retrieved with `downcast_ref`.
+#[derive(Debug, Error)]
+#[error("Failed after {attempts} attempt(s): {message}")]
+pub struct RetryError {
+    /// The number of attempts that were made.
+    pub attempts: u32,
+    message: String,
+}
+
+/// Returns whether the failure is likely to go away by itself: the proxy refusing, resetting, or closing the
+/// connection, or timing out, and replies that report a general failure, a refused connection, or a timeout.
+///
+/// Authentication failures, connections the policy doesn't allow, and unsupported requests are never retried.
+pub fn is_transient(error: &anyhow::Error) -> bool {
+    let is_transient_reply = |reply: u8| {
+        matches!(
+            reply,
+            SOCKS_REP_GENERAL_FAILURE | SOCKS_REP_CONNECTION_REFUSED | SOCKS_REP_CONNECTION_ATTEMPT_TIMEOUT
+        )
+    };
+
+    if let Some(error) = error.downcast_ref::<SocksError>() {
+        return match error {
+            SocksError::OperationFailed(reply) => is_transient_reply(*reply),
+            SocksError::ChainFailed(failure) => is_transient_reply(failure.reply),
+            SocksError::ConnectTimeout(_) => true,
+            _ => false,
+        };
+    }
+
+    matches!(
+        error.downcast_ref::<io::Error>().map(io::Error::kind),
+        Some(
+            io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::UnexpectedEof
+                | io::ErrorKind::TimedOut
+        )
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+    use crate::test_util::MockSocksServer;
+    use crate::{Credentials, Socks5Client, Socks6Client};
+
+    // Tests that the backoff doubles up to the maximum, and the jitter stays within its fraction.
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy::new(5).with_backoff(Duration::from_millis(100)).with_jitter(0.5);
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(3), Duration::from_millis(400));
+        assert_eq!(policy.backoff(40), MAX_BACKOFF);
+
+        for _ in 0..100 {
+            let delay = policy.jittered(Duration::from_millis(100));
+            assert!(delay >= Duration::from_millis(50) && delay <= Duration::from_millis(150));
+        }
+    }
+
+    // Tests which failures are retried by default.
+    #[test]
+    fn test_is_transient() {
+        assert!(is_transient(&io::Error::from(io::ErrorKind::ConnectionRefused).into()));
+        assert!(is_transient(&SocksError::OperationFailed(SOCKS_REP_GENERAL_FAILURE).into()));
+        assert!(is_transient(&SocksError::ConnectTimeout(Duration::from_secs(1)).into()));
+        assert!(!is_transient(&SocksError::OperationFailed(SOCKS_REP_CONNECTION_NOT_ALLOWED).into()));
+        assert!(!is_transient(&SocksError::AuthenticationFailed(None).into()));
+        assert!(!is_transient(&anyhow!("Something else went wrong.")));
+    }
+
+    // Tests that the operation runs until it succeeds, or the attempts run out.
+    #[tokio::test(start_paused = true)]
+    async fn test_run() -> Result<()> {
+        let policy = RetryPolicy::new(3).with_predicate(|_| true);
+        let attempts = AtomicU32::new(0);
+
+        let value = policy
+            .run(|attempt| {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                async move {
+                    ensure!(attempt == 2, "Not yet.");
+                    Ok(attempt)
+                }
+            })
+            .await?;
+        assert_eq!(value, 2);
+        assert_eq!(attempts.load(Ordering::Relaxed), 2);
+
+        let error = policy.run(|_| async { Err::<(), _>(anyhow!("Never.")) }).await.unwrap_err();
+        let retry = error.downcast_ref::<RetryError>().unwrap();
+        assert_eq!(retry.attempts, 3);
+        assert_eq!(error.to_string(), "Failed after 3 attempt(s): Never.");
+
+        Ok(())
+    }
+
+    // Tests that the clients retry a failure reply over fresh connections, but not a failed authentication.
+    #[tokio::test]
+    async fn test_client_retries() -> Result<()> {
+        let policy = RetryPolicy::new(3).with_backoff(Duration::from_millis(1));
+
+        let proxy = MockSocksServer::socks5().with_reply(SOCKS_REP_GENERAL_FAILURE);
+        let proxy_addr = proxy.bind().await?;
+        let client = Socks5Client::new(proxy_addr.to_string(), None).await?.with_retry_policy(policy.clone());
+        let error = client.connect("192.0.2.1:80".to_string(), None).await.unwrap_err();
+        assert_eq!(error.downcast_ref::<RetryError>().map(|e| e.attempts), Some(3));
+        assert!(matches!(error.downcast_ref(), Some(SocksError::OperationFailed(SOCKS_REP_GENERAL_FAILURE))));
+        assert_eq!(proxy.recordings().len(), 3);
+
+        let proxy = MockSocksServer::socks6().with_reply(SOCKS_REP_GENERAL_FAILURE);
+        let proxy_addr = proxy.bind().await?;
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?.with_retry_policy(policy.clone());
+        let error = client.connect("192.0.2.1:80", None, None).await.unwrap_err();
+        assert_eq!(error.downcast_ref::<RetryError>().map(|e| e.attempts), Some(3));
+        assert_eq!(proxy.recordings().len(), 3);
+
+        let proxy = MockSocksServer::socks5().with_credentials(Credentials::new("user", "secret"));
+        let proxy_addr = proxy.bind().await?;
+        let credentials = Some(Credentials::new("user", "wrong"));
+        let client = Socks5Client::new(proxy_addr.to_string(), credentials).await?.with_retry_policy(policy);
+        let error = client.connect("192.0.2.1:80".to_string(), None).await.unwrap_err();
+        assert_eq!(error.downcast_ref::<RetryError>().map(|e| e.attempts), Some(1));
+        assert!(matches!(error.downcast_ref(), Some(SocksError::AuthenticationFailed(_))));
+
+        Ok(())
+    }
+}
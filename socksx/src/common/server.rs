@@ -0,0 +1,991 @@
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{self, Instant};
+
+use crate::bandwidth::{BandwidthLimit, TunnelRate};
+use crate::budget::MemoryBudget;
+use crate::dialer::{ConnectionAddrs, Keepalive};
+use crate::metrics::{self, Metrics, NoopMetrics};
+use crate::policy::Policy;
+use crate::session::{LiveTunnel, TunnelRegistry};
+use crate::source_filter::SourceFilter;
+#[cfg(feature = "tls")]
+use crate::tls::TlsServer;
+use crate::SocksHandler;
+
+// How long a shed connection is given to take the reply that refuses it, before it is closed.
+const SHED_REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// What a `SocksServer` does when all sessions are running and its queue is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OverflowPolicy {
+    /// Stop accepting connections until there is room again, so that they wait in the kernel's backlog.
+    #[default]
+    Backpressure,
+    /// Keep accepting connections, and close the connection that has been queued the longest to make room.
+    ShedOldest,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = anyhow::Error;
+
+    // Parses the kebab-case name of a policy, e.g., `shed-oldest`.
+    fn from_str(policy: &str) -> Result<Self> {
+        match policy {
+            "backpressure" => Ok(OverflowPolicy::Backpressure),
+            "shed-oldest" => Ok(OverflowPolicy::ShedOldest),
+            _ => bail!("Unrecognized overflow policy: {}", policy),
+        }
+    }
+}
+
+/// What a `SocksServer` does with connections that arrive faster than its accept rate.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RateLimitPolicy {
+    /// Hold the connections in the queue until the rate allows their sessions to start. Once the queue is full,
+    /// the overflow policy applies.
+    #[default]
+    Delay,
+    /// Close the connections right after accepting them.
+    Close,
+}
+
+impl FromStr for RateLimitPolicy {
+    type Err = anyhow::Error;
+
+    // Parses the kebab-case name of a policy, e.g., `close`.
+    fn from_str(policy: &str) -> Result<Self> {
+        match policy {
+            "delay" => Ok(RateLimitPolicy::Delay),
+            "close" => Ok(RateLimitPolicy::Close),
+            _ => bail!("Unrecognized rate limit policy: {}", policy),
+        }
+    }
+}
+
+/// The rate at which a `SocksServer` starts sessions for new connections, as a token bucket: a session takes a
+/// token, and tokens are added at the rate, up to the burst.
+///
+/// Unlike the limit on concurrent sessions, this spreads out the handshakes of many clients that connect at once,
+/// e.g., when they reconnect after an outage, so that they don't starve the sessions that are established already.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AcceptRate {
+    per_second: f64,
+    burst: u32,
+    policy: RateLimitPolicy,
+}
+
+impl AcceptRate {
+    /// Creates a rate of the given number of sessions per second, of which up to the burst can start at once.
+    ///
+    /// # Returns
+    ///
+    /// An error unless both the rate and the burst are positive.
+    pub fn new(
+        per_second: f64,
+        burst: u32,
+    ) -> Result<Self> {
+        ensure!(per_second > 0.0, "The accept rate must be positive, not {}.", per_second);
+        ensure!(burst > 0, "The burst of an accept rate must be positive.");
+
+        Ok(Self {
+            per_second,
+            burst,
+            policy: RateLimitPolicy::default(),
+        })
+    }
+
+    /// Sets what happens to connections beyond the rate, which are delayed by default.
+    pub fn with_policy(
+        mut self,
+        policy: RateLimitPolicy,
+    ) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Returns the number of sessions per second.
+    pub fn per_second(&self) -> f64 {
+        self.per_second
+    }
+
+    /// Returns the number of sessions that can start at once.
+    pub fn burst(&self) -> u32 {
+        self.burst
+    }
+
+    /// Returns what happens to connections beyond the rate.
+    pub fn policy(&self) -> RateLimitPolicy {
+        self.policy
+    }
+}
+
+// The tokens of an accept rate. It's only used by the task that accepts connections, so it needs no
+// synchronization.
+struct TokenBucket {
+    rate: AcceptRate,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    // Creates a full bucket.
+    fn new(rate: AcceptRate) -> Self {
+        Self {
+            rate,
+            tokens: f64::from(rate.burst),
+            refilled: Instant::now(),
+        }
+    }
+
+    // Adds the tokens of the time since the last refill.
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let added = now.duration_since(self.refilled).as_secs_f64() * self.rate.per_second;
+        self.tokens = (self.tokens + added).min(f64::from(self.rate.burst));
+        self.refilled = now;
+    }
+
+    // Returns whether a token is available.
+    fn has_token(&mut self) -> bool {
+        self.refill();
+        self.tokens >= 1.0
+    }
+
+    // Takes a token, if one is available.
+    fn try_take(&mut self) -> bool {
+        if !self.has_token() {
+            return false;
+        }
+        self.tokens -= 1.0;
+
+        true
+    }
+
+    // Returns when the next token is available.
+    fn next_token(&self) -> Instant {
+        let missing = (1.0 - self.tokens).max(0.0);
+        self.refilled + Duration::from_secs_f64(missing / self.rate.per_second)
+    }
+}
+
+/// Counters that describe the load of a server.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ServerStats {
+    /// The number of connections accepted so far.
+    pub accepted: u64,
+    /// The number of queued connections that were closed to make room, without being served.
+    pub shed: u64,
+    /// The number of connections that arrived while the accept rate allowed no session to start, and were delayed.
+    pub rate_delayed: u64,
+    /// The number of connections that were closed, without being served, as they arrived faster than the accept
+    /// rate.
+    pub rate_closed: u64,
+    /// The number of connections that were closed, without being served, as their source address isn't allowed.
+    pub source_denied: u64,
+    /// The number of sessions currently running.
+    pub active: usize,
+    /// The number of accepted connections currently waiting for a session to finish.
+    pub queued: usize,
+}
+
+#[derive(Default)]
+struct Counters {
+    accepted: AtomicU64,
+    shed: AtomicU64,
+    rate_delayed: AtomicU64,
+    rate_closed: AtomicU64,
+    source_denied: AtomicU64,
+    active: AtomicUsize,
+    queued: AtomicUsize,
+}
+
+/// Accepts connections on a listener, and serves each of them with a handler in a task of its own.
+///
+/// The number of concurrent sessions can be limited. Connections accepted beyond the limit wait in a bounded
+/// queue until a session finishes; once the queue is full, the overflow policy decides between leaving new
+/// connections in the kernel's backlog, and closing the oldest queued connection. The rate at which sessions start
+/// can be limited as well, and peers can be denied by their source address.
+pub struct SocksServer {
+    listener: TcpListener,
+    handler: Arc<dyn SocksHandler + Send + Sync>,
+    limit: usize,
+    queue_capacity: usize,
+    overflow: OverflowPolicy,
+    accept_rate: Option<AcceptRate>,
+    source_filter: SourceFilter,
+    keepalive: Option<Keepalive>,
+    counters: Arc<Counters>,
+    metrics: Arc<dyn Metrics + Send + Sync>,
+    budget: Option<MemoryBudget>,
+    bandwidth: Option<BandwidthLimit>,
+    tunnels: TunnelRegistry,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsServer>,
+}
+
+impl SocksServer {
+    /// Creates a server that serves the connections of the listener with the handler, without a limit.
+    pub fn new(
+        listener: TcpListener,
+        handler: Arc<dyn SocksHandler + Send + Sync>,
+    ) -> Self {
+        Self {
+            listener,
+            handler,
+            limit: 0,
+            queue_capacity: 0,
+            overflow: OverflowPolicy::default(),
+            accept_rate: None,
+            source_filter: SourceFilter::default(),
+            keepalive: None,
+            counters: Arc::new(Counters::default()),
+            metrics: Arc::new(NoopMetrics),
+            budget: None,
+            bandwidth: None,
+            tunnels: TunnelRegistry::default(),
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    /// Limits the number of concurrent sessions, or removes the limit with zero.
+    pub fn with_limit(
+        mut self,
+        limit: usize,
+    ) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    /// Sets how many accepted connections may wait for a session to finish, once the limit is reached.
+    pub fn with_queue(
+        mut self,
+        capacity: usize,
+    ) -> Self {
+        self.queue_capacity = capacity;
+        self
+    }
+
+    /// Sets what happens when all sessions are running and the queue is full.
+    pub fn with_overflow_policy(
+        mut self,
+        overflow: OverflowPolicy,
+    ) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Limits the rate at which sessions start for new connections. Connections beyond the rate are delayed or
+    /// closed, according to the policy of the rate, before a task is spawned for them.
+    pub fn with_accept_rate(
+        mut self,
+        rate: AcceptRate,
+    ) -> Self {
+        self.accept_rate = Some(rate);
+        self
+    }
+
+    /// Closes the connections of peers that the filter doesn't allow as soon as they are accepted, before a task is
+    /// spawned for them, or a token of the accept rate is taken.
+    pub fn with_source_filter(
+        mut self,
+        filter: SourceFilter,
+    ) -> Self {
+        self.source_filter = filter;
+        self
+    }
+
+    /// Enables TCP keepalive on accepted connections.
+    pub fn with_keepalive(
+        mut self,
+        keepalive: Keepalive,
+    ) -> Self {
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    /// Sets the metrics that the number of active sessions and queued connections are reported to, as gauges.
+    pub fn with_metrics(
+        mut self,
+        metrics: Arc<dyn Metrics + Send + Sync>,
+    ) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Limits the bytes that the handshakes of all sessions buffer together. Requests that would exceed the
+    /// budget are refused with a general failure reply.
+    pub fn with_memory_budget(
+        mut self,
+        budget: MemoryBudget,
+    ) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+
+    /// Limits the bytes per second that the tunnels of all sessions relay together, sharing the limit fairly
+    /// between them.
+    pub fn with_bandwidth_limit(
+        mut self,
+        limit: BandwidthLimit,
+    ) -> Self {
+        self.bandwidth = Some(limit);
+        self
+    }
+
+    /// Serves connections over TLS. If the TLS server requires client certificates, the subject of a client's
+    /// certificate is passed to the handler as the identity of its session.
+    #[cfg(feature = "tls")]
+    pub fn with_tls(
+        mut self,
+        tls: TlsServer,
+    ) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Returns the counters of the server.
+    pub fn stats(&self) -> ServerStats {
+        ServerStats {
+            accepted: self.counters.accepted.load(Ordering::Relaxed),
+            shed: self.counters.shed.load(Ordering::Relaxed),
+            rate_delayed: self.counters.rate_delayed.load(Ordering::Relaxed),
+            rate_closed: self.counters.rate_closed.load(Ordering::Relaxed),
+            source_denied: self.counters.source_denied.load(Ordering::Relaxed),
+            active: self.counters.active.load(Ordering::Relaxed),
+            queued: self.counters.queued.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Returns the tunnels of the sessions that are established and still relay, in the order they were established.
+    pub fn tunnels(&self) -> Vec<LiveTunnel> {
+        self.tunnels.tunnels()
+    }
+
+    /// Returns the rates that the tunnels of the sessions relayed at recently, if the server has a bandwidth limit.
+    pub fn tunnel_rates(&self) -> Vec<TunnelRate> {
+        self.bandwidth.as_ref().map_or_else(Vec::new, BandwidthLimit::rates)
+    }
+
+    /// Replaces the policy of the handler, for the requests accepted from now on.
+    pub fn update_policy(
+        &self,
+        policy: Policy,
+    ) -> Result<()> {
+        self.handler.update_policy(policy)
+    }
+
+    /// Accepts and serves connections until accepting fails.
+    pub async fn run(&self) -> Result<()> {
+        let limit = if self.limit == 0 { Semaphore::MAX_PERMITS } else { self.limit };
+        let permits = Arc::new(Semaphore::new(limit));
+        let mut queue = VecDeque::new();
+        let mut bucket = self.accept_rate.map(TokenBucket::new);
+        let delaying = self.accept_rate.is_some_and(|rate| rate.policy == RateLimitPolicy::Delay);
+
+        loop {
+            // Start queued connections for as long as there is room, and the rate allows it.
+            while !queue.is_empty() {
+                let Ok(permit) = Arc::clone(&permits).try_acquire_owned() else {
+                    break;
+                };
+                if delaying && !bucket.as_mut().is_some_and(TokenBucket::try_take) {
+                    break;
+                }
+                self.spawn(queue.pop_front().unwrap(), permit);
+            }
+
+            while queue.len() > self.queue_capacity {
+                self.shed(queue.pop_front().unwrap());
+            }
+            self.counters.queued.store(queue.len(), Ordering::Relaxed);
+            self.metrics.set_gauge(metrics::CONNECTIONS_QUEUED, &[], queue.len() as i64);
+
+            let room = permits.available_permits() > 0;
+            let rate_allows = !delaying || bucket.as_mut().is_some_and(TokenBucket::has_token);
+            let accepting = (queue.is_empty() && room && rate_allows)
+                || queue.len() < self.queue_capacity
+                || self.overflow == OverflowPolicy::ShedOldest;
+            let waiting = !accepting || !queue.is_empty();
+            let next_token = bucket.as_ref().map_or_else(Instant::now, TokenBucket::next_token);
+
+            tokio::select! {
+                // The loop starts the queued connections, once there is room and a token again.
+                permit = permits.acquire(), if waiting && !room => {
+                    drop(permit?);
+                }
+                _ = time::sleep_until(next_token), if waiting && room => {}
+                accepted = self.listener.accept(), if accepting => {
+                    let (incoming, peer) = accepted?;
+                    self.counters.accepted.fetch_add(1, Ordering::Relaxed);
+
+                    if !self.source_filter.permits(peer.ip()) {
+                        self.counters.source_denied.fetch_add(1, Ordering::Relaxed);
+                        self.metrics.increment_counter(metrics::CONNECTIONS_SOURCE_DENIED, &[], 1);
+                        debug!("Closed the connection of {}, as its source address isn't allowed.", peer);
+                        continue;
+                    }
+
+                    if let Some(bucket) = &mut bucket {
+                        if !delaying && !bucket.try_take() {
+                            self.counters.rate_closed.fetch_add(1, Ordering::Relaxed);
+                            self.record_rate_limited("closed");
+                            debug!("Closed a connection, as connections arrive faster than the accept rate.");
+                            continue;
+                        }
+                        if delaying && !bucket.has_token() {
+                            self.counters.rate_delayed.fetch_add(1, Ordering::Relaxed);
+                            self.record_rate_limited("delayed");
+                        }
+                    }
+
+                    if let Some(keepalive) = &self.keepalive {
+                        if let Err(e) = keepalive.apply(&incoming) {
+                            warn!("Failed to enable keepalive on incoming connection: {:?}", e);
+                        }
+                    }
+
+                    queue.push_back(incoming);
+                }
+            }
+        }
+    }
+
+    // Counts a connection that arrived faster than the accept rate in the metrics.
+    fn record_rate_limited(
+        &self,
+        action: &str,
+    ) {
+        self.metrics.increment_counter(metrics::CONNECTIONS_RATE_LIMITED, &[("action", action)], 1);
+    }
+
+    // Serves the connection in a task of its own, which holds the permit until the session finishes.
+    // Refuses a queued connection with a general failure and closes it, to make room in the queue.
+    fn shed(
+        &self,
+        mut incoming: TcpStream,
+    ) {
+        self.counters.shed.fetch_add(1, Ordering::Relaxed);
+        debug!("Closing a queued connection, as the queue is full.");
+
+        // Over TLS, the reply can't be sent before the TLS handshake, so the connection is closed right away.
+        #[cfg(feature = "tls")]
+        if self.tls.is_some() {
+            return;
+        }
+
+        let handler = Arc::clone(&self.handler);
+        tokio::spawn(async move {
+            match time::timeout(SHED_REPLY_TIMEOUT, handler.refuse_overloaded(&mut incoming)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => debug!("Failed to refuse a shed connection: {}", e),
+                Err(_) => debug!("Closed a shed connection, as it didn't take the refusal in time."),
+            }
+        });
+    }
+
+    fn spawn(
+        &self,
+        mut incoming: TcpStream,
+        permit: OwnedSemaphorePermit,
+    ) {
+        let handler = Arc::clone(&self.handler);
+        let counters = Arc::clone(&self.counters);
+        let metrics = Arc::clone(&self.metrics);
+        let budget = self.budget.clone();
+        let bandwidth = self.bandwidth.clone();
+        let tunnels = self.tunnels.clone();
+        #[cfg(feature = "tls")]
+        let tls = self.tls.clone();
+        let active = counters.active.fetch_add(1, Ordering::Relaxed) + 1;
+        metrics.set_gauge(metrics::SESSIONS_ACTIVE, &[], active as i64);
+
+        tokio::spawn(async move {
+            let start_time = Instant::now();
+            let addrs = ConnectionAddrs::of(&incoming);
+            let serving = async {
+                #[cfg(feature = "tls")]
+                let served = match tls {
+                    Some(tls) => serve_tls(handler.as_ref(), &tls, incoming, addrs).await,
+                    None => handler.accept_request_from(&mut incoming, None, addrs).await,
+                };
+                #[cfg(not(feature = "tls"))]
+                let served = handler.accept_request_from(&mut incoming, None, addrs).await;
+                served
+            };
+            let serving = tunnels.scope(serving);
+            let serving = async {
+                match budget {
+                    Some(budget) => budget.scope(serving).await,
+                    None => serving.await,
+                }
+            };
+            let served = match bandwidth {
+                Some(bandwidth) => bandwidth.scope(serving).await,
+                None => serving.await,
+            };
+            if let Err(e) = served {
+                debug!("Session failed: {:?}", e);
+            }
+            debug!("Session finished after {}ms.", start_time.elapsed().as_millis());
+
+            let active = counters.active.fetch_sub(1, Ordering::Relaxed) - 1;
+            metrics.set_gauge(metrics::SESSIONS_ACTIVE, &[], active as i64);
+            drop(permit);
+        });
+    }
+}
+
+// Performs the TLS handshake with the client, and serves it with the identity of its certificate, if any.
+#[cfg(feature = "tls")]
+async fn serve_tls(
+    handler: &(dyn SocksHandler + Send + Sync),
+    tls: &TlsServer,
+    incoming: TcpStream,
+    addrs: ConnectionAddrs,
+) -> Result<()> {
+    let (mut stream, identity) = tls.accept(incoming).await?;
+    handler.accept_request_from(&mut stream, identity, addrs).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use bytes::BytesMut;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::sync::{mpsc, Notify};
+
+    use crate::constants::*;
+    use crate::interface::AsyncStream;
+    use crate::session::{SessionHooks, SessionInfo, TunnelInfo};
+    use crate::socks6::options::UnrecognizedOption;
+    use crate::socks6::{Socks6Command, Socks6Request};
+    use crate::socks5::{self, Socks5Reply};
+    use crate::{wire, Address, Route, Socks5Client, Socks5Handler, Socks6Client, Socks6Handler};
+
+    use super::*;
+
+    // Handler that holds every session until it is released, and then closes it.
+    struct BlockingHandler {
+        release: Arc<Notify>,
+    }
+
+    #[async_trait]
+    impl SocksHandler for BlockingHandler {
+        async fn accept_request(
+            &self,
+            _source: &mut dyn AsyncStream,
+        ) -> Result<()> {
+            self.release.notified().await;
+            Ok(())
+        }
+
+        async fn refuse_request(
+            &self,
+            _source: &mut dyn AsyncStream,
+        ) -> Result<()> {
+            Ok(())
+        }
+
+        async fn refuse_overloaded(
+            &self,
+            source: &mut dyn AsyncStream,
+        ) -> Result<()> {
+            socks5::write_reply(source, Socks5Reply::GeneralFailure).await
+        }
+
+        async fn setup(
+            &self,
+            _source: &mut dyn AsyncStream,
+        ) -> Result<Box<dyn AsyncStream>> {
+            bail!("Not supported.")
+        }
+    }
+
+    async fn start(
+        limit: usize,
+        queue: usize,
+        overflow: OverflowPolicy,
+    ) -> Result<(Arc<SocksServer>, std::net::SocketAddr, Arc<Notify>)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let release = Arc::new(Notify::new());
+
+        let handler = Arc::new(BlockingHandler { release: Arc::clone(&release) });
+        let server = SocksServer::new(listener, handler)
+            .with_limit(limit)
+            .with_queue(queue)
+            .with_overflow_policy(overflow);
+        let server = Arc::new(server);
+
+        let running = Arc::clone(&server);
+        tokio::spawn(async move { running.run().await });
+
+        Ok((server, addr, release))
+    }
+
+    async fn wait_for<F: Fn(ServerStats) -> bool>(
+        server: &SocksServer,
+        condition: F,
+    ) {
+        for _ in 0..200 {
+            if condition(server.stats()) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        panic!("Condition not met: {:?}", server.stats());
+    }
+
+    // Waits for the server to list the number of tunnels, for at most a second.
+    async fn wait_for_tunnels(
+        server: &SocksServer,
+        count: usize,
+    ) -> Vec<LiveTunnel> {
+        for _ in 0..200 {
+            let tunnels = server.tunnels();
+            if tunnels.len() == count {
+                return tunnels;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        panic!("Expected {} tunnels: {:?}", count, server.tunnels());
+    }
+
+    // Tests that the oldest queued connections are closed once the sessions and the queue are full.
+    #[tokio::test]
+    async fn test_shed_oldest() -> Result<()> {
+        let (server, addr, release) = start(2, 2, OverflowPolicy::ShedOldest).await?;
+
+        let mut clients = vec![];
+        for _ in 0..6 {
+            clients.push(TcpStream::connect(addr).await?);
+            let connected = clients.len() as u64;
+            wait_for(&server, |stats| stats.accepted == connected).await;
+        }
+        wait_for(&server, |stats| stats.active == 2 && stats.queued == 2 && stats.shed == 2).await;
+
+        // The third and fourth clients were queued the longest, and are refused with a general failure and closed.
+        for client in &mut clients[2..4] {
+            let mut reply = vec![];
+            tokio::time::timeout(Duration::from_secs(1), client.read_to_end(&mut reply)).await??;
+            assert_eq!(&reply[..2], &[SOCKS_VER_5, SOCKS_REP_GENERAL_FAILURE]);
+        }
+
+        // Once the sessions finish, the queued clients are served.
+        release.notify_waiters();
+        wait_for(&server, |stats| stats.active == 2 && stats.queued == 0).await;
+        release.notify_waiters();
+        wait_for(&server, |stats| stats.active == 0).await;
+        assert_eq!(server.stats().shed, 2);
+
+        Ok(())
+    }
+
+    // Tests that connections are left in the backlog, without being shed, once the sessions and the queue are full.
+    #[tokio::test]
+    async fn test_backpressure() -> Result<()> {
+        let (server, addr, release) = start(2, 1, OverflowPolicy::Backpressure).await?;
+
+        let mut clients = vec![];
+        for _ in 0..5 {
+            clients.push(TcpStream::connect(addr).await?);
+        }
+        wait_for(&server, |stats| stats.active == 2 && stats.queued == 1).await;
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(server.stats().accepted, 3);
+
+        // Each finished session makes room for the backlog, and nothing is shed.
+        let drained = async {
+            while server.stats().accepted < 5 || server.stats().active > 0 {
+                release.notify_waiters();
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(2), drained).await.expect("The backlog wasn't served in time");
+        assert_eq!(server.stats().shed, 0);
+
+        Ok(())
+    }
+
+    // Starts a server with unlimited sessions, that starts them at the rate.
+    async fn start_rate_limited(rate: AcceptRate) -> Result<(Arc<SocksServer>, std::net::SocketAddr)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let handler = Arc::new(BlockingHandler { release: Arc::new(Notify::new()) });
+        let server = Arc::new(SocksServer::new(listener, handler).with_queue(20).with_accept_rate(rate));
+        let running = Arc::clone(&server);
+        tokio::spawn(async move { running.run().await });
+
+        Ok((server, addr))
+    }
+
+    // Tests that a burst of connects is served no faster than the accept rate, and that every connection is
+    // served eventually, as the connections beyond the rate are delayed.
+    #[tokio::test]
+    async fn test_accept_rate_delay() -> Result<()> {
+        let start = Instant::now();
+        let (server, addr) = start_rate_limited(AcceptRate::new(20.0, 2)?).await?;
+
+        let mut clients = vec![];
+        for _ in 0..10 {
+            clients.push(TcpStream::connect(addr).await?);
+        }
+        loop {
+            let active = server.stats().active;
+            let allowed = 2.0 + start.elapsed().as_secs_f64() * 20.0;
+            assert!(active as f64 <= allowed, "{} sessions started, {} allowed", active, allowed);
+            if active == 10 {
+                break;
+            }
+            assert!(start.elapsed() < Duration::from_secs(2), "{:?}", server.stats());
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let stats = server.stats();
+        assert!(stats.rate_delayed > 0);
+        assert_eq!((stats.rate_closed, stats.shed), (0, 0));
+
+        Ok(())
+    }
+
+    // Tests that connections beyond the burst are closed without being served, if the rate says so.
+    #[tokio::test]
+    async fn test_accept_rate_close() -> Result<()> {
+        let rate = AcceptRate::new(0.1, 2)?.with_policy(RateLimitPolicy::Close);
+        let (server, addr) = start_rate_limited(rate).await?;
+
+        let mut clients = vec![];
+        for _ in 0..5 {
+            clients.push(TcpStream::connect(addr).await?);
+        }
+        wait_for(&server, |stats| stats.accepted == 5 && stats.active == 2 && stats.rate_closed == 3).await;
+        for client in &mut clients[2..] {
+            assert_eq!(client.read(&mut [0; 1]).await?, 0);
+        }
+        assert_eq!(server.stats().rate_delayed, 0);
+
+        assert!(AcceptRate::new(0.0, 1).is_err());
+        assert!(AcceptRate::new(1.0, 0).is_err());
+
+        Ok(())
+    }
+
+    // Tests that the connections of denied sources are closed without being served, and without taking a token.
+    #[tokio::test]
+    async fn test_source_filter() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let release = Arc::new(Notify::new());
+        let handler = Arc::new(BlockingHandler { release: Arc::clone(&release) });
+        let metrics = Arc::new(crate::metrics::InMemoryMetrics::new());
+        let filter = SourceFilter::new().with_allowed("10.0.0.0/8".parse()?);
+        let server = SocksServer::new(listener, handler)
+            .with_accept_rate(AcceptRate::new(0.1, 1)?.with_policy(RateLimitPolicy::Close))
+            .with_source_filter(filter)
+            .with_metrics(metrics.clone());
+        let server = Arc::new(server);
+        let running = Arc::clone(&server);
+        tokio::spawn(async move { running.run().await });
+
+        let mut clients = vec![];
+        for _ in 0..3 {
+            clients.push(TcpStream::connect(addr).await?);
+        }
+        wait_for(&server, |stats| stats.accepted == 3 && stats.source_denied == 3).await;
+        for client in &mut clients {
+            assert_eq!(client.read(&mut [0; 1]).await?, 0);
+        }
+        let stats = server.stats();
+        assert_eq!((stats.active, stats.rate_closed), (0, 0));
+        assert_eq!(metrics.counter(metrics::CONNECTIONS_SOURCE_DENIED, &[]), 3);
+
+        Ok(())
+    }
+
+    // Hooks that pass on the tunnel of every established session.
+    struct TunnelHooks {
+        established: mpsc::UnboundedSender<TunnelInfo>,
+    }
+
+    impl SessionHooks for TunnelHooks {
+        fn on_established(
+            &self,
+            _session: &SessionInfo,
+            tunnel: &TunnelInfo,
+        ) {
+            self.established.send(tunnel.clone()).unwrap();
+        }
+    }
+
+    // Tests that the handlers report the addresses of both legs of a session served by the server, which lists the
+    // tunnel until it closes, and that the clients describe their end of it.
+    #[tokio::test]
+    async fn test_tunnel_info() -> Result<()> {
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let destination_addr = destination.local_addr()?;
+
+        for version in [5, 6] {
+            let (established, mut tunnels) = mpsc::unbounded_channel();
+            let hooks = Arc::new(TunnelHooks { established });
+            let handler: Arc<dyn SocksHandler + Send + Sync> = match version {
+                5 => Arc::new(Socks5Handler::default().with_hooks(hooks)),
+                _ => Arc::new(Socks6Handler::default().with_hooks(hooks)),
+            };
+
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let proxy_addr = listener.local_addr()?;
+            let server = Arc::new(SocksServer::new(listener, handler));
+            let running = Arc::clone(&server);
+            tokio::spawn(async move { running.run().await });
+
+            let proxy = proxy_addr.to_string();
+            let (stream, info) = match version {
+                5 => Socks5Client::new(proxy, None).await?.connect_with_info(destination_addr, None).await?,
+                _ => Socks6Client::new(proxy, None).await?.connect_with_info(destination_addr, None, None).await?,
+            };
+            let (outbound, _) = destination.accept().await?;
+            assert_eq!(info.proxy, Some(proxy_addr));
+            assert_eq!(info.local, Some(stream.local_addr()?));
+            assert_eq!(info.requested, Address::try_from(destination_addr)?);
+            assert_eq!(info.binding, Address::new("0.0.0.0", 0));
+            assert_eq!(info.route, Route::Proxied);
+
+            let tunnel = tunnels.recv().await.unwrap();
+            assert_eq!(tunnel.peer, Some(stream.local_addr()?));
+            assert_eq!(tunnel.local, Some(proxy_addr));
+            assert_eq!(tunnel.requested, Address::try_from(destination_addr)?);
+            assert_eq!(tunnel.connected, Some(destination_addr));
+            assert_eq!(tunnel.outbound_local, Some(outbound.peer_addr()?));
+            let connect_info = tunnel.connect_info.unwrap();
+            assert_eq!((connect_info.addr, connect_info.attempts), (destination_addr, 1));
+            assert!(!connect_info.is_ipv6());
+
+            assert_eq!(wait_for_tunnels(&server, 1).await[0].info, tunnel);
+            drop(stream);
+            drop(outbound);
+            wait_for_tunnels(&server, 0).await;
+        }
+
+        Ok(())
+    }
+
+    // Tests that SOCKS6 requests with large options are refused with a general failure while the handshakes of other
+    // sources hold the memory budget, and that the budget is returned once those sessions are proxied.
+    #[tokio::test]
+    async fn test_memory_budget() -> Result<()> {
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let destination_addr = destination.local_addr()?;
+        tokio::spawn(async move {
+            let mut accepted = vec![];
+            while let Ok((stream, _)) = destination.accept().await {
+                accepted.push(stream);
+            }
+        });
+
+        let budget = MemoryBudget::new(4096);
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = listener.local_addr()?;
+        let server = SocksServer::new(listener, Arc::new(Socks6Handler::default())).with_memory_budget(budget.clone());
+        tokio::spawn(async move { server.run().await });
+
+        // Each request takes about 2 KB of options and initial data, which its source holds back.
+        let options = vec![UnrecognizedOption::new(0x1234, vec![0; 1000]).wrap()];
+        let destination = Address::try_from(destination_addr)?;
+        let request = Socks6Request::new(Socks6Command::Connect, destination, 1000, options, None);
+        let mut held = vec![];
+        let mut refused = 0;
+        for _ in 0..6 {
+            let mut client = TcpStream::connect(proxy_addr).await?;
+            client.write_all(&request.clone().into_socks_bytes()).await?;
+
+            // Requests beyond the budget are refused before authentication, with the operation reply alone.
+            let mut scratch = BytesMut::new();
+            let replied = wire::read_message(&mut client, &mut scratch, wire::parse_socks6_reply);
+            match tokio::time::timeout(Duration::from_millis(100), replied).await {
+                Ok(replied) => {
+                    assert_eq!(replied?.0, SOCKS_REP_GENERAL_FAILURE);
+                    refused += 1;
+                }
+                Err(_) => held.push(client),
+            }
+        }
+        assert_eq!((held.len(), refused), (2, 4));
+        assert!(budget.used() > 4000 && budget.used() <= 4096);
+
+        for mut client in held {
+            client.write_all(&[0; 1000]).await?;
+            let mut scratch = BytesMut::new();
+            wire::read_message(&mut client, &mut scratch, wire::parse_socks6_auth_reply).await?;
+            let (reply_code, _, _) = wire::read_message(&mut client, &mut scratch, wire::parse_socks6_reply).await?;
+            assert_eq!(reply_code, SOCKS_REP_SUCCEEDED);
+        }
+        for _ in 0..200 {
+            if budget.used() == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(budget.used(), 0);
+
+        Ok(())
+    }
+
+    // Tests that the tunnels of a server with a bandwidth limit share it, and leave it once they are closed.
+    #[tokio::test]
+    async fn test_bandwidth_limit() -> Result<()> {
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let destination_addr = destination.local_addr()?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = listener.local_addr()?;
+        let limit = BandwidthLimit::new(1_000_000)?;
+        let server = SocksServer::new(listener, Arc::new(Socks5Handler::default())).with_bandwidth_limit(limit);
+        let server = Arc::new(server);
+        let running = Arc::clone(&server);
+        tokio::spawn(async move { running.run().await });
+        assert!(server.tunnel_rates().is_empty());
+
+        let client = Socks5Client::new(proxy_addr.to_string(), None).await?;
+        let (mut stream, _) = client.connect(destination_addr, None).await?;
+        let (mut outbound, _) = destination.accept().await?;
+        stream.write_all(b"ping").await?;
+        let mut received = [0; 4];
+        outbound.read_exact(&mut received).await?;
+        assert_eq!(&received, b"ping");
+        assert_eq!(server.tunnel_rates().len(), 1);
+
+        drop((stream, outbound));
+        for _ in 0..200 {
+            if server.tunnel_rates().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert!(server.tunnel_rates().is_empty());
+
+        Ok(())
+    }
+
+    // Tests parsing the kebab-case names of the overflow and rate limit policies.
+    #[test]
+    fn test_overflow_policy_from_str() {
+        assert_eq!("backpressure".parse::<OverflowPolicy>().unwrap(), OverflowPolicy::Backpressure);
+        assert_eq!("shed-oldest".parse::<OverflowPolicy>().unwrap(), OverflowPolicy::ShedOldest);
+        assert!("shed-newest".parse::<OverflowPolicy>().is_err());
+        assert_eq!("delay".parse::<RateLimitPolicy>().unwrap(), RateLimitPolicy::Delay);
+        assert_eq!("close".parse::<RateLimitPolicy>().unwrap(), RateLimitPolicy::Close);
+    }
+}
@@ -0,0 +1,676 @@
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::future::{self, Future};
+use std::io;
+use std::hash::{BuildHasher, Hasher};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use anyhow::Result;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::time::Instant;
+
+use crate::bandwidth::{Share, Throttled};
+use crate::dialer::ConnectInfo;
+use crate::interface::AsyncStream;
+use crate::mirror::Mirror;
+use crate::{Address, Baggage, SocksError};
+
+tokio::task_local! {
+    // The registry that the tunnels established by the current task are listed in, if any.
+    static TUNNELS: TunnelRegistry;
+}
+
+/// The SOCKS6 metadata key under which a connection ID is propagated, just below the keys reserved for chaining.
+pub const CONNECTION_ID_METADATA_KEY: u16 = 997;
+
+/// Identifies a session, i.e., a single connection from a client through a proxy, for log correlation.
+///
+/// IDs are displayed as 16 hexadecimal digits. Within a process they never repeat, and the random
+/// seed they are derived from makes collisions between processes unlikely.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct ConnectionId(u64);
+
+impl ConnectionId {
+    /// Generates a new connection ID.
+    pub fn generate() -> Self {
+        static SEED: OnceLock<u64> = OnceLock::new();
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        let seed = *SEED.get_or_init(|| RandomState::new().build_hasher().finish());
+        let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+
+        Self(mix(seed.wrapping_add(count.wrapping_mul(0x9E37_79B9_7F4A_7C15))))
+    }
+
+    /// Returns the numeric value of the ID.
+    pub fn value(self) -> u64 {
+        self.0
+    }
+
+    /// Returns the ID of the session the error was produced in, if it is known.
+    pub fn of(error: &anyhow::Error) -> Option<Self> {
+        error.downcast_ref::<SessionError>().map(|error| error.id)
+    }
+
+    /// Marks the error as produced in this session, prefixing its message with the ID.
+    ///
+    /// The original error can still be retrieved with `downcast_ref`.
+    pub fn attach(
+        self,
+        error: anyhow::Error,
+    ) -> anyhow::Error {
+        if Self::of(&error).is_some() {
+            return error;
+        }
+
+        let message = error.to_string();
+        error.context(SessionError { id: self, message })
+    }
+}
+
+impl From<u64> for ConnectionId {
+    fn from(value: u64) -> Self {
+        Self(value)
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+impl FromStr for ConnectionId {
+    type Err = anyhow::Error;
+
+    fn from_str(id: &str) -> Result<Self> {
+        ensure!(id.len() == 16, "Invalid connection ID: {}", id);
+        let value = u64::from_str_radix(id, 16).map_err(|_| anyhow!("Invalid connection ID: {}", id))?;
+
+        Ok(Self(value))
+    }
+}
+
+/// An error produced during a session, displayed with the session's connection ID.
+#[derive(Clone, Debug, Error)]
+#[error("[{id}] {message}")]
+pub struct SessionError {
+    /// The ID of the session.
+    pub id: ConnectionId,
+    message: String,
+}
+
+/// What a handler knows about a session once it accepted the request, for its session hooks.
+#[derive(Clone, Debug)]
+pub struct SessionInfo {
+    /// The connection ID of the session.
+    pub id: ConnectionId,
+    /// The destination of the request.
+    pub destination: Address,
+    /// The name the source authenticated with, if it did.
+    pub identity: Option<String>,
+    /// The baggage sent along with the request, which is passed on to the next link of a chain (SOCKS6 only).
+    pub baggage: Baggage,
+}
+
+/// The addresses of both legs of an established session, for diagnostics.
+///
+/// Addresses are `None` where the transport doesn't have them, e.g., for in-memory streams, or connectors that
+/// don't report them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TunnelInfo {
+    /// The address of the source.
+    pub peer: Option<SocketAddr>,
+    /// The local address the source connected to.
+    pub local: Option<SocketAddr>,
+    /// The destination, as requested by the source.
+    pub requested: Address,
+    /// The address that was connected to: the destination after resolution, or the next link of a chain.
+    pub connected: Option<SocketAddr>,
+    /// The local address of the connection to the destination, or the next link.
+    pub outbound_local: Option<SocketAddr>,
+    /// The attempt that won the race of the connection to the destination, or the next link, which tells how
+    /// many attempts were started and which address family won. `None` for connectors that don't race attempts.
+    pub connect_info: Option<ConnectInfo>,
+}
+
+/// A tunnel that is established and still relaying, as listed by `SocksServer::tunnels`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LiveTunnel {
+    /// The connection ID of the session of the tunnel.
+    pub id: ConnectionId,
+    /// The addresses of both legs of the tunnel.
+    pub info: TunnelInfo,
+}
+
+// The tunnels that were established within the scope of a server and still relay, in the order they were
+// established.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TunnelRegistry {
+    tunnels: Arc<Mutex<Vec<Arc<LiveTunnel>>>>,
+}
+
+impl TunnelRegistry {
+    // Returns the tunnels that are listed.
+    pub(crate) fn tunnels(&self) -> Vec<LiveTunnel> {
+        self.tunnels.lock().unwrap().iter().map(|tunnel| LiveTunnel::clone(tunnel)).collect()
+    }
+
+    // Runs the future with the registry as the one that its tunnels are listed in.
+    pub(crate) async fn scope<F: Future>(
+        self,
+        future: F,
+    ) -> F::Output {
+        TUNNELS.scope(self, future).await
+    }
+}
+
+// A tunnel's entry in the registry of its task, which is removed when dropped.
+pub(crate) struct Listing {
+    registry: TunnelRegistry,
+    tunnel: Arc<LiveTunnel>,
+}
+
+impl Listing {
+    // Lists the tunnel of the session in the registry of the current task, if any.
+    pub(crate) fn current(
+        id: ConnectionId,
+        info: &TunnelInfo,
+    ) -> Option<Self> {
+        TUNNELS
+            .try_with(|registry| {
+                let tunnel = Arc::new(LiveTunnel { id, info: info.clone() });
+                registry.tunnels.lock().unwrap().push(Arc::clone(&tunnel));
+
+                Self {
+                    registry: registry.clone(),
+                    tunnel,
+                }
+            })
+            .ok()
+    }
+}
+
+impl Drop for Listing {
+    fn drop(&mut self) {
+        let mut tunnels = self.registry.tunnels.lock().unwrap();
+        tunnels.retain(|tunnel| !Arc::ptr_eq(tunnel, &self.tunnel));
+    }
+}
+
+/// Callbacks that let a handler decide on each session individually, installed with `with_hooks`.
+///
+/// Every callback has a default that leaves the session as the handler is configured.
+pub trait SessionHooks {
+    /// Returns a mirror for the bytes relayed in the session, if it is to be captured.
+    ///
+    /// The mirror receives everything sent to the destination, initial data included, and everything received
+    /// from it.
+    fn mirror(
+        &self,
+        _session: &SessionInfo,
+    ) -> Option<Mirror> {
+        None
+    }
+
+    /// Returns the limits of the session, given the limits of the handler, e.g., to lift them for some
+    /// identities.
+    fn limits(
+        &self,
+        _session: &SessionInfo,
+        limits: SessionLimits,
+    ) -> SessionLimits {
+        limits
+    }
+
+    /// Called once the session is established, before the relay starts, with the addresses of both legs.
+    fn on_established(
+        &self,
+        _session: &SessionInfo,
+        _tunnel: &TunnelInfo,
+    ) {
+    }
+
+    /// Called once the relay of the session ended, with how it ended.
+    fn on_close(
+        &self,
+        _session: &SessionInfo,
+        _summary: &CloseSummary,
+    ) {
+    }
+}
+
+/// Limits on a relayed session, after which it is closed regardless of its peers.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SessionLimits {
+    /// How long the relay may last.
+    pub lifetime: Option<Duration>,
+    /// How many bytes may be relayed, in both directions combined.
+    pub bytes: Option<u64>,
+}
+
+impl SessionLimits {
+    /// Creates limits that don't limit anything.
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    /// Limits how long the relay may last.
+    pub fn with_lifetime(
+        mut self,
+        lifetime: Duration,
+    ) -> Self {
+        self.lifetime = Some(lifetime);
+        self
+    }
+
+    /// Limits how many bytes may be relayed, in both directions combined.
+    pub fn with_byte_cap(
+        mut self,
+        bytes: u64,
+    ) -> Self {
+        self.bytes = Some(bytes);
+        self
+    }
+}
+
+/// Why a relayed session ended.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CloseReason {
+    /// Both sides closed the connection.
+    Closed,
+    /// The relay lasted as long as its limits allow.
+    LifetimeExceeded,
+    /// The relay relayed as many bytes as its limits allow.
+    ByteCapExceeded,
+    /// The policy of the handler was updated, and no longer allows the session.
+    PolicyRevoked,
+    /// The UDP association relayed no datagrams for as long as its idle timeout.
+    IdleTimeout,
+    /// Relaying failed, e.g., because a connection was reset.
+    Failed,
+}
+
+/// How a relayed session ended.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CloseSummary {
+    /// The number of bytes relayed from the source to the destination.
+    pub sent: u64,
+    /// The number of bytes relayed from the destination to the source.
+    pub received: u64,
+    /// How long the relay lasted.
+    pub duration: Duration,
+    /// Why the relay ended.
+    pub reason: CloseReason,
+}
+
+/// Relays between the source and destination of a session until both close the connection, a limit is
+/// exceeded, or the session is revoked.
+///
+/// Within the scope of a `BandwidthLimit`, the relay reads no faster than the share of the limit it is allowed.
+///
+/// A connection that timed out, e.g., because keepalive probes went unanswered, fails with `SocksError::PeerDead`.
+/// The summary is returned along with the error, with `CloseReason::Failed`.
+pub(crate) async fn relay(
+    id: ConnectionId,
+    source: &mut dyn AsyncStream,
+    destination: &mut dyn AsyncStream,
+    limits: SessionLimits,
+    revoked: impl Future<Output = ()>,
+) -> (CloseSummary, Result<()>) {
+    let started = Instant::now();
+    let cap = ByteCap::new(limits.bytes);
+    let share = Share::current(id);
+    let quota = share.as_ref().map(Share::quota);
+    let mut source = Metered::new(Throttled::new(source, quota.clone(), 0), &cap, 0);
+    let mut destination = Metered::new(Throttled::new(destination, quota, 1), &cap, 1);
+
+    // The lifetime is the only timer of a relay; the byte cap is enforced by the streams as they are read.
+    let lifetime = async {
+        match limits.lifetime {
+            Some(lifetime) => tokio::time::sleep(lifetime).await,
+            None => future::pending().await,
+        }
+    };
+    let (reason, relayed) = tokio::select! {
+        copied = tokio::io::copy_bidirectional(&mut source, &mut destination) => match copied {
+            Ok(_) if cap.is_reached() => (CloseReason::ByteCapExceeded, Ok(())),
+            Ok(_) => (CloseReason::Closed, Ok(())),
+            Err(e) => {
+                let error = match e.kind() {
+                    io::ErrorKind::TimedOut => SocksError::PeerDead(e).into(),
+                    _ => e.into(),
+                };
+                (CloseReason::Failed, Err(id.attach(error)))
+            }
+        },
+        _ = lifetime => (CloseReason::LifetimeExceeded, Ok(())),
+        _ = revoked => (CloseReason::PolicyRevoked, Ok(())),
+    };
+    if reason != CloseReason::Closed && reason != CloseReason::ByteCapExceeded {
+        // Let both sides know the session is over, as far as they still listen.
+        let _ = source.shutdown().await;
+        let _ = destination.shutdown().await;
+    }
+
+    let summary = CloseSummary {
+        sent: source.count,
+        received: destination.count,
+        duration: started.elapsed(),
+        reason,
+    };
+    debug!(
+        "[{}] Session closed after {}ms, having sent {} and received {} bytes ({:?}).",
+        id,
+        summary.duration.as_millis(),
+        summary.sent,
+        summary.received,
+        summary.reason
+    );
+
+    (summary, relayed)
+}
+
+// The bytes a relay may still relay in both directions combined, shared by its streams.
+struct ByteCap {
+    remaining: AtomicU64,
+    // The streams that wait for a read, to be woken once the cap is reached so they can end as well.
+    waiting: Mutex<[Option<Waker>; 2]>,
+}
+
+impl ByteCap {
+    fn new(bytes: Option<u64>) -> Self {
+        Self {
+            remaining: AtomicU64::new(bytes.unwrap_or(u64::MAX)),
+            waiting: Mutex::new([None, None]),
+        }
+    }
+
+    fn is_reached(&self) -> bool {
+        self.remaining.load(Ordering::Relaxed) == 0
+    }
+
+    fn consume(
+        &self,
+        length: u64,
+    ) {
+        if self.remaining.fetch_sub(length, Ordering::Relaxed) == length {
+            for waker in self.waiting.lock().unwrap().iter_mut().filter_map(Option::take) {
+                waker.wake();
+            }
+        }
+    }
+}
+
+// A stream of a relay that counts the bytes read from it, and reads as ended once the byte cap is reached.
+struct Metered<'a, S> {
+    inner: S,
+    cap: &'a ByteCap,
+    slot: usize,
+    count: u64,
+}
+
+impl<'a, S> Metered<'a, S> {
+    fn new(
+        inner: S,
+        cap: &'a ByteCap,
+        slot: usize,
+    ) -> Self {
+        Self {
+            inner,
+            cap,
+            slot,
+            count: 0,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<'_, S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let remaining = this.cap.remaining.load(Ordering::Relaxed);
+        if remaining == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        let read = if remaining < buf.remaining() as u64 {
+            // Read no more than the cap allows, so the counts end up exactly at the cap.
+            let mut limited = vec![0; remaining as usize];
+            let mut limited = ReadBuf::new(&mut limited);
+            let read = Pin::new(&mut this.inner).poll_read(cx, &mut limited);
+            buf.put_slice(limited.filled());
+            read.map_ok(|_| limited.filled().len())
+        } else {
+            let filled = buf.filled().len();
+            Pin::new(&mut this.inner).poll_read(cx, buf).map_ok(|_| buf.filled().len() - filled)
+        };
+
+        match read {
+            Poll::Ready(Ok(length)) => {
+                this.count += length as u64;
+                this.cap.consume(length as u64);
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => {
+                this.cap.waiting.lock().unwrap()[this.slot] = Some(cx.waker().clone());
+
+                // The other stream may have reached the cap in the meantime.
+                if this.cap.is_reached() {
+                    return Poll::Ready(Ok(()));
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<'_, S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Scrambles the bits of a counter value (the splitmix64 finalizer), which never maps two values to the same ID.
+fn mix(mut value: u64) -> u64 {
+    value = (value ^ (value >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    value ^ (value >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::Arc;
+
+    use tokio::io::AsyncReadExt;
+    use tokio::sync::mpsc;
+
+    use crate::test_util::Harness;
+    use crate::{Socks6Client, Socks6Handler};
+
+    use super::*;
+
+    // Tests that generated IDs don't repeat, and survive a round trip through their display.
+    #[test]
+    fn test_generate() {
+        let ids: HashSet<_> = (0..10_000).map(|_| ConnectionId::generate()).collect();
+        assert_eq!(ids.len(), 10_000);
+
+        let id = ConnectionId::generate();
+        assert_eq!(id.to_string().len(), 16);
+        assert_eq!(id.to_string().parse::<ConnectionId>().unwrap(), id);
+        assert!("abc".parse::<ConnectionId>().is_err());
+        assert!("zzzzzzzzzzzzzzzz".parse::<ConnectionId>().is_err());
+    }
+
+    // Tests that an attached ID shows up in the message, without hiding the original error.
+    #[test]
+    fn test_attach() {
+        let id = ConnectionId::from(0x2A);
+        let error = id.attach(SocksError::AuthenticationFailed(None).into());
+        assert_eq!(error.to_string(), "[000000000000002a] Authentication failed.");
+        assert_eq!(ConnectionId::of(&error), Some(id));
+        assert!(matches!(error.downcast_ref(), Some(SocksError::AuthenticationFailed(None))));
+
+        // The ID is only attached once.
+        let error = ConnectionId::from(0x2B).attach(error);
+        assert_eq!(ConnectionId::of(&error), Some(id));
+        assert!(ConnectionId::of(&anyhow!("Not in a session")).is_none());
+    }
+
+    // Hooks that cap the bytes of every session, and pass on how sessions end.
+    struct CappedHooks {
+        closed: mpsc::UnboundedSender<CloseSummary>,
+    }
+
+    impl SessionHooks for CappedHooks {
+        fn limits(
+            &self,
+            _session: &SessionInfo,
+            limits: SessionLimits,
+        ) -> SessionLimits {
+            limits.with_byte_cap(10)
+        }
+
+        fn on_close(
+            &self,
+            _session: &SessionInfo,
+            summary: &CloseSummary,
+        ) {
+            self.closed.send(*summary).unwrap();
+        }
+    }
+
+    // Tests that a session is closed once the byte cap of its hooks is reached, counting exactly up to the cap.
+    #[tokio::test]
+    async fn test_byte_cap() -> Result<()> {
+        let (closed, mut summaries) = mpsc::unbounded_channel();
+        let harness = Harness::socks6(Socks6Handler::default().with_hooks(Arc::new(CappedHooks { closed })));
+        let client = Socks6Client::for_streams(None);
+
+        let (mut stream, _) = harness.connect_socks6(&client, "example.com:80", None, None).await?;
+        stream.write_all(b"12345678").await?;
+
+        // The destination echoes all 8 bytes, of which only 2 fit within the cap.
+        let mut echoed = vec![];
+        stream.read_to_end(&mut echoed).await?;
+        assert_eq!(echoed, b"12");
+
+        let summary = summaries.recv().await.unwrap();
+        assert_eq!((summary.sent, summary.received), (8, 2));
+        assert_eq!(summary.reason, CloseReason::ByteCapExceeded);
+
+        Ok(())
+    }
+
+    // Tests that a relay is closed once its lifetime passed, even though neither side closed the connection.
+    #[tokio::test(start_paused = true)]
+    async fn test_lifetime() -> Result<()> {
+        let (mut client, mut source) = tokio::io::duplex(1024);
+        let (mut destination, mut server) = tokio::io::duplex(1024);
+        let limits = SessionLimits::none().with_lifetime(Duration::from_secs(60));
+        let relay = tokio::spawn(async move {
+            relay(ConnectionId::generate(), &mut source, &mut destination, limits, future::pending()).await
+        });
+
+        client.write_all(b"hello").await?;
+        server.read_exact(&mut [0; 5]).await?;
+
+        let (summary, relayed) = relay.await?;
+        relayed?;
+        assert_eq!((summary.sent, summary.received), (5, 0));
+        assert_eq!(summary.reason, CloseReason::LifetimeExceeded);
+        assert_eq!(summary.duration, Duration::from_secs(60));
+        assert_eq!(client.read(&mut [0; 1]).await?, 0);
+        assert_eq!(server.read(&mut [0; 1]).await?, 0);
+
+        Ok(())
+    }
+
+    // Stream whose reads fail as if the peer reset the connection.
+    struct ResetStream;
+
+    impl AsyncRead for ResetStream {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()))
+        }
+    }
+
+    impl AsyncWrite for ResetStream {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    // Tests that a relay that fails still summarizes the session, as having failed, along with the error.
+    #[tokio::test]
+    async fn test_relay_failed() -> Result<()> {
+        let (_destination, mut server) = tokio::io::duplex(1024);
+        let id = ConnectionId::generate();
+        let limits = SessionLimits::none();
+        let (summary, relayed) = relay(id, &mut ResetStream, &mut server, limits, future::pending()).await;
+
+        assert_eq!(summary.reason, CloseReason::Failed);
+        assert_eq!((summary.sent, summary.received), (0, 0));
+        let error = relayed.unwrap_err();
+        assert_eq!(ConnectionId::of(&error), Some(id));
+        assert_eq!(error.downcast_ref::<io::Error>().map(io::Error::kind), Some(io::ErrorKind::ConnectionReset));
+
+        Ok(())
+    }
+}
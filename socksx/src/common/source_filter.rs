@@ -0,0 +1,98 @@
+use std::net::IpAddr;
+
+use crate::addresses::IpNetwork;
+
+/// Decides which peers may connect to a listener, by their source address, before anything is read from them.
+///
+/// A peer is denied if its address is in one of the denied networks, or if there are allowed networks and its
+/// address is in none of them. Denied networks take precedence over allowed ones, so that, e.g., a host can be
+/// singled out of an allowed network. The default filter allows every peer.
+///
+/// Unlike a `DestinationGuard`, which decides on the destinations of requests, the filter decides on
+/// connections, which `SocksServer` and `TransparentProxy` close as soon as they accepted them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SourceFilter {
+    allowed: Vec<IpNetwork>,
+    denied: Vec<IpNetwork>,
+}
+
+impl SourceFilter {
+    /// Creates a filter that allows every peer.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allows peers in the network. Once a network is allowed, peers outside of every allowed network are denied.
+    pub fn with_allowed(
+        mut self,
+        network: IpNetwork,
+    ) -> Self {
+        self.allowed.push(network);
+        self
+    }
+
+    /// Denies peers in the network, even if an allowed network contains them.
+    pub fn with_denied(
+        mut self,
+        network: IpNetwork,
+    ) -> Self {
+        self.denied.push(network);
+        self
+    }
+
+    /// Returns the allowed networks.
+    pub fn allowed(&self) -> &[IpNetwork] {
+        &self.allowed
+    }
+
+    /// Returns the denied networks.
+    pub fn denied(&self) -> &[IpNetwork] {
+        &self.denied
+    }
+
+    /// Returns whether a peer with the source address may connect.
+    pub fn permits(
+        &self,
+        source: IpAddr,
+    ) -> bool {
+        if self.denied.iter().any(|network| network.contains(source)) {
+            return false;
+        }
+
+        self.allowed.is_empty() || self.allowed.iter().any(|network| network.contains(source))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+
+    // Tests that denied networks take precedence over allowed ones, and that allowing a network denies the rest.
+    #[test]
+    fn test_permits() -> Result<()> {
+        let addr = |addr: &str| addr.parse::<IpAddr>().unwrap();
+
+        let filter = SourceFilter::new();
+        assert!(filter.permits(addr("192.0.2.1")));
+        assert!(filter.permits(addr("2001:db8::1")));
+
+        let filter = SourceFilter::new().with_denied("192.0.2.0/24".parse()?);
+        assert!(!filter.permits(addr("192.0.2.1")));
+        assert!(!filter.permits(addr("::ffff:192.0.2.1")));
+        assert!(filter.permits(addr("198.51.100.1")));
+
+        let filter = SourceFilter::new()
+            .with_allowed("10.0.0.0/8".parse()?)
+            .with_allowed("[fd00::]/8".parse()?)
+            .with_denied("10.0.0.13".parse()?);
+        assert!(filter.permits(addr("10.1.2.3")));
+        assert!(filter.permits(addr("fd12::1")));
+        assert!(!filter.permits(addr("10.0.0.13")));
+        assert!(!filter.permits(addr("192.0.2.1")));
+        assert!(!filter.permits(addr("2001:db8::1")));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,193 @@
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::net::TcpStream;
+
+use crate::addresses::Address;
+use crate::bypass::Route;
+
+/// A stream that the clients return: a tunnel through the proxy, over the transport that the proxy is reached
+/// with, or a direct connection to a destination that bypasses the proxy.
+#[derive(Debug)]
+pub enum SocksStream {
+    /// A TCP connection, to the proxy or directly to the destination.
+    Tcp(TcpStream),
+    /// A connection to a proxy that listens on a Unix socket, see `Socks5Client::new`.
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+/// The addresses of a tunnel that a client established, the client-side counterpart of `TunnelInfo`.
+///
+/// Returned by `connect_with_info` on the clients. Addresses are `None` for a stream over a Unix socket.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientTunnelInfo {
+    /// The address of the proxy, or of the destination if it was connected to directly.
+    pub proxy: Option<SocketAddr>,
+    /// The local address of the connection.
+    pub local: Option<SocketAddr>,
+    /// The destination, as requested.
+    pub requested: Address,
+    /// The address the proxy reported to have bound for the tunnel, or the local address of a direct connection.
+    pub binding: Address,
+    /// Whether the tunnel goes through the proxy.
+    pub route: Route,
+}
+
+impl SocksStream {
+    // Describes the tunnel of the stream, to the requested destination and with the binding of the reply.
+    pub(crate) fn tunnel_info(
+        &self,
+        requested: Address,
+        binding: Address,
+        route: Route,
+    ) -> ClientTunnelInfo {
+        ClientTunnelInfo {
+            proxy: self.peer_addr().ok(),
+            local: self.local_addr().ok(),
+            requested,
+            binding,
+            route,
+        }
+    }
+
+    /// Returns the TCP connection, unless the stream is of another transport.
+    pub fn as_tcp(&self) -> Option<&TcpStream> {
+        match self {
+            SocksStream::Tcp(stream) => Some(stream),
+            #[cfg(unix)]
+            SocksStream::Unix(_) => None,
+        }
+    }
+
+    /// Returns the TCP connection, or the stream itself if it is of another transport.
+    pub fn into_tcp(self) -> Result<TcpStream, Self> {
+        match self {
+            SocksStream::Tcp(stream) => Ok(stream),
+            #[cfg(unix)]
+            stream => Err(stream),
+        }
+    }
+
+    /// Returns the address of the remote end of a TCP connection, i.e., of the proxy or a bypassed destination.
+    ///
+    /// Fails with `io::ErrorKind::Unsupported` for a stream over a Unix socket.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            SocksStream::Tcp(stream) => stream.peer_addr(),
+            #[cfg(unix)]
+            SocksStream::Unix(_) => Err(io::ErrorKind::Unsupported.into()),
+        }
+    }
+
+    /// Returns the address of the local end of a TCP connection.
+    ///
+    /// Fails with `io::ErrorKind::Unsupported` for a stream over a Unix socket.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            SocksStream::Tcp(stream) => stream.local_addr(),
+            #[cfg(unix)]
+            SocksStream::Unix(_) => Err(io::ErrorKind::Unsupported.into()),
+        }
+    }
+
+    // Reads what is available without waiting, like `TcpStream::try_read`.
+    pub(crate) fn try_read(
+        &self,
+        buffer: &mut [u8],
+    ) -> io::Result<usize> {
+        match self {
+            SocksStream::Tcp(stream) => stream.try_read(buffer),
+            #[cfg(unix)]
+            SocksStream::Unix(stream) => stream.try_read(buffer),
+        }
+    }
+}
+
+impl From<TcpStream> for SocksStream {
+    fn from(stream: TcpStream) -> Self {
+        SocksStream::Tcp(stream)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixStream> for SocksStream {
+    fn from(stream: UnixStream) -> Self {
+        SocksStream::Unix(stream)
+    }
+}
+
+impl AsyncRead for SocksStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            SocksStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            SocksStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for SocksStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            SocksStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            SocksStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            SocksStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            SocksStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            SocksStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            SocksStream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            SocksStream::Tcp(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            #[cfg(unix)]
+            SocksStream::Unix(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            SocksStream::Tcp(stream) => stream.is_write_vectored(),
+            #[cfg(unix)]
+            SocksStream::Unix(stream) => stream.is_write_vectored(),
+        }
+    }
+}
@@ -0,0 +1,325 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{self, Instant, Sleep};
+
+/// Read and write inactivity timeouts for a `TimeoutStream`.
+///
+/// Both are disabled by default.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Timeouts {
+    /// How long a read may make no progress before it fails.
+    pub read: Option<Duration>,
+    /// How long a write, flush, or shutdown may make no progress before it fails.
+    pub write: Option<Duration>,
+}
+
+impl Timeouts {
+    /// Returns whether neither timeout is set.
+    pub fn is_disabled(&self) -> bool {
+        self.read.is_none() && self.write.is_none()
+    }
+}
+
+/// The timer of one direction of a `TimeoutStream`, armed while an operation is pending.
+#[derive(Debug, Default)]
+struct Timer {
+    timeout: Option<Duration>,
+    sleep: Option<Pin<Box<Sleep>>>,
+    armed: bool,
+}
+
+impl Timer {
+    fn new(timeout: Option<Duration>) -> Self {
+        Self {
+            timeout,
+            ..Default::default()
+        }
+    }
+
+    fn set_timeout(
+        &mut self,
+        timeout: Option<Duration>,
+    ) {
+        self.timeout = timeout;
+        self.armed = false;
+    }
+
+    /// Checks a pending operation against the timer, arming it if the operation just became pending.
+    fn poll_pending<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<T>> {
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return Poll::Pending,
+        };
+
+        // The sleep is allocated once, and reset to a new deadline each time an operation becomes pending.
+        let sleep = self.sleep.get_or_insert_with(|| Box::pin(time::sleep(timeout)));
+        if !self.armed {
+            sleep.as_mut().reset(Instant::now() + timeout);
+            self.armed = true;
+        }
+
+        match sleep.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                self.armed = false;
+                Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("No progress within {:?}", timeout),
+                )))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    /// Observes the result of an operation, disarming the timer once it made progress.
+    fn poll<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        result: Poll<io::Result<T>>,
+    ) -> Poll<io::Result<T>> {
+        match result {
+            Poll::Ready(result) => {
+                self.armed = false;
+                Poll::Ready(result)
+            }
+            Poll::Pending => self.poll_pending(cx),
+        }
+    }
+}
+
+/// A stream whose reads and writes fail with `io::ErrorKind::TimedOut` if they make no progress in time.
+///
+/// The timer of a direction starts when an operation in that direction can't complete right away, and is reset
+/// whenever one completes, so a slow but steady peer never times out. Without timeouts, operations are passed
+/// through to the inner stream as they are.
+#[derive(Debug)]
+pub struct TimeoutStream<S> {
+    inner: S,
+    read: Timer,
+    write: Timer,
+}
+
+impl<S> TimeoutStream<S> {
+    /// Wraps the stream with the given timeouts.
+    pub fn new(
+        inner: S,
+        timeouts: Timeouts,
+    ) -> Self {
+        Self {
+            inner,
+            read: Timer::new(timeouts.read),
+            write: Timer::new(timeouts.write),
+        }
+    }
+
+    /// Sets the read inactivity timeout, or disables it with `None`.
+    pub fn set_read_timeout(
+        &mut self,
+        timeout: Option<Duration>,
+    ) {
+        self.read.set_timeout(timeout);
+    }
+
+    /// Sets the write inactivity timeout, or disables it with `None`.
+    pub fn set_write_timeout(
+        &mut self,
+        timeout: Option<Duration>,
+    ) {
+        self.write.set_timeout(timeout);
+    }
+
+    /// Returns the current timeouts.
+    pub fn timeouts(&self) -> Timeouts {
+        Timeouts {
+            read: self.read.timeout,
+            write: self.write.timeout,
+        }
+    }
+
+    /// Returns a reference to the inner stream.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the inner stream.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Returns the inner stream, dropping the timeouts.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TimeoutStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        this.read.poll(cx, result)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TimeoutStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        this.write.poll(cx, result)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_flush(cx);
+        this.write.poll(cx, result)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_shutdown(cx);
+        this.write.poll(cx, result)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_write_vectored(cx, bufs);
+        this.write.poll(cx, result)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    fn read_only(timeout: u64) -> Timeouts {
+        Timeouts {
+            read: Some(Duration::from_secs(timeout)),
+            write: None,
+        }
+    }
+
+    // Tests that a read from a stalled peer fails with TimedOut once the timeout has passed.
+    #[tokio::test(start_paused = true)]
+    async fn test_read_timeout() -> Result<()> {
+        let (stream, _peer) = duplex(64);
+        let mut stream = TimeoutStream::new(stream, read_only(5));
+
+        let started = Instant::now();
+        let error = stream.read(&mut [0; 8]).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() >= Duration::from_secs(5));
+
+        Ok(())
+    }
+
+    // Tests that each read that makes progress resets the timer, even if the total time exceeds the timeout.
+    #[tokio::test(start_paused = true)]
+    async fn test_read_progress_resets_timer() -> Result<()> {
+        let (stream, mut peer) = duplex(64);
+        let mut stream = TimeoutStream::new(stream, read_only(5));
+
+        tokio::spawn(async move {
+            for byte in 0..3u8 {
+                time::sleep(Duration::from_secs(4)).await;
+                peer.write_all(&[byte]).await.unwrap();
+            }
+
+            // Keep the peer open, but stalled.
+            time::sleep(Duration::from_secs(60)).await;
+        });
+
+        let mut received = [0; 3];
+        stream.read_exact(&mut received).await?;
+        assert_eq!(received, [0, 1, 2]);
+
+        let error = stream.read(&mut [0; 8]).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+
+        Ok(())
+    }
+
+    // Tests that a write to a peer that stopped reading fails with TimedOut, and a slow reader resets the timer.
+    #[tokio::test(start_paused = true)]
+    async fn test_write_timeout() -> Result<()> {
+        let (stream, mut peer) = duplex(4);
+        let timeouts = Timeouts {
+            read: None,
+            write: Some(Duration::from_secs(5)),
+        };
+        let mut stream = TimeoutStream::new(stream, timeouts);
+
+        let reader = tokio::spawn(async move {
+            let mut received = [0; 8];
+            for chunk in received.chunks_mut(4) {
+                time::sleep(Duration::from_secs(4)).await;
+                peer.read_exact(chunk).await.unwrap();
+            }
+
+            // Stop reading, but keep the peer open.
+            time::sleep(Duration::from_secs(60)).await;
+            received
+        });
+
+        stream.write_all(&[1; 12]).await?;
+        let error = stream.write_all(&[2; 4]).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(reader.await?, [1; 8]);
+
+        Ok(())
+    }
+
+    // Tests that timeouts can be changed on an existing stream, and that disabled timeouts never fire.
+    #[tokio::test(start_paused = true)]
+    async fn test_set_timeouts() -> Result<()> {
+        let (stream, mut peer) = duplex(64);
+        let mut stream = TimeoutStream::new(stream, Timeouts::default());
+        assert!(stream.timeouts().is_disabled());
+
+        tokio::spawn(async move {
+            time::sleep(Duration::from_secs(600)).await;
+            peer.write_all(b"late").await.unwrap();
+            time::sleep(Duration::from_secs(600)).await;
+        });
+
+        let mut received = [0; 4];
+        stream.read_exact(&mut received).await?;
+        assert_eq!(&received, b"late");
+
+        stream.set_read_timeout(Some(Duration::from_secs(1)));
+        let error = stream.read(&mut received).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+
+        Ok(())
+    }
+}
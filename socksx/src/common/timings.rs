@@ -0,0 +1,79 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// How long each phase of a connect through a proxy took, e.g., to tell whether a slow connect was spent on
+/// resolving the proxy, reaching it, authenticating, or waiting for the proxy to reach the destination.
+///
+/// The phases are those of the attempt that succeeded. Attempts that failed before it, as the retry policy
+/// allows, only count toward `total`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HandshakeTimings {
+    /// Resolving the hostname of the proxy again, if the re-resolution policy said so.
+    pub resolution: Option<Duration>,
+    /// Connecting to the proxy, from the first connection attempt of the race until one connected, or taking a
+    /// pooled connection.
+    pub proxy_connect: Duration,
+    /// The address of the proxy the winning connection attempt connected to, unless the connection was pooled or is
+    /// over a Unix socket.
+    pub proxy_addr: Option<SocketAddr>,
+    /// The number of connection attempts the race started, including the winning one, or 0 for a pooled connection.
+    pub connect_attempts: usize,
+    /// The TLS handshake with the proxy, if it was reached over TLS.
+    pub tls: Option<Duration>,
+    /// The authentication, from sending the greeting (SOCKS5) or the request (SOCKS6) until the proxy accepted the
+    /// client, challenges included.
+    pub authentication: Duration,
+    /// Waiting for the operation reply, which the proxy sends once it reached the destination.
+    pub operation_reply: Duration,
+    /// The number of attempts the retry policy made, including the one that succeeded.
+    pub attempts: u32,
+    /// The whole connect, every attempt included.
+    pub total: Duration,
+}
+
+impl HandshakeTimings {
+    // Returns the timings of a new attempt, with the given number.
+    pub(crate) fn attempt(attempt: u32) -> Self {
+        Self {
+            attempts: attempt,
+            ..Self::default()
+        }
+    }
+
+    /// Returns the phases that were measured, by the name they are labeled with in the metrics.
+    pub fn phases(&self) -> Vec<(&'static str, Duration)> {
+        let mut phases = vec![];
+        if let Some(resolution) = self.resolution {
+            phases.push(("resolution", resolution));
+        }
+        phases.push(("proxy_connect", self.proxy_connect));
+        if let Some(tls) = self.tls {
+            phases.push(("tls", tls));
+        }
+        phases.push(("authentication", self.authentication));
+        phases.push(("operation_reply", self.operation_reply));
+        phases.push(("total", self.total));
+
+        phases
+    }
+}
+
+// Measures consecutive phases, each from the end of the previous one.
+pub(crate) struct Stopwatch(Instant);
+
+impl Stopwatch {
+    pub(crate) fn start() -> Self {
+        Self(Instant::now())
+    }
+
+    // Returns the time since the previous lap, or since the start, and starts the next lap.
+    pub(crate) fn lap(&mut self) -> Duration {
+        let now = Instant::now();
+        let lap = now - self.0;
+        self.0 = now;
+
+        lap
+    }
+}
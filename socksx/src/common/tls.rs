@@ -0,0 +1,458 @@
+use std::convert::TryFrom;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Result;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::crypto::{ring, CryptoProvider};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{self, ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{client, server, TlsAcceptor, TlsConnector};
+
+use crate::SocksError;
+
+/// Reads the certificates of a PEM file, e.g., a certificate chain or the roots to trust.
+pub fn load_certificates<P: AsRef<Path>>(path: P) -> Result<Vec<CertificateDer<'static>>> {
+    let certificates = CertificateDer::pem_file_iter(path.as_ref())?.collect::<Result<Vec<_>, _>>()?;
+    ensure!(!certificates.is_empty(), "No certificates in {}", path.as_ref().display());
+
+    Ok(certificates)
+}
+
+/// Reads the first private key of a PEM file, in PKCS#1, PKCS#8, or SEC1 format.
+pub fn load_private_key<P: AsRef<Path>>(path: P) -> Result<PrivateKeyDer<'static>> {
+    Ok(PrivateKeyDer::from_pem_file(path.as_ref())?)
+}
+
+/// Connects to proxies over TLS, optionally authenticating with a client certificate (mTLS).
+///
+/// Handed to `Socks5Client::with_tls` or `Socks6Client::with_tls`, after which their `connect_tls` runs the SOCKS
+/// handshake over TLS. The proxy's certificate is verified against the given roots, for the hostname of the proxy
+/// unless another server name is set.
+#[derive(Clone)]
+pub struct TlsClient {
+    connector: TlsConnector,
+    server_name: Option<ServerName<'static>>,
+}
+
+impl TlsClient {
+    /// Creates a TLS client that trusts the given roots, without a client certificate.
+    pub fn new(roots: Vec<CertificateDer<'static>>) -> Result<Self> {
+        let config = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(root_store(roots)?)
+            .with_no_client_auth();
+
+        Ok(Self::from_config(config))
+    }
+
+    /// Creates a TLS client that trusts the given roots, and presents the certificate chain, with the private
+    /// key of its first certificate, to proxies that ask for it.
+    pub fn with_client_auth(
+        roots: Vec<CertificateDer<'static>>,
+        certificate_chain: Vec<CertificateDer<'static>>,
+        private_key: PrivateKeyDer<'static>,
+    ) -> Result<Self> {
+        let config = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(root_store(roots)?)
+            .with_client_auth_cert(certificate_chain, private_key)?;
+
+        Ok(Self::from_config(config))
+    }
+
+    /// Creates a TLS client from a rustls configuration, e.g., one with a custom certificate verifier.
+    pub fn from_config(config: ClientConfig) -> Self {
+        Self {
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name: None,
+        }
+    }
+
+    /// Sets the name the proxy's certificate is verified for, instead of the hostname it is connected to.
+    pub fn with_server_name<S: Into<String>>(
+        mut self,
+        server_name: S,
+    ) -> Result<Self> {
+        self.server_name = Some(ServerName::try_from(server_name.into())?);
+        Ok(self)
+    }
+
+    /// Performs the TLS handshake over a stream to the proxy.
+    ///
+    /// # Parameters
+    /// - `host`: The hostname or IP address of the proxy, unless a server name is set.
+    /// - `stream`: The stream to the proxy.
+    ///
+    /// # Returns
+    /// A `Result` containing the TLS stream, or `SocksError::TlsHandshakeFailed` if the handshake fails.
+    pub async fn connect<S>(
+        &self,
+        host: &str,
+        stream: S,
+    ) -> Result<client::TlsStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let server_name = match &self.server_name {
+            Some(server_name) => server_name.clone(),
+            None => ServerName::try_from(host.trim_start_matches('[').trim_end_matches(']').to_string())?,
+        };
+
+        self.connector
+            .connect(server_name, stream)
+            .await
+            .map_err(|e| classify(e.into()))
+    }
+}
+
+/// Accepts TLS connections for a server, optionally requiring and verifying client certificates (mTLS).
+///
+/// Handed to `SocksServer::with_tls`. With client authentication, the subject of a client's certificate is the
+/// identity of its sessions, as seen by the session hooks, unless the client authenticates within SOCKS as
+/// well.
+#[derive(Clone)]
+pub struct TlsServer {
+    acceptor: TlsAcceptor,
+}
+
+impl TlsServer {
+    /// Creates a TLS server with the certificate chain, and the private key of its first certificate, that
+    /// doesn't ask clients for certificates.
+    pub fn new(
+        certificate_chain: Vec<CertificateDer<'static>>,
+        private_key: PrivateKeyDer<'static>,
+    ) -> Result<Self> {
+        let config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certificate_chain, private_key)?;
+
+        Ok(Self::from_config(config))
+    }
+
+    /// Creates a TLS server like `new`, that requires every client to present a certificate issued by one of
+    /// the client roots. Clients without a valid certificate are rejected during the TLS handshake.
+    pub fn requiring_client_auth(
+        certificate_chain: Vec<CertificateDer<'static>>,
+        private_key: PrivateKeyDer<'static>,
+        client_roots: Vec<CertificateDer<'static>>,
+    ) -> Result<Self> {
+        let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(root_store(client_roots)?), provider())
+            .build()?;
+        let config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(certificate_chain, private_key)?;
+
+        Ok(Self::from_config(config))
+    }
+
+    /// Creates a TLS server from a rustls configuration, e.g., one with a custom client certificate verifier.
+    pub fn from_config(config: ServerConfig) -> Self {
+        Self {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+        }
+    }
+
+    /// Performs the TLS handshake with a client.
+    ///
+    /// # Parameters
+    /// - `stream`: The stream from the client.
+    ///
+    /// # Returns
+    /// A `Result` containing the TLS stream and the subject of the client's certificate, if it presented one,
+    /// or `SocksError::TlsHandshakeFailed` if the handshake fails.
+    pub async fn accept<S>(
+        &self,
+        stream: S,
+    ) -> Result<(server::TlsStream<S>, Option<String>)>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let stream = self.acceptor.accept(stream).await.map_err(|e| classify(e.into()))?;
+        let subject = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certificates| certificates.first())
+            .and_then(certificate_subject);
+
+        Ok((stream, subject))
+    }
+}
+
+/// Returns the subject of a certificate in the string form of RFC 4514, e.g., `CN=client,O=example`, with the
+/// attributes CN, OU, O, L, ST, and C. Other attributes are left out. The special characters of values are
+/// escaped, and the attributes of a multi-valued RDN are joined with `+`.
+pub fn certificate_subject(certificate: &CertificateDer<'_>) -> Option<String> {
+    let (_, certificate, _) = der_element(certificate)?;
+    let (_, mut fields, _) = der_element(certificate)?;
+
+    // Skip the version, if any, the serial number, the signature algorithm, the issuer, and the validity.
+    if fields.first() == Some(&0xa0) {
+        fields = der_element(fields)?.2;
+    }
+    for _ in 0..4 {
+        fields = der_element(fields)?.2;
+    }
+
+    // The subject is a sequence of sets of attributes, from the least to the most specific.
+    let (_, mut names, _) = der_element(fields)?;
+    let mut rdns = vec![];
+    while !names.is_empty() {
+        let (_, mut set, rest) = der_element(names)?;
+        names = rest;
+        let mut attributes = vec![];
+        while !set.is_empty() {
+            let (_, attribute, rest) = der_element(set)?;
+            set = rest;
+
+            let (_, oid, value) = der_element(attribute)?;
+            let (_, value, _) = der_element(value)?;
+            let label = match oid {
+                [0x55, 0x04, 0x03] => "CN",
+                [0x55, 0x04, 0x06] => "C",
+                [0x55, 0x04, 0x07] => "L",
+                [0x55, 0x04, 0x08] => "ST",
+                [0x55, 0x04, 0x0a] => "O",
+                [0x55, 0x04, 0x0b] => "OU",
+                _ => continue,
+            };
+            attributes.push(format!("{}={}", label, escape_attribute_value(&String::from_utf8_lossy(value))));
+        }
+        if !attributes.is_empty() {
+            rdns.push(attributes.join("+"));
+        }
+    }
+    rdns.reverse();
+
+    Some(rdns.join(","))
+}
+
+// Escapes an attribute value as RFC 4514 (section 2.4) requires, so that it can't be taken for more attributes.
+fn escape_attribute_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    let last = value.chars().count().saturating_sub(1);
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            '"' | '+' | ',' | ';' | '<' | '>' | '\\' => escaped.push('\\'),
+            '#' if i == 0 => escaped.push('\\'),
+            ' ' if i == 0 || i == last => escaped.push('\\'),
+            '\0' => {
+                escaped.push_str("\\00");
+                continue;
+            }
+            _ => {}
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+/// Marks an error that TLS caused, e.g., an alert of a peer that rejected a certificate, with
+/// `SocksError::TlsHandshakeFailed`, so that it can be told apart from SOCKS-level failures.
+///
+/// With TLS 1.3, a proxy rejects a client certificate after the client considers the handshake complete, so
+/// the rejection only surfaces when the SOCKS handshake reads from the stream.
+pub(crate) fn classify(error: anyhow::Error) -> anyhow::Error {
+    let cause = error
+        .chain()
+        .filter_map(|cause| cause.downcast_ref::<io::Error>())
+        .find_map(|error| error.get_ref().and_then(|inner| inner.downcast_ref::<rustls::Error>()))
+        .map(ToString::to_string);
+
+    match cause {
+        Some(cause) => error.context(SocksError::TlsHandshakeFailed(cause)),
+        None => error,
+    }
+}
+
+// Uses ring for the cryptography, regardless of the provider installed by the application.
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn root_store(roots: Vec<CertificateDer<'static>>) -> Result<RootCertStore> {
+    let mut store = RootCertStore::empty();
+    for root in roots {
+        store.add(root)?;
+    }
+
+    Ok(store)
+}
+
+// Splits a DER element into its tag and contents, followed by the bytes after it.
+fn der_element(bytes: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, bytes) = bytes.split_first()?;
+    let (&length, mut bytes) = bytes.split_first()?;
+
+    let mut length = length as usize;
+    if length >= 0x80 {
+        let octets = length & 0x7f;
+        if octets == 0 || octets > 4 || bytes.len() < octets {
+            return None;
+        }
+        length = bytes[..octets].iter().fold(0, |length, octet| length << 8 | *octet as usize);
+        bytes = &bytes[octets..];
+    }
+    if bytes.len() < length {
+        return None;
+    }
+
+    Some((tag, &bytes[..length], &bytes[length..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::session::{SessionHooks, SessionInfo, SessionLimits};
+    use crate::test_util::MockConnector;
+    use crate::{Socks5Client, Socks5Handler, Socks6Client, Socks6Handler, SocksServer};
+
+    fn certificates(pem: &[u8]) -> Vec<CertificateDer<'static>> {
+        CertificateDer::pem_slice_iter(pem).collect::<Result<_, _>>().unwrap()
+    }
+
+    fn private_key(pem: &[u8]) -> PrivateKeyDer<'static> {
+        PrivateKeyDer::from_pem_slice(pem).unwrap()
+    }
+
+    // Records the identities of the sessions.
+    #[derive(Default)]
+    struct Identities(Mutex<Vec<Option<String>>>);
+
+    impl SessionHooks for Identities {
+        fn limits(
+            &self,
+            session: &SessionInfo,
+            limits: SessionLimits,
+        ) -> SessionLimits {
+            self.0.lock().unwrap().push(session.identity.clone());
+            limits
+        }
+    }
+
+    // Tests that the subject of a certificate is read in the order of RFC 4514.
+    #[test]
+    fn test_certificate_subject() {
+        let client = certificates(include_bytes!("testdata/client.pem"));
+        assert_eq!(certificate_subject(&client[0]).as_deref(), Some("CN=client,O=socksx"));
+        assert_eq!(certificate_subject(&CertificateDer::from(vec![0x30, 0x05])), None);
+    }
+
+    // Tests that the special characters of values are escaped, so that a value can't pose as more attributes.
+    #[test]
+    fn test_certificate_subject_escaped() {
+        let escaped = certificates(include_bytes!("testdata/escaped.pem"));
+        let subject = certificate_subject(&escaped[0]);
+        assert_eq!(subject.as_deref(), Some(r"OU=x\+y+CN=\#x\,O=admin\\\ ,O=a\;b\<c\>"));
+
+        assert_eq!(escape_attribute_value(" a\0b "), r"\ a\00b\ ");
+        assert_eq!(escape_attribute_value("a#\"b"), r#"a#\"b"#);
+    }
+
+    // Tests that a server requiring client certificates only serves clients with a trusted one, passing its
+    // subject on as the identity, and that clients see rejections as TLS failures.
+    #[tokio::test]
+    async fn test_client_auth() -> Result<()> {
+        let ca = certificates(include_bytes!("testdata/ca.pem"));
+        let tls = TlsServer::requiring_client_auth(
+            certificates(include_bytes!("testdata/server.pem")),
+            private_key(include_bytes!("testdata/server-key.pem")),
+            ca.clone(),
+        )?;
+        let identities = Arc::new(Identities::default());
+        let handler = Socks6Handler::default()
+            .with_connector(Arc::new(MockConnector::new()))
+            .with_hooks(identities.clone());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = format!("localhost:{}", listener.local_addr()?.port());
+        let server = SocksServer::new(listener, Arc::new(handler)).with_tls(tls);
+        tokio::spawn(async move { server.run().await });
+
+        let trusted = TlsClient::with_client_auth(
+            ca.clone(),
+            certificates(include_bytes!("testdata/client.pem")),
+            private_key(include_bytes!("testdata/client-key.pem")),
+        )?;
+        let client = Socks6Client::new(proxy_addr.clone(), None).await?.with_tls(trusted);
+        let (mut tunnel, _) = client.connect_tls("example.com:80", None, None).await?;
+        tunnel.write_all(b"ping").await?;
+        let mut echoed = [0; 4];
+        tunnel.read_exact(&mut echoed).await?;
+        assert_eq!(&echoed, b"ping");
+        assert_eq!(*identities.0.lock().unwrap(), vec![Some(String::from("CN=client,O=socksx"))]);
+
+        let untrusted = TlsClient::with_client_auth(
+            ca.clone(),
+            certificates(include_bytes!("testdata/rogue-client.pem")),
+            private_key(include_bytes!("testdata/rogue-client-key.pem")),
+        )?;
+        for tls in [TlsClient::new(ca)?, untrusted] {
+            let client = Socks6Client::new(proxy_addr.clone(), None).await?.with_tls(tls);
+            let error = client.connect_tls("example.com:80", None, None).await.unwrap_err();
+            assert!(matches!(error.downcast_ref(), Some(SocksError::TlsHandshakeFailed(_))), "{:?}", error);
+        }
+
+        Ok(())
+    }
+
+    // Tests that the SOCKS5 client connects over TLS with its client certificate, and sees rejections as TLS
+    // failures.
+    #[tokio::test]
+    async fn test_client_auth_socks5() -> Result<()> {
+        let ca = certificates(include_bytes!("testdata/ca.pem"));
+        let tls = TlsServer::requiring_client_auth(
+            certificates(include_bytes!("testdata/server.pem")),
+            private_key(include_bytes!("testdata/server-key.pem")),
+            ca.clone(),
+        )?;
+        let identities = Arc::new(Identities::default());
+        let handler = Socks5Handler::default()
+            .with_connector(Arc::new(MockConnector::new()))
+            .with_hooks(identities.clone());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = format!("localhost:{}", listener.local_addr()?.port());
+        let server = SocksServer::new(listener, Arc::new(handler)).with_tls(tls);
+        tokio::spawn(async move { server.run().await });
+
+        let trusted = TlsClient::with_client_auth(
+            ca.clone(),
+            certificates(include_bytes!("testdata/client.pem")),
+            private_key(include_bytes!("testdata/client-key.pem")),
+        )?;
+        let client = Socks5Client::new(proxy_addr.clone(), None).await?.with_tls(trusted);
+        let (mut tunnel, _) = client.connect_tls("example.com:80", None).await?;
+        tunnel.write_all(b"ping").await?;
+        let mut echoed = [0; 4];
+        tunnel.read_exact(&mut echoed).await?;
+        assert_eq!(&echoed, b"ping");
+        assert_eq!(*identities.0.lock().unwrap(), vec![Some(String::from("CN=client,O=socksx"))]);
+
+        let untrusted = TlsClient::with_client_auth(
+            ca.clone(),
+            certificates(include_bytes!("testdata/rogue-client.pem")),
+            private_key(include_bytes!("testdata/rogue-client-key.pem")),
+        )?;
+        let client = Socks5Client::new(proxy_addr, None).await?.with_tls(untrusted);
+        let error = client.connect_tls("example.com:80", None).await.unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(SocksError::TlsHandshakeFailed(_))), "{:?}", error);
+
+        let plain = Socks5Client::new("localhost:1080", None).await?;
+        assert!(plain.connect_tls("example.com:80", None).await.is_err());
+
+        Ok(())
+    }
+}
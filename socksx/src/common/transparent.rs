@@ -0,0 +1,1126 @@
+use std::fmt;
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::addresses::IpNetwork;
+use crate::dialer::AddressFamilyPreference;
+use crate::guard::DestinationGuard;
+use crate::metrics::{self, Metrics, NoopMetrics};
+use crate::source_filter::SourceFilter;
+use crate::{bypass, util, Address, SocksClient};
+
+/// The tag of the rules installed by default, which also names their chain or table.
+pub const DEFAULT_RULE_TAG: &str = "socksx";
+
+/// The networks that are never redirected by default: loopback, which includes the redirect port itself.
+pub const DEFAULT_EXCLUDED_NETWORKS: [&str; 2] = ["127.0.0.0/8", "::1/128"];
+
+/// An address family the rules are installed for.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Family {
+    /// IPv4, managed with `iptables`.
+    Ipv4,
+    /// IPv6, managed with `ip6tables`.
+    Ipv6,
+}
+
+impl Family {
+    /// Returns the program that manages the rules of the family.
+    pub fn iptables(&self) -> &'static str {
+        match self {
+            Family::Ipv4 => "iptables",
+            Family::Ipv6 => "ip6tables",
+        }
+    }
+
+    // Returns whether the network is of the family.
+    fn contains(
+        &self,
+        network: &IpNetwork,
+    ) -> bool {
+        network.addr().is_ipv4() == (*self == Family::Ipv4)
+    }
+}
+
+/// The nat rules that redirect TCP connections to a transparent proxy's port, where their original destination is
+/// read with `get_original_dst`.
+///
+/// The rules redirect the connections of local processes (the OUTPUT hook), and optionally those routed through
+/// the host (the PREROUTING hook). They are installed by a `RuleBackend`, in a chain or table of their own named
+/// after the tag, so that removing them leaves the rules of others alone.
+///
+/// Destinations in the excluded networks aren't redirected. Exclude the proxy the connections are forwarded to,
+/// or they loop back into the transparent proxy. Alternatively, mark the proxy's connections with SO_MARK and
+/// exempt the mark with `with_mark`, which also works when the proxy runs on the same host.
+#[derive(Clone, Debug)]
+pub struct RedirectRules {
+    port: u16,
+    excluded: Vec<IpNetwork>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    mark: Option<u32>,
+    output: bool,
+    prerouting: bool,
+    families: Vec<Family>,
+    tag: String,
+    backend: Option<Arc<dyn RuleBackend + Send + Sync>>,
+}
+
+impl RedirectRules {
+    /// Creates the rules that redirect the connections of local processes to the port, except those to
+    /// `DEFAULT_EXCLUDED_NETWORKS`, for both IPv4 and IPv6.
+    pub fn new(port: u16) -> Self {
+        let excluded = DEFAULT_EXCLUDED_NETWORKS
+            .iter()
+            .map(|network| network.parse().expect("Default excluded networks are valid"))
+            .collect();
+
+        Self {
+            port,
+            excluded,
+            uid: None,
+            gid: None,
+            mark: None,
+            output: true,
+            prerouting: false,
+            families: vec![Family::Ipv4, Family::Ipv6],
+            tag: String::from(DEFAULT_RULE_TAG),
+            backend: None,
+        }
+    }
+
+    /// Excludes the destinations in the network from redirection, e.g., the address of the proxy.
+    pub fn with_excluded(
+        mut self,
+        network: IpNetwork,
+    ) -> Self {
+        self.excluded.push(network);
+        self
+    }
+
+    /// Only redirects the connections of local processes run by the user. This doesn't apply to PREROUTING.
+    pub fn with_uid(
+        mut self,
+        uid: u32,
+    ) -> Self {
+        self.uid = Some(uid);
+        self
+    }
+
+    /// Only redirects the connections of local processes run by the group. This doesn't apply to PREROUTING.
+    pub fn with_gid(
+        mut self,
+        gid: u32,
+    ) -> Self {
+        self.gid = Some(gid);
+        self
+    }
+
+    /// Exempts the packets with the firewall mark from redirection, e.g., those of a handler or client that sets it
+    /// with `with_mark`.
+    pub fn with_mark(
+        mut self,
+        mark: u32,
+    ) -> Self {
+        self.mark = Some(mark);
+        self
+    }
+
+    /// Sets whether the connections of local processes are redirected, which they are by default.
+    pub fn with_output(
+        mut self,
+        output: bool,
+    ) -> Self {
+        self.output = output;
+        self
+    }
+
+    /// Sets whether connections routed through the host are redirected, e.g., those of containers or of a LAN
+    /// that uses the host as its gateway. They aren't by default.
+    pub fn with_prerouting(
+        mut self,
+        prerouting: bool,
+    ) -> Self {
+        self.prerouting = prerouting;
+        self
+    }
+
+    /// Sets the address families the rules are installed for, by default both.
+    pub fn with_families(
+        mut self,
+        families: Vec<Family>,
+    ) -> Self {
+        self.families = families;
+        self
+    }
+
+    /// Sets the tag of the rules, e.g., to install several sets of rules side by side.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the rules, or an error if the tag isn't made of 1 to 28 ASCII letters, digits, `-`,
+    /// and `_`, as iptables chain names are limited to that.
+    pub fn with_tag<S: Into<String>>(
+        mut self,
+        tag: S,
+    ) -> Result<Self> {
+        let tag = tag.into();
+        ensure!(!tag.is_empty() && tag.len() <= 28, "Rule tag must be 1 to 28 characters long");
+        ensure!(
+            tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+            "Rule tag may only contain ASCII letters, digits, '-', and '_'"
+        );
+
+        self.tag = tag;
+        Ok(self)
+    }
+
+    /// Returns the tag of the rules.
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    /// Returns the port connections are redirected to.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Returns the networks that aren't redirected.
+    pub fn excluded(&self) -> &[IpNetwork] {
+        &self.excluded
+    }
+
+    /// Returns the user that the redirected connections of local processes are scoped to, if any.
+    pub fn uid(&self) -> Option<u32> {
+        self.uid
+    }
+
+    /// Returns the group that the redirected connections of local processes are scoped to, if any.
+    pub fn gid(&self) -> Option<u32> {
+        self.gid
+    }
+
+    /// Returns the firewall mark of the packets that aren't redirected, if any.
+    pub fn mark(&self) -> Option<u32> {
+        self.mark
+    }
+
+    /// Returns whether the connections of local processes are redirected.
+    pub fn redirects_output(&self) -> bool {
+        self.output
+    }
+
+    /// Returns whether connections routed through the host are redirected.
+    pub fn redirects_prerouting(&self) -> bool {
+        self.prerouting
+    }
+
+    /// Returns the address families the rules are installed for.
+    pub fn families(&self) -> &[Family] {
+        &self.families
+    }
+
+    /// Sets the backend that installs the rules. By default, `detect_backend` picks one when they are installed.
+    pub fn with_backend(
+        mut self,
+        backend: Arc<dyn RuleBackend + Send + Sync>,
+    ) -> Self {
+        self.backend = Some(backend);
+        self
+    }
+
+    /// Installs the rules, removing whatever was installed if that fails halfway.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing a guard that removes the rules when it is dropped, or an error with the output of the
+    /// command that failed. Installing rules requires root, or the CAP_NET_ADMIN capability.
+    pub fn install(&self) -> Result<InstalledRules> {
+        let backend = match &self.backend {
+            Some(backend) => Arc::clone(backend),
+            None => detect_backend()?,
+        };
+
+        // Leftovers of an earlier run that wasn't cleaned up would get in the way.
+        backend.remove(self);
+
+        let installed = InstalledRules {
+            rules: self.clone(),
+            backend: Arc::clone(&backend),
+        };
+        backend.install(self)?;
+        info!("Installed the {} redirect rules to port {} with {}.", self.tag, self.port, backend.name());
+
+        Ok(installed)
+    }
+}
+
+/// Installs and removes `RedirectRules` with the packet filter of a platform, e.g., `Iptables` or `Nftables`.
+pub trait RuleBackend: fmt::Debug {
+    /// Returns the name of the backend, for logging.
+    fn name(&self) -> &'static str;
+
+    /// Returns whether the backend can be used on this host, e.g., because its tools are installed.
+    fn is_available(&self) -> bool;
+
+    /// Installs the rules.
+    fn install(
+        &self,
+        rules: &RedirectRules,
+    ) -> Result<()>;
+
+    /// Removes the rules, ignoring the ones that aren't installed.
+    fn remove(
+        &self,
+        rules: &RedirectRules,
+    );
+}
+
+/// Picks the backend for this host: `Nftables` if `nft` is installed, otherwise `Iptables`.
+///
+/// # Returns
+///
+/// A `Result` containing the backend, or an error if neither is installed.
+pub fn detect_backend() -> Result<Arc<dyn RuleBackend + Send + Sync>> {
+    let backends: Vec<Arc<dyn RuleBackend + Send + Sync>> = vec![Arc::new(Nftables), Arc::new(Iptables)];
+    backends
+        .into_iter()
+        .find(|backend| backend.is_available())
+        .context("Neither nft nor iptables is installed")
+}
+
+/// Manages the rules with `iptables` and `ip6tables`.
+///
+/// The rules live in a chain of their own, named after the tag in uppercase, that the OUTPUT and PREROUTING
+/// chains jump to. Every rule is tagged with a comment.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Iptables;
+
+impl Iptables {
+    /// Returns the arguments of the `iptables` or `ip6tables` commands that install the rules for the family,
+    /// in order.
+    pub fn install_commands(
+        rules: &RedirectRules,
+        family: Family,
+    ) -> Vec<Vec<String>> {
+        let chain = rules.tag.to_uppercase();
+        let mut commands = vec![nat(&["-N", &chain])];
+
+        if let Some(mark) = rules.mark {
+            let mut command = nat(&["-A", &chain, "-m", "mark", "--mark", &mark.to_string()]);
+            command.extend(comment(rules));
+            command.extend(args(&["-j", "RETURN"]));
+            commands.push(command);
+        }
+        for network in rules.excluded.iter().filter(|network| family.contains(network)) {
+            let mut command = nat(&["-A", &chain, "-d", &network.to_string()]);
+            command.extend(comment(rules));
+            command.extend(args(&["-j", "RETURN"]));
+            commands.push(command);
+        }
+
+        let mut redirect = nat(&["-A", &chain, "-p", "tcp"]);
+        redirect.extend(comment(rules));
+        redirect.extend(args(&["-j", "REDIRECT", "--to-ports", &rules.port.to_string()]));
+        commands.push(redirect);
+
+        commands.extend(jumps(rules).into_iter().map(|jump| [nat(&["-A"]), jump].concat()));
+        commands
+    }
+
+    /// Returns the arguments of the `iptables` or `ip6tables` commands that remove the rules for the family, in
+    /// order: the jumps to the chain, then the chain itself.
+    pub fn remove_commands(
+        rules: &RedirectRules,
+        _family: Family,
+    ) -> Vec<Vec<String>> {
+        let chain = rules.tag.to_uppercase();
+        let mut commands: Vec<_> = jumps(rules).into_iter().map(|jump| [nat(&["-D"]), jump].concat()).collect();
+        commands.push(nat(&["-F", &chain]));
+        commands.push(nat(&["-X", &chain]));
+
+        commands
+    }
+}
+
+impl RuleBackend for Iptables {
+    fn name(&self) -> &'static str {
+        "iptables"
+    }
+
+    fn is_available(&self) -> bool {
+        run("iptables", &args(&["--version"]), None).is_ok()
+    }
+
+    fn install(
+        &self,
+        rules: &RedirectRules,
+    ) -> Result<()> {
+        for family in &rules.families {
+            for command in Self::install_commands(rules, *family) {
+                run(family.iptables(), &command, None)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn remove(
+        &self,
+        rules: &RedirectRules,
+    ) {
+        for family in &rules.families {
+            for command in Self::remove_commands(rules, *family) {
+                if let Err(e) = run(family.iptables(), &command, None) {
+                    debug!("Ignoring failed removal of a {} rule: {:#}", rules.tag, e);
+                }
+            }
+        }
+    }
+}
+
+/// Manages the rules with `nft`, in a table of their own named after the tag, which is replaced and deleted as a
+/// whole.
+///
+/// The table is of the `inet` family when the rules are for both address families, and of `ip` or `ip6` when
+/// they are for one.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Nftables;
+
+impl Nftables {
+    /// Returns the ruleset that `nft -f` installs the rules with. It deletes an earlier version of the table
+    /// first, in the same transaction.
+    pub fn ruleset(rules: &RedirectRules) -> String {
+        let table = format!("{} {}", table_family(rules), rules.tag);
+        let comment = format!("comment \"{}\"", rules.tag);
+        let mut ruleset = format!("table {}\ndelete table {}\ntable {} {{\n", table, table, table);
+
+        let mut hook = |name: &str, matches: String| {
+            ruleset.push_str(&format!("\tchain {} {{\n", name));
+            ruleset.push_str(&format!("\t\ttype nat hook {} priority -100; policy accept;\n", name));
+            ruleset.push_str(&format!("\t\tmeta l4proto tcp{} jump redirect {}\n", matches, comment));
+            ruleset.push_str("\t}\n");
+        };
+        if rules.output {
+            let mut owner = String::new();
+            if let Some(uid) = rules.uid {
+                owner.push_str(&format!(" meta skuid {}", uid));
+            }
+            if let Some(gid) = rules.gid {
+                owner.push_str(&format!(" meta skgid {}", gid));
+            }
+            hook("output", owner);
+        }
+        if rules.prerouting {
+            hook("prerouting", String::new());
+        }
+
+        ruleset.push_str("\tchain redirect {\n");
+        if let Some(mark) = rules.mark {
+            ruleset.push_str(&format!("\t\tmeta mark {} return {}\n", mark, comment));
+        }
+        let families = rules.families.iter();
+        for network in rules.excluded.iter().filter(|network| families.clone().any(|f| f.contains(network))) {
+            let protocol = if network.addr().is_ipv4() { "ip" } else { "ip6" };
+            ruleset.push_str(&format!("\t\t{} daddr {} return {}\n", protocol, network, comment));
+        }
+        ruleset.push_str(&format!("\t\tmeta l4proto tcp redirect to :{} {}\n", rules.port, comment));
+        ruleset.push_str("\t}\n}\n");
+
+        ruleset
+    }
+
+    /// Returns the ruleset that `nft -f` removes the rules with.
+    pub fn removal_ruleset(rules: &RedirectRules) -> String {
+        format!("delete table {} {}\n", table_family(rules), rules.tag)
+    }
+}
+
+impl RuleBackend for Nftables {
+    fn name(&self) -> &'static str {
+        "nftables"
+    }
+
+    fn is_available(&self) -> bool {
+        run("nft", &args(&["--version"]), None).is_ok()
+    }
+
+    fn install(
+        &self,
+        rules: &RedirectRules,
+    ) -> Result<()> {
+        run("nft", &args(&["-f", "-"]), Some(&Self::ruleset(rules)))
+    }
+
+    fn remove(
+        &self,
+        rules: &RedirectRules,
+    ) {
+        if let Err(e) = run("nft", &args(&["-f", "-"]), Some(&Self::removal_ruleset(rules))) {
+            debug!("Ignoring failed removal of the {} table: {:#}", rules.tag, e);
+        }
+    }
+}
+
+// Returns the nftables family of the table the rules are installed in.
+fn table_family(rules: &RedirectRules) -> &'static str {
+    match rules.families.as_slice() {
+        [Family::Ipv4] => "ip",
+        [Family::Ipv6] => "ip6",
+        _ => "inet",
+    }
+}
+
+// Returns the iptables rule specifications, without the operation, of the jumps from the built-in chains.
+fn jumps(rules: &RedirectRules) -> Vec<Vec<String>> {
+    let chain = rules.tag.to_uppercase();
+    let mut jumps = vec![];
+
+    if rules.output {
+        let mut jump = args(&["OUTPUT", "-p", "tcp"]);
+        if rules.uid.is_some() || rules.gid.is_some() {
+            jump.extend(args(&["-m", "owner"]));
+        }
+        if let Some(uid) = rules.uid {
+            jump.extend(args(&["--uid-owner", &uid.to_string()]));
+        }
+        if let Some(gid) = rules.gid {
+            jump.extend(args(&["--gid-owner", &gid.to_string()]));
+        }
+        jump.extend(comment(rules));
+        jump.extend(args(&["-j", &chain]));
+        jumps.push(jump);
+    }
+    if rules.prerouting {
+        let mut jump = args(&["PREROUTING", "-p", "tcp"]);
+        jump.extend(comment(rules));
+        jump.extend(args(&["-j", &chain]));
+        jumps.push(jump);
+    }
+
+    jumps
+}
+
+// Returns the iptables match that tags a rule.
+fn comment(rules: &RedirectRules) -> Vec<String> {
+    args(&["-m", "comment", "--comment", &rules.tag])
+}
+
+/// Removes the installed rules when it is dropped, e.g., when the `TransparentProxy` that installed them stops.
+#[derive(Debug)]
+pub struct InstalledRules {
+    rules: RedirectRules,
+    backend: Arc<dyn RuleBackend + Send + Sync>,
+}
+
+impl InstalledRules {
+    /// Returns the rules that were installed.
+    pub fn rules(&self) -> &RedirectRules {
+        &self.rules
+    }
+}
+
+impl Drop for InstalledRules {
+    fn drop(&mut self) {
+        self.backend.remove(&self.rules);
+        info!("Removed the {} redirect rules.", self.rules.tag);
+    }
+}
+
+/// Destinations that a `TransparentProxy` treats differently: a network, optionally limited to a port.
+///
+/// Rules are written as a network with an optional port, e.g., `10.0.0.0/8`, `10.0.0.1:443`, or
+/// `[2001:db8::]/32:443`. IPv6 networks with a port take brackets, as `::1:80` is an address.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DestinationRule {
+    network: IpNetwork,
+    port: Option<u16>,
+}
+
+impl DestinationRule {
+    /// Creates a rule that matches every port in the network.
+    pub fn new(network: IpNetwork) -> Self {
+        Self { network, port: None }
+    }
+
+    /// Limits the rule to the port.
+    pub fn with_port(
+        mut self,
+        port: u16,
+    ) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    /// Returns whether the destination is in the network, and on the port if the rule has one.
+    pub fn matches(
+        &self,
+        destination: SocketAddr,
+    ) -> bool {
+        self.network.contains(destination.ip()) && self.port.is_none_or(|port| port == destination.port())
+    }
+}
+
+impl FromStr for DestinationRule {
+    type Err = anyhow::Error;
+
+    fn from_str(rule: &str) -> Result<Self> {
+        if let Ok(network) = rule.parse() {
+            return Ok(Self::new(network));
+        }
+
+        let (network, port) = rule.rsplit_once(':').with_context(|| format!("Invalid destination rule: {}", rule))?;
+        let network = network.parse().with_context(|| format!("Invalid destination rule: {}", rule))?;
+        let port = port.parse().with_context(|| format!("Invalid port: {}", port))?;
+
+        Ok(Self::new(network).with_port(port))
+    }
+}
+
+/// What a `TransparentProxy` does with a redirected connection, decided by its original destination.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Decision {
+    /// Forward it through the proxy.
+    Proxy,
+    /// Connect to the destination directly, as it matches a bypass rule.
+    Bypass,
+    /// Close it, as the destination matches a drop rule.
+    Drop,
+    /// Close it, as the destination is the transparent proxy itself: the connection was redirected twice, most
+    /// likely because the proxy's own connections aren't exempted from the rules.
+    Loop,
+}
+
+impl Decision {
+    /// Returns the `decision` label of the `TRANSPARENT_DECISIONS` metric.
+    pub fn label(&self) -> &'static str {
+        match self {
+            Decision::Proxy => "proxied",
+            Decision::Bypass => "bypassed",
+            Decision::Drop => "dropped",
+            Decision::Loop => "looped",
+        }
+    }
+}
+
+/// Forwards the connections that the redirect rules send its way through a SOCKS proxy, to their original
+/// destination.
+///
+/// For a sidecar, it can reach some destinations directly, e.g., the services of its own cluster, with
+/// `with_bypassed`, and refuse others with `with_dropped`. Connections whose original destination is the
+/// transparent proxy itself are closed, as forwarding them would loop. Every decision is counted in
+/// `TRANSPARENT_DECISIONS`. Connections from sources that its `SourceFilter` doesn't allow are closed before a
+/// decision is made.
+pub struct TransparentProxy {
+    listener: TcpListener,
+    listen_addrs: Vec<SocketAddr>,
+    client: Arc<dyn SocksClient + Send + Sync>,
+    rules: Option<RedirectRules>,
+    mark: Option<u32>,
+    bypassed: Vec<DestinationRule>,
+    dropped: Vec<DestinationRule>,
+    source_filter: SourceFilter,
+    metrics: Arc<dyn Metrics + Send + Sync>,
+}
+
+impl TransparentProxy {
+    /// Creates a transparent proxy that forwards the connections accepted by the listener with the client.
+    pub fn new(
+        listener: TcpListener,
+        client: Arc<dyn SocksClient + Send + Sync>,
+    ) -> Self {
+        let listen_addrs = listen_addrs(&listener);
+
+        Self {
+            listener,
+            listen_addrs,
+            client,
+            rules: None,
+            mark: None,
+            bypassed: vec![],
+            dropped: vec![],
+            source_filter: SourceFilter::default(),
+            metrics: Arc::new(NoopMetrics),
+        }
+    }
+
+    /// Installs the rules while the transparent proxy runs, and removes them when it stops.
+    pub fn with_rules(
+        mut self,
+        rules: RedirectRules,
+    ) -> Self {
+        self.rules = Some(rules);
+        self
+    }
+
+    /// Exempts the packets with the firewall mark from the rules it installs, and sets the mark on the sockets of
+    /// the client and of bypassed connections, so that they don't loop back into it. A handler on the same host must
+    /// set the same mark with its `with_mark`. Setting the mark requires the CAP_NET_ADMIN capability.
+    pub fn with_mark(
+        mut self,
+        mark: u32,
+    ) -> Self {
+        match self.client.marked(mark) {
+            Some(client) => self.client = Arc::from(client),
+            None => warn!("The client can't set firewall mark {}, its connections may be redirected back.", mark),
+        }
+        self.mark = Some(mark);
+        self
+    }
+
+    /// Connects to the destinations that match the rule directly, instead of through the proxy.
+    pub fn with_bypassed(
+        mut self,
+        rule: DestinationRule,
+    ) -> Self {
+        self.bypassed.push(rule);
+        self
+    }
+
+    /// Closes the connections to the destinations that match the rule right away. Drop rules take precedence
+    /// over bypass rules.
+    pub fn with_dropped(
+        mut self,
+        rule: DestinationRule,
+    ) -> Self {
+        self.dropped.push(rule);
+        self
+    }
+
+    /// Closes the connections of peers that the filter doesn't allow as soon as they are accepted, and counts them
+    /// in `CONNECTIONS_SOURCE_DENIED`.
+    pub fn with_source_filter(
+        mut self,
+        filter: SourceFilter,
+    ) -> Self {
+        self.source_filter = filter;
+        self
+    }
+
+    /// Sets the metrics that the decision for each connection is counted in.
+    pub fn with_metrics(
+        mut self,
+        metrics: Arc<dyn Metrics + Send + Sync>,
+    ) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Decides what to do with a connection to the original destination.
+    pub fn decide(
+        &self,
+        destination: SocketAddr,
+    ) -> Decision {
+        if self.listen_addrs.contains(&destination) {
+            Decision::Loop
+        } else if self.dropped.iter().any(|rule| rule.matches(destination)) {
+            Decision::Drop
+        } else if self.bypassed.iter().any(|rule| rule.matches(destination)) {
+            Decision::Bypass
+        } else {
+            Decision::Proxy
+        }
+    }
+
+    /// Accepts and forwards connections until accepting fails, or the future is dropped.
+    pub async fn run(&self) -> Result<()> {
+        let _installed = match (&self.rules, self.mark) {
+            (Some(rules), Some(mark)) => Some(rules.clone().with_mark(mark).install()?),
+            (Some(rules), None) => Some(rules.install()?),
+            (None, _) => None,
+        };
+
+        loop {
+            let (stream, peer) = self.listener.accept().await?;
+            if !self.source_filter.permits(peer.ip()) {
+                self.metrics.increment_counter(metrics::CONNECTIONS_SOURCE_DENIED, &[], 1);
+                debug!("Closed the connection of {}, as its source address isn't allowed.", peer);
+                continue;
+            }
+
+            let destination = match util::get_original_dst(&stream) {
+                Ok(destination) => destination,
+                Err(e) => {
+                    warn!("Failed to get the original destination of {}: {:#}", peer, e);
+                    continue;
+                }
+            };
+
+            let decision = self.decide(destination);
+            self.metrics.increment_counter(metrics::TRANSPARENT_DECISIONS, &[("decision", decision.label())], 1);
+            match decision {
+                Decision::Drop => {
+                    debug!("Dropping the connection of {} to {}.", peer, destination);
+                    continue;
+                }
+                Decision::Loop => {
+                    warn!(
+                        "Closing the connection of {} that looped back to {}, exempt the proxy's own connections.",
+                        peer, destination
+                    );
+                    continue;
+                }
+                Decision::Proxy | Decision::Bypass => {}
+            }
+
+            let client = Arc::clone(&self.client);
+            let mark = self.mark;
+            tokio::spawn(async move {
+                if let Err(e) = forward(stream, destination, decision, client.as_ref(), mark).await {
+                    warn!("Failed to forward the connection of {}: {:#}", peer, e);
+                }
+            });
+        }
+    }
+}
+
+// Returns the addresses that connections to the listener arrive at, which are those of every interface if it
+// listens on the unspecified address.
+fn listen_addrs(listener: &TcpListener) -> Vec<SocketAddr> {
+    let Ok(local) = listener.local_addr() else {
+        return vec![];
+    };
+    if !local.ip().is_unspecified() {
+        return vec![local];
+    }
+
+    let mut addrs = util::interface_addrs().unwrap_or_default();
+    addrs.extend([IpAddr::from([127, 0, 0, 1]), IpAddr::from([0u16, 0, 0, 0, 0, 0, 0, 1])]);
+    addrs.into_iter().map(|ip| SocketAddr::new(ip, local.port())).collect()
+}
+
+// Forwards a redirected connection to its original destination, through the proxy or directly as decided.
+async fn forward(
+    mut stream: TcpStream,
+    destination: SocketAddr,
+    decision: Decision,
+    client: &(dyn SocksClient + Send + Sync),
+    mark: Option<u32>,
+) -> Result<()> {
+    let initial_data = util::try_read_initial_data(&mut stream).await?;
+    let destination = Address::Ip(destination);
+    let (mut outgoing, _) = match decision {
+        Decision::Bypass => {
+            let guard = DestinationGuard::permissive();
+            bypass::connect_direct(&destination, &guard, AddressFamilyPreference::default(), None, mark, initial_data)
+                .await?
+        }
+        _ => client.connect(destination, initial_data).await?,
+    };
+
+    tokio::io::copy_bidirectional(&mut stream, &mut outgoing).await?;
+    Ok(())
+}
+
+// Returns the arguments that start a command on the nat table, waiting for the lock of the other users.
+fn nat(rest: &[&str]) -> Vec<String> {
+    [&["-w", "-t", "nat"][..], rest].concat().into_iter().map(String::from).collect()
+}
+
+// Converts the arguments into owned strings.
+fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+// Runs the program with the arguments, and the input on its stdin, failing with its output if it doesn't succeed.
+fn run(
+    program: &str,
+    args: &[String],
+    input: Option<&str>,
+) -> Result<()> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run {}", program))?;
+    if let Some(input) = input {
+        child.stdin.take().expect("Stdin is piped").write_all(input.as_bytes())?;
+    }
+
+    let output = child.wait_with_output()?;
+    ensure!(
+        output.status.success(),
+        "{} {} failed: {}",
+        program,
+        args.join(" "),
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commands(commands: Vec<Vec<String>>) -> Vec<String> {
+        commands.into_iter().map(|command| command.join(" ")).collect()
+    }
+
+    // Tests the iptables commands that install and remove the default rules, with the proxy excluded.
+    #[test]
+    fn test_iptables_commands() -> Result<()> {
+        let rules = RedirectRules::new(42000).with_excluded("192.0.2.1/32".parse()?);
+
+        assert_eq!(
+            commands(Iptables::install_commands(&rules, Family::Ipv4)),
+            vec![
+                "-w -t nat -N SOCKSX",
+                "-w -t nat -A SOCKSX -d 127.0.0.0/8 -m comment --comment socksx -j RETURN",
+                "-w -t nat -A SOCKSX -d 192.0.2.1/32 -m comment --comment socksx -j RETURN",
+                "-w -t nat -A SOCKSX -p tcp -m comment --comment socksx -j REDIRECT --to-ports 42000",
+                "-w -t nat -A OUTPUT -p tcp -m comment --comment socksx -j SOCKSX",
+            ]
+        );
+        assert_eq!(
+            commands(Iptables::install_commands(&rules, Family::Ipv6))[1],
+            "-w -t nat -A SOCKSX -d ::1/128 -m comment --comment socksx -j RETURN"
+        );
+        assert_eq!(
+            commands(Iptables::remove_commands(&rules, Family::Ipv4)),
+            vec![
+                "-w -t nat -D OUTPUT -p tcp -m comment --comment socksx -j SOCKSX",
+                "-w -t nat -F SOCKSX",
+                "-w -t nat -X SOCKSX",
+            ]
+        );
+
+        Ok(())
+    }
+
+    // Tests the iptables jumps of rules that are scoped to a user and group, and that redirect routed connections
+    // as well.
+    #[test]
+    fn test_iptables_scoped() -> Result<()> {
+        let rules = RedirectRules::new(1080)
+            .with_uid(1000)
+            .with_gid(100)
+            .with_prerouting(true)
+            .with_tag("proxy_a")?;
+
+        let install = commands(Iptables::install_commands(&rules, Family::Ipv4));
+        assert_eq!(
+            &install[3..],
+            [
+                "-w -t nat -A OUTPUT -p tcp -m owner --uid-owner 1000 --gid-owner 100 -m comment --comment proxy_a \
+                 -j PROXY_A",
+                "-w -t nat -A PREROUTING -p tcp -m comment --comment proxy_a -j PROXY_A",
+            ]
+        );
+
+        let without_output = rules.with_output(false);
+        let remove = commands(Iptables::remove_commands(&without_output, Family::Ipv4));
+        assert!(!remove.iter().any(|command| command.contains("OUTPUT")));
+
+        assert!(RedirectRules::new(1080).with_tag("with space").is_err());
+        assert!(RedirectRules::new(1080).with_tag("x".repeat(29)).is_err());
+        Ok(())
+    }
+
+    // Tests that the packets with the mark are exempted before anything else is matched.
+    #[test]
+    fn test_mark_exemption() -> Result<()> {
+        let rules = RedirectRules::new(42000).with_mark(0x1080);
+
+        assert_eq!(
+            commands(Iptables::install_commands(&rules, Family::Ipv4))[1],
+            "-w -t nat -A SOCKSX -m mark --mark 4224 -m comment --comment socksx -j RETURN"
+        );
+        assert!(Nftables::ruleset(&rules).contains(
+            "\tchain redirect {
+\t\tmeta mark 4224 return comment \"socksx\"
+\t\tip daddr 127.0.0.0/8 return comment \"socksx\"
+"
+        ));
+
+        Ok(())
+    }
+
+    // Tests the decisions for destinations that are bypassed, dropped, or the transparent proxy itself.
+    #[tokio::test]
+    async fn test_decide() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let local = listener.local_addr()?;
+        let proxy = TransparentProxy::new(listener, Arc::new(crate::Socks6Client::for_streams(None)))
+            .with_bypassed("10.0.0.0/8".parse()?)
+            .with_bypassed("[2001:db8::]/32:443".parse()?)
+            .with_dropped("10.1.0.0/16:22".parse()?);
+
+        assert_eq!(proxy.decide(local), Decision::Loop);
+        assert_eq!(proxy.decide("10.1.2.3:22".parse()?), Decision::Drop);
+        assert_eq!(proxy.decide("10.1.2.3:80".parse()?), Decision::Bypass);
+        assert_eq!(proxy.decide("[2001:db8::1]:443".parse()?), Decision::Bypass);
+        assert_eq!(proxy.decide("[2001:db8::1]:80".parse()?), Decision::Proxy);
+        assert_eq!(proxy.decide("192.0.2.1:80".parse()?), Decision::Proxy);
+
+        assert_eq!("::1:80".parse::<DestinationRule>()?, DestinationRule::new("::1:80".parse()?));
+        assert!("10.0.0.0/8:http".parse::<DestinationRule>().is_err());
+        Ok(())
+    }
+
+    // Tests that the mark is set on the client as well.
+    #[tokio::test]
+    async fn test_client_mark() -> Result<()> {
+        use std::sync::atomic::{AtomicU32, Ordering};
+
+        use async_trait::async_trait;
+
+        use crate::SocksStream;
+
+        // Client that records the mark it is asked to set.
+        struct MarkableClient(Arc<AtomicU32>);
+
+        #[async_trait]
+        impl SocksClient for MarkableClient {
+            async fn connect(
+                &self,
+                _destination: Address,
+                _initial_data: Option<Vec<u8>>,
+            ) -> Result<(SocksStream, Address)> {
+                bail!("Not supported.")
+            }
+
+            fn marked(
+                &self,
+                mark: u32,
+            ) -> Option<Box<dyn SocksClient + Send + Sync>> {
+                self.0.store(mark, Ordering::Relaxed);
+                Some(Box::new(MarkableClient(Arc::clone(&self.0))))
+            }
+        }
+
+        let mark = Arc::new(AtomicU32::new(0));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy = TransparentProxy::new(listener, Arc::new(MarkableClient(Arc::clone(&mark)))).with_mark(0x1080);
+
+        assert_eq!(proxy.mark, Some(0x1080));
+        assert_eq!(mark.load(Ordering::Relaxed), 0x1080);
+        Ok(())
+    }
+
+    // Tests that the connections of denied sources are closed and counted before their destination is looked up.
+    #[tokio::test]
+    async fn test_source_filter() -> Result<()> {
+        use tokio::io::AsyncReadExt;
+
+        use crate::metrics::InMemoryMetrics;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let metrics = Arc::new(InMemoryMetrics::new());
+        let proxy = TransparentProxy::new(listener, Arc::new(crate::Socks6Client::for_streams(None)))
+            .with_source_filter(SourceFilter::new().with_denied("127.0.0.0/8".parse()?))
+            .with_metrics(metrics.clone());
+        tokio::spawn(async move { proxy.run().await });
+
+        let mut source = TcpStream::connect(addr).await?;
+        assert_eq!(source.read(&mut [0; 1]).await?, 0);
+        assert_eq!(metrics.counter(metrics::CONNECTIONS_SOURCE_DENIED, &[]), 1);
+        assert_eq!(metrics.counter_total(metrics::TRANSPARENT_DECISIONS), 0);
+        Ok(())
+    }
+
+    // Tests that a bypassed connection reaches its destination directly, without the client.
+    #[tokio::test]
+    async fn test_bypass_forwarding() -> Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let destination_addr = destination.local_addr()?;
+        tokio::spawn(async move {
+            let (mut stream, _) = destination.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.split();
+            tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+        });
+
+        let redirected = TcpListener::bind("127.0.0.1:0").await?;
+        let mut source = TcpStream::connect(redirected.local_addr()?).await?;
+        let (stream, _) = redirected.accept().await?;
+
+        // The client has no proxy to connect to, so using it fails.
+        let client = crate::Socks6Client::for_streams(None);
+        let forwarding =
+            tokio::spawn(async move { forward(stream, destination_addr, Decision::Bypass, &client, None).await });
+
+        source.write_all(b"hello").await?;
+        let mut echoed = [0; 5];
+        source.read_exact(&mut echoed).await?;
+        assert_eq!(&echoed, b"hello");
+
+        drop(source);
+        forwarding.await??;
+        Ok(())
+    }
+
+    // Tests the nftables ruleset of the default rules, with the proxy excluded.
+    #[test]
+    fn test_nftables_ruleset() -> Result<()> {
+        let rules = RedirectRules::new(42000).with_excluded("2001:db8::1/128".parse()?);
+
+        assert_eq!(
+            Nftables::ruleset(&rules),
+            "table inet socksx
+delete table inet socksx
+table inet socksx {
+\tchain output {
+\t\ttype nat hook output priority -100; policy accept;
+\t\tmeta l4proto tcp jump redirect comment \"socksx\"
+\t}
+\tchain redirect {
+\t\tip daddr 127.0.0.0/8 return comment \"socksx\"
+\t\tip6 daddr ::1/128 return comment \"socksx\"
+\t\tip6 daddr 2001:db8::1/128 return comment \"socksx\"
+\t\tmeta l4proto tcp redirect to :42000 comment \"socksx\"
+\t}
+}
+"
+        );
+        assert_eq!(Nftables::removal_ruleset(&rules), "delete table inet socksx\n");
+
+        Ok(())
+    }
+
+    // Tests the nftables ruleset of IPv4 rules that are scoped to a user and group, and that redirect routed
+    // connections as well.
+    #[test]
+    fn test_nftables_scoped() -> Result<()> {
+        let rules = RedirectRules::new(1080)
+            .with_uid(1000)
+            .with_gid(100)
+            .with_prerouting(true)
+            .with_families(vec![Family::Ipv4])
+            .with_tag("proxy_a")?;
+
+        assert_eq!(
+            Nftables::ruleset(&rules),
+            "table ip proxy_a
+delete table ip proxy_a
+table ip proxy_a {
+\tchain output {
+\t\ttype nat hook output priority -100; policy accept;
+\t\tmeta l4proto tcp meta skuid 1000 meta skgid 100 jump redirect comment \"proxy_a\"
+\t}
+\tchain prerouting {
+\t\ttype nat hook prerouting priority -100; policy accept;
+\t\tmeta l4proto tcp jump redirect comment \"proxy_a\"
+\t}
+\tchain redirect {
+\t\tip daddr 127.0.0.0/8 return comment \"proxy_a\"
+\t\tmeta l4proto tcp redirect to :1080 comment \"proxy_a\"
+\t}
+}
+"
+        );
+        assert_eq!(Nftables::removal_ruleset(&rules), "delete table ip proxy_a\n");
+
+        Ok(())
+    }
+}
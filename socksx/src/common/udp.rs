@@ -0,0 +1,509 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::future::{self, Future};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{self, UdpSocket};
+use tokio::time::{self, Instant};
+
+use crate::addresses::Address;
+use crate::constants::*;
+use crate::dialer::ConnectionAddrs;
+use crate::guard::DestinationGuard;
+use crate::interface::AsyncStream;
+use crate::metrics::Recorder;
+use crate::session::{CloseReason, CloseSummary, ConnectionId, SessionLimits};
+use crate::wire::{self, Parsed};
+use crate::SocksError;
+
+/// How long, by default, a UDP association may relay no datagrams before it is torn down.
+pub const DEFAULT_UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// The largest datagram that a UDP association relays by default, which is the largest UDP payload over IPv4.
+pub const DEFAULT_MAX_DATAGRAM_SIZE: usize = 65_507;
+
+/// Why a handler refused a UDP association.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UdpRefusal {
+    /// The client has as many associations as a client may have.
+    ClientLimit,
+    /// The handler relays as many associations as it may relay.
+    TotalLimit,
+    /// The client didn't name the address it sends from, e.g., with 0.0.0.0:0, which the relay doesn't allow.
+    UnspecifiedClient,
+}
+
+impl UdpRefusal {
+    /// Returns the `reason` label of the refusal in the metrics: `client_limit`, `total_limit`, or
+    /// `unspecified_client`.
+    pub fn label(&self) -> &'static str {
+        match self {
+            UdpRefusal::ClientLimit => "client_limit",
+            UdpRefusal::TotalLimit => "total_limit",
+            UdpRefusal::UnspecifiedClient => "unspecified_client",
+        }
+    }
+}
+
+impl fmt::Display for UdpRefusal {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        match self {
+            UdpRefusal::ClientLimit => write!(f, "too many associations of the client"),
+            UdpRefusal::TotalLimit => write!(f, "too many associations"),
+            UdpRefusal::UnspecifiedClient => write!(f, "the client address is unspecified"),
+        }
+    }
+}
+
+// The associations that are open, in total and by the IP address of their client.
+#[derive(Debug, Default)]
+struct Associations {
+    total: usize,
+    per_client: HashMap<IpAddr, usize>,
+}
+
+/// The limits of the UDP associations of a handler, whose datagrams it relays.
+///
+/// Set with `Socks5Handler::with_udp_relay`, which enables UDP ASSOCIATE. An association beyond the limits on
+/// the number of associations is refused with a "connection not allowed" reply. An open association is torn
+/// down, and its control connection closed, once it relayed no datagram for the idle timeout. Datagrams larger
+/// than the maximum datagram size are dropped. Clones share the same associations, so that the limits can cover
+/// several handlers.
+#[derive(Clone, Debug)]
+pub struct UdpRelayConfig {
+    idle_timeout: Duration,
+    max_datagram_size: usize,
+    max_per_client: Option<usize>,
+    max_associations: Option<usize>,
+    allow_unspecified_client: bool,
+    associations: Arc<Mutex<Associations>>,
+}
+
+impl Default for UdpRelayConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UdpRelayConfig {
+    /// Creates a configuration with the default idle timeout and maximum datagram size, which doesn't limit the
+    /// number of associations, and allows clients to leave their address unspecified.
+    pub fn new() -> Self {
+        Self {
+            idle_timeout: DEFAULT_UDP_IDLE_TIMEOUT,
+            max_datagram_size: DEFAULT_MAX_DATAGRAM_SIZE,
+            max_per_client: None,
+            max_associations: None,
+            allow_unspecified_client: true,
+            associations: Arc::default(),
+        }
+    }
+
+    /// Sets how long an association may relay no datagrams before it is torn down.
+    pub fn with_idle_timeout(
+        mut self,
+        idle_timeout: Duration,
+    ) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Sets the size of the largest datagram that is relayed, including its SOCKS header, which is at most
+    /// `DEFAULT_MAX_DATAGRAM_SIZE`.
+    pub fn with_max_datagram_size(
+        mut self,
+        max_datagram_size: usize,
+    ) -> Self {
+        self.max_datagram_size = max_datagram_size.min(DEFAULT_MAX_DATAGRAM_SIZE);
+        self
+    }
+
+    /// Limits the number of associations that are open at once for each IP address of a client.
+    pub fn with_max_associations_per_client(
+        mut self,
+        max_per_client: usize,
+    ) -> Self {
+        self.max_per_client = Some(max_per_client);
+        self
+    }
+
+    /// Limits the number of associations that are open at once.
+    pub fn with_max_associations(
+        mut self,
+        max_associations: usize,
+    ) -> Self {
+        self.max_associations = Some(max_associations);
+        self
+    }
+
+    /// Sets whether clients may leave the address they send from unspecified, e.g., with 0.0.0.0:0, as RFC 1928
+    /// allows. The first datagram from the IP address of the client's control connection then fixes its address.
+    pub fn with_unspecified_client(
+        mut self,
+        allow: bool,
+    ) -> Self {
+        self.allow_unspecified_client = allow;
+        self
+    }
+
+    /// Returns how long an association may relay no datagrams before it is torn down.
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+
+    /// Returns the size of the largest datagram that is relayed.
+    pub fn max_datagram_size(&self) -> usize {
+        self.max_datagram_size
+    }
+
+    /// Returns the limit on the associations of each IP address of a client, if any.
+    pub fn max_associations_per_client(&self) -> Option<usize> {
+        self.max_per_client
+    }
+
+    /// Returns the limit on the associations, if any.
+    pub fn max_associations(&self) -> Option<usize> {
+        self.max_associations
+    }
+
+    /// Returns whether clients may leave the address they send from unspecified.
+    pub fn allows_unspecified_client(&self) -> bool {
+        self.allow_unspecified_client
+    }
+
+    /// Returns the number of associations that are open.
+    pub fn active(&self) -> usize {
+        self.associations.lock().unwrap().total
+    }
+
+    /// Returns the number of associations that are open for the IP address of a client.
+    pub fn active_for(
+        &self,
+        client: IpAddr,
+    ) -> usize {
+        self.associations.lock().unwrap().per_client.get(&client).copied().unwrap_or(0)
+    }
+
+    // Counts an association of the client, unless that exceeds a limit. Associations of clients whose address is
+    // unknown only count towards the total.
+    fn register(
+        &self,
+        client: Option<IpAddr>,
+    ) -> std::result::Result<AssociationSlot, UdpRefusal> {
+        let mut associations = self.associations.lock().unwrap();
+        if let Some(client) = client {
+            let open = associations.per_client.get(&client).copied().unwrap_or(0);
+            if self.max_per_client.is_some_and(|max| open >= max) {
+                return Err(UdpRefusal::ClientLimit);
+            }
+        }
+        if self.max_associations.is_some_and(|max| associations.total >= max) {
+            return Err(UdpRefusal::TotalLimit);
+        }
+
+        associations.total += 1;
+        if let Some(client) = client {
+            *associations.per_client.entry(client).or_default() += 1;
+        }
+
+        Ok(AssociationSlot {
+            associations: self.associations.clone(),
+            client,
+        })
+    }
+}
+
+// An association as counted by `UdpRelayConfig::register`, which is no longer counted once the slot is dropped.
+#[derive(Debug)]
+struct AssociationSlot {
+    associations: Arc<Mutex<Associations>>,
+    client: Option<IpAddr>,
+}
+
+impl Drop for AssociationSlot {
+    fn drop(&mut self) {
+        let mut associations = self.associations.lock().unwrap();
+        associations.total -= 1;
+        if let Some(client) = self.client {
+            if let Some(open) = associations.per_client.get_mut(&client) {
+                *open -= 1;
+                if *open == 0 {
+                    associations.per_client.remove(&client);
+                }
+            }
+        }
+    }
+}
+
+// What is known of the address that the client sends its datagrams from.
+#[derive(Debug)]
+struct ClientFilter {
+    ip: Option<IpAddr>,
+    port: Option<u16>,
+}
+
+impl ClientFilter {
+    fn matches(
+        &self,
+        from: SocketAddr,
+    ) -> bool {
+        self.ip.is_none_or(|ip| ip == from.ip()) && self.port.is_none_or(|port| port == from.port())
+    }
+}
+
+// A UDP association of a client: the socket that relays its datagrams, and its share of the limits.
+#[derive(Debug)]
+pub(crate) struct UdpAssociation {
+    socket: UdpSocket,
+    config: UdpRelayConfig,
+    client: ClientFilter,
+    _slot: AssociationSlot,
+}
+
+impl UdpAssociation {
+    // Opens an association for a client that claimed to send its datagrams from the given address, over a
+    // control connection with the given addresses. Refusals are counted with the recorder.
+    pub(crate) async fn open(
+        config: &UdpRelayConfig,
+        claimed: &Address,
+        control: ConnectionAddrs,
+        recorder: Recorder<'_>,
+    ) -> Result<Self> {
+        let (claimed_ip, claimed_port) = match claimed {
+            Address::Ip(addr) => (Some(addr.ip()).filter(|ip| !ip.is_unspecified()), addr.port()),
+            Address::Domainname { port, .. } => (None, *port),
+            Address::Unix(_) => bail!(SocksError::UnsupportedAddressType(SOCKS_ATYP_UNIX)),
+        };
+        let peer_ip = control.peer.map(|peer| peer.ip());
+
+        let registered = match claimed_ip {
+            None if !config.allow_unspecified_client => Err(UdpRefusal::UnspecifiedClient),
+            _ => config.register(peer_ip.or(claimed_ip)),
+        };
+        let slot = match registered {
+            Ok(slot) => slot,
+            Err(refusal) => {
+                recorder.udp_refused(refusal.label());
+                bail!(SocksError::UdpAssociationRefused(refusal));
+            }
+        };
+
+        // Datagrams are received on the address that the client connected to, which it can reach.
+        let ip = control.local.map_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED), |local| local.ip());
+        let socket = UdpSocket::bind(SocketAddr::new(ip, 0)).await?;
+
+        Ok(Self {
+            socket,
+            config: config.clone(),
+            client: ClientFilter {
+                ip: claimed_ip.or(peer_ip),
+                port: Some(claimed_port).filter(|port| *port != 0),
+            },
+            _slot: slot,
+        })
+    }
+
+    // Returns the address that the association relays on, for the reply to the client.
+    pub(crate) fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    // Relays the datagrams of the client until it closes the control connection, the association relays nothing
+    // for the idle timeout, or it exceeds the limits of the session. Datagrams from the client go to the
+    // destination in their header, and any other datagram goes back to the client, once its address is known. The
+    // summary is returned along with the error if relaying fails.
+    pub(crate) async fn relay(
+        self,
+        id: ConnectionId,
+        control: &mut dyn AsyncStream,
+        guard: &DestinationGuard,
+        limits: SessionLimits,
+        revoked: impl Future<Output = ()>,
+        recorder: Recorder<'_>,
+    ) -> (CloseSummary, Result<()>) {
+        let started = Instant::now();
+        let idle = time::sleep(self.config.idle_timeout);
+        let lifetime = async {
+            match limits.lifetime {
+                Some(lifetime) => time::sleep(lifetime).await,
+                None => future::pending().await,
+            }
+        };
+        tokio::pin!(idle, lifetime, revoked);
+
+        let mut client = None;
+        let (mut sent, mut received) = (0, 0);
+        // One byte more than the largest datagram, to tell the datagrams that are too large.
+        let mut buffer = vec![0; self.config.max_datagram_size + 1];
+        let mut discarded = [0; 64];
+        let mut relayed = Ok(());
+        let reason = loop {
+            tokio::select! {
+                read = control.read(&mut discarded) => match read {
+                    // The association ends with the control connection, which carries nothing else.
+                    Ok(0) => break CloseReason::Closed,
+                    Ok(_) => continue,
+                    Err(e) => {
+                        relayed = Err(e.into());
+                        break CloseReason::Failed;
+                    }
+                },
+                _ = &mut idle => break CloseReason::IdleTimeout,
+                _ = &mut lifetime => break CloseReason::LifetimeExceeded,
+                _ = &mut revoked => break CloseReason::PolicyRevoked,
+                datagram = self.socket.recv_from(&mut buffer) => {
+                    let (length, from) = match datagram {
+                        Ok(datagram) => datagram,
+                        Err(e) => {
+                            relayed = Err(e.into());
+                            break CloseReason::Failed;
+                        }
+                    };
+                    let datagram = &buffer[..length];
+                    let relayed = if length > self.config.max_datagram_size {
+                        Err("oversized")
+                    } else if self.is_client(from, &mut client) {
+                        self.send(datagram, guard).await.map(|payload| sent += payload)
+                    } else if let Some(client) = client {
+                        self.reply(datagram, from, client).await.map(|payload| received += payload)
+                    } else {
+                        Err("unknown_source")
+                    };
+
+                    match relayed {
+                        Ok(()) => idle.as_mut().reset(Instant::now() + self.config.idle_timeout),
+                        Err(reason) => {
+                            debug!("[{}] Dropped a datagram of {} bytes from {} ({}).", id, length, from, reason);
+                            recorder.udp_dropped(reason);
+                        }
+                    }
+                }
+            }
+
+            if limits.bytes.is_some_and(|cap| sent + received >= cap) {
+                break CloseReason::ByteCapExceeded;
+            }
+        };
+        if reason != CloseReason::Closed {
+            // Let the client know that the association is over, as far as it still listens.
+            let _ = control.shutdown().await;
+        }
+
+        let summary = CloseSummary {
+            sent,
+            received,
+            duration: started.elapsed(),
+            reason,
+        };
+        debug!(
+            "[{}] UDP association closed after {}ms, having sent {} and received {} bytes ({:?}).",
+            id,
+            summary.duration.as_millis(),
+            summary.sent,
+            summary.received,
+            summary.reason
+        );
+
+        (summary, relayed)
+    }
+
+    // Returns whether a datagram came from the client. The first datagram that matches what is known of the
+    // client fixes its address, for the rest of the association.
+    fn is_client(
+        &self,
+        from: SocketAddr,
+        client: &mut Option<SocketAddr>,
+    ) -> bool {
+        match client {
+            Some(client) => *client == from,
+            None if self.client.matches(from) => {
+                *client = Some(from);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Sends the payload of a datagram of the client to the destination in its header, if the guard allows it.
+    // Returns the size of the payload, or why the datagram was dropped.
+    async fn send(
+        &self,
+        datagram: &[u8],
+        guard: &DestinationGuard,
+    ) -> std::result::Result<u64, &'static str> {
+        let (fragment, destination, header) = match wire::parse_socks5_udp_header(datagram) {
+            Ok(Parsed::Complete((fragment, destination), header)) => (fragment, destination, header),
+            _ => return Err("malformed"),
+        };
+        // Reassembling fragments is optional, and not supported.
+        if fragment != 0 {
+            return Err("fragmented");
+        }
+
+        let checked = guard.check(&destination).await.map_err(|_| "blocked")?;
+        let target = match checked {
+            Address::Ip(addr) => addr,
+            Address::Domainname { host, port } => {
+                let resolved = net::lookup_host((host.as_str(), port)).await.ok().and_then(|mut addrs| addrs.next());
+                resolved.ok_or("unreachable")?
+            }
+            Address::Unix(_) => return Err("malformed"),
+        };
+        guard.check_resolved(&destination, &[target.ip()]).map_err(|_| "blocked")?;
+
+        let payload = &datagram[header..];
+        self.socket.send_to(payload, target).await.map_err(|_| "unreachable")?;
+
+        Ok(payload.len() as u64)
+    }
+
+    // Sends a datagram from elsewhere back to the client, with the address it came from in its header. Returns
+    // the size of the payload, or why the datagram was dropped.
+    async fn reply(
+        &self,
+        datagram: &[u8],
+        from: SocketAddr,
+        client: SocketAddr,
+    ) -> std::result::Result<u64, &'static str> {
+        let mut reply = Vec::with_capacity(datagram.len() + 22);
+        wire::encode_socks5_udp_header(0, &Address::Ip(from), &mut reply);
+        reply.extend_from_slice(datagram);
+        if reply.len() > self.config.max_datagram_size {
+            return Err("oversized");
+        }
+        self.socket.send_to(&reply, client).await.map_err(|_| "unreachable")?;
+
+        Ok(datagram.len() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Tests that associations are refused beyond the limits, and count again once their slot is dropped.
+    #[test]
+    fn test_register() {
+        let config = UdpRelayConfig::new().with_max_associations_per_client(2).with_max_associations(3);
+        let first = IpAddr::from([192, 0, 2, 1]);
+        let second = IpAddr::from([192, 0, 2, 2]);
+
+        let slots = [config.register(Some(first)).unwrap(), config.register(Some(first)).unwrap()];
+        assert_eq!(config.register(Some(first)).unwrap_err(), UdpRefusal::ClientLimit);
+        let unknown = config.register(None).unwrap();
+        assert_eq!(config.register(Some(second)).unwrap_err(), UdpRefusal::TotalLimit);
+        assert_eq!((config.active(), config.active_for(first)), (3, 2));
+
+        drop(unknown);
+        let _second = config.clone().register(Some(second)).unwrap();
+        drop(slots);
+        assert_eq!((config.active(), config.active_for(first), config.active_for(second)), (1, 0, 1));
+    }
+}
@@ -1,7 +1,20 @@
-use std::net::SocketAddr;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
 
 use anyhow::Result;
-use tokio::net::{self, TcpStream};
+#[cfg(target_os = "linux")]
+use tokio::io::Interest;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf};
+use tokio::net::{self, TcpStream, UdpSocket};
+
+#[cfg(not(target_os = "linux"))]
+use crate::SocksError;
+
+/// The size of the read buffer used during handshakes, enough to hold most handshakes in full.
+const HANDSHAKE_BUFFER_SIZE: usize = 512;
 
 /// Retrieves the original destination address from a socket on a Linux system.
 ///
@@ -61,6 +74,221 @@ pub fn get_original_dst<S>(_socket: S) -> Result<SocketAddr> {
     todo!();
 }
 
+/// Makes a UDP socket report the original destination of each datagram, for `get_original_dst_udp`.
+///
+/// With TPROXY redirection, the original destination is the address the datagram was sent to
+/// before it was redirected. Only supported on Linux.
+///
+/// # Parameters
+///
+/// * `socket`: The UDP socket that receives the redirected datagrams.
+///
+/// # Returns
+///
+/// Returns a `Result` indicating success, or `SocksError::Unsupported` on other platforms.
+#[cfg(target_os = "linux")]
+pub fn enable_original_dst_udp(socket: &UdpSocket) -> Result<()> {
+    use nix::sys::socket::{self, sockopt};
+
+    if socket.local_addr()?.is_ipv4() {
+        socket::setsockopt(socket, sockopt::Ipv4OrigDstAddr, &true)?;
+    } else {
+        socket::setsockopt(socket, sockopt::Ipv6OrigDstAddr, &true)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn enable_original_dst_udp(_socket: &UdpSocket) -> Result<()> {
+    Err(SocksError::Unsupported("UDP original destinations").into())
+}
+
+/// Lists the IP addresses of the host's network interfaces, e.g., to learn which addresses a listener on an
+/// unspecified address can be reached at.
+///
+/// # Returns
+///
+/// Returns a `Result` containing the addresses, or `SocksError::Unsupported` on other platforms.
+#[cfg(target_os = "linux")]
+pub fn interface_addrs() -> Result<Vec<IpAddr>> {
+    let addrs = nix::ifaddrs::getifaddrs()?
+        .filter_map(|interface| interface.address)
+        .filter_map(|address| {
+            let v4 = address.as_sockaddr_in().map(|addr| IpAddr::V4(addr.ip()));
+            v4.or_else(|| address.as_sockaddr_in6().map(|addr| IpAddr::V6(addr.ip())))
+        })
+        .collect();
+
+    Ok(addrs)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn interface_addrs() -> Result<Vec<IpAddr>> {
+    Err(SocksError::Unsupported("listing interface addresses").into())
+}
+
+/// Receives a datagram along with its source and original destination.
+///
+/// The socket must have been prepared with `enable_original_dst_udp`.
+///
+/// # Parameters
+///
+/// * `socket`: The UDP socket that receives the redirected datagrams.
+/// * `buf`: The buffer the payload is written to.
+///
+/// # Returns
+///
+/// Returns a `Result` containing the length of the payload, its source, and its original destination.
+#[cfg(target_os = "linux")]
+pub async fn get_original_dst_udp(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> Result<(usize, SocketAddr, SocketAddr)> {
+    use std::os::unix::io::AsRawFd;
+
+    let (length, source, control) = socket
+        .async_io(Interest::READABLE, || original_dst::recv(socket.as_raw_fd(), buf))
+        .await?;
+
+    match original_dst::parse_control(&control)? {
+        Some(original_dst) => Ok((length, source, original_dst)),
+        None => bail!("Datagram lacks its original destination, is `enable_original_dst_udp` called?"),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn get_original_dst_udp(
+    _socket: &UdpSocket,
+    _buf: &mut [u8],
+) -> Result<(usize, SocketAddr, SocketAddr)> {
+    Err(SocksError::Unsupported("UDP original destinations").into())
+}
+
+/// Receiving datagrams with their control messages, and parsing those (Linux only).
+#[cfg(target_os = "linux")]
+mod original_dst {
+    use std::convert::TryInto;
+    use std::io;
+    use std::mem;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+    use std::os::unix::io::RawFd;
+
+    use anyhow::Result;
+
+    /// Receives a datagram, returning the length of its payload, its source, and its control messages.
+    pub(super) fn recv(
+        fd: RawFd,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr, Vec<u8>)> {
+        // A `u64` array keeps the control buffer aligned for `cmsghdr`.
+        let mut control = [0u64; 16];
+        let mut source: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+
+        let mut message: libc::msghdr = unsafe { mem::zeroed() };
+        message.msg_name = &mut source as *mut libc::sockaddr_storage as *mut libc::c_void;
+        message.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        message.msg_controllen = mem::size_of_val(&control) as _;
+
+        // SAFETY: every pointer in `message` points to a live buffer of the given length.
+        let length = unsafe { libc::recvmsg(fd, &mut message, 0) };
+        if length < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // The original destination may be among the control messages that didn't fit.
+        if message.msg_flags & libc::MSG_CTRUNC != 0 {
+            let message = "The control messages of the datagram were truncated";
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+
+        // SAFETY: the kernel initialized `msg_namelen` bytes of the source, and `msg_controllen` bytes
+        // of the control buffer, which are both within their buffers.
+        let source = unsafe {
+            std::slice::from_raw_parts(
+                &source as *const libc::sockaddr_storage as *const u8,
+                message.msg_namelen as usize,
+            )
+        };
+        let control =
+            unsafe { std::slice::from_raw_parts(control.as_ptr() as *const u8, message.msg_controllen as usize) };
+
+        let source = parse_sockaddr(source).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+        Ok((length as usize, source, control.to_vec()))
+    }
+
+    /// Finds the original destination among the control messages of a datagram.
+    pub(super) fn parse_control(mut control: &[u8]) -> Result<Option<SocketAddr>> {
+        let header_length = align(mem::size_of::<libc::cmsghdr>());
+
+        while control.len() >= header_length {
+            let length = usize::from_ne_bytes(control[..mem::size_of::<usize>()].try_into()?);
+            let level = read_i32(&control[mem::size_of::<usize>()..])?;
+            let kind = read_i32(&control[mem::size_of::<usize>() + 4..])?;
+            ensure!(
+                length >= header_length && length <= control.len(),
+                "Invalid control message length: {}",
+                length
+            );
+
+            let data = &control[header_length..length];
+            match (level, kind) {
+                (libc::SOL_IP, libc::IP_ORIGDSTADDR) | (libc::SOL_IPV6, libc::IPV6_ORIGDSTADDR) => {
+                    return Ok(Some(parse_sockaddr(data)?));
+                }
+                _ => {}
+            }
+
+            control = &control[align(length).min(control.len())..];
+        }
+
+        Ok(None)
+    }
+
+    /// Parses a `sockaddr_in` or `sockaddr_in6`, in the layout the kernel writes them.
+    pub(super) fn parse_sockaddr(bytes: &[u8]) -> Result<SocketAddr> {
+        ensure!(bytes.len() >= 2, "Truncated socket address: {} bytes", bytes.len());
+
+        let family = u16::from_ne_bytes([bytes[0], bytes[1]]) as libc::c_int;
+        match family {
+            libc::AF_INET => {
+                ensure!(bytes.len() >= 8, "Truncated IPv4 socket address: {} bytes", bytes.len());
+                let port = u16::from_be_bytes([bytes[2], bytes[3]]);
+                let ip: [u8; 4] = bytes[4..8].try_into()?;
+
+                Ok(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from(ip), port)))
+            }
+            libc::AF_INET6 => {
+                ensure!(bytes.len() >= 28, "Truncated IPv6 socket address: {} bytes", bytes.len());
+                let port = u16::from_be_bytes([bytes[2], bytes[3]]);
+                let flowinfo = u32::from_be_bytes(bytes[4..8].try_into()?);
+                let ip: [u8; 16] = bytes[8..24].try_into()?;
+                let scope_id = u32::from_ne_bytes(bytes[24..28].try_into()?);
+
+                Ok(SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(ip), port, flowinfo, scope_id)))
+            }
+            family => bail!("Unsupported socket address family: {}", family),
+        }
+    }
+
+    // Rounds a length up to the alignment of control messages.
+    fn align(length: usize) -> usize {
+        let alignment = mem::size_of::<usize>();
+        (length + alignment - 1) & !(alignment - 1)
+    }
+
+    fn read_i32(bytes: &[u8]) -> Result<i32> {
+        Ok(i32::from_ne_bytes(bytes[..4].try_into()?))
+    }
+}
+
 /// Resolves a given address to a `SocketAddr`.
 ///
 /// # Parameters
@@ -86,6 +314,30 @@ pub async fn resolve_addr<S: Into<String>>(addr: S) -> Result<SocketAddr> {
     }
 }
 
+/// Resolves a given address to all of its `SocketAddr`s, in DNS order.
+///
+/// # Parameters
+///
+/// * `addr`: The address, either as a domain name or IP address.
+///
+/// # Returns
+///
+/// Returns a `Result` containing the non-empty list of resolved `SocketAddr`s or an error.
+pub async fn resolve_all<S: Into<String>>(addr: S) -> Result<Vec<SocketAddr>> {
+    let addr: String = addr.into();
+
+    // First, try to parse address as socket address.
+    if let Ok(addr) = addr.parse() {
+        return Ok(vec![addr]);
+    }
+
+    // Otherwise, address is probably a domain name.
+    let addresses: Vec<SocketAddr> = net::lookup_host(addr).await?.collect();
+    ensure!(!addresses.is_empty(), "Domain name didn't resolve to an IP address.");
+
+    Ok(addresses)
+}
+
 /// Attempts to read the initial data from a TCP stream.
 ///
 /// # Parameters
@@ -102,10 +354,153 @@ pub async fn try_read_initial_data(stream: &mut TcpStream) -> Result<Option<Vec<
     match stream.try_read_buf(&mut initial_data) {
         Ok(0) => Ok(None),
         Ok(_) => Ok(Some(initial_data)),
-        Err(e) => {
-            return Err(e.into());
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Shuts a stream down in an orderly way, e.g., a tunnel returned by a client, once the caller is done writing.
+///
+/// Whatever was written is flushed before the write side is shut down, so that the peer sees the end of the data
+/// (a FIN on TCP) after all of it. With a drain timeout, inbound data that is still pending is read and discarded
+/// until the peer closes its side too, or the timeout passes, so that it isn't met with a reset. Dropping the
+/// stream afterwards closes it.
+///
+/// # Parameters
+///
+/// * `stream`: The stream to shut down.
+/// * `drain`: How long to discard inbound data while waiting for the peer to close, if at all.
+///
+/// # Returns
+///
+/// Returns a `Result` containing whether the peer closed its side before the drain timeout, which is `false`
+/// without one, or an error.
+pub async fn shutdown_gracefully<S>(
+    stream: &mut S,
+    drain: Option<Duration>,
+) -> Result<bool>
+where
+    S: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    stream.flush().await?;
+    stream.shutdown().await?;
+
+    let drain = match drain {
+        Some(drain) => drain,
+        None => return Ok(false),
+    };
+    let discard = async {
+        let mut buffer = [0; 4096];
+        while stream.read(&mut buffer).await? != 0 {}
+
+        Ok::<_, io::Error>(())
+    };
+    match tokio::time::timeout(drain, discard).await {
+        Ok(closed) => closed.map(|_| true).map_err(Into::into),
+        Err(_) => Ok(false),
+    }
+}
+
+/// Wraps a stream in a buffered reader for the handshake phase.
+///
+/// Handshakes consist of many small fields, so reading them through a buffer saves a syscall for
+/// nearly every field. Bytes that are read ahead must be passed on with `forward_read_ahead`.
+pub(crate) fn handshake_reader<S: AsyncRead>(stream: S) -> BufReader<S> {
+    BufReader::with_capacity(HANDSHAKE_BUFFER_SIZE, stream)
+}
+
+/// Ends the handshake phase by writing the bytes that were read ahead to the destination.
+///
+/// # Parameters
+///
+/// * `reader`: The reader returned by `handshake_reader`.
+/// * `destination`: The stream that receives the remaining bytes from the source.
+///
+/// # Returns
+///
+/// Returns a `Result` containing the underlying stream, or an error.
+pub(crate) async fn forward_read_ahead<S, D>(
+    reader: BufReader<S>,
+    destination: &mut D,
+) -> Result<S>
+where
+    S: AsyncRead,
+    D: AsyncWrite + Unpin,
+{
+    let read_ahead = reader.buffer();
+    if !read_ahead.is_empty() {
+        destination.write_all(read_ahead).await?;
+    }
+
+    Ok(reader.into_inner())
+}
+
+/// A stream that replays bytes which were already read from it, before reading on.
+///
+/// Used to hand a connection over to another handler once the version byte has been read.
+pub(crate) struct Rewound<S> {
+    prefix: Vec<u8>,
+    position: usize,
+    inner: S,
+}
+
+impl<S> Rewound<S> {
+    pub(crate) fn new(
+        prefix: Vec<u8>,
+        inner: S,
+    ) -> Self {
+        Self {
+            prefix,
+            position: 0,
+            inner,
         }
     }
+
+    /// Returns the replayed bytes that haven't been read yet.
+    pub(crate) fn remaining(&self) -> &[u8] {
+        &self.prefix[self.position..]
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewound<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.position < self.prefix.len() {
+            let length = buf.remaining().min(self.prefix.len() - self.position);
+            buf.put_slice(&self.prefix[self.position..self.position + length]);
+            self.position += length;
+
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewound<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
 }
 
 #[cfg(test)]
@@ -125,9 +520,9 @@ mod tests {
         }
     }
 
-    impl Into<String> for MockSocketAddr {
-        fn into(self) -> String {
-            self.addr
+    impl From<MockSocketAddr> for String {
+        fn from(addr: MockSocketAddr) -> String {
+            addr.addr
         }
     }
 
@@ -150,4 +545,134 @@ mod tests {
         let result = resolve_addr(mock_addr).await;
         assert!(result.is_ok());
     }
+
+    // Test that replayed bytes are read before the rest of the stream.
+    #[tokio::test]
+    async fn test_rewound() -> Result<()> {
+        use tokio::io::AsyncReadExt;
+
+        let mut rewound = Rewound::new(b"hello ".to_vec(), &b"world"[..]);
+        let mut first = [0; 3];
+        rewound.read_exact(&mut first).await?;
+        assert_eq!(rewound.remaining(), b"lo ");
+
+        let mut rest = vec![];
+        rewound.read_to_end(&mut rest).await?;
+        assert_eq!(rest, b"lo world");
+        Ok(())
+    }
+
+    // Test that a graceful shutdown ends the data with a FIN after all of it, and discards what the peer still sends
+    // until it closes.
+    #[tokio::test]
+    async fn test_shutdown_gracefully() -> Result<()> {
+        let listener = net::TcpListener::bind("127.0.0.1:0").await?;
+        let mut stream = TcpStream::connect(listener.local_addr()?).await?;
+        let (mut peer, _) = listener.accept().await?;
+
+        let peer = tokio::spawn(async move {
+            let mut received = vec![];
+            peer.read_to_end(&mut received).await?;
+            // The FIN arrived, but the peer still writes before closing, as an SMTP server sends its goodbye.
+            peer.write_all(b"221 bye").await?;
+            Ok::<_, io::Error>(received)
+        });
+
+        stream.write_all(b"QUIT").await?;
+        assert!(shutdown_gracefully(&mut stream, Some(Duration::from_secs(5))).await?);
+        assert_eq!(peer.await??, b"QUIT");
+
+        Ok(())
+    }
+
+    // Test that the drain gives up once its timeout passes when the peer doesn't close, and that it isn't waited for
+    // without one.
+    #[tokio::test(start_paused = true)]
+    async fn test_shutdown_gracefully_deadline() -> Result<()> {
+        let (mut stream, mut peer) = tokio::io::duplex(64);
+        peer.write_all(b"pending").await?;
+
+        let started = tokio::time::Instant::now();
+        assert!(!shutdown_gracefully(&mut stream, Some(Duration::from_secs(2))).await?);
+        assert_eq!(started.elapsed(), Duration::from_secs(2));
+
+        let mut received = vec![];
+        peer.read_to_end(&mut received).await?;
+        assert!(received.is_empty());
+
+        let (mut stream, _peer) = tokio::io::duplex(64);
+        assert!(!shutdown_gracefully(&mut stream, None).await?);
+        assert_eq!(started.elapsed(), Duration::from_secs(2));
+
+        Ok(())
+    }
+
+    // Test parsing the control messages of a datagram, as captured on x86-64 Linux.
+    #[cfg(all(target_os = "linux", target_pointer_width = "64", target_endian = "little"))]
+    #[test]
+    fn test_parse_original_dst_control() {
+        // A `SO_TIMESTAMP` message (level 1, type 29), followed by `IP_ORIGDSTADDR` for 10.0.0.1:53.
+        let control: Vec<u8> = [
+            &[32, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 29, 0, 0, 0][..],
+            &[0x11; 16][..],
+            &[32, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 20, 0, 0, 0][..],
+            &[2, 0, 0, 53, 10, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0][..],
+        ]
+        .concat();
+        let original_dst = original_dst::parse_control(&control).unwrap();
+        assert_eq!(original_dst, Some("10.0.0.1:53".parse().unwrap()));
+
+        // `IPV6_ORIGDSTADDR` (level 41, type 74) for [2001:db8::1]:443, padded to eight bytes.
+        let control: Vec<u8> = [
+            &[44, 0, 0, 0, 0, 0, 0, 0, 41, 0, 0, 0, 74, 0, 0, 0][..],
+            &[10, 0, 1, 187, 0, 0, 0, 0][..],
+            &[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1][..],
+            &[0, 0, 0, 0, 0, 0, 0, 0][..],
+        ]
+        .concat();
+        let original_dst = original_dst::parse_control(&control).unwrap();
+        assert_eq!(original_dst, Some("[2001:db8::1]:443".parse().unwrap()));
+
+        // No original destination, and a length past the end of the buffer.
+        assert_eq!(original_dst::parse_control(&[]).unwrap(), None);
+        let mut truncated = control.clone();
+        truncated[0] = 200;
+        assert!(original_dst::parse_control(&truncated).is_err());
+    }
+
+    // Test that a datagram on loopback reports the address it was sent to as its original destination.
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_get_original_dst_udp() -> Result<()> {
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        enable_original_dst_udp(&socket)?;
+
+        let sender = UdpSocket::bind("127.0.0.1:0").await?;
+        sender.send_to(b"hello", socket.local_addr()?).await?;
+
+        let mut buf = [0; 16];
+        let (length, source, original_dst) = get_original_dst_udp(&socket, &mut buf).await?;
+        assert_eq!(&buf[..length], b"hello");
+        assert_eq!(source, sender.local_addr()?);
+        assert_eq!(original_dst, socket.local_addr()?);
+        Ok(())
+    }
+
+    // Test that the interface addresses include the loopback address.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_interface_addrs() -> Result<()> {
+        assert!(interface_addrs()?.contains(&"127.0.0.1".parse()?));
+        Ok(())
+    }
+
+    // Test resolve_all function
+    #[tokio::test]
+    async fn test_resolve_all() {
+        let result = resolve_all("[::1]:8080").await;
+        assert_eq!(result.unwrap(), vec!["[::1]:8080".parse::<SocketAddr>().unwrap()]);
+
+        let result = resolve_all("localhost:8080").await;
+        assert!(!result.unwrap().is_empty());
+    }
 }
@@ -0,0 +1,247 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use anyhow::Result;
+use bytes::{Buf, Bytes};
+use futures::{ready, Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::MaybeTlsStream;
+
+/// A WebSocket connection exposed as a byte stream, e.g., to reach a proxy behind a WebSocket ingress.
+///
+/// Every write is sent as a binary message, and the payloads of received binary messages are concatenated.
+/// Fragmented messages are reassembled, and pings are answered, without the reader noticing. A close frame
+/// reads as the end of the stream.
+///
+/// The stream can be given to `Socks6Client::connect_with_stream` to run the SOCKS handshake over it, or to a
+/// handler, on the server side.
+pub struct WebSocketStream<S> {
+    inner: tokio_tungstenite::WebSocketStream<S>,
+    pending: Bytes,
+    flushing: bool,
+}
+
+impl<S> WebSocketStream<S> {
+    /// Wraps an established WebSocket connection, e.g., one accepted with `tokio_tungstenite::accept_async`.
+    pub fn new(inner: tokio_tungstenite::WebSocketStream<S>) -> Self {
+        Self {
+            inner,
+            pending: Bytes::new(),
+            flushing: false,
+        }
+    }
+
+    /// Returns the WebSocket connection, dropping the part of a received message that wasn't read yet.
+    pub fn into_inner(self) -> tokio_tungstenite::WebSocketStream<S> {
+        self.inner
+    }
+}
+
+/// Connects to a WebSocket server, given a ws or wss URL, and performs the upgrade.
+///
+/// # Parameters
+///
+/// - `url`: The URL of the WebSocket endpoint, e.g., `wss://proxy.example.com/socks`.
+///
+/// # Returns
+///
+/// Returns a `Result` containing the connection as a byte stream, or an error if the upgrade failed.
+pub async fn connect(url: &str) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>> {
+    let (inner, response) = tokio_tungstenite::connect_async(url).await?;
+    debug!("Upgraded to a WebSocket connection with {} ({}).", url, response.status());
+
+    Ok(WebSocketStream::new(inner))
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WebSocketStream<S> {
+    // Completes the flush of the most recent write, which is started, but not awaited, by `poll_write`.
+    fn poll_pending_flush(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.flushing {
+            ready!(Pin::new(&mut self.inner).poll_flush(cx)).map_err(into_io_error)?;
+            self.flushing = false;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocketStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            if !this.pending.is_empty() {
+                let len = this.pending.len().min(buf.remaining());
+                buf.put_slice(&this.pending[..len]);
+                this.pending.advance(len);
+
+                return Poll::Ready(Ok(()));
+            }
+
+            // A reader waiting for an answer must not wait on its own request, if that wasn't flushed yet.
+            if let Poll::Ready(Err(e)) = this.poll_pending_flush(cx) {
+                return Poll::Ready(Err(e));
+            }
+
+            match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => this.pending = data,
+                Some(Ok(Message::Text(_))) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Unexpected text message on a byte stream.",
+                    )))
+                }
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Ok(_)) => continue,
+                Some(Err(WsError::ConnectionClosed)) | Some(Err(WsError::AlreadyClosed)) => {
+                    return Poll::Ready(Ok(()))
+                }
+                Some(Err(e)) => return Poll::Ready(Err(into_io_error(e))),
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocketStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending_flush(cx))?;
+        ready!(Pin::new(&mut this.inner).poll_ready(cx)).map_err(into_io_error)?;
+
+        let message = Message::Binary(Bytes::copy_from_slice(buf));
+        Pin::new(&mut this.inner).start_send(message).map_err(into_io_error)?;
+
+        // Messages are buffered until flushed, which callers of a byte stream don't always do, e.g., before
+        // waiting for a reply. The flush is started here, and completed by the next read or write.
+        this.flushing = true;
+        if let Poll::Ready(Err(e)) = this.poll_pending_flush(cx) {
+            return Poll::Ready(Err(e));
+        }
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.flushing = true;
+        this.poll_pending_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        match ready!(Pin::new(&mut self.get_mut().inner).poll_close(cx)) {
+            Ok(()) | Err(WsError::ConnectionClosed) | Err(WsError::AlreadyClosed) => Poll::Ready(Ok(())),
+            Err(e) => Poll::Ready(Err(into_io_error(e))),
+        }
+    }
+}
+
+// Converts a WebSocket error into an I/O error, keeping the kind of underlying I/O errors.
+fn into_io_error(error: WsError) -> io::Error {
+    match error {
+        WsError::Io(e) => e,
+        WsError::ConnectionClosed | WsError::AlreadyClosed => io::Error::from(io::ErrorKind::BrokenPipe),
+        e => io::Error::other(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use crate::{Socks6Client, Socks6Handler, SocksHandler};
+
+    use super::*;
+
+    // Tests that fragmented messages are concatenated and pings are answered, without the reader noticing.
+    #[tokio::test]
+    async fn test_byte_stream() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("ws://{}", listener.local_addr()?);
+
+        let server = tokio::spawn(async move {
+            let (incoming, _) = listener.accept().await?;
+            let mut websocket = tokio_tungstenite::accept_async(incoming).await?;
+            websocket.send(Message::Ping(Bytes::from_static(b"ping"))).await?;
+            websocket.send(Message::Binary(Bytes::from_static(b"hel"))).await?;
+            websocket.send(Message::Binary(Bytes::from_static(b"lo"))).await?;
+
+            let mut received = vec![];
+            while let Some(message) = websocket.next().await {
+                received.push(message?);
+            }
+            Ok::<_, anyhow::Error>(received)
+        });
+
+        let mut stream = connect(&url).await?;
+        let mut received = [0; 5];
+        stream.read_exact(&mut received).await?;
+        assert_eq!(&received, b"hello");
+
+        stream.write_all(b"ok").await?;
+        stream.shutdown().await?;
+        assert_eq!(stream.read(&mut received).await?, 0);
+
+        let received = server.await??;
+        assert!(received.contains(&Message::Pong(Bytes::from_static(b"ping"))));
+        assert!(received.contains(&Message::Binary(Bytes::from_static(b"ok"))));
+        assert!(matches!(received.last(), Some(Message::Close(_))));
+
+        Ok(())
+    }
+
+    // Tests a tunnel end to end, with the handshake run over a WebSocket connection to a bridge, which hands
+    // the connection to a handler.
+    #[tokio::test]
+    async fn test_tunnel_over_websocket() -> Result<()> {
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let destination_addr = destination.local_addr()?;
+        tokio::spawn(async move {
+            let (mut incoming, _) = destination.accept().await?;
+            let (mut reader, mut writer) = incoming.split();
+            tokio::io::copy(&mut reader, &mut writer).await
+        });
+
+        let bridge = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("ws://{}/socks", bridge.local_addr()?);
+        tokio::spawn(async move {
+            let (incoming, _) = bridge.accept().await?;
+            let mut stream = WebSocketStream::new(tokio_tungstenite::accept_async(incoming).await?);
+            Socks6Handler::default().accept_request(&mut stream).await
+        });
+
+        let stream = connect(&url).await?;
+        let client = Socks6Client::for_streams(None);
+        let (mut stream, _) = client
+            .connect_with_stream(stream, destination_addr.to_string(), Some(b"hello".to_vec()), None)
+            .await?;
+
+        let mut received = [0; 11];
+        stream.write_all(b" world").await?;
+        stream.read_exact(&mut received).await?;
+        assert_eq!(&received, b"hello world");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,242 @@
+use std::convert::TryInto;
+use std::net::SocketAddr;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::BytesMut;
+use tokio::io::AsyncWriteExt;
+
+use crate::{Address, wire, Credentials, SocksClient, SocksError};
+use crate::dialer::{AddressFamilyPreference, Keepalive, ProxyEndpoint};
+use crate::session::ConnectionId;
+use crate::stream::SocksStream;
+
+/// A client for HTTP proxies, which tunnels connections with the CONNECT method.
+///
+/// It implements `SocksClient`, so version-generic code can fall back to it where only an HTTP proxy is
+/// available.
+#[derive(Clone)]
+pub struct HttpConnectClient {
+    proxy: ProxyEndpoint,
+    credentials: Option<Credentials>,
+    family_preference: AddressFamilyPreference,
+    keepalive: Option<Keepalive>,
+}
+
+impl HttpConnectClient {
+    /// Creates a new `HttpConnectClient`.
+    ///
+    /// # Arguments
+    ///
+    /// * `proxy_addr` - The address of the HTTP proxy, as `host:port`, or as `unix:/path/to/socket`.
+    /// * `credentials` - Optional credentials, sent with basic proxy authorization.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the new `HttpConnectClient` instance.
+    pub async fn new<A: Into<String>>(
+        proxy_addr: A,
+        credentials: Option<Credentials>,
+    ) -> Result<Self> {
+        let proxy = ProxyEndpoint::resolve(proxy_addr).await?;
+
+        Ok(HttpConnectClient {
+            proxy,
+            credentials,
+            family_preference: AddressFamilyPreference::default(),
+            keepalive: None,
+        })
+    }
+
+    /// Sets the policy that determines which of the proxy's addresses are used, and in which order.
+    pub fn with_family_preference(
+        mut self,
+        family_preference: AddressFamilyPreference,
+    ) -> Self {
+        self.family_preference = family_preference;
+        self
+    }
+
+    /// Enables TCP keepalive on the connections to the proxy, which carry the tunnels once established.
+    pub fn with_keepalive(
+        mut self,
+        keepalive: Keepalive,
+    ) -> Self {
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    /// Returns the proxy address used for the most recent successful connection, if any.
+    pub fn proxy_addr(&self) -> Option<SocketAddr> {
+        self.proxy.current_addr()
+    }
+
+    /// Establishes a tunnel to the specified destination with a CONNECT request.
+    ///
+    /// If `initial_data` is provided, it is written through the tunnel once it has been established, before
+    /// the stream is returned. A failure at that stage is reported as `SocksError::InitialDataWrite`.
+    ///
+    /// # Arguments
+    ///
+    /// * `destination` - The target address and port to connect to.
+    /// * `initial_data` - Optional data to deliver to the destination before returning the stream.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing a tuple with a `SocksStream` to the destination and the destination itself, as HTTP
+    /// proxies don't announce the address they bound. A proxy that rejects the credentials, or demands some,
+    /// fails with `SocksError::HttpProxyAuthenticationRequired`, any other unsuccessful status with
+    /// `SocksError::HttpConnectFailed`. Errors carry the connection ID of the attempt.
+    pub async fn connect<A>(
+        &self,
+        destination: A,
+        initial_data: Option<Vec<u8>>,
+    ) -> Result<(SocksStream, Address)>
+        where
+            A: TryInto<Address>,
+            A::Error: Into<anyhow::Error>,
+    {
+        let id = ConnectionId::generate();
+        self.connect_session(id, destination, initial_data)
+            .await
+            .map_err(|e| id.attach(e))
+    }
+
+    // Establishes a tunnel to the destination, for the session with the given ID.
+    async fn connect_session<A>(
+        &self,
+        id: ConnectionId,
+        destination: A,
+        initial_data: Option<Vec<u8>>,
+    ) -> Result<(SocksStream, Address)>
+        where
+            A: TryInto<Address>,
+            A::Error: Into<anyhow::Error>,
+    {
+        let destination = destination.try_into().map_err(Into::into)?;
+        debug!("[{}] Connecting to {} through the HTTP proxy.", id, destination);
+
+        let mut stream = self.connect_proxy().await?;
+
+        let mut request = vec![];
+        wire::encode_http_connect_request(&destination, self.credentials.as_ref(), &mut request);
+        stream.write_all(&request).await?;
+
+        // The response head is read up to the empty line only, so nothing sent through the tunnel is lost.
+        let response = wire::read_message(&mut stream, &mut BytesMut::new(), wire::parse_http_response).await?;
+        match response.status {
+            _ if response.is_success() => {}
+            407 => {
+                let challenge = response.header("Proxy-Authenticate").unwrap_or("none");
+                debug!("[{}] Proxy demands authentication: {}.", id, challenge);
+                bail!(SocksError::HttpProxyAuthenticationRequired);
+            }
+            status => bail!(SocksError::HttpConnectFailed(status)),
+        }
+
+        // Deliver initial data through the established tunnel.
+        if let Some(initial_data) = initial_data {
+            stream
+                .write_all(&initial_data)
+                .await
+                .map_err(SocksError::InitialDataWrite)?;
+        }
+
+        Ok((stream, destination))
+    }
+
+    /// Connects to the proxy, racing its addresses according to the family preference.
+    async fn connect_proxy(&self) -> Result<SocksStream> {
+        let (stream, _) = self.proxy.connect(self.family_preference).await?;
+        if let (Some(keepalive), Some(tcp)) = (&self.keepalive, stream.as_tcp()) {
+            keepalive.apply(tcp)?;
+        }
+
+        Ok(stream)
+    }
+}
+
+#[async_trait]
+impl SocksClient for HttpConnectClient {
+    async fn connect(
+        &self,
+        destination: Address,
+        initial_data: Option<Vec<u8>>,
+    ) -> Result<(SocksStream, Address)> {
+        HttpConnectClient::connect(self, destination, initial_data).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    // Serves a single CONNECT request with the given response, returning the request head, and echoing the
+    // tunnel after a successful response.
+    async fn proxy(response: &'static str) -> Result<(SocketAddr, tokio::task::JoinHandle<Result<String>>)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            let mut head = vec![];
+            while !head.ends_with(b"\r\n\r\n") {
+                head.push(stream.read_u8().await?);
+            }
+
+            stream.write_all(response.as_bytes()).await?;
+            if response.starts_with("HTTP/1.1 200") {
+                let (mut reader, mut writer) = stream.split();
+                tokio::io::copy(&mut reader, &mut writer).await?;
+            }
+
+            Ok(String::from_utf8(head)?)
+        });
+
+        Ok((addr, server))
+    }
+
+    // Tests a tunnel through the version-generic `SocksClient` trait, including basic authorization and data
+    // the proxy sends right behind its response.
+    #[tokio::test]
+    async fn test_connect() -> Result<()> {
+        let (proxy_addr, server) = proxy("HTTP/1.1 200 Connection established\r\nVia: proxy\r\n\r\nearly").await?;
+
+        let credentials = Credentials::new("user", "secret");
+        let client: Box<dyn SocksClient + Send + Sync> =
+            Box::new(HttpConnectClient::new(proxy_addr.to_string(), Some(credentials)).await?);
+        let (mut stream, binding) = client.connect(Address::new("example.com", 443), Some(b"hello".to_vec())).await?;
+        assert_eq!(binding, Address::new("example.com", 443));
+
+        let mut received = [0; 10];
+        stream.read_exact(&mut received).await?;
+        assert_eq!(&received, b"earlyhello");
+        drop(stream);
+
+        let head = server.await??;
+        assert!(head.starts_with("CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n"));
+        assert!(head.contains("Proxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\n"));
+
+        Ok(())
+    }
+
+    // Tests that unsuccessful statuses fail with their code, and that 407 is distinguishable.
+    #[tokio::test]
+    async fn test_connect_rejected() -> Result<()> {
+        let (proxy_addr, _) =
+            proxy("HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic\r\n\r\n").await?;
+        let client = HttpConnectClient::new(proxy_addr.to_string(), None).await?;
+        let error = client.connect("192.0.2.1:80".to_string(), None).await.unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(SocksError::HttpProxyAuthenticationRequired)));
+
+        let (proxy_addr, _) = proxy("HTTP/1.1 403 Forbidden\r\n\r\n").await?;
+        let client = HttpConnectClient::new(proxy_addr.to_string(), None).await?;
+        let error = client.connect("192.0.2.1:80".to_string(), None).await.unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(SocksError::HttpConnectFailed(403))));
+
+        Ok(())
+    }
+}
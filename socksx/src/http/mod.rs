@@ -0,0 +1,29 @@
+pub use http_client::HttpConnectClient;
+
+mod http_client;
+
+/// The status line and headers of an HTTP response, e.g., of a proxy to a CONNECT request.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl HttpResponse {
+    /// Returns the value of the first header with the given name, which is matched case-insensitively.
+    pub fn header(
+        &self,
+        name: &str,
+    ) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns whether the status is successful (2xx).
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
@@ -22,22 +22,91 @@ extern crate num_derive;
 
 pub use tokio::io::copy_bidirectional;
 
+/// HTTP CONNECT client, for environments without a SOCKS proxy.
+pub use http::HttpConnectClient;
 /// Represents network addresses.
-pub use addresses::{Address, ProxyAddress};
+pub use addresses::{Address, ChainSpec, ProxyAddress, ScopePolicy};
+/// Tracing values propagated across the links of a chain.
+pub use baggage::Baggage;
+/// Fair sharing of a bandwidth limit between the tunnels of a server.
+pub use bandwidth::{BandwidthLimit, TunnelRate};
+/// A limit on the memory that the handshakes of a server buffer.
+pub use budget::MemoryBudget;
+/// Destinations the clients connect to without the proxy.
+pub use bypass::{BypassList, BypassRule, Route};
 /// Manages user credentials.
-pub use credentials::Credentials;
+pub use credentials::{CredentialSource, Credentials, DeferredCredentials, PasswordVerifier};
+/// Keeps the clients from connecting to internal destinations.
+pub use guard::DestinationGuard;
+/// Errors that can be distinguished by callers.
+pub use errors::{NegotiationError, SocksError, UnknownValue};
+/// Health checks of the proxies the clients connect through.
+pub use health::{HealthMonitor, HealthProbe, HealthReport, HealthStatus};
 /// Handles SOCKS protocol.
-pub use interface::SocksHandler;
+pub use interface::{
+    client_from_env, client_from_proxy_addr, client_with_bypass, AsyncStream, SocksClient, SocksHandler,
+};
+/// Serves the connections of a listener with a handler.
+pub use server::{AcceptRate, OverflowPolicy, RateLimitPolicy, ServerStats, SocksServer};
+/// Serves the clients of every SOCKS version on a single port.
+pub use dispatch::ProtocolDispatcher;
+/// Sessions of a listener, to decide on one by one.
+pub use listener::{IncomingRequest, IncomingSession, SocksListener};
+/// Measurements of the handshakes and sessions, for any metrics backend.
+pub use metrics::{InMemoryMetrics, Metrics, NoopMetrics};
+/// Copies of the bytes relayed in a session.
+pub use mirror::{Mirror, MirrorPolicy};
+/// Authenticates clients against a file of hashed passwords.
+pub use passwd::{HashScheme, PasswordFileAuthenticator};
+/// Decides which sessions the handlers allow.
+pub use policy::{Policy, Rule};
+/// Where the handlers resolve domain name destinations.
+pub use resolver::ResolutionPolicy;
+/// Retrying the connects of the clients.
+pub use retry::{RetryError, RetryPolicy};
+/// Identifies sessions in errors and logs, limits them, and decides on them individually.
+pub use session::{
+    CloseReason, CloseSummary, ConnectionId, LiveTunnel, SessionHooks, SessionInfo, SessionLimits, TunnelInfo,
+};
 /// SOCKS5 client and handler.
 pub use socks5::{Socks5Client, Socks5Handler};
 /// SOCKS6 client and handler.
-pub use socks6::{Socks6Client, Socks6Handler};
-pub use util::{get_original_dst, resolve_addr, try_read_initial_data};
+pub use socks6::{InitialDataDelivery, InitialDataMode, Socks6Client, Socks6Draft, Socks6Handler};
+/// Keeps peers from networks that should never reach a listener from being served.
+pub use source_filter::SourceFilter;
+/// The streams the clients return, over TCP or a Unix socket.
+pub use stream::{ClientTunnelInfo, SocksStream};
+/// Inactivity timeouts for established tunnels.
+pub use timeout::{TimeoutStream, Timeouts};
+/// Per-phase durations of the connects of the clients.
+pub use timings::HandshakeTimings;
+/// Limits of the UDP associations of the handlers.
+pub use udp::{UdpRefusal, UdpRelayConfig};
+pub use util::{
+    enable_original_dst_udp, get_original_dst, get_original_dst_udp, resolve_addr, resolve_all, shutdown_gracefully,
+    try_read_initial_data,
+};
 
 /// Common network address representations
 #[path = "./common/addresses.rs"]
 pub mod addresses;
 
+/// Named values that travel along with SOCKS6 requests through every link of a chain.
+#[path = "./common/baggage.rs"]
+pub mod baggage;
+
+/// A bandwidth limit that the tunnels of a server share fairly.
+#[path = "./common/bandwidth.rs"]
+pub mod bandwidth;
+
+/// The memory budget of the buffers of the handshakes of a server.
+#[path = "./common/budget.rs"]
+pub mod budget;
+
+/// Destinations that the clients connect to directly instead of through the proxy, like curl's `NO_PROXY`.
+#[path = "./common/bypass.rs"]
+pub mod bypass;
+
 /// SOCKS protocol Constants used across the crate.
 #[path = "./common/constants.rs"]
 pub mod constants;
@@ -46,17 +115,116 @@ pub mod constants;
 #[path = "./common/credentials.rs"]
 pub mod credentials;
 
+/// Outbound connection establishment shared by the clients and handlers.
+#[path = "./common/dialer.rs"]
+pub mod dialer;
+
+/// Hands connections to the handler of the SOCKS version their first byte names.
+#[path = "./common/dispatch.rs"]
+pub mod dispatch;
+
+/// Errors specific to the SOCKS clients and handlers.
+#[path = "./common/errors.rs"]
+pub mod errors;
+
+/// Guards against destinations in internal networks, e.g., to prevent server-side request forgery.
+#[path = "./common/guard.rs"]
+pub mod guard;
+
+/// Health checks of proxies, and monitoring them in the background.
+#[path = "./common/health.rs"]
+pub mod health;
+
 /// Main interface for handling SOCKS.
 #[path = "./common/interface.rs"]
 pub mod interface;
 
+/// A stream of the sessions of a listener whose handshake completed, for callers that decide on each.
+#[path = "./common/listener.rs"]
+pub mod listener;
+
+/// Backend-agnostic metrics of the clients, handlers, and servers.
+#[path = "./common/metrics.rs"]
+pub mod metrics;
+
+/// Capturing the bytes relayed in sessions.
+#[path = "./common/mirror.rs"]
+pub mod mirror;
+
+/// Password files with hashed credentials, like those of htpasswd.
+#[path = "./common/passwd.rs"]
+pub mod passwd;
+
+/// Access policies for the handlers, replaceable at runtime.
+#[path = "./common/policy.rs"]
+pub mod policy;
+
+/// HTTP CONNECT implementations.
+pub mod http;
+
 /// SOCKS5-specific implementations.
 pub mod socks5;
 
 /// SOCKS6-specific implementations.
 pub mod socks6;
 
+/// Retry policies for the connects of the clients.
+#[path = "./common/retry.rs"]
+pub mod retry;
+
+/// Domain name resolution and caching.
+#[path = "./common/resolver.rs"]
+pub mod resolver;
+
+/// Scripted SOCKS servers and an in-memory harness for testing, enabled by the `test-util` feature.
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+
+/// Accept loop with bounded admission of sessions.
+#[path = "./common/server.rs"]
+pub mod server;
+
+/// Connection IDs for correlating the logs and errors of a session, and hooks into sessions.
+#[path = "./common/session.rs"]
+pub mod session;
+
+/// Allow and deny lists of the source addresses of the peers of a listener.
+#[path = "./common/source_filter.rs"]
+pub mod source_filter;
+
+/// Streams of the clients, over the transport that the proxy is reached with.
+#[path = "./common/stream.rs"]
+pub mod stream;
+
+/// Read and write inactivity timeouts for streams.
+#[path = "./common/timeout.rs"]
+pub mod timeout;
+
+/// How long the phases of the connects of the clients took.
+#[path = "./common/timings.rs"]
+pub mod timings;
+
+/// TLS transport to proxies and for servers, with client certificates, enabled by the `tls` feature.
+#[cfg(feature = "tls")]
+#[path = "./common/tls.rs"]
+pub mod tls;
+
+/// Transparent proxying on Linux, with the iptables rules it needs, enabled by the `transparent` feature.
+#[cfg(all(feature = "transparent", target_os = "linux"))]
+#[path = "./common/transparent.rs"]
+pub mod transparent;
+
+/// UDP relaying for the associations of the handlers, and its limits.
+#[path = "./common/udp.rs"]
+pub mod udp;
+
 /// Utility functions and helpers.
 #[path = "./common/util.rs"]
 pub mod util;
 
+pub mod wire;
+
+/// WebSocket transport for proxies behind a WebSocket ingress, enabled by the `websocket` feature.
+#[cfg(feature = "websocket")]
+#[path = "./common/websocket.rs"]
+pub mod websocket;
@@ -8,18 +8,21 @@
 #[macro_use]
 extern crate human_panic;
 
-use std::{convert::TryInto, sync::Arc};
+use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Result;
 use clap::Parser;
 use dotenv::dotenv;
-use itertools::Itertools;
 use log::LevelFilter;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Semaphore;
-use tokio::time::Instant;
+use tokio::net::TcpListener;
 
-use socksx::{self, Socks5Handler, Socks6Handler, SocksHandler};
+use socksx::{
+    self, AcceptRate, BandwidthLimit, ChainSpec, DestinationGuard, OverflowPolicy, ProxyAddress, RateLimitPolicy,
+    SessionLimits, Socks5Handler, Socks6Handler, SocksHandler, SocksServer, SourceFilter, UdpRelayConfig,
+};
+use socksx::addresses::IpNetwork;
+use socksx::dialer::{AddressFamilyPreference, Keepalive};
 
 // Alias for SOCKS handler with Arc and Sync/Send trait bounds
 type Handler = Arc<dyn SocksHandler + Sync + Send>;
@@ -28,9 +31,73 @@ type Handler = Arc<dyn SocksHandler + Sync + Send>;
 #[derive(Parser)]
 #[clap(version = env!("CARGO_PKG_VERSION"))]
 struct Args {
-    /// Entry in the proxy chain, the order is preserved
+    /// Entry in the proxy chain, or a comma-separated list of entries, the order is preserved
     #[clap(short, long, env = "CHAIN")]
-    chain: Vec<String>,
+    chain: Vec<ChainSpec>,
+
+    /// Address family policy for outbound connects (dns-order, prefer-ipv4, prefer-ipv6, ipv4-only, ipv6-only)
+    #[clap(short, long, env = "FAMILY", default_value = "dns-order")]
+    family: AddressFamilyPreference,
+
+    /// Idle time in seconds before keepalive probes are sent on both legs of a tunnel (disabled if omitted)
+    #[clap(short, long, env = "KEEPALIVE")]
+    keepalive: Option<u64>,
+
+    /// Seconds an outbound connect may take, including resolution and every attempt
+    #[clap(long, env = "CONNECT_TIMEOUT", default_value = "15")]
+    connect_timeout: u64,
+
+    /// Seconds a tunnel may last before it is closed (unlimited if omitted)
+    #[clap(long, env = "MAX_LIFETIME")]
+    max_lifetime: Option<u64>,
+
+    /// Bytes a tunnel may relay in both directions combined before it is closed (unlimited if omitted)
+    #[clap(long, env = "MAX_BYTES")]
+    max_bytes: Option<u64>,
+
+    /// Allows destinations that are Unix socket paths, a socksx extension to the address types
+    #[clap(long, env = "UNIX_DESTINATIONS")]
+    unix_destinations: bool,
+
+    /// Refuses destinations on the proxy's host: loopback, link-local, cloud metadata, and listening addresses
+    #[clap(long, env = "SSRF_PROTECTION")]
+    ssrf_protection: bool,
+
+    /// Network that destinations are refused in, or a comma-separated list of networks
+    #[clap(long, env = "BLOCKED_NETWORKS", value_delimiter = ',')]
+    blocked_networks: Vec<IpNetwork>,
+
+    /// Network that destinations are allowed in despite the blocked ones, e.g., of a local next proxy
+    #[clap(long, env = "ALLOWED_NETWORKS", value_delimiter = ',')]
+    allowed_networks: Vec<IpNetwork>,
+
+    /// Relays the datagrams of UDP ASSOCIATE requests (SOCKS5 only)
+    #[clap(long, env = "UDP")]
+    udp: bool,
+
+    /// Seconds a UDP association may relay no datagrams before it is torn down
+    #[clap(long, env = "UDP_IDLE_TIMEOUT", default_value = "120")]
+    udp_idle_timeout: u64,
+
+    /// Bytes of the largest datagram relayed, including its SOCKS header
+    #[clap(long, env = "UDP_MAX_DATAGRAM", default_value = "65507")]
+    udp_max_datagram: usize,
+
+    /// UDP associations open at once per client IP address (unlimited if omitted)
+    #[clap(long, env = "UDP_MAX_PER_CLIENT")]
+    udp_max_per_client: Option<usize>,
+
+    /// UDP associations open at once (unlimited if omitted)
+    #[clap(long, env = "UDP_MAX_ASSOCIATIONS")]
+    udp_max_associations: Option<usize>,
+
+    /// Refuses UDP associations whose client leaves the address it sends from unspecified, e.g., 0.0.0.0:0
+    #[clap(long, env = "UDP_REQUIRE_CLIENT_ADDRESS")]
+    udp_require_client_address: bool,
+
+    /// Firewall mark set on outbound connections, e.g., to exempt them from transparent proxy redirects
+    #[clap(long, env = "MARK")]
+    mark: Option<u32>,
 
     /// Prints debug information
     #[clap(short, long, env = "DEBUG")]
@@ -44,6 +111,38 @@ struct Args {
     #[clap(short, long, env = "LIMIT", default_value = "256")]
     limit: usize,
 
+    /// Connections that may wait for a free slot once the limit is reached
+    #[clap(short, long, env = "QUEUE", default_value = "64")]
+    queue: usize,
+
+    /// What to do when the limit is reached and the queue is full (backpressure, shed-oldest)
+    #[clap(short, long, env = "OVERFLOW", default_value = "backpressure")]
+    overflow: OverflowPolicy,
+
+    /// Sessions started per second at most, to spread out the handshakes of clients that connect at once
+    #[clap(long, env = "ACCEPT_RATE")]
+    accept_rate: Option<f64>,
+
+    /// Sessions that may start at once within the accept rate (defaults to the rate)
+    #[clap(long, env = "ACCEPT_BURST")]
+    accept_burst: Option<u32>,
+
+    /// What to do with connections beyond the accept rate (delay, close)
+    #[clap(long, env = "ACCEPT_RATE_POLICY", default_value = "delay")]
+    accept_rate_policy: RateLimitPolicy,
+
+    /// Network that clients may connect from, or a comma-separated list of networks (any network if omitted)
+    #[clap(long, env = "ALLOWED_SOURCES", value_delimiter = ',')]
+    allowed_sources: Vec<IpNetwork>,
+
+    /// Network whose clients are disconnected as soon as they connect, even if an allowed network contains them
+    #[clap(long, env = "DENIED_SOURCES", value_delimiter = ',')]
+    denied_sources: Vec<IpNetwork>,
+
+    /// Bytes per second that all tunnels relay together at most, shared fairly between them (unlimited if omitted)
+    #[clap(long, env = "BANDWIDTH_LIMIT")]
+    bandwidth_limit: Option<u64>,
+
     /// Port for the SOCKS server
     #[clap(short, long, env = "PORT", default_value = "1080")]
     port: u16,
@@ -76,68 +175,91 @@ async fn main() -> Result<()> {
     // TODO: validate host
 
     // Convert and collect chain arguments
-    let chain = args.chain.iter().cloned().map(|c| c.try_into()).try_collect()?;
-
-    // Create a semaphore for connection limiting
-    let semaphore = if args.limit > 0 {
-        Some(Arc::new(Semaphore::new(args.limit)))
-    } else {
-        None
-    };
+    let chain: Vec<ProxyAddress> = args.chain.into_iter().flat_map(Vec::from).collect();
 
     // Bind TCP listener to the specified host and port
     let listener = TcpListener::bind(format!("{}:{}", args.host, args.port)).await?;
     // Determine the appropriate SOCKS handler based on the specified version and restricting them to 5 and 6
+    let keepalive = args.keepalive.map(|idle| Keepalive::new(Duration::from_secs(idle)));
+    let connect_timeout = Duration::from_secs(args.connect_timeout);
+    let mut session_limits = SessionLimits::none();
+    if let Some(lifetime) = args.max_lifetime {
+        session_limits = session_limits.with_lifetime(Duration::from_secs(lifetime));
+    }
+    if let Some(bytes) = args.max_bytes {
+        session_limits = session_limits.with_byte_cap(bytes);
+    }
+    let mut guard = DestinationGuard::permissive();
+    if args.ssrf_protection {
+        guard = DestinationGuard::host_local().with_listener(listener.local_addr()?)?;
+    }
+    guard = args.blocked_networks.into_iter().fold(guard, DestinationGuard::with_blocked);
+    guard = args.allowed_networks.into_iter().fold(guard, DestinationGuard::with_allowed);
+    let mut udp_relay = UdpRelayConfig::new()
+        .with_idle_timeout(Duration::from_secs(args.udp_idle_timeout))
+        .with_max_datagram_size(args.udp_max_datagram)
+        .with_unspecified_client(!args.udp_require_client_address);
+    if let Some(max) = args.udp_max_per_client {
+        udp_relay = udp_relay.with_max_associations_per_client(max);
+    }
+    if let Some(max) = args.udp_max_associations {
+        udp_relay = udp_relay.with_max_associations(max);
+    }
     let handler: Handler = match args.socks {
-        5 => Arc::new(Socks5Handler::new(chain)),
-        6 => Arc::new(Socks6Handler::new(chain)),
+        5 => {
+            let handler = Socks5Handler::new(chain)
+                .with_family_preference(args.family)
+                .with_connect_timeout(connect_timeout)
+                .with_session_limits(session_limits)
+                .with_unix_destinations(args.unix_destinations)
+                .with_destination_guard(guard);
+            let handler = if args.udp { handler.with_udp_relay(udp_relay) } else { handler };
+            let handler = match keepalive {
+                Some(keepalive) => handler.with_keepalive(keepalive),
+                None => handler,
+            };
+            match args.mark {
+                Some(mark) => Arc::new(handler.with_mark(mark)),
+                None => Arc::new(handler),
+            }
+        }
+        6 => {
+            let handler = Socks6Handler::new(chain)
+                .with_family_preference(args.family)
+                .with_connect_timeout(connect_timeout)
+                .with_session_limits(session_limits)
+                .with_unix_destinations(args.unix_destinations)
+                .with_destination_guard(guard);
+            let handler = match keepalive {
+                Some(keepalive) => handler.with_keepalive(keepalive),
+                None => handler,
+            };
+            match args.mark {
+                Some(mark) => Arc::new(handler.with_mark(mark)),
+                None => Arc::new(handler),
+            }
+        }
         _ => unreachable!(),
     };
 
-    // Main event loop for accepting incoming connections
-    loop {
-        let (incoming, _) = listener.accept().await?;
-
-        let handler = Arc::clone(&handler);
-        let semaphore = semaphore.clone();
-
-        tokio::spawn(process(incoming, handler, semaphore));
+    // Accept incoming connections, with a limit on concurrent sessions and a bounded queue beyond it
+    let mut server = SocksServer::new(listener, handler)
+        .with_limit(args.limit)
+        .with_queue(args.queue)
+        .with_overflow_policy(args.overflow);
+    if let Some(keepalive) = keepalive {
+        server = server.with_keepalive(keepalive);
     }
-}
-
-/// Asynchronously processes an incoming connection
-///
-/// # Parameters
-///
-/// - `incoming`: The incoming `TcpStream`.
-/// - `handler`: The SOCKS handler.
-/// - `semaphore`: An optional semaphore for limiting concurrent connections.
-///
-/// # Returns
-///
-/// Returns a `Result` indicating the success or failure of the operation.
-async fn process(
-    incoming: TcpStream,
-    handler: Handler,
-    semaphore: Option<Arc<Semaphore>>,
-) -> Result<()> {
-    let mut incoming = incoming;
-    let start_time = Instant::now();
-
-    // Handle the incoming connection based on the availability of permits
-    if let Some(semaphore) = semaphore {
-        let permit = semaphore.try_acquire();
-        if permit.is_ok() {
-            handler.accept_request(&mut incoming).await?;
-        } else {
-            handler.refuse_request(&mut incoming).await?;
-        }
-    } else {
-        handler.accept_request(&mut incoming).await?;
+    if let Some(rate) = args.accept_rate {
+        let burst = args.accept_burst.unwrap_or(rate.ceil() as u32);
+        server = server.with_accept_rate(AcceptRate::new(rate, burst)?.with_policy(args.accept_rate_policy));
+    }
+    let source_filter = args.allowed_sources.into_iter().fold(SourceFilter::new(), SourceFilter::with_allowed);
+    let source_filter = args.denied_sources.into_iter().fold(source_filter, SourceFilter::with_denied);
+    server = server.with_source_filter(source_filter);
+    if let Some(bytes_per_second) = args.bandwidth_limit {
+        server = server.with_bandwidth_limit(BandwidthLimit::new(bytes_per_second)?);
     }
 
-    // Log the time taken to process the request
-    println!("{}ms", Instant::now().saturating_duration_since(start_time).as_millis());
-
-    Ok(())
+    server.run().await
 }
@@ -0,0 +1,307 @@
+// GSSAPI authentication (RFC 1961) for `Socks5Handler`, with the mechanism left to a `GssapiAuthenticator`.
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::BytesMut;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+use crate::constants::*;
+use crate::{wire, SocksError};
+
+/// How many tokens of the client the handler accepts before giving up on establishing a security context.
+pub const MAX_GSSAPI_ROUNDS: usize = 8;
+
+/// What a `GssapiContext` makes of a token of the client.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum GssapiStep {
+    /// The context needs another token of the client, which answers the given token.
+    Continue(Vec<u8>),
+    /// The context is established, and the client is authenticated as the principal. The final token, if any, is
+    /// sent to the client.
+    Established {
+        /// The final token of the handler, if the mechanism produced one.
+        token: Option<Vec<u8>>,
+        /// The name of the authenticated client, e.g., `alice@EXAMPLE.COM`.
+        principal: String,
+    },
+}
+
+/// The acceptor side of a GSSAPI security context, e.g., one backed by `gss_accept_sec_context`.
+///
+/// A context is created for each client that selects GSSAPI, and dropped once the sub-negotiation ends.
+#[async_trait]
+pub trait GssapiContext {
+    /// Accepts a token of the client, failing if the client can't be authenticated.
+    async fn accept(
+        &mut self,
+        token: &[u8],
+    ) -> Result<GssapiStep>;
+
+    /// Protects a message with the established context (`gss_wrap`).
+    fn wrap(
+        &mut self,
+        message: &[u8],
+    ) -> Result<Vec<u8>>;
+
+    /// Verifies and extracts a message protected by the client (`gss_unwrap`).
+    fn unwrap(
+        &mut self,
+        token: &[u8],
+    ) -> Result<Vec<u8>>;
+}
+
+/// Creates the security contexts with which `Socks5Handler` authenticates clients that select GSSAPI, so that the
+/// mechanism, e.g., Kerberos through a system GSSAPI library, is up to the caller.
+pub trait GssapiAuthenticator {
+    /// Creates a context for a new client.
+    fn new_context(&self) -> Result<Box<dyn GssapiContext + Send>>;
+}
+
+/// Runs the GSSAPI sub-negotiation (RFC 1961) with a client that selected GSSAPI, and returns the principal it
+/// authenticated as.
+///
+/// The handler always selects no per-message protection, so the tunnel isn't encapsulated. A client that fails
+/// the exchange gets an abort message, and the sub-negotiation fails with `SocksError::AuthenticationFailed`.
+pub(crate) async fn accept_gssapi<S>(
+    stream: &mut S,
+    authenticator: &(dyn GssapiAuthenticator + Send + Sync),
+    scratch: &mut BytesMut,
+) -> Result<String>
+where
+    S: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    match exchange(stream, authenticator, scratch).await {
+        Ok(principal) => Ok(principal),
+        Err(e) => {
+            debug!("GSSAPI authentication failed: {}", e);
+            let mut abort = vec![];
+            wire::encode_socks5_gssapi_message(SOCKS_GSSAPI_ABORT, &[], &mut abort)?;
+            let _ = stream.write_all(&abort).await;
+
+            bail!(SocksError::AuthenticationFailed(None))
+        }
+    }
+}
+
+// Establishes the security context, and negotiates the protection level, returning the principal.
+async fn exchange<S>(
+    stream: &mut S,
+    authenticator: &(dyn GssapiAuthenticator + Send + Sync),
+    scratch: &mut BytesMut,
+) -> Result<String>
+where
+    S: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let mut context = authenticator.new_context()?;
+
+    let mut principal = None;
+    for _ in 0..MAX_GSSAPI_ROUNDS {
+        let token = read_token(stream, scratch, SOCKS_GSSAPI_AUTHENTICATION).await?;
+        let reply = match context.accept(&token).await? {
+            GssapiStep::Continue(reply) => Some(reply),
+            GssapiStep::Established { token, principal: name } => {
+                principal = Some(name);
+                token
+            }
+        };
+
+        if let Some(reply) = reply {
+            write_token(stream, SOCKS_GSSAPI_AUTHENTICATION, &reply).await?;
+        }
+        if principal.is_some() {
+            break;
+        }
+    }
+    let principal = principal.ok_or_else(|| anyhow!("No security context after {} tokens", MAX_GSSAPI_ROUNDS))?;
+
+    let token = read_token(stream, scratch, SOCKS_GSSAPI_PROTECTION).await?;
+    let requested = context.unwrap(&token)?;
+    debug!("Client {} requested protection level {:?}, selecting none.", principal, requested);
+
+    let selected = context.wrap(&[SOCKS_GSSAPI_PROTECTION_NONE])?;
+    write_token(stream, SOCKS_GSSAPI_PROTECTION, &selected).await?;
+
+    Ok(principal)
+}
+
+// Reads a message of the client, failing unless it has the expected type.
+async fn read_token<S>(
+    stream: &mut S,
+    scratch: &mut BytesMut,
+    expected: u8,
+) -> Result<Vec<u8>>
+where
+    S: AsyncRead + Unpin + ?Sized,
+{
+    let (kind, token) = wire::read_message(stream, scratch, wire::parse_socks5_gssapi_message).await?;
+    ensure!(kind != SOCKS_GSSAPI_ABORT, "Client aborted the GSSAPI sub-negotiation");
+    ensure!(kind == expected, "Unexpected GSSAPI message type: {:#04x}", kind);
+
+    Ok(token)
+}
+
+// Writes a message of the given type to the client.
+async fn write_token<S>(
+    stream: &mut S,
+    kind: u8,
+    token: &[u8],
+) -> Result<()>
+where
+    S: AsyncWrite + Unpin + ?Sized,
+{
+    let mut message = vec![];
+    wire::encode_socks5_gssapi_message(kind, token, &mut message)?;
+    stream.write_all(&message).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::policy::{Policy, Rule};
+    use crate::socks5::Socks5Handler;
+
+    const PRINCIPAL: &str = "alice@EXAMPLE.COM";
+
+    // Authenticator whose contexts expect canned tokens, and answer with canned tokens, instead of talking to a KDC.
+    // Messages are "wrapped" by prefixing them with `W:`.
+    struct ScriptedAuthenticator;
+
+    struct ScriptedContext {
+        round: usize,
+    }
+
+    impl GssapiAuthenticator for ScriptedAuthenticator {
+        fn new_context(&self) -> Result<Box<dyn GssapiContext + Send>> {
+            Ok(Box::new(ScriptedContext { round: 0 }))
+        }
+    }
+
+    #[async_trait]
+    impl GssapiContext for ScriptedContext {
+        async fn accept(
+            &mut self,
+            token: &[u8],
+        ) -> Result<GssapiStep> {
+            self.round += 1;
+            match (self.round, token) {
+                (1, b"client-1") => Ok(GssapiStep::Continue(b"server-1".to_vec())),
+                (2, b"client-2") => Ok(GssapiStep::Established {
+                    token: Some(b"server-2".to_vec()),
+                    principal: PRINCIPAL.to_string(),
+                }),
+                _ => bail!("Defective token in round {}", self.round),
+            }
+        }
+
+        fn wrap(
+            &mut self,
+            message: &[u8],
+        ) -> Result<Vec<u8>> {
+            Ok([b"W:", message].concat())
+        }
+
+        fn unwrap(
+            &mut self,
+            token: &[u8],
+        ) -> Result<Vec<u8>> {
+            token.strip_prefix(b"W:").map(<[u8]>::to_vec).ok_or_else(|| anyhow!("Bad integrity"))
+        }
+    }
+
+    // Returns the bytes of a GSSAPI message.
+    fn message(
+        kind: u8,
+        token: &[u8],
+    ) -> Vec<u8> {
+        let mut bytes = vec![];
+        wire::encode_socks5_gssapi_message(kind, token, &mut bytes).unwrap();
+        bytes
+    }
+
+    const REQUEST: [u8; 10] = [SOCKS_VER_5, SOCKS_CMD_CONNECT, SOCKS_RSV, SOCKS_ATYP_IPV4, 192, 0, 2, 1, 0, 80];
+
+    // Tests the token exchange and protection level negotiation of a client that authenticates, and that its
+    // principal is the identity the policy decides on.
+    #[tokio::test]
+    async fn test_gssapi_accept() -> Result<()> {
+        let handler = Socks5Handler::default()
+            .with_gssapi(Arc::new(ScriptedAuthenticator))
+            .with_policy(Policy::deny_all().with_rule(Rule::allow().for_identity(PRINCIPAL)));
+
+        let handshake = [
+            vec![SOCKS_VER_5, 0x02, SOCKS_AUTH_NOT_REQUIRED, SOCKS_AUTH_GSSAPI],
+            message(SOCKS_GSSAPI_AUTHENTICATION, b"client-1"),
+            message(SOCKS_GSSAPI_AUTHENTICATION, b"client-2"),
+            message(SOCKS_GSSAPI_PROTECTION, b"W:\x01"),
+            REQUEST.to_vec(),
+        ]
+        .concat();
+        let (mut client, mut source) = tokio::io::duplex(4096);
+        client.write_all(&handshake).await?;
+        let (session, request) = handler.accept(&mut source).await?;
+        assert_eq!(request.destination, crate::Address::new("192.0.2.1", 80));
+        drop(session);
+        drop(source);
+
+        let expected = [
+            vec![SOCKS_VER_5, SOCKS_AUTH_GSSAPI],
+            message(SOCKS_GSSAPI_AUTHENTICATION, b"server-1"),
+            message(SOCKS_GSSAPI_AUTHENTICATION, b"server-2"),
+            message(SOCKS_GSSAPI_PROTECTION, &[b'W', b':', SOCKS_GSSAPI_PROTECTION_NONE]),
+        ]
+        .concat();
+        let mut replies = vec![];
+        client.read_to_end(&mut replies).await?;
+        assert_eq!(replies, expected);
+
+        Ok(())
+    }
+
+    // Tests that clients with a defective token, a protection level message that fails the integrity check, or that
+    // abort, get an abort message and are rejected before their request is read.
+    #[tokio::test]
+    async fn test_gssapi_reject() -> Result<()> {
+        let handler = Socks5Handler::default().with_gssapi(Arc::new(ScriptedAuthenticator));
+        let greeting = vec![SOCKS_VER_5, 0x01, SOCKS_AUTH_GSSAPI];
+        let cases = vec![
+            (vec![message(SOCKS_GSSAPI_AUTHENTICATION, b"forged")], vec![]),
+            (
+                vec![
+                    message(SOCKS_GSSAPI_AUTHENTICATION, b"client-1"),
+                    message(SOCKS_GSSAPI_AUTHENTICATION, b"client-2"),
+                    message(SOCKS_GSSAPI_PROTECTION, b"\x01"),
+                ],
+                vec![
+                    message(SOCKS_GSSAPI_AUTHENTICATION, b"server-1"),
+                    message(SOCKS_GSSAPI_AUTHENTICATION, b"server-2"),
+                ],
+            ),
+            (
+                vec![message(SOCKS_GSSAPI_AUTHENTICATION, b"client-1"), message(SOCKS_GSSAPI_ABORT, &[])],
+                vec![message(SOCKS_GSSAPI_AUTHENTICATION, b"server-1")],
+            ),
+        ];
+
+        for (messages, replies) in cases {
+            let handshake = [vec![greeting.clone()], messages, vec![REQUEST.to_vec()]].concat().concat();
+            let (mut client, mut source) = tokio::io::duplex(4096);
+            client.write_all(&handshake).await?;
+            let error = handler.accept(&mut source).await.err().unwrap();
+            assert!(matches!(error.downcast_ref(), Some(SocksError::AuthenticationFailed(None))));
+            drop(source);
+
+            let selection = vec![SOCKS_VER_5, SOCKS_AUTH_GSSAPI];
+            let expected = [vec![selection], replies, vec![message(SOCKS_GSSAPI_ABORT, &[])]];
+            let mut received = vec![];
+            client.read_to_end(&mut received).await?;
+            assert_eq!(received, expected.concat().concat());
+        }
+
+        Ok(())
+    }
+}
@@ -1,19 +1,25 @@
 use anyhow::Result;
+use bytes::BytesMut;
 use num_traits::FromPrimitive;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 
 pub use s5_client::Socks5Client;
-pub use s5_handler::Socks5Handler;
+pub use s5_handler::{PendingSession, Socks5Handler, DEFAULT_METHODS_TIMEOUT};
 
-use crate::addresses::{self, Address};
+use crate::addresses::Address;
 use crate::constants::*;
+use crate::{errors, wire, SocksError};
 
+/// GSSAPI authentication (RFC 1961) for `Socks5Handler`, enabled by the `gssapi` feature.
+#[cfg(feature = "gssapi")]
+pub mod gssapi;
 mod s5_client;
 mod s5_handler;
 
 /// Represents the different commands for SOCKS5 protocol.
 #[repr(u8)]
 #[derive(Clone, Debug, FromPrimitive, PartialEq)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub enum Socks5Command {
     Connect = 0x01,
     Bind = 0x02,
@@ -21,7 +27,8 @@ pub enum Socks5Command {
 }
 
 /// Represents a SOCKS5 request.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub struct Socks5Request {
     pub command: Socks5Command,
     pub destination: Address,
@@ -54,16 +61,43 @@ impl Socks5Request {
     ///
     /// A vector of bytes representing the request.
     pub fn into_socks_bytes(self) -> Vec<u8> {
-        let mut data = vec![SOCKS_VER_5, SOCKS_CMD_CONNECT, SOCKS_RSV];
-        data.extend(self.destination.as_socks_bytes());
+        let mut data = Vec::with_capacity(3 + self.destination.encoded_len());
+        wire::encode_socks5_request(&self, &mut data);
 
         data
     }
 }
 
+/// Policy for the version byte of the username/password sub-negotiation (RFC 1929).
+///
+/// The RFC mandates 0x01, but several deployed servers answer with, and some clients send, the SOCKS version
+/// 0x05 instead.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum AuthVersionPolicy {
+    /// Accept either 0x01 or 0x05, like curl does.
+    #[default]
+    Lenient,
+    /// Only accept 0x01.
+    Strict,
+}
+
+impl AuthVersionPolicy {
+    /// Returns whether the policy accepts the given version byte.
+    pub fn accepts(
+        &self,
+        version: u8,
+    ) -> bool {
+        match self {
+            AuthVersionPolicy::Lenient => version == SOCKS_AUTH_VER || version == SOCKS_VER_5,
+            AuthVersionPolicy::Strict => version == SOCKS_AUTH_VER,
+        }
+    }
+}
+
 /// Represents different reply codes for SOCKS5 protocol.
 #[repr(u8)]
 #[derive(Clone, Debug, FromPrimitive, PartialEq)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub enum Socks5Reply {
     Success = 0x00,
     GeneralFailure = 0x01,
@@ -77,6 +111,19 @@ pub enum Socks5Reply {
     ConnectionAttemptTimeOut = 0x09,
 }
 
+impl Socks5Reply {
+    /// Determines the reply that best describes why an operation failed.
+    ///
+    /// RFC 1928 has no reply for a connect that timed out, so a timeout is reported as HostUnreachable, which
+    /// clients treat the same way: the destination couldn't be reached.
+    pub fn from_error(error: &anyhow::Error) -> Self {
+        match errors::reply_code(error) {
+            SOCKS_REP_CONNECTION_ATTEMPT_TIMEOUT => Socks5Reply::HostUnreachable,
+            reply_code => Self::from_u8(reply_code).unwrap_or(Socks5Reply::GeneralFailure),
+        }
+    }
+}
+
 /// Writes a SOCKS5 reply to the provided stream.
 ///
 /// # Arguments
@@ -92,22 +139,12 @@ pub async fn write_reply<S>(
     reply: Socks5Reply,
 ) -> Result<()>
     where
-        S: AsyncWrite + Unpin,
+        S: AsyncWrite + Unpin + ?Sized,
 {
-    let reply = [
-        SOCKS_VER_5,
-        reply as u8,
-        SOCKS_RSV,
-        SOCKS_ATYP_IPV4,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-    ];
-
-    stream.write(&reply).await?;
+    let mut bytes = Vec::with_capacity(10);
+    wire::encode_socks5_reply(reply as u8, &Address::new("0.0.0.0", 0), &mut bytes);
+
+    stream.write_all(&bytes).await?;
 
     Ok(())
 }
@@ -123,19 +160,22 @@ pub async fn write_reply<S>(
 /// A `Result` containing the address associated with the reply if successful, or an error if the reply indicates failure.
 pub async fn read_reply<S>(stream: &mut S) -> Result<Address>
     where
-        S: AsyncRead + Unpin,
+        S: AsyncRead + Unpin + ?Sized,
 {
-    let mut operation_reply = [0; 3];
-    stream.read_exact(&mut operation_reply).await?;
-
-    let reply_code = operation_reply[1];
-    ensure!(
-        reply_code == SOCKS_REP_SUCCEEDED,
-        "CONNECT operation failed: {}",
-        reply_code
-    );
+    read_reply_observed(stream, |_| ()).await
+}
 
-    let binding = addresses::read_address(stream).await?;
+// Reads a SOCKS5 reply like `read_reply`, passing its reply code to `observe` before checking it.
+pub(crate) async fn read_reply_observed<S>(
+    stream: &mut S,
+    observe: impl FnOnce(u8),
+) -> Result<Address>
+    where
+        S: AsyncRead + Unpin + ?Sized,
+{
+    let (reply_code, binding) = wire::read_message(stream, &mut BytesMut::new(), wire::parse_socks5_reply).await?;
+    observe(reply_code);
+    ensure!(reply_code == SOCKS_REP_SUCCEEDED, SocksError::OperationFailed(reply_code));
 
     Ok(binding)
 }
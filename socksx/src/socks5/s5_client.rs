@@ -1,18 +1,48 @@
 use std::convert::TryInto;
 use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use async_trait::async_trait;
+use bytes::BytesMut;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 
-use crate::{Address, constants::*, Credentials};
-use crate::socks5::{self, Socks5Request};
+use crate::addresses::ScopePolicy;
+use crate::{Address, constants::*, wire, Credentials, ProxyAddress, SocksClient, SocksError};
+use crate::credentials::{CredentialSource, DeferredCredentials};
+use crate::bypass::{self, BypassList, Route};
+use crate::guard::DestinationGuard;
+use crate::health::{self, HealthMonitor, HealthProbe, HealthReport, HealthStatus};
+use crate::dialer::{AddressFamilyPreference, Keepalive, ProxyEndpoint, ReResolution};
+use crate::metrics::{Metrics, NoopMetrics, Recorder};
+use crate::retry::RetryPolicy;
+use crate::session::ConnectionId;
+use crate::stream::{ClientTunnelInfo, SocksStream};
+use crate::timeout::{TimeoutStream, Timeouts};
+use crate::timings::{HandshakeTimings, Stopwatch};
+use crate::socks5::{self, AuthVersionPolicy, Socks5Request};
+#[cfg(feature = "tls")]
+use crate::tls::{self, TlsClient};
 
 /// Represents a SOCKS5 client for connecting to proxy servers.
 #[derive(Clone)]
 pub struct Socks5Client {
-    proxy_addr: SocketAddr,
-    credentials: Option<Credentials>,
+    proxy: ProxyEndpoint,
+    credentials: Option<DeferredCredentials>,
+    family_preference: AddressFamilyPreference,
+    keepalive: Option<Keepalive>,
+    timeouts: Timeouts,
+    auth_version: AuthVersionPolicy,
+    metrics: Arc<dyn Metrics + Send + Sync>,
+    retry: RetryPolicy,
+    bypass: BypassList,
+    guard: DestinationGuard,
+    scope_policy: ScopePolicy,
+    canary: Option<Address>,
+    health: Arc<Mutex<HealthStatus>>,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsClient>,
 }
 
 impl Socks5Client {
@@ -20,7 +50,8 @@ impl Socks5Client {
     ///
     /// # Arguments
     ///
-    /// * `proxy_addr` - The address of the SOCKS5 proxy server.
+    /// * `proxy_addr` - The address of the SOCKS5 proxy server, as `host:port`, or as `unix:/path/to/socket` for a
+    ///   proxy that listens on a Unix socket.
     /// * `credentials` - Optional SOCKS5 authentication credentials.
     ///
     /// # Returns
@@ -30,58 +61,648 @@ impl Socks5Client {
         proxy_addr: A,
         credentials: Option<Credentials>,
     ) -> Result<Self> {
-        let proxy_addr = crate::resolve_addr(proxy_addr).await?;
+        let proxy = ProxyEndpoint::resolve(proxy_addr).await?;
 
         Ok(Socks5Client {
-            proxy_addr,
-            credentials,
+            proxy,
+            credentials: credentials.map(DeferredCredentials::from),
+            family_preference: AddressFamilyPreference::default(),
+            keepalive: None,
+            timeouts: Timeouts::default(),
+            auth_version: AuthVersionPolicy::default(),
+            metrics: Arc::new(NoopMetrics),
+            retry: RetryPolicy::default(),
+            bypass: BypassList::new(),
+            guard: DestinationGuard::permissive(),
+            scope_policy: ScopePolicy::default(),
+            canary: None,
+            health: Arc::new(Mutex::new(HealthStatus::Unknown)),
+            #[cfg(feature = "tls")]
+            tls: None,
+        })
+    }
+
+    /// Creates a new `Socks5Client` from a proxy address, using the credentials it carries.
+    ///
+    /// # Arguments
+    ///
+    /// * `proxy_addr` - The address of the SOCKS5 proxy server.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the new `Socks5Client` instance, or an error if the address isn't of a SOCKS5 proxy.
+    pub async fn from_proxy_addr(proxy_addr: ProxyAddress) -> Result<Self> {
+        proxy_addr.ensure_version(SOCKS_VER_5)?;
+
+        Self::new(proxy_addr.host_port(), proxy_addr.credentials).await
+    }
+
+    /// Sets where the credentials come from, replacing those the client was created with. They're read on the
+    /// first connect, which fails if they can't be read, and kept from then on.
+    pub fn with_credential_source(
+        mut self,
+        source: CredentialSource,
+    ) -> Self {
+        self.credentials = Some(DeferredCredentials::new(source));
+        self
+    }
+
+    /// Sets up TLS for `connect_tls`, which connects to the proxy over TLS, e.g., to authenticate with a client
+    /// certificate.
+    #[cfg(feature = "tls")]
+    pub fn with_tls(
+        mut self,
+        tls: TlsClient,
+    ) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Sets the policy that determines which of the proxy's addresses are used, and in which order.
+    pub fn with_family_preference(
+        mut self,
+        family_preference: AddressFamilyPreference,
+    ) -> Self {
+        self.family_preference = family_preference;
+        self
+    }
+
+    /// Enables TCP keepalive on the connections to the proxy, which carry the tunnels once established.
+    pub fn with_keepalive(
+        mut self,
+        keepalive: Keepalive,
+    ) -> Self {
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    /// Sets the policy that determines when the proxy's hostname is resolved again.
+    ///
+    /// By default, the hostname is only resolved when the client is created.
+    pub fn with_re_resolution(
+        mut self,
+        re_resolution: ReResolution,
+    ) -> Self {
+        self.proxy.set_re_resolution(re_resolution);
+        self
+    }
+
+    /// Sets how long a connection attempt to one of the proxy's addresses has a head start, before the next
+    /// address is tried as well. As the addresses are interleaved by family, the next one is of the other family
+    /// if the proxy has both. Defaults to `CONNECTION_ATTEMPT_DELAY`.
+    pub fn with_proxy_attempt_delay(
+        mut self,
+        delay: Duration,
+    ) -> Self {
+        self.proxy.set_attempt_delay(delay);
+        self
+    }
+
+    /// Sets the firewall mark (SO_MARK) of the sockets the proxy, and destinations that bypass it, are connected
+    /// from. Packet filter rules can match on it, e.g., to keep a transparent proxy's own connections from being
+    /// redirected back to it. Setting the mark requires the CAP_NET_ADMIN capability.
+    pub fn with_mark(
+        mut self,
+        mark: u32,
+    ) -> Self {
+        self.proxy.set_mark(Some(mark));
+        self
+    }
+
+    /// Sets which version bytes are accepted in the proxy's username/password sub-negotiation status.
+    ///
+    /// By default, the client is lenient and accepts 0x05 as well as 0x01.
+    pub fn with_auth_version_policy(
+        mut self,
+        policy: AuthVersionPolicy,
+    ) -> Self {
+        self.auth_version = policy;
+        self
+    }
+
+    /// Sets the metrics that the handshakes and the replies of the proxy are counted in.
+    pub fn with_metrics(
+        mut self,
+        metrics: Arc<dyn Metrics + Send + Sync>,
+    ) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Sets the policy that decides whether `connect` tries again after a failure, each time over a fresh
+    /// connection to the proxy. By default, a single attempt is made.
+    pub fn with_retry_policy(
+        mut self,
+        retry: RetryPolicy,
+    ) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Sets the destinations that `connect` reaches directly, instead of through the proxy.
+    pub fn with_bypass(
+        mut self,
+        bypass: BypassList,
+    ) -> Self {
+        self.bypass = bypass;
+        self
+    }
+
+    /// Sets the guard that decides which destinations `connect` refuses before contacting the proxy, e.g.,
+    /// `DestinationGuard::internal()` to refuse internal addresses. By default, nothing is refused.
+    pub fn with_destination_guard(
+        mut self,
+        guard: DestinationGuard,
+    ) -> Self {
+        self.guard = guard;
+        self
+    }
+
+    /// Sets what `connect` does with the scope ID of a link-local IPv6 destination, which can't be sent to the proxy.
+    /// By default, it's left out with a warning.
+    pub fn with_scope_policy(
+        mut self,
+        scope_policy: ScopePolicy,
+    ) -> Self {
+        self.scope_policy = scope_policy;
+        self
+    }
+
+    /// Sets the read inactivity timeout of the streams returned by `connect_timed`.
+    pub fn with_read_timeout(
+        mut self,
+        timeout: Duration,
+    ) -> Self {
+        self.timeouts.read = Some(timeout);
+        self
+    }
+
+    /// Sets the write inactivity timeout of the streams returned by `connect_timed`.
+    pub fn with_write_timeout(
+        mut self,
+        timeout: Duration,
+    ) -> Self {
+        self.timeouts.write = Some(timeout);
+        self
+    }
+
+    /// Sets the destination that health checks connect to, and close right away. It should be cheap to connect
+    /// to, and always up.
+    pub fn with_health_canary(
+        mut self,
+        canary: Address,
+    ) -> Self {
+        self.canary = Some(canary);
+        self
+    }
+
+    /// Checks that the proxy is alive and authenticates the client, by connecting to the canary destination set
+    /// with `with_health_canary` over a connection of its own, and closing the tunnel right away.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the latency and what was negotiated, or why the check failed.
+    pub async fn health_check(&self) -> Result<HealthReport> {
+        self.check_health(None).await
+    }
+
+    /// Starts checking the health of every address of the proxy in the background, once per interval, like
+    /// `health_check`. The status is available from `health` until the monitor is dropped, and connects skip
+    /// addresses that fail their checks while others pass.
+    pub fn spawn_health_monitor(
+        &self,
+        interval: Duration,
+    ) -> HealthMonitor {
+        let client = self.clone();
+        health::spawn_monitor(interval, self.proxy.clone(), self.health.clone(), move |addr| {
+            let client = client.clone();
+            async move { client.check_health(addr).await }
         })
     }
 
+    /// Returns the health of the proxy, as last determined by a monitor, which clones of the client share.
+    pub fn health(&self) -> HealthStatus {
+        self.health.lock().unwrap().clone()
+    }
+
+    // Checks the health of the proxy at the given address, or at any of its addresses.
+    async fn check_health(
+        &self,
+        addr: Option<SocketAddr>,
+    ) -> Result<HealthReport> {
+        let canary = match &self.canary {
+            Some(canary) => canary.clone(),
+            None => bail!("No canary destination to check the health of the proxy with, see `with_health_canary`."),
+        };
+
+        let id = ConnectionId::generate();
+        let started = Instant::now();
+        let mut stream = match addr {
+            Some(addr) => SocksStream::Tcp(self.proxy.connect_to(addr).await?),
+            None => self.connect_proxy(&mut HandshakeTimings::default()).await?,
+        };
+        let proxy = stream.peer_addr().ok();
+        let mut timings = HandshakeTimings::default();
+        let binding = self.handshake(id, canary, None, &mut stream, &mut timings).await.map_err(|e| id.attach(e))?;
+
+        Ok(HealthReport {
+            latency: started.elapsed(),
+            proxy,
+            probe: HealthProbe::Canary,
+            binding,
+        })
+    }
+
+    /// Returns the proxy address used for the most recent successful connection, if any.
+    ///
+    /// Clients share this between concurrent connections; the address of a particular connection is the
+    /// `peer_addr` of its stream.
+    pub fn proxy_addr(&self) -> Option<SocketAddr> {
+        self.proxy.current_addr()
+    }
+
+    /// Returns the currently known addresses of the proxy.
+    pub fn proxy_addrs(&self) -> Vec<SocketAddr> {
+        self.proxy.addrs()
+    }
+
     /// Establishes a SOCKS5 connection to the specified destination.
     ///
+    /// SOCKS5 cannot carry initial data in the handshake. If `initial_data` is provided, it is written
+    /// through the tunnel once it has been established, before the stream is returned. A failure at
+    /// that stage is reported as `SocksError::InitialDataWrite`.
+    ///
     /// # Arguments
     ///
     /// * `destination` - The target address and port to connect to.
+    /// * `initial_data` - Optional data to deliver to the destination before returning the stream.
     ///
     /// # Returns
     ///
-    /// A `Result` containing a tuple with a `TcpStream` to the destination and the bound address.
+    /// A `Result` containing a tuple with a `SocksStream` to the destination and the bound address.
+    /// Errors carry the connection ID of the last attempt, and how many attempts were made if the retry policy
+    /// allows more than one.
     pub async fn connect<A>(
         &self,
         destination: A,
-    ) -> Result<(TcpStream, Address)>
+        initial_data: Option<Vec<u8>>,
+    ) -> Result<(SocksStream, Address)>
+        where
+            A: TryInto<Address>,
+            A::Error: Into<anyhow::Error>,
+    {
+        let (stream, binding, _) = self.connect_routed(destination, initial_data).await?;
+        Ok((stream, binding))
+    }
+
+    /// Establishes a SOCKS5 connection to the specified destination, or a direct connection if the destination is
+    /// on the bypass list.
+    ///
+    /// # Arguments
+    ///
+    /// * `destination` - The target address and port to connect to.
+    /// * `initial_data` - Optional data to deliver to the destination before returning the stream.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing a tuple with a `SocksStream` to the destination, the bound address, or the local
+    /// address of a direct connection, and whether the proxy was used.
+    pub async fn connect_routed<A>(
+        &self,
+        destination: A,
+        initial_data: Option<Vec<u8>>,
+    ) -> Result<(SocksStream, Address, Route)>
         where
-            A: TryInto<Address, Error = anyhow::Error>,
+            A: TryInto<Address>,
+            A::Error: Into<anyhow::Error>,
     {
-        if let Some(Credentials { username, password }) = &self.credentials {
-            ensure!(username.len() > 255, "Username MUST NOT be larger than 255 bytes.");
-            ensure!(password.len() > 255, "Password MUST NOT be larger than 255 bytes.");
+        let destination = destination.try_into().map_err(Into::into)?;
+        let destination = self.guard.check(&destination).await?;
+        if self.bypass.matches(&destination) {
+            let (stream, binding) = bypass::connect_direct(
+                &destination,
+                &self.guard,
+                self.family_preference,
+                self.keepalive,
+                self.proxy.mark(),
+                initial_data,
+            )
+            .await?;
+            return Ok((stream, binding, Route::Direct));
         }
 
-        // Create SOCKS5 CONNECT request.
-        let request = Socks5Request::new(SOCKS_CMD_CONNECT, destination.try_into()?);
+        let (stream, binding, _) = self.connect_proxied(destination, initial_data).await?;
+        Ok((stream, binding, Route::Proxied))
+    }
+
+    /// Establishes a connection to the specified destination like `connect_routed`, and describes the tunnel that
+    /// was established.
+    ///
+    /// # Arguments
+    ///
+    /// * `destination` - The target address and port to connect to.
+    /// * `initial_data` - Optional data to deliver to the destination before returning the stream.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing a tuple with a `SocksStream` to the destination and the addresses of its tunnel.
+    pub async fn connect_with_info<A>(
+        &self,
+        destination: A,
+        initial_data: Option<Vec<u8>>,
+    ) -> Result<(SocksStream, ClientTunnelInfo)>
+        where
+            A: TryInto<Address>,
+            A::Error: Into<anyhow::Error>,
+    {
+        let requested = destination.try_into().map_err(Into::into)?;
+        let (stream, binding, route) = self.connect_routed(requested.clone(), initial_data).await?;
+        let info = stream.tunnel_info(requested, binding, route);
+
+        Ok((stream, info))
+    }
+
+    /// Establishes a SOCKS5 connection to the specified destination like `connect`, and reports how long each
+    /// phase of the connect took.
+    ///
+    /// The timings are recorded in the metrics as well. Destinations on the bypass list are connected to
+    /// directly, which only counts toward `total`.
+    ///
+    /// # Arguments
+    ///
+    /// * `destination` - The target address and port to connect to.
+    /// * `initial_data` - Optional data to deliver to the destination before returning the stream.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing a tuple with a `SocksStream` to the destination, the bound address, and the timings of
+    /// the connect.
+    pub async fn connect_with_timings<A>(
+        &self,
+        destination: A,
+        initial_data: Option<Vec<u8>>,
+    ) -> Result<(SocksStream, Address, HandshakeTimings)>
+        where
+            A: TryInto<Address>,
+            A::Error: Into<anyhow::Error>,
+    {
+        let started = Instant::now();
+        let destination = destination.try_into().map_err(Into::into)?;
+        let destination = self.guard.check(&destination).await?;
+        if self.bypass.matches(&destination) {
+            let (stream, binding) = bypass::connect_direct(
+                &destination,
+                &self.guard,
+                self.family_preference,
+                self.keepalive,
+                self.proxy.mark(),
+                initial_data,
+            )
+            .await?;
+            let timings = HandshakeTimings {
+                total: started.elapsed(),
+                ..HandshakeTimings::attempt(1)
+            };
+            return Ok((stream, binding, timings));
+        }
+
+        self.connect_proxied(destination, initial_data).await
+    }
+
+    // Connects to the destination through the proxy, with as many attempts as the retry policy allows, and records
+    // the timings of the attempt that succeeded.
+    async fn connect_proxied(
+        &self,
+        destination: Address,
+        initial_data: Option<Vec<u8>>,
+    ) -> Result<(SocksStream, Address, HandshakeTimings)> {
+        let started = Instant::now();
+        let (stream, binding, mut timings) = self
+            .retry
+            .run(|attempt| self.connect_attempt(destination.clone(), initial_data.clone(), attempt))
+            .await?;
+        timings.total = started.elapsed();
+        Recorder::client(self.metrics.as_ref(), "5").timings(&timings);
+        debug!("Connected through the SOCKS5 proxy, taking {:?}.", timings.phases());
+
+        Ok((stream, binding, timings))
+    }
 
-        let mut stream = TcpStream::connect(&self.proxy_addr).await?;
+    // Makes a single attempt to connect, over a fresh connection to the proxy.
+    async fn connect_attempt(
+        &self,
+        destination: Address,
+        initial_data: Option<Vec<u8>>,
+        attempt: u32,
+    ) -> Result<(SocksStream, Address, HandshakeTimings)> {
+        let id = ConnectionId::generate();
+        let recorder = Recorder::client(self.metrics.as_ref(), "5");
+        recorder.started();
+        let mut timings = HandshakeTimings::attempt(attempt);
+        match self.connect_session(id, destination, initial_data, &mut timings).await {
+            Ok((stream, binding)) => {
+                recorder.succeeded();
+                Ok((stream, binding, timings))
+            }
+            Err(error) => {
+                recorder.failed(&error);
+                Err(id.attach(error))
+            }
+        }
+    }
+
+    /// Establishes a SOCKS5 connection to the specified destination, returning a stream with the client's read
+    /// and write inactivity timeouts.
+    ///
+    /// # Arguments
+    ///
+    /// * `destination` - The target address and port to connect to.
+    /// * `initial_data` - Optional data to deliver to the destination before returning the stream.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing a tuple with a `TimeoutStream` to the destination and the bound address.
+    pub async fn connect_timed<A>(
+        &self,
+        destination: A,
+        initial_data: Option<Vec<u8>>,
+    ) -> Result<(TimeoutStream<SocksStream>, Address)>
+        where
+            A: TryInto<Address>,
+            A::Error: Into<anyhow::Error>,
+    {
+        let (stream, binding) = self.connect(destination, initial_data).await?;
+        Ok((TimeoutStream::new(stream, self.timeouts), binding))
+    }
+
+    /// Establishes a SOCKS5 connection to the specified destination through the proxy, over TLS as set up with
+    /// `with_tls`.
+    ///
+    /// Failures of the TLS handshake, including a proxy rejecting the client certificate, result in
+    /// `SocksError::TlsHandshakeFailed`, as opposed to failures of the SOCKS handshake.
+    ///
+    /// # Arguments
+    ///
+    /// * `destination` - The target address and port to connect to.
+    /// * `initial_data` - Optional data to deliver to the destination before returning the stream.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing a tuple with the TLS stream to the destination and the bound address.
+    #[cfg(feature = "tls")]
+    pub async fn connect_tls<A>(
+        &self,
+        destination: A,
+        initial_data: Option<Vec<u8>>,
+    ) -> Result<(tokio_rustls::client::TlsStream<SocksStream>, Address)>
+        where
+            A: TryInto<Address>,
+            A::Error: Into<anyhow::Error>,
+    {
+        let tls = match &self.tls {
+            Some(tls) => tls,
+            None => bail!("TLS isn't set up for this client, see `with_tls`."),
+        };
+        let started = Instant::now();
+        let destination = self.guard.check(&destination.try_into().map_err(Into::into)?).await?;
+        let destination = self.scope_policy.apply(destination)?;
+        self.credentials().await?;
+
+        let id = ConnectionId::generate();
+        let recorder = Recorder::client(self.metrics.as_ref(), "5");
+        recorder.started();
+        let mut timings = HandshakeTimings::attempt(1);
+        let connected = async {
+            let stream = self.connect_proxy(&mut timings).await?;
+            let mut watch = Stopwatch::start();
+            let mut stream = tls.connect(self.proxy.hostname(), stream).await?;
+            timings.tls = Some(watch.lap());
+            let binding = self
+                .handshake(id, destination, initial_data, &mut stream, &mut timings)
+                .await
+                .map_err(tls::classify)?;
+
+            Ok::<_, anyhow::Error>((stream, binding))
+        }
+        .await;
+
+        match connected {
+            Ok(connected) => {
+                recorder.succeeded();
+                timings.total = started.elapsed();
+                recorder.timings(&timings);
+                Ok(connected)
+            }
+            Err(error) => {
+                recorder.failed(&error);
+                Err(id.attach(error))
+            }
+        }
+    }
+
+    // Establishes a SOCKS5 connection to the destination, for the session with the given ID, measuring its phases
+    // into the timings.
+    async fn connect_session<A>(
+        &self,
+        id: ConnectionId,
+        destination: A,
+        initial_data: Option<Vec<u8>>,
+        timings: &mut HandshakeTimings,
+    ) -> Result<(SocksStream, Address)>
+        where
+            A: TryInto<Address>,
+            A::Error: Into<anyhow::Error>,
+    {
+        // Credentials that can't be read fail the connect before the proxy is connected to.
+        self.credentials().await?;
+
+        let destination = self.scope_policy.apply(destination.try_into().map_err(Into::into)?)?;
+        let mut stream = self.connect_proxy(timings).await?;
+        let binding = self.handshake(id, destination, initial_data, &mut stream, timings).await?;
+
+        Ok((stream, binding))
+    }
+
+    // Negotiates a connect to the destination over a connection to the proxy, for the session with the given ID.
+    async fn handshake<S>(
+        &self,
+        id: ConnectionId,
+        destination: Address,
+        initial_data: Option<Vec<u8>>,
+        stream: &mut S,
+        timings: &mut HandshakeTimings,
+    ) -> Result<Address>
+        where
+            S: AsyncRead + AsyncWrite + Unpin,
+    {
+        // Create SOCKS5 CONNECT request.
+        debug!("[{}] Connecting to {} through the SOCKS5 proxy.", id, destination);
+        let request = Socks5Request::new(SOCKS_CMD_CONNECT, destination);
 
         // Enter authentication negotiation.
-        let auth_method = self.negotiate_auth_method(&mut stream).await?;
+        let credentials = self.credentials().await?;
+        let mut watch = Stopwatch::start();
+        let auth_method = self.negotiate_auth_method(stream, credentials).await?;
         if auth_method == SOCKS_AUTH_USERNAME_PASSWORD {
-            if let Some(credentials) = &self.credentials {
-                self.authenticate(&mut stream, credentials).await?;
+            if let Some(credentials) = credentials {
+                self.authenticate(stream, credentials).await?;
             } else {
                 unreachable!();
             }
         }
+        timings.authentication = watch.lap();
 
         // Send SOCKS request information.
         let request_bytes = request.into_socks_bytes();
-        stream.write(&request_bytes).await?;
+        stream.write_all(&request_bytes).await?;
 
         // Read operation reply.
-        let binding = socks5::read_reply(&mut stream).await?;
+        let recorder = Recorder::client(self.metrics.as_ref(), "5");
+        let binding = socks5::read_reply_observed(stream, |code| recorder.reply_received(code)).await?;
+        timings.operation_reply = watch.lap();
 
-        Ok((stream, binding))
+        // Deliver initial data through the established tunnel.
+        if let Some(initial_data) = initial_data {
+            stream
+                .write_all(&initial_data)
+                .await
+                .map_err(SocksError::InitialDataWrite)?;
+        }
+
+        Ok(binding)
+    }
+
+    // Returns the credentials of the client, if any, reading them from their source the first time.
+    async fn credentials(&self) -> Result<Option<&Credentials>> {
+        let credentials = match &self.credentials {
+            Some(credentials) => credentials.get().await?,
+            None => return Ok(None),
+        };
+        ensure!(credentials.username.len() <= 255, "Username MUST NOT be larger than 255 bytes.");
+        ensure!(credentials.password.len() <= 255, "Password MUST NOT be larger than 255 bytes.");
+
+        Ok(Some(credentials))
+    }
+
+    /// Connects to the proxy, racing its addresses according to the family preference.
+    ///
+    /// The proxy's hostname is resolved again first if the re-resolution policy says so. How long each of that
+    /// took goes into the timings.
+    async fn connect_proxy(
+        &self,
+        timings: &mut HandshakeTimings,
+    ) -> Result<SocksStream> {
+        timings.resolution = self.proxy.refresh_if_due().await;
+        let mut watch = Stopwatch::start();
+        let (stream, info) = self.proxy.connect_stream(self.family_preference).await?;
+        timings.proxy_connect = watch.lap();
+        timings.proxy_addr = info.as_ref().map(|info| info.addr);
+        timings.connect_attempts = info.map_or(1, |info| info.attempts);
+        if let (Some(keepalive), Some(tcp)) = (&self.keepalive, stream.as_tcp()) {
+            keepalive.apply(tcp)?;
+        }
+
+        Ok(stream)
     }
 
     /// Negotiates the SOCKS5 authentication method with the proxy server.
@@ -89,41 +710,39 @@ impl Socks5Client {
     /// # Arguments
     ///
     /// * `stream` - The TCP stream connected to the proxy server.
+    /// * `credentials` - The credentials of the client, if any.
     ///
     /// # Returns
     ///
     /// A `Result` containing the selected authentication method.
-    async fn negotiate_auth_method(
-        &self,
-        stream: &mut TcpStream,
-    ) -> Result<u8> {
-        let mut request = vec![SOCKS_VER_5, 0x01, SOCKS_AUTH_NOT_REQUIRED];
-        if self.credentials.is_some() {
-            request[1] = 0x02;
-            request.push(SOCKS_AUTH_USERNAME_PASSWORD);
+    async fn negotiate_auth_method<S>(
+        &self,
+        stream: &mut S,
+        credentials: Option<&Credentials>,
+    ) -> Result<u8>
+        where
+            S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut methods = vec![SOCKS_AUTH_NOT_REQUIRED];
+        if credentials.is_some() {
+            methods.push(SOCKS_AUTH_USERNAME_PASSWORD);
         }
 
-        stream.write(&request).await?;
-
-        let mut reply = [0; 2];
-        stream.read_exact(&mut reply).await?;
-
-        let socks_version = reply[0];
-        if socks_version != SOCKS_VER_5 {
-            bail!("Proxy uses a different SOCKS version: {}.", socks_version);
-        }
+        let mut request = vec![];
+        wire::encode_socks5_greeting(&methods, &mut request);
+        stream.write_all(&request).await?;
 
-        let auth_method = reply[1];
+        let auth_method = wire::read_message(stream, &mut BytesMut::new(), wire::parse_socks5_method_selection).await?;
         match auth_method {
             0x00 => Ok(auth_method),
             0x02 => {
-                if self.credentials.is_none() {
+                if credentials.is_none() {
                     bail!("Proxy demands authentication, but no credentials are provided.");
                 } else {
                     Ok(auth_method)
                 }
             }
-            0xFF => bail!("Proxy did not accept authentication method."),
+            0xFF => bail!(SocksError::NoAcceptableAuthMethod),
             _ => bail!("Proxy proposed unsupported authentication method: {}.", auth_method),
         }
     }
@@ -138,33 +757,385 @@ impl Socks5Client {
     /// # Returns
     ///
     /// A `Result` indicating success or an error if authentication fails.
-    async fn authenticate(
+    async fn authenticate<S>(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut S,
         credentials: &Credentials,
-    ) -> Result<()> {
-        let mut request = vec![SOCKS_AUTH_VER];
-        request.extend(credentials.as_socks_bytes());
-
-        stream.write(&request).await?;
-
-        let mut reply = [0; 2];
-        stream.read_exact(&mut reply).await?;
+    ) -> Result<()>
+        where
+            S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut request = vec![];
+        wire::encode_socks5_credentials(credentials, &mut request);
+        stream.write_all(&request).await?;
 
-        let auth_version = reply[0];
-        if auth_version != SOCKS_AUTH_VER {
-            bail!(
-                "Proxy uses a different authentication method version: {}.",
-                auth_version
-            );
-        }
+        let status = wire::read_message(stream, &mut BytesMut::new(), |bytes| {
+            wire::parse_socks5_auth_status_with(bytes, self.auth_version)
+        })
+        .await?;
 
         // Check if status indicates success. If not, bail to close the connection.
-        let status = reply[1];
         if status != SOCKS_AUTH_SUCCESS {
-            bail!("Authentication with the provided credentials failed.");
+            bail!(SocksError::AuthenticationFailed(None));
         }
 
         Ok(())
     }
 }
+
+#[async_trait]
+impl SocksClient for Socks5Client {
+    async fn connect(
+        &self,
+        destination: Address,
+        initial_data: Option<Vec<u8>>,
+    ) -> Result<(SocksStream, Address)> {
+        Socks5Client::connect(self, destination, initial_data).await
+    }
+
+    async fn connect_routed(
+        &self,
+        destination: Address,
+        initial_data: Option<Vec<u8>>,
+    ) -> Result<(SocksStream, Address, Route)> {
+        Socks5Client::connect_routed(self, destination, initial_data).await
+    }
+
+    fn marked(
+        &self,
+        mark: u32,
+    ) -> Option<Box<dyn SocksClient + Send + Sync>> {
+        Some(Box::new(self.clone().with_mark(mark)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    use crate::metrics::{InMemoryMetrics, HANDSHAKE_PHASE_DURATION};
+    use crate::test_util::{MockSocksServer, Phase};
+    use crate::wire::vectors;
+
+    use super::*;
+
+    // Test that initial data is delivered to the destination before the stream is returned.
+    #[tokio::test]
+    async fn test_connect_with_initial_data() -> Result<()> {
+        let proxy = MockSocksServer::socks5();
+        let proxy_addr = proxy.bind().await?;
+
+        let client = Socks5Client::new(proxy_addr.to_string(), None).await?;
+        let (mut stream, _) = client.connect("192.0.2.1:80".to_string(), Some(b"hello".to_vec())).await?;
+
+        let mut received = [0; 5];
+        stream.read_exact(&mut received).await?;
+        assert_eq!(&received, b"hello");
+
+        let recording = &proxy.recordings()[0];
+        assert_eq!(recording.destination, Some(Address::new("192.0.2.1", 80)));
+        assert_eq!(recording.tunnel, b"hello".to_vec());
+        Ok(())
+    }
+
+    // Test that the client sends exactly the golden handshakes, with and without credentials, and takes the binding
+    // from the golden reply.
+    #[tokio::test]
+    async fn test_golden_vectors() -> Result<()> {
+        let reply = vectors::socks5_reply_success();
+        let cases = vec![
+            (
+                None,
+                vectors::socks5_connect_ipv4(),
+                vec![(vectors::socks5_greeting_no_auth().bytes, vectors::socks5_selection_no_auth().bytes)],
+            ),
+            (
+                Some(vectors::socks5_credentials()),
+                vectors::socks5_connect_domain(),
+                vec![
+                    (
+                        vectors::socks5_greeting_username_password().bytes,
+                        vectors::socks5_selection_username_password().bytes,
+                    ),
+                    (vectors::socks5_credentials().bytes, vectors::socks5_auth_success().bytes),
+                ],
+            ),
+        ];
+
+        for (credentials, request, mut script) in cases {
+            script.push((request.bytes, reply.bytes));
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let proxy_addr = listener.local_addr()?;
+            let proxy = tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await?;
+                for (expected, answer) in script {
+                    let mut received = vec![0; expected.len()];
+                    stream.read_exact(&mut received).await?;
+                    assert_eq!(received, expected);
+                    stream.write_all(answer).await?;
+                }
+                Ok::<_, anyhow::Error>(())
+            });
+
+            let client = Socks5Client::new(proxy_addr.to_string(), credentials.map(|c| c.value)).await?;
+            let (_, binding) = client.connect(request.value.destination, None).await?;
+            assert_eq!(binding, reply.value.1);
+            proxy.await??;
+        }
+
+        Ok(())
+    }
+
+    // Test that the client can be used through the version-generic `SocksClient` trait.
+    #[tokio::test]
+    async fn test_connect_through_socks_client_trait() -> Result<()> {
+        let proxy = MockSocksServer::socks5().with_binding(Address::new("10.0.0.1", 1080));
+        let proxy_addr = proxy.bind().await?;
+
+        let client: Box<dyn SocksClient + Send + Sync> =
+            Box::new(Socks5Client::new(proxy_addr.to_string(), None).await?);
+        let (mut stream, binding) = client
+            .connect(Address::new("example.com", 443), Some(b"hi".to_vec()))
+            .await?;
+        assert_eq!(binding, Address::new("10.0.0.1", 1080));
+
+        let mut received = [0; 2];
+        stream.read_exact(&mut received).await?;
+        assert_eq!(&received, b"hi");
+
+        Ok(())
+    }
+
+    // Test creating a client from a proxy address, including its credentials.
+    #[tokio::test]
+    async fn test_from_proxy_addr() -> Result<()> {
+        let proxy = MockSocksServer::socks5().with_credentials(Credentials::new("user", "secret"));
+        let proxy_addr = proxy.bind().await?;
+
+        let client = Socks5Client::from_proxy_addr(format!("socks5://user:secret@{}", proxy_addr).try_into()?).await?;
+        client.connect("192.0.2.1:80".to_string(), None).await?;
+        assert_eq!(proxy.recordings()[0].credentials, Some(Credentials::new("user", "secret")));
+
+        let socks6 = format!("socks6://{}", proxy_addr).try_into()?;
+        assert!(Socks5Client::from_proxy_addr(socks6).await.is_err());
+        Ok(())
+    }
+
+    // Test username/password authentication, with the right and the wrong credentials.
+    #[tokio::test]
+    async fn test_connect_with_credentials() -> Result<()> {
+        let proxy = MockSocksServer::socks5().with_credentials(Credentials::new("user", "secret"));
+        let proxy_addr = proxy.bind().await?;
+
+        let client = Socks5Client::new(proxy_addr.to_string(), Some(Credentials::new("user", "secret"))).await?;
+        client.connect("192.0.2.1:80".to_string(), None).await?;
+
+        let client = Socks5Client::new(proxy_addr.to_string(), Some(Credentials::new("user", "wrong"))).await?;
+        assert!(client.connect("192.0.2.1:80".to_string(), None).await.is_err());
+
+        let client = Socks5Client::new(proxy_addr.to_string(), None).await?;
+        assert!(client.connect("192.0.2.1:80".to_string(), None).await.is_err());
+
+        let recordings = proxy.recordings();
+        assert_eq!(recordings[0].methods, vec![SOCKS_AUTH_NOT_REQUIRED, SOCKS_AUTH_USERNAME_PASSWORD]);
+        assert_eq!(recordings[1].credentials, Some(Credentials::new("user", "wrong")));
+        Ok(())
+    }
+
+    // Test that a rejecting reply, even a late one, fails the connect.
+    #[tokio::test]
+    async fn test_connect_rejected() -> Result<()> {
+        let proxy = MockSocksServer::socks5()
+            .with_reply(SOCKS_REP_CONNECTION_REFUSED)
+            .with_delay(Phase::Reply, Duration::from_millis(20));
+        let proxy_addr = proxy.bind().await?;
+
+        let client = Socks5Client::new(proxy_addr.to_string(), None).await?;
+        assert!(client.connect("192.0.2.1:80".to_string(), None).await.is_err());
+        Ok(())
+    }
+
+    // Test that a health check connects to the canary, and that one is needed.
+    #[tokio::test]
+    async fn test_health_check() -> Result<()> {
+        let proxy = MockSocksServer::socks5().with_credentials(Credentials::new("user", "secret"));
+        let proxy_addr = proxy.bind().await?;
+
+        let client = Socks5Client::new(proxy_addr.to_string(), Some(Credentials::new("user", "secret"))).await?;
+        assert!(client.health_check().await.is_err());
+
+        let canary = Address::new("192.0.2.1", 80);
+        let report = client.with_health_canary(canary.clone()).health_check().await?;
+        assert_eq!(report.probe, HealthProbe::Canary);
+        assert_eq!(report.proxy, Some(proxy_addr));
+        let recordings = proxy.recordings();
+        assert_eq!(recordings.len(), 1);
+        assert_eq!(recordings[0].destination, Some(canary));
+        Ok(())
+    }
+
+    // Test that a proxy answering the sub-negotiation with version 0x05 is only accepted by a lenient client.
+    #[tokio::test]
+    async fn test_auth_version_policy() -> Result<()> {
+        let credentials = Credentials::new("user", "secret");
+        let proxy = MockSocksServer::socks5()
+            .with_credentials(credentials.clone())
+            .with_auth_version(SOCKS_VER_5);
+        let proxy_addr = proxy.bind().await?;
+
+        let client = Socks5Client::new(proxy_addr.to_string(), Some(credentials.clone())).await?;
+        client.connect("192.0.2.1:80".to_string(), None).await?;
+
+        let client = Socks5Client::new(proxy_addr.to_string(), Some(credentials))
+            .await?
+            .with_auth_version_policy(AuthVersionPolicy::Strict);
+        let error = client.connect("192.0.2.1:80".to_string(), None).await.unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(SocksError::AuthVersionMismatch(0x05))));
+        Ok(())
+    }
+
+    // Test that the streams of `connect_timed` inherit the client's read timeout.
+    #[tokio::test]
+    async fn test_connect_timed() -> Result<()> {
+        let proxy = MockSocksServer::socks5();
+        let proxy_addr = proxy.bind().await?;
+
+        let client = Socks5Client::new(proxy_addr.to_string(), None)
+            .await?
+            .with_read_timeout(Duration::from_millis(100));
+        let (mut stream, _) = client.connect_timed("192.0.2.1:80".to_string(), Some(b"hello".to_vec())).await?;
+        assert_eq!(stream.timeouts().read, Some(Duration::from_millis(100)));
+
+        let mut received = [0; 5];
+        stream.read_exact(&mut received).await?;
+        assert_eq!(&received, b"hello");
+
+        // Nothing else is echoed, so the next read stalls.
+        let error = stream.read(&mut received).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+        Ok(())
+    }
+
+    // Tests that the timings of a connect that was retried are those of the attempt that succeeded, while the
+    // failed attempt counts toward the total.
+    #[tokio::test]
+    async fn test_connect_with_timings() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let failing = MockSocksServer::socks5()
+                .with_delay(Phase::Reply, Duration::from_millis(50))
+                .with_reply(SOCKS_REP_GENERAL_FAILURE);
+            if let Ok((stream, _)) = listener.accept().await {
+                let _ = failing.serve(stream).await;
+            }
+            while let Ok((stream, _)) = listener.accept().await {
+                let proxy = MockSocksServer::socks5();
+                tokio::spawn(async move { proxy.serve(stream).await });
+            }
+        });
+
+        let metrics = Arc::new(InMemoryMetrics::new());
+        let client = Socks5Client::new(proxy_addr.to_string(), None)
+            .await?
+            .with_metrics(metrics.clone())
+            .with_retry_policy(RetryPolicy::new(2).with_backoff(Duration::from_millis(1)));
+        let (_, _, timings) = client.connect_with_timings("192.0.2.1:80".to_string(), None).await?;
+        assert_eq!(timings.attempts, 2);
+        assert_eq!(timings.proxy_addr, Some(proxy_addr));
+        assert!(timings.operation_reply < Duration::from_millis(50));
+        assert!(timings.total >= Duration::from_millis(50));
+
+        let labels = [("role", "client"), ("version", "5"), ("phase", "total")];
+        assert_eq!(metrics.durations(HANDSHAKE_PHASE_DURATION, &labels), vec![timings.total]);
+        Ok(())
+    }
+
+    // Tests that credentials from the environment are read on the first connect, which fails before connecting to
+    // the proxy while they're missing.
+    #[tokio::test]
+    async fn test_credential_source() -> Result<()> {
+        let proxy = MockSocksServer::socks5().with_credentials(Credentials::new("user", "secret"));
+        let proxy_addr = proxy.bind().await?;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        static SET: AtomicBool = AtomicBool::new(false);
+        let lookup = |name: &str| match (name, SET.load(Ordering::SeqCst)) {
+            ("PROXY_USERNAME", true) => Ok("user".to_string()),
+            ("PROXY_PASSWORD", true) => Ok("secret".to_string()),
+            _ => Err(std::env::VarError::NotPresent),
+        };
+        let mut client = Socks5Client::new(proxy_addr.to_string(), None).await?;
+        let source = CredentialSource::Env("PROXY".to_string());
+        client.credentials = Some(DeferredCredentials::new(source).with_lookup(lookup));
+
+        let error = client.connect("192.0.2.1:80".to_string(), None).await.unwrap_err();
+        assert!(error.to_string().contains("PROXY_USERNAME"));
+        assert!(proxy.recordings().is_empty());
+
+        SET.store(true, Ordering::SeqCst);
+        client.connect("192.0.2.1:80".to_string(), None).await?;
+        assert_eq!(proxy.recordings()[0].credentials, Some(Credentials::new("user", "secret")));
+        Ok(())
+    }
+
+    // Tests that a proxy listening on a Unix socket is connected to at its `unix:` path, with the usual handshake.
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_connect_over_unix_socket() -> Result<()> {
+        use crate::test_util::MockConnector;
+        use crate::{Socks5Handler, SocksHandler};
+
+        let path = std::env::temp_dir().join(format!("socksx-s5-client-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path)?;
+        let connector = Arc::new(MockConnector::new());
+        let handler = Socks5Handler::default().with_connector(connector.clone());
+        tokio::spawn(async move {
+            let (mut source, _) = listener.accept().await?;
+            handler.accept_request(&mut source).await
+        });
+
+        let client = Socks5Client::new(format!("unix:{}", path.display()), None).await?;
+        assert!(client.proxy_addrs().is_empty());
+        let (mut stream, _, timings) = client.connect_with_timings("192.0.2.1:80".to_string(), None).await?;
+        assert!(matches!(stream, SocksStream::Unix(_)));
+        assert_eq!(timings.proxy_addr, None);
+
+        stream.write_all(b"hello").await?;
+        assert_eq!(connector.received(0, 5).await, b"hello".to_vec());
+        assert_eq!(connector.destinations()[0].address, Address::new("192.0.2.1", 80));
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    // Tests that a proxy listening on a Unix socket is health checked at its socket, without an address to report.
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_health_check_over_unix_socket() -> Result<()> {
+        use crate::test_util::MockConnector;
+        use crate::{Socks5Handler, SocksHandler};
+
+        let path = std::env::temp_dir().join(format!("socksx-s5-health-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path)?;
+        let connector = Arc::new(MockConnector::new());
+        let handler = Socks5Handler::default().with_connector(connector.clone());
+        tokio::spawn(async move {
+            let (mut source, _) = listener.accept().await?;
+            handler.accept_request(&mut source).await
+        });
+
+        let canary = Address::new("192.0.2.1", 80);
+        let client = Socks5Client::new(format!("unix:{}", path.display()), None).await?.with_health_canary(canary);
+        let report = client.health_check().await?;
+        assert_eq!(report.probe, HealthProbe::Canary);
+        assert_eq!(report.proxy, None);
+        assert_eq!(connector.destinations()[0].address, Address::new("192.0.2.1", 80));
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -1,17 +1,56 @@
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
 use anyhow::Result;
 use async_trait::async_trait;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use bytes::BytesMut;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::time;
+
+use crate::{constants::*, Credentials, PasswordVerifier};
+use crate::addresses::{Address, ProxyAddress};
+use crate::baggage::Baggage;
+use crate::dialer::{
+    self, AddressFamilyPreference, ConnectionAddrs, Connector, Dialer, Keepalive, DEFAULT_CONNECT_TIMEOUT,
+};
+use crate::guard::DestinationGuard;
+use crate::resolver::{ResolutionPolicy, Resolver};
+use crate::metrics::{Metrics, NoopMetrics, Recorder};
+use crate::mirror::{Mirror, MirroredStream};
+use crate::policy::{Policy, SharedPolicy};
+use crate::session::{self, CloseSummary, ConnectionId, SessionHooks, SessionInfo, SessionLimits, TunnelInfo};
+#[cfg(feature = "gssapi")]
+use crate::socks5::gssapi::{self, GssapiAuthenticator};
+use crate::socks5::{self, AuthVersionPolicy, Socks5Command, Socks5Reply, Socks5Request};
+use crate::interface::AsyncStream;
+use crate::udp::{UdpAssociation, UdpRelayConfig};
+use crate::{util, wire, NegotiationError, SocksError, SocksHandler};
 
-use crate::{constants::*, Credentials};
-use crate::addresses::{self, ProxyAddress};
-use crate::socks5::{self, Socks5Reply};
-use crate::SocksHandler;
+/// How long the handler waits, by default, for the methods that a client declared in its greeting.
+pub const DEFAULT_METHODS_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// Represents a SOCKS5 handler for processing client requests.
 #[derive(Clone)]
 pub struct Socks5Handler {
-    credentials: Option<Credentials>,
+    verifier: Option<Arc<dyn PasswordVerifier + Send + Sync>>,
+    dialer: Dialer,
+    connector: Option<Arc<dyn Connector + Send + Sync>>,
+    guard: DestinationGuard,
+    auth_version: AuthVersionPolicy,
+    #[cfg(feature = "gssapi")]
+    gssapi: Option<Arc<dyn GssapiAuthenticator + Send + Sync>>,
+    method_preference: Vec<u8>,
+    methods_timeout: Duration,
+    connect_timeout: Duration,
+    hooks: Option<Arc<dyn SessionHooks + Send + Sync>>,
+    session_limits: SessionLimits,
+    policy: SharedPolicy,
+    reevaluate_policy: bool,
+    allow_unix_destinations: bool,
+    udp_relay: Option<UdpRelayConfig>,
+    resolution: ResolutionPolicy,
+    metrics: Arc<dyn Metrics + Send + Sync>,
     //chain: Vec<ProxyAddress>,
 }
 
@@ -33,10 +72,803 @@ impl Socks5Handler {
     /// A new `Socks5Handler` instance.
     pub fn new(_chain: Vec<ProxyAddress>) -> Self {
         Socks5Handler {
-            credentials: None,
+            verifier: None,
+            dialer: Dialer::default(),
+            connector: None,
+            guard: DestinationGuard::permissive(),
+            auth_version: AuthVersionPolicy::default(),
+            #[cfg(feature = "gssapi")]
+            gssapi: None,
+            method_preference: vec![SOCKS_AUTH_GSSAPI, SOCKS_AUTH_USERNAME_PASSWORD, SOCKS_AUTH_NOT_REQUIRED],
+            methods_timeout: DEFAULT_METHODS_TIMEOUT,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            hooks: None,
+            session_limits: SessionLimits::none(),
+            policy: SharedPolicy::new(Policy::allow_all()),
+            reevaluate_policy: false,
+            allow_unix_destinations: false,
+            udp_relay: None,
+            resolution: ResolutionPolicy::default(),
+            metrics: Arc::new(NoopMetrics),
             //chain,
         }
     }
+
+    /// Requires clients to authenticate with the given username and password (RFC 1929).
+    ///
+    /// This is a shorthand for `with_password_verifier` with the credentials as the verifier.
+    pub fn with_credentials(
+        self,
+        credentials: Credentials,
+    ) -> Self {
+        self.with_password_verifier(Arc::new(credentials))
+    }
+
+    /// Requires clients to authenticate with a username and password (RFC 1929) that the verifier accepts, e.g.,
+    /// a `PasswordFileAuthenticator`.
+    pub fn with_password_verifier(
+        mut self,
+        verifier: Arc<dyn PasswordVerifier + Send + Sync>,
+    ) -> Self {
+        self.verifier = Some(verifier);
+        self
+    }
+
+    /// Sets which version bytes are accepted in the username/password sub-negotiation of clients.
+    ///
+    /// By default, the handler is lenient and accepts 0x05 as well as 0x01.
+    pub fn with_auth_version_policy(
+        mut self,
+        policy: AuthVersionPolicy,
+    ) -> Self {
+        self.auth_version = policy;
+        self
+    }
+
+    /// Lets clients authenticate with GSSAPI (RFC 1961), with security contexts created by the authenticator. The
+    /// principal a client authenticates as is its identity for the policy and the hooks.
+    #[cfg(feature = "gssapi")]
+    pub fn with_gssapi(
+        mut self,
+        authenticator: Arc<dyn GssapiAuthenticator + Send + Sync>,
+    ) -> Self {
+        self.gssapi = Some(authenticator);
+        self
+    }
+
+    /// Sets the authentication methods the handler selects from, most preferred first. The first one that the
+    /// client offers, and the handler can perform, is selected; if there is none, the client is rejected.
+    ///
+    /// By default, GSSAPI is preferred when an authenticator for it is set, then username/password authentication
+    /// when a verifier is set, then no authentication.
+    /// To require authentication, leave out `SOCKS_AUTH_NOT_REQUIRED`.
+    pub fn with_method_preference(
+        mut self,
+        methods: Vec<u8>,
+    ) -> Self {
+        self.method_preference = methods;
+        self
+    }
+
+    /// Sets how long the handler waits for the methods that a client declared in its greeting, before it rejects
+    /// the greeting as truncated. Defaults to `DEFAULT_METHODS_TIMEOUT`.
+    pub fn with_methods_timeout(
+        mut self,
+        timeout: Duration,
+    ) -> Self {
+        self.methods_timeout = timeout;
+        self
+    }
+
+    /// Sets the policy that determines which of the destination's addresses are used, and in which order.
+    pub fn with_family_preference(
+        mut self,
+        family_preference: AddressFamilyPreference,
+    ) -> Self {
+        self.dialer.set_family_preference(family_preference);
+        self
+    }
+
+    /// Enables TCP keepalive on the outbound connections of the default connector.
+    ///
+    /// The source's connection belongs to the caller, who can enable it there with `Keepalive::apply`.
+    pub fn with_keepalive(
+        mut self,
+        keepalive: Keepalive,
+    ) -> Self {
+        self.dialer.set_keepalive(Some(keepalive));
+        self
+    }
+
+    /// Sets the firewall mark (SO_MARK) of the sockets that the default connector connects from. Packet filter
+    /// rules can match on it, e.g., to keep the handler's own connections from being redirected back to it by the
+    /// rules of a transparent proxy. Setting the mark requires the CAP_NET_ADMIN capability.
+    pub fn with_mark(
+        mut self,
+        mark: u32,
+    ) -> Self {
+        self.dialer.set_mark(Some(mark));
+        self
+    }
+
+    /// Sets the TTL of the packets of the connections that the default connector makes, or their hop limit over
+    /// IPv6, instead of the system default. A zero TTL, which isn't valid, keeps the system default.
+    pub fn with_ttl(
+        mut self,
+        ttl: u8,
+    ) -> Self {
+        self.dialer.set_ttl(Some(ttl));
+        self
+    }
+
+    /// Sets how long an outbound connect may take, including the resolution of the destination and every
+    /// connection attempt, before it is given up. Defaults to `DEFAULT_CONNECT_TIMEOUT`.
+    pub fn with_connect_timeout(
+        mut self,
+        timeout: Duration,
+    ) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Sets the resolver used for domain name destinations, e.g., a `CachingResolver`.
+    pub fn with_resolver(
+        mut self,
+        resolver: Arc<dyn Resolver + Send + Sync>,
+    ) -> Self {
+        self.dialer.set_resolver(resolver);
+        self
+    }
+
+    /// Sets where domain name destinations are resolved: at this hop, or by the custom connector (see
+    /// `ResolutionPolicy`). Defaults to `ResolutionPolicy::PassThroughWhenChaining`.
+    pub fn with_resolution_policy(
+        mut self,
+        resolution: ResolutionPolicy,
+    ) -> Self {
+        self.resolution = resolution;
+        self
+    }
+
+    /// Sets the connector used for outbound connections, instead of dialing them over TCP.
+    ///
+    /// The family preference and resolver of the handler only apply to its default connector, unless the
+    /// resolution policy resolves names before they are handed to the connector.
+    pub fn with_connector(
+        mut self,
+        connector: Arc<dyn Connector + Send + Sync>,
+    ) -> Self {
+        self.connector = Some(connector);
+        self
+    }
+
+    /// Sets the guard that decides which destinations may be connected to, e.g., `DestinationGuard::host_local`
+    /// to keep sources from reaching the proxy's host. By default, every destination is allowed.
+    ///
+    /// Requests for blocked destinations are refused with a ConnectionNotAllowed reply. Domain names are
+    /// checked each time the default connector resolves them, right before connecting to the result. Custom
+    /// connectors resolve out of the handler's sight, so only the destination as requested is checked for them,
+    /// unless the guard resolves it locally.
+    pub fn with_destination_guard(
+        mut self,
+        guard: DestinationGuard,
+    ) -> Self {
+        self.dialer.set_guard(guard.clone());
+        self.guard = guard;
+        self
+    }
+
+    /// Limits how long sessions may be relayed, and how many bytes they may relay, after which they are closed.
+    ///
+    /// The hooks can adjust the limits for each session, e.g., by identity.
+    pub fn with_session_limits(
+        mut self,
+        limits: SessionLimits,
+    ) -> Self {
+        self.session_limits = limits;
+        self
+    }
+
+    /// Sets the policy that decides which sessions are allowed, by default one that allows all. Requests it
+    /// denies are refused with a ConnectionNotAllowed reply.
+    ///
+    /// The policy can be replaced with `update_policy` while the handler serves sessions.
+    pub fn with_policy(
+        mut self,
+        policy: Policy,
+    ) -> Self {
+        self.policy = SharedPolicy::new(policy);
+        self
+    }
+
+    /// Sets whether established sessions are re-evaluated when the policy is updated, closing the ones it no
+    /// longer allows. By default, established sessions keep the decision they were accepted with.
+    pub fn with_policy_reevaluation(
+        mut self,
+        reevaluate: bool,
+    ) -> Self {
+        self.reevaluate_policy = reevaluate;
+        self
+    }
+
+    /// Allows destinations that are Unix socket paths, which are a socksx extension (see `SOCKS_ATYP_UNIX`).
+    ///
+    /// By default, requests for them are refused with an AddressTypeNotSupported reply, like requests with any
+    /// other unknown address type. The connector has to support them: the default one connects to the path.
+    pub fn with_unix_destinations(
+        mut self,
+        allow: bool,
+    ) -> Self {
+        self.allow_unix_destinations = allow;
+        self
+    }
+
+    /// Relays the datagrams of UDP ASSOCIATE requests within the limits of the configuration.
+    ///
+    /// By default, UDP ASSOCIATE is refused with a CommandNotSupported reply. The datagrams of an association are
+    /// checked against the destination guard, and the association is subject to the session limits and policy.
+    pub fn with_udp_relay(
+        mut self,
+        config: UdpRelayConfig,
+    ) -> Self {
+        self.udp_relay = Some(config);
+        self
+    }
+
+    /// Sets the hooks that decide on each session individually, e.g., whether it is mirrored.
+    pub fn with_hooks(
+        mut self,
+        hooks: Arc<dyn SessionHooks + Send + Sync>,
+    ) -> Self {
+        self.hooks = Some(hooks);
+        self
+    }
+
+    /// Sets the metrics that the handshakes, replies, and relayed bytes of the sessions are counted in.
+    pub fn with_metrics(
+        mut self,
+        metrics: Arc<dyn Metrics + Send + Sync>,
+    ) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    // Returns the recorder for the measurements of the sessions.
+    fn recorder(&self) -> Recorder<'_> {
+        Recorder::handler(self.metrics.as_ref(), "5")
+    }
+
+    // Writes a reply to the source, counting it.
+    async fn write_reply<S>(
+        &self,
+        stream: &mut S,
+        reply: Socks5Reply,
+    ) -> Result<()>
+    where
+        S: AsyncWrite + Unpin + ?Sized,
+    {
+        self.recorder().reply_sent(reply.clone() as u8);
+        socks5::write_reply(stream, reply).await
+    }
+
+    // Writes a successful reply with the address the handler is bound to, counting it.
+    async fn write_binding<S>(
+        &self,
+        stream: &mut S,
+        binding: &Address,
+    ) -> Result<()>
+    where
+        S: AsyncWrite + Unpin + ?Sized,
+    {
+        self.recorder().reply_sent(SOCKS_REP_SUCCEEDED);
+        let mut bytes = vec![];
+        wire::encode_socks5_reply(SOCKS_REP_SUCCEEDED, binding, &mut bytes);
+        stream.write_all(&bytes).await?;
+
+        Ok(())
+    }
+
+    // Returns the connector used for outbound connections.
+    fn connector(&self) -> &(dyn Connector + Send + Sync) {
+        match &self.connector {
+            Some(connector) => connector.as_ref(),
+            None => &self.dialer,
+        }
+    }
+
+    // Returns the destination as it is handed on, resolved at this hop or not according to the resolution policy.
+    async fn outbound_destination(
+        &self,
+        destination: &Address,
+        upstream: bool,
+    ) -> Result<Address> {
+        self.resolution.destination_for(destination, upstream, &self.dialer, self.connect_timeout).await
+    }
+
+    // Connects to a destination with the connector, within the connect timeout, if the guard allows it. Where the
+    // destination is resolved is up to the resolution policy.
+    async fn connect_outbound(
+        &self,
+        address: &Address,
+    ) -> Result<(Box<dyn AsyncStream>, ConnectionAddrs)> {
+        let address = &self.outbound_destination(address, self.connector.is_some()).await?;
+        if self.connector.is_some() {
+            let address = self.guard.check(address).await?;
+            return dialer::connect_within(self.connector(), &address, self.connect_timeout).await;
+        }
+
+        dialer::connect_within(self.connector(), address, self.connect_timeout).await
+    }
+
+    /// Negotiates authentication with the source and reads its request, leaving it to the caller what to do
+    /// with it.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The stream representing the client connection.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the pending session, which decides the fate of the request, and the request itself.
+    pub async fn accept<'a>(
+        &'a self,
+        source: &'a mut dyn AsyncStream,
+    ) -> Result<(PendingSession<'a>, Socks5Request)> {
+        self.accept_as(source, None).await
+    }
+
+    // Accepts a request like `accept`, from a source that its transport authenticated as the given identity, if
+    // any. The identity is replaced by the username the source authenticates with, if it does.
+    async fn accept_as<'a>(
+        &'a self,
+        source: &'a mut dyn AsyncStream,
+        identity: Option<String>,
+    ) -> Result<(PendingSession<'a>, Socks5Request)> {
+        let id = ConnectionId::generate();
+        let recorder = self.recorder();
+        recorder.started();
+        let session = self.read_session(source, id, identity).await.map_err(|e| {
+            recorder.failed(&e);
+            id.attach(e)
+        })?;
+        let request = session.request.clone();
+
+        Ok((session, request))
+    }
+
+    // Reads the method negotiation of a client into the scratch buffer, and returns the methods it offers. The
+    // methods must follow the header within the methods timeout, or the negotiation is rejected as truncated.
+    async fn read_methods(
+        &self,
+        source: &mut BufReader<&mut dyn AsyncStream>,
+        scratch: &mut BytesMut,
+    ) -> Result<Vec<u8>> {
+        scratch.clear();
+        let mut header = [0; 2];
+        source.read_exact(&mut header).await?;
+        scratch.extend_from_slice(&header);
+
+        let methods = wire::fill_message(source, scratch, wire::parse_socks5_negotiation);
+        match time::timeout(self.methods_timeout, methods).await {
+            Ok(Ok(methods)) => Ok(methods),
+            Ok(Err(e)) if e.downcast_ref::<io::Error>().map(io::Error::kind) != Some(io::ErrorKind::UnexpectedEof) => {
+                Err(e)
+            }
+            // The client stopped sending, or closed the connection, before all declared methods arrived.
+            _ => bail!(SocksError::MalformedNegotiation(NegotiationError::TruncatedMethods {
+                declared: header[1],
+                received: scratch.len() - header.len(),
+            })),
+        }
+    }
+
+    // Selects the most preferred method that the client offers, and the handler can perform.
+    fn select_method(
+        &self,
+        offered: &[u8],
+    ) -> u8 {
+        let supported = |method: &u8| match *method {
+            #[cfg(feature = "gssapi")]
+            SOCKS_AUTH_GSSAPI => self.gssapi.is_some(),
+            SOCKS_AUTH_USERNAME_PASSWORD => self.verifier.is_some(),
+            SOCKS_AUTH_NOT_REQUIRED => true,
+            _ => false,
+        };
+
+        self.method_preference
+            .iter()
+            .copied()
+            .find(|method| supported(method) && offered.contains(method))
+            .unwrap_or(SOCKS_AUTH_NO_ACCEPTABLE_METHODS)
+    }
+
+    // Negotiates authentication and reads the request, for the session with the given ID.
+    async fn read_session<'a>(
+        &'a self,
+        source: &'a mut dyn AsyncStream,
+        id: ConnectionId,
+        mut identity: Option<String>,
+    ) -> Result<PendingSession<'a>> {
+        let mut reader = util::handshake_reader(source);
+        let source = &mut reader;
+
+        // Get all authentication methods the client proposes.
+        let mut scratch = BytesMut::new();
+        let methods = match self.read_methods(source, &mut scratch).await {
+            Err(e) => {
+                if let Some(SocksError::MalformedNegotiation(error)) = e.downcast_ref() {
+                    debug!("[{}] Malformed method negotiation: {}", id, error);
+                    // The client may have stopped sending, but still be reading.
+                    let mut response = vec![];
+                    wire::encode_socks5_method_selection(SOCKS_AUTH_NO_ACCEPTABLE_METHODS, &mut response);
+                    let _ = source.write_all(&response).await;
+                }
+                return Err(e);
+            }
+            Ok(methods) => methods,
+        };
+
+        let method = self.select_method(&methods);
+        info!("[{}] Use authentication method: {}", id, method);
+
+        let mut response = vec![];
+        wire::encode_socks5_method_selection(method, &mut response);
+        source.write_all(&response).await?;
+        ensure!(method != SOCKS_AUTH_NO_ACCEPTABLE_METHODS, SocksError::NoAcceptableAuthMethod);
+
+        // Some clients send the sub-negotiation and request without waiting for the selection. Messages are read no
+        // further than they extend, so whatever follows the negotiation stays in the reader for the next parser.
+        if !source.buffer().is_empty() {
+            debug!("[{}] Client pipelined {} bytes behind the method negotiation.", id, source.buffer().len());
+        }
+
+        // Enter method-specific sub-negotiation
+        #[cfg(feature = "gssapi")]
+        if let (SOCKS_AUTH_GSSAPI, Some(authenticator)) = (method, &self.gssapi) {
+            let principal = gssapi::accept_gssapi(source, authenticator.as_ref(), &mut scratch).await?;
+            info!("[{}] Authenticated with GSSAPI as {}.", id, principal);
+            identity = Some(principal);
+        }
+        if method == SOCKS_AUTH_USERNAME_PASSWORD {
+            let Credentials {
+                username: uname,
+                password: passwd,
+            } = wire::read_message(source, &mut scratch, |bytes| {
+                wire::parse_socks5_credentials_with(bytes, self.auth_version)
+            })
+            .await?;
+
+            let status = match &self.verifier {
+                Some(verifier) if verifier.verify(&uname, &passwd).await => SOCKS_AUTH_SUCCESS,
+                Some(_) => SOCKS_AUTH_FAILED,
+                None => unreachable!(),
+            };
+
+            let mut response = vec![];
+            wire::encode_socks5_auth_status(status, &mut response);
+            source.write_all(&response).await?;
+
+            ensure!(status == SOCKS_AUTH_SUCCESS, "Username/password authentication failed.");
+            identity = Some(String::from_utf8_lossy(&uname).into_owned());
+        }
+
+        let request = wire::read_message(source, &mut scratch, wire::parse_socks5_request).await?;
+        let supported = match request.command {
+            Socks5Command::Connect => true,
+            Socks5Command::UdpAssociate => self.udp_relay.is_some(),
+            Socks5Command::Bind => false,
+        };
+        if !supported {
+            self.write_reply(source, Socks5Reply::CommandNotSupported).await?;
+            bail!(SocksError::CommandNotSupported(request.command as u8));
+        }
+        if matches!(request.destination, Address::Unix(_)) && !self.allow_unix_destinations {
+            self.write_reply(source, Socks5Reply::AddressTypeNotSupported).await?;
+            bail!(SocksError::UnsupportedAddressType(SOCKS_ATYP_UNIX));
+        }
+        debug!("[{}] Received a request for {}.", id, request.destination);
+
+        let session = PendingSession {
+            handler: self,
+            reader,
+            request,
+            id,
+            identity,
+            source_addrs: ConnectionAddrs::default(),
+        };
+        if !self.policy.current().allows(&session.info()) {
+            let destination = session.request.destination.to_string();
+            session.write_rejection(Socks5Reply::ConnectionNotAllowed).await?;
+            bail!(SocksError::ConnectionNotAllowed(destination));
+        }
+
+        Ok(session)
+    }
+}
+
+/// A SOCKS5 request that was accepted by `Socks5Handler::accept`, but not acted upon yet.
+///
+/// The source is authenticated, and waits for the reply, which is sent once the session is rejected or
+/// proxied.
+pub struct PendingSession<'a> {
+    handler: &'a Socks5Handler,
+    reader: BufReader<&'a mut dyn AsyncStream>,
+    request: Socks5Request,
+    id: ConnectionId,
+    identity: Option<String>,
+    source_addrs: ConnectionAddrs,
+}
+
+impl<'a> PendingSession<'a> {
+    /// Returns the connection ID of the session.
+    pub fn id(&self) -> ConnectionId {
+        self.id
+    }
+
+    /// Sets the addresses of the connection of the source, for the `TunnelInfo` the hooks receive.
+    pub fn with_source_addrs(
+        mut self,
+        addrs: ConnectionAddrs,
+    ) -> Self {
+        self.source_addrs = addrs;
+        self
+    }
+
+    /// Refuses the request with the given reply, e.g., `Socks5Reply::ConnectionNotAllowed`.
+    pub async fn reject(
+        self,
+        reply: Socks5Reply,
+    ) -> Result<()> {
+        self.handler.recorder().rejected();
+        self.write_rejection(reply).await
+    }
+
+    // Writes the reply that refuses the request.
+    async fn write_rejection(
+        self,
+        reply: Socks5Reply,
+    ) -> Result<()> {
+        let id = self.id;
+        debug!("[{}] Rejecting the request with {:?}.", id, reply);
+
+        let handler = self.handler;
+        handler.write_reply(self.reader.into_inner(), reply).await.map_err(|e| id.attach(e))
+    }
+
+    /// Leaves the request to the caller, which replies to it and serves the source itself, without a reply of
+    /// the handler.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the bytes the source sent after its request, which the handler read already and
+    /// which come before anything read from the source from now on.
+    pub async fn take_over(self) -> Result<Vec<u8>> {
+        debug!("[{}] Handing the session over.", self.id);
+        Ok(self.reader.buffer().to_vec())
+    }
+
+    /// Connects to the destination of the request, and relays between it and the source until either
+    /// closes the connection.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing how the relay ended.
+    pub async fn proxy_to_destination(self) -> Result<CloseSummary> {
+        self.proxy(None).await
+    }
+
+    /// Relays between the source and the given stream, which the caller connected to the destination, until
+    /// either closes the connection.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing how the relay ended.
+    pub async fn proxy_via<S>(
+        self,
+        outbound: S,
+    ) -> Result<CloseSummary>
+    where
+        S: AsyncStream + 'static,
+    {
+        self.proxy(Some(Box::new(outbound))).await
+    }
+
+    // Establishes the session, and relays within the limits of the session, which is reported to the hooks.
+    async fn proxy(
+        self,
+        outbound: Option<Box<dyn AsyncStream>>,
+    ) -> Result<CloseSummary> {
+        let id = self.id;
+        let handler = self.handler;
+        if let (Socks5Command::UdpAssociate, Some(config)) = (&self.request.command, &handler.udp_relay) {
+            ensure!(outbound.is_none(), "[{}] A UDP association can't be relayed through a stream.", id);
+            return self.associate(config).await;
+        }
+
+        let session = self.info();
+        let limits = match &handler.hooks {
+            Some(hooks) => hooks.limits(&session, handler.session_limits),
+            None => handler.session_limits,
+        };
+
+        let (source, mut destination, tunnel) = self.establish(outbound).await.map_err(|e| id.attach(e))?;
+        if let Some(hooks) = &handler.hooks {
+            hooks.on_established(&session, &tunnel);
+        }
+        let _listing = session::Listing::current(id, &tunnel);
+        let revoked = handler.policy.revoked(session.clone(), handler.reevaluate_policy);
+        let (summary, relayed) = session::relay(id, source, &mut destination, limits, revoked).await;
+        handler.recorder().closed(&summary);
+        if let Some(hooks) = &handler.hooks {
+            hooks.on_close(&session, &summary);
+        }
+        relayed?;
+
+        Ok(summary)
+    }
+
+    // Opens the UDP association of the request, and relays its datagrams within the limits of the session, which
+    // is reported to the hooks.
+    async fn associate(
+        self,
+        config: &UdpRelayConfig,
+    ) -> Result<CloseSummary> {
+        let id = self.id;
+        let handler = self.handler;
+        let session = self.info();
+        let limits = match &handler.hooks {
+            Some(hooks) => hooks.limits(&session, handler.session_limits),
+            None => handler.session_limits,
+        };
+
+        let recorder = handler.recorder();
+        let opened = self.open_association(config).await;
+        match &opened {
+            Ok(_) => recorder.succeeded(),
+            Err(e) => recorder.failed(e),
+        }
+        let (source, association, tunnel) = opened.map_err(|e| id.attach(e))?;
+        if let Some(hooks) = &handler.hooks {
+            hooks.on_established(&session, &tunnel);
+        }
+        let _listing = session::Listing::current(id, &tunnel);
+        let revoked = handler.policy.revoked(session.clone(), handler.reevaluate_policy);
+        let (summary, relayed) = association.relay(id, source, &handler.guard, limits, revoked, recorder).await;
+        recorder.closed(&summary);
+        if let Some(hooks) = &handler.hooks {
+            hooks.on_close(&session, &summary);
+        }
+        relayed.map_err(|e| id.attach(e))?;
+
+        Ok(summary)
+    }
+
+    // Opens the UDP association, unless the relay refuses it, and replies to the source with the address that
+    // the association relays on. Returns the addresses of the association along with it.
+    async fn open_association(
+        self,
+        config: &UdpRelayConfig,
+    ) -> Result<(&'a mut dyn AsyncStream, UdpAssociation, TunnelInfo)> {
+        let PendingSession {
+            handler,
+            reader,
+            request,
+            id,
+            source_addrs,
+            ..
+        } = self;
+        // Nothing but the end of the control connection matters after the request, so read-ahead is dropped.
+        let source = reader.into_inner();
+
+        let opened = UdpAssociation::open(config, &request.destination, source_addrs, handler.recorder()).await;
+        let association = match opened {
+            Ok(association) => association,
+            Err(e) => {
+                debug!("[{}] Refusing the UDP association: {}", id, e);
+                handler.write_reply(source, Socks5Reply::from_error(&e)).await?;
+                return Err(e);
+            }
+        };
+        let bound = association.local_addr()?;
+        debug!("[{}] Relaying the datagrams of {} on {}.", id, request.destination, bound);
+        handler.write_binding(source, &Address::Ip(bound)).await?;
+        source.flush().await?;
+
+        let tunnel = TunnelInfo {
+            peer: source_addrs.peer,
+            local: source_addrs.local,
+            requested: request.destination,
+            connected: None,
+            outbound_local: Some(bound),
+            connect_info: None,
+        };
+
+        Ok((source, association, tunnel))
+    }
+
+    // Describes the session to the hooks of the handler.
+    fn info(&self) -> SessionInfo {
+        SessionInfo {
+            id: self.id,
+            destination: self.request.destination.clone(),
+            identity: self.identity.clone(),
+            baggage: Baggage::new(),
+        }
+    }
+
+    // Asks the hooks of the handler whether the session is mirrored.
+    fn mirror(&self) -> Option<Mirror> {
+        self.handler.hooks.as_ref()?.mirror(&self.info())
+    }
+
+    // Connects to the destination, unless a stream to it is given, and completes the handshake with the source.
+    // Returns the addresses of both legs along with the streams.
+    async fn establish(
+        self,
+        outbound: Option<Box<dyn AsyncStream>>,
+    ) -> Result<(&'a mut dyn AsyncStream, Box<dyn AsyncStream>, TunnelInfo)> {
+        let recorder = self.handler.recorder();
+        let established = self.complete(outbound).await;
+        match &established {
+            Ok(_) => recorder.succeeded(),
+            Err(e) => recorder.failed(e),
+        }
+
+        established
+    }
+
+    // Establishes the session like `establish`, without measuring the outcome of the handshake.
+    async fn complete(
+        self,
+        outbound: Option<Box<dyn AsyncStream>>,
+    ) -> Result<(&'a mut dyn AsyncStream, Box<dyn AsyncStream>, TunnelInfo)> {
+        let mirror = self.mirror();
+        let PendingSession {
+            handler,
+            mut reader,
+            request,
+            id,
+            source_addrs,
+            ..
+        } = self;
+
+        let connected = match outbound {
+            Some(outbound) => Ok((outbound, ConnectionAddrs::default())),
+            None => {
+                debug!("[{}] Connecting to {}.", id, request.destination);
+                handler.connect_outbound(&request.destination).await
+            }
+        };
+        let (mut destination, destination_addrs) = match connected {
+            Ok(connected) => connected,
+            Err(e) => {
+                // Notify source why the connection could not be set up.
+                handler.write_reply(&mut reader, Socks5Reply::from_error(&e)).await?;
+                return Err(e);
+            }
+        };
+        if let Some(mirror) = mirror {
+            destination = Box::new(MirroredStream::new(destination, mirror.start(id)));
+        }
+
+        // Bytes the client sent along with the request belong to the destination.
+        let source = util::forward_read_ahead(reader, &mut destination).await?;
+
+        // Notify source that the connection has been set up.
+        handler.write_reply(source, Socks5Reply::Success).await?;
+        source.flush().await?;
+
+        let tunnel = TunnelInfo {
+            peer: source_addrs.peer,
+            local: source_addrs.local,
+            requested: request.destination,
+            connected: destination_addrs.peer,
+            outbound_local: destination_addrs.local,
+            connect_info: destination_addrs.connect_info,
+        };
+
+        Ok((source, destination, tunnel))
+    }
 }
 
 #[async_trait]
@@ -45,20 +877,56 @@ impl SocksHandler for Socks5Handler {
     ///
     /// # Arguments
     ///
-    /// * `source` - The TCP stream representing the client connection.
+    /// * `source` - The stream representing the client connection.
     ///
     /// # Returns
     ///
     /// A `Result` indicating success or an error.
     async fn accept_request(
         &self,
-        source: &mut TcpStream,
+        source: &mut dyn AsyncStream,
     ) -> Result<()> {
-        let mut destination = self.setup(source).await?;
+        self.accept_request_from(source, None, ConnectionAddrs::default()).await
+    }
 
-        // Start bidirectional copy, after this the connection closes.
-        tokio::io::copy_bidirectional(source, &mut destination).await?;
+    /// Accepts a SOCKS5 client request from a source that its transport authenticated already.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The stream representing the client connection.
+    /// * `identity` - The identity of the source, unless it authenticates with a username as well.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or an error.
+    async fn accept_request_as(
+        &self,
+        source: &mut dyn AsyncStream,
+        identity: Option<String>,
+    ) -> Result<()> {
+        self.accept_request_from(source, identity, ConnectionAddrs::default()).await
+    }
 
+    /// Accepts a SOCKS5 client request like `accept_request_as`, from a source connected over the given
+    /// addresses.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The stream representing the client connection.
+    /// * `identity` - The identity of the source, unless it authenticates with a username as well.
+    /// * `addrs` - The addresses of the connection of the source, for the `TunnelInfo` of the session.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or an error.
+    async fn accept_request_from(
+        &self,
+        source: &mut dyn AsyncStream,
+        identity: Option<String>,
+        addrs: ConnectionAddrs,
+    ) -> Result<()> {
+        let (session, _) = self.accept_as(source, identity).await?;
+        session.with_source_addrs(addrs).proxy_to_destination().await?;
         Ok(())
     }
 
@@ -66,17 +934,27 @@ impl SocksHandler for Socks5Handler {
     ///
     /// # Arguments
     ///
-    /// * `source` - The TCP stream representing the client connection.
+    /// * `source` - The stream representing the client connection.
     ///
     /// # Returns
     ///
     /// A `Result` indicating success or an error.
     async fn refuse_request(
         &self,
-        source: &mut TcpStream,
+        source: &mut dyn AsyncStream,
     ) -> Result<()> {
         // Notify source that the connection is refused.
-        socks5::write_reply(source, Socks5Reply::ConnectionRefused).await?;
+        self.write_reply(source, Socks5Reply::ConnectionRefused).await?;
+
+        Ok(())
+    }
+
+    /// Refuses a SOCKS5 client request with a general failure, as the server has no room for it.
+    async fn refuse_overloaded(
+        &self,
+        source: &mut dyn AsyncStream,
+    ) -> Result<()> {
+        self.write_reply(source, Socks5Reply::GeneralFailure).await?;
 
         Ok(())
     }
@@ -85,95 +963,498 @@ impl SocksHandler for Socks5Handler {
     ///
     /// # Arguments
     ///
-    /// * `source` - The TCP stream representing the client connection.
+    /// * `source` - The stream representing the client connection.
     ///
     /// # Returns
     ///
-    /// A `Result` containing a TCP stream representing the destination connection.
+    /// A `Result` containing a stream representing the destination connection. Errors carry the
+    /// connection ID of the session.
     async fn setup(
         &self,
-        source: &mut TcpStream,
-    ) -> Result<TcpStream> {
-        let mut request = [0; 2];
-        source.read_exact(&mut request).await?;
+        source: &mut dyn AsyncStream,
+    ) -> Result<Box<dyn AsyncStream>> {
+        let (session, request) = self.accept(source).await?;
+        let id = session.id();
+        if request.command == Socks5Command::UdpAssociate {
+            // A UDP association has no stream to hand out.
+            session.reject(Socks5Reply::CommandNotSupported).await?;
+            return Err(id.attach(SocksError::CommandNotSupported(SOCKS_CMD_UDP_ASSOCIATE).into()));
+        }
+        let (_, destination, _) = session.establish(None).await.map_err(|e| id.attach(e))?;
+
+        Ok(destination)
+    }
+
+    /// Replaces the policy of the handler, for the requests accepted from now on.
+    ///
+    /// # Arguments
+    ///
+    /// * `policy` - The new policy.
+    ///
+    /// # Returns
+    ///
+    /// An `Ok(())`, as the handler supports policies.
+    fn update_policy(
+        &self,
+        policy: Policy,
+    ) -> Result<()> {
+        self.policy.update(policy);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::io::{AsyncReadExt, DuplexStream};
+    use tokio::net::{TcpListener, TcpStream, UdpSocket};
+    use tokio::task::JoinHandle;
+
+    use super::*;
+    use crate::metrics::{InMemoryMetrics, UDP_ASSOCIATIONS_REFUSED};
+    use crate::session::CloseReason;
+    use crate::test_util::StallingResolver;
+    use crate::udp::UdpRefusal;
+    use crate::wire::vectors;
+
+    // Tests that a connect that doesn't complete in time, resolution included, is answered with HostUnreachable.
+    #[tokio::test(start_paused = true)]
+    async fn test_connect_timeout_reply() -> Result<()> {
+        let handler = Socks5Handler::default()
+            .with_resolver(Arc::new(StallingResolver))
+            .with_connect_timeout(Duration::from_secs(5));
 
-        let socks_version = request[0];
+        let (mut client, mut source) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move { handler.accept_request(&mut source).await });
 
-        if socks_version != SOCKS_VER_5 {
-            bail!("Client uses a different SOCKS version: {}.", socks_version);
+        let mut handshake = vec![SOCKS_VER_5, 0x01, SOCKS_AUTH_NOT_REQUIRED];
+        handshake.extend_from_slice(&[SOCKS_VER_5, SOCKS_CMD_CONNECT, SOCKS_RSV, SOCKS_ATYP_DOMAINNAME, 17]);
+        handshake.extend_from_slice(b"blackhole.invalid");
+        handshake.extend_from_slice(&80u16.to_be_bytes());
+        client.write_all(&handshake).await?;
+
+        let started = tokio::time::Instant::now();
+        let mut reply = [0; 12];
+        client.read_exact(&mut reply).await?;
+        assert_eq!(reply[3], Socks5Reply::HostUnreachable as u8);
+        assert!(started.elapsed() < Duration::from_secs(6));
+
+        let error = server.await?.unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(SocksError::ConnectTimeout(_))));
+        Ok(())
+    }
+
+    // Tests that a name is resolved before it is handed to a custom connector only when resolved locally, within the
+    // connect timeout.
+    #[tokio::test(start_paused = true)]
+    async fn test_resolution_policy() -> Result<()> {
+        let mut request = vec![SOCKS_VER_5, 0x01, SOCKS_AUTH_NOT_REQUIRED];
+        request.extend_from_slice(&[SOCKS_VER_5, SOCKS_CMD_CONNECT, SOCKS_RSV, SOCKS_ATYP_DOMAINNAME, 11]);
+        request.extend_from_slice(b"example.com");
+        request.extend_from_slice(&80u16.to_be_bytes());
+
+        for (resolution, timeout, expected) in [
+            (ResolutionPolicy::PassThroughWhenChaining, 5, Some(Address::new("example.com", 80))),
+            (ResolutionPolicy::ResolveLocal, 90, Some(Address::new("192.0.2.1", 80))),
+            (ResolutionPolicy::ResolveLocal, 5, None),
+        ] {
+            let connector = crate::test_util::MockConnector::new();
+            let handler = Socks5Handler::default()
+                .with_connector(Arc::new(connector.clone()))
+                .with_resolver(Arc::new(StallingResolver))
+                .with_resolution_policy(resolution)
+                .with_connect_timeout(Duration::from_secs(timeout));
+            let (mut client, mut source) = tokio::io::duplex(4096);
+            let server = tokio::spawn(async move { handler.accept_request(&mut source).await });
+            client.write_all(&request).await?;
+
+            let mut reply = [0; 12];
+            client.read_exact(&mut reply).await?;
+            match expected {
+                Some(expected) => assert_eq!(connector.destinations()[0].address, expected),
+                None => {
+                    assert_eq!(reply[3], Socks5Reply::HostUnreachable as u8);
+                    assert!(matches!(server.await?.unwrap_err().downcast_ref(), Some(SocksError::ConnectTimeout(_))));
+                }
+            }
         }
 
-        // Get all authentication methods the client proposes.
-        let nmethods = request[1] as usize;
+        Ok(())
+    }
+
+    // Tests that a destination without an address of the required family is answered with the right reply.
+    #[tokio::test]
+    async fn test_family_preference_reply() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = listener.local_addr()?;
+
+        let handler = Socks5Handler::default().with_family_preference(AddressFamilyPreference::Ipv6Only);
+        let server = tokio::spawn(async move {
+            let (mut incoming, _) = listener.accept().await.unwrap();
+            handler.accept_request(&mut incoming).await
+        });
+
+        let mut stream = TcpStream::connect(proxy_addr).await?;
+        stream.write_all(&[SOCKS_VER_5, 0x01, SOCKS_AUTH_NOT_REQUIRED]).await?;
+        stream.write_all(&[SOCKS_VER_5, SOCKS_CMD_CONNECT, SOCKS_RSV, SOCKS_ATYP_IPV4, 127, 0, 0, 1, 0, 80]).await?;
+
+        let mut reply = [0; 12];
+        stream.read_exact(&mut reply).await?;
+        assert_eq!(reply[3], SOCKS_REP_ADDRESS_TYPE_NOT_SUPPORTED);
+
+        assert!(server.await?.is_err());
+        Ok(())
+    }
+
+    // Tests that an accepted request can be rejected with a reply of the caller's choice, or proxied through a stream
+    // the caller connected.
+    #[tokio::test]
+    async fn test_accept() -> Result<()> {
+        let request = vectors::socks5_connect_ipv4();
+        let handshake = [vectors::socks5_greeting_no_auth().bytes, request.bytes].concat();
+        let handler = Socks5Handler::default();
+
+        let (mut client, mut source) = tokio::io::duplex(4096);
+        client.write_all(&handshake).await?;
+        let (session, accepted) = handler.accept(&mut source).await?;
+        assert_eq!(accepted, request.value);
+        session.reject(Socks5Reply::ConnectionNotAllowed).await?;
 
-        let mut methods = vec![0; nmethods];
-        source.read_exact(&mut methods).await?;
+        let selection = vectors::socks5_selection_no_auth();
+        let expected = [selection.bytes, vectors::socks5_reply_connection_not_allowed().bytes];
+        let mut replies = [0; 12];
+        client.read_exact(&mut replies).await?;
+        assert_eq!(replies[..], expected.concat());
 
-        let method = if self.credentials.is_some() && methods.contains(&SOCKS_AUTH_USERNAME_PASSWORD) {
-            SOCKS_AUTH_USERNAME_PASSWORD
-        } else if methods.contains(&SOCKS_AUTH_NOT_REQUIRED) {
-            SOCKS_AUTH_NOT_REQUIRED
-        } else {
-            SOCKS_AUTH_NO_ACCEPTABLE_METHODS
+        let (mut client, mut source) = tokio::io::duplex(4096);
+        let (outbound, mut destination) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            let (session, _) = handler.accept(&mut source).await?;
+            session.proxy_via(outbound).await
+        });
+        client.write_all(&handshake).await?;
+        client.write_all(b"hello").await?;
+
+        let mut received = [0; 5];
+        destination.read_exact(&mut received).await?;
+        assert_eq!(&received, b"hello");
+        client.read_exact(&mut replies).await?;
+        assert_eq!(replies[3], Socks5Reply::Success as u8);
+
+        Ok(())
+    }
+
+    // Tests the username/password sub-negotiation of a client that sends version 0x05, under both policies, and
+    // that wrong credentials are rejected.
+    #[tokio::test]
+    async fn test_auth_version_policy() -> Result<()> {
+        let handshake = |version: u8, password: &[u8]| {
+            let mut handshake = vec![SOCKS_VER_5, 0x01, SOCKS_AUTH_USERNAME_PASSWORD, version, 0x04];
+            handshake.extend_from_slice(b"user");
+            handshake.push(password.len() as u8);
+            handshake.extend_from_slice(password);
+            let request = [SOCKS_VER_5, SOCKS_CMD_CONNECT, SOCKS_RSV, SOCKS_ATYP_IPV4, 192, 0, 2, 1, 0, 80];
+            handshake.extend_from_slice(&request);
+            handshake
         };
+        let handler = Socks5Handler::default().with_credentials(Credentials::new("user", "secret"));
 
-        info!("Use authentication method: {}", method);
+        let (mut client, mut source) = tokio::io::duplex(4096);
+        client.write_all(&handshake(SOCKS_VER_5, b"secret")).await?;
+        let (session, _) = handler.accept(&mut source).await?;
+        session.reject(Socks5Reply::ConnectionNotAllowed).await?;
 
-        let response = [SOCKS_VER_5, method];
-        source.write(&response).await?;
+        let mut replies = [0; 4];
+        client.read_exact(&mut replies).await?;
+        assert_eq!(replies, [SOCKS_VER_5, SOCKS_AUTH_USERNAME_PASSWORD, SOCKS_AUTH_VER, SOCKS_AUTH_SUCCESS]);
 
-        // Enter method-specific sub-negotiation
-        if method == SOCKS_AUTH_USERNAME_PASSWORD {
-            let mut request = [0; 2];
-            source.read_exact(&mut request).await?;
-
-            let auth_version = request[0];
-            if auth_version != SOCKS_AUTH_VER {
-                bail!(
-                    "Client uses a different authentication method version: {}.",
-                    auth_version
-                );
-            }
+        let (mut client, mut source) = tokio::io::duplex(4096);
+        client.write_all(&handshake(SOCKS_AUTH_VER, b"wrong")).await?;
+        assert!(handler.accept(&mut source).await.is_err());
+        client.read_exact(&mut replies).await?;
+        assert_eq!(replies[3], SOCKS_AUTH_FAILED);
 
-            let ulen = request[1] as usize;
-            let mut uname = vec![0; ulen];
-            source.read_exact(&mut uname).await?;
+        let strict = handler.with_auth_version_policy(AuthVersionPolicy::Strict);
+        let (mut client, mut source) = tokio::io::duplex(4096);
+        client.write_all(&handshake(SOCKS_VER_5, b"secret")).await?;
+        let error = strict.accept(&mut source).await.err().unwrap();
+        assert!(matches!(error.downcast_ref(), Some(SocksError::AuthVersionMismatch(0x05))));
 
-            let plen = request[1] as usize;
-            let mut passwd = vec![0; plen];
-            source.read_exact(&mut passwd).await?;
+        Ok(())
+    }
 
-            let status = if let Some(Credentials { username, password }) = &self.credentials {
-                if &uname != username || &passwd != password {
-                    SOCKS_AUTH_SUCCESS
-                } else {
-                    0x01u8
-                }
-            } else {
-                unreachable!()
-            };
+    // Tests that malformed method negotiations are answered with 0xFF and rejected with the matching error, instead
+    // of leaving the client hanging.
+    #[tokio::test(start_paused = true)]
+    async fn test_malformed_negotiation() -> Result<()> {
+        let handler = Socks5Handler::default();
+        let cases = vec![
+            (vec![SOCKS_VER_5, 0x00], NegotiationError::NoMethods),
+            (vec![SOCKS_VER_5, 0x02, 0x00, 0x00], NegotiationError::DuplicateMethod(0x00)),
+            (
+                vec![SOCKS_VER_5, 0x03, 0x00],
+                NegotiationError::TruncatedMethods {
+                    declared: 3,
+                    received: 1,
+                },
+            ),
+        ];
 
-            let response = [SOCKS_VER_5, status];
-            source.write(&response).await?;
+        for (greeting, expected) in cases {
+            let (mut client, mut source) = tokio::io::duplex(4096);
+            client.write_all(&greeting).await?;
+            let error = handler.accept(&mut source).await.err().unwrap();
+            match error.downcast_ref() {
+                Some(SocksError::MalformedNegotiation(error)) => assert_eq!(*error, expected),
+                _ => panic!("Unexpected error: {}", error),
+            }
 
-            ensure!(status == SOCKS_AUTH_SUCCESS, "Username/password authentication failed.");
+            let mut reply = [0; 2];
+            client.read_exact(&mut reply).await?;
+            assert_eq!(reply, [SOCKS_VER_5, SOCKS_AUTH_NO_ACCEPTABLE_METHODS]);
         }
 
-        let mut request = [0; 3];
-        source.read_exact(&mut request).await?;
+        Ok(())
+    }
+
+    // Tests that the most preferred method that the client offers and the handler can perform is selected, and that
+    // a client without one is answered with 0xFF, with the request glued to the greeting in every case.
+    #[tokio::test]
+    async fn test_method_preference() -> Result<()> {
+        let credentials = Credentials::new("user", "secret");
+        let cases = vec![
+            (Socks5Handler::default(), vec![0x02, 0x00], SOCKS_AUTH_NOT_REQUIRED),
+            (Socks5Handler::default(), vec![0x02, 0x01], SOCKS_AUTH_NO_ACCEPTABLE_METHODS),
+            (
+                Socks5Handler::default().with_credentials(credentials.clone()),
+                vec![0x00, 0x02],
+                SOCKS_AUTH_USERNAME_PASSWORD,
+            ),
+            (
+                Socks5Handler::default()
+                    .with_credentials(credentials.clone())
+                    .with_method_preference(vec![SOCKS_AUTH_NOT_REQUIRED, SOCKS_AUTH_USERNAME_PASSWORD]),
+                vec![0x02, 0x00],
+                SOCKS_AUTH_NOT_REQUIRED,
+            ),
+            (
+                Socks5Handler::default()
+                    .with_credentials(credentials.clone())
+                    .with_method_preference(vec![SOCKS_AUTH_USERNAME_PASSWORD]),
+                vec![0x00],
+                SOCKS_AUTH_NO_ACCEPTABLE_METHODS,
+            ),
+        ];
+
+        for (handler, methods, expected) in cases {
+            let mut handshake = vec![];
+            wire::encode_socks5_greeting(&methods, &mut handshake);
+            if expected == SOCKS_AUTH_USERNAME_PASSWORD {
+                wire::encode_socks5_credentials(&credentials, &mut handshake);
+            }
+            let request = [SOCKS_VER_5, SOCKS_CMD_CONNECT, SOCKS_RSV, SOCKS_ATYP_IPV4, 192, 0, 2, 1, 0, 80];
+            handshake.extend_from_slice(&request);
 
-        let command = request[1];
-        if command != SOCKS_CMD_CONNECT {
-            unimplemented!();
+            let (mut client, mut source) = tokio::io::duplex(4096);
+            client.write_all(&handshake).await?;
+            let accepted = handler.accept(&mut source).await;
+
+            let mut selection = [0; 2];
+            client.read_exact(&mut selection).await?;
+            assert_eq!(selection, [SOCKS_VER_5, expected], "offered {:?}", methods);
+            match accepted {
+                Ok((_, request)) => assert_eq!(request.destination, Address::new("192.0.2.1", 80)),
+                Err(e) => {
+                    assert_eq!(expected, SOCKS_AUTH_NO_ACCEPTABLE_METHODS);
+                    assert!(matches!(e.downcast_ref(), Some(SocksError::NoAcceptableAuthMethod)));
+                }
+            }
         }
 
-        let destination = addresses::read_address(source).await?;
-        let destination = TcpStream::connect(destination.to_string()).await?;
+        Ok(())
+    }
 
-        // Notify source that the connection has been set up.
-        socks5::write_reply(source, Socks5Reply::Success).await?;
-        source.flush().await?;
+    // Tests that data pipelined right behind the request is not lost to the handshake buffer.
+    #[tokio::test]
+    async fn test_pipelined_data_reaches_destination() -> Result<()> {
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let port = destination.local_addr()?.port().to_be_bytes();
 
-        Ok(destination)
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let (mut incoming, _) = listener.accept().await.unwrap();
+            Socks5Handler::default().accept_request(&mut incoming).await.unwrap();
+        });
+
+        let mut handshake = vec![SOCKS_VER_5, 0x01, SOCKS_AUTH_NOT_REQUIRED];
+        handshake.extend([SOCKS_VER_5, SOCKS_CMD_CONNECT, SOCKS_RSV, SOCKS_ATYP_IPV4, 127, 0, 0, 1, port[0], port[1]]);
+        handshake.extend(b"hello");
+
+        let mut stream = TcpStream::connect(proxy_addr).await?;
+        stream.write_all(&handshake).await?;
+
+        let (mut incoming, _) = destination.accept().await?;
+        let mut received = [0; 5];
+        incoming.read_exact(&mut received).await?;
+        assert_eq!(&received, b"hello");
+
+        let mut replies = [0; 12];
+        stream.read_exact(&mut replies).await?;
+        assert_eq!(replies[3], Socks5Reply::Success as u8);
+        Ok(())
+    }
+
+    // Tests that a client that writes its negotiation, credentials, request, and data in a single write, before
+    // reading any reply, is served through a dispatcher, and receives every reply in order.
+    #[tokio::test]
+    async fn test_pipelined_handshake() -> Result<()> {
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let port = destination.local_addr()?.port().to_be_bytes();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = listener.local_addr()?;
+        let handler = Socks5Handler::default().with_credentials(Credentials::new("user", "secret"));
+        let dispatcher = crate::ProtocolDispatcher::new().with_socks5(Arc::new(handler));
+        tokio::spawn(async move {
+            let (mut incoming, _) = listener.accept().await.unwrap();
+            dispatcher.accept_request(&mut incoming).await.unwrap();
+        });
+
+        let mut handshake = vec![SOCKS_VER_5, 0x02, SOCKS_AUTH_NOT_REQUIRED, SOCKS_AUTH_USERNAME_PASSWORD];
+        handshake.extend([SOCKS_AUTH_VER, 0x04]);
+        handshake.extend(b"user");
+        handshake.push(0x06);
+        handshake.extend(b"secret");
+        handshake.extend([SOCKS_VER_5, SOCKS_CMD_CONNECT, SOCKS_RSV, SOCKS_ATYP_IPV4, 127, 0, 0, 1, port[0], port[1]]);
+        handshake.extend(b"hello");
+
+        let mut stream = TcpStream::connect(proxy_addr).await?;
+        stream.write_all(&handshake).await?;
+
+        let (mut incoming, _) = destination.accept().await?;
+        let mut received = [0; 5];
+        incoming.read_exact(&mut received).await?;
+        assert_eq!(&received, b"hello");
+
+        let mut replies = [0; 14];
+        stream.read_exact(&mut replies).await?;
+        assert_eq!(replies[..4], [SOCKS_VER_5, SOCKS_AUTH_USERNAME_PASSWORD, SOCKS_AUTH_VER, SOCKS_AUTH_SUCCESS]);
+        assert_eq!(replies[4..8], [SOCKS_VER_5, Socks5Reply::Success as u8, SOCKS_RSV, SOCKS_ATYP_IPV4]);
+
+        Ok(())
+    }
+
+    // Starts a UDP association with the handler from the given peer, claiming to send from the given address.
+    // Returns the control connection, the reply code and binding, and the session.
+    async fn start_association(
+        handler: &Socks5Handler,
+        peer: SocketAddr,
+        claimed: Address,
+    ) -> Result<(DuplexStream, u8, Address, JoinHandle<Result<CloseSummary>>)> {
+        let handler = handler.clone();
+        let addrs = ConnectionAddrs {
+            peer: Some(peer),
+            local: Some("127.0.0.1:1080".parse()?),
+            connect_info: None,
+        };
+        let (mut client, mut source) = tokio::io::duplex(4096);
+        let session = tokio::spawn(async move {
+            let (session, _) = handler.accept(&mut source).await?;
+            session.with_source_addrs(addrs).proxy_to_destination().await
+        });
+
+        let mut handshake = vec![];
+        wire::encode_socks5_greeting(&[SOCKS_AUTH_NOT_REQUIRED], &mut handshake);
+        wire::encode_socks5_request(&Socks5Request::new(SOCKS_CMD_UDP_ASSOCIATE, claimed), &mut handshake);
+        client.write_all(&handshake).await?;
+        let mut selection = [0; 2];
+        client.read_exact(&mut selection).await?;
+        let (code, binding) = wire::read_message(&mut client, &mut BytesMut::new(), wire::parse_socks5_reply).await?;
+
+        Ok((client, code, binding, session))
+    }
+
+    // Tests that datagrams are relayed both ways through an association, which is torn down along with its control
+    // connection once it relayed nothing for the idle timeout.
+    #[tokio::test]
+    async fn test_udp_idle_expiry() -> Result<()> {
+        let config = UdpRelayConfig::new().with_idle_timeout(Duration::from_millis(300));
+        let handler = Socks5Handler::default().with_udp_relay(config.clone());
+        let remote = UdpSocket::bind("127.0.0.1:0").await?;
+        let client = UdpSocket::bind("127.0.0.1:0").await?;
+
+        let unspecified = Address::new("0.0.0.0", 0);
+        let started = start_association(&handler, client.local_addr()?, unspecified).await?;
+        let (mut control, code, binding, session) = started;
+        assert_eq!(code, SOCKS_REP_SUCCEEDED);
+        let Address::Ip(relay) = binding else {
+            panic!("Unexpected binding: {}", binding);
+        };
+        assert_eq!(config.active(), 1);
+
+        let mut datagram = vec![];
+        wire::encode_socks5_udp_header(0, &Address::Ip(remote.local_addr()?), &mut datagram);
+        datagram.extend_from_slice(b"ping");
+        client.send_to(&datagram, relay).await?;
+        let mut buffer = [0; 64];
+        let (length, from) = remote.recv_from(&mut buffer).await?;
+        assert_eq!(&buffer[..length], b"ping");
+
+        remote.send_to(b"pong", from).await?;
+        let length = client.recv(&mut buffer).await?;
+        let mut expected = vec![];
+        wire::encode_socks5_udp_header(0, &Address::Ip(remote.local_addr()?), &mut expected);
+        expected.extend_from_slice(b"pong");
+        assert_eq!(&buffer[..length], expected.as_slice());
+
+        let started = tokio::time::Instant::now();
+        assert_eq!(control.read(&mut buffer).await?, 0);
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        let summary = session.await??;
+        assert_eq!(summary.reason, CloseReason::IdleTimeout);
+        assert_eq!((summary.sent, summary.received), (4, 4));
+        assert_eq!(config.active(), 0);
+        Ok(())
+    }
+
+    // Tests that associations beyond the cap of a client, or from an unspecified address when that isn't allowed,
+    // are refused and counted, and that the cap frees up once an association ends.
+    #[tokio::test]
+    async fn test_udp_client_cap() -> Result<()> {
+        let metrics = Arc::new(InMemoryMetrics::new());
+        let config = UdpRelayConfig::new().with_max_associations_per_client(1).with_unspecified_client(false);
+        let handler = Socks5Handler::default().with_udp_relay(config.clone()).with_metrics(metrics.clone());
+        let claimed = Address::new("192.0.2.1", 5353);
+
+        let (control, code, _, first) = start_association(&handler, "192.0.2.1:40000".parse()?, claimed.clone()).await?;
+        assert_eq!(code, SOCKS_REP_SUCCEEDED);
+
+        let (_, code, _, second) = start_association(&handler, "192.0.2.1:40001".parse()?, claimed.clone()).await?;
+        assert_eq!(code, SOCKS_REP_CONNECTION_NOT_ALLOWED);
+        let error = second.await?.unwrap_err();
+        let refusal = SocksError::UdpAssociationRefused(UdpRefusal::ClientLimit);
+        assert_eq!(error.downcast_ref::<SocksError>().map(ToString::to_string), Some(refusal.to_string()));
+
+        let (_, code, _, _other) = start_association(&handler, "192.0.2.2:40000".parse()?, claimed.clone()).await?;
+        assert_eq!(code, SOCKS_REP_SUCCEEDED);
+        let unspecified = Address::new("0.0.0.0", 0);
+        let (_, code, _, _) = start_association(&handler, "192.0.2.3:40000".parse()?, unspecified).await?;
+        assert_eq!(code, SOCKS_REP_CONNECTION_NOT_ALLOWED);
+
+        let refused = |reason| {
+            let labels = [("role", "handler"), ("version", "5"), ("reason", reason)];
+            metrics.counter(UDP_ASSOCIATIONS_REFUSED, &labels)
+        };
+        assert_eq!((refused("client_limit"), refused("unspecified_client")), (1, 1));
+        assert_eq!(config.active_for("192.0.2.1".parse()?), 1);
+
+        drop(control);
+        assert_eq!(first.await??.reason, CloseReason::Closed);
+        assert_eq!(config.active_for("192.0.2.1".parse()?), 0);
+        let (_, code, _, _) = start_association(&handler, "192.0.2.1:40002".parse()?, claimed).await?;
+        assert_eq!(code, SOCKS_REP_SUCCEEDED);
+        Ok(())
     }
 }
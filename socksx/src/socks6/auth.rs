@@ -0,0 +1,477 @@
+use std::fmt;
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::{wire, Credentials, PasswordVerifier};
+use crate::credentials::{CredentialSource, DeferredCredentials};
+use crate::socks6::options::{AuthDataOption, AuthMethod};
+use crate::wire::Parsed;
+
+/// How many challenges the client and the handler exchange by default, before giving up on the authentication.
+pub const DEFAULT_MAX_AUTH_ROUNDS: usize = 8;
+
+/// The identity of an authenticated client, for whatever decides what the client may do.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Identity {
+    name: Option<String>,
+}
+
+impl Identity {
+    /// Creates the identity of a client that didn't authenticate.
+    pub fn anonymous() -> Self {
+        Self::default()
+    }
+
+    /// Creates the identity of a client known by the given name, e.g., a username.
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        Self { name: Some(name.into()) }
+    }
+
+    /// Returns the name of the client, if it isn't anonymous.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+}
+
+/// What an `Authenticator` decides on in a round of authentication.
+#[derive(Clone, Copy, Debug)]
+pub struct AuthRequest<'a> {
+    /// The methods the client advertised.
+    pub methods: &'a [AuthMethod],
+    /// The method-specific data the client sent in this round.
+    pub data: &'a [AuthDataOption],
+    /// The challenge sent in the previous round, if any.
+    pub challenge: Option<&'a [u8]>,
+    /// The round, starting at zero for the request itself.
+    pub round: usize,
+}
+
+impl<'a> AuthRequest<'a> {
+    /// Returns the data the client sent for the given method in this round.
+    pub fn data_for(
+        &self,
+        method: AuthMethod,
+    ) -> Option<&'a [u8]> {
+        self.data.iter().find(|data| data.method == method).map(|data| data.data.as_slice())
+    }
+
+    /// Returns whether the client offered the given method, by advertising it or sending data for it.
+    pub fn offers(
+        &self,
+        method: &AuthMethod,
+    ) -> bool {
+        self.methods.contains(method) || self.data.iter().any(|data| &data.method == method)
+    }
+}
+
+/// The decision of an `Authenticator` on a round of authentication.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AuthOutcome {
+    /// The client is authenticated as the identity, with the selected method.
+    Accept(Identity, AuthMethod),
+    /// The client isn't authenticated, for the given reason, which is only logged.
+    Reject(String),
+    /// The client isn't authenticated, for the given machine-readable reason, which the handler reports to the
+    /// client if enabled with `Socks6Handler::with_auth_failure_reasons`, and the given message, which is only
+    /// logged.
+    RejectWith(AuthFailureReason, String),
+    /// The selected method needs another round, in which the client answers the challenge.
+    Continue(AuthMethod, Vec<u8>),
+}
+
+/// Why a SOCKS6 proxy rejected the authentication of a client, reported in the failed authentication reply as a
+/// socksx extension (see `auth_failure_option`).
+///
+/// Codes from `USER_DEFINED_AUTH_FAILURE_REASONS` are left to authenticators, e.g., for reasons specific to a
+/// deployment. Other codes are reserved for this crate.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum AuthFailureReason {
+    /// The credentials, e.g., the username and password, are wrong.
+    BadCredentials,
+    /// The credentials are right, but the account may not be used, e.g., after too many failed attempts.
+    AccountLocked,
+    /// The method isn't supported for this client, e.g., because it has no credentials for it.
+    MethodUnsupported,
+    /// The token, e.g., a bearer token, has expired.
+    TokenExpired,
+    /// A reason defined by the authenticator, created with `user_defined`.
+    UserDefined(UserDefinedReason),
+}
+
+/// The code of a reason defined by an authenticator, which is always in `USER_DEFINED_AUTH_FAILURE_REASONS`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct UserDefinedReason(u16);
+
+impl UserDefinedReason {
+    /// Returns the code of the reason.
+    pub fn code(self) -> u16 {
+        self.0
+    }
+}
+
+/// The codes of `AuthFailureReason` that are left to authenticators.
+pub const USER_DEFINED_AUTH_FAILURE_REASONS: std::ops::RangeInclusive<u16> = 0x8000..=0xFFFF;
+
+impl AuthFailureReason {
+    /// Creates a reason defined by the authenticator, failing if the code is outside of
+    /// `USER_DEFINED_AUTH_FAILURE_REASONS`.
+    pub fn user_defined(code: u16) -> Result<Self> {
+        ensure!(
+            USER_DEFINED_AUTH_FAILURE_REASONS.contains(&code),
+            "Reason code {:#06x} isn't in the user-defined range",
+            code
+        );
+
+        Ok(AuthFailureReason::UserDefined(UserDefinedReason(code)))
+    }
+
+    /// Returns the code of the reason, as reported to the client.
+    pub fn code(&self) -> u16 {
+        match self {
+            AuthFailureReason::BadCredentials => 1,
+            AuthFailureReason::AccountLocked => 2,
+            AuthFailureReason::MethodUnsupported => 3,
+            AuthFailureReason::TokenExpired => 4,
+            AuthFailureReason::UserDefined(reason) => reason.code(),
+        }
+    }
+
+    /// Returns the reason with the given code, if the code is assigned or user-defined.
+    pub fn from_code(code: u16) -> Option<Self> {
+        match code {
+            1 => Some(AuthFailureReason::BadCredentials),
+            2 => Some(AuthFailureReason::AccountLocked),
+            3 => Some(AuthFailureReason::MethodUnsupported),
+            4 => Some(AuthFailureReason::TokenExpired),
+            code => AuthFailureReason::user_defined(code).ok(),
+        }
+    }
+}
+
+impl fmt::Display for AuthFailureReason {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        match self {
+            AuthFailureReason::BadCredentials => write!(f, "bad credentials"),
+            AuthFailureReason::AccountLocked => write!(f, "account locked"),
+            AuthFailureReason::MethodUnsupported => write!(f, "method unsupported"),
+            AuthFailureReason::TokenExpired => write!(f, "token expired"),
+            AuthFailureReason::UserDefined(reason) => write!(f, "user-defined reason {:#06x}", reason.code()),
+        }
+    }
+}
+
+/// Decides whether a SOCKS6 client is authenticated, e.g., by checking its credentials against a directory.
+///
+/// `Socks6Handler` drives the exchange: it calls `authenticate` for the request, and again for each answer
+/// to a challenge, until the client is accepted or rejected.
+#[async_trait]
+pub trait Authenticator {
+    /// Returns the methods the authenticator supports, in order of preference.
+    ///
+    /// A rejected client is told which of these it offered, or that none were acceptable.
+    fn methods(&self) -> Vec<AuthMethod>;
+
+    /// Decides on a round of authentication.
+    async fn authenticate(
+        &self,
+        request: AuthRequest<'_>,
+    ) -> AuthOutcome;
+}
+
+/// Accepts every client as anonymous, without authentication.
+#[derive(Clone, Debug, Default)]
+pub struct NoAuth;
+
+#[async_trait]
+impl Authenticator for NoAuth {
+    fn methods(&self) -> Vec<AuthMethod> {
+        vec![AuthMethod::NoAuthentication]
+    }
+
+    async fn authenticate(
+        &self,
+        _request: AuthRequest<'_>,
+    ) -> AuthOutcome {
+        AuthOutcome::Accept(Identity::anonymous(), AuthMethod::NoAuthentication)
+    }
+}
+
+/// Accepts clients that authenticate with the given username and password (RFC 1929), as that username.
+#[derive(Clone, Debug)]
+pub struct StaticUserPass {
+    credentials: Credentials,
+}
+
+impl StaticUserPass {
+    /// Creates an authenticator that accepts the given credentials.
+    pub fn new(credentials: Credentials) -> Self {
+        Self { credentials }
+    }
+}
+
+#[async_trait]
+impl Authenticator for StaticUserPass {
+    fn methods(&self) -> Vec<AuthMethod> {
+        vec![AuthMethod::UsernamePassword]
+    }
+
+    async fn authenticate(
+        &self,
+        request: AuthRequest<'_>,
+    ) -> AuthOutcome {
+        authenticate_user_pass(&self.credentials, request).await
+    }
+}
+
+/// Decides on the username and password (RFC 1929) the client sent with the verifier, accepting the client as
+/// that username. This is how the username/password authenticators authenticate.
+pub async fn authenticate_user_pass(
+    verifier: &(dyn PasswordVerifier + Sync),
+    request: AuthRequest<'_>,
+) -> AuthOutcome {
+    let offered = match request.data_for(AuthMethod::UsernamePassword) {
+        Some(offered) => offered,
+        None => return AuthOutcome::Reject(String::from("No username and password")),
+    };
+
+    match wire::parse_socks5_credentials(offered) {
+        Ok(Parsed::Complete(offered, _)) => {
+            let username = String::from_utf8_lossy(&offered.username).into_owned();
+            if verifier.verify(&offered.username, &offered.password).await {
+                AuthOutcome::Accept(Identity::new(username), AuthMethod::UsernamePassword)
+            } else {
+                let message = format!("Wrong username or password for {}", username);
+                AuthOutcome::RejectWith(AuthFailureReason::BadCredentials, message)
+            }
+        }
+        _ => AuthOutcome::Reject(String::from("Malformed username and password")),
+    }
+}
+
+/// Decides how a `Socks6Client` authenticates to the proxy, e.g., with a token obtained from an identity provider.
+///
+/// The client advertises the methods and sends the data along with the request. If the proxy answers with a
+/// challenge for one of the methods, the client sends the response, until the proxy decides.
+#[async_trait]
+pub trait ClientAuthenticator {
+    /// Returns the methods advertised to the proxy.
+    fn methods(&self) -> Vec<AuthMethod>;
+
+    /// Returns the method-specific data sent along with the request.
+    async fn request_data(&self) -> Result<Vec<AuthDataOption>>;
+
+    /// Returns whether the data of `request_data` accompanies the method advertisement in an option of its own, a
+    /// socksx extension (see `AuthMethodDataOption`), instead of being sent as authentication data options.
+    /// Defaults to false.
+    fn advertises_data(&self) -> bool {
+        false
+    }
+
+    /// Returns the response to a challenge of the proxy for the selected method, in the given round.
+    ///
+    /// By default, challenges aren't expected, and fail the handshake.
+    async fn respond(
+        &self,
+        method: AuthMethod,
+        _challenge: &[u8],
+        _round: usize,
+    ) -> Result<Vec<AuthDataOption>> {
+        bail!("Unexpected authentication challenge for {:?}", method)
+    }
+}
+
+/// Authenticates with a username and password (RFC 1929).
+#[derive(Clone, Debug)]
+pub struct UserPassAuthenticator {
+    credentials: DeferredCredentials,
+    advertised: bool,
+}
+
+impl UserPassAuthenticator {
+    /// Creates an authenticator that sends the given credentials.
+    pub fn new(credentials: Credentials) -> Self {
+        Self {
+            credentials: credentials.into(),
+            advertised: false,
+        }
+    }
+
+    /// Creates an authenticator that sends the credentials of the source, which are read when they're first
+    /// sent, and kept from then on.
+    pub fn from_source(source: CredentialSource) -> Self {
+        Self {
+            credentials: DeferredCredentials::new(source),
+            advertised: false,
+        }
+    }
+
+    /// Sets whether the credentials are attached to the method advertisement, which only socksx proxies
+    /// understand, instead of being sent as an authentication data option.
+    pub fn with_advertised_data(
+        mut self,
+        enabled: bool,
+    ) -> Self {
+        self.advertised = enabled;
+        self
+    }
+}
+
+#[async_trait]
+impl ClientAuthenticator for UserPassAuthenticator {
+    fn methods(&self) -> Vec<AuthMethod> {
+        vec![AuthMethod::UsernamePassword]
+    }
+
+    async fn request_data(&self) -> Result<Vec<AuthDataOption>> {
+        let credentials = self.credentials.get().await?;
+        let Credentials { username, password } = credentials;
+        ensure!(username.len() <= 255, "Username MUST NOT be larger than 255 bytes.");
+        ensure!(password.len() <= 255, "Password MUST NOT be larger than 255 bytes.");
+
+        let mut data = vec![];
+        wire::encode_socks5_credentials(credentials, &mut data);
+        Ok(vec![AuthDataOption::new(AuthMethod::UsernamePassword, data)])
+    }
+
+    fn advertises_data(&self) -> bool {
+        self.advertised
+    }
+}
+
+/// Authenticates with a bearer token, e.g., an OAuth access token.
+///
+/// The token is sent as the data of `AuthMethod::BearerToken`, prefixed with its length as two bytes, since the
+/// option is padded.
+#[derive(Clone, Debug)]
+pub struct BearerTokenAuthenticator {
+    token: String,
+    advertised: bool,
+}
+
+impl BearerTokenAuthenticator {
+    /// Creates an authenticator that sends the given token.
+    pub fn new<S: Into<String>>(token: S) -> Self {
+        Self {
+            token: token.into(),
+            advertised: false,
+        }
+    }
+
+    /// Sets whether the token is attached to the method advertisement, which only socksx proxies understand,
+    /// instead of being sent as an authentication data option.
+    pub fn with_advertised_data(
+        mut self,
+        enabled: bool,
+    ) -> Self {
+        self.advertised = enabled;
+        self
+    }
+}
+
+#[async_trait]
+impl ClientAuthenticator for BearerTokenAuthenticator {
+    fn methods(&self) -> Vec<AuthMethod> {
+        vec![AuthMethod::BearerToken]
+    }
+
+    async fn request_data(&self) -> Result<Vec<AuthDataOption>> {
+        ensure!(self.token.len() <= 0xFFF0, "Bearer token MUST NOT be larger than 65520 bytes.");
+
+        let mut data = Vec::with_capacity(2 + self.token.len());
+        data.extend_from_slice(&(self.token.len() as u16).to_be_bytes());
+        data.extend_from_slice(self.token.as_bytes());
+        Ok(vec![AuthDataOption::new(AuthMethod::BearerToken, data)])
+    }
+
+    fn advertises_data(&self) -> bool {
+        self.advertised
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request<'a>(
+        methods: &'a [AuthMethod],
+        data: &'a [AuthDataOption],
+    ) -> AuthRequest<'a> {
+        AuthRequest {
+            methods,
+            data,
+            challenge: None,
+            round: 0,
+        }
+    }
+
+    // Tests that every client is accepted as anonymous without authentication.
+    #[tokio::test]
+    async fn test_no_auth() {
+        let outcome = NoAuth.authenticate(request(&[], &[])).await;
+        assert_eq!(outcome, AuthOutcome::Accept(Identity::anonymous(), AuthMethod::NoAuthentication));
+    }
+
+    // Tests that the right credentials are accepted as their username, and others rejected.
+    #[tokio::test]
+    async fn test_static_user_pass() {
+        let authenticator = StaticUserPass::new(Credentials::new("user", "secret"));
+        let data = |credentials: Credentials| {
+            let mut data = vec![];
+            wire::encode_socks5_credentials(&credentials, &mut data);
+            vec![AuthDataOption::new(AuthMethod::UsernamePassword, data)]
+        };
+
+        let right = data(Credentials::new("user", "secret"));
+        let outcome = authenticator.authenticate(request(&[], &right)).await;
+        assert_eq!(outcome, AuthOutcome::Accept(Identity::new("user"), AuthMethod::UsernamePassword));
+
+        let wrong = data(Credentials::new("user", "wrong"));
+        let outcome = authenticator.authenticate(request(&[], &wrong)).await;
+        assert!(matches!(outcome, AuthOutcome::RejectWith(AuthFailureReason::BadCredentials, _)));
+
+        let malformed = vec![AuthDataOption::new(AuthMethod::UsernamePassword, vec![0x01, 0x05])];
+        let outcome = authenticator.authenticate(request(&[], &malformed)).await;
+        assert!(matches!(outcome, AuthOutcome::Reject(_)));
+
+        let outcome = authenticator.authenticate(request(&[AuthMethod::UsernamePassword], &[])).await;
+        assert!(matches!(outcome, AuthOutcome::Reject(_)));
+    }
+
+    // Tests that reasons round-trip through their codes, and that only codes in the user-defined range are left to
+    // authenticators.
+    #[test]
+    fn test_auth_failure_reason_codes() {
+        for code in [1, 2, 3, 4, 0x8000, 0xABCD, 0xFFFF] {
+            assert_eq!(AuthFailureReason::from_code(code).map(|reason| reason.code()), Some(code));
+        }
+        assert_eq!(AuthFailureReason::from_code(4), Some(AuthFailureReason::TokenExpired));
+        assert_eq!(AuthFailureReason::from_code(0), None);
+        assert_eq!(AuthFailureReason::from_code(0x7FFF), None);
+
+        let reason = AuthFailureReason::user_defined(0x8001).unwrap();
+        assert!(matches!(reason, AuthFailureReason::UserDefined(reason) if reason.code() == 0x8001));
+        assert!(AuthFailureReason::user_defined(2).is_err());
+    }
+
+    // Tests the data the provided client authenticators send along with the request.
+    #[tokio::test]
+    async fn test_client_authenticators() -> Result<()> {
+        let authenticator = UserPassAuthenticator::new(Credentials::new("user", "secret"));
+        let data = authenticator.request_data().await?;
+        assert_eq!(data[0].data, b"\x01\x04user\x06secret".to_vec());
+        assert!(authenticator.respond(AuthMethod::UsernamePassword, b"", 1).await.is_err());
+
+        let long = UserPassAuthenticator::new(Credentials::new(vec![b'a'; 256], vec![]));
+        assert!(long.request_data().await.is_err());
+
+        let data = BearerTokenAuthenticator::new("token").request_data().await?;
+        assert_eq!(data[0].method, AuthMethod::BearerToken);
+        assert_eq!(data[0].data, b"\x00\x05token".to_vec());
+
+        Ok(())
+    }
+}
@@ -1,5 +1,30 @@
+use std::fmt;
+use std::net::IpAddr;
+
+use num_traits::FromPrimitive;
+
 use crate::addresses::ProxyAddress;
-use crate::socks6::options::{MetadataOption, SocksOption};
+use crate::socks6::options::{MetadataOption, SocksOption, SocksOptions};
+use crate::socks6::Socks6Reply;
+
+/// The reply metadata key under which the number of links a successful request traversed is reported.
+pub const CHAIN_HOPS_METADATA_KEY: u16 = 996;
+/// The reply metadata key under which the index of the link that failed to go further is reported.
+pub const CHAIN_FAILED_HOP_METADATA_KEY: u16 = 995;
+/// The reply metadata key under which the address that link failed to reach is reported.
+pub const CHAIN_FAILED_ADDRESS_METADATA_KEY: u16 = 994;
+/// The reply metadata key under which the reply code that link failed with is reported.
+pub const CHAIN_FAILED_REPLY_METADATA_KEY: u16 = 993;
+
+/// Where `SocksChain::detour` inserts its links, relative to the links after the current one.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum DetourPlacement {
+    /// Right after the current link, so the inserted links are visited next.
+    #[default]
+    BeforeRemaining,
+    /// At the end of the chain, after the links that are already ahead.
+    AfterRemaining,
+}
 
 /// The `SocksChain` struct is used for managing a chain of SOCKS proxy addresses.
 #[derive(Clone, Debug)]
@@ -19,12 +44,15 @@ impl Default for SocksChain {
 }
 
 impl SocksChain {
-    /// Creates a new `SocksChain` with a given index and list of proxy addresses.
-    pub fn new(
+    /// Creates a new `SocksChain` with a given index and list of proxy addresses, e.g., a `ChainSpec`.
+    pub fn new<L: Into<Vec<ProxyAddress>>>(
         index: usize,
-        links: Vec<ProxyAddress>,
+        links: L,
     ) -> Self {
-        Self { index, links }
+        Self {
+            index,
+            links: links.into(),
+        }
     }
 
     /// Returns a reference to the current `ProxyAddress` based on the index.
@@ -50,23 +78,48 @@ impl SocksChain {
         link
     }
 
-    /// Inserts additional `ProxyAddress`es into the chain at the current position.
+    /// Inserts additional `ProxyAddress`es into the chain, either after the current link or at the end.
     /// If the chain is empty, appends the root and then the new links.
+    ///
+    /// Links that are the same hop as the current link, or as a link ahead of it, are skipped, and so are
+    /// repeated links, so the chain doesn't revisit a proxy. Links are the same hop if they have the same
+    /// version and port, and the same host regardless of case, a trailing dot, or how an IP address is written.
+    ///
+    /// # Parameters
+    /// - `links`: The links to insert, in order.
+    /// - `placement`: Whether the links go before or after the links that are already ahead.
+    ///
+    /// # Returns
+    /// The number of links that were inserted.
     pub fn detour(
         &mut self,
         links: &[ProxyAddress],
-    ) {
-        let links = links.iter().cloned();
-
+        placement: DetourPlacement,
+    ) -> usize {
         if self.links.is_empty() {
             // This means we're currently at the root.
             // We'll append ourself as the root link.
             self.links.push(ProxyAddress::root());
-            self.links.extend(links);
-        } else {
-            let position = self.index + 1..self.index + 1;
-            self.links.splice(position, links);
         }
+
+        let mut inserted: Vec<ProxyAddress> = vec![];
+        for link in links {
+            let visited = self.links[self.index..].iter().chain(&inserted).any(|other| same_hop(link, other));
+            if !visited {
+                inserted.push(link.clone());
+            }
+        }
+
+        let count = inserted.len();
+        match placement {
+            DetourPlacement::BeforeRemaining => {
+                let position = self.index + 1..self.index + 1;
+                self.links.splice(position, inserted);
+            }
+            DetourPlacement::AfterRemaining => self.links.extend(inserted),
+        }
+
+        count
     }
 
     /// Converts the `SocksChain` into a vector of `SocksOption`s.
@@ -87,6 +140,90 @@ impl SocksChain {
     }
 }
 
+// Returns whether both addresses lead to the same proxy, comparing hosts case-insensitively, without a trailing
+// dot, and IP addresses by value, e.g., `[::1]` and `0:0:0:0:0:0:0:1`.
+fn same_hop(
+    a: &ProxyAddress,
+    b: &ProxyAddress,
+) -> bool {
+    let host = |link: &ProxyAddress| {
+        let host = link.host.trim_end_matches('.');
+        match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+            Ok(ip) => ip.to_string(),
+            Err(_) => host.to_ascii_lowercase(),
+        }
+    };
+
+    a.socks_version == b.socks_version && a.port == b.port && host(a) == host(b)
+}
+
+/// Where a chained request failed, as reported back to the originating client in the reply metadata.
+///
+/// The link that failed to reach the next link, or the destination, reports it. The links before it relay
+/// the report unchanged.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ChainFailure {
+    /// The index of the link that failed to go further, within the chain's links.
+    pub hop: usize,
+    /// The address of the next link, or the destination, that couldn't be reached.
+    pub address: String,
+    /// The reply code that link failed with.
+    pub reply: u8,
+}
+
+impl ChainFailure {
+    /// Creates a new `ChainFailure`.
+    pub fn new(
+        hop: usize,
+        address: String,
+        reply: u8,
+    ) -> Self {
+        Self { hop, address, reply }
+    }
+
+    /// Converts the failure into the metadata options that report it.
+    pub fn as_options(&self) -> Vec<SocksOption> {
+        vec![
+            MetadataOption::new(CHAIN_FAILED_HOP_METADATA_KEY, self.hop.to_string()).wrap(),
+            MetadataOption::new(CHAIN_FAILED_ADDRESS_METADATA_KEY, self.address.clone()).wrap(),
+            MetadataOption::new(CHAIN_FAILED_REPLY_METADATA_KEY, self.reply.to_string()).wrap(),
+        ]
+    }
+
+    /// Reads a failure from the options of a reply, if they report one completely.
+    pub fn from_options(options: &[SocksOption]) -> Option<Self> {
+        let metadata = options.metadata();
+
+        Some(Self {
+            hop: metadata.get(&CHAIN_FAILED_HOP_METADATA_KEY)?.parse().ok()?,
+            address: metadata.get(&CHAIN_FAILED_ADDRESS_METADATA_KEY)?.to_string(),
+            reply: metadata.get(&CHAIN_FAILED_REPLY_METADATA_KEY)?.parse().ok()?,
+        })
+    }
+}
+
+impl fmt::Display for ChainFailure {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        match Socks6Reply::from_u8(self.reply) {
+            Some(reply) => write!(f, "link {} failed to reach {}: {:?}", self.hop, self.address, reply),
+            None => write!(f, "link {} failed to reach {}: reply {}", self.hop, self.address, self.reply),
+        }
+    }
+}
+
+/// Converts the number of links a successful request traversed into the metadata option that reports it.
+pub fn hops_option(hops: usize) -> SocksOption {
+    MetadataOption::new(CHAIN_HOPS_METADATA_KEY, hops.to_string()).wrap()
+}
+
+/// Reads the number of links a successful request traversed from the options of a reply, if reported.
+pub fn hops_from_options(options: &[SocksOption]) -> Option<usize> {
+    options.metadata().get(&CHAIN_HOPS_METADATA_KEY)?.parse().ok()
+}
+
 // Test cases for `SocksChain`.
 #[cfg(test)]
 mod tests {
@@ -137,7 +274,11 @@ mod tests {
     #[test]
     pub fn test_detour_empty_chain() {
         let mut chain = SocksChain::default();
-        chain.detour(&[ProxyAddress::new(6, String::from("localhost"), 1, None)]);
+        let inserted = chain.detour(
+            &[ProxyAddress::new(6, String::from("localhost"), 1, None)],
+            DetourPlacement::BeforeRemaining,
+        );
+        assert_eq!(inserted, 1);
         assert_eq!(chain.index, 0);
         assert_eq!(chain.links[0], ProxyAddress::root());
         assert_eq!(chain.links[1], ProxyAddress::new(6, String::from("localhost"), 1, None));
@@ -159,9 +300,63 @@ mod tests {
             ProxyAddress::new(6, String::from("localhost"), 4, None),
             ProxyAddress::new(6, String::from("localhost"), 5, None),
         ];
-        chain.detour(&extra);
+        assert_eq!(chain.clone().detour(&extra, DetourPlacement::AfterRemaining), 2);
+        assert_eq!(chain.detour(&extra, DetourPlacement::BeforeRemaining), 2);
 
         let order: Vec<u16> = chain.links.iter().map(|l| l.port).collect();
         assert_eq!(order, vec![1, 2, 4, 5, 3]);
     }
+
+    // Tests that links overlapping the current link or the links ahead of it are skipped, but not links behind it.
+    #[test]
+    pub fn test_detour_overlapping() {
+        let link = |host: &str, port| ProxyAddress::new(6, String::from(host), port, None);
+        let mut chain = SocksChain::new(1, vec![link("a", 1), link("b", 2), link("c", 3)]);
+
+        let extra = vec![link("B.", 2), link("a", 1), link("c", 3), link("d", 4), link("c", 30)];
+        assert_eq!(chain.detour(&extra, DetourPlacement::AfterRemaining), 3);
+
+        let order: Vec<(&str, u16)> = chain.links.iter().map(|l| (l.host.as_str(), l.port)).collect();
+        assert_eq!(order, vec![("a", 1), ("b", 2), ("c", 3), ("a", 1), ("d", 4), ("c", 30)]);
+
+        // IP addresses are compared by value, and a different version is a different hop.
+        let mut chain = SocksChain::new(0, vec![link("[::1]", 1)]);
+        assert_eq!(chain.detour(&[link("0:0:0:0:0:0:0:1", 1)], DetourPlacement::BeforeRemaining), 0);
+        let socks5 = ProxyAddress::new(5, String::from("::1"), 1, None);
+        assert_eq!(chain.detour(&[socks5], DetourPlacement::BeforeRemaining), 1);
+    }
+
+    // Tests that repeated links are inserted once, and that an empty set of links leaves the chain as it is.
+    #[test]
+    pub fn test_detour_duplicates() {
+        let link = |port| ProxyAddress::new(6, String::from("localhost"), port, None);
+        let mut chain = SocksChain::new(0, vec![link(1), link(2)]);
+
+        assert_eq!(chain.detour(&[link(3), link(3), link(4), link(3)], DetourPlacement::BeforeRemaining), 2);
+        let order: Vec<u16> = chain.links.iter().map(|l| l.port).collect();
+        assert_eq!(order, vec![1, 3, 4, 2]);
+
+        assert_eq!(chain.detour(&[], DetourPlacement::AfterRemaining), 0);
+        assert_eq!(chain.links.len(), 4);
+
+        let mut chain = SocksChain::default();
+        assert_eq!(chain.detour(&[], DetourPlacement::BeforeRemaining), 0);
+        assert_eq!(chain.links, vec![ProxyAddress::root()]);
+    }
+
+    // Tests that a chain failure and the hop count survive a round trip through reply options.
+    #[test]
+    pub fn test_progress_options() {
+        let failure = ChainFailure::new(1, String::from("socks6://localhost:3"), 5);
+        let mut options = failure.as_options();
+        assert_eq!(ChainFailure::from_options(&options), Some(failure.clone()));
+        assert_eq!(failure.to_string(), "link 1 failed to reach socks6://localhost:3: ConnectionRefused");
+
+        // An incomplete report is ignored.
+        options.pop();
+        assert_eq!(ChainFailure::from_options(&options), None);
+
+        assert_eq!(hops_from_options(&[hops_option(3)]), Some(3));
+        assert_eq!(hops_from_options(&options), None);
+    }
 }
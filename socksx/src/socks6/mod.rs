@@ -1,27 +1,41 @@
 // General purpose SOCKS6 module.
 use std::collections::HashMap;
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 
 use anyhow::{ensure, Result};
+use bytes::{Bytes, BytesMut};
 use num_traits::FromPrimitive;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 
 // Module imports
-pub use chain::SocksChain;
-pub use s6_client::Socks6Client;
-pub use s6_handler::Socks6Handler;
+pub use auth::{
+    AuthFailureReason, AuthOutcome, AuthRequest, Authenticator, BearerTokenAuthenticator, ClientAuthenticator,
+    Identity, NoAuth, StaticUserPass, UserDefinedReason, UserPassAuthenticator, USER_DEFINED_AUTH_FAILURE_REASONS,
+};
+pub use chain::{ChainFailure, DetourPlacement, SocksChain};
+pub use pool::PoolStats;
+pub use s6_client::{InitialDataDelivery, InitialDataMode, Socks6Client};
+pub use s6_handler::{PendingSession, ReplyBinding, Socks6Handler};
+pub use validation::{option_findings, validate_options, Finding, Severity, ValidationError, ValidationPolicy};
 
-use crate::{constants::*, ProxyAddress};
-use crate::addresses::{self, Address};
+use crate::{constants::*, errors, wire, ProxyAddress, SocksError};
+use crate::wire::{MessageReader, Parsed};
+use crate::errors::UnknownValue;
+use crate::addresses::Address;
 use crate::socks6::options::{
-    AuthMethodAdvertisementOption, AuthMethodSelectionOption, MetadataOption, SocksOption, UnrecognizedOption,
+    AuthMethodAdvertisementOption, AuthMethodSelectionOption, MetadataOption, SocksOption, SocksOptions,
+    UnrecognizedOption,
 };
 
 // Sub-modules
+pub mod auth;
 pub mod chain;
 pub mod options;
+mod pool;
 mod s6_client;
 mod s6_handler;
+pub mod validation;
 
 /// Authentication methods supported.
 #[repr(u8)]
@@ -33,9 +47,21 @@ pub enum AuthMethod {
     NoAcceptableMethods = 0xFF,
 }
 
+impl TryFrom<u8> for AuthMethod {
+    type Error = UnknownValue;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Self::from_u8(value).ok_or(UnknownValue {
+            kind: "authentication method",
+            value,
+        })
+    }
+}
+
 /// Command types in SOCKS6.
 #[repr(u8)]
 #[derive(Clone, Debug, FromPrimitive, PartialEq)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub enum Socks6Command {
     NoOp = 0x00,
     Connect = 0x01,
@@ -43,6 +69,84 @@ pub enum Socks6Command {
     UdpAssociate = 0x03,
 }
 
+impl TryFrom<u8> for Socks6Command {
+    type Error = UnknownValue;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Self::from_u8(value).ok_or(UnknownValue {
+            kind: "SOCKS6 command",
+            value,
+        })
+    }
+}
+
+/// Revisions of the SOCKS6 draft, which differ in their version byte and option kinds.
+///
+/// Differences in the layout of messages, e.g., of the replies, aren't covered: every revision is laid out like
+/// revision 11.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Socks6Draft {
+    /// Revision 11, which is spoken unless configured otherwise.
+    #[default]
+    Draft11,
+    /// Revision 13, as far as its option kinds go, see `SOCKS6_DRAFT_13`.
+    Draft13,
+}
+
+impl Socks6Draft {
+    /// Returns the wire values of the revision.
+    pub fn constants(self) -> &'static Socks6DraftConstants {
+        match self {
+            Socks6Draft::Draft11 => &SOCKS6_DRAFT_11,
+            Socks6Draft::Draft13 => &SOCKS6_DRAFT_13,
+        }
+    }
+}
+
+/// How strictly SOCKS6 messages are parsed.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ParseMode {
+    /// Rejects messages that deviate from the draft in any way.
+    Strict,
+    /// Accepts harmless deviations, and records each of them as a `Diagnostic`.
+    #[default]
+    Lenient,
+    /// Accepts what `Lenient` accepts, and recovers from options whose data doesn't parse, as long as their
+    /// length is sane: each is kept as a `SocksOption::Malformed`, recorded as `Diagnostic::MalformedOption`, and
+    /// parsing continues with the next option.
+    Recover,
+}
+
+/// A deviation from the draft, which strict parsing rejects and lenient parsing records.
+#[derive(Clone, Copy, Debug, Error, Eq, PartialEq)]
+pub enum Diagnostic {
+    #[error("padding byte is {0:#04x} instead of zero")]
+    NonzeroPadding(u8),
+    #[error("options block ends with {0} bytes that don't form an option")]
+    TrailingBytes(usize),
+    #[error("length of option {kind} isn't a multiple of 4: {length}")]
+    UnalignedOption { kind: u16, length: u16 },
+    #[error("option {kind} of {length} bytes is malformed, and kept as an unrecognized option")]
+    MalformedOption { kind: u16, length: u16 },
+}
+
+impl ParseMode {
+    /// Rejects the deviation in strict mode, or records it in lenient mode.
+    pub(crate) fn deviate(
+        self,
+        diagnostic: Diagnostic,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> Result<()> {
+        match self {
+            ParseMode::Strict => Err(diagnostic.into()),
+            ParseMode::Lenient | ParseMode::Recover => {
+                diagnostics.push(diagnostic);
+                Ok(())
+            }
+        }
+    }
+}
+
 /// Represents a SOCKS6 request.
 #[derive(Clone, Debug)]
 pub struct Socks6Request {
@@ -51,24 +155,105 @@ pub struct Socks6Request {
     pub initial_data_length: u16,
     pub options: Vec<SocksOption>,
     pub metadata: HashMap<u16, String>,
+    raw_options: Option<Bytes>,
+    diagnostics: Vec<Diagnostic>,
+    packed_metadata: bool,
+    compressed_metadata: bool,
+}
+
+/// Requests are equal if they carry the same command, destination, options, and metadata, regardless of how
+/// they were parsed, or whether their metadata is sent packed or compressed.
+impl PartialEq for Socks6Request {
+    fn eq(
+        &self,
+        other: &Self,
+    ) -> bool {
+        self.command == other.command
+            && self.destination == other.destination
+            && self.initial_data_length == other.initial_data_length
+            && self.options == other.options
+            && self.metadata == other.metadata
+    }
 }
 
 impl Socks6Request {
     /// Constructor for Socks6Request
     pub fn new(
-        command: u8,
+        command: Socks6Command,
         destination: Address,
         initial_data_length: u16,
         options: Vec<SocksOption>,
         metadata: Option<HashMap<u16, String>>,
     ) -> Self {
         Socks6Request {
-            command: Socks6Command::from_u8(command).unwrap(),
+            command,
             destination,
             initial_data_length,
             options,
             metadata: metadata.unwrap_or_default(),
+            raw_options: None,
+            diagnostics: vec![],
+            packed_metadata: false,
+            compressed_metadata: false,
+        }
+    }
+
+    /// Sets whether the metadata is sent packed, in as few options as fit, instead of in an option per entry.
+    ///
+    /// Packed metadata is a socksx extension. Handlers of socksx decode both forms, but other proxies are
+    /// unlikely to, so only pack the metadata for proxies known to support it.
+    pub fn with_packed_metadata(
+        mut self,
+        packed_metadata: bool,
+    ) -> Self {
+        self.packed_metadata = packed_metadata;
+        self
+    }
+
+    /// Sets whether the metadata is sent packed and deflate-compressed, see `options::write_compressed_metadata`.
+    ///
+    /// Compressed metadata is a socksx extension as well, which only proxies that advertise support for it with
+    /// `metadata_compression_option` decode.
+    pub fn with_compressed_metadata(
+        mut self,
+        compressed_metadata: bool,
+    ) -> Self {
+        self.compressed_metadata = compressed_metadata;
+        self
+    }
+
+    /// Returns the deviations from the draft that were accepted while parsing the request leniently.
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    pub(crate) fn with_diagnostics(
+        mut self,
+        diagnostics: Vec<Diagnostic>,
+    ) -> Self {
+        self.diagnostics = diagnostics;
+        self
+    }
+
+    /// Returns the options block exactly as it was read, without its length prefix.
+    ///
+    /// This is only retained by `read_request_raw`, or by a handler configured to, and isn't
+    /// updated when the options are modified afterwards.
+    pub fn raw_options(&self) -> Option<&Bytes> {
+        self.raw_options.as_ref()
+    }
+
+    /// Returns the raw bytes of each option as it was read, paired with the parsed option.
+    ///
+    /// # Returns
+    /// `None` if the raw options weren't retained, or if the options no longer match them.
+    pub(crate) fn raw_option_pairs(&self) -> Option<Vec<(&SocksOption, &[u8])>> {
+        let raw = wire::split_options(self.raw_options.as_ref()?);
+        if raw.len() != self.options.len() {
+            return None;
         }
+
+        Some(self.options.iter().zip(raw).collect())
     }
 
     /// Chain function to link multiple proxies.
@@ -94,7 +279,7 @@ impl Socks6Request {
         };
 
         if !static_links.is_empty() {
-            chain.detour(static_links);
+            chain.detour(static_links, DetourPlacement::BeforeRemaining);
         }
 
         if chain.links.is_empty() {
@@ -106,148 +291,477 @@ impl Socks6Request {
 
     /// Convert the request into a byte sequence for SOCKS6.
     pub fn into_socks_bytes(self) -> Vec<u8> {
-        let mut data = vec![SOCKS_VER_6, SOCKS_CMD_CONNECT];
-        data.extend(self.destination.as_socks_bytes());
+        let mut data = Vec::with_capacity(self.encoded_len());
+        self.write_socks_bytes(&mut data);
+
+        data
+    }
+
+    /// Returns the number of bytes `write_socks_bytes` appends for this request.
+    pub fn encoded_len(&self) -> usize {
+        2 + self.destination.encoded_len() + 1 + 2 + self.options_len()
+    }
+
+    /// Appends the SOCKS6 representation of the request to the buffer.
+    pub fn write_socks_bytes(
+        &self,
+        data: &mut Vec<u8>,
+    ) {
+        self.write_socks_bytes_for(Socks6Draft::default(), data);
+    }
+
+    /// Appends the request to the buffer, using the version byte and option kinds of the given draft revision.
+    pub fn write_socks_bytes_for(
+        &self,
+        draft: Socks6Draft,
+        data: &mut Vec<u8>,
+    ) {
+        self.write_socks_bytes_with(data, draft, &[]);
+    }
+
+    /// Appends the request to the buffer, with already encoded options following its own options.
+    pub(crate) fn write_socks_bytes_with(
+        &self,
+        data: &mut Vec<u8>,
+        draft: Socks6Draft,
+        encoded_options: &[u8],
+    ) {
+        data.reserve(self.encoded_len() + encoded_options.len());
+        data.extend_from_slice(&[draft.constants().version, self.command.clone() as u8]);
+        self.destination.write_socks_bytes(data);
         data.push(SOCKS_PADDING);
 
-        let options_bytes: Vec<_> = self.options.into_iter().flat_map(|o| o.as_socks_bytes()).collect();
-        let options_bytes_length = (options_bytes.len() as u16).to_be_bytes();
+        let options_length = self.options_len() + encoded_options.len();
+        data.extend_from_slice(&(options_length as u16).to_be_bytes());
+        if let Some(advertisement) = self.implied_advertisement() {
+            advertisement.write_socks_bytes_for(draft, data);
+        }
+        for option in self.options.iter().filter(|o| !matches!(o, SocksOption::Metadata(_))) {
+            option.write_socks_bytes_for(draft, data);
+        }
+        let metadata = self.sorted_metadata();
+        if self.compressed_metadata {
+            options::write_compressed_metadata(&metadata, data);
+        } else if self.packed_metadata {
+            options::write_packed_metadata(&metadata, data);
+        } else {
+            for option in metadata {
+                option.wrap().write_socks_bytes_for(draft, data);
+            }
+        }
+        data.extend_from_slice(encoded_options);
+    }
+
+    // Returns the combined length of the encoded options.
+    pub(crate) fn options_len(&self) -> usize {
+        let advertisement = self.implied_advertisement().map_or(0, |a| a.encoded_len());
+        let others: usize = self
+            .options
+            .iter()
+            .filter(|o| !matches!(o, SocksOption::Metadata(_)))
+            .map(SocksOption::encoded_len)
+            .sum();
+        let metadata = self.sorted_metadata();
+        let metadata = if self.compressed_metadata {
+            options::compressed_metadata_len(&metadata)
+        } else if self.packed_metadata {
+            options::packed_metadata_len(&metadata)
+        } else {
+            metadata.iter().map(MetadataOption::encoded_len).sum()
+        };
 
-        data.extend(options_bytes_length.iter());
-        data.extend(options_bytes.iter());
+        advertisement + others + metadata
+    }
 
-        data
+    // Returns the metadata options, and the metadata entries that the options don't already carry, ordered by
+    // key, so that the same metadata is always encoded the same.
+    //
+    // The sort is stable, and the entries follow the options, so the metadata map wins when the request is
+    // parsed again.
+    fn sorted_metadata(&self) -> Vec<MetadataOption> {
+        let mut metadata: Vec<MetadataOption> = self
+            .options
+            .iter()
+            .filter_map(|o| match o {
+                SocksOption::Metadata(m) => Some(m.clone()),
+                _ => None,
+            })
+            .chain(self.implied_metadata().into_iter().map(|(key, value)| MetadataOption::new(key, value)))
+            .collect();
+        metadata.sort_by_key(|m| m.key);
+
+        metadata
+    }
+
+    // Returns the metadata entries that the options don't already carry.
+    fn implied_metadata(&self) -> Vec<(u16, String)> {
+        self.metadata
+            .iter()
+            .filter(|(key, value)| {
+                !self.options.iter().any(|o| match o {
+                    SocksOption::Metadata(m) => m.key == **key && &m.value == *value,
+                    _ => false,
+                })
+            })
+            .map(|(key, value)| (*key, value.clone()))
+            .collect()
+    }
+
+    // Returns the advertisement that carries the initial data length, if the options lack one.
+    fn implied_advertisement(&self) -> Option<SocksOption> {
+        if self.initial_data_length > 0 && self.options.auth_advertisement().is_none() {
+            Some(AuthMethodAdvertisementOption::new(self.initial_data_length, vec![]).wrap())
+        } else {
+            None
+        }
     }
 }
 
 /// Reads a SOCKS6 request from the provided stream.
+///
+/// If the future is dropped before the request is complete, the bytes read so far are lost. Use
+/// `read_request_resumable` where the read may be cancelled, e.g., by a timeout.
 pub async fn read_request<S>(stream: &mut S) -> Result<Socks6Request>
 where
-    S: AsyncRead + Unpin,
+    S: AsyncRead + Unpin + ?Sized,
+{
+    read_request_buf(stream, &mut BytesMut::new()).await
+}
+
+/// Reads a SOCKS6 request from the provided stream, and validates it.
+///
+/// # Returns
+/// The request, or a `ValidationError` when it parses fine but the policy rejects it.
+pub async fn read_request_validated<S>(
+    stream: &mut S,
+    policy: ValidationPolicy,
+) -> Result<Socks6Request>
+where
+    S: AsyncRead + Unpin + ?Sized,
 {
-    // Read SOCKS version and command type.
-    let mut request = [0; 2];
-    stream.read_exact(&mut request).await?;
+    let request = read_request(stream).await?;
+    request.validate(policy)?;
 
-    let [version, command] = request;
+    Ok(request)
+}
 
-    // Validate the request.
-    ensure!(version == SOCKS_VER_6, "Version mismatch!");
-    ensure!(command == SOCKS_CMD_CONNECT, "Only COMMAND is supported!");
+/// Reads a SOCKS6 request from the provided stream, using `scratch` as the intermediate buffer.
+///
+/// Reusing the same scratch buffer for every message on a connection avoids allocating
+/// intermediate buffers for the address and options of each message.
+pub async fn read_request_buf<S>(
+    stream: &mut S,
+    scratch: &mut BytesMut,
+) -> Result<Socks6Request>
+where
+    S: AsyncRead + Unpin + ?Sized,
+{
+    read_request_buf_for(stream, scratch, Socks6Draft::default()).await
+}
 
-    let destination = addresses::read_address(stream).await?;
+/// Reads a SOCKS6 request of the given draft revision, using `scratch` as the intermediate buffer.
+pub async fn read_request_buf_for<S>(
+    stream: &mut S,
+    scratch: &mut BytesMut,
+    draft: Socks6Draft,
+) -> Result<Socks6Request>
+where
+    S: AsyncRead + Unpin + ?Sized,
+{
+    wire::read_message(stream, scratch, |bytes| wire::parse_socks6_request_for(bytes, draft)).await
+}
 
-    let mut padding = [0; 1];
-    stream.read_exact(&mut padding).await?;
+/// Reads a SOCKS6 request from the provided stream, retaining the raw options block.
+///
+/// This costs a copy of the options block, which is available through `Socks6Request::raw_options`.
+pub async fn read_request_raw<S>(stream: &mut S) -> Result<Socks6Request>
+where
+    S: AsyncRead + Unpin + ?Sized,
+{
+    read_request_with(stream, &mut MessageReader::new(), Socks6Draft::default(), ParseMode::default(), true).await
+}
 
-    let options = read_options(stream).await?;
+/// Reads a SOCKS6 request of the given draft revision from the provided stream, in the given mode.
+///
+/// The deviations a lenient read accepted are available through `Socks6Request::diagnostics`.
+pub async fn read_request_with_mode<S>(
+    stream: &mut S,
+    draft: Socks6Draft,
+    mode: ParseMode,
+) -> Result<Socks6Request>
+where
+    S: AsyncRead + Unpin + ?Sized,
+{
+    read_request_with(stream, &mut MessageReader::new(), draft, mode, false).await
+}
 
-    let mut initial_data_length = 0;
-    let mut metadata = HashMap::new();
-    for option in &options {
-        match option {
-            SocksOption::AuthMethodAdvertisement(advertisement) => {
-                // Make note of initial data length for convenience.
-                initial_data_length = advertisement.initial_data_length;
-            }
-            SocksOption::Metadata(key_value) => {
-                metadata.insert(key_value.key, key_value.value.clone());
-            }
-            _ => {}
-        }
+/// Reads a SOCKS6 request of the given draft revision through a `MessageReader`.
+///
+/// This is cancellation safe: if the future is dropped, calling this again with the same reader resumes
+/// the request where it left off.
+pub async fn read_request_resumable<S>(
+    stream: &mut S,
+    reader: &mut MessageReader,
+    draft: Socks6Draft,
+) -> Result<Socks6Request>
+where
+    S: AsyncRead + Unpin + ?Sized,
+{
+    read_request_with(stream, reader, draft, ParseMode::default(), false).await
+}
 
-        if let SocksOption::Metadata(key_value) = option {
-            metadata.insert(key_value.key, key_value.value.clone());
-        }
+/// Reads a SOCKS6 request through a `MessageReader` in the given mode, and retains its raw options block if asked to.
+pub(crate) async fn read_request_with<S>(
+    stream: &mut S,
+    reader: &mut MessageReader,
+    draft: Socks6Draft,
+    mode: ParseMode,
+    retain_raw_options: bool,
+) -> Result<Socks6Request>
+where
+    S: AsyncRead + Unpin + ?Sized,
+{
+    let mut request = reader.read(stream, |bytes| wire::parse_socks6_request_with(bytes, draft, mode)).await?;
+    if retain_raw_options {
+        // The reader holds exactly the request, and the options block comes last.
+        let options_start = 2 + request.destination.encoded_len() + 1 + 2;
+        request.raw_options = Some(Bytes::copy_from_slice(&reader.buffered()[options_start..]));
     }
 
-    Ok(Socks6Request::new(
-        command,
-        destination,
-        initial_data_length,
-        options,
-        Some(metadata),
-    ))
+    Ok(request)
 }
 
 /// Reads the SOCKS6 options from the stream.
 pub async fn read_options<S>(stream: &mut S) -> Result<Vec<SocksOption>>
 where
-    S: AsyncRead + Unpin,
+    S: AsyncRead + Unpin + ?Sized,
+{
+    read_options_buf(stream, &mut BytesMut::new()).await
+}
+
+/// Reads the SOCKS6 options from the stream, using `scratch` as the intermediate buffer.
+///
+/// The whole options block is read into the scratch buffer at once, and the options are parsed
+/// from slices of it.
+pub async fn read_options_buf<S>(
+    stream: &mut S,
+    scratch: &mut BytesMut,
+) -> Result<Vec<SocksOption>>
+where
+    S: AsyncRead + Unpin + ?Sized,
+{
+    wire::read_message(stream, scratch, wire::parse_options).await
+}
+
+/// Reads the SOCKS6 options of the given draft revision from the stream, in the given mode.
+///
+/// # Returns
+/// The options, and the deviations from the draft that a lenient read accepted.
+pub async fn read_options_with_mode<S>(
+    stream: &mut S,
+    draft: Socks6Draft,
+    mode: ParseMode,
+) -> Result<(Vec<SocksOption>, Vec<Diagnostic>)>
+where
+    S: AsyncRead + Unpin + ?Sized,
+{
+    let parse = |bytes: &[u8]| wire::parse_options_with(bytes, draft, mode);
+    wire::read_message(stream, &mut BytesMut::new(), parse).await
+}
+
+/// Reads the SOCKS6 options from the stream, along with the raw options block without its length prefix.
+pub async fn read_options_raw<S>(stream: &mut S) -> Result<(Vec<SocksOption>, Bytes)>
+where
+    S: AsyncRead + Unpin + ?Sized,
 {
-    let mut options = Vec::new();
+    let mut scratch = BytesMut::new();
+    let options = read_options_buf(stream, &mut scratch).await?;
+
+    Ok((options, scratch.split_off(2).freeze()))
+}
 
-    let mut options_length = [0; 2];
-    stream.read_exact(&mut options_length).await?;
+/// The reply metadata key under which a proxy reports the most bytes of initial data it accepts, a socksx extension,
+/// in its authentication or operation reply. A proxy that reports fewer bytes than a request carries in its
+/// authentication reply left the initial data unread, and in its operation reply discarded it.
+pub const INITIAL_DATA_METADATA_KEY: u16 = 992;
 
-    let options_length = ((options_length[0] as u16) << 8) | options_length[1] as u16;
-    let mut options_bytes_read = 0;
+/// Converts the most bytes of initial data a proxy accepts into the metadata option that reports it, e.g., 0 for
+/// a proxy that refuses initial data.
+pub fn initial_data_option(accepted: u16) -> SocksOption {
+    MetadataOption::new(INITIAL_DATA_METADATA_KEY, accepted.to_string()).wrap()
+}
 
-    while options_bytes_read < options_length {
-        let mut buffer = [0; 4];
-        stream.read_exact(&mut buffer).await?;
+/// Reads the most bytes of initial data a proxy accepts from the options of a reply, if reported.
+pub fn initial_data_accepted(options: &[SocksOption]) -> Option<u16> {
+    options.metadata().get(&INITIAL_DATA_METADATA_KEY)?.parse().ok()
+}
 
-        let [kind_0, kind_1, length_0, length_1] = buffer;
-        let kind = ((kind_0 as u16) << 8) | kind_1 as u16;
-        let length = ((length_0 as u16) << 8) | length_1 as u16;
+/// Converts support for deflate-compressed metadata into the option that advertises it, a socksx extension: in a
+/// request, the client's, and in an authentication reply, the proxy's.
+pub fn metadata_compression_option() -> SocksOption {
+    UnrecognizedOption::new(SOCKS_OKIND_METADATA_COMPRESSION, vec![SOCKS_METADATA_COMPRESSION_DEFLATE]).wrap()
+}
 
-        // Read remaining bytes of this option.
-        let mut options_data = vec![0; (length - 4) as usize];
-        stream.read_exact(&mut options_data).await?;
+/// Reads from the options of a message whether its sender supports deflate-compressed metadata.
+pub fn supports_metadata_compression(options: &[SocksOption]) -> bool {
+    options.unrecognized().any(|option| {
+        option.kind() == SOCKS_OKIND_METADATA_COMPRESSION && option.data().contains(&SOCKS_METADATA_COMPRESSION_DEFLATE)
+    })
+}
 
-        let option = match kind {
-            0x0002 => AuthMethodAdvertisementOption::from_socks_bytes(options_data)?,
-            0x0003 => AuthMethodSelectionOption::from_socks_bytes(options_data)?,
-            0xFDE8 => MetadataOption::from_socks_bytes(options_data)?,
-            _ => UnrecognizedOption::new(kind, options_data.to_vec()).wrap(),
-        };
+/// The reply metadata key under which a proxy reports why it rejected the authentication of a client, a socksx
+/// extension, in its failed authentication reply.
+pub const AUTH_FAILURE_METADATA_KEY: u16 = 991;
+
+/// Converts the reason a proxy rejected the authentication of a client into the metadata option that reports it.
+pub fn auth_failure_option(reason: AuthFailureReason) -> SocksOption {
+    MetadataOption::new(AUTH_FAILURE_METADATA_KEY, reason.code().to_string()).wrap()
+}
+
+/// Reads the reason a proxy rejected the authentication of a client from the options of a reply, if reported with
+/// a known code.
+pub fn auth_failure_reason(options: &[SocksOption]) -> Option<AuthFailureReason> {
+    AuthFailureReason::from_code(options.metadata().get(&AUTH_FAILURE_METADATA_KEY)?.parse().ok()?)
+}
+
+/// The authentication reply of a SOCKS6 proxy.
+///
+/// The reply is returned as-is; deciding what a failed status means is left to the caller.
+#[derive(Clone, Debug)]
+pub struct AuthenticationReply {
+    /// The status of the reply, e.g., `SOCKS_AUTH_SUCCESS`.
+    pub status: u8,
+    /// The authentication method the proxy selected, if it sent a selection.
+    pub selection: Option<AuthMethodSelectionOption>,
+    /// The other options of the reply.
+    pub options: Vec<SocksOption>,
+    /// The deviations from the draft that were accepted while reading the reply leniently.
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl AuthenticationReply {
+    /// Returns whether the proxy accepted the authentication.
+    pub fn is_success(&self) -> bool {
+        self.status == SOCKS_AUTH_SUCCESS
+    }
 
-        options.push(option);
-        options_bytes_read += length;
+    /// Returns the most bytes of initial data the proxy accepts, if it reported it with `initial_data_option`.
+    pub fn initial_data_accepted(&self) -> Option<u16> {
+        initial_data_accepted(&self.options)
     }
 
-    Ok(options)
+    /// Returns why the proxy rejected the authentication, if it reported it with `auth_failure_option`.
+    pub fn failure_reason(&self) -> Option<AuthFailureReason> {
+        auth_failure_reason(&self.options)
+    }
 }
 
-/// Reads the authentication response.
-pub async fn read_no_authentication<S>(stream: &mut S) -> Result<Vec<SocksOption>>
+/// Reads the authentication reply, regardless of its status.
+pub async fn read_authentication_reply<S>(stream: &mut S) -> Result<AuthenticationReply>
+where
+    S: AsyncRead + Unpin + ?Sized,
+{
+    read_authentication_reply_for(stream, Socks6Draft::default()).await
+}
+
+/// Reads the authentication reply of the given draft revision in the given mode, regardless of its status.
+pub async fn read_authentication_reply_with_mode<S>(
+    stream: &mut S,
+    draft: Socks6Draft,
+    mode: ParseMode,
+) -> Result<AuthenticationReply>
 where
-    S: AsyncRead + Unpin,
+    S: AsyncRead + Unpin + ?Sized,
 {
-    // Read auth reply
-    let mut reply = [0; 1];
-    stream.read_exact(&mut reply).await?;
+    let parse = |bytes: &[u8]| wire::parse_socks6_auth_reply_with(bytes, draft, mode);
+    let ((status, options), diagnostics) = wire::read_message(stream, &mut BytesMut::new(), parse).await?;
 
-    let socks_version = reply[0];
-    ensure!(
-        socks_version == SOCKS_VER_6,
-        "Proxy uses a different SOCKS version: {}",
-        socks_version
-    );
+    Ok(authentication_reply(status, options, diagnostics))
+}
 
-    let mut reply = [0; 1];
-    stream.read_exact(&mut reply).await?;
+/// Reads the authentication reply like `read_authentication_reply_with_mode`, or the operation reply of a proxy
+/// that refused the request before authentication, which fails with `SocksError::OperationFailed`.
+///
+/// A refusal with a general failure looks like a failed authentication, and is read as one.
+pub(crate) async fn read_authentication_reply_or_refusal<S>(
+    stream: &mut S,
+    draft: Socks6Draft,
+    mode: ParseMode,
+) -> Result<AuthenticationReply>
+where
+    S: AsyncRead + Unpin + ?Sized,
+{
+    let parse = |bytes: &[u8]| match bytes.get(1) {
+        Some(&status) if status != SOCKS_AUTH_SUCCESS && status != SOCKS_AUTH_FAILED => {
+            match wire::parse_socks6_reply_with(bytes, draft, mode)? {
+                Parsed::Complete(((reply_code, _, _), _), _) => Err(SocksError::OperationFailed(reply_code).into()),
+                Parsed::Incomplete(needed) => Ok(Parsed::Incomplete(needed)),
+            }
+        }
+        _ => wire::parse_socks6_auth_reply_with(bytes, draft, mode),
+    };
+    let ((status, options), diagnostics) = wire::read_message(stream, &mut BytesMut::new(), parse).await?;
 
-    let status = reply[0];
-    ensure!(
-        status == SOCKS_AUTH_SUCCESS,
-        "Authentication with proxy failed: {}",
-        status
-    );
+    Ok(authentication_reply(status, options, diagnostics))
+}
 
-    let options = read_options(stream).await?;
+// Separates the method selection from the other options of an authentication reply.
+fn authentication_reply(
+    status: u8,
+    options: Vec<SocksOption>,
+    diagnostics: Vec<Diagnostic>,
+) -> AuthenticationReply {
+    let mut selection = None;
+    let mut remaining = Vec::with_capacity(options.len());
+    for option in options {
+        match option {
+            SocksOption::AuthMethodSelection(option) if selection.is_none() => selection = Some(option),
+            option => remaining.push(option),
+        }
+    }
 
-    Ok(options)
+    AuthenticationReply {
+        status,
+        selection,
+        options: remaining,
+        diagnostics,
+    }
+}
+
+/// Reads the authentication reply of the given draft revision, regardless of its status.
+pub async fn read_authentication_reply_for<S>(
+    stream: &mut S,
+    draft: Socks6Draft,
+) -> Result<AuthenticationReply>
+where
+    S: AsyncRead + Unpin + ?Sized,
+{
+    read_authentication_reply_with_mode(stream, draft, ParseMode::default()).await
+}
+
+/// Reads the authentication response, failing unless the proxy accepted the authentication.
+///
+/// # Returns
+/// The options of the reply, without the method selection. Use `read_authentication_reply` to inspect it.
+pub async fn read_no_authentication<S>(stream: &mut S) -> Result<Vec<SocksOption>>
+where
+    S: AsyncRead + Unpin + ?Sized,
+{
+    let reply = read_authentication_reply(stream).await?;
+    ensure!(reply.is_success(), "Authentication with proxy failed: {}", reply.status);
+
+    Ok(reply.options)
 }
 
 /// Writes a reply to indicate no authentication is needed.
 pub async fn write_no_authentication<S>(stream: &mut S) -> Result<()>
 where
-    S: AsyncWrite + Unpin,
+    S: AsyncWrite + Unpin + ?Sized,
 {
     // Write auth reply
-    let auth_reply = [SOCKS_VER_6, SOCKS_AUTH_SUCCESS, 0x00u8, 0x00u8];
-    stream.write(&auth_reply).await?;
+    let mut auth_reply = Vec::with_capacity(4);
+    wire::encode_socks6_auth_reply(SOCKS_AUTH_SUCCESS, &[], &mut auth_reply);
+    stream.write_all(&auth_reply).await?;
 
     Ok(())
 }
@@ -258,7 +772,7 @@ pub async fn write_initial_data<S>(
     _request: &Socks6Request,
 ) -> Result<()>
 where
-    S: AsyncWrite + Unpin,
+    S: AsyncWrite + Unpin + ?Sized,
 {
     // Not yet implemented.
     Ok(())
@@ -280,64 +794,148 @@ pub enum Socks6Reply {
     ConnectionAttemptTimeOut = 0x09,
 }
 
-/// Writes a SOCKS6 reply to the stream.
+impl Socks6Reply {
+    /// Determines the reply that best describes why an operation failed.
+    pub fn from_error(error: &anyhow::Error) -> Self {
+        Self::from_u8(errors::reply_code(error)).unwrap_or(Socks6Reply::GeneralFailure)
+    }
+}
+
+/// Writes a SOCKS6 reply to the stream, with the bound address and reply options.
 pub async fn write_reply<S>(
     stream: &mut S,
     reply: Socks6Reply,
+    binding: &Address,
+    options: &[SocksOption],
+) -> Result<()>
+where
+    S: AsyncWrite + Unpin + ?Sized,
+{
+    write_reply_for(stream, Socks6Draft::default(), reply, binding, options).await
+}
+
+/// Writes a SOCKS6 reply of the given draft revision to the stream.
+pub async fn write_reply_for<S>(
+    stream: &mut S,
+    draft: Socks6Draft,
+    reply: Socks6Reply,
+    binding: &Address,
+    options: &[SocksOption],
 ) -> Result<()>
 where
-    S: AsyncWrite + Unpin,
+    S: AsyncWrite + Unpin + ?Sized,
 {
-    let reply = [
-        SOCKS_VER_6,
-        reply as u8,
-        SOCKS_PADDING,
-        SOCKS_ATYP_IPV4,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-        0x00,
-    ];
-
-    stream.write(&reply).await?;
+    let options_length: usize = options.iter().map(SocksOption::encoded_len).sum();
+    let mut bytes = Vec::with_capacity(3 + binding.encoded_len() + 2 + options_length);
+    wire::encode_socks6_reply_for(reply as u8, binding, options, draft, &mut bytes);
+
+    stream.write_all(&bytes).await?;
 
     Ok(())
 }
 
+/// Writes a SOCKS6 reply to the stream, with an unspecified bound address and no options.
+pub async fn write_simple_reply<S>(
+    stream: &mut S,
+    reply: Socks6Reply,
+) -> Result<()>
+where
+    S: AsyncWrite + Unpin + ?Sized,
+{
+    write_reply(stream, reply, &Address::new("0.0.0.0", 0), &[]).await
+}
+
 /// Reads a SOCKS6 reply from the stream.
 pub async fn read_reply<S>(stream: &mut S) -> Result<(Address, Vec<SocksOption>)>
 where
-    S: AsyncRead + Unpin,
+    S: AsyncRead + Unpin + ?Sized,
 {
-    let mut operation_reply = [0; 3];
-    stream.read_exact(&mut operation_reply).await?;
-
-    let reply_code = operation_reply[1];
-    ensure!(
-        reply_code == SOCKS_REP_SUCCEEDED,
-        "CONNECT operation failed: {:?}",
-        Socks6Reply::from_u8(reply_code)
-    );
+    read_reply_for(stream, Socks6Draft::default()).await
+}
 
-    let binding = addresses::read_address(stream).await?;
-    let options = read_options(stream).await?;
+/// Reads a SOCKS6 reply of the given draft revision from the stream.
+///
+/// A failure reply that reports where a chain failed results in `SocksError::ChainFailed`.
+pub async fn read_reply_for<S>(
+    stream: &mut S,
+    draft: Socks6Draft,
+) -> Result<(Address, Vec<SocksOption>)>
+where
+    S: AsyncRead + Unpin + ?Sized,
+{
+    let (binding, options, _) = read_reply_with_mode(stream, draft, ParseMode::default()).await?;
 
     Ok((binding, options))
 }
 
+/// Reads a SOCKS6 reply of the given draft revision from the stream, in the given mode.
+///
+/// # Returns
+/// The binding and options, and the deviations from the draft that a lenient read accepted.
+pub async fn read_reply_with_mode<S>(
+    stream: &mut S,
+    draft: Socks6Draft,
+    mode: ParseMode,
+) -> Result<(Address, Vec<SocksOption>, Vec<Diagnostic>)>
+where
+    S: AsyncRead + Unpin + ?Sized,
+{
+    read_reply_observed(stream, draft, mode, |_| ()).await
+}
+
+// Reads a SOCKS6 reply like `read_reply_with_mode`, passing its reply code to `observe` before checking it.
+pub(crate) async fn read_reply_observed<S>(
+    stream: &mut S,
+    draft: Socks6Draft,
+    mode: ParseMode,
+    observe: impl FnOnce(u8),
+) -> Result<(Address, Vec<SocksOption>, Vec<Diagnostic>)>
+where
+    S: AsyncRead + Unpin + ?Sized,
+{
+    let parse = |bytes: &[u8]| wire::parse_socks6_reply_with(bytes, draft, mode);
+    let ((reply_code, binding, options), diagnostics) = wire::read_message(stream, &mut BytesMut::new(), parse).await?;
+    observe(reply_code);
+    if reply_code != SOCKS_REP_SUCCEEDED {
+        if let Some(failure) = ChainFailure::from_options(&options) {
+            return Err(SocksError::ChainFailed(failure).into());
+        }
+    }
+    ensure!(reply_code == SOCKS_REP_SUCCEEDED, SocksError::OperationFailed(reply_code));
+
+    Ok((binding, options, diagnostics))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::request_vectors;
+
+    // Tests that the shared request vectors parse into what they were encoded from.
+    #[tokio::test]
+    async fn test_read_request_vectors() -> Result<()> {
+        for (name, bytes) in request_vectors() {
+            let request = read_request(&mut &bytes[..]).await?;
+            let expected = match name {
+                "metadata" => (0, vec![(1, "one"), (7, "seven")]),
+                "unusual ordering" => (3, vec![(1, "one")]),
+                _ => (0, vec![]),
+            };
+
+            let mut metadata: Vec<_> = request.metadata.iter().map(|(key, value)| (*key, value.as_str())).collect();
+            metadata.sort();
+            assert_eq!((request.initial_data_length, metadata), expected, "{}", name);
+            assert_eq!(request.into_socks_bytes(), bytes, "{}", name);
+        }
+
+        Ok(())
+    }
 
     // Test creation of a new Socks6Request.
     #[test]
     fn test_new_socks6_request() {
         let request = Socks6Request::new(
-            Socks6Command::Connect as u8,
+            Socks6Command::Connect,
             Address::new("192.168.1.1", 80),
             0,
             vec![],
@@ -359,7 +957,7 @@ mod tests {
     #[test]
     fn test_into_socks_bytes() {
         let request = Socks6Request::new(
-            Socks6Command::Connect as u8,
+            Socks6Command::Connect,
             Address::new("192.168.1.1", 80),
             0,
             vec![],
@@ -369,4 +967,448 @@ mod tests {
         let expected_result: Vec<u8> = vec![6, 1, 1, 192, 168, 1, 1, 0, 80, 0, 0, 0];
         assert_eq!(result, expected_result);
     }
+
+    // Test that command and authentication method bytes convert without panicking.
+    #[test]
+    fn test_try_from_u8() {
+        assert_eq!(Socks6Command::try_from(0x02), Ok(Socks6Command::Bind));
+        assert_eq!(
+            Socks6Command::try_from(0x09),
+            Err(UnknownValue {
+                kind: "SOCKS6 command",
+                value: 0x09
+            })
+        );
+
+        assert!(matches!(AuthMethod::try_from(0x02), Ok(AuthMethod::UsernamePassword)));
+        assert_eq!(AuthMethod::try_from(0x03).unwrap_err().value, 0x03);
+        assert!(options::AuthMethod::try_from(0x04).is_err());
+    }
+
+    // Test that a reply with a binding and options reads back the same.
+    #[tokio::test]
+    async fn test_reply_round_trip() -> Result<()> {
+        for binding in [Address::new("0.0.0.0", 0), Address::new("::1", 1080), Address::new("example.com", 443)] {
+            let options = vec![MetadataOption::new(1, String::from("value")).wrap()];
+
+            let mut bytes = vec![];
+            write_reply(&mut bytes, Socks6Reply::Success, &binding, &options).await?;
+            let (read_binding, read_options) = read_reply(&mut &bytes[..]).await?;
+            assert_eq!(read_binding, binding);
+            assert!(matches!(&read_options[..], [SocksOption::Metadata(m)] if m.key == 1 && m.value == "value"));
+        }
+
+        let mut bytes = vec![];
+        write_simple_reply(&mut bytes, Socks6Reply::HostUnreachable).await?;
+        assert!(read_reply(&mut &bytes[..]).await.is_err());
+        assert_eq!(bytes, vec![SOCKS_VER_6, SOCKS_REP_HOST_UNREACHABLE, SOCKS_PADDING, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
+        Ok(())
+    }
+
+    // Test that the authentication reply is returned with its selection, whatever the status.
+    #[tokio::test]
+    async fn test_read_authentication_reply() -> Result<()> {
+        let options = vec![
+            MetadataOption::new(1, String::from("value")).wrap(),
+            AuthMethodSelectionOption::new(options::AuthMethod::UsernamePassword).wrap(),
+        ];
+        let mut bytes = vec![];
+        wire::encode_socks6_auth_reply(SOCKS_AUTH_FAILED, &options, &mut bytes);
+
+        let reply = read_authentication_reply(&mut &bytes[..]).await?;
+        assert!(!reply.is_success());
+        assert_eq!(reply.selection.map(|s| s.method), Some(options::AuthMethod::UsernamePassword));
+        assert!(matches!(&reply.options[..], [SocksOption::Metadata(m)] if m.key == 1));
+        assert!(read_no_authentication(&mut &bytes[..]).await.is_err());
+
+        let mut bytes = vec![];
+        wire::encode_socks6_auth_reply(SOCKS_AUTH_SUCCESS, &options, &mut bytes);
+        let remaining = read_no_authentication(&mut &bytes[..]).await?;
+        assert_eq!(remaining.len(), 1);
+        Ok(())
+    }
+
+    // Test that every command survives serialization.
+    #[tokio::test]
+    async fn test_into_socks_bytes_command() -> Result<()> {
+        for command in [
+            Socks6Command::NoOp,
+            Socks6Command::Connect,
+            Socks6Command::Bind,
+            Socks6Command::UdpAssociate,
+        ] {
+            let request = Socks6Request::new(command.clone(), Address::new("example.com", 80), 0, vec![], None);
+            let bytes = request.into_socks_bytes();
+            assert_eq!(bytes[1], command.clone() as u8);
+
+            let parsed = read_request(&mut &bytes[..]).await?;
+            assert_eq!(parsed.command, command);
+            assert_eq!(parsed.destination, Address::new("example.com", 80));
+        }
+
+        let mut bytes = Socks6Request::new(Socks6Command::Connect, Address::new("example.com", 80), 0, vec![], None)
+            .into_socks_bytes();
+        bytes[1] = 0x04;
+        let error = read_request(&mut &bytes[..]).await.unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(errors::SocksError::UnknownCommand(0x04))));
+        Ok(())
+    }
+
+    // Test that the initial data length survives serialization, with and without an advertisement.
+    #[tokio::test]
+    async fn test_into_socks_bytes_initial_data_length() -> Result<()> {
+        for (initial_data_length, options) in [
+            (0, vec![]),
+            (5, vec![]),
+            (u16::MAX, vec![MetadataOption::new(1, String::from("value")).wrap()]),
+            (7, vec![AuthMethodAdvertisementOption::new(7, vec![options::AuthMethod::UsernamePassword]).wrap()]),
+        ] {
+            let request = Socks6Request::new(
+                Socks6Command::Connect,
+                Address::new("192.168.1.1", 80),
+                initial_data_length,
+                options,
+                None,
+            );
+            let length = request.encoded_len();
+            let bytes = request.into_socks_bytes();
+            assert_eq!(bytes.len(), length);
+
+            let parsed = read_request(&mut &bytes[..]).await?;
+            assert_eq!(parsed.initial_data_length, initial_data_length);
+
+            let advertisements = parsed
+                .options
+                .iter()
+                .filter(|o| matches!(o, SocksOption::AuthMethodAdvertisement(_)))
+                .count();
+            assert_eq!(advertisements, if initial_data_length > 0 { 1 } else { 0 });
+        }
+        Ok(())
+    }
+
+    // Test that metadata survives a parse, serialize, parse round trip, including the chain keys.
+    #[tokio::test]
+    async fn test_metadata_round_trip() -> Result<()> {
+        let chain = SocksChain::new(
+            0,
+            vec![
+                ProxyAddress::new(6, String::from("localhost"), 1080, None),
+                ProxyAddress::new(6, String::from("localhost"), 1081, None),
+            ],
+        );
+        let mut metadata = HashMap::new();
+        metadata.insert(7, String::from("seven"));
+        metadata.insert(1, String::from("one"));
+
+        // The chain is carried as explicit options, the other metadata only in the map.
+        let request = Socks6Request::new(
+            Socks6Command::Connect,
+            Address::new("example.com", 80),
+            0,
+            chain.as_options(),
+            Some(metadata),
+        );
+        let parsed = read_request(&mut &request.into_socks_bytes()[..]).await?;
+        assert_eq!(parsed.metadata.len(), 2 + 4);
+        assert_eq!(parsed.metadata.get(&999), Some(&String::from("2")));
+        assert_eq!(parsed.metadata.get(&998), Some(&String::from("0")));
+        assert_eq!(parsed.metadata.get(&1001), Some(&String::from("socks6://localhost:1081")));
+
+        // Serializing the parsed request again doesn't duplicate the options it was parsed from.
+        let bytes = parsed.clone().into_socks_bytes();
+        assert_eq!(bytes.len(), parsed.encoded_len());
+        let reparsed = read_request(&mut &bytes[..]).await?;
+        assert_eq!(reparsed.metadata, parsed.metadata);
+        assert_eq!(reparsed.options.len(), parsed.options.len());
+
+        // Without any options, the metadata is emitted ordered by key.
+        let request = Socks6Request::new(
+            Socks6Command::Connect,
+            Address::new("example.com", 80),
+            0,
+            vec![],
+            Some(parsed.metadata.clone()),
+        );
+        let reparsed = read_request(&mut &request.into_socks_bytes()[..]).await?;
+        assert_eq!(reparsed.metadata, parsed.metadata);
+
+        let keys: Vec<u16> = reparsed
+            .options
+            .iter()
+            .filter_map(|o| match o {
+                SocksOption::Metadata(m) => Some(m.key),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(keys, vec![1, 7, 998, 999, 1000, 1001]);
+        Ok(())
+    }
+
+    // Test that encoded_len matches the actual output, and that the request is written into the given buffer.
+    #[test]
+    fn test_request_encoded_len() {
+        for destination in [
+            Address::new("192.168.1.1", 80),
+            Address::new("::1", 443),
+            Address::new("example.com", 8080),
+        ] {
+            let request = Socks6Request::new(
+                Socks6Command::Connect,
+                destination,
+                4,
+                vec![
+                    AuthMethodAdvertisementOption::new(4, vec![]).wrap(),
+                    MetadataOption::new(1, String::from("value")).wrap(),
+                ],
+                None,
+            );
+
+            let mut buffer = Vec::with_capacity(request.encoded_len());
+            request.write_socks_bytes(&mut buffer);
+            assert_eq!(buffer.len(), request.encoded_len());
+            assert_eq!(buffer.capacity(), request.encoded_len());
+            assert_eq!(buffer, request.into_socks_bytes());
+        }
+    }
+
+    // Test that validated reads reject requests the policy rejects, but return the others.
+    #[tokio::test]
+    async fn test_read_request_validated() -> Result<()> {
+        let bytes = Socks6Request::new(Socks6Command::Connect, Address::new("192.0.2.1", 0), 0, vec![], None)
+            .into_socks_bytes();
+
+        let error = read_request_validated(&mut &bytes[..], ValidationPolicy::default()).await.unwrap_err();
+        assert_eq!(error.downcast_ref::<ValidationError>().unwrap().findings, vec![Finding::ZeroPort]);
+
+        let request = read_request_validated(&mut &bytes[..], ValidationPolicy::lenient()).await?;
+        assert_eq!(request.destination, Address::new("192.0.2.1", 0));
+        Ok(())
+    }
+
+    // Test that the raw options block is only retained when asked for, and matches the bytes that were read.
+    #[tokio::test]
+    async fn test_read_request_raw() -> Result<()> {
+        let options = vec![
+            MetadataOption::new(1, String::from("a")).wrap(),
+            MetadataOption::new(2, String::from("b")).wrap(),
+        ];
+        let bytes = Socks6Request::new(Socks6Command::Connect, Address::new("example.com", 80), 0, options, None)
+            .into_socks_bytes();
+        let options_start = 2 + Address::new("example.com", 80).encoded_len() + 1;
+
+        assert!(read_request(&mut &bytes[..]).await?.raw_options().is_none());
+
+        let request = read_request_raw(&mut &bytes[..]).await?;
+        assert_eq!(request.raw_options().unwrap(), &bytes[options_start + 2..]);
+        assert_eq!(request.raw_option_pairs().unwrap().len(), 2);
+
+        let (options, raw) = read_options_raw(&mut &bytes[options_start..]).await?;
+        assert_eq!(options.len(), 2);
+        assert_eq!(raw, &bytes[options_start + 2..]);
+        Ok(())
+    }
+
+    // Test that a request read through a timeout that keeps dropping the read halfway is resumed, not misparsed.
+    #[tokio::test(start_paused = true)]
+    async fn test_read_request_resumable() -> Result<()> {
+        use std::time::Duration;
+
+        let options = vec![MetadataOption::new(1, "a".repeat(32)).wrap()];
+        let request = Socks6Request::new(Socks6Command::Connect, Address::new("example.com", 80), 0, options, None);
+        let bytes = request.clone().into_socks_bytes();
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            for chunk in bytes.chunks(3) {
+                client.write_all(chunk).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            client
+        });
+
+        let mut reader = MessageReader::new();
+        let mut cancelled = 0;
+        let parsed = loop {
+            let read = read_request_resumable(&mut server, &mut reader, Socks6Draft::default());
+            match tokio::time::timeout(Duration::from_millis(25), read).await {
+                Ok(parsed) => break parsed?,
+                Err(_) => {
+                    assert!(reader.is_partial());
+                    cancelled += 1;
+                }
+            }
+        };
+
+        assert!(cancelled > 0);
+        assert!(!reader.is_partial());
+        assert_eq!(parsed.destination, request.destination);
+        assert_eq!(parsed.options.len(), 1);
+        Ok(())
+    }
+
+    // Test that a malformed request fails the same way when it is read again, instead of hanging.
+    #[tokio::test]
+    async fn test_read_request_resumable_malformed() -> Result<()> {
+        let mut reader = MessageReader::new();
+        let bytes = [SOCKS_VER_6, 0x09, 0, 0, 0];
+        for _ in 0..2 {
+            let error = read_request_resumable(&mut &bytes[..], &mut reader, Socks6Draft::default()).await;
+            assert!(matches!(error.unwrap_err().downcast_ref(), Some(crate::SocksError::UnknownCommand(0x09))));
+        }
+        Ok(())
+    }
+
+    // Test that a scratch buffer is reused, instead of reallocated, across requests.
+    #[tokio::test]
+    async fn test_read_request_reuses_scratch() -> Result<()> {
+        let request = Socks6Request::new(
+            Socks6Command::Connect,
+            Address::new("example.com", 80),
+            0,
+            vec![MetadataOption::new(1, "a".repeat(64)).wrap(), MetadataOption::new(2, "b".repeat(64)).wrap()],
+            None,
+        );
+        let bytes = request.into_socks_bytes();
+
+        let mut scratch = BytesMut::with_capacity(1024);
+        let pointer = scratch.as_ptr();
+
+        for _ in 0..3 {
+            let parsed = read_request_buf(&mut &bytes[..], &mut scratch).await?;
+            assert_eq!(parsed.metadata.len(), 2);
+            assert_eq!(parsed.destination, Address::new("example.com", 80));
+        }
+
+        assert_eq!(scratch.capacity(), 1024);
+        assert_eq!(scratch.as_ptr(), pointer);
+        Ok(())
+    }
+
+    // Reader that counts its reads and hands out all of its remaining bytes on each of them.
+    struct CountingReader {
+        data: Vec<u8>,
+        position: usize,
+        reads: usize,
+    }
+
+    impl AsyncRead for CountingReader {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            self.reads += 1;
+            let length = buf.remaining().min(self.data.len() - self.position);
+            buf.put_slice(&self.data[self.position..self.position + length]);
+            self.position += length;
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    // Test that reading a request through the handshake reader takes far fewer reads.
+    #[tokio::test]
+    async fn test_buffered_request_reads() -> Result<()> {
+        let request = Socks6Request::new(
+            Socks6Command::Connect,
+            Address::new("example.com", 80),
+            0,
+            vec![MetadataOption::new(1, String::from("a")).wrap(), MetadataOption::new(2, String::from("b")).wrap()],
+            None,
+        );
+        let bytes = request.into_socks_bytes();
+
+        let mut unbuffered = CountingReader { data: bytes.clone(), position: 0, reads: 0 };
+        read_request(&mut unbuffered).await?;
+
+        let mut buffered = crate::util::handshake_reader(CountingReader { data: bytes, position: 0, reads: 0 });
+        read_request(&mut buffered).await?;
+
+        assert!(unbuffered.reads >= 6, "{} reads", unbuffered.reads);
+        assert_eq!(buffered.get_ref().reads, 1);
+        Ok(())
+    }
+
+    // Test that metadata reads back the same from either encoding, and that packing it never takes more bytes.
+    #[tokio::test]
+    async fn test_packed_metadata() -> Result<()> {
+        for count in [0u16, 1, 50] {
+            let metadata: HashMap<u16, String> = (0..count).map(|key| (key, format!("value-{}", key))).collect();
+            let request = |packed| {
+                Socks6Request::new(Socks6Command::Connect, Address::new("example.com", 80), 0, vec![], None)
+                    .with_packed_metadata(packed)
+            };
+
+            let mut unpacked = request(false);
+            unpacked.metadata = metadata.clone();
+            let mut packed = request(true);
+            packed.metadata = metadata.clone();
+            let (unpacked, packed) = (unpacked.into_socks_bytes(), packed.into_socks_bytes());
+
+            assert_eq!(read_request(&mut &unpacked[..]).await?.metadata, metadata);
+            assert_eq!(read_request(&mut &packed[..]).await?.metadata, metadata);
+            match count {
+                0 => assert_eq!(packed, unpacked),
+                // The entry count and value length cost more than the option header saves for a single entry.
+                1 => assert_eq!(packed.len(), unpacked.len() + 4),
+                _ => assert!(packed.len() < unpacked.len(), "{} >= {}", packed.len(), unpacked.len()),
+            }
+        }
+        Ok(())
+    }
+
+    // Test that compressed metadata reads back the same, smaller than packed, and that it lifts the size limit of
+    // the options block up to the limit on inflated metadata.
+    #[tokio::test]
+    async fn test_compressed_metadata() -> Result<()> {
+        let request = |metadata: HashMap<u16, String>, compressed| {
+            let mut request =
+                Socks6Request::new(Socks6Command::Connect, Address::new("example.com", 80), 0, vec![], None)
+                    .with_packed_metadata(true)
+                    .with_compressed_metadata(compressed);
+            request.metadata = metadata;
+            request
+        };
+
+        let metadata: HashMap<u16, String> = (0..50).map(|key| (key, format!("value-{}", key % 5))).collect();
+        let packed = request(metadata.clone(), false).into_socks_bytes();
+        let compressed = request(metadata.clone(), true);
+        assert_eq!(compressed.encoded_len(), compressed.clone().into_socks_bytes().len());
+        let compressed = compressed.into_socks_bytes();
+        assert!(compressed.len() < packed.len(), "{} >= {}", compressed.len(), packed.len());
+        assert_eq!(read_request(&mut &compressed[..]).await?.metadata, metadata);
+
+        // Entries that don't compress are sent packed.
+        let metadata = HashMap::from([(1, String::from("v"))]);
+        assert_eq!(request(metadata.clone(), true).into_socks_bytes(), request(metadata, false).into_socks_bytes());
+
+        // Four values of 60000 bytes take more than an options block unless compressed, five inflate beyond the limit.
+        let metadata: HashMap<u16, String> = (0..4).map(|key| (key, "x".repeat(60_000))).collect();
+        let compressed = request(metadata.clone(), true).into_socks_bytes();
+        assert_eq!(read_request(&mut &compressed[..]).await?.metadata, metadata);
+        let metadata: HashMap<u16, String> = (0..5).map(|key| (key, "x".repeat(60_000))).collect();
+        let compressed = request(metadata, true).into_socks_bytes();
+        let error = read_request(&mut &compressed[..]).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("inflate"), "{:#}", error);
+
+        Ok(())
+    }
+
+    // Test that the same metadata is encoded to the same bytes, regardless of the order it was inserted in.
+    #[test]
+    fn test_metadata_order() {
+        for packed in [false, true] {
+            let encode = |keys: &[u16]| {
+                let mut request =
+                    Socks6Request::new(Socks6Command::Connect, Address::new("example.com", 80), 0, vec![], None)
+                        .with_packed_metadata(packed);
+                request.metadata = keys.iter().map(|key| (*key, key.to_string())).collect();
+                request.into_socks_bytes()
+            };
+
+            let keys: Vec<u16> = (0..50).collect();
+            let reversed: Vec<u16> = keys.iter().rev().copied().collect();
+            assert_eq!(encode(&keys), encode(&reversed));
+        }
+    }
 }
\ No newline at end of file
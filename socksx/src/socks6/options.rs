@@ -1,41 +1,298 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
 use anyhow::Result;
 use num_traits::FromPrimitive;
 
+use crate::constants::*;
+use crate::errors::UnknownValue;
+use crate::socks6::Socks6Draft;
+
 /// Represents SOCKS authentication methods.
 #[repr(u8)]
 #[derive(Clone, Debug, FromPrimitive, PartialEq)]
+#[cfg_attr(any(test, feature = "arbitrary"), derive(arbitrary::Arbitrary))]
 pub enum AuthMethod {
     NoAuthentication = 0x00,
     Gssapi = 0x01,
     UsernamePassword = 0x02,
+    /// A bearer token, e.g., an OAuth access token, in the range of methods reserved for private use.
+    BearerToken = 0x80,
     NoAcceptableMethods = 0xFF,
 }
 
+impl TryFrom<u8> for AuthMethod {
+    type Error = UnknownValue;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Self::from_u8(value).ok_or(UnknownValue {
+            kind: "authentication method",
+            value,
+        })
+    }
+}
+
+/// Kinds of SOCKS options, regardless of the draft revision that numbers them.
+///
+/// Converting a kind from a number and back never loses it: a number without a variant of its own is kept in
+/// `Other`. The `From` conversions use the numbering of the default draft revision.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum SocksOptionKind {
+    Stack,
+    AuthMethodAdvertisement,
+    AuthMethodSelection,
+    AuthData,
+    Metadata,
+    /// A kind that isn't recognized, with the number it has on the wire.
+    Other(u16),
+}
+
+impl SocksOptionKind {
+    /// Returns the kind with the given number in the draft revision, if it has a variant of its own.
+    pub fn known_for(
+        kind: u16,
+        draft: Socks6Draft,
+    ) -> Option<Self> {
+        use SocksOptionKind::*;
+
+        let constants = draft.constants();
+        match kind {
+            k if k == constants.okind_stack => Some(Stack),
+            k if k == constants.okind_auth_meth_adv => Some(AuthMethodAdvertisement),
+            k if k == constants.okind_auth_meth_sel => Some(AuthMethodSelection),
+            k if k == constants.okind_auth_data => Some(AuthData),
+            k if k == constants.okind_metadata => Some(Metadata),
+            _ => None,
+        }
+    }
+
+    /// Returns the kind with the given number in the draft revision, which is `Other` if it isn't recognized.
+    pub fn from_u16_for(
+        kind: u16,
+        draft: Socks6Draft,
+    ) -> Self {
+        Self::known_for(kind, draft).unwrap_or(SocksOptionKind::Other(kind))
+    }
+
+    /// Returns the number of the kind in the draft revision.
+    pub fn to_u16_for(
+        self,
+        draft: Socks6Draft,
+    ) -> u16 {
+        use SocksOptionKind::*;
+
+        let constants = draft.constants();
+        match self {
+            Stack => constants.okind_stack,
+            AuthMethodAdvertisement => constants.okind_auth_meth_adv,
+            AuthMethodSelection => constants.okind_auth_meth_sel,
+            AuthData => constants.okind_auth_data,
+            Metadata => constants.okind_metadata,
+            Other(kind) => kind,
+        }
+    }
+}
+
+impl From<u16> for SocksOptionKind {
+    fn from(kind: u16) -> Self {
+        Self::from_u16_for(kind, Socks6Draft::default())
+    }
+}
+
+impl From<SocksOptionKind> for u16 {
+    fn from(kind: SocksOptionKind) -> Self {
+        kind.to_u16_for(Socks6Draft::default())
+    }
+}
+
 /// Enumerates the types of SOCKS options.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum SocksOption {
     AuthMethodAdvertisement(AuthMethodAdvertisementOption),
     AuthMethodSelection(AuthMethodSelectionOption),
+    AuthData(AuthDataOption),
     Metadata(MetadataOption),
     Unrecognized(UnrecognizedOption),
+    /// An option of a known kind whose data couldn't be parsed, kept as read by a parse in `ParseMode::Recover`.
+    /// Unlike unrecognized options, malformed options are never forwarded.
+    Malformed(UnrecognizedOption),
 }
 
 impl SocksOption {
     /// Converts the SOCKS option to a vector of bytes.
     pub fn as_socks_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.encoded_len());
+        self.write_socks_bytes(&mut bytes);
+
+        bytes
+    }
+
+    /// Returns the number of bytes `write_socks_bytes` appends for this option, including padding.
+    pub fn encoded_len(&self) -> usize {
         use SocksOption::*;
 
         match self {
-            AuthMethodAdvertisement(option) => option.clone().into_socks_bytes(),
-            AuthMethodSelection(option) => option.clone().into_socks_bytes(),
-            Metadata(option) => option.clone().into_socks_bytes(),
-            Unrecognized(option) => option.clone().into_socks_bytes(),
+            AuthMethodAdvertisement(option) => option.encoded_len(),
+            AuthMethodSelection(option) => option.encoded_len(),
+            AuthData(option) => option.encoded_len(),
+            Metadata(option) => option.encoded_len(),
+            Unrecognized(option) | Malformed(option) => option.encoded_len(),
+        }
+    }
+
+    /// Returns the kind of the option, taking the number of a stack option in the default draft revision.
+    pub fn kind(&self) -> SocksOptionKind {
+        self.kind_for(Socks6Draft::default())
+    }
+
+    /// Returns the kind of the option, taking the number of a stack option in the given draft revision.
+    ///
+    /// Unrecognized and malformed options are of kind `Other` with their number, even if the number is of a kind
+    /// that has a typed variant, as they weren't read as one. Stack options have no typed variant, so unrecognized
+    /// options are of kind `Stack` if their number is of stack options.
+    pub fn kind_for(
+        &self,
+        draft: Socks6Draft,
+    ) -> SocksOptionKind {
+        use SocksOption::*;
+
+        match self {
+            AuthMethodAdvertisement(_) => SocksOptionKind::AuthMethodAdvertisement,
+            AuthMethodSelection(_) => SocksOptionKind::AuthMethodSelection,
+            AuthData(_) => SocksOptionKind::AuthData,
+            Metadata(_) => SocksOptionKind::Metadata,
+            Unrecognized(option) => match SocksOptionKind::known_for(option.kind(), draft) {
+                Some(SocksOptionKind::Stack) => SocksOptionKind::Stack,
+                _ => SocksOptionKind::Other(option.kind()),
+            },
+            Malformed(option) => SocksOptionKind::Other(option.kind()),
+        }
+    }
+
+    /// Appends the SOCKS representation of the option to the buffer.
+    pub fn write_socks_bytes(
+        &self,
+        bytes: &mut Vec<u8>,
+    ) {
+        self.write_socks_bytes_for(Socks6Draft::default(), bytes);
+    }
+
+    /// Appends the option to the buffer, using the option kinds of the given draft revision.
+    pub fn write_socks_bytes_for(
+        &self,
+        draft: Socks6Draft,
+        bytes: &mut Vec<u8>,
+    ) {
+        use SocksOption::*;
+
+        let kind = self.kind_for(draft).to_u16_for(draft);
+        match self {
+            AuthMethodAdvertisement(option) => option.write_socks_bytes_as(kind, bytes),
+            AuthMethodSelection(option) => option.write_socks_bytes_as(kind, bytes),
+            AuthData(option) => option.write_socks_bytes_as(kind, bytes),
+            Metadata(option) => option.write_socks_bytes_as(kind, bytes),
+            Unrecognized(option) | Malformed(option) => option.write_socks_bytes(bytes),
         }
     }
 }
 
+/// Typed lookups among the options of a message, so that consumers don't have to match on every option.
+///
+/// Where an option occurs more than once, the first occurrence counts, except for metadata, where a later
+/// entry for the same key replaces an earlier one.
+pub trait SocksOptions {
+    /// Returns the first authentication method advertisement.
+    fn auth_advertisement(&self) -> Option<&AuthMethodAdvertisementOption>;
+
+    /// Returns the first authentication method selection.
+    fn auth_selection(&self) -> Option<&AuthMethodSelectionOption>;
+
+    /// Returns the first authentication data for the given method.
+    fn auth_data(
+        &self,
+        method: &AuthMethod,
+    ) -> Option<&AuthDataOption>;
+
+    /// Returns the initial data length announced by the advertisement, or zero without one.
+    fn initial_data_length(&self) -> u16 {
+        self.auth_advertisement().map_or(0, |advertisement| advertisement.initial_data_length)
+    }
+
+    /// Collects the metadata by key.
+    fn metadata(&self) -> HashMap<u16, &str>;
+
+    /// Returns the first option of the given kind, taking numbers in the default draft revision.
+    fn find<K: Into<SocksOptionKind>>(
+        &self,
+        kind: K,
+    ) -> Option<&SocksOption>;
+
+    /// Returns the options that weren't recognized, in order.
+    fn unrecognized(&self) -> impl Iterator<Item = &UnrecognizedOption>;
+
+    /// Returns the first TTL stack option.
+    fn ttl(&self) -> Option<TtlOption> {
+        self.unrecognized().find_map(TtlOption::from_unrecognized)
+    }
+
+    /// Returns the first option with method-specific data for the method advertisement.
+    fn auth_method_data(&self) -> Option<AuthMethodDataOption> {
+        self.unrecognized().find_map(AuthMethodDataOption::from_unrecognized)
+    }
+}
+
+impl SocksOptions for [SocksOption] {
+    fn auth_advertisement(&self) -> Option<&AuthMethodAdvertisementOption> {
+        self.iter().find_map(|option| match option {
+            SocksOption::AuthMethodAdvertisement(advertisement) => Some(advertisement),
+            _ => None,
+        })
+    }
+
+    fn auth_selection(&self) -> Option<&AuthMethodSelectionOption> {
+        self.iter().find_map(|option| match option {
+            SocksOption::AuthMethodSelection(selection) => Some(selection),
+            _ => None,
+        })
+    }
+
+    fn auth_data(
+        &self,
+        method: &AuthMethod,
+    ) -> Option<&AuthDataOption> {
+        self.iter().find_map(|option| match option {
+            SocksOption::AuthData(data) if &data.method == method => Some(data),
+            _ => None,
+        })
+    }
+
+    fn metadata(&self) -> HashMap<u16, &str> {
+        self.iter()
+            .filter_map(|option| match option {
+                SocksOption::Metadata(metadata) => Some((metadata.key, metadata.value.as_str())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn find<K: Into<SocksOptionKind>>(
+        &self,
+        kind: K,
+    ) -> Option<&SocksOption> {
+        let kind = kind.into();
+        self.iter().find(|option| option.kind() == kind)
+    }
+
+    fn unrecognized(&self) -> impl Iterator<Item = &UnrecognizedOption> {
+        self.iter().filter_map(|option| match option {
+            SocksOption::Unrecognized(option) => Some(option),
+            _ => None,
+        })
+    }
+}
+
 /// Represents the authentication methods supported by the server.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct AuthMethodAdvertisementOption {
     pub initial_data_length: u16,
     pub methods: Vec<AuthMethod>,
@@ -59,7 +316,8 @@ impl AuthMethodAdvertisementOption {
     }
 
     /// Deserializes the option from bytes.
-    pub fn from_socks_bytes(bytes: Vec<u8>) -> Result<SocksOption> {
+    pub fn from_socks_bytes<B: AsRef<[u8]>>(bytes: B) -> Result<SocksOption> {
+        let bytes = bytes.as_ref();
         ensure!(bytes.len() >= 2, "Expected at least two bytes, got: {}", bytes.len());
         let initial_data_length = ((bytes[0] as u16) << 8) | bytes[1] as u16;
 
@@ -68,10 +326,10 @@ impl AuthMethodAdvertisementOption {
             .skip(2)
             .filter(|m| {
                 let m = **m;
-                // Ingore "No Authentication Required" (implied) and padding bytes.
-                m > 0 && m < 3
+                // Ingore "No Authentication Required" (implied), padding bytes, and "No Acceptable Methods".
+                m > 0 && m != SOCKS_AUTH_NO_ACCEPTABLE_METHODS
             })
-            .map(|m| AuthMethod::from_u8(*m).unwrap())
+            .filter_map(|m| AuthMethod::try_from(*m).ok())
             .collect();
 
         Ok(Self::new(initial_data_length, methods).wrap())
@@ -79,15 +337,109 @@ impl AuthMethodAdvertisementOption {
 
     /// Serializes the option into bytes.
     pub fn into_socks_bytes(self) -> Vec<u8> {
-        let mut data = self.initial_data_length.to_be_bytes().to_vec();
-        data.extend(self.methods.iter().cloned().map(|m| m as u8));
+        let mut bytes = Vec::with_capacity(self.encoded_len());
+        self.write_socks_bytes(&mut bytes);
+
+        bytes
+    }
+
+    /// Returns the number of bytes `write_socks_bytes` appends for this option, including padding.
+    pub fn encoded_len(&self) -> usize {
+        padded_len(2 + self.methods.len())
+    }
 
-        combine_and_pad(0x02, data)
+    /// Appends the SOCKS representation of the option to the buffer.
+    pub fn write_socks_bytes(
+        &self,
+        bytes: &mut Vec<u8>,
+    ) {
+        self.write_socks_bytes_as(SocksOptionKind::AuthMethodAdvertisement.into(), bytes);
+    }
+
+    // Appends the option to the buffer under the given kind, which differs between draft revisions.
+    pub(crate) fn write_socks_bytes_as(
+        &self,
+        kind: u16,
+        bytes: &mut Vec<u8>,
+    ) {
+        let start = write_header(kind, 2 + self.methods.len(), bytes);
+        bytes.extend_from_slice(&self.initial_data_length.to_be_bytes());
+        bytes.extend(self.methods.iter().cloned().map(|m| m as u8));
+        write_padding(start, bytes);
+    }
+}
+
+/// A typed view of the option that carries method-specific data along with the method advertisement, a socksx
+/// extension of kind `SOCKS_OKIND_AUTH_METHOD_DATA`, so that methods without a challenge, e.g.,
+/// username/password, complete along with the request.
+///
+/// The option is parsed as an unrecognized option, which proxies that don't know the extension ignore. On the
+/// wire, the number of entries takes two bytes, followed by the entries, each the method, the length of the data
+/// as two bytes, and the data. The padding follows the last entry.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuthMethodDataOption {
+    /// The method-specific data, without padding. Entries for methods that aren't recognized are dropped when
+    /// parsing.
+    pub method_data: Vec<AuthDataOption>,
+}
+
+impl AuthMethodDataOption {
+    /// Constructs a new `AuthMethodDataOption`, with at most `u16::MAX` bytes of data per method.
+    pub fn new(method_data: Vec<AuthDataOption>) -> Self {
+        Self { method_data }
+    }
+
+    /// Returns the data for the given method.
+    pub fn data_for(
+        &self,
+        method: &AuthMethod,
+    ) -> Option<&[u8]> {
+        self.method_data.iter().find(|data| &data.method == method).map(|data| data.data.as_slice())
+    }
+
+    /// Reads the option from an unrecognized option, if it's of kind `SOCKS_OKIND_AUTH_METHOD_DATA` and its
+    /// entries fit in it.
+    pub fn from_unrecognized(option: &UnrecognizedOption) -> Option<Self> {
+        if option.kind() != SOCKS_OKIND_AUTH_METHOD_DATA {
+            return None;
+        }
+        let (count, mut entries) = match option.data() {
+            [high, low, entries @ ..] => (u16::from_be_bytes([*high, *low]), entries),
+            _ => return None,
+        };
+
+        let mut method_data = vec![];
+        for _ in 0..count {
+            let (method, length) = match entries {
+                [method, high, low, ..] => (*method, u16::from_be_bytes([*high, *low]) as usize),
+                _ => return None,
+            };
+            let data = entries.get(3..3 + length)?;
+            if let Ok(method) = AuthMethod::try_from(method) {
+                method_data.push(AuthDataOption::new(method, data.to_vec()));
+            }
+            entries = &entries[3 + length..];
+        }
+
+        Some(Self { method_data })
+    }
+
+    /// Wraps the instance into a `SocksOption`, as an unrecognized option of kind `SOCKS_OKIND_AUTH_METHOD_DATA`.
+    pub fn wrap(self) -> SocksOption {
+        let mut data = Vec::with_capacity(2 + self.method_data.iter().map(|d| 3 + d.data.len()).sum::<usize>());
+        data.extend_from_slice(&(self.method_data.len() as u16).to_be_bytes());
+        for entry in &self.method_data {
+            data.push(entry.method.clone() as u8);
+            data.extend_from_slice(&(entry.data.len() as u16).to_be_bytes());
+            data.extend_from_slice(&entry.data);
+        }
+
+        UnrecognizedOption::new(SOCKS_OKIND_AUTH_METHOD_DATA, data).wrap()
     }
 }
 
 /// Represents the authentication methods selected by the client.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct AuthMethodSelectionOption {
     pub method: AuthMethod,
 }
@@ -104,27 +456,115 @@ impl AuthMethodSelectionOption {
     }
 
     /// Deserializes the option from bytes.
-    pub fn from_socks_bytes(bytes: Vec<u8>) -> Result<SocksOption> {
+    pub fn from_socks_bytes<B: AsRef<[u8]>>(bytes: B) -> Result<SocksOption> {
+        let bytes = bytes.as_ref();
         ensure!(bytes.len() == 4, "Expected exactly four bytes, got: {}", bytes.len());
 
-        let method = bytes[0];
-        if let Some(method) = AuthMethod::from_u8(method) {
-            Ok(Self::new(method).wrap())
-        } else {
-            bail!("Not a valid authentication method selection: {}", method)
-        }
+        Ok(Self::new(AuthMethod::try_from(bytes[0])?).wrap())
+    }
+
+    /// Serializes the option into bytes.
+    pub fn into_socks_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.encoded_len());
+        self.write_socks_bytes(&mut bytes);
+
+        bytes
+    }
+
+    /// Returns the number of bytes `write_socks_bytes` appends for this option, including padding.
+    pub fn encoded_len(&self) -> usize {
+        padded_len(1)
+    }
+
+    /// Appends the SOCKS representation of the option to the buffer.
+    pub fn write_socks_bytes(
+        &self,
+        bytes: &mut Vec<u8>,
+    ) {
+        self.write_socks_bytes_as(SocksOptionKind::AuthMethodSelection.into(), bytes);
+    }
+
+    // Appends the option to the buffer under the given kind, which differs between draft revisions.
+    pub(crate) fn write_socks_bytes_as(
+        &self,
+        kind: u16,
+        bytes: &mut Vec<u8>,
+    ) {
+        let start = write_header(kind, 1, bytes);
+        bytes.push(self.method.clone() as u8);
+        write_padding(start, bytes);
+    }
+}
+
+/// Represents the method-specific authentication data sent by the client.
+///
+/// Parsing strips the padding. Zero bytes at the end of the data can't be told apart from padding, and are
+/// stripped along with it, so methods whose data may end with zero bytes must encode their own lengths.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuthDataOption {
+    pub method: AuthMethod,
+    pub data: Vec<u8>,
+}
+
+impl AuthDataOption {
+    /// Constructs a new `AuthDataOption`.
+    pub fn new(
+        method: AuthMethod,
+        data: Vec<u8>,
+    ) -> Self {
+        Self { method, data }
+    }
+
+    /// Wraps the instance into a `SocksOption`.
+    pub fn wrap(self) -> SocksOption {
+        SocksOption::AuthData(self)
+    }
+
+    /// Deserializes the option from bytes.
+    pub fn from_socks_bytes<B: AsRef<[u8]>>(bytes: B) -> Result<SocksOption> {
+        let bytes = bytes.as_ref();
+        ensure!(!bytes.is_empty(), "Expected at least one byte, got none");
+        let end = bytes[1..].iter().rposition(|&b| b != 0).map_or(1, |i| i + 2);
+
+        Ok(Self::new(AuthMethod::try_from(bytes[0])?, bytes[1..end].to_vec()).wrap())
     }
 
     /// Serializes the option into bytes.
     pub fn into_socks_bytes(self) -> Vec<u8> {
-        let data = vec![self.method as u8];
+        let mut bytes = Vec::with_capacity(self.encoded_len());
+        self.write_socks_bytes(&mut bytes);
+
+        bytes
+    }
+
+    /// Returns the number of bytes `write_socks_bytes` appends for this option, including padding.
+    pub fn encoded_len(&self) -> usize {
+        padded_len(1 + self.data.len())
+    }
+
+    /// Appends the SOCKS representation of the option to the buffer.
+    pub fn write_socks_bytes(
+        &self,
+        bytes: &mut Vec<u8>,
+    ) {
+        self.write_socks_bytes_as(SocksOptionKind::AuthData.into(), bytes);
+    }
 
-        combine_and_pad(0x03, data)
+    // Appends the option to the buffer under the given kind, which differs between draft revisions.
+    pub(crate) fn write_socks_bytes_as(
+        &self,
+        kind: u16,
+        bytes: &mut Vec<u8>,
+    ) {
+        let start = write_header(kind, 1 + self.data.len(), bytes);
+        bytes.push(self.method.clone() as u8);
+        bytes.extend_from_slice(&self.data);
+        write_padding(start, bytes);
     }
 }
 
 /// Represents a metadata option.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct MetadataOption {
     pub key: u16,
     pub value: String,
@@ -145,14 +585,20 @@ impl MetadataOption {
     }
 
     /// Deserializes the option from bytes.
-    pub fn from_socks_bytes(bytes: Vec<u8>) -> Result<SocksOption> {
+    pub fn from_socks_bytes<B: AsRef<[u8]>>(bytes: B) -> Result<SocksOption> {
+        let bytes = bytes.as_ref();
         ensure!(bytes.len() >= 4, "Expected at least four bytes, got: {}", bytes.len());
         let key = ((bytes[0] as u16) << 8) | bytes[1] as u16;
         let length = ((bytes[2] as u16) << 8) | bytes[3] as u16;
+        ensure!(
+            bytes.len() >= (length as usize) + 4,
+            "Metadata value of {} bytes doesn't fit in the option",
+            length
+        );
 
-        let value = bytes[4..(length as usize) + 4].to_vec();
-        if let Ok(value) = String::from_utf8(value) {
-            Ok(Self::new(key, value).wrap())
+        let value = &bytes[4..(length as usize) + 4];
+        if let Ok(value) = std::str::from_utf8(value) {
+            Ok(Self::new(key, value.to_string()).wrap())
         } else {
             bail!("Not a valid metadata UTF-8 string: {:?}", bytes[2..].to_vec())
         }
@@ -160,17 +606,221 @@ impl MetadataOption {
 
     /// Serializes the option into bytes.
     pub fn into_socks_bytes(self) -> Vec<u8> {
-        let mut data = self.key.to_be_bytes().to_vec();
-        data.extend((self.value.len() as u16).to_be_bytes().iter());
-        data.extend(self.value.as_bytes().iter());
+        let mut bytes = Vec::with_capacity(self.encoded_len());
+        self.write_socks_bytes(&mut bytes);
+
+        bytes
+    }
+
+    /// Returns the number of bytes `write_socks_bytes` appends for this option, including padding.
+    pub fn encoded_len(&self) -> usize {
+        padded_len(2 + 2 + self.value.len())
+    }
+
+    /// Appends the SOCKS representation of the option to the buffer.
+    pub fn write_socks_bytes(
+        &self,
+        bytes: &mut Vec<u8>,
+    ) {
+        self.write_socks_bytes_as(SocksOptionKind::Metadata.into(), bytes);
+    }
+
+    // Appends the option to the buffer under the given kind, which differs between draft revisions.
+    pub(crate) fn write_socks_bytes_as(
+        &self,
+        kind: u16,
+        bytes: &mut Vec<u8>,
+    ) {
+        let start = write_header(kind, 2 + 2 + self.value.len(), bytes);
+        bytes.extend_from_slice(&self.key.to_be_bytes());
+        bytes.extend_from_slice(&(self.value.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(self.value.as_bytes());
+        write_padding(start, bytes);
+    }
+}
+
+// The most bytes the entries of a single packed metadata option may take, so that the option fits with its
+// header, entry count, and padding.
+const MAX_PACKED_ENTRIES_LEN: usize = u16::MAX as usize - 4 - 2 - 4;
+
+// The bit of the entry count of a packed metadata option that flags its entries as compressed.
+const COMPRESSED_ENTRIES_FLAG: u16 = 0x8000;
+
+/// The most bytes that the compressed metadata of a message may inflate to, together, so that a small message
+/// can't make its receiver allocate much more.
+pub const MAX_INFLATED_METADATA_LEN: usize = 256 * 1024;
+
+/// Returns the number of bytes `write_packed_metadata` appends for the metadata.
+pub fn packed_metadata_len(metadata: &[MetadataOption]) -> usize {
+    packed_chunks(metadata).iter().map(|chunk| padded_len(2 + entries_len(chunk))).sum()
+}
+
+/// Appends the metadata as packed metadata options, a socksx extension of kind `SOCKS_OKIND_PACKED_METADATA`.
+///
+/// Each option carries the number of its entries, followed by as many entries as fit, each a key and a
+/// length-prefixed value. This saves the header and padding of an option per entry.
+pub fn write_packed_metadata(
+    metadata: &[MetadataOption],
+    bytes: &mut Vec<u8>,
+) {
+    for chunk in packed_chunks(metadata) {
+        let start = write_header(SOCKS_OKIND_PACKED_METADATA, 2 + entries_len(chunk), bytes);
+        bytes.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+        write_entries(chunk, bytes);
+        write_padding(start, bytes);
+    }
+}
+
+/// Returns the number of bytes `write_compressed_metadata` appends for the metadata.
+pub fn compressed_metadata_len(metadata: &[MetadataOption]) -> usize {
+    packed_chunks(metadata)
+        .iter()
+        .map(|chunk| match compress_entries(chunk) {
+            Some(compressed) => padded_len(2 + 2 + compressed.len()),
+            None => padded_len(2 + entries_len(chunk)),
+        })
+        .sum()
+}
+
+/// Appends the metadata as packed metadata options like `write_packed_metadata`, with the entries of each option
+/// deflate-compressed, unless that doesn't make them smaller.
+///
+/// The entry count of an option with compressed entries has its highest bit set, and is followed by the length
+/// of the compressed entries. Only proxies that advertise support (see `metadata_compression_option`) decode them.
+pub fn write_compressed_metadata(
+    metadata: &[MetadataOption],
+    bytes: &mut Vec<u8>,
+) {
+    for chunk in packed_chunks(metadata) {
+        let Some(compressed) = compress_entries(chunk) else {
+            write_packed_metadata(chunk, bytes);
+            continue;
+        };
+
+        let start = write_header(SOCKS_OKIND_PACKED_METADATA, 2 + 2 + compressed.len(), bytes);
+        bytes.extend_from_slice(&(chunk.len() as u16 | COMPRESSED_ENTRIES_FLAG).to_be_bytes());
+        bytes.extend_from_slice(&(compressed.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&compressed);
+        write_padding(start, bytes);
+    }
+}
 
-        // kind: 65000
-        combine_and_pad(0xFDE8, data)
+/// Decodes the data of a packed metadata option into a metadata option per entry, in order.
+///
+/// Compressed entries may inflate to `MAX_INFLATED_METADATA_LEN` bytes at most.
+pub fn decode_packed_metadata(data: &[u8]) -> Result<Vec<SocksOption>> {
+    let mut inflate_limit = MAX_INFLATED_METADATA_LEN;
+    decode_packed_metadata_within(data, &mut inflate_limit)
+}
+
+/// Decodes the data of a packed metadata option like `decode_packed_metadata`, taking the bytes that compressed
+/// entries inflate to from the limit, which they may not exceed.
+pub(crate) fn decode_packed_metadata_within(
+    data: &[u8],
+    inflate_limit: &mut usize,
+) -> Result<Vec<SocksOption>> {
+    ensure!(data.len() >= 2, "Expected at least two bytes, got: {}", data.len());
+    let count = u16::from_be_bytes([data[0], data[1]]);
+    if count & COMPRESSED_ENTRIES_FLAG == 0 {
+        return decode_entries(&data[2..], count);
     }
+
+    ensure!(data.len() >= 4, "Compressed metadata lacks its length");
+    let length = u16::from_be_bytes([data[2], data[3]]) as usize;
+    ensure!(4 + length <= data.len(), "Compressed metadata of {} bytes doesn't fit in the option", length);
+
+    // Packed entries never take more than an option, compressed or not.
+    let limit = (*inflate_limit).min(MAX_PACKED_ENTRIES_LEN);
+    let entries = miniz_oxide::inflate::decompress_to_vec_with_limit(&data[4..4 + length], limit)
+        .map_err(|e| anyhow!("Failed to inflate compressed metadata within {} bytes: {}", limit, e))?;
+    *inflate_limit -= entries.len();
+
+    decode_entries(&entries, count & !COMPRESSED_ENTRIES_FLAG)
+}
+
+// Returns whether the raw option, header included, is a packed metadata option with compressed entries.
+pub(crate) fn is_compressed_packed_metadata(raw: &[u8]) -> bool {
+    raw.len() >= 6
+        && u16::from_be_bytes([raw[0], raw[1]]) == SOCKS_OKIND_PACKED_METADATA
+        && u16::from_be_bytes([raw[4], raw[5]]) & COMPRESSED_ENTRIES_FLAG != 0
+}
+
+// Appends the entries of a packed metadata option.
+fn write_entries(
+    entries: &[MetadataOption],
+    bytes: &mut Vec<u8>,
+) {
+    for entry in entries {
+        bytes.extend_from_slice(&entry.key.to_be_bytes());
+        bytes.extend_from_slice(&(entry.value.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(entry.value.as_bytes());
+    }
+}
+
+// Returns the entries deflate-compressed, if that, with the length it takes, makes them smaller.
+fn compress_entries(entries: &[MetadataOption]) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(entries_len(entries));
+    write_entries(entries, &mut bytes);
+    let compressed = miniz_oxide::deflate::compress_to_vec(&bytes, 6);
+
+    Some(compressed).filter(|compressed| 2 + compressed.len() < bytes.len())
+}
+
+// Decodes the given number of entries of a packed metadata option, ignoring the bytes after them.
+fn decode_entries(
+    mut entries: &[u8],
+    count: u16,
+) -> Result<Vec<SocksOption>> {
+    let mut options = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        ensure!(entries.len() >= 4, "Packed metadata ends before entry {} of {}", options.len() + 1, count);
+        let key = u16::from_be_bytes([entries[0], entries[1]]);
+        let length = u16::from_be_bytes([entries[2], entries[3]]) as usize;
+        ensure!(
+            4 + length <= entries.len(),
+            "Packed metadata value of {} bytes doesn't fit in the option",
+            length
+        );
+
+        let value = std::str::from_utf8(&entries[4..4 + length])
+            .map_err(|_| anyhow!("Not a valid metadata UTF-8 string for key {}", key))?;
+        options.push(MetadataOption::new(key, value.to_string()).wrap());
+        entries = &entries[4 + length..];
+    }
+
+    Ok(options)
+}
+
+// Splits the metadata into runs of entries that each fit in a packed metadata option.
+fn packed_chunks(metadata: &[MetadataOption]) -> Vec<&[MetadataOption]> {
+    let mut chunks = vec![];
+    let mut start = 0;
+    let mut length = 0;
+    for (i, entry) in metadata.iter().enumerate() {
+        let entry_len = 4 + entry.value.len();
+        if i > start && length + entry_len > MAX_PACKED_ENTRIES_LEN {
+            chunks.push(&metadata[start..i]);
+            start = i;
+            length = 0;
+        }
+        length += entry_len;
+    }
+    if start < metadata.len() {
+        chunks.push(&metadata[start..]);
+    }
+
+    chunks
+}
+
+// Returns the number of bytes the entries take in a packed metadata option.
+fn entries_len(entries: &[MetadataOption]) -> usize {
+    entries.iter().map(|entry| 4 + entry.value.len()).sum()
 }
 
 /// Represents an unrecognized option.
-#[derive(Clone, Debug)]
+///
+/// Its data is opaque, including any padding it was received with, so that it is written back unchanged.
+#[derive(Clone, Debug, PartialEq)]
 pub struct UnrecognizedOption {
     kind: u16,
     data: Vec<u8>,
@@ -185,49 +835,151 @@ impl UnrecognizedOption {
         Self { kind, data }
     }
 
+    /// Returns the kind of the option.
+    pub fn kind(&self) -> u16 {
+        self.kind
+    }
+
+    /// Returns the data of the option.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
     /// Wraps the instance into a `SocksOption`.
     pub fn wrap(self) -> SocksOption {
         SocksOption::Unrecognized(self)
     }
 
-    /// Deserializes the option from bytes.
+    /// Serializes the option into bytes.
     pub fn into_socks_bytes(self) -> Vec<u8> {
-        combine_and_pad(self.kind, self.data)
+        let mut bytes = Vec::with_capacity(self.encoded_len());
+        self.write_socks_bytes(&mut bytes);
+
+        bytes
+    }
+
+    /// Returns the number of bytes `write_socks_bytes` appends for this option, including padding.
+    ///
+    /// Unlike other options, the data is only padded up to the next multiple of four bytes.
+    pub fn encoded_len(&self) -> usize {
+        (2 + 2 + self.data.len()).next_multiple_of(4)
+    }
+
+    /// Appends the SOCKS representation of the option to the buffer.
+    pub fn write_socks_bytes(
+        &self,
+        bytes: &mut Vec<u8>,
+    ) {
+        let start = bytes.len();
+        bytes.reserve(self.encoded_len());
+        bytes.extend_from_slice(&self.kind.to_be_bytes());
+        bytes.extend_from_slice(&(self.encoded_len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&self.data);
+        write_padding(start, bytes);
     }
 }
 
-/// Combines and pads the SOCKS option bytes.
+/// The connections of a session that a stack option applies to.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Eq, FromPrimitive, PartialEq)]
+pub enum StackLeg {
+    ClientProxy = SOCKS_STACK_LEG_CLIENT_PROXY,
+    ProxyRemote = SOCKS_STACK_LEG_PROXY_REMOTE,
+    Both = SOCKS_STACK_LEG_BOTH,
+}
+
+impl StackLeg {
+    /// Returns whether the leg includes the connection between the proxy and the destination.
+    pub fn includes_remote(self) -> bool {
+        matches!(self, StackLeg::ProxyRemote | StackLeg::Both)
+    }
+}
+
+/// A typed view of the stack option that requests, or in a reply grants, the TTL (IPv4) or hop limit (IPv6) of
+/// the packets of a leg.
+///
+/// Stack options are parsed as unrecognized options of kind `SocksOptionKind::Stack`, which this view is read
+/// from, so that other stack options are still passed on untouched. On the wire, the leg takes the upper two bits
+/// and the level the lower six bits of the first byte, followed by the code and the TTL.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TtlOption {
+    pub leg: StackLeg,
+    pub ttl: u8,
+}
+
+impl TtlOption {
+    /// Constructs a new `TtlOption`.
+    pub fn new(
+        leg: StackLeg,
+        ttl: u8,
+    ) -> Self {
+        Self { leg, ttl }
+    }
+
+    /// Reads the option from an unrecognized option, if it's a TTL stack option.
+    pub fn from_unrecognized(option: &UnrecognizedOption) -> Option<Self> {
+        if option.kind() != SOCKS_OKIND_STACK {
+            return None;
+        }
+        match *option.data() {
+            [leg_level, SOCKS_STACK_CODE_TTL, ttl, ..] if leg_level & 0x3F == SOCKS_STACK_LEVEL_IP => {
+                let leg = StackLeg::from_u8(leg_level >> 6)?;
+                Some(Self { leg, ttl })
+            }
+            _ => None,
+        }
+    }
+
+    /// Wraps the instance into a `SocksOption`, as an unrecognized option of kind `SocksOptionKind::Stack`.
+    pub fn wrap(self) -> SocksOption {
+        let leg_level = (self.leg as u8) << 6 | SOCKS_STACK_LEVEL_IP;
+        UnrecognizedOption::new(SOCKS_OKIND_STACK, vec![leg_level, SOCKS_STACK_CODE_TTL, self.ttl]).wrap()
+    }
+}
+
+/// Computes the total length of an option, including its kind, length, and padding bytes.
 ///
 /// # Parameters
 ///
-/// - `kind`: The kind of the SOCKS option.
-/// - `data`: The data associated with the SOCKS option.
+/// - `data_length`: The number of data bytes of the SOCKS option.
 ///
 /// # Returns
 ///
-/// A vector of bytes representing the padded SOCKS option.
-fn combine_and_pad(
-    kind: u16,
-    data: Vec<u8>,
-) -> Vec<u8> {
+/// The number of bytes the option occupies on the wire.
+fn padded_len(data_length: usize) -> usize {
     // The total length of the option is the combined number of bytes of
     // the kind, length, and data fields, plus the number of padding bytes.
-    let option_length = data.len() + 2 + 2;
-    let padding_bytes = vec![0; 4 - (option_length % 4)];
-    let total_length: u16 = (option_length + padding_bytes.len()) as u16;
+    let option_length = data_length + 2 + 2;
+    option_length + 4 - (option_length % 4)
+}
 
-    let mut bytes = vec![];
-    bytes.extend(kind.to_be_bytes().iter());
-    bytes.extend(total_length.to_be_bytes().iter());
-    bytes.extend(data);
-    bytes.extend(padding_bytes);
+/// Appends the kind and total length of an option, returning where the option starts.
+fn write_header(
+    kind: u16,
+    data_length: usize,
+    bytes: &mut Vec<u8>,
+) -> usize {
+    let start = bytes.len();
+    bytes.reserve(padded_len(data_length));
+    bytes.extend_from_slice(&kind.to_be_bytes());
+    bytes.extend_from_slice(&(padded_len(data_length) as u16).to_be_bytes());
+
+    start
+}
 
-    bytes
+/// Pads the option that starts at `start` up to the length announced in its header.
+fn write_padding(
+    start: usize,
+    bytes: &mut Vec<u8>,
+) {
+    let total_length = u16::from_be_bytes([bytes[start + 2], bytes[start + 3]]) as usize;
+    bytes.resize(start + total_length, 0);
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::wire;
 
     // Test the AuthMethod enum conversion from primitive types
     #[test]
@@ -249,11 +1001,10 @@ mod tests {
     fn test_auth_method_advertisement_option_wrap() {
         let option = AuthMethodAdvertisementOption::new(0, vec![]);
         let wrapped = option.wrap();
-        if let SocksOption::AuthMethodAdvertisement(_) = wrapped {
-            assert!(true);
-        } else {
-            assert!(false, "Expected AuthMethodAdvertisement variant");
-        }
+        assert!(
+            matches!(wrapped, SocksOption::AuthMethodAdvertisement(_)),
+            "Expected AuthMethodAdvertisement variant"
+        );
     }
 
     // Test the from_socks_bytes function for AuthMethodAdvertisementOption
@@ -264,4 +1015,277 @@ mod tests {
         // Verify the result according to your expectations
         assert!(result.is_ok());
     }
+
+    // Test the wire format of method-specific data, whose entries hold zero and 0xFF bytes that mustn't be taken
+    // for padding, and which leaves the advertisement as the draft defines it.
+    #[test]
+    fn test_auth_method_data() {
+        let option = AuthMethodDataOption::new(vec![
+            AuthDataOption::new(AuthMethod::UsernamePassword, vec![0x00, 0xFF, 0x00]),
+            AuthDataOption::new(AuthMethod::NoAuthentication, vec![]),
+        ])
+        .wrap();
+        let expected = vec![
+            0xFD, 0xEB, 0x00, 0x10, // kind, length
+            0x00, 0x02, // entry count
+            0x02, 0x00, 0x03, 0x00, 0xFF, 0x00, // username/password data
+            0x00, 0x00, 0x00, // no authentication data
+            0x00, // padding
+        ];
+        assert_eq!(option.encoded_len(), expected.len());
+        assert_eq!(option.as_socks_bytes(), expected);
+
+        let mut block = vec![];
+        wire::encode_options_for(&[option], Socks6Draft::default(), &mut block);
+        let parsed = match wire::parse_options(&block).unwrap() {
+            wire::Parsed::Complete(options, _) => options.auth_method_data().unwrap(),
+            wire::Parsed::Incomplete(_) => panic!("Expected a complete options block"),
+        };
+        assert_eq!(parsed.method_data.len(), 2);
+        assert_eq!(parsed.data_for(&AuthMethod::UsernamePassword), Some(&[0x00, 0xFF, 0x00][..]));
+        assert_eq!(parsed.data_for(&AuthMethod::NoAuthentication), Some(&[][..]));
+
+        let bytes = AuthMethodAdvertisementOption::new(5, vec![AuthMethod::UsernamePassword]).into_socks_bytes();
+        assert_eq!(bytes, vec![0x00, 0x02, 0x00, 0x08, 0x00, 0x05, 0x02, 0x00]);
+    }
+
+    // Test that entries of unknown methods are skipped, and that entries that don't fit in the option are rejected.
+    #[test]
+    fn test_auth_method_data_parse() {
+        let data = vec![0x00, 0x02, 0x42, 0x00, 0x01, 0x07, 0x02, 0x00, 0x01, 0x08, 0x00, 0x00];
+        let option = AuthMethodDataOption::from_unrecognized(&UnrecognizedOption::new(0xFDEB, data)).unwrap();
+        assert_eq!(option.method_data, vec![AuthDataOption::new(AuthMethod::UsernamePassword, vec![0x08])]);
+
+        for data in [vec![0x00], vec![0x00, 0x01, 0x02, 0x00], vec![0x00, 0x01, 0x02, 0x00, 0x04, 0x01]] {
+            assert_eq!(AuthMethodDataOption::from_unrecognized(&UnrecognizedOption::new(0xFDEB, data)), None);
+        }
+        let other = UnrecognizedOption::new(0xFDEA, vec![0x00, 0x00]);
+        assert_eq!(AuthMethodDataOption::from_unrecognized(&other), None);
+    }
+
+    // Test that authentication data survives serialization, and that the padding is stripped when parsing.
+    #[test]
+    fn test_auth_data_option() {
+        let option = AuthDataOption::new(AuthMethod::UsernamePassword, vec![1, 0, 3]);
+        let bytes = option.clone().into_socks_bytes();
+        assert_eq!(bytes, vec![0x00, 0x04, 0x00, 0x0C, 0x02, 1, 0, 3, 0, 0, 0, 0]);
+        assert_eq!(AuthDataOption::from_socks_bytes(&bytes[4..]).unwrap(), option.wrap());
+
+        let empty = AuthDataOption::new(AuthMethod::Gssapi, vec![]);
+        let bytes = empty.clone().into_socks_bytes();
+        assert_eq!(AuthDataOption::from_socks_bytes(&bytes[4..]).unwrap(), empty.wrap());
+        assert!(AuthDataOption::from_socks_bytes([0x09]).is_err());
+    }
+
+    // Test that an unrecognized option is written back exactly as it was parsed, padding included.
+    #[test]
+    fn test_unrecognized_option_round_trip() {
+        let bytes = UnrecognizedOption::new(0x1234, vec![1, 2, 3]).into_socks_bytes();
+        assert_eq!(bytes, vec![0x12, 0x34, 0x00, 0x08, 1, 2, 3, 0]);
+
+        let options = crate::wire::decode_options(&bytes, crate::Socks6Draft::default()).unwrap();
+        match &options[0] {
+            SocksOption::Unrecognized(option) => {
+                assert_eq!(option.kind(), 0x1234);
+                assert_eq!(option.data(), &[1, 2, 3, 0]);
+            }
+            _ => panic!("Expected an unrecognized option"),
+        }
+        assert_eq!(options[0].as_socks_bytes(), bytes);
+    }
+
+    // Test that every number survives the conversion into a kind and back, in every draft revision.
+    #[test]
+    fn test_socks_option_kind_round_trip() {
+        for draft in [Socks6Draft::Draft11, Socks6Draft::Draft13] {
+            let constants = draft.constants();
+            for kind in [0, 0x01, 0x02, 0x03, 0x04, 0x05, 0x10, 0x11, 0x12, 0x13, 0xFDE7, 0xFDE8, 0xFDE9, 0xFFFF] {
+                assert_eq!(SocksOptionKind::from_u16_for(kind, draft).to_u16_for(draft), kind);
+            }
+
+            assert_eq!(
+                SocksOptionKind::from_u16_for(constants.okind_auth_data, draft),
+                SocksOptionKind::AuthData
+            );
+            assert_eq!(SocksOptionKind::known_for(0xFFFF, draft), None);
+        }
+
+        assert_eq!(SocksOptionKind::from(0x0002), SocksOptionKind::AuthMethodAdvertisement);
+        assert_eq!(SocksOptionKind::from(0x0010), SocksOptionKind::Other(0x0010));
+        assert_eq!(SocksOptionKind::from(0xFDE8), SocksOptionKind::Metadata);
+        assert_eq!(SocksOptionKind::from(0), SocksOptionKind::Other(0));
+        assert_eq!(u16::from(SocksOptionKind::Other(0xFFFF)), 0xFFFF);
+        assert_eq!(
+            SocksOptionKind::from_u16_for(0x0002, Socks6Draft::Draft13),
+            SocksOptionKind::Other(0x0002)
+        );
+    }
+
+    // Test that unrecognized and malformed options are of kind `Other` in every draft revision, even with the number
+    // of a typed kind, unless they are stack options.
+    #[test]
+    fn test_unrecognized_option_kind() {
+        let option = UnrecognizedOption::new(0x0010, vec![]).wrap();
+        assert_eq!(option.kind(), SocksOptionKind::Other(0x0010));
+        assert_eq!(option.kind_for(Socks6Draft::Draft13), SocksOptionKind::Other(0x0010));
+        let option = UnrecognizedOption::new(SOCKS_OKIND_METADATA, vec![]).wrap();
+        assert_eq!(option.kind(), SocksOptionKind::Other(SOCKS_OKIND_METADATA));
+        let option = SocksOption::Malformed(UnrecognizedOption::new(SOCKS_OKIND_AUTH_DATA, vec![]));
+        assert_eq!(option.kind(), SocksOptionKind::Other(SOCKS_OKIND_AUTH_DATA));
+        let option = UnrecognizedOption::new(SOCKS_OKIND_STACK, vec![]).wrap();
+        assert_eq!(option.kind_for(Socks6Draft::Draft13), SocksOptionKind::Stack);
+        assert_eq!(MetadataOption::new(1, String::new()).wrap().kind(), SocksOptionKind::Metadata);
+    }
+
+    // Test that a TTL stack option is written as a stack option, and read back from one with its padding, but not
+    // from stack options of another level or code.
+    #[test]
+    fn test_ttl_option() {
+        let option = TtlOption::new(StackLeg::ProxyRemote, 42).wrap();
+        assert_eq!(option.as_socks_bytes(), [0x00, 0x01, 0x00, 0x08, 0x81, 0x03, 42, 0x00]);
+        assert_eq!(option.kind(), SocksOptionKind::Stack);
+
+        let padded = UnrecognizedOption::new(SOCKS_OKIND_STACK, vec![0xC1, 0x03, 7, 0x00]);
+        assert_eq!(TtlOption::from_unrecognized(&padded), Some(TtlOption::new(StackLeg::Both, 7)));
+        let tos = UnrecognizedOption::new(SOCKS_OKIND_STACK, vec![0x81, 0x01, 7, 0x00]);
+        assert_eq!(TtlOption::from_unrecognized(&tos), None);
+        let tcp = UnrecognizedOption::new(SOCKS_OKIND_STACK, vec![0x84, 0x03, 7, 0x00]);
+        assert_eq!(TtlOption::from_unrecognized(&tcp), None);
+
+        let options = [tos.wrap(), MetadataOption::new(1, String::new()).wrap(), option];
+        assert_eq!(options.ttl(), Some(TtlOption::new(StackLeg::ProxyRemote, 42)));
+        assert!(!StackLeg::ClientProxy.includes_remote());
+    }
+
+    // Test that compressed entries can't inflate beyond the limit, or beyond an option, however small they are.
+    #[test]
+    fn test_compressed_metadata_bomb() {
+        let bomb = |entries: &[u8]| {
+            let compressed = miniz_oxide::deflate::compress_to_vec(entries, 10);
+            let mut data = (1 | COMPRESSED_ENTRIES_FLAG).to_be_bytes().to_vec();
+            data.extend_from_slice(&(compressed.len() as u16).to_be_bytes());
+            data.extend_from_slice(&compressed);
+            data
+        };
+
+        let data = bomb(&vec![0; 10 * 1024 * 1024]);
+        assert!(data.len() < 16 * 1024);
+        assert!(decode_packed_metadata(&data).is_err());
+
+        // An entry of 1000 bytes, within an option but not within what's left of the limit.
+        let mut entry = vec![0x00, 0x01, 0x03, 0xE8];
+        entry.extend_from_slice(&[b'x'; 1000]);
+        let data = bomb(&entry);
+        assert_eq!(decode_packed_metadata(&data).unwrap().len(), 1);
+        let mut limit = 1003;
+        assert!(decode_packed_metadata_within(&data, &mut limit).is_err());
+        let mut limit = 1004;
+        assert!(decode_packed_metadata_within(&data, &mut limit).is_ok());
+        assert_eq!(limit, 0);
+
+        // A length beyond the option.
+        let mut truncated = data.clone();
+        truncated.truncate(data.len() - 1);
+        assert!(decode_packed_metadata(&truncated).is_err());
+    }
+
+    // Test the typed lookups over a mix of options, including duplicates of each kind.
+    #[test]
+    fn test_socks_options_lookups() {
+        let options = vec![
+            MetadataOption::new(1, "first".to_string()).wrap(),
+            UnrecognizedOption::new(0x1234, vec![1]).wrap(),
+            AuthMethodAdvertisementOption::new(5, vec![AuthMethod::UsernamePassword]).wrap(),
+            AuthDataOption::new(AuthMethod::UsernamePassword, vec![1]).wrap(),
+            AuthMethodSelectionOption::new(AuthMethod::Gssapi).wrap(),
+            MetadataOption::new(2, "other".to_string()).wrap(),
+            AuthMethodAdvertisementOption::new(9, vec![AuthMethod::Gssapi]).wrap(),
+            AuthDataOption::new(AuthMethod::BearerToken, vec![2]).wrap(),
+            AuthDataOption::new(AuthMethod::UsernamePassword, vec![3]).wrap(),
+            AuthMethodSelectionOption::new(AuthMethod::UsernamePassword).wrap(),
+            UnrecognizedOption::new(0x5678, vec![2]).wrap(),
+            MetadataOption::new(1, "last".to_string()).wrap(),
+        ];
+
+        assert_eq!(options.auth_advertisement().unwrap().methods, vec![AuthMethod::UsernamePassword]);
+        assert_eq!(options.initial_data_length(), 5);
+        assert_eq!(options.auth_selection().unwrap().method, AuthMethod::Gssapi);
+        assert_eq!(options.auth_data(&AuthMethod::UsernamePassword).unwrap().data, vec![1]);
+        assert_eq!(options.auth_data(&AuthMethod::BearerToken).unwrap().data, vec![2]);
+        assert!(options.auth_data(&AuthMethod::Gssapi).is_none());
+
+        let metadata = options.metadata();
+        assert_eq!(metadata.len(), 2);
+        assert_eq!(metadata[&1], "last");
+        assert_eq!(metadata[&2], "other");
+
+        let metadata = options.find(SocksOptionKind::Metadata);
+        assert!(matches!(metadata, Some(SocksOption::Metadata(m)) if m.value == "first"));
+        assert!(matches!(options.find(SOCKS_OKIND_AUTH_DATA), Some(SocksOption::AuthData(d)) if d.data == [1]));
+        assert!(matches!(options.find(0x5678), Some(SocksOption::Unrecognized(o)) if o.data() == [2]));
+        assert!(options.find(0x9999).is_none());
+
+        let kinds: Vec<u16> = options.unrecognized().map(UnrecognizedOption::kind).collect();
+        assert_eq!(kinds, vec![0x1234, 0x5678]);
+    }
+
+    // Test that the lookups find nothing among no options.
+    #[test]
+    fn test_socks_options_empty() {
+        let options: &[SocksOption] = &[];
+        assert!(options.auth_advertisement().is_none());
+        assert!(options.auth_selection().is_none());
+        assert_eq!(options.initial_data_length(), 0);
+        assert!(options.metadata().is_empty());
+        assert_eq!(options.unrecognized().count(), 0);
+    }
+
+    // Test that encoded_len matches the number of bytes actually written, for every option type.
+    #[test]
+    fn test_encoded_len_matches_output() {
+        let mut options = vec![
+            AuthMethodSelectionOption::new(AuthMethod::UsernamePassword).wrap(),
+            UnrecognizedOption::new(0x1234, vec![]).wrap(),
+        ];
+        for length in 0..12 {
+            options.push(AuthMethodAdvertisementOption::new(10, vec![AuthMethod::Gssapi; length]).wrap());
+            options.push(MetadataOption::new(1, "x".repeat(length)).wrap());
+            options.push(UnrecognizedOption::new(0x1234, vec![0xAB; length]).wrap());
+            options.push(AuthDataOption::new(AuthMethod::UsernamePassword, vec![0xAB; length]).wrap());
+        }
+
+        let mut buffer = vec![0xFF];
+        for option in &options {
+            let bytes = option.as_socks_bytes();
+            assert_eq!(bytes.len(), option.encoded_len());
+            assert_eq!(bytes.len() % 4, 0);
+
+            let before = buffer.len();
+            option.write_socks_bytes(&mut buffer);
+            assert_eq!(&buffer[before..], &bytes[..]);
+        }
+    }
+
+    // Test that packed metadata too large for one option is split over several, which decode to the entries.
+    #[test]
+    fn test_packed_metadata_chunks() -> Result<()> {
+        let metadata: Vec<_> = (0..3).map(|key| MetadataOption::new(key, "x".repeat(30000))).collect();
+        let mut bytes = vec![];
+        write_packed_metadata(&metadata, &mut bytes);
+        assert_eq!(bytes.len(), packed_metadata_len(&metadata));
+
+        let mut decoded = vec![];
+        let mut rest = &bytes[..];
+        while !rest.is_empty() {
+            assert_eq!(u16::from_be_bytes([rest[0], rest[1]]), SOCKS_OKIND_PACKED_METADATA);
+            let length = u16::from_be_bytes([rest[2], rest[3]]) as usize;
+            decoded.extend(decode_packed_metadata(&rest[4..length])?);
+            rest = &rest[length..];
+        }
+        assert_eq!(decoded.len(), 3);
+        assert!(matches!(&decoded[2], SocksOption::Metadata(m) if m.key == 2 && m.value.len() == 30000));
+
+        assert!(decode_packed_metadata(&[0, 1, 0, 1, 0, 5, b'x']).is_err());
+        Ok(())
+    }
 }
\ No newline at end of file
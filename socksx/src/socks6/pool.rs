@@ -0,0 +1,177 @@
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+
+use crate::dialer::{AddressFamilyPreference, ProxyEndpoint};
+use crate::stream::SocksStream;
+
+/// Counters that describe the effectiveness of a connection pool.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PoolStats {
+    /// The number of connects that were served with a pooled connection.
+    pub hits: u64,
+    /// The number of connects that had to open a fresh connection.
+    pub misses: u64,
+    /// The number of pooled connections that were found to be stale and discarded.
+    pub discards: u64,
+}
+
+/// A pool of connections to the proxy that have been established in advance.
+///
+/// The connections await their SOCKS request, so handing one out saves connecting.
+/// The pool is replenished in the background after connections are taken from it.
+pub(crate) struct ConnectionPool {
+    endpoint: ProxyEndpoint,
+    family_preference: AddressFamilyPreference,
+    size: usize,
+    idle: Mutex<VecDeque<SocksStream>>,
+    replenishing: AtomicBool,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    discards: AtomicU64,
+}
+
+impl ConnectionPool {
+    /// Creates a new, empty pool that keeps up to `size` connections to the proxy.
+    pub(crate) fn new(
+        endpoint: ProxyEndpoint,
+        family_preference: AddressFamilyPreference,
+        size: usize,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            endpoint,
+            family_preference,
+            size,
+            idle: Mutex::new(VecDeque::with_capacity(size)),
+            replenishing: AtomicBool::new(false),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            discards: AtomicU64::new(0),
+        })
+    }
+
+    /// Returns the maximum number of pooled connections.
+    pub(crate) fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the counters of the pool.
+    pub(crate) fn stats(&self) -> PoolStats {
+        PoolStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            discards: self.discards.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Takes a healthy pooled connection, or opens a fresh one if none is available.
+    pub(crate) async fn get(self: &Arc<Self>) -> Result<SocksStream> {
+        let pooled = loop {
+            let stream = self.idle.lock().unwrap().pop_front();
+            match stream {
+                Some(stream) if is_healthy(&stream) => break Some(stream),
+                Some(_) => {
+                    self.discards.fetch_add(1, Ordering::Relaxed);
+                }
+                None => break None,
+            }
+        };
+
+        self.replenish();
+
+        if let Some(stream) = pooled {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            Ok(stream)
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            let (stream, _) = self.endpoint.connect(self.family_preference).await?;
+            Ok(stream)
+        }
+    }
+
+    // Fills the pool up to its size in the background, unless that is already happening.
+    fn replenish(self: &Arc<Self>) {
+        if self.replenishing.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let pool = Arc::clone(self);
+        tokio::spawn(async move {
+            while pool.idle.lock().unwrap().len() < pool.size {
+                match pool.endpoint.connect(pool.family_preference).await {
+                    Ok((stream, _)) => pool.idle.lock().unwrap().push_back(stream),
+                    Err(e) => {
+                        debug!("Failed to replenish the connection pool: {}", e);
+                        break;
+                    }
+                }
+            }
+
+            pool.replenishing.store(false, Ordering::Release);
+        });
+    }
+}
+
+/// Probes whether a pooled connection is still usable, without blocking.
+///
+/// An idle connection to the proxy has nothing to read; EOF or unexpected data means it is stale.
+fn is_healthy(stream: &SocksStream) -> bool {
+    let mut buffer = [0; 1];
+    matches!(stream.try_read(&mut buffer), Err(e) if e.kind() == ErrorKind::WouldBlock)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    // Waits until the background task has filled the pool.
+    async fn wait_until_full(pool: &ConnectionPool) {
+        while pool.idle.lock().unwrap().len() < pool.size {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    // Tests that the first connect misses, and later connects are served from the pool.
+    #[tokio::test]
+    async fn test_pool_hits_after_replenish() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let endpoint = ProxyEndpoint::resolve(listener.local_addr()?.to_string()).await?;
+        let pool = ConnectionPool::new(endpoint, AddressFamilyPreference::default(), 2);
+
+        let _first = pool.get().await?;
+        wait_until_full(&pool).await;
+        let _second = pool.get().await?;
+
+        assert_eq!(pool.stats(), PoolStats { hits: 1, misses: 1, discards: 0 });
+        Ok(())
+    }
+
+    // Tests that connections closed by the proxy are discarded instead of handed out.
+    #[tokio::test]
+    async fn test_pool_discards_stale_connections() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let endpoint = ProxyEndpoint::resolve(listener.local_addr()?.to_string()).await?;
+        let pool = ConnectionPool::new(endpoint, AddressFamilyPreference::default(), 1);
+
+        let _first = pool.get().await?;
+        wait_until_full(&pool).await;
+
+        // Accept and immediately close both connections at the proxy side.
+        for _ in 0..2 {
+            let (incoming, _) = listener.accept().await?;
+            drop(incoming);
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let _second = pool.get().await?;
+        assert_eq!(pool.stats(), PoolStats { hits: 0, misses: 2, discards: 1 });
+        Ok(())
+    }
+}
@@ -1,28 +1,94 @@
-use std::{convert::TryInto, net::SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use std::{collections::HashMap, convert::TryInto, net::SocketAddr, sync::Arc};
 
-use anyhow::{ensure, Result};
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
+use anyhow::{bail, ensure, Result};
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 
-use crate::{Address, constants::*, Credentials};
-use crate::socks6::{self, Socks6Request};
-use crate::socks6::{
-    AuthMethod,
-    options::{AuthMethodAdvertisementOption, SocksOption},
+use crate::addresses::ScopePolicy;
+use crate::{Address, Baggage, constants::*, wire, Credentials, ProxyAddress, SocksClient, SocksError};
+use crate::bypass::{self, BypassList, Route};
+use crate::credentials::CredentialSource;
+use crate::guard::DestinationGuard;
+use crate::health::{self, HealthMonitor, HealthProbe, HealthReport, HealthStatus};
+use crate::timeout::{TimeoutStream, Timeouts};
+use crate::dialer::{AddressFamilyPreference, Keepalive, ProxyEndpoint, ReResolution};
+use crate::socks6::{self, AuthenticationReply, Diagnostic, ParseMode, Socks6Command, Socks6Draft, Socks6Request};
+use crate::socks6::auth::{ClientAuthenticator, UserPassAuthenticator, DEFAULT_MAX_AUTH_ROUNDS};
+use crate::socks6::pool::{ConnectionPool, PoolStats};
+use crate::socks6::options::{
+    AuthDataOption, AuthMethod, AuthMethodAdvertisementOption, AuthMethodDataOption, MetadataOption, SocksOption,
+    SocksOptions,
 };
+use crate::metrics::{Metrics, NoopMetrics, Recorder};
+use crate::retry::RetryPolicy;
+use crate::session::{ConnectionId, CONNECTION_ID_METADATA_KEY};
+use crate::stream::{ClientTunnelInfo, SocksStream};
+use crate::timings::{HandshakeTimings, Stopwatch};
+#[cfg(feature = "tls")]
+use crate::tls::{self, TlsClient};
+
+/// When a `Socks6Client` sends the initial data of a connect.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum InitialDataMode {
+    /// Along with the request, as the draft intends, even to a proxy that refused initial data before.
+    #[default]
+    Optimistic,
+    /// Along with the request until a proxy refuses initial data, and after the operation reply from then on, so
+    /// that it doesn't leave before the request is allowed.
+    Conservative,
+}
+
+/// How the initial data of a connect reached the destination.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InitialDataDelivery {
+    /// There was no initial data.
+    None,
+    /// Along with the request, forwarded by the proxy before the operation reply.
+    Optimistic,
+    /// Through the tunnel after the operation reply, as the client held it back or the proxy discarded it.
+    PostHandshake,
+}
 
 /// Represents a SOCKS6 client.
 #[derive(Clone)]
 pub struct Socks6Client {
-    proxy_addr: SocketAddr,
-    credentials: Option<Credentials>,
+    proxy: ProxyEndpoint,
+    authenticator: Option<Arc<dyn ClientAuthenticator + Send + Sync>>,
+    max_auth_rounds: usize,
+    family_preference: AddressFamilyPreference,
+    keepalive: Option<Keepalive>,
+    pool: Option<Arc<ConnectionPool>>,
+    default_options: Arc<[u8]>,
+    draft: Socks6Draft,
+    parse_mode: ParseMode,
+    metrics: Arc<dyn Metrics + Send + Sync>,
+    retry: RetryPolicy,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsClient>,
+    connection_id: Option<ConnectionId>,
+    connection_id_metadata: bool,
+    packed_metadata: bool,
+    metadata_compression: bool,
+    metadata_compression_supported: Arc<AtomicBool>,
+    initial_data_mode: InitialDataMode,
+    initial_data_refused: Arc<AtomicBool>,
+    timeouts: Timeouts,
+    bypass: BypassList,
+    guard: DestinationGuard,
+    scope_policy: ScopePolicy,
+    canary: Option<Address>,
+    health: Arc<Mutex<HealthStatus>>,
 }
 
 impl Socks6Client {
     /// Creates a new Socks6Client.
     ///
     /// # Parameters
-    /// - `proxy_addr`: The address of the SOCKS6 proxy.
+    /// - `proxy_addr`: The address of the SOCKS6 proxy, as `host:port`, or as `unix:/path/to/socket` for a proxy
+    ///   that listens on a Unix socket.
     /// - `credentials`: Optional credentials for authentication.
     ///
     /// # Returns
@@ -31,14 +97,514 @@ impl Socks6Client {
         proxy_addr: A,
         credentials: Option<Credentials>,
     ) -> Result<Self> {
-        let proxy_addr = crate::resolve_addr(proxy_addr).await?;
+        let proxy = ProxyEndpoint::resolve(proxy_addr).await?;
 
         Ok(Socks6Client {
-            proxy_addr,
-            credentials,
+            proxy,
+            authenticator: user_pass(credentials),
+            max_auth_rounds: DEFAULT_MAX_AUTH_ROUNDS,
+            family_preference: AddressFamilyPreference::default(),
+            keepalive: None,
+            pool: None,
+            default_options: Arc::from(Vec::new()),
+            draft: Socks6Draft::default(),
+            parse_mode: ParseMode::default(),
+            metrics: Arc::new(NoopMetrics),
+            retry: RetryPolicy::default(),
+            #[cfg(feature = "tls")]
+            tls: None,
+            connection_id: None,
+            connection_id_metadata: false,
+            packed_metadata: false,
+            metadata_compression: false,
+            metadata_compression_supported: Arc::new(AtomicBool::new(false)),
+            initial_data_mode: InitialDataMode::default(),
+            initial_data_refused: Arc::new(AtomicBool::new(false)),
+            timeouts: Timeouts::default(),
+            bypass: BypassList::new(),
+            guard: DestinationGuard::permissive(),
+            scope_policy: ScopePolicy::default(),
+            canary: None,
+            health: Arc::new(Mutex::new(HealthStatus::Unknown)),
+        })
+    }
+
+    /// Creates a new Socks6Client from a proxy address, using the credentials it carries.
+    ///
+    /// # Parameters
+    /// - `proxy_addr`: The address of the SOCKS6 proxy.
+    ///
+    /// # Returns
+    /// A `Result` containing a new `Socks6Client`, or an error if the address isn't of a SOCKS6 proxy.
+    pub async fn from_proxy_addr(proxy_addr: ProxyAddress) -> Result<Self> {
+        proxy_addr.ensure_version(SOCKS_VER_6)?;
+
+        Self::new(proxy_addr.host_port(), proxy_addr.credentials).await
+    }
+
+    /// Creates a new Socks6Client for a hop that is reached over a stream, e.g., the next link of a chain.
+    ///
+    /// Like `for_streams`, the client can only be used with `connect_with_stream`.
+    pub(crate) fn for_hop(proxy_addr: ProxyAddress) -> Result<Self> {
+        proxy_addr.ensure_version(SOCKS_VER_6)?;
+
+        Ok(Self::for_streams(proxy_addr.credentials))
+    }
+
+    /// Creates a new Socks6Client without a proxy address, for use with `connect_with_stream` only.
+    ///
+    /// This suits proxies that are reached through something other than a TCP connection.
+    ///
+    /// # Parameters
+    /// - `credentials`: Optional credentials for authentication.
+    pub fn for_streams(credentials: Option<Credentials>) -> Self {
+        Socks6Client {
+            proxy: ProxyEndpoint::unresolved(),
+            authenticator: user_pass(credentials),
+            max_auth_rounds: DEFAULT_MAX_AUTH_ROUNDS,
+            family_preference: AddressFamilyPreference::default(),
+            keepalive: None,
+            pool: None,
+            default_options: Arc::from(Vec::new()),
+            draft: Socks6Draft::default(),
+            parse_mode: ParseMode::default(),
+            metrics: Arc::new(NoopMetrics),
+            retry: RetryPolicy::default(),
+            #[cfg(feature = "tls")]
+            tls: None,
+            connection_id: None,
+            connection_id_metadata: false,
+            packed_metadata: false,
+            metadata_compression: false,
+            metadata_compression_supported: Arc::new(AtomicBool::new(false)),
+            initial_data_mode: InitialDataMode::default(),
+            initial_data_refused: Arc::new(AtomicBool::new(false)),
+            timeouts: Timeouts::default(),
+            bypass: BypassList::new(),
+            guard: DestinationGuard::permissive(),
+            scope_policy: ScopePolicy::default(),
+            canary: None,
+            health: Arc::new(Mutex::new(HealthStatus::Unknown)),
+        }
+    }
+
+    /// Sets the authenticator that decides how the client authenticates to the proxy, replacing the credentials
+    /// it was created with.
+    pub fn with_authenticator(
+        mut self,
+        authenticator: Arc<dyn ClientAuthenticator + Send + Sync>,
+    ) -> Self {
+        self.authenticator = Some(authenticator);
+        self
+    }
+
+    /// Sets where the credentials come from, replacing the authenticator. They're read on the first connect,
+    /// which fails if they can't be read, and kept from then on.
+    pub fn with_credential_source(
+        self,
+        source: CredentialSource,
+    ) -> Self {
+        self.with_authenticator(Arc::new(UserPassAuthenticator::from_source(source)))
+    }
+
+    /// Sets how many challenges of the proxy are answered before the handshake fails, which keeps a proxy from
+    /// challenging the client forever. Defaults to `DEFAULT_MAX_AUTH_ROUNDS`.
+    pub fn with_max_auth_rounds(
+        mut self,
+        rounds: usize,
+    ) -> Self {
+        self.max_auth_rounds = rounds;
+        self
+    }
+
+    /// Sets options that are sent along with every request, after the options given to `connect`.
+    ///
+    /// The options are serialized once here, instead of on every connect.
+    pub fn with_default_options(
+        mut self,
+        options: Vec<SocksOption>,
+    ) -> Self {
+        let mut encoded = Vec::with_capacity(options.iter().map(SocksOption::encoded_len).sum());
+        for option in &options {
+            option.write_socks_bytes_for(self.draft, &mut encoded);
+        }
+
+        self.default_options = Arc::from(encoded);
+        self
+    }
+
+    /// Sets options that are sent along with every request, already encoded for the client's draft revision.
+    pub(crate) fn with_raw_default_options(
+        mut self,
+        encoded: Vec<u8>,
+    ) -> Self {
+        self.default_options = Arc::from(encoded);
+        self
+    }
+
+    /// Sets the revision of the SOCKS6 draft that is spoken with the proxy, instead of `Draft11`.
+    pub fn with_draft(
+        mut self,
+        draft: Socks6Draft,
+    ) -> Self {
+        // The default options were encoded with the option kinds of the previous revision.
+        let options = wire::decode_options(&self.default_options, self.draft).unwrap_or_default();
+        self.draft = draft;
+        self.with_default_options(options)
+    }
+
+    /// Sets how strictly the replies of the proxy are parsed, instead of leniently.
+    ///
+    /// Deviations from the draft that a lenient client accepts are logged.
+    pub fn with_parse_mode(
+        mut self,
+        mode: ParseMode,
+    ) -> Self {
+        self.parse_mode = mode;
+        self
+    }
+
+    /// Sets the metrics that the handshakes and the replies of the proxy are counted in.
+    pub fn with_metrics(
+        mut self,
+        metrics: Arc<dyn Metrics + Send + Sync>,
+    ) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Sets the policy that decides whether `connect` tries again after a failure, each time over a fresh
+    /// connection to the proxy, bypassing the pool. By default, a single attempt is made.
+    pub fn with_retry_policy(
+        mut self,
+        retry: RetryPolicy,
+    ) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Sets up TLS for `connect_tls`, which connects to the proxy over TLS, e.g., to authenticate with a client
+    /// certificate.
+    #[cfg(feature = "tls")]
+    pub fn with_tls(
+        mut self,
+        tls: TlsClient,
+    ) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Sets the destinations that `connect` reaches directly, instead of through the proxy.
+    ///
+    /// The options of a request to such a destination are dropped, as there is no proxy to send them to.
+    /// `connect_tls` and `connect_with_stream` always use the proxy.
+    pub fn with_bypass(
+        mut self,
+        bypass: BypassList,
+    ) -> Self {
+        self.bypass = bypass;
+        self
+    }
+
+    /// Sets the guard that decides which destinations `connect` refuses before contacting the proxy, e.g.,
+    /// `DestinationGuard::internal()` to refuse internal addresses. By default, nothing is refused.
+    ///
+    /// `connect_tls` and `connect_with_stream` check the destination as well.
+    pub fn with_destination_guard(
+        mut self,
+        guard: DestinationGuard,
+    ) -> Self {
+        self.guard = guard;
+        self
+    }
+
+    /// Sets what the client does with the scope ID of a link-local IPv6 destination, which can't be sent to the
+    /// proxy. By default, it's left out with a warning.
+    pub fn with_scope_policy(
+        mut self,
+        scope_policy: ScopePolicy,
+    ) -> Self {
+        self.scope_policy = scope_policy;
+        self
+    }
+
+    /// Sends the metadata of every request packed, in as few options as fit, instead of in an option per entry.
+    ///
+    /// This saves a few bytes per entry, but packed metadata is a socksx extension, so only enable it for
+    /// proxies known to decode it, e.g., a `Socks6Handler`.
+    pub fn with_packed_metadata(
+        mut self,
+        enabled: bool,
+    ) -> Self {
+        self.packed_metadata = enabled;
+        self
+    }
+
+    /// Advertises support for compressed metadata to the proxy, and sends the metadata of every request packed
+    /// and deflate-compressed once the proxy advertised support in turn, i.e., from the handshake after the first
+    /// one with it on. Proxies without support never see compressed metadata.
+    ///
+    /// This suits chains with many links, or large baggage, whose metadata would come close to the size limit of
+    /// the options block.
+    pub fn with_metadata_compression(
+        mut self,
+        enabled: bool,
+    ) -> Self {
+        self.metadata_compression = enabled;
+        self
+    }
+
+    // Shares whether the proxy advertised support for compressed metadata with other clients, e.g., those of a
+    // handler for the same next link, so that they compress from their first handshake on once one learned it.
+    pub(crate) fn with_metadata_compression_supported(
+        mut self,
+        supported: Arc<AtomicBool>,
+    ) -> Self {
+        self.metadata_compression_supported = supported;
+        self
+    }
+
+    // Returns whether the metadata of the next request is compressed.
+    fn compresses_metadata(&self) -> bool {
+        self.metadata_compression && self.metadata_compression_supported.load(Ordering::Relaxed)
+    }
+
+    /// Sends the connection ID of every handshake to the proxy as metadata, so its logs can be correlated.
+    ///
+    /// The ID is attached to errors of the handshake either way.
+    pub fn with_connection_id_metadata(
+        mut self,
+        enabled: bool,
+    ) -> Self {
+        self.connection_id_metadata = enabled;
+        self
+    }
+
+    /// Uses the given connection ID for every handshake, instead of generating one each time.
+    pub(crate) fn with_connection_id(
+        mut self,
+        id: ConnectionId,
+    ) -> Self {
+        self.connection_id = Some(id);
+        self
+    }
+
+    /// Sets the policy that determines which of the proxy's addresses are used, and in which order.
+    pub fn with_family_preference(
+        mut self,
+        family_preference: AddressFamilyPreference,
+    ) -> Self {
+        self.family_preference = family_preference;
+        self.rebuild_pool();
+        self
+    }
+
+    /// Sets when the initial data of a connect is sent, by default along with the request.
+    ///
+    /// Whatever the mode, initial data that a proxy reports it discarded in its operation reply is sent again after
+    /// the reply, so it reaches the destination exactly once. A proxy that refuses initial data in its
+    /// authentication reply (see `Socks6Handler::with_initial_data`) leaves it unread, so that it follows the reply
+    /// without being sent again.
+    ///
+    /// The draft has no reply field for discarded initial data, so the report is a socksx extension
+    /// (`socks6::initial_data_option`). Initial data that a proxy discards without reporting it is taken as
+    /// delivered, and lost. Against such proxies, hold the initial data back and send it through the tunnel.
+    pub fn with_initial_data_mode(
+        mut self,
+        mode: InitialDataMode,
+    ) -> Self {
+        self.initial_data_mode = mode;
+        self
+    }
+
+    /// Enables TCP keepalive on the connections to the proxy, which carry the tunnels once established.
+    pub fn with_keepalive(
+        mut self,
+        keepalive: Keepalive,
+    ) -> Self {
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    /// Sets the policy that determines when the proxy's hostname is resolved again.
+    ///
+    /// By default, the hostname is only resolved when the client is created.
+    pub fn with_re_resolution(
+        mut self,
+        re_resolution: ReResolution,
+    ) -> Self {
+        self.proxy.set_re_resolution(re_resolution);
+        self.rebuild_pool();
+        self
+    }
+
+    /// Keeps up to `size` connections to the proxy established in advance, to save the TCP handshake.
+    ///
+    /// Pooled connections are replenished in the background, and are checked for staleness before use.
+    /// Clones of the client share the pool.
+    pub fn with_pool(
+        mut self,
+        size: usize,
+    ) -> Self {
+        self.pool = Some(ConnectionPool::new(self.proxy.clone(), self.family_preference, size));
+        self
+    }
+
+    /// Returns the counters of the connection pool, if the client has one.
+    pub fn pool_stats(&self) -> Option<PoolStats> {
+        self.pool.as_ref().map(|p| p.stats())
+    }
+
+    // Recreates the pool, if any, so it follows changes to the client's settings.
+    fn rebuild_pool(&mut self) {
+        if let Some(pool) = &self.pool {
+            self.pool = Some(ConnectionPool::new(self.proxy.clone(), self.family_preference, pool.size()));
+        }
+    }
+
+    /// Sets how long a connection attempt to one of the proxy's addresses has a head start, before the next
+    /// address is tried as well. As the addresses are interleaved by family, the next one is of the other family
+    /// if the proxy has both. Defaults to `CONNECTION_ATTEMPT_DELAY`.
+    pub fn with_proxy_attempt_delay(
+        mut self,
+        delay: Duration,
+    ) -> Self {
+        self.proxy.set_attempt_delay(delay);
+        self.rebuild_pool();
+        self
+    }
+
+    /// Sets the firewall mark (SO_MARK) of the sockets the proxy, and destinations that bypass it, are connected
+    /// from. Packet filter rules can match on it, e.g., to keep a transparent proxy's own connections from being
+    /// redirected back to it. Setting the mark requires the CAP_NET_ADMIN capability.
+    pub fn with_mark(
+        mut self,
+        mark: u32,
+    ) -> Self {
+        self.proxy.set_mark(Some(mark));
+        self.rebuild_pool();
+        self
+    }
+
+    /// Sets the read inactivity timeout of the streams returned by `connect_timed`.
+    pub fn with_read_timeout(
+        mut self,
+        timeout: Duration,
+    ) -> Self {
+        self.timeouts.read = Some(timeout);
+        self
+    }
+
+    /// Sets the write inactivity timeout of the streams returned by `connect_timed`.
+    pub fn with_write_timeout(
+        mut self,
+        timeout: Duration,
+    ) -> Self {
+        self.timeouts.write = Some(timeout);
+        self
+    }
+
+    /// Sets the destination that health checks connect to, and close right away, when the proxy doesn't support
+    /// NOOP requests. It should be cheap to connect to, and always up.
+    pub fn with_health_canary(
+        mut self,
+        canary: Address,
+    ) -> Self {
+        self.canary = Some(canary);
+        self
+    }
+
+    /// Checks that the proxy is alive and authenticates the client, over a connection of its own.
+    ///
+    /// The check is a NOOP request, or a connect to the canary destination set with `with_health_canary` when the
+    /// proxy answers that NOOP isn't supported.
+    ///
+    /// # Returns
+    /// A `Result` containing the latency and what was negotiated, or why the check failed.
+    pub async fn health_check(&self) -> Result<HealthReport> {
+        self.check_health(None).await
+    }
+
+    /// Starts checking the health of every address of the proxy in the background, once per interval, like
+    /// `health_check`. The status is available from `health` until the monitor is dropped, and connects skip
+    /// addresses that fail their checks while others pass.
+    pub fn spawn_health_monitor(
+        &self,
+        interval: Duration,
+    ) -> HealthMonitor {
+        let client = self.clone();
+        health::spawn_monitor(interval, self.proxy.clone(), self.health.clone(), move |addr| {
+            let client = client.clone();
+            async move { client.check_health(addr).await }
+        })
+    }
+
+    /// Returns the health of the proxy, as last determined by a monitor, which clones of the client share.
+    pub fn health(&self) -> HealthStatus {
+        self.health.lock().unwrap().clone()
+    }
+
+    // Checks the health of the proxy at the given address, or at any of its addresses.
+    async fn check_health(
+        &self,
+        addr: Option<SocketAddr>,
+    ) -> Result<HealthReport> {
+        let id = ConnectionId::generate();
+        let started = Instant::now();
+        let (mut stream, proxy) = self.connect_unpooled(addr).await?;
+
+        let noop = Address::new("0.0.0.0", 0);
+        let mut timings = HandshakeTimings::default();
+        let noop = self.exchange(id, Socks6Command::NoOp, noop, None, None, &mut stream, &mut timings).await;
+        let (probe, binding) = match (noop, &self.canary) {
+            (Ok((binding, _, _)), _) => (HealthProbe::NoOp, binding),
+            (Err(e), Some(canary)) if refuses_noop(&e) => {
+                debug!("[{}] The proxy doesn't support NOOP, connecting to the canary {}.", id, canary);
+                let (mut stream, _) = self.connect_unpooled(proxy).await?;
+                let connect =
+                    self.exchange(id, Socks6Command::Connect, canary.clone(), None, None, &mut stream, &mut timings);
+                let (binding, _, _) = connect.await.map_err(|e| id.attach(e))?;
+                (HealthProbe::Canary, binding)
+            }
+            (Err(e), _) => return Err(id.attach(e)),
+        };
+
+        Ok(HealthReport {
+            latency: started.elapsed(),
+            proxy,
+            probe,
+            binding,
         })
     }
 
+    // Connects to the proxy at the given address, or at any of its addresses, bypassing the pool. Returns the address
+    // connected to, which a proxy on a Unix socket doesn't have.
+    async fn connect_unpooled(
+        &self,
+        addr: Option<SocketAddr>,
+    ) -> Result<(SocksStream, Option<SocketAddr>)> {
+        let stream = match addr {
+            Some(addr) => SocksStream::Tcp(self.proxy.connect_to(addr).await?),
+            None => self.connect_proxy(true, &mut HandshakeTimings::default()).await?,
+        };
+        let proxy = stream.peer_addr().ok();
+
+        Ok((stream, proxy))
+    }
+
+    /// Returns the proxy address used for the most recent successful connection, if any.
+    ///
+    /// Clients share this between concurrent connections; the address of a particular connection is the
+    /// `peer_addr` of its stream.
+    pub fn proxy_addr(&self) -> Option<SocketAddr> {
+        self.proxy.current_addr()
+    }
+
+    /// Returns the currently known addresses of the proxy.
+    pub fn proxy_addrs(&self) -> Vec<SocketAddr> {
+        self.proxy.addrs()
+    }
+
     /// Connects to a given destination through the SOCKS6 proxy.
     ///
     /// # Parameters
@@ -47,85 +613,1503 @@ impl Socks6Client {
     /// - `options`: Optional SOCKS options.
     ///
     /// # Returns
-    /// A `Result` containing a tuple of the `TcpStream` and the bound `Address`, or an error.
+    /// A `Result` containing a tuple of the `SocksStream` and the bound `Address`, or an error, which reports how
+    /// many attempts were made if the retry policy allows more than one.
     pub async fn connect<A>(
         &self,
         destination: A,
         initial_data: Option<Vec<u8>>,
         options: Option<Vec<SocksOption>>,
-    ) -> Result<(TcpStream, Address)>
+    ) -> Result<(SocksStream, Address)>
     where
-        A: TryInto<Address, Error = anyhow::Error>,
+        A: TryInto<Address>,
+        A::Error: Into<anyhow::Error>,
     {
-        let mut stream = TcpStream::connect(&self.proxy_addr).await?;
-        let binding = self.handshake(destination, initial_data, options, &mut stream).await?;
-
+        let (stream, binding, _) = self.connect_with_delivery(destination, initial_data, options).await?;
         Ok((stream, binding))
     }
 
-    /// Conducts the handshake process with the SOCKS6 proxy.
+    /// Connects to a given destination through the SOCKS6 proxy, or directly if the destination is on the bypass
+    /// list.
     ///
-    /// This method implements the handshake protocol as per [socks6-draft11].
-    /// [socks6-draft11]: https://tools.ietf.org/html/draft-olteanu-intarea-socks-6-11
+    /// # Parameters
+    /// - `destination`: The destination to connect to.
+    /// - `initial_data`: Optional initial data to send.
+    /// - `options`: Optional SOCKS options, which are dropped for a direct connection.
+    ///
+    /// # Returns
+    /// A `Result` containing a tuple of the `SocksStream`, the bound `Address`, or the local address of a direct
+    /// connection, and whether the proxy was used.
+    pub async fn connect_routed<A>(
+        &self,
+        destination: A,
+        initial_data: Option<Vec<u8>>,
+        options: Option<Vec<SocksOption>>,
+    ) -> Result<(SocksStream, Address, Route)>
+    where
+        A: TryInto<Address>,
+        A::Error: Into<anyhow::Error>,
+    {
+        let (stream, binding, route, _) = self.connect_routed_with_delivery(destination, initial_data, options).await?;
+        Ok((stream, binding, route))
+    }
+
+    /// Connects to a given destination like `connect_routed`, and describes the tunnel that was established.
+    ///
+    /// # Parameters
+    /// - `destination`: The destination to connect to.
+    /// - `initial_data`: Optional initial data to send.
+    /// - `options`: Optional SOCKS options, which are dropped for a direct connection.
+    ///
+    /// # Returns
+    /// A `Result` containing a tuple of the `SocksStream` and the addresses of its tunnel.
+    pub async fn connect_with_info<A>(
+        &self,
+        destination: A,
+        initial_data: Option<Vec<u8>>,
+        options: Option<Vec<SocksOption>>,
+    ) -> Result<(SocksStream, ClientTunnelInfo)>
+    where
+        A: TryInto<Address>,
+        A::Error: Into<anyhow::Error>,
+    {
+        let requested = destination.try_into().map_err(Into::into)?;
+        let (stream, binding, route) = self.connect_routed(requested.clone(), initial_data, options).await?;
+        let info = stream.tunnel_info(requested, binding, route);
+
+        Ok((stream, info))
+    }
+
+    /// Connects to a given destination like `connect`, and reports how the initial data reached it.
+    ///
+    /// Destinations on the bypass list get the initial data right after connecting, which is reported as
+    /// `InitialDataDelivery::PostHandshake`.
     ///
     /// # Parameters
     /// - `destination`: The destination to connect to.
     /// - `initial_data`: Optional initial data to send.
     /// - `options`: Optional SOCKS options.
-    /// - `stream`: The mutable reference to the `TcpStream`.
     ///
     /// # Returns
-    /// A `Result` containing the bound `Address` or an error.
-    pub async fn handshake<A>(
+    /// A `Result` containing a tuple of the `SocksStream`, the bound `Address`, and how the initial data traveled.
+    pub async fn connect_with_delivery<A>(
         &self,
         destination: A,
         initial_data: Option<Vec<u8>>,
         options: Option<Vec<SocksOption>>,
-        stream: &mut TcpStream,
-    ) -> Result<Address>
+    ) -> Result<(SocksStream, Address, InitialDataDelivery)>
+    where
+        A: TryInto<Address>,
+        A::Error: Into<anyhow::Error>,
+    {
+        let (stream, binding, _, delivery) =
+            self.connect_routed_with_delivery(destination, initial_data, options).await?;
+        Ok((stream, binding, delivery))
+    }
+
+    // Connects to the destination through the proxy, or directly if it's on the bypass list, reporting the route
+    // and how the initial data reached the destination.
+    async fn connect_routed_with_delivery<A>(
+        &self,
+        destination: A,
+        initial_data: Option<Vec<u8>>,
+        options: Option<Vec<SocksOption>>,
+    ) -> Result<(SocksStream, Address, Route, InitialDataDelivery)>
     where
-        A: TryInto<Address, Error = anyhow::Error>,
+        A: TryInto<Address>,
+        A::Error: Into<anyhow::Error>,
     {
-        if let Some(Credentials { username, password }) = &self.credentials {
-            ensure!(username.len() > 255, "Username MUST NOT be larger than 255 bytes.");
-            ensure!(password.len() > 255, "Password MUST NOT be larger than 255 bytes.");
+        let destination = destination.try_into().map_err(Into::into)?;
+        let destination = self.guard.check(&destination).await?;
+        if self.bypass.matches(&destination) {
+            let delivery = match &initial_data {
+                Some(data) if !data.is_empty() => InitialDataDelivery::PostHandshake,
+                _ => InitialDataDelivery::None,
+            };
+            let (stream, binding) = bypass::connect_direct(
+                &destination,
+                &self.guard,
+                self.family_preference,
+                self.keepalive,
+                self.proxy.mark(),
+                initial_data,
+            )
+            .await?;
+            return Ok((stream, binding, Route::Direct, delivery));
         }
 
-        // Prepare initial data.
-        let initial_data = initial_data.unwrap_or_default();
-        ensure!(
-            initial_data.len() <= 2 ^ 14,
-            "Initial data MUST NOT be larger than 16384 bytes."
-        );
-        let initial_data_length = initial_data.len() as u16;
+        let (stream, binding, delivery, _) = self.connect_proxied(destination, initial_data, options).await?;
+        Ok((stream, binding, Route::Proxied, delivery))
+    }
 
-        // Prepare SOCKS options.
-        let mut auth_methods = vec![];
-        if self.credentials.is_some() {
-            auth_methods.push(AuthMethod::UsernamePassword);
+    /// Connects to a given destination like `connect`, and reports how long each phase of the connect took.
+    ///
+    /// The timings are recorded in the metrics as well. Destinations on the bypass list are connected to
+    /// directly, which only counts toward `total`.
+    ///
+    /// # Parameters
+    /// - `destination`: The destination to connect to.
+    /// - `initial_data`: Optional initial data to send.
+    /// - `options`: Optional SOCKS options.
+    ///
+    /// # Returns
+    /// A `Result` containing a tuple of the `SocksStream`, the bound `Address`, and the timings of the connect.
+    pub async fn connect_with_timings<A>(
+        &self,
+        destination: A,
+        initial_data: Option<Vec<u8>>,
+        options: Option<Vec<SocksOption>>,
+    ) -> Result<(SocksStream, Address, HandshakeTimings)>
+    where
+        A: TryInto<Address>,
+        A::Error: Into<anyhow::Error>,
+    {
+        let started = Instant::now();
+        let destination = destination.try_into().map_err(Into::into)?;
+        let destination = self.guard.check(&destination).await?;
+        if self.bypass.matches(&destination) {
+            let (stream, binding) = bypass::connect_direct(
+                &destination,
+                &self.guard,
+                self.family_preference,
+                self.keepalive,
+                self.proxy.mark(),
+                initial_data,
+            )
+            .await?;
+            let timings = HandshakeTimings {
+                total: started.elapsed(),
+                ..HandshakeTimings::attempt(1)
+            };
+            return Ok((stream, binding, timings));
         }
 
-        let auth_methods_adv = AuthMethodAdvertisementOption::new(initial_data_length, vec![]);
-        let mut options = options.unwrap_or_default();
-        options.push(auth_methods_adv.wrap());
+        let (stream, binding, _, timings) = self.connect_proxied(destination, initial_data, options).await?;
+        Ok((stream, binding, timings))
+    }
 
-        // Create SOCKS6 CONNECT request.
-        let request = Socks6Request::new(
-            SOCKS_CMD_CONNECT,
-            destination.try_into()?,
-            initial_data_length,
-            options,
-            None,
-        );
+    // Connects to the destination through the proxy, with as many attempts as the retry policy allows, and records
+    // the timings of the attempt that succeeded.
+    async fn connect_proxied(
+        &self,
+        destination: Address,
+        initial_data: Option<Vec<u8>>,
+        options: Option<Vec<SocksOption>>,
+    ) -> Result<(SocksStream, Address, InitialDataDelivery, HandshakeTimings)> {
+        let started = Instant::now();
+        let (stream, binding, delivery, mut timings) = self
+            .retry
+            .run(|attempt| {
+                let (destination, initial_data, options) = (destination.clone(), initial_data.clone(), options.clone());
+                async move {
+                    let mut timings = HandshakeTimings::attempt(attempt);
+                    let mut stream = self.connect_proxy(attempt > 1, &mut timings).await?;
+                    let (binding, _, delivery) = self
+                        .handshake_timed(destination, initial_data, options, &mut stream, &mut timings)
+                        .await?;
+
+                    Ok((stream, binding, delivery, timings))
+                }
+            })
+            .await?;
+        timings.total = started.elapsed();
+        self.record_timings(&timings);
 
-        // Send SOCKS request information.
-        let request_bytes = request.into_socks_bytes();
-        stream.write(&request_bytes).await?;
+        Ok((stream, binding, delivery, timings))
+    }
 
-        // Wait for authentication and operation reply.
-        let _ = socks6::read_no_authentication(stream).await?;
-        let (binding, _) = socks6::read_reply(stream).await?;
+    // Records the timings of a connect in the metrics, and logs them.
+    fn record_timings(
+        &self,
+        timings: &HandshakeTimings,
+    ) {
+        Recorder::client(self.metrics.as_ref(), "6").timings(timings);
+        debug!("Connected through the SOCKS6 proxy, taking {:?}.", timings.phases());
+    }
 
-        Ok(binding)
+    /// Connects to a given destination through the SOCKS6 proxy, over TLS as set up with `with_tls`.
+    ///
+    /// Failures of the TLS handshake, including a proxy rejecting the client certificate, result in
+    /// `SocksError::TlsHandshakeFailed`, as opposed to failures of the SOCKS handshake.
+    ///
+    /// # Parameters
+    /// - `destination`: The destination to connect to.
+    /// - `initial_data`: Optional initial data to send.
+    /// - `options`: Optional SOCKS options.
+    ///
+    /// # Returns
+    /// A `Result` containing a tuple of the TLS stream and the bound `Address`, or an error.
+    #[cfg(feature = "tls")]
+    pub async fn connect_tls<A>(
+        &self,
+        destination: A,
+        initial_data: Option<Vec<u8>>,
+        options: Option<Vec<SocksOption>>,
+    ) -> Result<(tokio_rustls::client::TlsStream<SocksStream>, Address)>
+    where
+        A: TryInto<Address>,
+        A::Error: Into<anyhow::Error>,
+    {
+        let (stream, binding, _) = self.connect_tls_with_timings(destination, initial_data, options).await?;
+        Ok((stream, binding))
+    }
+
+    /// Connects to a given destination through the SOCKS6 proxy over TLS like `connect_tls`, and reports how long
+    /// each phase of the connect took, the TLS handshake included.
+    ///
+    /// # Returns
+    /// A `Result` containing a tuple of the TLS stream, the bound `Address`, and the timings of the connect.
+    #[cfg(feature = "tls")]
+    pub async fn connect_tls_with_timings<A>(
+        &self,
+        destination: A,
+        initial_data: Option<Vec<u8>>,
+        options: Option<Vec<SocksOption>>,
+    ) -> Result<(tokio_rustls::client::TlsStream<SocksStream>, Address, HandshakeTimings)>
+    where
+        A: TryInto<Address>,
+        A::Error: Into<anyhow::Error>,
+    {
+        let tls = match &self.tls {
+            Some(tls) => tls,
+            None => bail!("TLS isn't set up for this client, see `with_tls`."),
+        };
+        let started = Instant::now();
+        let mut timings = HandshakeTimings::attempt(1);
+        let destination = self.guard.check(&destination.try_into().map_err(Into::into)?).await?;
+        let stream = self.connect_proxy(false, &mut timings).await?;
+        let mut watch = Stopwatch::start();
+        let mut stream = tls.connect(self.proxy.hostname(), stream).await?;
+        timings.tls = Some(watch.lap());
+        let (binding, _, _) = self
+            .handshake_timed(destination, initial_data, options, &mut stream, &mut timings)
+            .await
+            .map_err(tls::classify)?;
+        timings.total = started.elapsed();
+        self.record_timings(&timings);
+
+        Ok((stream, binding, timings))
+    }
+
+    /// Connects to a given destination through the SOCKS6 proxy, returning a stream with the client's read and
+    /// write inactivity timeouts.
+    ///
+    /// # Parameters
+    /// - `destination`: The destination to connect to.
+    /// - `initial_data`: Optional initial data to send.
+    /// - `options`: Optional SOCKS options.
+    ///
+    /// # Returns
+    /// A `Result` containing a tuple of the `TimeoutStream` and the bound `Address`, or an error.
+    pub async fn connect_timed<A>(
+        &self,
+        destination: A,
+        initial_data: Option<Vec<u8>>,
+        options: Option<Vec<SocksOption>>,
+    ) -> Result<(TimeoutStream<SocksStream>, Address)>
+    where
+        A: TryInto<Address>,
+        A::Error: Into<anyhow::Error>,
+    {
+        let (stream, binding) = self.connect(destination, initial_data, options).await?;
+        Ok((TimeoutStream::new(stream, self.timeouts), binding))
+    }
+
+    /// Connects to a given destination through the SOCKS6 proxy, sending the metadata along with the request.
+    ///
+    /// Each entry becomes a metadata option. An entry replaces an option for the same key among `options`,
+    /// so that no key is sent twice.
+    ///
+    /// # Parameters
+    /// - `destination`: The destination to connect to.
+    /// - `initial_data`: Optional initial data to send.
+    /// - `options`: Optional SOCKS options.
+    /// - `metadata`: The metadata, by key.
+    ///
+    /// # Returns
+    /// A `Result` containing a tuple of the `SocksStream` and the bound `Address`, or an error if a value
+    /// doesn't fit in an option.
+    pub async fn connect_with_metadata<A>(
+        &self,
+        destination: A,
+        initial_data: Option<Vec<u8>>,
+        options: Option<Vec<SocksOption>>,
+        metadata: HashMap<u16, String>,
+    ) -> Result<(SocksStream, Address)>
+    where
+        A: TryInto<Address>,
+        A::Error: Into<anyhow::Error>,
+    {
+        let options = merge_metadata(options.unwrap_or_default(), metadata, self.compresses_metadata())?;
+        self.connect(destination, initial_data, Some(options)).await
+    }
+
+    /// Connects to a given destination through the SOCKS6 proxy, sending the baggage along with the request.
+    ///
+    /// The baggage is passed on unchanged by every link of a chain, and is available to the session hooks of
+    /// each. Its entries replace options for the same metadata keys among `options`.
+    ///
+    /// # Parameters
+    /// - `destination`: The destination to connect to.
+    /// - `initial_data`: Optional initial data to send.
+    /// - `options`: Optional SOCKS options.
+    /// - `baggage`: The baggage, e.g., a trace ID.
+    ///
+    /// # Returns
+    /// A `Result` containing a tuple of the `SocksStream` and the bound `Address`.
+    pub async fn connect_with_baggage<A>(
+        &self,
+        destination: A,
+        initial_data: Option<Vec<u8>>,
+        options: Option<Vec<SocksOption>>,
+        baggage: &Baggage,
+    ) -> Result<(SocksStream, Address)>
+    where
+        A: TryInto<Address>,
+        A::Error: Into<anyhow::Error>,
+    {
+        self.connect_with_metadata(destination, initial_data, options, baggage.to_metadata()).await
+    }
+
+    /// Connects to a given destination through the SOCKS6 proxy, over an already established stream to it.
+    ///
+    /// # Parameters
+    /// - `stream`: The stream to the proxy, e.g., an in-memory stream or a different transport.
+    /// - `destination`: The destination to connect to.
+    /// - `initial_data`: Optional initial data to send.
+    /// - `options`: Optional SOCKS options.
+    ///
+    /// # Returns
+    /// A `Result` containing a tuple of the stream, now a tunnel to the destination, and the bound `Address`.
+    pub async fn connect_with_stream<A, S>(
+        &self,
+        mut stream: S,
+        destination: A,
+        initial_data: Option<Vec<u8>>,
+        options: Option<Vec<SocksOption>>,
+    ) -> Result<(S, Address)>
+    where
+        A: TryInto<Address>,
+        A::Error: Into<anyhow::Error>,
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let binding = self.handshake(destination, initial_data, options, &mut stream).await?;
+
+        Ok((stream, binding))
+    }
+
+    /// Connects to the proxy, racing its addresses according to the family preference.
+    ///
+    /// The proxy's hostname is resolved again first if the re-resolution policy says so.
+    /// If the client has a pool, a pooled connection is used when available, unless a fresh one is asked for.
+    /// How long each of that took goes into the timings.
+    async fn connect_proxy(
+        &self,
+        fresh: bool,
+        timings: &mut HandshakeTimings,
+    ) -> Result<SocksStream> {
+        let stream = match &self.pool {
+            Some(pool) if !fresh => {
+                let mut watch = Stopwatch::start();
+                let stream = pool.get().await?;
+                timings.proxy_connect = watch.lap();
+                stream
+            }
+            _ => {
+                timings.resolution = self.proxy.refresh_if_due().await;
+                let mut watch = Stopwatch::start();
+                let (stream, info) = self.proxy.connect_stream(self.family_preference).await?;
+                timings.proxy_connect = watch.lap();
+                timings.proxy_addr = info.as_ref().map(|info| info.addr);
+                timings.connect_attempts = info.map_or(1, |info| info.attempts);
+                stream
+            }
+        };
+        if let (Some(keepalive), Some(tcp)) = (&self.keepalive, stream.as_tcp()) {
+            keepalive.apply(tcp)?;
+        }
+
+        Ok(stream)
+    }
+
+    /// Conducts the handshake process with the SOCKS6 proxy.
+    ///
+    /// This method implements the handshake protocol as per [socks6-draft11].
+    /// [socks6-draft11]: https://tools.ietf.org/html/draft-olteanu-intarea-socks-6-11
+    ///
+    /// # Parameters
+    /// - `destination`: The destination to connect to.
+    /// - `initial_data`: Optional initial data to send.
+    /// - `options`: Optional SOCKS options.
+    /// - `stream`: The mutable reference to the stream connected to the proxy.
+    ///
+    /// The request and the initial data are sent with a single write, so they can leave in one segment.
+    ///
+    /// # Returns
+    /// A `Result` containing the bound `Address` or an error.
+    pub async fn handshake<A, S>(
+        &self,
+        destination: A,
+        initial_data: Option<Vec<u8>>,
+        options: Option<Vec<SocksOption>>,
+        stream: &mut S,
+    ) -> Result<Address>
+    where
+        A: TryInto<Address>,
+        A::Error: Into<anyhow::Error>,
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (binding, _, _) = self.handshake_with_reply(destination, initial_data, options, stream).await?;
+
+        Ok(binding)
+    }
+
+    /// Conducts the handshake process with the SOCKS6 proxy like `handshake`, and reports how the initial data
+    /// reached the destination.
+    ///
+    /// # Returns
+    /// A `Result` containing the bound `Address` and how the initial data traveled, or an error.
+    pub async fn handshake_with_delivery<A, S>(
+        &self,
+        destination: A,
+        initial_data: Option<Vec<u8>>,
+        options: Option<Vec<SocksOption>>,
+        stream: &mut S,
+    ) -> Result<(Address, InitialDataDelivery)>
+    where
+        A: TryInto<Address>,
+        A::Error: Into<anyhow::Error>,
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (binding, _, delivery) = self.handshake_with_reply(destination, initial_data, options, stream).await?;
+
+        Ok((binding, delivery))
+    }
+
+    /// Conducts the handshake process with the SOCKS6 proxy, like `handshake`.
+    ///
+    /// # Returns
+    /// A `Result` containing the bound `Address`, the options of the operation reply, and how the initial data
+    /// traveled, or an error.
+    pub(crate) async fn handshake_with_reply<A, S>(
+        &self,
+        destination: A,
+        initial_data: Option<Vec<u8>>,
+        options: Option<Vec<SocksOption>>,
+        stream: &mut S,
+    ) -> Result<(Address, Vec<SocksOption>, InitialDataDelivery)>
+    where
+        A: TryInto<Address>,
+        A::Error: Into<anyhow::Error>,
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut timings = HandshakeTimings::default();
+        self.handshake_timed(destination, initial_data, options, stream, &mut timings).await
+    }
+
+    // Conducts the handshake process like `handshake_with_reply`, measuring the authentication and the wait for the
+    // operation reply into the timings.
+    async fn handshake_timed<A, S>(
+        &self,
+        destination: A,
+        initial_data: Option<Vec<u8>>,
+        options: Option<Vec<SocksOption>>,
+        stream: &mut S,
+        timings: &mut HandshakeTimings,
+    ) -> Result<(Address, Vec<SocksOption>, InitialDataDelivery)>
+    where
+        A: TryInto<Address>,
+        A::Error: Into<anyhow::Error>,
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let id = self.connection_id.unwrap_or_else(ConnectionId::generate);
+        let recorder = Recorder::client(self.metrics.as_ref(), "6");
+        recorder.started();
+        match self.exchange(id, Socks6Command::Connect, destination, initial_data, options, stream, timings).await {
+            Ok(reply) => {
+                recorder.succeeded();
+                Ok(reply)
+            }
+            Err(error) => {
+                recorder.failed(&error);
+                Err(id.attach(error))
+            }
+        }
+    }
+
+    // Sends a request with the command and reads the replies of a handshake, for the session with the given ID.
+    #[allow(clippy::too_many_arguments)]
+    async fn exchange<A, S>(
+        &self,
+        id: ConnectionId,
+        command: Socks6Command,
+        destination: A,
+        initial_data: Option<Vec<u8>>,
+        options: Option<Vec<SocksOption>>,
+        stream: &mut S,
+        timings: &mut HandshakeTimings,
+    ) -> Result<(Address, Vec<SocksOption>, InitialDataDelivery)>
+    where
+        A: TryInto<Address>,
+        A::Error: Into<anyhow::Error>,
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        // Prepare initial data.
+        let initial_data = initial_data.unwrap_or_default();
+        ensure!(
+            initial_data.len() <= 2usize.pow(14),
+            "Initial data MUST NOT be larger than 16384 bytes."
+        );
+
+        // A conservative client holds the initial data until the operation reply once a proxy refused it.
+        let hold = self.initial_data_mode == InitialDataMode::Conservative
+            && self.initial_data_refused.load(Ordering::Relaxed);
+        let (initial_data, mut deferred) = if hold { (vec![], initial_data) } else { (initial_data, vec![]) };
+        let initial_data_length = initial_data.len() as u16;
+
+        // Prepare SOCKS options, including the methods and data of the authenticator.
+        let mut options = options.unwrap_or_default();
+        let mut auth_methods = vec![];
+        let mut advertised_data = vec![];
+        if let Some(authenticator) = &self.authenticator {
+            auth_methods = authenticator.methods();
+            let auth_data = authenticator.request_data().await?;
+            if authenticator.advertises_data() {
+                advertised_data = auth_data;
+            } else {
+                options.extend(auth_data.into_iter().map(AuthDataOption::wrap));
+            }
+        }
+
+        let auth_methods_adv = AuthMethodAdvertisementOption::new(initial_data_length, auth_methods);
+        options.push(auth_methods_adv.wrap());
+        if !advertised_data.is_empty() {
+            options.push(AuthMethodDataOption::new(advertised_data).wrap());
+        }
+        if self.connection_id_metadata {
+            options.push(MetadataOption::new(CONNECTION_ID_METADATA_KEY, id.to_string()).wrap());
+        }
+        if self.metadata_compression {
+            options.push(socks6::metadata_compression_option());
+        }
+
+        // Create SOCKS6 request, only guarding the destinations of connects.
+        let destination = destination.try_into().map_err(Into::into)?;
+        let destination = match command {
+            Socks6Command::Connect => self.guard.check(&destination).await?,
+            _ => destination,
+        };
+        let destination = self.scope_policy.apply(destination)?;
+        debug!("[{}] Sending a {:?} request for {} to the SOCKS6 proxy.", id, command, destination);
+        let request = Socks6Request::new(command, destination, initial_data_length, options, None)
+            .with_packed_metadata(self.packed_metadata)
+            .with_compressed_metadata(self.compresses_metadata());
+        let options_length = request.options_len() + self.default_options.len();
+        ensure!(
+            options_length <= u16::MAX as usize,
+            "Options MUST NOT be larger than {} bytes, got: {}",
+            u16::MAX,
+            options_length
+        );
+
+        // Send SOCKS request information, directly followed by the initial data.
+        let request_length = request.encoded_len() + self.default_options.len();
+        let mut request_bytes = Vec::with_capacity(request_length + initial_data.len());
+        request.write_socks_bytes_with(&mut request_bytes, self.draft, &self.default_options);
+        request_bytes.extend_from_slice(&initial_data);
+        let mut watch = Stopwatch::start();
+        stream.write_all(&request_bytes).await?;
+
+        // Wait for authentication and operation reply, answering the challenges of the proxy in between.
+        let mut auth_reply = socks6::read_authentication_reply_or_refusal(stream, self.draft, self.parse_mode).await?;
+        log_diagnostics(&id, &auth_reply.diagnostics);
+        let mut round = 1;
+        while let Some((method, challenge)) = challenge(&auth_reply) {
+            let authenticator = match &self.authenticator {
+                Some(authenticator) => authenticator,
+                None => bail!("Unexpected authentication challenge for {:?}", method),
+            };
+            ensure!(
+                round <= self.max_auth_rounds,
+                "Proxy still challenging after {} rounds of authentication",
+                self.max_auth_rounds
+            );
+            debug!("[{}] Answering authentication challenge {} for {:?}.", id, round, method);
+
+            let response = authenticator.respond(method, &challenge, round).await?;
+            let response: Vec<SocksOption> = response.into_iter().map(AuthDataOption::wrap).collect();
+            let mut message = vec![];
+            wire::encode_options_for(&response, self.draft, &mut message);
+            stream.write_all(&message).await?;
+
+            auth_reply = socks6::read_authentication_reply_with_mode(stream, self.draft, self.parse_mode).await?;
+            log_diagnostics(&id, &auth_reply.diagnostics);
+            round += 1;
+        }
+        if !auth_reply.is_success() {
+            // Without a selection, the proxy doesn't say why, so the credentials are assumed to be at fault.
+            let error = match auth_reply.selection {
+                Some(selection) if selection.method == AuthMethod::NoAcceptableMethods => {
+                    SocksError::NoAcceptableAuthMethod
+                }
+                _ => SocksError::AuthenticationFailed(auth_reply.failure_reason()),
+            };
+            return Err(error.into());
+        }
+        timings.authentication = watch.lap();
+        if self.metadata_compression && socks6::supports_metadata_compression(&auth_reply.options) {
+            self.metadata_compression_supported.store(true, Ordering::Relaxed);
+        }
+
+        let recorder = Recorder::client(self.metrics.as_ref(), "6");
+        let (binding, options, diagnostics) =
+            socks6::read_reply_observed(stream, self.draft, self.parse_mode, |code| recorder.reply_received(code))
+                .await?;
+        timings.operation_reply = watch.lap();
+        log_diagnostics(&id, &diagnostics);
+
+        // A proxy that refuses initial data in its authentication reply leaves it unread, so it follows the operation
+        // reply. One that reports it discarded the initial data in its operation reply gets it again through the
+        // tunnel, once. Without a report, which only socksx proxies send, the initial data is taken as delivered.
+        let refused = |accepted: Option<u16>| accepted.is_some_and(|accepted| accepted < initial_data_length);
+        let unread = refused(auth_reply.initial_data_accepted());
+        if unread {
+            debug!("[{}] The proxy left the initial data unread, it follows the operation reply.", id);
+            self.initial_data_refused.store(true, Ordering::Relaxed);
+        } else if refused(socks6::initial_data_accepted(&options)) {
+            debug!("[{}] The proxy discarded the initial data, sending it after the operation reply.", id);
+            self.initial_data_refused.store(true, Ordering::Relaxed);
+            deferred = initial_data;
+        }
+
+        let delivery = if unread {
+            InitialDataDelivery::PostHandshake
+        } else if !deferred.is_empty() {
+            stream.write_all(&deferred).await?;
+            stream.flush().await?;
+            InitialDataDelivery::PostHandshake
+        } else if initial_data_length > 0 {
+            InitialDataDelivery::Optimistic
+        } else {
+            InitialDataDelivery::None
+        };
+
+        Ok((binding, options, delivery))
+    }
+}
+
+/// Logs the deviations from the draft that were accepted in a reply of the proxy.
+fn log_diagnostics(
+    id: &ConnectionId,
+    diagnostics: &[Diagnostic],
+) {
+    for diagnostic in diagnostics {
+        debug!("[{}] Accepted a reply that deviates from the draft: {}.", id, diagnostic);
+    }
+}
+
+/// Returns whether the proxy answered a request with a reply saying the command isn't supported.
+fn refuses_noop(error: &anyhow::Error) -> bool {
+    matches!(error.downcast_ref(), Some(SocksError::OperationFailed(SOCKS_REP_COMMAND_NOT_SUPPORTED)))
+}
+
+/// Returns the username/password authenticator for the credentials, if any.
+fn user_pass(credentials: Option<Credentials>) -> Option<Arc<dyn ClientAuthenticator + Send + Sync>> {
+    credentials.map(|credentials| Arc::new(UserPassAuthenticator::new(credentials)) as _)
+}
+
+/// Returns the selected method and the challenge for it, if the authentication reply is a challenge: a failed
+/// reply that selects a method and carries data for it.
+fn challenge(reply: &AuthenticationReply) -> Option<(AuthMethod, Vec<u8>)> {
+    if reply.is_success() {
+        return None;
+    }
+
+    let method = reply.selection.as_ref()?.method.clone();
+    let data = reply.options.auth_data(&method)?.data.clone();
+
+    Some((method, data))
+}
+
+/// Converts the metadata into options, ordered by key, that replace the options for the same keys.
+///
+/// Unless the metadata is sent compressed, the options have to fit in an options block as they are.
+fn merge_metadata(
+    options: Vec<SocksOption>,
+    metadata: HashMap<u16, String>,
+    compressed: bool,
+) -> Result<Vec<SocksOption>> {
+    let mut metadata: Vec<MetadataOption> = metadata
+        .into_iter()
+        .map(|(key, value)| MetadataOption::new(key, value))
+        .collect();
+    metadata.sort_by_key(|option| option.key);
+
+    for option in &metadata {
+        ensure!(
+            option.encoded_len() <= u16::MAX as usize,
+            "Metadata value for key {} is too large: {} bytes",
+            option.key,
+            option.value.len()
+        );
+    }
+
+    let mut options: Vec<SocksOption> = options
+        .into_iter()
+        .filter(|o| match o {
+            SocksOption::Metadata(m) => !metadata.iter().any(|option| option.key == m.key),
+            _ => true,
+        })
+        .collect();
+    options.extend(metadata.into_iter().map(MetadataOption::wrap));
+
+    let length: usize = options.iter().map(SocksOption::encoded_len).sum();
+    ensure!(
+        compressed || length <= u16::MAX as usize,
+        "Options MUST NOT be larger than {} bytes, got: {}",
+        u16::MAX,
+        length
+    );
+
+    Ok(options)
+}
+
+#[async_trait]
+impl SocksClient for Socks6Client {
+    async fn connect(
+        &self,
+        destination: Address,
+        initial_data: Option<Vec<u8>>,
+    ) -> Result<(SocksStream, Address)> {
+        Socks6Client::connect(self, destination, initial_data, None).await
+    }
+
+    async fn connect_routed(
+        &self,
+        destination: Address,
+        initial_data: Option<Vec<u8>>,
+    ) -> Result<(SocksStream, Address, Route)> {
+        Socks6Client::connect_routed(self, destination, initial_data, None).await
+    }
+
+    fn marked(
+        &self,
+        mark: u32,
+    ) -> Option<Box<dyn SocksClient + Send + Sync>> {
+        Some(Box::new(self.clone().with_mark(mark)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use tokio::io::{self, AsyncReadExt, DuplexStream, ReadBuf};
+    use tokio::net::TcpListener;
+
+    use crate::socks6::{BearerTokenAuthenticator, Socks6Reply};
+    use crate::metrics::{InMemoryMetrics, HANDSHAKE_PHASE_DURATION};
+    use crate::test_util::{Harness, MockConnector, MockSocksServer, Phase};
+    use crate::wire::vectors;
+    use crate::{Socks6Handler, SocksHandler};
+
+    use super::*;
+
+    // Client authenticator that answers each challenge with the challenge reversed, followed by the round.
+    struct ReverseAuthenticator;
+
+    #[async_trait]
+    impl ClientAuthenticator for ReverseAuthenticator {
+        fn methods(&self) -> Vec<AuthMethod> {
+            vec![AuthMethod::Gssapi]
+        }
+
+        async fn request_data(&self) -> Result<Vec<AuthDataOption>> {
+            Ok(vec![])
+        }
+
+        async fn respond(
+            &self,
+            method: AuthMethod,
+            challenge: &[u8],
+            round: usize,
+        ) -> Result<Vec<AuthDataOption>> {
+            let mut response: Vec<u8> = challenge.iter().rev().copied().collect();
+            response.push(b'0' + round as u8);
+            Ok(vec![AuthDataOption::new(method, response)])
+        }
+    }
+
+    // Transport that records the size of every write call.
+    struct RecordingStream {
+        inner: DuplexStream,
+        writes: Vec<usize>,
+    }
+
+    impl AsyncRead for RecordingStream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for RecordingStream {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+            if let Poll::Ready(Ok(n)) = result {
+                self.writes.push(n);
+            }
+
+            result
+        }
+
+        fn poll_flush(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    // Tests that the client sends exactly the golden request, followed by its initial data, takes the binding from
+    // the golden reply, and fails on the golden authentication failure.
+    #[tokio::test]
+    async fn test_golden_vectors() -> Result<()> {
+        let request = vectors::socks6_connect_with_metadata();
+        let reply = vectors::socks6_reply_success();
+        let client = Socks6Client::for_streams(None);
+
+        let auth_replies = [(vectors::socks6_auth_success(), true), (vectors::socks6_auth_failure(), false)];
+        for (auth_reply, accepted) in auth_replies {
+            let (stream, mut proxy) = tokio::io::duplex(4096);
+            let answers = [auth_reply.bytes, reply.bytes].concat();
+            let length = request.bytes.len() + vectors::SOCKS6_INITIAL_DATA.len();
+            let proxy = tokio::spawn(async move {
+                let mut received = vec![0; length];
+                proxy.read_exact(&mut received).await?;
+                proxy.write_all(&answers).await?;
+                Ok::<_, anyhow::Error>(received)
+            });
+
+            let metadata = vec![MetadataOption::new(1, String::from("value")).wrap()];
+            let destination = request.value.destination.clone();
+            let initial_data = Some(vectors::SOCKS6_INITIAL_DATA.to_vec());
+            let result = client.connect_with_stream(stream, destination, initial_data, Some(metadata)).await;
+            assert_eq!(proxy.await??, [request.bytes, vectors::SOCKS6_INITIAL_DATA].concat());
+
+            match result {
+                Ok((_, binding)) => assert!(accepted && binding == reply.value.1),
+                Err(error) => {
+                    assert!(!accepted);
+                    assert!(matches!(error.downcast_ref(), Some(SocksError::AuthenticationFailed(_))));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Tests that the request and the initial data are sent with a single write call.
+    #[tokio::test]
+    async fn test_request_and_initial_data_coalesced() -> Result<()> {
+        let (client_end, proxy_end) = io::duplex(4096);
+        let mut stream = RecordingStream {
+            inner: client_end,
+            writes: vec![],
+        };
+
+        let proxy = MockSocksServer::socks6();
+        let server = proxy.clone();
+        tokio::spawn(async move { server.serve(proxy_end).await });
+
+        let client = Socks6Client::new("127.0.0.1:1080", None).await?;
+        client
+            .handshake("127.0.0.1:80".to_string(), Some(b"hello".to_vec()), None, &mut stream)
+            .await?;
+
+        let mut echoed = [0; 5];
+        stream.read_exact(&mut echoed).await?;
+        assert_eq!(&echoed, b"hello");
+
+        assert_eq!(proxy.recordings()[0].initial_data, b"hello".to_vec());
+        assert_eq!(stream.writes.len(), 1);
+        Ok(())
+    }
+
+    // Tests that initial data a proxy discarded is sent again after the operation reply, so that the destination
+    // gets it exactly once, before the data of the tunnel.
+    #[tokio::test]
+    async fn test_initial_data_resent_when_discarded() -> Result<()> {
+        let proxy = MockSocksServer::socks6().with_dropped_initial_data();
+        let client = Socks6Client::new(proxy.bind().await?.to_string(), None).await?;
+
+        let (mut stream, _, delivery) =
+            client.connect_with_delivery("192.0.2.1:80", Some(b"hello".to_vec()), None).await?;
+        assert_eq!(delivery, InitialDataDelivery::PostHandshake);
+
+        stream.write_all(b"world").await?;
+        let mut echoed = [0; 10];
+        stream.read_exact(&mut echoed).await?;
+        assert_eq!(&echoed, b"helloworld");
+        assert_eq!(proxy.recordings()[0].tunnel, b"helloworld".to_vec());
+
+        // A proxy that keeps the initial data gets it once, along with the request.
+        let proxy = MockSocksServer::socks6();
+        let client = Socks6Client::new(proxy.bind().await?.to_string(), None).await?;
+        let (_, _, delivery) = client.connect_with_delivery("192.0.2.1:80", Some(b"hello".to_vec()), None).await?;
+        assert_eq!(delivery, InitialDataDelivery::Optimistic);
+        let (_, _, delivery) = client.connect_with_delivery("192.0.2.1:80", None, None).await?;
+        assert_eq!(delivery, InitialDataDelivery::None);
+
+        Ok(())
+    }
+
+    // Tests the limit of detecting discarded initial data: a proxy that discards it without reporting it, as the
+    // draft offers no way to, is taken to have delivered it, and the destination never gets it.
+    #[tokio::test]
+    async fn test_initial_data_discarded_unreported() -> Result<()> {
+        let proxy = MockSocksServer::socks6().with_silently_dropped_initial_data();
+        let client = Socks6Client::new(proxy.bind().await?.to_string(), None).await?;
+
+        let (mut stream, _, delivery) =
+            client.connect_with_delivery("192.0.2.1:80", Some(b"hello".to_vec()), None).await?;
+        assert_eq!(delivery, InitialDataDelivery::Optimistic);
+
+        stream.write_all(b"world").await?;
+        let mut echoed = [0; 5];
+        stream.read_exact(&mut echoed).await?;
+        assert_eq!(&echoed, b"world");
+        assert_eq!(proxy.recordings()[0].initial_data, b"hello".to_vec());
+        assert_eq!(proxy.recordings()[0].tunnel, b"world".to_vec());
+
+        Ok(())
+    }
+
+    // Tests that a SOCKS6 proxy that rejects the authentication fails the connect.
+    #[tokio::test]
+    async fn test_authentication_rejected() -> Result<()> {
+        let proxy = MockSocksServer::socks6().with_credentials(Credentials::new("user", "secret"));
+        let proxy_addr = proxy.bind().await?;
+
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+        let error = client.connect("192.0.2.1:80".to_string(), None, None).await.err().unwrap();
+        assert!(matches!(error.downcast_ref(), Some(SocksError::NoAcceptableAuthMethod)));
+        assert_eq!(proxy.recordings()[0].destination, Some(Address::new("192.0.2.1", 80)));
+
+        let client = Socks6Client::new(proxy_addr.to_string(), Some(Credentials::new("user", "wrong"))).await?;
+        let error = client.connect("192.0.2.1:80".to_string(), None, None).await.err().unwrap();
+        assert!(matches!(error.downcast_ref(), Some(SocksError::AuthenticationFailed(_))));
+        assert_eq!(proxy.recordings()[1].credentials, Some(Credentials::new("user", "wrong")));
+        Ok(())
+    }
+
+    // Tests username/password authentication through the authentication data option.
+    #[tokio::test]
+    async fn test_authentication_with_credentials() -> Result<()> {
+        let proxy = MockSocksServer::socks6().with_credentials(Credentials::new("user", "secret"));
+        let proxy_addr = proxy.bind().await?;
+
+        let client = Socks6Client::new(proxy_addr.to_string(), Some(Credentials::new("user", "secret"))).await?;
+        let (mut stream, _) = client.connect("192.0.2.1:80".to_string(), Some(b"hi".to_vec()), None).await?;
+
+        let mut echoed = [0; 2];
+        stream.read_exact(&mut echoed).await?;
+        assert_eq!(&echoed, b"hi");
+
+        let recording = &proxy.recordings()[0];
+        assert_eq!(recording.credentials, Some(Credentials::new("user", "secret")));
+        assert!(recording.options.iter().any(|o| matches!(
+            o,
+            SocksOption::AuthMethodAdvertisement(a) if a.methods == vec![AuthMethod::UsernamePassword]
+        )));
+        Ok(())
+    }
+
+    // Tests that a client speaking draft 13 authenticates with a draft 13 proxy, while a draft 11 client's
+    // authentication data isn't recognized by it.
+    #[tokio::test]
+    async fn test_draft13_proxy() -> Result<()> {
+        let credentials = Credentials::new("user", "secret");
+        let handler = Socks6Handler::default()
+            .with_credentials(credentials.clone())
+            .with_draft(Socks6Draft::Draft13);
+        let harness = Harness::socks6(handler);
+
+        let client = Socks6Client::for_streams(Some(credentials.clone()))
+            .with_default_options(vec![MetadataOption::new(1, String::from("default")).wrap()])
+            .with_draft(Socks6Draft::Draft13);
+        let (mut stream, _) = harness.connect_socks6(&client, "192.0.2.1:80".to_string(), None, None).await?;
+        stream.write_all(b"hello").await?;
+        assert_eq!(harness.connector().received(0, 5).await, b"hello".to_vec());
+
+        let client = Socks6Client::for_streams(Some(credentials));
+        let error = harness
+            .connect_socks6(&client, "192.0.2.1:80".to_string(), None, None)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(error.downcast_ref(), Some(SocksError::AuthenticationFailed(_))));
+        Ok(())
+    }
+
+    // Tests that a reply with an unknown address type fails the connect with a typed error.
+    #[tokio::test]
+    async fn test_reply_with_unknown_address_type() -> Result<()> {
+        let (client_end, mut proxy_end) = io::duplex(4096);
+        tokio::spawn(async move {
+            socks6::read_request(&mut proxy_end).await?;
+            socks6::write_no_authentication(&mut proxy_end).await?;
+
+            let mut reply = vec![];
+            crate::wire::encode_socks6_reply(SOCKS_REP_SUCCEEDED, &Address::new("10.0.0.1", 80), &[], &mut reply);
+            reply[3] = 0x05;
+            proxy_end.write_all(&reply).await?;
+            Ok::<_, anyhow::Error>(())
+        });
+
+        let client = Socks6Client::for_streams(None);
+        let error = client
+            .connect_with_stream(client_end, "192.0.2.1:80".to_string(), None, None)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(crate::SocksError::UnsupportedAddressType(0x05))
+        ));
+        Ok(())
+    }
+
+    // Tests creating a client from a proxy address, which must be of a SOCKS6 proxy.
+    #[tokio::test]
+    async fn test_from_proxy_addr() -> Result<()> {
+        let proxy = MockSocksServer::socks6();
+        let proxy_addr = proxy.bind().await?;
+
+        let client = Socks6Client::from_proxy_addr(format!("socks6://{}", proxy_addr).try_into()?).await?;
+        client.connect("192.0.2.1:80".to_string(), None, None).await?;
+        assert_eq!(client.proxy_addr(), Some(proxy_addr));
+
+        let socks5 = ProxyAddress::new(SOCKS_VER_5, proxy_addr.ip().to_string(), proxy_addr.port(), None);
+        assert!(Socks6Client::from_proxy_addr(socks5).await.is_err());
+        Ok(())
+    }
+
+    // Tests that the factory creates a client of the version the proxy address carries.
+    #[tokio::test]
+    async fn test_client_from_proxy_addr() -> Result<()> {
+        for (version, proxy) in [
+            (SOCKS_VER_5, MockSocksServer::socks5()),
+            (SOCKS_VER_6, MockSocksServer::socks6()),
+        ] {
+            let proxy_addr = proxy.bind().await?;
+            let proxy_addr = ProxyAddress::new(version, proxy_addr.ip().to_string(), proxy_addr.port(), None);
+            let client = crate::client_from_proxy_addr(proxy_addr).await?;
+            client.connect(Address::new("192.0.2.1", 80), None).await?;
+            assert_eq!(proxy.recordings().len(), 1);
+        }
+
+        let unknown = ProxyAddress::new(4, String::from("127.0.0.1"), 1080, None);
+        assert!(crate::client_from_proxy_addr(unknown).await.is_err());
+        Ok(())
+    }
+
+    // Tests that the connection ID is sent along as metadata when enabled, and carried by handshake errors.
+    #[tokio::test]
+    async fn test_connection_id_metadata() -> Result<()> {
+        let proxy = MockSocksServer::socks6().with_reply(SOCKS_REP_CONNECTION_REFUSED);
+
+        let client = Socks6Client::for_streams(None).with_connection_id_metadata(true);
+        let error = client
+            .handshake("192.0.2.1:80".to_string(), None, None, &mut proxy.duplex())
+            .await
+            .unwrap_err();
+
+        let sent = proxy.recordings()[0].options.iter().find_map(|o| match o {
+            SocksOption::Metadata(m) if m.key == CONNECTION_ID_METADATA_KEY => m.value.parse().ok(),
+            _ => None,
+        });
+        assert!(sent.is_some());
+        assert_eq!(ConnectionId::of(&error), sent);
+        assert!(error.to_string().starts_with(&format!("[{}] ", sent.unwrap())));
+
+        Ok(())
+    }
+
+    // Tests that the caller's options, the default options, and the advertisement each appear exactly once.
+    #[tokio::test]
+    async fn test_options_appear_once() -> Result<()> {
+        let proxy = MockSocksServer::socks6();
+        let mut client_end = proxy.duplex();
+
+        let client = Socks6Client::new("127.0.0.1:1080", None)
+            .await?
+            .with_default_options(vec![MetadataOption::new(2, String::from("default")).wrap()]);
+        let options = vec![MetadataOption::new(1, String::from("caller")).wrap()];
+        client
+            .handshake("127.0.0.1:80".to_string(), None, Some(options), &mut client_end)
+            .await?;
+
+        let options = proxy.recordings()[0].options.clone();
+        assert_eq!(options.len(), 3);
+
+        let keys: Vec<u16> = options
+            .iter()
+            .filter_map(|o| match o {
+                SocksOption::Metadata(m) => Some(m.key),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(keys, vec![1, 2]);
+        assert_eq!(
+            options.iter().filter(|o| matches!(o, SocksOption::AuthMethodAdvertisement(_))).count(),
+            1
+        );
+        Ok(())
+    }
+
+    // Tests that the handler receives exactly the metadata that was sent, without duplicate keys.
+    #[tokio::test]
+    async fn test_connect_with_metadata() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = listener.local_addr()?;
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            let handler = Socks6Handler::new(vec![]);
+            let (session, request) = handler.accept(&mut stream).await?;
+            session.reject(Socks6Reply::ConnectionRefused).await?;
+
+            Ok::<_, anyhow::Error>(request)
+        });
+
+        let mut metadata = HashMap::new();
+        metadata.insert(2, String::from("two"));
+        metadata.insert(1, String::from("one"));
+        let options = vec![
+            MetadataOption::new(1, String::from("replaced")).wrap(),
+            MetadataOption::new(3, String::from("three")).wrap(),
+        ];
+
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+        let result = client
+            .connect_with_metadata("192.0.2.1:80".to_string(), None, Some(options), metadata)
+            .await;
+        assert!(result.is_err());
+
+        let request = server.await??;
+        let keys: Vec<u16> = request
+            .options
+            .iter()
+            .filter_map(|o| match o {
+                SocksOption::Metadata(m) => Some(m.key),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(keys, vec![1, 2, 3]);
+        assert_eq!(request.metadata.len(), 3);
+        assert_eq!(request.metadata.get(&1), Some(&String::from("one")));
+        assert_eq!(request.metadata.get(&2), Some(&String::from("two")));
+        assert_eq!(request.metadata.get(&3), Some(&String::from("three")));
+
+        Ok(())
+    }
+
+    // Tests that the metadata is only sent compressed once the proxy advertised support, in the handshakes after
+    // the first, and never to a proxy without support.
+    #[tokio::test]
+    async fn test_metadata_compression() -> Result<()> {
+        let metadata: HashMap<u16, String> =
+            (1..=20).map(|key| (key, format!("proxy-{}.example.com", key % 3))).collect();
+        let packed_kind = SOCKS_OKIND_PACKED_METADATA.to_be_bytes();
+
+        for advertised in [false, true] {
+            let client = Socks6Client::for_streams(None).with_metadata_compression(true);
+            for round in 0..2 {
+                let (mut stream, mut proxy) = tokio::io::duplex(4096);
+                let proxying = async {
+                    let request = socks6::read_request_raw(&mut proxy).await?;
+                    let options = if advertised { vec![socks6::metadata_compression_option()] } else { vec![] };
+                    let mut reply = vec![];
+                    let draft = Socks6Draft::default();
+                    wire::encode_socks6_auth_reply_for(SOCKS_AUTH_SUCCESS, &options, draft, &mut reply);
+                    proxy.write_all(&reply).await?;
+                    socks6::write_reply(&mut proxy, Socks6Reply::Success, &Address::new("0.0.0.0", 0), &[]).await?;
+                    Ok::<_, anyhow::Error>(request)
+                };
+                let options = merge_metadata(vec![], metadata.clone(), false)?;
+                let handshake = client.handshake_with_reply("192.0.2.1:80", None, Some(options), &mut stream);
+                let (handshake, request) = tokio::join!(handshake, proxying);
+                handshake?;
+
+                let request = request?;
+                assert_eq!(request.metadata, metadata);
+                assert!(socks6::supports_metadata_compression(&request.options));
+                let compressed = request.raw_options().unwrap().windows(2).any(|w| w == packed_kind);
+                assert_eq!(compressed, advertised && round == 1, "advertised: {}, round: {}", advertised, round);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Tests that metadata values that don't fit in an option are refused before connecting.
+    #[test]
+    fn test_merge_metadata_too_large() {
+        let mut metadata = HashMap::new();
+        metadata.insert(1, "x".repeat(u16::MAX as usize));
+        assert!(merge_metadata(vec![], metadata, false).is_err());
+
+        let mut metadata = HashMap::new();
+        metadata.insert(1, "x".repeat(40_000));
+        metadata.insert(2, "x".repeat(40_000));
+        assert!(merge_metadata(vec![], metadata, false).is_err());
+    }
+
+    // Tests a two-round challenge/response flow driven by a client authenticator, with initial data.
+    #[tokio::test]
+    async fn test_authenticator_challenges() -> Result<()> {
+        let challenges = vec![b"abc".to_vec(), b"xyz".to_vec()];
+        let proxy = MockSocksServer::socks6().with_challenges(AuthMethod::Gssapi, challenges);
+        let proxy_addr = proxy.bind().await?;
+
+        let client = Socks6Client::new(proxy_addr.to_string(), None)
+            .await?
+            .with_authenticator(Arc::new(ReverseAuthenticator));
+        let (mut stream, _) = client.connect("192.0.2.1:80".to_string(), Some(b"hi".to_vec()), None).await?;
+
+        let mut echoed = [0; 2];
+        stream.read_exact(&mut echoed).await?;
+        assert_eq!(&echoed, b"hi");
+
+        let recording = &proxy.recordings()[0];
+        let responses: Vec<&[u8]> = recording.responses.iter().map(|r| &r[..4]).collect();
+        assert_eq!(responses, vec![&b"cba1"[..], &b"zyx2"[..]]);
+        assert_eq!(recording.initial_data, b"hi".to_vec());
+
+        // Without an authenticator that expects them, challenges fail the handshake.
+        let client = Socks6Client::new(proxy_addr.to_string(), Some(Credentials::new("user", "secret"))).await?;
+        assert!(client.connect("192.0.2.1:80".to_string(), None, None).await.is_err());
+        Ok(())
+    }
+
+    // Tests that a bearer token is advertised and sent along with the request.
+    #[tokio::test]
+    async fn test_bearer_token() -> Result<()> {
+        let proxy = MockSocksServer::socks6();
+        let authenticator = BearerTokenAuthenticator::new("token");
+        let client = Socks6Client::for_streams(None).with_authenticator(Arc::new(authenticator));
+        client.handshake("192.0.2.1:80".to_string(), None, None, &mut proxy.duplex()).await?;
+
+        let options = &proxy.recordings()[0].options;
+        assert!(options.iter().any(|o| matches!(
+            o,
+            SocksOption::AuthMethodAdvertisement(a) if a.methods == vec![AuthMethod::BearerToken]
+        )));
+        assert!(options.iter().any(|o| matches!(
+            o,
+            SocksOption::AuthData(d) if d.method == AuthMethod::BearerToken && d.data.starts_with(b"\x00\x05token")
+        )));
+
+        // Configured for it, the token is attached to the advertisement instead.
+        let authenticator = BearerTokenAuthenticator::new("token").with_advertised_data(true);
+        let client = Socks6Client::for_streams(None).with_authenticator(Arc::new(authenticator));
+        client.handshake("192.0.2.1:80".to_string(), None, None, &mut proxy.duplex()).await?;
+
+        let options = &proxy.recordings()[1].options;
+        let method_data = options.auth_method_data().unwrap();
+        assert_eq!(method_data.data_for(&AuthMethod::BearerToken), Some(&b"\x00\x05token"[..]));
+        assert!(options.auth_data(&AuthMethod::BearerToken).is_none());
+        Ok(())
+    }
+
+    // Tests a health check by NOOP, and by a connect to the canary against a proxy that doesn't support NOOP.
+    #[tokio::test]
+    async fn test_health_check() -> Result<()> {
+        let proxy = MockSocksServer::socks6();
+        let proxy_addr = proxy.bind().await?;
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+
+        let report = client.health_check().await?;
+        assert_eq!(report.probe, HealthProbe::NoOp);
+        assert_eq!(report.proxy, Some(proxy_addr));
+        assert_eq!(proxy.recordings()[0].bytes[1], Socks6Command::NoOp as u8);
+
+        // A Socks6Handler only implements CONNECT.
+        let handler = Arc::new(Socks6Handler::default().with_connector(Arc::new(MockConnector::new())));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let handler_addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let handler = handler.clone();
+                tokio::spawn(async move { handler.accept_request(&mut stream).await });
+            }
+        });
+
+        let client = Socks6Client::new(handler_addr.to_string(), None).await?;
+        let error = client.health_check().await.unwrap_err();
+        assert!(refuses_noop(&error));
+
+        let client = client.with_health_canary(Address::new("192.0.2.1", 80));
+        let report = client.health_check().await?;
+        assert_eq!(report.probe, HealthProbe::Canary);
+        assert_eq!(report.proxy, Some(handler_addr));
+        Ok(())
+    }
+
+    // Waits for the health status of the client to satisfy the predicate, for at most five seconds.
+    async fn wait_for_health(
+        client: &Socks6Client,
+        predicate: impl Fn(&HealthStatus) -> bool,
+    ) -> HealthStatus {
+        let waiting = async {
+            loop {
+                let status = client.health();
+                if predicate(&status) {
+                    return status;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), waiting).await.expect("health status didn't change")
+    }
+
+    // Tests that the monitor follows a proxy that flips between healthy and failing.
+    #[tokio::test]
+    async fn test_health_monitor() -> Result<()> {
+        let healthy = Arc::new(AtomicBool::new(true));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = listener.local_addr()?;
+        let serving = healthy.clone();
+        tokio::spawn(async move {
+            let proxy = MockSocksServer::socks6().with_reply(SOCKS_REP_GENERAL_FAILURE);
+            while let Ok((stream, _)) = listener.accept().await {
+                let proxy = match serving.load(Ordering::SeqCst) {
+                    true => MockSocksServer::socks6(),
+                    false => proxy.clone(),
+                };
+                tokio::spawn(async move { proxy.serve(stream).await });
+            }
+        });
+
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+        assert_eq!(client.health(), HealthStatus::Unknown);
+        let _monitor = client.spawn_health_monitor(Duration::from_millis(20));
+
+        match wait_for_health(&client, HealthStatus::is_healthy).await {
+            HealthStatus::Healthy(report) => assert_eq!(report.proxy, Some(proxy_addr)),
+            status => panic!("Unexpected status: {:?}", status),
+        }
+
+        healthy.store(false, Ordering::SeqCst);
+        wait_for_health(&client, |status| matches!(status, HealthStatus::Unhealthy(_))).await;
+
+        healthy.store(true, Ordering::SeqCst);
+        wait_for_health(&client, HealthStatus::is_healthy).await;
+        Ok(())
+    }
+
+    // Tests that the timings of a connect attribute a slow operation reply to its phase, and are recorded in the
+    // metrics.
+    #[tokio::test]
+    async fn test_connect_with_timings() -> Result<()> {
+        let proxy = MockSocksServer::socks6().with_delay(Phase::Reply, Duration::from_millis(50));
+        let proxy_addr = proxy.bind().await?;
+        let metrics = Arc::new(InMemoryMetrics::new());
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?.with_metrics(metrics.clone());
+
+        let (_, _, timings) = client.connect_with_timings("192.0.2.1:80", None, None).await?;
+        assert_eq!(timings.attempts, 1);
+        assert_eq!(timings.resolution, None);
+        assert_eq!(timings.proxy_addr, Some(proxy_addr));
+        assert_eq!(timings.connect_attempts, 1);
+        assert!(timings.operation_reply >= Duration::from_millis(50));
+        let phases: Duration = timings.phases().iter().filter(|(phase, _)| *phase != "total").map(|(_, d)| *d).sum();
+        assert!(timings.total >= phases);
+
+        let labels = [("role", "client"), ("version", "6"), ("phase", "operation_reply")];
+        assert_eq!(metrics.durations(HANDSHAKE_PHASE_DURATION, &labels), vec![timings.operation_reply]);
+        Ok(())
+    }
+
+    // Tests that a proxy listening on a Unix socket is connected to at its `unix:` path, through the
+    // version-generic trait.
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_connect_over_unix_socket() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("socksx-s6-client-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path)?;
+        let connector = Arc::new(MockConnector::new());
+        let handler = Arc::new(Socks6Handler::default().with_connector(connector.clone()));
+        tokio::spawn(async move {
+            while let Ok((mut source, _)) = listener.accept().await {
+                let handler = handler.clone();
+                tokio::spawn(async move { handler.accept_request(&mut source).await });
+            }
+        });
+
+        let client = Socks6Client::new(format!("unix:{}", path.display()), None).await?;
+        let client: Box<dyn SocksClient + Send + Sync> = Box::new(client);
+        for (index, host) in ["192.0.2.1", "192.0.2.2"].iter().enumerate() {
+            let (mut stream, _) = client.connect(Address::new(*host, 80), Some(b"hello".to_vec())).await?;
+            assert!(matches!(stream, SocksStream::Unix(_)));
+            assert_eq!(connector.received(index, 5).await, b"hello".to_vec());
+
+            let mut echoed = [0; 5];
+            stream.read_exact(&mut echoed).await?;
+            assert_eq!(&echoed, b"hello");
+        }
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    // Tests that a proxy listening on a Unix socket is health checked at its socket, by both a check and a monitor,
+    // and that the canary connect after a refused NOOP reconnects to the socket as well.
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_health_check_over_unix_socket() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("socksx-s6-health-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path)?;
+        let connector = Arc::new(MockConnector::new());
+        let handler = Arc::new(Socks6Handler::default().with_connector(connector.clone()));
+        tokio::spawn(async move {
+            while let Ok((mut source, _)) = listener.accept().await {
+                let handler = handler.clone();
+                tokio::spawn(async move { handler.accept_request(&mut source).await });
+            }
+        });
+
+        let canary = Address::new("192.0.2.1", 80);
+        let client = Socks6Client::new(format!("unix:{}", path.display()), None).await?.with_health_canary(canary);
+        let report = client.health_check().await?;
+        assert_eq!(report.probe, HealthProbe::Canary);
+        assert_eq!(report.proxy, None);
+        assert_eq!(connector.destinations()[0].address, Address::new("192.0.2.1", 80));
+
+        let _monitor = client.spawn_health_monitor(Duration::from_millis(20));
+        match wait_for_health(&client, HealthStatus::is_healthy).await {
+            HealthStatus::Healthy(report) => assert_eq!(report.proxy, None),
+            status => panic!("Unexpected status: {:?}", status),
+        }
+
+        std::fs::remove_file(&path)?;
+        Ok(())
     }
 }
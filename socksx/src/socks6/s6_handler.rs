@@ -1,18 +1,99 @@
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::collections::HashMap;
+use std::future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
 use anyhow::Result;
 use async_trait::async_trait;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use bytes::BytesMut;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
 
-use crate::{Socks6Client, SocksHandler};
-use crate::addresses::ProxyAddress;
-use crate::socks6::{self, Socks6Reply};
+use crate::{errors, util, wire, Credentials, Socks6Client, SocksError, SocksHandler};
+use crate::constants::*;
+use crate::addresses::{Address, ProxyAddress};
+use crate::baggage::Baggage;
+use crate::budget::Charge;
+use crate::interface::AsyncStream;
+use crate::dialer::{
+    self, AddressFamilyPreference, ConnectionAddrs, Connector, Dialer, Keepalive, DEFAULT_CONNECT_TIMEOUT,
+};
+use crate::guard::DestinationGuard;
+use crate::resolver::{ResolutionPolicy, Resolver};
+use crate::metrics::{Metrics, NoopMetrics, Recorder};
+use crate::mirror::{Mirror, MirroredStream};
+use crate::policy::{Policy, SharedPolicy};
+use crate::session::{
+    self, CloseSummary, ConnectionId, SessionHooks, SessionInfo, SessionLimits, TunnelInfo,
+    CONNECTION_ID_METADATA_KEY,
+};
+use crate::socks6::{
+    self, chain, ChainFailure, Diagnostic, ParseMode, Socks6Command, Socks6Draft, Socks6Reply, Socks6Request,
+    ValidationError, ValidationPolicy,
+};
+use crate::socks6::auth::{
+    AuthOutcome, AuthRequest, Authenticator, Identity, NoAuth, StaticUserPass, DEFAULT_MAX_AUTH_ROUNDS,
+};
+use crate::socks6::options::{
+    self, AuthDataOption, AuthMethod, AuthMethodSelectionOption, SocksOption, SocksOptionKind, SocksOptions, StackLeg,
+    TtlOption,
+};
+use crate::wire::MessageReader;
 
 /// Implements a SOCKS6 handler.
 #[derive(Clone)]
 pub struct Socks6Handler {
     static_links: Vec<ProxyAddress>,
+    dialer: Dialer,
+    connector: Option<Arc<dyn Connector + Send + Sync>>,
+    guard: DestinationGuard,
+    fallback: Option<Arc<dyn SocksHandler + Send + Sync>>,
+    authenticator: Arc<dyn Authenticator + Send + Sync>,
+    max_auth_rounds: usize,
+    auth_failure_reasons: bool,
+    validation: ValidationPolicy,
+    draft: Socks6Draft,
+    parse_mode: ParseMode,
+    retain_raw_options: bool,
+    forward_denylist: Vec<u16>,
+    connection_id_metadata: bool,
+    packed_metadata: bool,
+    metadata_compression: bool,
+    next_link_compression: Arc<Mutex<HashMap<String, Arc<AtomicBool>>>>,
+    hop_count: bool,
+    max_requested_ttl: Option<u8>,
+    initial_data: bool,
+    connect_timeout: Duration,
+    hooks: Option<Arc<dyn SessionHooks + Send + Sync>>,
+    session_limits: SessionLimits,
+    policy: SharedPolicy,
+    reevaluate_policy: bool,
+    allow_unix_destinations: bool,
+    resolution: ResolutionPolicy,
+    reply_passthrough: Option<Vec<SocksOptionKind>>,
+    reply_binding: ReplyBinding,
+    metrics: Arc<dyn Metrics + Send + Sync>,
+}
+
+/// The address that a `Socks6Handler` reports as the binding in its success reply.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ReplyBinding {
+    /// The unspecified address `0.0.0.0:0`, which reveals nothing about the outbound connection.
+    #[default]
+    Unspecified,
+    /// The local address of this link's connection to the destination or the next link, if the connector knows it.
+    Local,
+    /// The binding that the next link reported in its reply, or the local address of the connection to the
+    /// destination if this is the last link.
+    Upstream,
 }
 
+/// How long a source whose authentication failed may keep sending its initial data, before the
+/// connection is closed regardless.
+const AUTH_FAILURE_DRAIN_TIMEOUT: Duration = Duration::from_millis(100);
+
 impl Default for Socks6Handler {
     /// Default constructor for `Socks6Handler`.
     fn default() -> Self {
@@ -29,93 +110,2729 @@ impl Socks6Handler {
     /// # Returns
     /// A new `Socks6Handler`.
     pub fn new(static_links: Vec<ProxyAddress>) -> Self {
-        Socks6Handler { static_links }
+        Socks6Handler {
+            static_links,
+            dialer: Dialer::default(),
+            connector: None,
+            guard: DestinationGuard::permissive(),
+            fallback: None,
+            authenticator: Arc::new(NoAuth),
+            max_auth_rounds: DEFAULT_MAX_AUTH_ROUNDS,
+            auth_failure_reasons: false,
+            validation: ValidationPolicy::default(),
+            draft: Socks6Draft::default(),
+            parse_mode: ParseMode::default(),
+            retain_raw_options: false,
+            forward_denylist: default_forward_denylist(),
+            connection_id_metadata: false,
+            packed_metadata: false,
+            metadata_compression: false,
+            next_link_compression: Arc::new(Mutex::new(HashMap::new())),
+            hop_count: false,
+            max_requested_ttl: None,
+            initial_data: true,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            hooks: None,
+            session_limits: SessionLimits::none(),
+            policy: SharedPolicy::new(Policy::allow_all()),
+            reevaluate_policy: false,
+            allow_unix_destinations: false,
+            resolution: ResolutionPolicy::default(),
+            reply_passthrough: None,
+            reply_binding: ReplyBinding::default(),
+            metrics: Arc::new(NoopMetrics),
+        }
     }
-}
 
-#[async_trait]
-impl SocksHandler for Socks6Handler {
-    /// Accepts a request from the source and sets up a tunnel to the destination.
+    /// Sets the policy that determines which addresses are used for outbound connects, and in which order.
     ///
-    /// # Parameters
-    /// - `source`: A mutable reference to the source TCP stream.
+    /// This applies both to destinations and to the next proxy in a chain.
+    pub fn with_family_preference(
+        mut self,
+        family_preference: AddressFamilyPreference,
+    ) -> Self {
+        self.dialer.set_family_preference(family_preference);
+        self
+    }
+
+    /// Enables TCP keepalive on the outbound connections of the default connector.
     ///
-    /// # Returns
-    /// An `Ok(())` if the tunnel is successfully set up, otherwise an error.
-    async fn accept_request(
-        &self,
-        source: &mut TcpStream,
-    ) -> Result<()> {
-        let mut destination = self.setup(source).await?;
+    /// The source's connection belongs to the caller, who can enable it there with `Keepalive::apply`.
+    pub fn with_keepalive(
+        mut self,
+        keepalive: Keepalive,
+    ) -> Self {
+        self.dialer.set_keepalive(Some(keepalive));
+        self
+    }
 
-        // Start bidirectional copy, after this the connection closes.
-        tokio::io::copy_bidirectional(source, &mut destination).await?;
+    /// Sets the firewall mark (SO_MARK) of the sockets that the default connector connects from. Packet filter
+    /// rules can match on it, e.g., to keep the handler's own connections from being redirected back to it by the
+    /// rules of a transparent proxy. Setting the mark requires the CAP_NET_ADMIN capability.
+    ///
+    /// This applies both to destinations and to the next proxy in a chain.
+    pub fn with_mark(
+        mut self,
+        mark: u32,
+    ) -> Self {
+        self.dialer.set_mark(Some(mark));
+        self
+    }
 
-        Ok(())
+    /// Sets the TTL of the packets of the connections that the default connector makes, or their hop limit over
+    /// IPv6, instead of the system default. A zero TTL, which isn't valid, keeps the system default.
+    ///
+    /// This applies both to destinations and to the next proxy in a chain. Sources may request another TTL for
+    /// the connection to their destination if `with_requested_ttl` allows it.
+    pub fn with_ttl(
+        mut self,
+        ttl: u8,
+    ) -> Self {
+        self.dialer.set_ttl(Some(ttl));
+        self
     }
 
-    /// Refuses a request from the source.
+    /// Lets sources request the TTL, or hop limit, of the connection to their destination with a TTL stack option
+    /// (see `TtlOption`), up to the given maximum. A zero maximum allows no requests, as by default.
     ///
-    /// # Parameters
-    /// - `source`: A mutable reference to the source TCP stream.
+    /// Requests are only granted with the default connector, by the last link of a chain. Requests outside of
+    /// one up to the maximum are clamped to that range. The granted TTL is reported in a TTL stack option of the
+    /// success reply, so that sources learn whether, and how, their request was honored.
+    pub fn with_requested_ttl(
+        mut self,
+        max: u8,
+    ) -> Self {
+        self.max_requested_ttl = Some(max).filter(|&max| max > 0);
+        self
+    }
+
+    /// Sets how long an outbound connect may take, including the resolution of the destination and every
+    /// connection attempt, before it is given up. Defaults to `DEFAULT_CONNECT_TIMEOUT`.
     ///
-    /// # Returns
-    /// An `Ok(())` if the source is successfully notified of the refusal, otherwise an error.
-    async fn refuse_request(
+    /// This applies both to destinations and to the next proxy in a chain.
+    pub fn with_connect_timeout(
+        mut self,
+        timeout: Duration,
+    ) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Sets the resolver used for domain name destinations, e.g., a `CachingResolver`.
+    pub fn with_resolver(
+        mut self,
+        resolver: Arc<dyn Resolver + Send + Sync>,
+    ) -> Self {
+        self.dialer.set_resolver(resolver);
+        self
+    }
+
+    /// Sets where domain name destinations are resolved: at this hop, by the next link of a chain, or by the
+    /// custom connector (see `ResolutionPolicy`). Defaults to `ResolutionPolicy::PassThroughWhenChaining`.
+    pub fn with_resolution_policy(
+        mut self,
+        resolution: ResolutionPolicy,
+    ) -> Self {
+        self.resolution = resolution;
+        self
+    }
+
+    /// Sets the connector used for outbound connections, instead of dialing them over TCP.
+    ///
+    /// The family preference and resolver of the handler only apply to its default connector, unless the
+    /// resolution policy resolves names before they are handed to the connector or the next link.
+    pub fn with_connector(
+        mut self,
+        connector: Arc<dyn Connector + Send + Sync>,
+    ) -> Self {
+        self.connector = Some(connector);
+        self
+    }
+
+    /// Sets the guard that decides which destinations may be connected to, e.g., `DestinationGuard::host_local`
+    /// to keep sources from reaching the proxy's host. By default, every destination is allowed.
+    ///
+    /// Requests for blocked destinations are refused with a ConnectionNotAllowed reply. Domain names are
+    /// checked each time the default connector resolves them, right before connecting to the result. Custom
+    /// connectors resolve out of the handler's sight, so only the destination as requested is checked for them,
+    /// unless the guard resolves it locally.
+    ///
+    /// The guard applies to the next proxy in a chain as well, which has to be allowed if it runs on the same host.
+    pub fn with_destination_guard(
+        mut self,
+        guard: DestinationGuard,
+    ) -> Self {
+        self.dialer.set_guard(guard.clone());
+        self.guard = guard;
+        self
+    }
+
+    /// Limits how long sessions may be relayed, and how many bytes they may relay, after which they are closed.
+    ///
+    /// The hooks can adjust the limits for each session, e.g., by identity. Sessions handed over to the
+    /// fallback handler are subject to these limits, but not to the hooks.
+    pub fn with_session_limits(
+        mut self,
+        limits: SessionLimits,
+    ) -> Self {
+        self.session_limits = limits;
+        self
+    }
+
+    /// Sets the policy that decides which sessions are allowed, by default one that allows all. Requests it
+    /// denies are refused with a ConnectionNotAllowed reply.
+    ///
+    /// The policy can be replaced with `update_policy` while the handler serves sessions.
+    pub fn with_policy(
+        mut self,
+        policy: Policy,
+    ) -> Self {
+        self.policy = SharedPolicy::new(policy);
+        self
+    }
+
+    /// Sets whether established sessions are re-evaluated when the policy is updated, closing the ones it no
+    /// longer allows. By default, established sessions keep the decision they were accepted with.
+    pub fn with_policy_reevaluation(
+        mut self,
+        reevaluate: bool,
+    ) -> Self {
+        self.reevaluate_policy = reevaluate;
+        self
+    }
+
+    /// Allows destinations that are Unix socket paths, which are a socksx extension (see `SOCKS_ATYP_UNIX`).
+    ///
+    /// By default, requests for them are refused with an AddressTypeNotSupported reply, like requests with any
+    /// other unknown address type. The connector has to support them: the default one connects to the path.
+    pub fn with_unix_destinations(
+        mut self,
+        allow: bool,
+    ) -> Self {
+        self.allow_unix_destinations = allow;
+        self
+    }
+
+    /// Sets the hooks that decide on each session individually, e.g., whether it is mirrored.
+    ///
+    /// Sessions handed over to the fallback handler are subject to the hooks of that handler.
+    pub fn with_hooks(
+        mut self,
+        hooks: Arc<dyn SessionHooks + Send + Sync>,
+    ) -> Self {
+        self.hooks = Some(hooks);
+        self
+    }
+
+    /// Hands connections from clients that speak another SOCKS version to the given handler.
+    ///
+    /// This allows a `Socks5Handler` to serve SOCKS5 clients on the same port. Without a fallback,
+    /// such clients get a version mismatch reply, and `setup` fails with `SocksError::VersionMismatch`.
+    pub fn with_fallback(
+        mut self,
+        fallback: Arc<dyn SocksHandler + Send + Sync>,
+    ) -> Self {
+        self.fallback = Some(fallback);
+        self
+    }
+
+    /// Requires clients to authenticate with the given username and password.
+    ///
+    /// This is a shorthand for `with_authenticator` with a `StaticUserPass` authenticator.
+    pub fn with_credentials(
+        self,
+        credentials: Credentials,
+    ) -> Self {
+        self.with_authenticator(Arc::new(StaticUserPass::new(credentials)))
+    }
+
+    /// Sets the authenticator that decides whether clients are authenticated, instead of `NoAuth`.
+    pub fn with_authenticator(
+        mut self,
+        authenticator: Arc<dyn Authenticator + Send + Sync>,
+    ) -> Self {
+        self.authenticator = authenticator;
+        self
+    }
+
+    /// Sets how many challenges the authenticator may send before the source is rejected, which keeps an
+    /// authenticator from looping forever. Defaults to `DEFAULT_MAX_AUTH_ROUNDS`.
+    pub fn with_max_auth_rounds(
+        mut self,
+        rounds: usize,
+    ) -> Self {
+        self.max_auth_rounds = rounds;
+        self
+    }
+
+    /// Sets whether sources whose authentication is rejected are told why, when the authenticator gives a reason
+    /// with `AuthOutcome::RejectWith` (see `auth_failure_option`). Disabled by default, as the reason tells a
+    /// source probing for accounts more than it needs to know, e.g., whether an account exists.
+    pub fn with_auth_failure_reasons(
+        mut self,
+        enabled: bool,
+    ) -> Self {
+        self.auth_failure_reasons = enabled;
+        self
+    }
+
+    /// Sets the policy requests are validated against before they are acted upon.
+    ///
+    /// Rejected requests get a general failure reply. Defaults to `ValidationPolicy::default()`.
+    pub fn with_validation(
+        mut self,
+        validation: ValidationPolicy,
+    ) -> Self {
+        self.validation = validation;
+        self
+    }
+
+    /// Sets the revision of the SOCKS6 draft that is spoken with clients and next links, instead of `Draft11`.
+    ///
+    /// Clients that send the version byte of another revision get a version mismatch reply.
+    pub fn with_draft(
+        mut self,
+        draft: Socks6Draft,
+    ) -> Self {
+        self.draft = draft;
+        self
+    }
+
+    /// Sets how strictly requests are parsed, instead of leniently.
+    ///
+    /// Requests that a strict handler rejects get a general failure reply, and the deviations that a lenient
+    /// handler accepts are logged and available through `Socks6Request::diagnostics`.
+    pub fn with_parse_mode(
+        mut self,
+        mode: ParseMode,
+    ) -> Self {
+        self.parse_mode = mode;
+        self
+    }
+
+    /// Retains the raw options block of every request, so options are forwarded byte for byte to the next link.
+    ///
+    /// Without this, forwarded options are serialized again, and no copy of the options block is kept.
+    pub fn with_raw_options(
+        mut self,
+        retain: bool,
+    ) -> Self {
+        self.retain_raw_options = retain;
+        self
+    }
+
+    /// Sets the kinds of unrecognized options that are never passed on between the previous and next link.
+    ///
+    /// Defaults to the authentication option kinds of every supported draft revision, so credentials meant
+    /// for this proxy don't leak to the next one.
+    pub fn with_forward_denylist(
+        mut self,
+        kinds: Vec<u16>,
+    ) -> Self {
+        self.forward_denylist = kinds;
+        self
+    }
+
+    /// Sends the connection ID of each session to the next link of a chain as metadata, so the logs of
+    /// both links can be correlated.
+    ///
+    /// A connection ID sent by the source is adopted and forwarded instead, if enabled. Otherwise, it is ignored
+    /// and stripped from the forwarded options, so sources can't forge the IDs in the logs of the links.
+    pub fn with_connection_id_metadata(
+        mut self,
+        enabled: bool,
+    ) -> Self {
+        self.connection_id_metadata = enabled;
+        self
+    }
+
+    /// Sends the metadata to the next link of a chain packed, in as few options as fit, instead of in an option
+    /// per entry.
+    ///
+    /// Packed metadata is a socksx extension, so only enable this if every next link is a `Socks6Handler`.
+    /// Handlers decode packed metadata from their sources regardless.
+    pub fn with_packed_metadata(
+        mut self,
+        enabled: bool,
+    ) -> Self {
+        self.packed_metadata = enabled;
+        self
+    }
+
+    /// Advertises support for compressed metadata in the authentication reply to sources that advertise it as
+    /// well, e.g., a `Socks6Client` with metadata compression, which compress the metadata of their following
+    /// requests in turn.
+    ///
+    /// Handlers decode compressed metadata regardless, up to `options::MAX_INFLATED_METADATA_LEN` bytes per
+    /// request. The advertisement of the source isn't passed on to the next link. Instead, the handler advertises
+    /// support to the next link itself, and compresses the metadata it sends there once the next link advertised
+    /// support in turn.
+    pub fn with_metadata_compression(
+        mut self,
+        enabled: bool,
+    ) -> Self {
+        self.metadata_compression = enabled;
+        self
+    }
+
+    /// Reports the number of links a request traversed in the success reply, when this handler is the last
+    /// link of the chain. The links before it relay the report to the source.
+    pub fn with_hop_count(
+        mut self,
+        enabled: bool,
+    ) -> Self {
+        self.hop_count = enabled;
+        self
+    }
+
+    /// Sets the kinds of options of the next link's success reply that are passed on to the source, e.g., the
+    /// unrecognized kinds of session grants, or `SocksOptionKind::Metadata`.
+    ///
+    /// By default, the options that would be forwarded in a request are passed on: the metadata, and unrecognized
+    /// options that aren't denied. Authentication options are never passed on, whatever their kind.
+    pub fn with_reply_passthrough(
+        mut self,
+        kinds: Vec<SocksOptionKind>,
+    ) -> Self {
+        self.reply_passthrough = Some(kinds);
+        self
+    }
+
+    /// Sets the address that is reported as the binding in the success reply, which is unspecified by default.
+    pub fn with_reply_binding(
+        mut self,
+        binding: ReplyBinding,
+    ) -> Self {
+        self.reply_binding = binding;
+        self
+    }
+
+    /// Sets whether initial data is accepted, which it is by default. Sources can send data along with their
+    /// request, which reaches the destination before the operation reply and before the source learns whether
+    /// its request was allowed. Disable this where data must not pass before the request is evaluated.
+    ///
+    /// Without initial data, the authentication reply reports that none is accepted (see `initial_data_option`),
+    /// and whatever initial data a source sends anyway is left unread, so that it follows the operation reply like
+    /// the data of the tunnel.
+    pub fn with_initial_data(
+        mut self,
+        enabled: bool,
+    ) -> Self {
+        self.initial_data = enabled;
+        self
+    }
+
+    /// Sets the metrics that the handshakes, replies, and relayed bytes of the sessions are counted in.
+    pub fn with_metrics(
+        mut self,
+        metrics: Arc<dyn Metrics + Send + Sync>,
+    ) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    // Returns the recorder for the measurements of the sessions.
+    fn recorder(&self) -> Recorder<'_> {
+        Recorder::handler(self.metrics.as_ref(), "6")
+    }
+
+    // Authenticates the source, driving the authenticator through as many rounds as it needs. Returns the
+    // identity, the options of the successful authentication reply, and the initial data if it had to be read
+    // before a round, as it precedes the source's answer on the stream.
+    async fn authenticate<S>(
+        &self,
+        source: &mut S,
+        request: &Socks6Request,
+        id: ConnectionId,
+        charge: &mut Charge,
+    ) -> Result<(Identity, Vec<SocksOption>, Option<Vec<u8>>)>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let methods: Vec<AuthMethod> = request
+            .options
+            .iter()
+            .filter_map(|option| match option {
+                SocksOption::AuthMethodAdvertisement(advertisement) => Some(advertisement.methods.clone()),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        let mut data = auth_data(&request.options);
+        let mut challenge: Option<Vec<u8>> = None;
+        let mut initial_data = None;
+
+        for round in 0.. {
+            let auth_request = AuthRequest {
+                methods: &methods,
+                data: &data,
+                challenge: challenge.as_deref(),
+                round,
+            };
+            let outcome = match self.authenticator.authenticate(auth_request).await {
+                AuthOutcome::Continue(..) if round == self.max_auth_rounds => {
+                    AuthOutcome::Reject(format!("No decision after {} rounds", round + 1))
+                }
+                outcome => outcome,
+            };
+
+            let (reason, message) = match outcome {
+                AuthOutcome::Accept(identity, method) => {
+                    debug!("[{}] Authenticated as {:?} with {:?}.", id, identity.name(), method);
+                    let mut options = match method {
+                        AuthMethod::NoAuthentication => vec![],
+                        method => vec![AuthMethodSelectionOption::new(method).wrap()],
+                    };
+                    if !self.initial_data {
+                        options.push(socks6::initial_data_option(0));
+                    }
+                    if self.metadata_compression && socks6::supports_metadata_compression(&request.options) {
+                        options.push(socks6::metadata_compression_option());
+                    }
+
+                    return Ok((identity, options, initial_data));
+                }
+                AuthOutcome::Reject(message) => (None, message),
+                AuthOutcome::RejectWith(reason, message) => (Some(reason), message),
+                AuthOutcome::Continue(method, next) => {
+                    if initial_data.is_none() && request.initial_data_length > 0 {
+                        let mut buffer = vec![0; request.initial_data_length as usize];
+                        source.read_exact(&mut buffer).await?;
+                        initial_data = Some(buffer);
+                    }
+
+                    // The challenge goes out in a failed reply that selects the method, answered by a block of
+                    // options with the method-specific data of the next round.
+                    let options = vec![
+                        AuthMethodSelectionOption::new(method.clone()).wrap(),
+                        AuthDataOption::new(method, next.clone()).wrap(),
+                    ];
+                    let mut reply = vec![];
+                    wire::encode_socks6_auth_reply_for(SOCKS_AUTH_FAILED, &options, self.draft, &mut reply);
+                    source.write_all(&reply).await?;
+                    source.flush().await?;
+
+                    let parse = |bytes: &[u8]| wire::parse_options_with(bytes, self.draft, self.parse_mode);
+                    let (answer, _) = wire::read_message_charged(source, &mut BytesMut::new(), parse, charge).await?;
+                    data = auth_data(&answer);
+                    challenge = Some(next);
+                    continue;
+                }
+            };
+
+            debug!("[{}] Authentication failed ({:?}): {}", id, reason, message);
+            let selected = self
+                .authenticator
+                .methods()
+                .into_iter()
+                .find(|method| auth_request.offers(method))
+                .unwrap_or(AuthMethod::NoAcceptableMethods);
+
+            let mut options = vec![AuthMethodSelectionOption::new(selected).wrap()];
+            if let Some(reason) = reason.filter(|_| self.auth_failure_reasons) {
+                options.push(socks6::auth_failure_option(reason));
+            }
+            let unread = if initial_data.is_some() { 0 } else { request.initial_data_length };
+            refuse_authentication(source, self.draft, &options, unread).await?;
+            bail!(SocksError::AuthenticationFailed(reason));
+        }
+
+        unreachable!("Authentication ends within the maximum number of rounds")
+    }
+
+    // Writes the reply that best describes why the request failed, with an unspecified bound address.
+    async fn write_failure_reply(
         &self,
-        source: &mut TcpStream,
+        replies: &mut Vec<u8>,
+        error: &anyhow::Error,
     ) -> Result<()> {
-        // Notify source that the connection is refused.
-        socks6::write_reply(source, Socks6Reply::ConnectionRefused).await?;
+        let binding = Address::new("0.0.0.0", 0);
+        let options = match error.downcast_ref::<SocksError>() {
+            Some(SocksError::ChainFailed(failure)) => failure.as_options(),
+            _ => vec![],
+        };
+        let reply = Socks6Reply::from_error(error);
+        self.recorder().reply_sent(reply.clone() as u8);
+        socks6::write_reply_for(replies, self.draft, reply, &binding, &options).await
+    }
 
-        Ok(())
+    // Returns whether the option is passed on unmodified between the previous and next link: the metadata,
+    // baggage included, except for the chain, which is emitted again with the next index, and the connection ID
+    // unless connection ID metadata is enabled, and unrecognized options that aren't denied, except for the
+    // advertisement of metadata compression and the method-specific data, which are per link.
+    fn is_forwarded(
+        &self,
+        option: &SocksOption,
+    ) -> bool {
+        match option {
+            SocksOption::Metadata(metadata) => {
+                metadata.key < 998 && (metadata.key != CONNECTION_ID_METADATA_KEY || self.connection_id_metadata)
+            }
+            SocksOption::Unrecognized(option) => {
+                !matches!(option.kind(), SOCKS_OKIND_METADATA_COMPRESSION | SOCKS_OKIND_AUTH_METHOD_DATA)
+                    && !self.forward_denylist.contains(&option.kind())
+            }
+            _ => false,
+        }
     }
 
-    /// Sets up the connection to the destination.
+    // Returns the options of the next link's reply that are relayed to the source: those of the passthrough kinds,
+    // or the ones that would be forwarded without passthrough kinds, but never authentication or malformed options.
+    fn relayed_options(
+        &self,
+        options: Vec<SocksOption>,
+    ) -> Vec<SocksOption> {
+        options
+            .into_iter()
+            .filter(|option| !is_auth_option(option) && !matches!(option, SocksOption::Malformed(_)))
+            .filter(|option| match &self.reply_passthrough {
+                Some(kinds) => kinds.contains(&option.kind_for(self.draft)),
+                None => self.is_forwarded(option),
+            })
+            .collect()
+    }
+
+    // Returns the encoded options of the request that are forwarded unmodified to the next link. Compressed metadata
+    // is decoded per link, so it's always encoded again, and only compressed if the next link supports it.
+    fn forwarded_options(
+        &self,
+        request: &Socks6Request,
+        compress: bool,
+    ) -> Vec<u8> {
+        let forwarded = |option: &SocksOption| self.is_forwarded(option);
+
+        let mut bytes = vec![];
+        if self.packed_metadata {
+            // Packing re-encodes the metadata, sorted by key, after the other options.
+            let mut metadata = vec![];
+            for option in request.options.iter().filter(|option| forwarded(option)) {
+                match option {
+                    SocksOption::Metadata(m) => metadata.push(m.clone()),
+                    _ => option.write_socks_bytes_for(self.draft, &mut bytes),
+                }
+            }
+            metadata.sort_by_key(|m| m.key);
+            if compress {
+                options::write_compressed_metadata(&metadata, &mut bytes);
+            } else {
+                options::write_packed_metadata(&metadata, &mut bytes);
+            }
+
+            return bytes;
+        }
+
+        match request.raw_option_pairs() {
+            Some(pairs) => {
+                for (option, raw) in pairs.into_iter().filter(|(option, _)| forwarded(option)) {
+                    if options::is_compressed_packed_metadata(raw) {
+                        option.write_socks_bytes_for(self.draft, &mut bytes);
+                    } else {
+                        bytes.extend_from_slice(raw);
+                    }
+                }
+            }
+            None => {
+                for option in request.options.iter().filter(|option| forwarded(option)) {
+                    option.write_socks_bytes_for(self.draft, &mut bytes);
+                }
+            }
+        }
+
+        bytes
+    }
+
+    // Returns whether the next link advertised support for compressed metadata, shared by the clients for it.
+    fn next_link_compression(
+        &self,
+        next: &ProxyAddress,
+    ) -> Arc<AtomicBool> {
+        let mut links = self.next_link_compression.lock().unwrap();
+        links.entry(next.to_string()).or_default().clone()
+    }
+
+    // Returns the connector used for outbound connections.
+    fn connector(&self) -> &(dyn Connector + Send + Sync) {
+        match &self.connector {
+            Some(connector) => connector.as_ref(),
+            None => &self.dialer,
+        }
+    }
+
+    // Returns the destination as it is handed on, resolved at this hop or not according to the resolution policy.
+    async fn outbound_destination(
+        &self,
+        destination: &Address,
+        upstream: bool,
+    ) -> Result<Address> {
+        self.resolution.destination_for(destination, upstream, &self.dialer, self.connect_timeout).await
+    }
+
+    // Connects to a destination or the next link with the connector, within the connect timeout, if the guard
+    // allows it. The default connector connects with the TTL, if any, instead of its own.
+    async fn connect_outbound(
+        &self,
+        address: &Address,
+        ttl: Option<u8>,
+    ) -> Result<(Box<dyn AsyncStream>, ConnectionAddrs)> {
+        if self.connector.is_some() {
+            let address = self.guard.check(address).await?;
+            return dialer::connect_within(self.connector(), &address, self.connect_timeout).await;
+        }
+
+        dialer::connect_within(self.dialer_with_ttl(ttl).as_ref(), address, self.connect_timeout).await
+    }
+
+    // Returns the default connector, which connects with the TTL, if any, instead of its own.
+    fn dialer_with_ttl(
+        &self,
+        ttl: Option<u8>,
+    ) -> Cow<'_, Dialer> {
+        let Some(ttl) = ttl else {
+            return Cow::Borrowed(&self.dialer);
+        };
+        let mut dialer = self.dialer.clone();
+        dialer.set_ttl(Some(ttl));
+
+        Cow::Owned(dialer)
+    }
+
+    // Returns the TTL granted to the request, if it asks for one for the connection to its destination and the
+    // handler allows it, clamped to the range the handler allows.
+    fn granted_ttl(
+        &self,
+        request: &Socks6Request,
+    ) -> Option<u8> {
+        let max = self.max_requested_ttl.filter(|_| self.connector.is_none())?;
+        let requested = request.options.ttl().filter(|option| option.leg.includes_remote())?;
+        let granted = requested.ttl.clamp(1, max);
+        if granted != requested.ttl {
+            debug!("Clamped the requested TTL of {} to {}.", requested.ttl, granted);
+        }
+
+        Some(granted)
+    }
+
+    /// Connects to the destination of the request, either directly or through the next link in the chain.
+    ///
+    /// Along with the stream, the options of the next link's reply that are relayed to the source are returned,
+    /// the binding the next link reported, and the addresses of the connection to the destination or the next
+    /// link. Within a chain, a failure is reported as `SocksError::ChainFailed`, by this link unless a later link
+    /// reported it already. Whether a domain name destination is resolved at this link is up to the resolution
+    /// policy.
+    async fn connect(
+        &self,
+        request: &Socks6Request,
+        id: ConnectionId,
+    ) -> Result<(Box<dyn AsyncStream>, Vec<SocksOption>, Option<Address>, ConnectionAddrs)> {
+        let destination = request.destination.clone();
+        let chain = request.chain(&self.static_links)?;
+        let custom = self.connector.is_some();
+
+        let mut chain = match chain {
+            Some(chain) => chain,
+            None => {
+                let ttl = self.granted_ttl(request);
+                let destination = self.outbound_destination(&destination, custom).await?;
+                let (stream, addrs) = self.connect_outbound(&destination, ttl).await?;
+                return Ok((stream, ttl_options(ttl), None, addrs));
+            }
+        };
+
+        let hop = chain.index;
+        let next = match chain.next_link() {
+            Some(next) => next.clone(),
+            None => {
+                let ttl = self.granted_ttl(request);
+                let connected = async {
+                    let destination = self.outbound_destination(&destination, custom).await?;
+                    self.connect_outbound(&destination, ttl).await
+                };
+                let (destination, addrs) = match connected.await {
+                    Ok(connected) => connected,
+                    Err(e) => return Err(report_failure(e, hop, destination.to_string())),
+                };
+                let mut options = ttl_options(ttl);
+                if self.hop_count {
+                    options.push(chain::hops_option(hop + 1));
+                }
+
+                return Ok((destination, options, None, addrs));
+            }
+        };
+
+        let destination = match self.outbound_destination(&destination, true).await {
+            Ok(destination) => destination,
+            Err(e) => return Err(report_failure(e, hop, destination.to_string())),
+        };
+        let connected: Result<_> = async {
+            let (mut proxy, addrs) = self.connect_outbound(&Address::try_from(&next)?, None).await?;
+
+            // A connection ID from the source is among the forwarded options already.
+            let sent_id = request.metadata.contains_key(&CONNECTION_ID_METADATA_KEY);
+            let propagate_id = self.connection_id_metadata && !sent_id;
+            // Whether the next link supports compressed metadata is learned from its first handshake.
+            let compression = self.next_link_compression(&next);
+            let compress = self.metadata_compression && compression.load(Ordering::Relaxed);
+            let client = Socks6Client::for_hop(next.clone())?
+                .with_draft(self.draft)
+                .with_raw_default_options(self.forwarded_options(request, compress))
+                .with_connection_id(id)
+                .with_connection_id_metadata(propagate_id)
+                .with_packed_metadata(self.packed_metadata)
+                .with_metadata_compression(self.metadata_compression)
+                .with_metadata_compression_supported(compression);
+
+            let (binding, options, _) = client
+                .handshake_with_reply(destination, None, Some(chain.as_options()), &mut proxy)
+                .await?;
+
+            Ok((proxy, self.relayed_options(options), Some(binding), addrs))
+        }
+        .await;
+
+        connected.map_err(|e| report_failure(e, hop, next.to_string()))
+    }
+
+    /// Reads a request from the source and authenticates it, leaving it to the caller what to do with it.
+    ///
+    /// Requests that can't be acted upon, e.g., for their command, are refused before they are returned.
+    /// Clients of another SOCKS version get a version mismatch reply, as the fallback handler is only
+    /// used by `accept_request` and `setup`.
     ///
     /// # Parameters
-    /// - `source`: A mutable reference to the source TCP stream.
+    /// - `source`: A mutable reference to the source stream.
     ///
     /// # Returns
-    /// A `Result` containing the destination `TcpStream` if successful, otherwise an error.
-    async fn setup(
-        &self,
-        source: &mut TcpStream,
-    ) -> Result<TcpStream> {
-        // Receive SOCKS request, and allow unauthenticated access.
-        let request = socks6::read_request(source).await?;
-        socks6::write_no_authentication(source).await?;
+    /// A `Result` containing the pending session, which decides the fate of the request, and the request itself.
+    pub async fn accept<'a>(
+        &'a self,
+        source: &'a mut dyn AsyncStream,
+    ) -> Result<(PendingSession<'a>, Socks6Request)> {
+        let mut id = ConnectionId::generate();
+        match self.read_session(source, &mut id, false, None).await.map_err(|e| id.attach(e))? {
+            Accepted::Pending(session) => {
+                let request = session.request.clone();
+                Ok((*session, request))
+            }
+            Accepted::Fallback(_) => unreachable!("The fallback handler is not used by accept"),
+        }
+    }
 
-        let destination = request.destination.to_string();
-        let chain = request.chain(&self.static_links)?;
+    // Reads and authenticates a request, or hands the source to the fallback handler if allowed. The
+    // connection ID is replaced by the one the source sent along, if any. A source that doesn't authenticate
+    // keeps the identity its transport authenticated it as, if any.
+    async fn read_session<'a>(
+        &'a self,
+        source: &'a mut dyn AsyncStream,
+        id: &mut ConnectionId,
+        use_fallback: bool,
+        transport_identity: Option<String>,
+    ) -> Result<Accepted<'a>> {
+        let recorder = self.recorder();
+        recorder.started();
+        let accepted = self.receive_session(source, id, use_fallback, transport_identity);
+        accepted.await.inspect_err(|e| recorder.failed(e))
+    }
 
-        let mut destination = if let Some(mut chain) = chain {
-            if let Some(next) = chain.next_link() {
-                let next = next.clone();
+    // Reads a session like `read_session`, without measuring it.
+    async fn receive_session<'a>(
+        &'a self,
+        source: &'a mut dyn AsyncStream,
+        id: &mut ConnectionId,
+        use_fallback: bool,
+        transport_identity: Option<String>,
+    ) -> Result<Accepted<'a>> {
+        // Receive SOCKS request. The authentication reply is buffered, so that it leaves together
+        // with the operation reply in a single write.
+        let mut reader = util::handshake_reader(source);
+        let mut messages = MessageReader::charged(Charge::current());
+        let request = socks6::read_request_with(
+            &mut reader,
+            &mut messages,
+            self.draft,
+            self.parse_mode,
+            self.retain_raw_options,
+        );
+        let request = request.await.and_then(|request| {
+            // Only CONNECT is implemented, other commands are refused like unknown ones.
+            if request.command != Socks6Command::Connect {
+                bail!(SocksError::CommandNotSupported(request.command.clone() as u8));
+            }
+            if matches!(request.destination, Address::Unix(_)) && !self.allow_unix_destinations {
+                bail!(SocksError::UnsupportedAddressType(SOCKS_ATYP_UNIX));
+            }
+            request.validate(self.validation)?;
 
-                let proxy_addr = format!("{}:{}", next.host, next.port);
-                let client = Socks6Client::new(proxy_addr, next.credentials).await?;
+            // The initial data is charged along with the request, so that a source whose initial data exceeds
+            // the budget is refused before it is authenticated.
+            messages.charge(request.initial_data_length as usize)?;
+            Ok(request)
+        });
 
-                let (outgoing, _) = client.connect(destination, None, Some(chain.as_options())).await?;
-                outgoing
-            } else {
-                TcpStream::connect(destination).await?
+        let request = match request {
+            Ok(request) => request,
+            Err(e) => {
+                match e.downcast_ref::<SocksError>() {
+                    Some(SocksError::VersionMismatch(version)) => {
+                        if let Some(fallback) = self.fallback.as_ref().filter(|_| use_fallback) {
+                            debug!("[{}] Handing a SOCKS{} client over to the fallback handler.", id, version);
+
+                            // Replay the bytes that were read already, including the version byte.
+                            let mut prefix = messages.buffered().to_vec();
+                            prefix.extend_from_slice(reader.buffer());
+                            let mut source = util::Rewound::new(prefix, reader.into_inner());
+
+                            let mut destination = fallback.setup(&mut source).await?;
+                            destination.write_all(source.remaining()).await?;
+                            return Ok(Accepted::Fallback(destination));
+                        }
+
+                        let mut reply = vec![];
+                        wire::encode_socks6_version_mismatch_for(self.draft, &mut reply);
+                        reader.into_inner().write_all(&reply).await?;
+                    }
+                    // Let the source know if the request was refused for its command, address type, or validity,
+                    // without an authentication reply, as the source wasn't authenticated.
+                    _ if is_refusal(&e) => {
+                        let mut replies = vec![];
+                        self.write_failure_reply(&mut replies, &e).await?;
+                        reader.into_inner().write_all(&replies).await?;
+                    }
+                    _ => {}
+                }
+                return Err(e);
             }
-        } else {
-            TcpStream::connect(destination).await?
         };
 
-        // Send initial data
-        if request.initial_data_length > 0 {
-            let mut initial_data = vec![0; request.initial_data_length as usize];
-            source.read_exact(&mut initial_data).await?;
-            destination.write(&initial_data).await?;
+        if self.connection_id_metadata {
+            if let Some(adopted) = request.metadata.get(&CONNECTION_ID_METADATA_KEY).and_then(|v| v.parse().ok()) {
+                *id = adopted;
+            }
+        }
+        debug!("[{}] Received a request for {}.", id, request.destination);
+        for diagnostic in request.diagnostics() {
+            debug!("[{}] Accepted a request that deviates from the draft: {}.", id, diagnostic);
         }
 
-        // Notify source that the connection has been set up.
-        socks6::write_reply(source, Socks6Reply::Success).await?;
-        source.flush().await?;
+        // Decide on authentication first, so the initial data of a rejected source is never read, unless
+        // the authenticator needed more than one round.
+        let mut charge = messages.into_charge();
+        let mut replies = vec![];
+        let (identity, options, initial_data) = self.authenticate(&mut reader, &request, *id, &mut charge).await?;
+        let identity = match transport_identity {
+            Some(name) if identity.name().is_none() => Identity::new(name),
+            _ => identity,
+        };
+        wire::encode_socks6_auth_reply_for(SOCKS_AUTH_SUCCESS, &options, self.draft, &mut replies);
+
+        let session = PendingSession {
+            handler: self,
+            reader,
+            request,
+            id: *id,
+            identity,
+            initial_data,
+            replies,
+            source_addrs: ConnectionAddrs::default(),
+            _charge: charge,
+        };
+        if !self.policy.current().allows(&session.info()) {
+            let destination = session.request.destination.to_string();
+            session.write_rejection(Socks6Reply::ConnectionNotAllowed).await?;
+            bail!(SocksError::ConnectionNotAllowed(destination));
+        }
+
+        Ok(Accepted::Pending(Box::new(session)))
+    }
+}
+
+/// A SOCKS6 request that was accepted by `Socks6Handler::accept`, but not acted upon yet.
+///
+/// The source is authenticated, and waits for the operation reply, which is sent once the session is
+/// rejected or proxied.
+pub struct PendingSession<'a> {
+    handler: &'a Socks6Handler,
+    reader: BufReader<&'a mut dyn AsyncStream>,
+    request: Socks6Request,
+    id: ConnectionId,
+    identity: Identity,
+    initial_data: Option<Vec<u8>>,
+    replies: Vec<u8>,
+    source_addrs: ConnectionAddrs,
+    // Returns the buffered bytes of the handshake to the memory budget once the session is proxied or rejected.
+    _charge: Charge,
+}
+
+impl<'a> PendingSession<'a> {
+    /// Returns the connection ID of the session.
+    pub fn id(&self) -> ConnectionId {
+        self.id
+    }
+
+    /// Sets the addresses of the connection of the source, for the `TunnelInfo` the hooks receive.
+    pub fn with_source_addrs(
+        mut self,
+        addrs: ConnectionAddrs,
+    ) -> Self {
+        self.source_addrs = addrs;
+        self
+    }
+
+    /// Returns the identity the source authenticated as.
+    pub fn identity(&self) -> &Identity {
+        &self.identity
+    }
+
+    /// Refuses the request with the given reply, e.g., `Socks6Reply::ConnectionNotAllowed`.
+    pub async fn reject(
+        self,
+        reply: Socks6Reply,
+    ) -> Result<()> {
+        self.handler.recorder().rejected();
+        self.write_rejection(reply).await
+    }
+
+    // Writes the reply that refuses the request.
+    async fn write_rejection(
+        mut self,
+        reply: Socks6Reply,
+    ) -> Result<()> {
+        let id = self.id;
+        debug!("[{}] Rejecting the request with {:?}.", id, reply);
+        self.handler.recorder().reply_sent(reply.clone() as u8);
+
+        let result: Result<()> = async move {
+            let binding = Address::new("0.0.0.0", 0);
+            socks6::write_reply_for(&mut self.replies, self.handler.draft, reply, &binding, &[]).await?;
+            self.reader.into_inner().write_all(&self.replies).await?;
+            Ok(())
+        }
+        .await;
+
+        result.map_err(|e| id.attach(e))
+    }
+
+    /// Leaves the request to the caller, which sends the operation reply and serves the source itself. The
+    /// authentication reply, which the handler holds back until the operation reply, is sent first.
+    ///
+    /// # Returns
+    /// A `Result` containing the bytes the source sent after its request, which the handler read already and
+    /// which come before anything read from the source from now on. Initial data the source sent along with its
+    /// request is among them, or follows them.
+    pub async fn take_over(self) -> Result<Vec<u8>> {
+        let id = self.id;
+        debug!("[{}] Handing the session over.", id);
+
+        let PendingSession {
+            reader,
+            initial_data,
+            replies,
+            ..
+        } = self;
+        let mut read_ahead = initial_data.unwrap_or_default();
+        read_ahead.extend_from_slice(reader.buffer());
+
+        let result: Result<()> = async move {
+            let source = reader.into_inner();
+            source.write_all(&replies).await?;
+            source.flush().await?;
+            Ok(())
+        }
+        .await;
+        result.map_err(|e| id.attach(e))?;
+
+        Ok(read_ahead)
+    }
+
+    /// Connects to the destination of the request, directly or through the next link of the chain, and
+    /// relays between it and the source until either closes the connection.
+    ///
+    /// # Returns
+    /// A `Result` containing how the relay ended.
+    pub async fn proxy_to_destination(self) -> Result<CloseSummary> {
+        self.proxy(None).await
+    }
+
+    /// Relays between the source and the given stream, which the caller connected to the destination, until
+    /// either closes the connection.
+    ///
+    /// # Returns
+    /// A `Result` containing how the relay ended.
+    pub async fn proxy_via<S>(
+        self,
+        outbound: S,
+    ) -> Result<CloseSummary>
+    where
+        S: AsyncStream + 'static,
+    {
+        self.proxy(Some(Box::new(outbound))).await
+    }
+
+    // Establishes the session, and relays within the limits of the session, which is reported to the hooks.
+    async fn proxy(
+        self,
+        outbound: Option<Box<dyn AsyncStream>>,
+    ) -> Result<CloseSummary> {
+        let id = self.id;
+        let handler = self.handler;
+        let session = self.info();
+        let limits = match &handler.hooks {
+            Some(hooks) => hooks.limits(&session, handler.session_limits),
+            None => handler.session_limits,
+        };
+
+        let (source, mut destination, tunnel) = self.establish(outbound).await.map_err(|e| id.attach(e))?;
+        if let Some(hooks) = &handler.hooks {
+            hooks.on_established(&session, &tunnel);
+        }
+        let _listing = session::Listing::current(id, &tunnel);
+        let revoked = handler.policy.revoked(session.clone(), handler.reevaluate_policy);
+        let (summary, relayed) = session::relay(id, source, &mut destination, limits, revoked).await;
+        handler.recorder().closed(&summary);
+        if let Some(hooks) = &handler.hooks {
+            hooks.on_close(&session, &summary);
+        }
+        relayed?;
+
+        Ok(summary)
+    }
 
-        Ok(destination)
+    // Describes the session to the hooks of the handler.
+    fn info(&self) -> SessionInfo {
+        SessionInfo {
+            id: self.id,
+            destination: self.request.destination.clone(),
+            identity: self.identity.name().map(String::from),
+            baggage: Baggage::from_metadata(&self.request.metadata),
+        }
+    }
+
+    // Asks the hooks of the handler whether the session is mirrored.
+    fn mirror(&self) -> Option<Mirror> {
+        self.handler.hooks.as_ref()?.mirror(&self.info())
+    }
+
+    // Connects to the destination, unless a stream to it is given, and completes the handshake with the source.
+    // Returns the addresses of both legs along with the streams.
+    async fn establish(
+        self,
+        outbound: Option<Box<dyn AsyncStream>>,
+    ) -> Result<(&'a mut dyn AsyncStream, Box<dyn AsyncStream>, TunnelInfo)> {
+        let recorder = self.handler.recorder();
+        let established = self.complete(outbound).await;
+        match &established {
+            Ok(_) => recorder.succeeded(),
+            Err(e) => recorder.failed(e),
+        }
+
+        established
+    }
+
+    // Establishes the session like `establish`, without measuring the outcome of the handshake.
+    async fn complete(
+        self,
+        outbound: Option<Box<dyn AsyncStream>>,
+    ) -> Result<(&'a mut dyn AsyncStream, Box<dyn AsyncStream>, TunnelInfo)> {
+        let mirror = self.mirror();
+        let PendingSession {
+            handler,
+            mut reader,
+            request,
+            id,
+            initial_data,
+            mut replies,
+            source_addrs,
+            ..
+        } = self;
+
+        // Connect while the initial data arrives, unless it was read during authentication already. A failed
+        // connect doesn't wait for the initial data, which is left unread.
+        let (connected, initial_data) = {
+            let connecting = async {
+                match outbound {
+                    Some(outbound) => Ok((outbound, vec![], None, ConnectionAddrs::default())),
+                    None => handler.connect(&request, id).await,
+                }
+            };
+            let reading = async {
+                // The source was told that initial data is refused, so it's left unread, unless authentication had
+                // to read past it already.
+                if !handler.initial_data {
+                    return Ok(initial_data.unwrap_or_default());
+                }
+
+                read_initial_data(&mut reader, initial_data, request.initial_data_length).await
+            };
+            tokio::pin!(connecting, reading);
+
+            tokio::select! {
+                connected = &mut connecting => {
+                    let initial_data = if connected.is_ok() { reading.await? } else { vec![] };
+                    (connected, initial_data)
+                }
+                initial_data = &mut reading => (connecting.await, initial_data?),
+            }
+        };
+        let (mut destination, relayed, upstream_binding, destination_addrs) = match connected {
+            Ok(connected) => connected,
+            Err(e) => {
+                // Notify source why the connection could not be set up.
+                handler.write_failure_reply(&mut replies, &e).await?;
+                reader.into_inner().write_all(&replies).await?;
+                return Err(e);
+            }
+        };
+        if let Some(mirror) = mirror {
+            destination = Box::new(MirroredStream::new(destination, mirror.start(id)));
+        }
+
+        if !initial_data.is_empty() {
+            destination.write_all(&initial_data).await?;
+        }
+
+        // Bytes the client sent after the initial data belong to the destination as well.
+        let source = util::forward_read_ahead(reader, &mut destination).await?;
+
+        // Notify source that the connection has been set up. The bound address of the outgoing
+        // connection isn't known for every connector, so it's left unspecified unless the handler reports
+        // one. Options from the next link's reply are relayed.
+        let local_binding = destination_addrs.local.map(Address::Ip);
+        let binding = match handler.reply_binding {
+            ReplyBinding::Unspecified => None,
+            ReplyBinding::Local => local_binding,
+            ReplyBinding::Upstream => upstream_binding.or(local_binding),
+        };
+        let binding = binding.unwrap_or_else(|| Address::new("0.0.0.0", 0));
+        handler.recorder().reply_sent(Socks6Reply::Success as u8);
+        socks6::write_reply_for(&mut replies, handler.draft, Socks6Reply::Success, &binding, &relayed).await?;
+        source.write_all(&replies).await?;
+        source.flush().await?;
+
+        let tunnel = TunnelInfo {
+            peer: source_addrs.peer,
+            local: source_addrs.local,
+            requested: request.destination,
+            connected: destination_addrs.peer,
+            outbound_local: destination_addrs.local,
+            connect_info: destination_addrs.connect_info,
+        };
+
+        Ok((source, destination, tunnel))
+    }
+}
+
+// The outcome of reading a request: a pending session, or a source served by the fallback handler.
+enum Accepted<'a> {
+    Pending(Box<PendingSession<'a>>),
+    Fallback(Box<dyn AsyncStream>),
+}
+
+#[async_trait]
+impl SocksHandler for Socks6Handler {
+    /// Accepts a request from the source and sets up a tunnel to the destination.
+    ///
+    /// # Parameters
+    /// - `source`: A mutable reference to the source stream.
+    ///
+    /// # Returns
+    /// An `Ok(())` if the tunnel is successfully set up, otherwise an error.
+    async fn accept_request(
+        &self,
+        source: &mut dyn AsyncStream,
+    ) -> Result<()> {
+        self.accept_request_from(source, None, ConnectionAddrs::default()).await
+    }
+
+    /// Accepts a request from a source that its transport authenticated already, like `accept_request`.
+    ///
+    /// # Parameters
+    /// - `source`: A mutable reference to the source stream.
+    /// - `identity`: The identity of the source, unless it authenticates within SOCKS as well.
+    ///
+    /// # Returns
+    /// An `Ok(())` if the tunnel is successfully set up, otherwise an error.
+    async fn accept_request_as(
+        &self,
+        source: &mut dyn AsyncStream,
+        identity: Option<String>,
+    ) -> Result<()> {
+        self.accept_request_from(source, identity, ConnectionAddrs::default()).await
+    }
+
+    /// Accepts a request like `accept_request_as`, from a source connected over the given addresses.
+    ///
+    /// # Parameters
+    /// - `source`: A mutable reference to the source stream.
+    /// - `identity`: The identity of the source, unless it authenticates within SOCKS as well.
+    /// - `addrs`: The addresses of the connection of the source, for the `TunnelInfo` of the session.
+    ///
+    /// # Returns
+    /// An `Ok(())` if the tunnel is successfully set up, otherwise an error.
+    async fn accept_request_from(
+        &self,
+        source: &mut dyn AsyncStream,
+        identity: Option<String>,
+        addrs: ConnectionAddrs,
+    ) -> Result<()> {
+        let mut id = ConnectionId::generate();
+        match self.read_session(source, &mut id, true, identity).await.map_err(|e| id.attach(e))? {
+            Accepted::Pending(session) => session.with_source_addrs(addrs).proxy_to_destination().await.map(|_| ()),
+            Accepted::Fallback(mut destination) => {
+                let limits = self.session_limits;
+                session::relay(id, source, &mut destination, limits, future::pending()).await.1
+            }
+        }
+    }
+
+    /// Refuses a request from the source.
+    ///
+    /// # Parameters
+    /// - `source`: A mutable reference to the source stream.
+    ///
+    /// # Returns
+    /// An `Ok(())` if the source is successfully notified of the refusal, otherwise an error.
+    async fn refuse_request(
+        &self,
+        source: &mut dyn AsyncStream,
+    ) -> Result<()> {
+        // Notify source that the connection is refused.
+        self.recorder().reply_sent(Socks6Reply::ConnectionRefused as u8);
+        let binding = Address::new("0.0.0.0", 0);
+        socks6::write_reply_for(source, self.draft, Socks6Reply::ConnectionRefused, &binding, &[]).await?;
+
+        Ok(())
+    }
+
+    /// Refuses a request from the source with a general failure, as the server has no room for it.
+    async fn refuse_overloaded(
+        &self,
+        source: &mut dyn AsyncStream,
+    ) -> Result<()> {
+        self.recorder().reply_sent(Socks6Reply::GeneralFailure as u8);
+        let binding = Address::new("0.0.0.0", 0);
+        socks6::write_reply_for(source, self.draft, Socks6Reply::GeneralFailure, &binding, &[]).await?;
+
+        Ok(())
+    }
+
+    /// Sets up the connection to the destination.
+    ///
+    /// # Parameters
+    /// - `source`: A mutable reference to the source stream.
+    ///
+    /// # Returns
+    /// A `Result` containing the stream to the destination if successful, otherwise an error carrying the
+    /// connection ID of the session.
+    async fn setup(
+        &self,
+        source: &mut dyn AsyncStream,
+    ) -> Result<Box<dyn AsyncStream>> {
+        let mut id = ConnectionId::generate();
+        let destination = match self.read_session(source, &mut id, true, None).await {
+            Ok(Accepted::Pending(session)) => session.establish(None).await.map(|(_, destination, _)| destination),
+            Ok(Accepted::Fallback(destination)) => Ok(destination),
+            Err(e) => Err(e),
+        };
+
+        destination.map_err(|e| id.attach(e))
+    }
+
+    /// Replaces the policy of the handler, and of the fallback handler if any, for the requests accepted from
+    /// now on.
+    ///
+    /// # Parameters
+    /// - `policy`: The new policy.
+    ///
+    /// # Returns
+    /// An `Ok(())` if the policy is replaced, otherwise an error if the fallback handler doesn't support
+    /// policies.
+    fn update_policy(
+        &self,
+        policy: Policy,
+    ) -> Result<()> {
+        if let Some(fallback) = &self.fallback {
+            fallback.update_policy(policy.clone())?;
+        }
+        self.policy.update(policy);
+        Ok(())
+    }
+}
+
+/// Returns the initial data that was read already, or reads as much as the request advertised.
+async fn read_initial_data<R>(
+    reader: &mut R,
+    read: Option<Vec<u8>>,
+    length: u16,
+) -> Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    if let Some(initial_data) = read {
+        return Ok(initial_data);
+    }
+
+    let mut initial_data = vec![0; length as usize];
+    reader.read_exact(&mut initial_data).await?;
+    Ok(initial_data)
+}
+
+/// Returns the method-specific data among the options.
+fn auth_data(options: &[SocksOption]) -> Vec<AuthDataOption> {
+    let advertised = options.auth_method_data().map(|data| data.method_data);
+    options
+        .iter()
+        .filter_map(|option| match option {
+            SocksOption::AuthData(data) => Some(data.clone()),
+            _ => None,
+        })
+        .chain(advertised.unwrap_or_default())
+        .collect()
+}
+
+/// Returns whether the option is an authentication option, recognized or not, in any supported draft revision.
+fn is_auth_option(option: &SocksOption) -> bool {
+    match option {
+        SocksOption::AuthMethodAdvertisement(_)
+        | SocksOption::AuthMethodSelection(_)
+        | SocksOption::AuthData(_) => true,
+        SocksOption::Unrecognized(option) | SocksOption::Malformed(option) => {
+            default_forward_denylist().contains(&option.kind())
+        }
+        SocksOption::Metadata(_) => false,
+    }
+}
+
+/// Returns the options that report the granted TTL, if any, to the source.
+fn ttl_options(ttl: Option<u8>) -> Vec<SocksOption> {
+    ttl.map(|ttl| TtlOption::new(StackLeg::ProxyRemote, ttl).wrap()).into_iter().collect()
+}
+
+/// Returns the authentication option kinds of every supported draft revision.
+fn default_forward_denylist() -> Vec<u16> {
+    [Socks6Draft::Draft11, Socks6Draft::Draft13]
+        .iter()
+        .flat_map(|&draft| {
+            [
+                SocksOptionKind::AuthMethodAdvertisement,
+                SocksOptionKind::AuthMethodSelection,
+                SocksOptionKind::AuthData,
+            ]
+            .map(|kind| kind.to_u16_for(draft))
+        })
+        .chain([SOCKS_OKIND_AUTH_METHOD_DATA])
+        .collect()
+}
+
+/// Marks the error as a chain failure at the given link, unless a later link reported the failure already.
+fn report_failure(
+    error: anyhow::Error,
+    hop: usize,
+    address: String,
+) -> anyhow::Error {
+    if let Some(SocksError::ChainFailed(_)) = error.downcast_ref::<SocksError>() {
+        return error;
+    }
+
+    let failure = ChainFailure::new(hop, address, errors::reply_code(&error));
+    error.context(SocksError::ChainFailed(failure))
+}
+
+/// Returns whether reading the request failed because the request was refused, rather than because
+/// the source is broken, in which case the source is told why.
+fn is_refusal(error: &anyhow::Error) -> bool {
+    let refused = matches!(
+        error.downcast_ref::<SocksError>(),
+        Some(
+            SocksError::CommandNotSupported(_)
+                | SocksError::UnknownCommand(_)
+                | SocksError::UnsupportedAddressType(_)
+                | SocksError::InvalidDomain(_)
+                | SocksError::MemoryBudgetExhausted(_)
+        )
+    );
+
+    refused || error.is::<ValidationError>() || error.is::<Diagnostic>()
+}
+
+/// Writes a failed authentication reply to the source, without reading the initial data it advertised.
+///
+/// The initial data that arrives within a short time is discarded unread, so closing the connection
+/// doesn't reset it before the source could read the reply.
+async fn refuse_authentication<S>(
+    source: &mut S,
+    draft: Socks6Draft,
+    options: &[SocksOption],
+    initial_data_length: u16,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut reply = vec![];
+    wire::encode_socks6_auth_reply_for(SOCKS_AUTH_FAILED, options, draft, &mut reply);
+    source.write_all(&reply).await?;
+    source.flush().await?;
+
+    let mut initial_data = source.take(initial_data_length as u64);
+    let _ = tokio::time::timeout(AUTH_FAILURE_DRAIN_TIMEOUT, io::copy(&mut initial_data, &mut io::sink())).await;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use socket2::SockRef;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::socks6::auth::ClientAuthenticator;
+    use crate::socks6::{AuthFailureReason, InitialDataMode, UserPassAuthenticator};
+    use crate::socks6::options::{
+        AuthDataOption, AuthMethodAdvertisementOption, MetadataOption, SocksOptions, UnrecognizedOption,
+    };
+    use crate::test_util::{request_vectors, Harness, MockConnector, MockSocksServer, StallingResolver};
+    use crate::wire::vectors;
+    use crate::Socks5Handler;
+
+    // Tests that a connect that doesn't complete in time, resolution included, is answered with
+    // ConnectionAttemptTimeOut.
+    #[tokio::test(start_paused = true)]
+    async fn test_connect_timeout_reply() -> Result<()> {
+        let handler = Socks6Handler::default()
+            .with_resolver(Arc::new(StallingResolver))
+            .with_connect_timeout(Duration::from_secs(5));
+
+        let (mut client, mut source) = tokio::io::duplex(4096);
+        tokio::spawn(async move { handler.accept_request(&mut source).await });
+
+        let destination = Address::new("blackhole.invalid", 80);
+        let request = Socks6Request::new(Socks6Command::Connect, destination, 0, vec![], None);
+        client.write_all(&request.into_socks_bytes()).await?;
+
+        let started = tokio::time::Instant::now();
+        let mut scratch = BytesMut::new();
+        wire::read_message(&mut client, &mut scratch, wire::parse_socks6_auth_reply).await?;
+        let (reply_code, _, _) = wire::read_message(&mut client, &mut scratch, wire::parse_socks6_reply).await?;
+        assert_eq!(reply_code, SOCKS_REP_CONNECTION_ATTEMPT_TIMEOUT);
+        assert!(started.elapsed() < Duration::from_secs(6));
+
+        Ok(())
+    }
+
+    // Tests that a connect to an unroutable TEST-NET address, over the network, is given up on after a short timeout
+    // and answered with ConnectionAttemptTimeOut. Networks that answer for the address anyway, e.g., through a
+    // transparent proxy, have to do so before the timeout.
+    #[tokio::test]
+    async fn test_connect_timeout_unroutable() -> Result<()> {
+        let timeout = Duration::from_millis(300);
+        let handler = Socks6Handler::default().with_connect_timeout(timeout);
+
+        let (mut client, mut source) = tokio::io::duplex(4096);
+        tokio::spawn(async move { handler.accept_request(&mut source).await });
+
+        let request = Socks6Request::new(Socks6Command::Connect, Address::new("203.0.113.1", 9), 0, vec![], None);
+        client.write_all(&request.into_socks_bytes()).await?;
+
+        let started = tokio::time::Instant::now();
+        let mut scratch = BytesMut::new();
+        let replied = async {
+            wire::read_message(&mut client, &mut scratch, wire::parse_socks6_auth_reply).await?;
+            wire::read_message(&mut client, &mut scratch, wire::parse_socks6_reply).await
+        };
+        let (reply_code, _, _) = tokio::time::timeout(Duration::from_secs(5), replied).await??;
+        if started.elapsed() >= timeout {
+            assert_eq!(reply_code, SOCKS_REP_CONNECTION_ATTEMPT_TIMEOUT);
+        }
+        assert!(started.elapsed() < timeout + Duration::from_secs(1), "{:?}", started.elapsed());
+
+        Ok(())
+    }
+
+    // Connector whose connections are served by another handler, so handlers can be chained in memory.
+    struct HandlerConnector(Arc<Socks6Handler>);
+
+    #[async_trait]
+    impl Connector for HandlerConnector {
+        async fn connect(
+            &self,
+            _address: &Address,
+        ) -> Result<Box<dyn AsyncStream>> {
+            let (stream, mut source) = tokio::io::duplex(64 * 1024);
+            let handler = Arc::clone(&self.0);
+            tokio::spawn(async move { handler.accept_request(&mut source).await });
+
+            Ok(Box::new(stream))
+        }
+    }
+
+    // Authenticator that challenges the source to send a nonce back reversed, and accepts it as "alice".
+    struct ReverseAuthenticator;
+
+    #[async_trait]
+    impl Authenticator for ReverseAuthenticator {
+        fn methods(&self) -> Vec<AuthMethod> {
+            vec![AuthMethod::Gssapi]
+        }
+
+        async fn authenticate(
+            &self,
+            request: AuthRequest<'_>,
+        ) -> AuthOutcome {
+            let challenge = match request.challenge {
+                Some(challenge) => challenge,
+                None => return AuthOutcome::Continue(AuthMethod::Gssapi, b"nonce".to_vec()),
+            };
+
+            let expected: Vec<u8> = challenge.iter().rev().copied().collect();
+            match request.data_for(AuthMethod::Gssapi) {
+                Some(answer) if answer == expected => {
+                    AuthOutcome::Accept(Identity::new("alice"), AuthMethod::Gssapi)
+                }
+                _ => AuthOutcome::Reject(String::from("Wrong answer")),
+            }
+        }
+    }
+
+    fn kinds(options: &[SocksOption]) -> Vec<u16> {
+        options
+            .iter()
+            .filter_map(|option| match option {
+                SocksOption::Unrecognized(option) => Some(option.kind()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Tests that initial data sent along with the request reaches the destination.
+    #[tokio::test]
+    async fn test_initial_data_reaches_destination() -> Result<()> {
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let destination_addr = destination.local_addr()?;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let (mut incoming, _) = listener.accept().await.unwrap();
+            Socks6Handler::default().accept_request(&mut incoming).await.unwrap();
+        });
+
+        let client = Socks6Client::new(proxy_addr.to_string(), None).await?;
+        let (mut stream, _) = client.connect(destination_addr, Some(b"hello".to_vec()), None).await?;
+
+        let (mut incoming, _) = destination.accept().await?;
+        let mut received = [0; 5];
+        incoming.read_exact(&mut received).await?;
+        assert_eq!(&received, b"hello");
+
+        // The tunnel keeps working after the initial data.
+        stream.write_all(b"world").await?;
+        incoming.read_exact(&mut received).await?;
+        assert_eq!(&received, b"world");
+
+        Ok(())
+    }
+
+    // Reads the authentication and operation replies, returning the reply code.
+    async fn read_reply_code(stream: &mut tokio::io::DuplexStream) -> Result<u8> {
+        let mut scratch = BytesMut::new();
+        wire::read_message(stream, &mut scratch, wire::parse_socks6_auth_reply).await?;
+        let (reply_code, _, _) = wire::read_message(stream, &mut scratch, wire::parse_socks6_reply).await?;
+
+        Ok(reply_code)
+    }
+
+    // Reads the operation reply of a request that was refused before authentication, returning the reply code.
+    async fn read_refusal_code(stream: &mut tokio::io::DuplexStream) -> Result<u8> {
+        let (reply_code, _, _) = wire::read_message(stream, &mut BytesMut::new(), wire::parse_socks6_reply).await?;
+
+        Ok(reply_code)
+    }
+
+    // Sends a request with 5 bytes of initial data, which follow after a delay unless the replies arrive
+    // before, and returns the reply code along with how long it took to arrive.
+    async fn request_with_late_initial_data(
+        harness: &Harness,
+        delay: Duration,
+    ) -> Result<(u8, Duration)> {
+        let request = Socks6Request::new(Socks6Command::Connect, Address::new("192.0.2.1", 80), 5, vec![], None);
+        let mut stream = harness.stream();
+        let started = tokio::time::Instant::now();
+        stream.write_all(&request.into_socks_bytes()).await?;
+
+        // The handler writes both replies at once, so none are partially read when the timeout expires.
+        let reply_code = match tokio::time::timeout(delay, read_reply_code(&mut stream)).await {
+            Ok(reply_code) => reply_code?,
+            Err(_) => {
+                stream.write_all(b"hello").await?;
+                read_reply_code(&mut stream).await?
+            }
+        };
+
+        Ok((reply_code, started.elapsed()))
+    }
+
+    // Tests that the outbound connect overlaps with the arrival of the initial data, rather than adding up.
+    #[tokio::test(start_paused = true)]
+    async fn test_connect_while_reading_initial_data() -> Result<()> {
+        let delay = Duration::from_millis(100);
+        let harness = Harness::with_connector_socks6(Socks6Handler::default(), MockConnector::new().with_delay(delay));
+
+        let (reply_code, elapsed) = request_with_late_initial_data(&harness, delay).await?;
+        assert_eq!(reply_code, SOCKS_REP_SUCCEEDED);
+        assert!(elapsed < delay * 3 / 2, "took {:?}", elapsed);
+        assert_eq!(harness.connector().received(0, 5).await, b"hello");
+
+        Ok(())
+    }
+
+    // Tests that a failed connect is answered right away, without waiting for the initial data.
+    #[tokio::test(start_paused = true)]
+    async fn test_connect_failure_before_initial_data() -> Result<()> {
+        let connector = MockConnector::new()
+            .with_delay(Duration::from_millis(10))
+            .with_error(io::ErrorKind::ConnectionRefused);
+        let harness = Harness::with_connector_socks6(Socks6Handler::default(), connector);
+
+        let (reply_code, elapsed) = request_with_late_initial_data(&harness, Duration::from_secs(60)).await?;
+        assert_eq!(reply_code, SOCKS_REP_CONNECTION_REFUSED);
+        assert!(elapsed < Duration::from_secs(1), "took {:?}", elapsed);
+        assert!(harness.connector().destinations().is_empty());
+
+        Ok(())
+    }
+
+    // Tests that a handler without initial data reports that in its authentication reply, and replies without
+    // reading the initial data, which follows the operation reply like the data of the tunnel.
+    #[tokio::test]
+    async fn test_initial_data_refused() -> Result<()> {
+        let harness = Harness::socks6(Socks6Handler::default().with_initial_data(false));
+        let request = Socks6Request::new(Socks6Command::Connect, Address::new("192.0.2.1", 80), 5, vec![], None);
+        let mut stream = harness.stream();
+        stream.write_all(&request.into_socks_bytes()).await?;
+
+        let replies = async {
+            let mut scratch = BytesMut::new();
+            let (_, options) = wire::read_message(&mut stream, &mut scratch, wire::parse_socks6_auth_reply).await?;
+            let (reply_code, _, _) = wire::read_message(&mut stream, &mut scratch, wire::parse_socks6_reply).await?;
+            Ok::<_, anyhow::Error>((options, reply_code))
+        };
+        let (options, reply_code) = tokio::time::timeout(Duration::from_secs(1), replies).await??;
+        assert_eq!(options.metadata().get(&socks6::INITIAL_DATA_METADATA_KEY), Some(&"0"));
+        assert_eq!(reply_code, SOCKS_REP_SUCCEEDED);
+
+        stream.write_all(b"helloworld").await?;
+        assert_eq!(harness.connector().received(0, 10).await, b"helloworld");
+
+        Ok(())
+    }
+
+    // Tests every pairing of a handler that accepts or refuses initial data with a client in either mode, over two
+    // connects as a conservative client learns from the first: the destination gets the initial data exactly once,
+    // before the data of the tunnel.
+    #[tokio::test]
+    async fn test_initial_data_compatibility() -> Result<()> {
+        for accepted in [true, false] {
+            for mode in [InitialDataMode::Optimistic, InitialDataMode::Conservative] {
+                let harness = Harness::socks6(Socks6Handler::default().with_initial_data(accepted));
+                let client = Socks6Client::for_streams(None).with_initial_data_mode(mode);
+
+                for i in 0..2 {
+                    let case = format!("accepted: {}, mode: {:?}, connect: {}", accepted, mode, i);
+                    let connecting = harness.connect_socks6(&client, "192.0.2.1:80", Some(b"hello".to_vec()), None);
+                    let (mut stream, _) = tokio::time::timeout(Duration::from_secs(5), connecting).await??;
+                    stream.write_all(b"world").await?;
+
+                    let receiving = harness.connector().received(i, 10);
+                    let received = tokio::time::timeout(Duration::from_secs(5), receiving).await?;
+                    assert_eq!(received, b"helloworld", "{}", case);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Tests that requests with an unimplemented or unknown command are refused with a reply, instead of a
+    // dropped connection.
+    #[tokio::test]
+    async fn test_unknown_command_refused() -> Result<()> {
+        for command in [SOCKS_CMD_NOOP, SOCKS_CMD_BIND, SOCKS_CMD_UDP_ASSOCIATE, 0x09] {
+            let harness = Harness::socks6(Socks6Handler::default());
+            let mut stream = harness.stream();
+
+            let mut request = Socks6Request::new(Socks6Command::Connect, Address::new("192.0.2.1", 80), 0, vec![], None)
+                .into_socks_bytes();
+            request[1] = command;
+            stream.write_all(&request).await?;
+
+            assert_eq!(read_refusal_code(&mut stream).await?, SOCKS_REP_COMMAND_NOT_SUPPORTED);
+            assert!(harness.connector().destinations().is_empty());
+        }
+
+        Ok(())
+    }
+
+    // Tests that a request with an unknown address type is refused with a reply, instead of a panic.
+    #[tokio::test]
+    async fn test_unknown_address_type_refused() -> Result<()> {
+        let harness = Harness::socks6(Socks6Handler::default());
+        let mut stream = harness.stream();
+
+        let mut request = Socks6Request::new(Socks6Command::Connect, Address::new("192.0.2.1", 80), 0, vec![], None)
+            .into_socks_bytes();
+        request[2] = 0x05;
+        stream.write_all(&request).await?;
+
+        assert_eq!(read_refusal_code(&mut stream).await?, SOCKS_REP_ADDRESS_TYPE_NOT_SUPPORTED);
+        assert!(harness.connector().destinations().is_empty());
+
+        Ok(())
+    }
+
+    // Tests that the handler parses the shared request vectors exactly like `socks6::read_request`.
+    #[tokio::test]
+    async fn test_request_vectors() -> Result<()> {
+        for (name, bytes) in request_vectors() {
+            let expected = socks6::read_request(&mut &bytes[..]).await?;
+
+            let handler = Socks6Handler::default();
+            let (mut client, mut source) = tokio::io::duplex(1024);
+            client.write_all(&bytes).await?;
+            client.write_all(&vec![0; expected.initial_data_length as usize]).await?;
+            let (_, request) = handler.accept(&mut source).await?;
+
+            assert_eq!(request.metadata, expected.metadata, "{}", name);
+            assert_eq!(request.initial_data_length, expected.initial_data_length, "{}", name);
+            assert_eq!(request.into_socks_bytes(), expected.into_socks_bytes(), "{}", name);
+        }
+
+        Ok(())
+    }
+
+    // Tests that a request for a Unix socket path is refused like an unknown address type, unless the handler
+    // allows Unix destinations.
+    #[tokio::test]
+    async fn test_unix_destination() -> Result<()> {
+        let destination = Address::unix("/run/service.sock")?;
+        let request = Socks6Request::new(Socks6Command::Connect, destination.clone(), 0, vec![], None);
+
+        for allow in [false, true] {
+            let harness = Harness::socks6(Socks6Handler::default().with_unix_destinations(allow));
+            let mut stream = harness.stream();
+            stream.write_all(&request.clone().into_socks_bytes()).await?;
+
+            if allow {
+                assert_eq!(read_reply_code(&mut stream).await?, SOCKS_REP_SUCCEEDED);
+                assert_eq!(harness.connector().destinations()[0].address, destination);
+            } else {
+                assert_eq!(read_refusal_code(&mut stream).await?, SOCKS_REP_ADDRESS_TYPE_NOT_SUPPORTED);
+                assert!(harness.connector().destinations().is_empty());
+            }
+        }
+
+        Ok(())
+    }
+
+    // Tests that a request the validation policy rejects is refused before anything is connected, and that the
+    // policy can allow it.
+    #[tokio::test]
+    async fn test_invalid_request_refused() -> Result<()> {
+        let request = Socks6Request::new(Socks6Command::Connect, Address::new("192.0.2.1", 0), 0, vec![], None);
+
+        let harness = Harness::socks6(Socks6Handler::default());
+        let mut stream = harness.stream();
+        stream.write_all(&request.clone().into_socks_bytes()).await?;
+
+        assert_eq!(read_refusal_code(&mut stream).await?, SOCKS_REP_GENERAL_FAILURE);
+        assert!(harness.connector().destinations().is_empty());
+
+        let harness = Harness::socks6(Socks6Handler::default().with_validation(ValidationPolicy::lenient()));
+        let mut stream = harness.stream();
+        stream.write_all(&request.into_socks_bytes()).await?;
+
+        let mut scratch = BytesMut::new();
+        wire::read_message(&mut stream, &mut scratch, wire::parse_socks6_auth_reply).await?;
+        let (reply_code, _, _) = wire::read_message(&mut stream, &mut scratch, wire::parse_socks6_reply).await?;
+        assert_eq!(reply_code, SOCKS_REP_SUCCEEDED);
+        assert_eq!(harness.connector().destinations()[0].address, Address::new("192.0.2.1", 0));
+
+        Ok(())
+    }
+
+    // Tests that a request with ambiguous options, i.e., duplicate authentication data, is answered with a
+    // general failure.
+    #[tokio::test]
+    async fn test_duplicate_options_refused() -> Result<()> {
+        let options = vec![
+            AuthDataOption::new(AuthMethod::UsernamePassword, vec![1]).wrap(),
+            AuthDataOption::new(AuthMethod::UsernamePassword, vec![2]).wrap(),
+        ];
+        let request = Socks6Request::new(Socks6Command::Connect, Address::new("192.0.2.1", 80), 0, options, None);
+
+        let harness = Harness::socks6(Socks6Handler::default());
+        let mut stream = harness.stream();
+        stream.write_all(&request.into_socks_bytes()).await?;
+
+        assert_eq!(read_refusal_code(&mut stream).await?, SOCKS_REP_GENERAL_FAILURE);
+        assert!(harness.connector().destinations().is_empty());
+
+        Ok(())
+    }
+
+    // Tests that a request with nonzero padding is refused by a strict handler, and proxied by a lenient one.
+    #[tokio::test]
+    async fn test_parse_mode() -> Result<()> {
+        let request = Socks6Request::new(Socks6Command::Connect, Address::new("192.0.2.1", 80), 0, vec![], None);
+        let mut bytes = request.clone().into_socks_bytes();
+        bytes[2 + request.destination.encoded_len()] = 0x01;
+
+        for mode in [ParseMode::Strict, ParseMode::Lenient] {
+            let harness = Harness::socks6(Socks6Handler::default().with_parse_mode(mode));
+            let mut stream = harness.stream();
+            stream.write_all(&bytes).await?;
+
+            if mode == ParseMode::Strict {
+                assert_eq!(read_refusal_code(&mut stream).await?, SOCKS_REP_GENERAL_FAILURE);
+                assert!(harness.connector().destinations().is_empty());
+            } else {
+                assert_eq!(read_reply_code(&mut stream).await?, SOCKS_REP_SUCCEEDED);
+                assert_eq!(harness.connector().destinations().len(), 1);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Tests that a handler that recovers from malformed options accepts a request with one, reports it among the
+    // diagnostics of the request, and doesn't forward it to the next link.
+    #[tokio::test]
+    async fn test_parse_mode_recover() -> Result<()> {
+        let kind = Socks6Draft::default().constants().okind_metadata;
+        let mut malformed = kind.to_be_bytes().to_vec();
+        malformed.extend_from_slice(&[0x00, 0x08, 0x00, 0x01, 0x00, 0x10]);
+
+        let mut bytes = Socks6Request::new(Socks6Command::Connect, Address::new("192.0.2.1", 80), 0, vec![], None)
+            .into_socks_bytes();
+        bytes.truncate(bytes.len() - 2);
+        bytes.extend_from_slice(&(malformed.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&malformed);
+
+        let handler = Socks6Handler::default().with_parse_mode(ParseMode::Recover);
+        let (mut client, mut source) = tokio::io::duplex(4096);
+        client.write_all(&bytes).await?;
+        let (session, request) = handler.accept(&mut source).await?;
+        session.reject(Socks6Reply::ConnectionRefused).await?;
+
+        assert_eq!(request.diagnostics(), &[Diagnostic::MalformedOption { kind, length: 8 }]);
+        assert!(matches!(&request.options[..], [SocksOption::Malformed(option)] if option.kind() == kind));
+
+        let next = MockSocksServer::socks6();
+        let handler = Socks6Handler::new(vec![ProxyAddress::new(6, String::from("next"), 1080, None)])
+            .with_connector(Arc::new(next.clone()))
+            .with_parse_mode(ParseMode::Recover);
+        let (mut client, mut source) = tokio::io::duplex(4096);
+        client.write_all(&bytes).await?;
+        handler.setup(&mut source).await?;
+
+        let recording = &next.recordings()[0];
+        assert!(!recording.bytes.windows(malformed.len()).any(|w| w == &malformed[..]));
+
+        Ok(())
+    }
+
+    // Tests that metadata is forwarded to the next link byte for byte when raw options are retained, and
+    // serialized again otherwise.
+    #[tokio::test]
+    async fn test_forward_raw_options() -> Result<()> {
+        // Pad the option further than needed, which serializing it again doesn't preserve.
+        let mut raw_option = MetadataOption::new(1, String::from("value")).wrap().as_socks_bytes();
+        raw_option[3] += 4;
+        raw_option.extend_from_slice(&[0; 4]);
+
+        let mut request = Socks6Request::new(Socks6Command::Connect, Address::new("192.0.2.1", 80), 0, vec![], None)
+            .into_socks_bytes();
+        request.truncate(request.len() - 2);
+        request.extend_from_slice(&(raw_option.len() as u16).to_be_bytes());
+        request.extend_from_slice(&raw_option);
+
+        for retain in [true, false] {
+            let next = MockSocksServer::socks6();
+            let handler = Socks6Handler::new(vec![ProxyAddress::new(6, String::from("next"), 1080, None)])
+                .with_connector(Arc::new(next.clone()))
+                .with_raw_options(retain);
+
+            let (mut client, mut source) = tokio::io::duplex(4096);
+            tokio::spawn(async move { handler.accept_request(&mut source).await });
+            client.write_all(&request).await?;
+
+            let mut scratch = BytesMut::new();
+            wire::read_message(&mut client, &mut scratch, wire::parse_socks6_auth_reply).await?;
+            let (reply_code, _, _) = wire::read_message(&mut client, &mut scratch, wire::parse_socks6_reply).await?;
+            assert_eq!(reply_code, SOCKS_REP_SUCCEEDED);
+
+            let recording = &next.recordings()[0];
+            let forwarded = recording.bytes.windows(raw_option.len()).any(|w| w == &raw_option[..]);
+            assert_eq!(forwarded, retain);
+            assert!(recording.options.iter().any(|o| matches!(o, SocksOption::Metadata(m) if m.key == 1)));
+            assert_eq!(recording.destination, Some(Address::new("192.0.2.1", 80)));
+        }
+
+        Ok(())
+    }
+
+    // Tests that a connection ID sent by the source is adopted in errors and forwarded to the next link once, if
+    // connection ID metadata is enabled, that it is ignored and stripped otherwise, and that the handler can send
+    // its own.
+    #[tokio::test]
+    async fn test_connection_id() -> Result<()> {
+        let id = MetadataOption::new(CONNECTION_ID_METADATA_KEY, String::from("000000000000002a")).wrap();
+        let request = Socks6Request::new(Socks6Command::Connect, Address::new("192.0.2.1", 80), 0, vec![id], None);
+
+        for adopt in [true, false] {
+            let refusing = MockConnector::new().with_error(io::ErrorKind::ConnectionRefused);
+            let handler = Socks6Handler::default()
+                .with_connector(Arc::new(refusing))
+                .with_connection_id_metadata(adopt);
+            let (mut client, mut source) = tokio::io::duplex(4096);
+            client.write_all(&request.clone().into_socks_bytes()).await?;
+            let error = handler.setup(&mut source).await.err().unwrap();
+            assert_eq!(ConnectionId::of(&error) == Some(ConnectionId::from(0x2A)), adopt);
+            assert_eq!(error.to_string().starts_with("[000000000000002a] "), adopt);
+        }
+
+        let without_id = Socks6Request::new(Socks6Command::Connect, Address::new("192.0.2.1", 80), 0, vec![], None);
+        for (request, propagate) in [(request.clone(), false), (request, true), (without_id, true)] {
+            let next = MockSocksServer::socks6();
+            let handler = Socks6Handler::new(vec![ProxyAddress::new(6, String::from("next"), 1080, None)])
+                .with_connector(Arc::new(next.clone()))
+                .with_connection_id_metadata(propagate);
+
+            let (mut client, mut source) = tokio::io::duplex(4096);
+            client.write_all(&request.clone().into_socks_bytes()).await?;
+            handler.setup(&mut source).await?;
+
+            let ids: Vec<ConnectionId> = next.recordings()[0]
+                .options
+                .iter()
+                .filter_map(|option| match option {
+                    SocksOption::Metadata(m) if m.key == CONNECTION_ID_METADATA_KEY => m.value.parse().ok(),
+                    _ => None,
+                })
+                .collect();
+            let sent = request.options.iter().any(|option| match option {
+                SocksOption::Metadata(m) => m.key == CONNECTION_ID_METADATA_KEY,
+                _ => false,
+            });
+            match (sent, propagate) {
+                (true, true) => assert_eq!(ids, vec![ConnectionId::from(0x2A)]),
+                (true, false) => assert!(ids.is_empty()),
+                (false, _) => assert_eq!(ids.len(), 1),
+            }
+        }
+
+        Ok(())
+    }
+
+    // Tests that the options of the last link's reply reach the client through two handlers, as far as every link
+    // passes their kinds on, without authentication options, and with the binding of the last link.
+    #[tokio::test]
+    async fn test_reply_passthrough() -> Result<()> {
+        let grant = MetadataOption::new(42, String::from("grant")).wrap();
+        let binding = Address::new("203.0.113.7", 4000);
+        let last = MockSocksServer::socks6().with_binding(binding.clone()).with_reply_options(vec![
+            grant.clone(),
+            UnrecognizedOption::new(0x4321, vec![4, 5]).wrap(),
+            AuthDataOption::new(AuthMethod::UsernamePassword, vec![1, 2, 3]).wrap(),
+        ]);
+        let second = Socks6Handler::default()
+            .with_connector(Arc::new(last.clone()))
+            .with_reply_binding(ReplyBinding::Upstream);
+        let first = Socks6Handler::new(vec![
+            ProxyAddress::new(6, String::from("second"), 1080, None),
+            ProxyAddress::new(6, String::from("last"), 1080, None),
+        ])
+        .with_connector(Arc::new(HandlerConnector(Arc::new(second))))
+        .with_reply_passthrough(vec![SocksOptionKind::Metadata, SocksOptionKind::AuthData])
+        .with_reply_binding(ReplyBinding::Upstream);
+
+        let (mut client, mut source) = tokio::io::duplex(4096);
+        tokio::spawn(async move { first.accept_request(&mut source).await });
+        let request = Socks6Request::new(Socks6Command::Connect, Address::new("192.0.2.1", 80), 0, vec![], None);
+        client.write_all(&request.into_socks_bytes()).await?;
+
+        let mut scratch = BytesMut::new();
+        wire::read_message(&mut client, &mut scratch, wire::parse_socks6_auth_reply).await?;
+        let (reply_code, reply_binding, options) =
+            wire::read_message(&mut client, &mut scratch, wire::parse_socks6_reply).await?;
+        assert_eq!(reply_code, SOCKS_REP_SUCCEEDED);
+        assert_eq!(reply_binding, binding);
+        assert_eq!(options, vec![grant]);
+
+        Ok(())
+    }
+
+    // Tests that a handler decodes packed metadata from its source, and packs the metadata it forwards if asked to.
+    #[tokio::test]
+    async fn test_packed_metadata() -> Result<()> {
+        let mut request = Socks6Request::new(Socks6Command::Connect, Address::new("192.0.2.1", 80), 0, vec![], None)
+            .with_packed_metadata(true);
+        request.metadata = (1..=5).map(|key| (key, format!("value-{}", key))).collect();
+
+        for packed in [false, true] {
+            let next = MockSocksServer::socks6();
+            let handler = Socks6Handler::new(vec![ProxyAddress::new(6, String::from("next"), 1080, None)])
+                .with_connector(Arc::new(next.clone()))
+                .with_packed_metadata(packed);
+
+            let (mut client, mut source) = tokio::io::duplex(4096);
+            client.write_all(&request.clone().into_socks_bytes()).await?;
+            handler.setup(&mut source).await?;
+
+            let recording = &next.recordings()[0];
+            let forwarded: HashMap<u16, String> = recording
+                .options
+                .iter()
+                .filter_map(|option| match option {
+                    SocksOption::Metadata(m) if m.key < 998 => Some((m.key, m.value.clone())),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(forwarded, request.metadata);
+
+            let kind = SOCKS_OKIND_PACKED_METADATA.to_be_bytes();
+            assert_eq!(recording.bytes.windows(2).any(|w| w == kind), packed);
+        }
+
+        Ok(())
+    }
+
+    // Tests that a handler decodes compressed metadata, advertises support for it only if enabled and advertised by
+    // the source, and doesn't pass the advertisement of the source on to the next link, but its own if enabled.
+    #[tokio::test]
+    async fn test_metadata_compression() -> Result<()> {
+        let options = vec![socks6::metadata_compression_option()];
+        let mut request = Socks6Request::new(Socks6Command::Connect, Address::new("192.0.2.1", 80), 0, options, None)
+            .with_compressed_metadata(true);
+        request.metadata = (1..=20).map(|key| (key, "x".repeat(100))).collect();
+
+        for (enabled, advertised) in [(false, true), (true, false), (true, true)] {
+            let next = MockSocksServer::socks6();
+            let handler = Socks6Handler::new(vec![ProxyAddress::new(6, String::from("next"), 1080, None)])
+                .with_connector(Arc::new(next.clone()))
+                .with_metadata_compression(enabled);
+            let mut request = request.clone();
+            if !advertised {
+                request.options.clear();
+            }
+
+            let (mut client, mut source) = tokio::io::duplex(4096);
+            client.write_all(&request.clone().into_socks_bytes()).await?;
+            handler.setup(&mut source).await?;
+            let reply = socks6::read_authentication_reply(&mut client).await?;
+            assert_eq!(socks6::supports_metadata_compression(&reply.options), enabled && advertised);
+
+            let recording = &next.recordings()[0];
+            assert_eq!(socks6::supports_metadata_compression(&recording.options), enabled);
+            let forwarded = recording.options.metadata();
+            assert!(request.metadata.iter().all(|(key, value)| forwarded.get(key) == Some(&value.as_str())));
+        }
+
+        Ok(())
+    }
+
+    // Tests that a handler compresses the metadata it sends to the next link once that advertised support, and
+    // never forwards the compressed metadata of the source as it is to a next link without support.
+    #[tokio::test]
+    async fn test_metadata_compression_next_link() -> Result<()> {
+        let packed_kind = SOCKS_OKIND_PACKED_METADATA.to_be_bytes();
+        let mut request = Socks6Request::new(Socks6Command::Connect, Address::new("192.0.2.1", 80), 0, vec![], None)
+            .with_compressed_metadata(true);
+        request.metadata.insert(1, "x".repeat(100));
+
+        for supported in [false, true] {
+            let next = MockSocksServer::socks6();
+            let next = if supported { next.with_metadata_compression() } else { next };
+            let handler = Socks6Handler::new(vec![ProxyAddress::new(6, String::from("next"), 1080, None)])
+                .with_connector(Arc::new(next.clone()))
+                .with_raw_options(true)
+                .with_metadata_compression(true);
+
+            for _ in 0..2 {
+                let (mut client, mut source) = tokio::io::duplex(4096);
+                client.write_all(&request.clone().into_socks_bytes()).await?;
+                handler.setup(&mut source).await?;
+            }
+
+            let recordings = next.recordings();
+            for (round, recording) in recordings.iter().enumerate() {
+                assert_eq!(recording.options.metadata().get(&1), Some(&"x".repeat(100).as_str()));
+                let compressed = recording.bytes.windows(2).any(|w| w == packed_kind);
+                assert_eq!(compressed, supported && round == 1, "supported: {}, round: {}", supported, round);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Tests that unrecognized options travel unchanged through a chain of two handlers in both directions, unless
+    // their kind is denied.
+    #[tokio::test]
+    async fn test_forward_unrecognized_options() -> Result<()> {
+        let custom = UnrecognizedOption::new(0x1234, vec![1, 2, 3]).wrap();
+        let denied = UnrecognizedOption::new(SOCKS6_DRAFT_13.okind_auth_data, vec![1, 2, 3]).wrap();
+
+        let last = MockSocksServer::socks6()
+            .with_reply_options(vec![UnrecognizedOption::new(0x4321, vec![4, 5]).wrap(), denied.clone()]);
+        let second = Socks6Handler::default().with_connector(Arc::new(last.clone()));
+        let first = Socks6Handler::new(vec![
+            ProxyAddress::new(6, String::from("second"), 1080, None),
+            ProxyAddress::new(6, String::from("last"), 1080, None),
+        ])
+        .with_connector(Arc::new(HandlerConnector(Arc::new(second))));
+
+        let options = vec![custom.clone(), denied];
+        let request = Socks6Request::new(Socks6Command::Connect, Address::new("192.0.2.1", 80), 0, options, None);
+
+        let (mut client, mut source) = tokio::io::duplex(4096);
+        tokio::spawn(async move { first.accept_request(&mut source).await });
+        client.write_all(&request.into_socks_bytes()).await?;
+
+        let mut scratch = BytesMut::new();
+        wire::read_message(&mut client, &mut scratch, wire::parse_socks6_auth_reply).await?;
+        let (reply_code, _, options) = wire::read_message(&mut client, &mut scratch, wire::parse_socks6_reply).await?;
+        assert_eq!(reply_code, SOCKS_REP_SUCCEEDED);
+        assert_eq!(kinds(&options), vec![0x4321]);
+
+        let recording = &last.recordings()[0];
+        let raw = custom.as_socks_bytes();
+        assert!(recording.bytes.windows(raw.len()).any(|w| w == &raw[..]));
+        assert_eq!(kinds(&recording.options), vec![0x1234]);
+        assert_eq!(recording.destination, Some(Address::new("192.0.2.1", 80)));
+
+        Ok(())
+    }
+
+    // Tests that a failure at the second link of three is reported back to the client, and that a successful
+    // request reports the number of links it traversed.
+    #[tokio::test]
+    async fn test_chain_progress() -> Result<()> {
+        let links = vec![
+            ProxyAddress::new(6, String::from("second"), 1080, None),
+            ProxyAddress::new(6, String::from("third"), 1080, None),
+        ];
+        let chain = |second: Socks6Handler| {
+            let second = HandlerConnector(Arc::new(second));
+            Socks6Handler::new(links.clone()).with_connector(Arc::new(second))
+        };
+
+        // The second link can't reach the third.
+        let refusing = MockConnector::new().with_error(io::ErrorKind::ConnectionRefused);
+        let first = chain(Socks6Handler::default().with_connector(Arc::new(refusing)));
+        let (mut client, mut source) = tokio::io::duplex(4096);
+        tokio::spawn(async move { first.accept_request(&mut source).await });
+
+        let error = Socks6Client::for_streams(None)
+            .handshake("192.0.2.1:80".to_string(), None, None, &mut client)
+            .await
+            .unwrap_err();
+        match error.downcast_ref::<SocksError>() {
+            Some(SocksError::ChainFailed(failure)) => {
+                assert_eq!(failure, &ChainFailure::new(1, links[1].to_string(), SOCKS_REP_CONNECTION_REFUSED));
+            }
+            _ => panic!("Expected a chain failure, got: {:?}", error),
+        }
+
+        // The third link reaches the destination.
+        let third = Socks6Handler::default().with_connector(Arc::new(MockConnector::new())).with_hop_count(true);
+        let first = chain(Socks6Handler::default().with_connector(Arc::new(HandlerConnector(Arc::new(third)))));
+        let (mut client, mut source) = tokio::io::duplex(4096);
+        tokio::spawn(async move { first.accept_request(&mut source).await });
+
+        let (_, options, _) = Socks6Client::for_streams(None)
+            .handshake_with_reply("192.0.2.1:80".to_string(), None, None, &mut client)
+            .await?;
+        assert_eq!(chain::hops_from_options(&options), Some(3));
+
+        Ok(())
+    }
+
+    // Tests that a requested TTL is clamped to the range the handler allows, set on the connection to the
+    // destination, and reported in the success reply, and that it's ignored unless the handler allows it.
+    #[tokio::test]
+    async fn test_requested_ttl() -> Result<()> {
+        let destination = TcpListener::bind("127.0.0.1:0").await?;
+        let address = Address::Ip(destination.local_addr()?);
+        let request = |leg, ttl| {
+            let options = vec![TtlOption::new(leg, ttl).wrap()];
+            Socks6Request::new(Socks6Command::Connect, address.clone(), 0, options, None)
+        };
+
+        let handler = Socks6Handler::default().with_ttl(30).with_requested_ttl(64);
+        for (requested, granted) in [(10, 10), (200, 64), (0, 1)] {
+            let ttl = handler.granted_ttl(&request(StackLeg::ProxyRemote, requested));
+            assert_eq!(ttl, Some(granted));
+            let (stream, _) = handler.dialer_with_ttl(ttl).connect(&address).await?;
+            assert_eq!(SockRef::from(&stream).ttl_v4()?, u32::from(granted));
+        }
+        let (stream, _) = handler.dialer_with_ttl(None).connect(&address).await?;
+        assert_eq!(SockRef::from(&stream).ttl_v4()?, 30);
+        assert_eq!(Socks6Handler::default().with_ttl(0).dialer.socket_config().ttl, None);
+
+        assert_eq!(handler.granted_ttl(&request(StackLeg::Both, 10)), Some(10));
+        assert_eq!(handler.granted_ttl(&request(StackLeg::ClientProxy, 10)), None);
+        assert_eq!(Socks6Handler::default().granted_ttl(&request(StackLeg::ProxyRemote, 10)), None);
+        let disallowed = Socks6Handler::default().with_requested_ttl(0);
+        assert_eq!(disallowed.granted_ttl(&request(StackLeg::ProxyRemote, 10)), None);
+        let custom = handler.clone().with_connector(Arc::new(MockConnector::new()));
+        assert_eq!(custom.granted_ttl(&request(StackLeg::ProxyRemote, 10)), None);
+
+        // The clamped TTL is reported to the source.
+        let (mut client, mut source) = tokio::io::duplex(4096);
+        tokio::spawn(async move { handler.accept_request(&mut source).await });
+        let options = vec![TtlOption::new(StackLeg::ProxyRemote, 200).wrap()];
+        let (_, options, _) = Socks6Client::for_streams(None)
+            .handshake_with_reply(address, None, Some(options), &mut client)
+            .await?;
+        assert_eq!(options.ttl(), Some(TtlOption::new(StackLeg::ProxyRemote, 64)));
+
+        Ok(())
+    }
+
+    // Resolver that answers every name with a TEST-NET address.
+    struct FixedResolver;
+
+    #[async_trait]
+    impl Resolver for FixedResolver {
+        async fn resolve(
+            &self,
+            _host: &str,
+        ) -> Result<Vec<std::net::IpAddr>> {
+            Ok(vec!["192.0.2.7".parse()?])
+        }
+    }
+
+    // Tests that a domain name survives unresolved through a two-hop chain unless the first link resolves it, and
+    // that a link that always passes names through refuses them without an upstream.
+    #[tokio::test]
+    async fn test_resolution_policy() -> Result<()> {
+        let links = vec![ProxyAddress::new(6, String::from("second"), 1080, None)];
+        let cases = [
+            (ResolutionPolicy::PassThroughWhenChaining, Address::new("example.com", 80)),
+            (ResolutionPolicy::AlwaysPassThrough, Address::new("example.com", 80)),
+            (ResolutionPolicy::ResolveLocal, Address::new("192.0.2.7", 80)),
+        ];
+        for (resolution, expected) in cases {
+            let connector = MockConnector::new();
+            let second = Socks6Handler::default()
+                .with_connector(Arc::new(connector.clone()))
+                .with_resolution_policy(resolution);
+            let first = Socks6Handler::new(links.clone())
+                .with_connector(Arc::new(HandlerConnector(Arc::new(second))))
+                .with_resolver(Arc::new(FixedResolver))
+                .with_resolution_policy(resolution);
+            let (mut client, mut source) = tokio::io::duplex(4096);
+            tokio::spawn(async move { first.accept_request(&mut source).await });
+
+            Socks6Client::for_streams(None)
+                .handshake("example.com:80".to_string(), None, None, &mut client)
+                .await?;
+            assert_eq!(connector.destinations()[0].address, expected, "{:?}", resolution);
+        }
+
+        let handler = Socks6Handler::default().with_resolution_policy(ResolutionPolicy::AlwaysPassThrough);
+        let (mut client, mut source) = tokio::io::duplex(4096);
+        tokio::spawn(async move { handler.accept_request(&mut source).await });
+        let error = Socks6Client::for_streams(None)
+            .handshake("example.com:80".to_string(), None, None, &mut client)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(SocksError::OperationFailed(SOCKS_REP_ADDRESS_TYPE_NOT_SUPPORTED))
+        ));
+
+        Ok(())
+    }
+
+    // Tests that an accepted request can be rejected with a reply of the caller's choice, or proxied through a stream
+    // the caller connected.
+    #[tokio::test]
+    async fn test_accept() -> Result<()> {
+        let connector = MockConnector::new();
+        let handler = Socks6Handler::default().with_connector(Arc::new(connector.clone()));
+        let request = Socks6Request::new(Socks6Command::Connect, Address::new("192.0.2.1", 80), 0, vec![], None);
+
+        let (mut client, mut source) = tokio::io::duplex(4096);
+        client.write_all(&request.clone().into_socks_bytes()).await?;
+        let (session, accepted) = handler.accept(&mut source).await?;
+        assert_eq!(accepted.destination, Address::new("192.0.2.1", 80));
+        session.reject(Socks6Reply::ConnectionNotAllowed).await?;
+
+        let mut scratch = BytesMut::new();
+        wire::read_message(&mut client, &mut scratch, wire::parse_socks6_auth_reply).await?;
+        let (reply_code, _, _) = wire::read_message(&mut client, &mut scratch, wire::parse_socks6_reply).await?;
+        assert_eq!(reply_code, Socks6Reply::ConnectionNotAllowed as u8);
+
+        let (mut client, mut source) = tokio::io::duplex(4096);
+        let (outbound, mut destination) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            let (session, _) = handler.accept(&mut source).await?;
+            session.proxy_via(outbound).await
+        });
+        client.write_all(&request.into_socks_bytes()).await?;
+        client.write_all(b"hello").await?;
+
+        let mut scratch = BytesMut::new();
+        wire::read_message(&mut client, &mut scratch, wire::parse_socks6_auth_reply).await?;
+        let (reply_code, _, _) = wire::read_message(&mut client, &mut scratch, wire::parse_socks6_reply).await?;
+        assert_eq!(reply_code, SOCKS_REP_SUCCEEDED);
+
+        let mut received = [0; 5];
+        destination.read_exact(&mut received).await?;
+        assert_eq!(&received, b"hello");
+        assert!(connector.destinations().is_empty());
+
+        Ok(())
+    }
+
+    // Tests that the handler accepts the golden request as the typed request it encodes, and answers it with exactly
+    // the golden replies.
+    #[tokio::test]
+    async fn test_golden_vectors() -> Result<()> {
+        let request = vectors::socks6_connect_with_metadata();
+        let handler = Socks6Handler::default();
+
+        let (mut client, mut source) = tokio::io::duplex(4096);
+        client.write_all(request.bytes).await?;
+        client.write_all(vectors::SOCKS6_INITIAL_DATA).await?;
+        let (session, accepted) = handler.accept(&mut source).await?;
+        assert_eq!(accepted, request.value);
+        session.reject(Socks6Reply::ConnectionRefused).await?;
+        drop(source);
+
+        let expected = [vectors::socks6_auth_success().bytes, vectors::socks6_reply_connection_refused().bytes];
+        let mut replies = vec![];
+        client.read_to_end(&mut replies).await?;
+        assert_eq!(replies, expected.concat());
+
+        Ok(())
+    }
+
+    // Tests that a client of another version gets a version mismatch reply, and the caller a typed error.
+    #[tokio::test]
+    async fn test_version_mismatch() -> Result<()> {
+        let (mut client, mut source) = tokio::io::duplex(1024);
+        client.write_all(&[SOCKS_VER_5, 1, SOCKS_AUTH_NOT_REQUIRED]).await?;
+
+        let error = Socks6Handler::default().accept_request(&mut source).await.unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(SocksError::VersionMismatch(SOCKS_VER_5))));
+
+        drop(source);
+        let mut reply = vec![];
+        client.read_to_end(&mut reply).await?;
+        assert_eq!(reply, vec![SOCKS_VER_6]);
+        Ok(())
+    }
+
+    // Tests that a SOCKS5 client is served by the fallback handler, including the data it pipelined.
+    #[tokio::test]
+    async fn test_version_mismatch_fallback() -> Result<()> {
+        let connector = MockConnector::new();
+        let fallback = Socks5Handler::default().with_connector(Arc::new(connector.clone()));
+        let harness = Harness::socks6(Socks6Handler::default().with_fallback(Arc::new(fallback)));
+        let mut stream = harness.stream();
+
+        let mut handshake = vec![];
+        wire::encode_socks5_greeting(&[SOCKS_AUTH_NOT_REQUIRED], &mut handshake);
+        let request = crate::socks5::Socks5Request::new(SOCKS_CMD_CONNECT, Address::new("192.0.2.1", 80));
+        wire::encode_socks5_request(&request, &mut handshake);
+        handshake.extend_from_slice(b"hello");
+        stream.write_all(&handshake).await?;
+
+        let mut scratch = BytesMut::new();
+        let method = wire::read_message(&mut stream, &mut scratch, wire::parse_socks5_method_selection).await?;
+        assert_eq!(method, SOCKS_AUTH_NOT_REQUIRED);
+        let (reply_code, _) = wire::read_message(&mut stream, &mut scratch, wire::parse_socks5_reply).await?;
+        assert_eq!(reply_code, SOCKS_REP_SUCCEEDED);
+
+        let mut echoed = [0; 5];
+        stream.read_exact(&mut echoed).await?;
+        assert_eq!(&echoed, b"hello");
+        assert_eq!(connector.destinations()[0].address, Address::new("192.0.2.1", 80));
+        assert!(harness.connector().destinations().is_empty());
+        Ok(())
+    }
+
+    // Tests that a source with the right credentials gets through, and one without doesn't.
+    #[tokio::test]
+    async fn test_credentials() -> Result<()> {
+        let credentials = Credentials::new("user", "secret");
+        let harness = Harness::socks6(Socks6Handler::default().with_credentials(credentials.clone()));
+
+        let client = Socks6Client::for_streams(Some(credentials));
+        let (mut stream, _) = harness.connect_socks6(&client, "192.0.2.1:80".to_string(), None, None).await?;
+        stream.write_all(b"hello").await?;
+        assert_eq!(harness.connector().received(0, 5).await, b"hello".to_vec());
+
+        let client = Socks6Client::for_streams(Some(Credentials::new("user", "wrong")));
+        let result = harness.connect_socks6(&client, "192.0.2.1:80".to_string(), None, None).await;
+        assert!(result.is_err());
+
+        let client = Socks6Client::for_streams(None);
+        let result = harness.connect_socks6(&client, "192.0.2.1:80".to_string(), None, None).await;
+        assert!(result.is_err());
+        assert_eq!(harness.connector().destinations().len(), 1);
+
+        Ok(())
+    }
+
+    // Tests that a rejected source isn't waited on for the initial data it advertised but never sends.
+    #[tokio::test]
+    async fn test_authentication_failure_skips_initial_data() -> Result<()> {
+        let (mut client, mut source) = tokio::io::duplex(4096);
+
+        let mut auth_data = vec![];
+        wire::encode_socks5_credentials(&Credentials::new("user", "wrong"), &mut auth_data);
+        let options = vec![
+            AuthMethodAdvertisementOption::new(1000, vec![AuthMethod::UsernamePassword]).wrap(),
+            AuthDataOption::new(AuthMethod::UsernamePassword, auth_data).wrap(),
+        ];
+        let request = Socks6Request::new(Socks6Command::Connect, Address::new("192.0.2.1", 80), 1000, options, None);
+        client.write_all(&request.into_socks_bytes()).await?;
+
+        let handler = Socks6Handler::default().with_credentials(Credentials::new("user", "secret"));
+        let result = tokio::time::timeout(Duration::from_secs(1), handler.setup(&mut source)).await?;
+        assert!(matches!(
+            result.err().unwrap().downcast_ref(),
+            Some(SocksError::AuthenticationFailed(_))
+        ));
+
+        let (status, _) = wire::read_message(&mut client, &mut BytesMut::new(), wire::parse_socks6_auth_reply).await?;
+        assert_eq!(status, SOCKS_AUTH_FAILED);
+        Ok(())
+    }
+
+    // Tests that the handler drives a challenge through the authenticator, passing on the identity it accepted, and
+    // that a wrong answer is rejected.
+    #[tokio::test]
+    async fn test_authenticator_continue() -> Result<()> {
+        for (answer, accepted) in [(&b"ecnon"[..], true), (&b"nonce"[..], false)] {
+            let handler = Socks6Handler::default().with_authenticator(Arc::new(ReverseAuthenticator));
+            let (mut client, mut source) = tokio::io::duplex(4096);
+            let (outbound, mut destination) = tokio::io::duplex(4096);
+            let server = tokio::spawn(async move {
+                let (session, _) = handler.accept(&mut source).await?;
+                let identity = session.identity().clone();
+                session.proxy_via(outbound).await?;
+
+                Ok::<_, anyhow::Error>(identity)
+            });
+
+            let options = vec![AuthMethodAdvertisementOption::new(5, vec![AuthMethod::Gssapi]).wrap()];
+            let request = Socks6Request::new(Socks6Command::Connect, Address::new("192.0.2.1", 80), 5, options, None);
+            client.write_all(&request.into_socks_bytes()).await?;
+            client.write_all(b"hello").await?;
+
+            let mut scratch = BytesMut::new();
+            let (status, options) = wire::read_message(&mut client, &mut scratch, wire::parse_socks6_auth_reply).await?;
+            assert_eq!(status, SOCKS_AUTH_FAILED);
+            assert!(options.iter().any(|o| matches!(o, SocksOption::AuthData(d) if d.data == b"nonce")));
+
+            let mut message = vec![];
+            wire::encode_options(&[AuthDataOption::new(AuthMethod::Gssapi, answer.to_vec()).wrap()], &mut message);
+            client.write_all(&message).await?;
+
+            let (status, options) = wire::read_message(&mut client, &mut scratch, wire::parse_socks6_auth_reply).await?;
+            assert!(!options.iter().any(|o| matches!(o, SocksOption::AuthData(_))));
+            if !accepted {
+                assert_eq!(status, SOCKS_AUTH_FAILED);
+                let error = server.await?.unwrap_err();
+                assert!(matches!(error.downcast_ref(), Some(SocksError::AuthenticationFailed(_))));
+                continue;
+            }
+
+            assert_eq!(status, SOCKS_AUTH_SUCCESS);
+            let (reply_code, _, _) = wire::read_message(&mut client, &mut scratch, wire::parse_socks6_reply).await?;
+            assert_eq!(reply_code, SOCKS_REP_SUCCEEDED);
+
+            // The initial data was read before the answer, and still reaches the destination.
+            let mut received = [0; 5];
+            destination.read_exact(&mut received).await?;
+            assert_eq!(&received, b"hello");
+
+            drop(client);
+            drop(destination);
+            assert_eq!(server.await??, Identity::new("alice"));
+        }
+
+        Ok(())
+    }
+
+    // Authenticator that sends the challenges of a script one by one, expecting each to be answered with the
+    // challenge and the round, and accepts the source as "alice" once the script is done.
+    struct ScriptedAuthenticator(Vec<&'static [u8]>);
+
+    #[async_trait]
+    impl Authenticator for ScriptedAuthenticator {
+        fn methods(&self) -> Vec<AuthMethod> {
+            vec![AuthMethod::Gssapi]
+        }
+
+        async fn authenticate(
+            &self,
+            request: AuthRequest<'_>,
+        ) -> AuthOutcome {
+            if request.round > 0 {
+                let mut expected = self.0[request.round - 1].to_vec();
+                expected.push(b'0' + request.round as u8);
+                if request.data_for(AuthMethod::Gssapi) != Some(&expected[..]) {
+                    return AuthOutcome::Reject(format!("Wrong answer in round {}", request.round));
+                }
+            }
+
+            match self.0.get(request.round) {
+                Some(challenge) => AuthOutcome::Continue(AuthMethod::Gssapi, challenge.to_vec()),
+                None => AuthOutcome::Accept(Identity::new("alice"), AuthMethod::Gssapi),
+            }
+        }
+    }
+
+    // Client authenticator that answers each challenge with the challenge and the round.
+    struct EchoingClientAuthenticator;
+
+    #[async_trait]
+    impl ClientAuthenticator for EchoingClientAuthenticator {
+        fn methods(&self) -> Vec<AuthMethod> {
+            vec![AuthMethod::Gssapi]
+        }
+
+        async fn request_data(&self) -> Result<Vec<AuthDataOption>> {
+            Ok(vec![])
+        }
+
+        async fn respond(
+            &self,
+            method: AuthMethod,
+            challenge: &[u8],
+            round: usize,
+        ) -> Result<Vec<AuthDataOption>> {
+            let mut response = challenge.to_vec();
+            response.push(b'0' + round as u8);
+            Ok(vec![AuthDataOption::new(method, response)])
+        }
+    }
+
+    // Tests a three-round exchange between a client and a handler, both driven by their authenticators, and that
+    // either side gives up on an exchange that takes more rounds than it allows.
+    #[tokio::test]
+    async fn test_three_round_authentication() -> Result<()> {
+        let script: Vec<&'static [u8]> = vec![b"one", b"two", b"three"];
+        let handler = Socks6Handler::default().with_authenticator(Arc::new(ScriptedAuthenticator(script.clone())));
+        let harness = Harness::socks6(handler);
+        let client = Socks6Client::for_streams(None).with_authenticator(Arc::new(EchoingClientAuthenticator));
+        let (mut stream, _) = harness.connect_socks6(&client, "192.0.2.1:80", Some(b"hello".to_vec()), None).await?;
+        assert_eq!(harness.connector().received(0, 5).await, b"hello");
+        stream.write_all(b"world").await?;
+        assert_eq!(harness.connector().received(0, 10).await, b"helloworld");
+
+        let impatient = client.clone().with_max_auth_rounds(2);
+        let error = harness.connect_socks6(&impatient, "192.0.2.1:80", None, None).await.unwrap_err();
+        assert!(error.to_string().contains("after 2 rounds"), "{}", error);
+
+        let handler = Socks6Handler::default()
+            .with_authenticator(Arc::new(ScriptedAuthenticator(script)))
+            .with_max_auth_rounds(2);
+        let harness = Harness::socks6(handler);
+        let error = harness.connect_socks6(&client, "192.0.2.1:80", None, None).await.unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(SocksError::AuthenticationFailed(_))));
+        assert!(harness.connector().destinations().is_empty());
+
+        Ok(())
+    }
+
+    // Tests that credentials attached to the method advertisement authenticate the client without authentication
+    // data options, and that wrong ones are still rejected.
+    #[tokio::test]
+    async fn test_advertised_credentials() -> Result<()> {
+        let handler = Socks6Handler::default().with_credentials(Credentials::new("user", "secret"));
+        let harness = Harness::socks6(handler);
+
+        let authenticator = UserPassAuthenticator::new(Credentials::new("user", "secret")).with_advertised_data(true);
+        let client = Socks6Client::for_streams(None).with_authenticator(Arc::new(authenticator));
+        let (mut stream, _) = harness.connect_socks6(&client, "192.0.2.1:80", Some(b"hi".to_vec()), None).await?;
+        stream.write_all(b"!").await?;
+        assert_eq!(harness.connector().received(0, 3).await, b"hi!");
+
+        let authenticator = UserPassAuthenticator::new(Credentials::new("user", "wrong")).with_advertised_data(true);
+        let client = Socks6Client::for_streams(None).with_authenticator(Arc::new(authenticator));
+        let error = harness.connect_socks6(&client, "192.0.2.1:80", None, None).await.unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(SocksError::AuthenticationFailed(_))));
+
+        // The credentials are for this link, and aren't forwarded to the next.
+        let next = MockSocksServer::socks6();
+        let handler = Socks6Handler::new(vec![ProxyAddress::new(6, String::from("next"), 1080, None)])
+            .with_credentials(Credentials::new("user", "secret"))
+            .with_connector(Arc::new(next.clone()));
+        let authenticator = UserPassAuthenticator::new(Credentials::new("user", "secret")).with_advertised_data(true);
+        let client = Socks6Client::for_streams(None).with_authenticator(Arc::new(authenticator));
+        let (mut stream, mut source) = tokio::io::duplex(4096);
+        tokio::spawn(async move { handler.setup(&mut source).await });
+        client.handshake("192.0.2.1:80", None, None, &mut stream).await?;
+        assert!(next.recordings()[0].options.auth_method_data().is_none());
+
+        Ok(())
+    }
+
+    // Authenticator that rejects every client for a user-defined reason.
+    struct QuotaAuthenticator;
+
+    #[async_trait]
+    impl Authenticator for QuotaAuthenticator {
+        fn methods(&self) -> Vec<AuthMethod> {
+            vec![AuthMethod::UsernamePassword]
+        }
+
+        async fn authenticate(
+            &self,
+            _request: AuthRequest<'_>,
+        ) -> AuthOutcome {
+            let reason = AuthFailureReason::user_defined(0x8001).unwrap();
+            AuthOutcome::RejectWith(reason, String::from("Quota exceeded"))
+        }
+    }
+
+    // Tests that the client learns why its authentication was rejected only when the handler reports reasons, and
+    // that user-defined reasons reach it as well.
+    #[tokio::test]
+    async fn test_auth_failure_reasons() -> Result<()> {
+        let client = Socks6Client::for_streams(Some(Credentials::new("user", "wrong")));
+        let handler = Socks6Handler::default().with_credentials(Credentials::new("user", "secret"));
+
+        let harness = Harness::socks6(handler.clone());
+        let error = harness.connect_socks6(&client, "192.0.2.1:80", None, None).await.unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(SocksError::AuthenticationFailed(None))));
+
+        let harness = Harness::socks6(handler.with_auth_failure_reasons(true));
+        let error = harness.connect_socks6(&client, "192.0.2.1:80", None, None).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(SocksError::AuthenticationFailed(Some(AuthFailureReason::BadCredentials)))
+        ));
+        assert!(error.to_string().ends_with("Authentication failed: bad credentials."), "{}", error);
+
+        let handler = Socks6Handler::default()
+            .with_authenticator(Arc::new(QuotaAuthenticator))
+            .with_auth_failure_reasons(true);
+        let harness = Harness::socks6(handler);
+        let error = harness.connect_socks6(&client, "192.0.2.1:80", None, None).await.unwrap_err();
+        let reason = match error.downcast_ref() {
+            Some(SocksError::AuthenticationFailed(Some(AuthFailureReason::UserDefined(reason)))) => *reason,
+            _ => panic!("Unexpected error: {:?}", error),
+        };
+        assert_eq!(reason.code(), 0x8001);
+        assert!(harness.connector().destinations().is_empty());
+
+        Ok(())
     }
 }
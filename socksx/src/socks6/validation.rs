@@ -0,0 +1,446 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use thiserror::Error;
+
+use crate::addresses::Address;
+use crate::baggage;
+use crate::socks6::options::{SocksOption, SocksOptionKind};
+use crate::socks6::Socks6Request;
+
+/// A problem in a request that parses fine, but doesn't make sense.
+#[derive(Clone, Copy, Debug, Error, Eq, PartialEq)]
+pub enum Finding {
+    /// The destination port is 0.
+    #[error("destination port is 0")]
+    ZeroPort,
+    /// The destination is an empty domain name.
+    #[error("destination domain name is empty")]
+    EmptyDomain,
+    /// The request has an initial data length, but no authentication method advertisement to carry it.
+    #[error("initial data length without an authentication method advertisement")]
+    InitialDataWithoutAdvertisement,
+    /// The request carries more than one authentication method advertisement.
+    #[error("multiple authentication method advertisements")]
+    MultipleAdvertisements,
+    /// An explicit chain option disagrees with the metadata under the same reserved key.
+    #[error("chain metadata key {0} conflicts with an explicit chain option")]
+    ChainKeyConflict(u16),
+    /// An option that may appear at most once appears more than once: the authentication method selection,
+    /// or the authentication data for the same method.
+    #[error("duplicate {0:?} option")]
+    DuplicateOption(SocksOptionKind),
+    /// More than one metadata option has the same key.
+    #[error("duplicate metadata key {0}")]
+    DuplicateMetadataKey(u16),
+    /// The baggage exceeds `MAX_BAGGAGE_ENTRY_LEN` in an entry, or `MAX_BAGGAGE_LEN` in all entries together.
+    #[error("oversized baggage")]
+    OversizedBaggage,
+}
+
+/// How a finding is treated by `Socks6Request::validate`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Severity {
+    /// The finding is not reported.
+    Ignore,
+    /// The finding is logged as a warning, but the request is accepted.
+    Warn,
+    /// The request is rejected.
+    Error,
+}
+
+/// Determines which findings reject a request, and which are only warned about.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ValidationPolicy {
+    pub zero_port: Severity,
+    pub empty_domain: Severity,
+    pub initial_data_without_advertisement: Severity,
+    pub multiple_advertisements: Severity,
+    pub chain_key_conflict: Severity,
+    pub duplicate_option: Severity,
+    pub duplicate_metadata_key: Severity,
+    pub oversized_baggage: Severity,
+}
+
+impl Default for ValidationPolicy {
+    /// Rejects requests that can't be acted upon, or whose options are ambiguous, and warns about an initial
+    /// data length without an advertisement, which is implied when the request is serialized, and about
+    /// duplicate metadata keys, of which the last value is used. Oversized baggage is rejected, so that it isn't
+    /// passed on through a chain.
+    fn default() -> Self {
+        Self {
+            zero_port: Severity::Error,
+            empty_domain: Severity::Error,
+            initial_data_without_advertisement: Severity::Warn,
+            multiple_advertisements: Severity::Error,
+            chain_key_conflict: Severity::Error,
+            duplicate_option: Severity::Error,
+            duplicate_metadata_key: Severity::Warn,
+            oversized_baggage: Severity::Error,
+        }
+    }
+}
+
+impl ValidationPolicy {
+    /// Rejects requests with any finding.
+    pub fn strict() -> Self {
+        Self::all(Severity::Error)
+    }
+
+    /// Accepts requests with any finding, but warns about them.
+    pub fn lenient() -> Self {
+        Self::all(Severity::Warn)
+    }
+
+    // Returns a policy that treats every finding the same.
+    fn all(severity: Severity) -> Self {
+        Self {
+            zero_port: severity,
+            empty_domain: severity,
+            initial_data_without_advertisement: severity,
+            multiple_advertisements: severity,
+            chain_key_conflict: severity,
+            duplicate_option: severity,
+            duplicate_metadata_key: severity,
+            oversized_baggage: severity,
+        }
+    }
+
+    /// Returns how the given finding is treated.
+    pub fn severity(
+        &self,
+        finding: Finding,
+    ) -> Severity {
+        match finding {
+            Finding::ZeroPort => self.zero_port,
+            Finding::EmptyDomain => self.empty_domain,
+            Finding::InitialDataWithoutAdvertisement => self.initial_data_without_advertisement,
+            Finding::MultipleAdvertisements => self.multiple_advertisements,
+            Finding::ChainKeyConflict(_) => self.chain_key_conflict,
+            Finding::DuplicateOption(_) => self.duplicate_option,
+            Finding::DuplicateMetadataKey(_) => self.duplicate_metadata_key,
+            Finding::OversizedBaggage => self.oversized_baggage,
+        }
+    }
+}
+
+/// The findings that made a request invalid under a validation policy.
+#[derive(Clone, Debug, Error, Eq, PartialEq)]
+pub struct ValidationError {
+    /// The findings the policy treats as errors, in the order they were found.
+    pub findings: Vec<Finding>,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        write!(f, "Invalid SOCKS6 request: ")?;
+        for (i, finding) in self.findings.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", finding)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Socks6Request {
+    /// Returns every finding in the request, regardless of policy.
+    pub fn findings(&self) -> Vec<Finding> {
+        let mut findings = vec![];
+
+        let (empty_domain, port) = match &self.destination {
+            Address::Domainname { host, port } => (host.is_empty(), Some(*port)),
+            Address::Ip(address) => (false, Some(address.port())),
+            // Unix socket paths have no port, and are never empty once parsed.
+            Address::Unix(_) => (false, None),
+        };
+        if port == Some(0) {
+            findings.push(Finding::ZeroPort);
+        }
+        if empty_domain {
+            findings.push(Finding::EmptyDomain);
+        }
+
+        let advertisements = self
+            .options
+            .iter()
+            .filter(|option| matches!(option, SocksOption::AuthMethodAdvertisement(_)))
+            .count();
+        if self.initial_data_length > 0 && advertisements == 0 {
+            findings.push(Finding::InitialDataWithoutAdvertisement);
+        }
+        findings.extend(option_findings(&self.options));
+
+        for option in &self.options {
+            if let SocksOption::Metadata(option) = option {
+                let reserved = option.key >= 998;
+                let conflicts = self.metadata.get(&option.key).is_some_and(|value| value != &option.value);
+                if reserved && conflicts && !findings.contains(&Finding::ChainKeyConflict(option.key)) {
+                    findings.push(Finding::ChainKeyConflict(option.key));
+                }
+            }
+        }
+        if baggage::is_oversized(&self.metadata) {
+            findings.push(Finding::OversizedBaggage);
+        }
+
+        findings
+    }
+
+    /// Checks the request for combinations that parse fine, but don't make sense.
+    ///
+    /// Findings the policy treats as warnings are logged.
+    ///
+    /// # Returns
+    /// A `ValidationError` with the findings the policy treats as errors, if there are any.
+    pub fn validate(
+        &self,
+        policy: ValidationPolicy,
+    ) -> Result<(), ValidationError> {
+        judge(self.findings(), policy)
+    }
+}
+
+/// Returns every finding in a list of options on its own, regardless of policy: multiple advertisements,
+/// and duplicate singleton options and metadata keys. Each is reported once, in the order of the options.
+pub fn option_findings(options: &[SocksOption]) -> Vec<Finding> {
+    let mut findings = vec![];
+    let mut advertised = false;
+    let mut selected = false;
+    let mut auth_data = HashSet::new();
+    let mut keys = HashSet::new();
+
+    for option in options {
+        let finding = match option {
+            SocksOption::AuthMethodAdvertisement(_) if advertised => Finding::MultipleAdvertisements,
+            SocksOption::AuthMethodAdvertisement(_) => {
+                advertised = true;
+                continue;
+            }
+            SocksOption::AuthMethodSelection(_) if selected => Finding::DuplicateOption(option.kind()),
+            SocksOption::AuthMethodSelection(_) => {
+                selected = true;
+                continue;
+            }
+            SocksOption::AuthData(data) if !auth_data.insert(data.method.clone() as u8) => {
+                Finding::DuplicateOption(option.kind())
+            }
+            SocksOption::Metadata(metadata) if !keys.insert(metadata.key) => {
+                Finding::DuplicateMetadataKey(metadata.key)
+            }
+            _ => continue,
+        };
+
+        if !findings.contains(&finding) {
+            findings.push(finding);
+        }
+    }
+
+    findings
+}
+
+/// Checks a list of options on its own, e.g., before they are parsed into a request.
+///
+/// Findings the policy treats as warnings are logged.
+///
+/// # Returns
+/// A `ValidationError` with the findings the policy treats as errors, if there are any.
+pub fn validate_options(
+    options: &[SocksOption],
+    policy: ValidationPolicy,
+) -> Result<(), ValidationError> {
+    judge(option_findings(options), policy)
+}
+
+// Sorts the findings by their severity under the policy, logging the warnings.
+fn judge(
+    findings: Vec<Finding>,
+    policy: ValidationPolicy,
+) -> Result<(), ValidationError> {
+    let mut errors = vec![];
+    for finding in findings {
+        match policy.severity(finding) {
+            Severity::Ignore => {}
+            Severity::Warn => warn!("Accepting SOCKS6 request with {}.", finding),
+            Severity::Error => errors.push(finding),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(ValidationError { findings: errors })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::socks6::options::{
+        AuthDataOption, AuthMethod, AuthMethodAdvertisementOption, AuthMethodSelectionOption, MetadataOption,
+    };
+    use crate::socks6::Socks6Command;
+
+    fn connect_request(
+        destination: Address,
+        initial_data_length: u16,
+        options: Vec<SocksOption>,
+    ) -> Socks6Request {
+        Socks6Request::new(Socks6Command::Connect, destination, initial_data_length, options, None)
+    }
+
+    fn advertisement() -> SocksOption {
+        AuthMethodAdvertisementOption::new(0, vec![]).wrap()
+    }
+
+    // Tests that a sensible request has no findings.
+    #[test]
+    fn test_valid_request() {
+        let request = connect_request(Address::new("example.com", 80), 0, vec![]);
+        assert!(request.findings().is_empty());
+        assert_eq!(request.validate(ValidationPolicy::strict()), Ok(()));
+    }
+
+    // Tests that a destination port of 0 is found.
+    #[test]
+    fn test_zero_port() {
+        let request = connect_request(Address::new("192.0.2.1", 0), 0, vec![]);
+        assert_eq!(request.findings(), vec![Finding::ZeroPort]);
+    }
+
+    // Tests that an empty domain name destination is found.
+    #[test]
+    fn test_empty_domain() {
+        let request = connect_request(
+            Address::Domainname {
+                host: String::new(),
+                port: 80,
+            },
+            0,
+            vec![],
+        );
+        assert_eq!(request.findings(), vec![Finding::EmptyDomain]);
+    }
+
+    // Tests that an initial data length is only found without an advertisement.
+    #[test]
+    fn test_initial_data_without_advertisement() {
+        let request = connect_request(Address::new("192.0.2.1", 80), 5, vec![]);
+        assert_eq!(request.findings(), vec![Finding::InitialDataWithoutAdvertisement]);
+
+        let request = connect_request(Address::new("192.0.2.1", 80), 5, vec![advertisement()]);
+        assert!(request.findings().is_empty());
+    }
+
+    // Tests that more than one advertisement is found.
+    #[test]
+    fn test_multiple_advertisements() {
+        let request = connect_request(Address::new("192.0.2.1", 80), 0, vec![advertisement(), advertisement()]);
+        assert_eq!(request.findings(), vec![Finding::MultipleAdvertisements]);
+    }
+
+    // Tests that duplicate singleton options and metadata keys are found once each, by kind.
+    #[test]
+    fn test_duplicate_options() {
+        let options = vec![
+            AuthMethodSelectionOption::new(AuthMethod::NoAuthentication).wrap(),
+            AuthDataOption::new(AuthMethod::UsernamePassword, vec![1]).wrap(),
+            MetadataOption::new(1, String::from("a")).wrap(),
+            AuthDataOption::new(AuthMethod::BearerToken, vec![2]).wrap(),
+            MetadataOption::new(1, String::from("b")).wrap(),
+            AuthMethodSelectionOption::new(AuthMethod::NoAuthentication).wrap(),
+            AuthDataOption::new(AuthMethod::UsernamePassword, vec![3]).wrap(),
+            MetadataOption::new(1, String::from("c")).wrap(),
+        ];
+        assert_eq!(
+            option_findings(&options),
+            vec![
+                Finding::DuplicateMetadataKey(1),
+                Finding::DuplicateOption(SocksOptionKind::AuthMethodSelection),
+                Finding::DuplicateOption(SocksOptionKind::AuthData),
+            ]
+        );
+
+        let error = validate_options(&options, ValidationPolicy::default()).unwrap_err();
+        assert_eq!(
+            error.findings,
+            vec![
+                Finding::DuplicateOption(SocksOptionKind::AuthMethodSelection),
+                Finding::DuplicateOption(SocksOptionKind::AuthData),
+            ]
+        );
+        assert_eq!(validate_options(&options, ValidationPolicy::lenient()), Ok(()));
+
+        let request = connect_request(Address::new("192.0.2.1", 80), 0, options);
+        assert_eq!(request.findings().len(), 3);
+    }
+
+    // Tests that chain options disagreeing with the metadata are found, but other metadata isn't.
+    #[test]
+    fn test_chain_key_conflict() {
+        let options = vec![
+            MetadataOption::new(1, String::from("a")).wrap(),
+            MetadataOption::new(999, String::from("2")).wrap(),
+            MetadataOption::new(1000, String::from("socks6://a:1080")).wrap(),
+        ];
+        let mut metadata = HashMap::new();
+        metadata.insert(1, String::from("b"));
+        metadata.insert(999, String::from("3"));
+        metadata.insert(1000, String::from("socks6://a:1080"));
+
+        let request = Socks6Request::new(
+            Socks6Command::Connect,
+            Address::new("192.0.2.1", 80),
+            0,
+            options,
+            Some(metadata),
+        );
+        assert_eq!(request.findings(), vec![Finding::ChainKeyConflict(999)]);
+    }
+
+    // Tests that a baggage entry beyond its limit is found, but metadata outside the baggage keys isn't.
+    #[test]
+    fn test_oversized_baggage() {
+        let large = format!("trace={}", "x".repeat(baggage::MAX_BAGGAGE_ENTRY_LEN));
+        let request_with = |key| {
+            let metadata = HashMap::from([(key, large.clone())]);
+            Socks6Request::new(Socks6Command::Connect, Address::new("192.0.2.1", 80), 0, vec![], Some(metadata))
+        };
+        assert!(request_with(899).findings().is_empty());
+
+        let request = request_with(900);
+        assert_eq!(request.findings(), vec![Finding::OversizedBaggage]);
+        assert!(request.validate(ValidationPolicy::default()).is_err());
+    }
+
+    // Tests that the policy decides which findings reject the request.
+    #[test]
+    fn test_validate_policy() {
+        let request = connect_request(Address::new("192.0.2.1", 0), 5, vec![]);
+
+        let error = request.validate(ValidationPolicy::default()).unwrap_err();
+        assert_eq!(error.findings, vec![Finding::ZeroPort]);
+        assert_eq!(
+            error.to_string(),
+            "Invalid SOCKS6 request: destination port is 0"
+        );
+
+        let error = request.validate(ValidationPolicy::strict()).unwrap_err();
+        assert_eq!(error.findings, vec![Finding::ZeroPort, Finding::InitialDataWithoutAdvertisement]);
+
+        assert_eq!(request.validate(ValidationPolicy::lenient()), Ok(()));
+
+        let policy = ValidationPolicy {
+            zero_port: Severity::Ignore,
+            ..ValidationPolicy::default()
+        };
+        assert_eq!(request.validate(policy), Ok(()));
+    }
+}
@@ -0,0 +1,839 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::BytesMut;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf};
+use tokio::net::TcpListener;
+
+use crate::constants::*;
+use crate::dialer::Connector;
+use crate::interface::AsyncStream;
+use crate::resolver::Resolver;
+use crate::socks6::options::{AuthDataOption, AuthMethod, AuthMethodSelectionOption, SocksOption, SocksOptions};
+use crate::socks6;
+use crate::{wire, Address, Credentials, Socks5Handler, Socks6Client, Socks6Handler, SocksHandler};
+
+/// How long `MockConnector::received` waits for the bytes of a destination.
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The points in a handshake at which a `MockSocksServer` can be told to wait.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Phase {
+    /// Before the authentication method selection (SOCKS5), or the authentication reply (SOCKS6).
+    MethodSelection,
+    /// Before the status of the username/password authentication (SOCKS5 only).
+    Authentication,
+    /// Before the operation reply.
+    Reply,
+}
+
+/// Everything a client sent to a `MockSocksServer` over one connection.
+#[derive(Clone, Debug, Default)]
+pub struct Recording {
+    /// All bytes the client sent, in order.
+    pub bytes: Vec<u8>,
+    /// The authentication methods the client proposed (SOCKS5 only).
+    pub methods: Vec<u8>,
+    /// The credentials the client authenticated with.
+    pub credentials: Option<Credentials>,
+    /// The destination of the request.
+    pub destination: Option<Address>,
+    /// The options of the request (SOCKS6 only).
+    pub options: Vec<SocksOption>,
+    /// The method-specific data the client answered each challenge with (SOCKS6 only).
+    pub responses: Vec<Vec<u8>>,
+    /// The initial data sent along with the request (SOCKS6 only).
+    pub initial_data: Vec<u8>,
+    /// The bytes the client sent through the tunnel after a successful reply.
+    pub tunnel: Vec<u8>,
+}
+
+/// A SOCKS server that runs a scripted handshake, for testing clients.
+///
+/// The server accepts every request with a success reply unless configured otherwise. After a
+/// successful reply it acts as the destination, and echoes everything sent through the tunnel.
+/// What each client sent is recorded for later assertions.
+#[derive(Clone)]
+pub struct MockSocksServer {
+    version: u8,
+    reply: u8,
+    credentials: Option<Credentials>,
+    auth_version: u8,
+    challenges: Vec<(AuthMethod, Vec<u8>)>,
+    delays: HashMap<Phase, Duration>,
+    binding: Address,
+    reply_options: Vec<SocksOption>,
+    drop_initial_data: bool,
+    report_dropped_initial_data: bool,
+    metadata_compression: bool,
+    recordings: Arc<Mutex<Vec<Recording>>>,
+}
+
+impl MockSocksServer {
+    /// Creates a mock SOCKS5 server.
+    pub fn socks5() -> Self {
+        Self::new(SOCKS_VER_5)
+    }
+
+    /// Creates a mock SOCKS6 server.
+    pub fn socks6() -> Self {
+        Self::new(SOCKS_VER_6)
+    }
+
+    fn new(version: u8) -> Self {
+        Self {
+            version,
+            reply: SOCKS_REP_SUCCEEDED,
+            credentials: None,
+            auth_version: SOCKS_AUTH_VER,
+            challenges: vec![],
+            delays: HashMap::new(),
+            binding: Address::new("0.0.0.0", 0),
+            reply_options: vec![],
+            drop_initial_data: false,
+            report_dropped_initial_data: false,
+            metadata_compression: false,
+            recordings: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    /// Answers requests with the given reply code, e.g., `SOCKS_REP_CONNECTION_REFUSED`.
+    pub fn with_reply(
+        mut self,
+        reply: u8,
+    ) -> Self {
+        self.reply = reply;
+        self
+    }
+
+    /// Requires username/password authentication with the given credentials.
+    pub fn with_credentials(
+        mut self,
+        credentials: Credentials,
+    ) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// Answers username/password authentication with the given sub-negotiation version byte, instead of 0x01
+    /// (SOCKS5 only).
+    pub fn with_auth_version(
+        mut self,
+        version: u8,
+    ) -> Self {
+        self.auth_version = version;
+        self
+    }
+
+    /// Sends the given challenges for the method one by one before deciding on authentication, accepting any
+    /// response (SOCKS6 only).
+    pub fn with_challenges(
+        mut self,
+        method: AuthMethod,
+        challenges: Vec<Vec<u8>>,
+    ) -> Self {
+        self.challenges = challenges.into_iter().map(|challenge| (method.clone(), challenge)).collect();
+        self
+    }
+
+    /// Waits for the given duration before answering at the given phase.
+    pub fn with_delay(
+        mut self,
+        phase: Phase,
+        delay: Duration,
+    ) -> Self {
+        self.delays.insert(phase, delay);
+        self
+    }
+
+    /// Sets the address announced in successful replies.
+    pub fn with_binding(
+        mut self,
+        binding: Address,
+    ) -> Self {
+        self.binding = binding;
+        self
+    }
+
+    /// Sets the options sent along with operation replies (SOCKS6 only).
+    pub fn with_reply_options(
+        mut self,
+        options: Vec<SocksOption>,
+    ) -> Self {
+        self.reply_options = options;
+        self
+    }
+
+    /// Discards the initial data instead of passing it on to the destination, and reports that in the operation
+    /// reply with `socks6::initial_data_option` (SOCKS6 only).
+    pub fn with_dropped_initial_data(mut self) -> Self {
+        self.drop_initial_data = true;
+        self.report_dropped_initial_data = true;
+        self
+    }
+
+    /// Discards the initial data instead of passing it on to the destination, without reporting it, like a proxy
+    /// that doesn't support initial data (SOCKS6 only).
+    pub fn with_silently_dropped_initial_data(mut self) -> Self {
+        self.drop_initial_data = true;
+        self
+    }
+
+    /// Advertises support for compressed metadata to clients that advertise it as well (SOCKS6 only).
+    pub fn with_metadata_compression(mut self) -> Self {
+        self.metadata_compression = true;
+        self
+    }
+
+    /// Returns what the clients sent so far, one recording per connection.
+    pub fn recordings(&self) -> Vec<Recording> {
+        self.recordings.lock().unwrap().clone()
+    }
+
+    /// Listens on a random loopback port, and serves every connection in the background.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the address to connect to, or an error.
+    pub async fn bind(&self) -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let server = self.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let server = server.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = server.serve(stream).await {
+                        debug!("Mock SOCKS server failed: {}", e);
+                    }
+                });
+            }
+        });
+
+        Ok(addr)
+    }
+
+    /// Serves a single in-memory connection in the background.
+    ///
+    /// # Returns
+    ///
+    /// The client's end of the connection.
+    pub fn duplex(&self) -> DuplexStream {
+        let (client, server_end) = tokio::io::duplex(64 * 1024);
+
+        let server = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = server.serve(server_end).await {
+                debug!("Mock SOCKS server failed: {}", e);
+            }
+        });
+
+        client
+    }
+
+    /// Runs the scripted handshake over the given stream, followed by the echoing tunnel.
+    pub async fn serve<S>(
+        &self,
+        stream: S,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let index = {
+            let mut recordings = self.recordings.lock().unwrap();
+            recordings.push(Recording::default());
+            recordings.len() - 1
+        };
+
+        let mut stream = Tap {
+            inner: stream,
+            recordings: Arc::clone(&self.recordings),
+            index,
+        };
+
+        let accepted = if self.version == SOCKS_VER_5 {
+            self.serve_socks5(&mut stream, index).await?
+        } else {
+            self.serve_socks6(&mut stream, index).await?
+        };
+
+        if accepted {
+            self.echo(&mut stream, index).await?;
+        }
+
+        Ok(())
+    }
+
+    // Runs the SOCKS5 script, returning whether the request was accepted.
+    async fn serve_socks5<S>(
+        &self,
+        stream: &mut S,
+        index: usize,
+    ) -> Result<bool>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut scratch = BytesMut::new();
+
+        let methods = wire::read_message(stream, &mut scratch, wire::parse_socks5_greeting).await?;
+        self.record(index, |r| r.methods = methods.clone());
+
+        let method = match &self.credentials {
+            Some(_) if methods.contains(&SOCKS_AUTH_USERNAME_PASSWORD) => SOCKS_AUTH_USERNAME_PASSWORD,
+            None if methods.contains(&SOCKS_AUTH_NOT_REQUIRED) => SOCKS_AUTH_NOT_REQUIRED,
+            _ => SOCKS_AUTH_NO_ACCEPTABLE_METHODS,
+        };
+
+        self.delay(Phase::MethodSelection).await;
+        let mut reply = vec![];
+        wire::encode_socks5_method_selection(method, &mut reply);
+        stream.write_all(&reply).await?;
+
+        if method == SOCKS_AUTH_NO_ACCEPTABLE_METHODS {
+            return Ok(false);
+        }
+
+        if method == SOCKS_AUTH_USERNAME_PASSWORD {
+            let credentials = wire::read_message(stream, &mut scratch, wire::parse_socks5_credentials).await?;
+            let status = if Some(&credentials) == self.credentials.as_ref() {
+                SOCKS_AUTH_SUCCESS
+            } else {
+                SOCKS_AUTH_FAILED
+            };
+            self.record(index, |r| r.credentials = Some(credentials.clone()));
+
+            self.delay(Phase::Authentication).await;
+            stream.write_all(&[self.auth_version, status]).await?;
+
+            if status != SOCKS_AUTH_SUCCESS {
+                return Ok(false);
+            }
+        }
+
+        let request = wire::read_message(stream, &mut scratch, wire::parse_socks5_request).await?;
+        self.record(index, |r| r.destination = Some(request.destination.clone()));
+
+        self.delay(Phase::Reply).await;
+        let mut reply = vec![];
+        wire::encode_socks5_reply(self.reply, &self.binding, &mut reply);
+        stream.write_all(&reply).await?;
+
+        Ok(self.reply == SOCKS_REP_SUCCEEDED)
+    }
+
+    // Runs the SOCKS6 script, returning whether the request was accepted.
+    async fn serve_socks6<S>(
+        &self,
+        stream: &mut S,
+        index: usize,
+    ) -> Result<bool>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let request = wire::read_message(stream, &mut BytesMut::new(), wire::parse_socks6_request).await?;
+        let credentials = request.options.iter().find_map(|option| match option {
+            SocksOption::AuthData(data) if data.method == AuthMethod::UsernamePassword => {
+                match wire::parse_socks5_credentials(&data.data) {
+                    Ok(wire::Parsed::Complete(credentials, _)) => Some(credentials),
+                    _ => None,
+                }
+            }
+            _ => None,
+        });
+
+        self.record(index, |r| {
+            r.destination = Some(request.destination.clone());
+            r.options = request.options.clone();
+            r.credentials = credentials.clone();
+        });
+
+        let (status, method) = match (&self.credentials, &credentials) {
+            (None, _) => (SOCKS_AUTH_SUCCESS, None),
+            (Some(_), None) => (SOCKS_AUTH_FAILED, Some(AuthMethod::NoAcceptableMethods)),
+            (Some(expected), Some(offered)) => {
+                let status = if offered == expected { SOCKS_AUTH_SUCCESS } else { SOCKS_AUTH_FAILED };
+                (status, Some(AuthMethod::UsernamePassword))
+            }
+        };
+        let mut options: Vec<_> = method.map(|m| AuthMethodSelectionOption::new(m).wrap()).into_iter().collect();
+        if self.metadata_compression && socks6::supports_metadata_compression(&request.options) {
+            options.push(socks6::metadata_compression_option());
+        }
+
+        self.delay(Phase::MethodSelection).await;
+
+        // The initial data precedes the response to a challenge on the stream.
+        let mut initial_data = vec![0; request.initial_data_length as usize];
+        if !self.challenges.is_empty() {
+            stream.read_exact(&mut initial_data).await?;
+        }
+        for (method, challenge) in &self.challenges {
+            let options = vec![
+                AuthMethodSelectionOption::new(method.clone()).wrap(),
+                AuthDataOption::new(method.clone(), challenge.clone()).wrap(),
+            ];
+            let mut reply = vec![];
+            wire::encode_socks6_auth_reply(SOCKS_AUTH_FAILED, &options, &mut reply);
+            stream.write_all(&reply).await?;
+
+            let response = wire::read_message(stream, &mut BytesMut::new(), wire::parse_options).await?;
+            let response = response.auth_data(method).map(|data| data.data.clone());
+            self.record(index, |r| r.responses.push(response.clone().unwrap_or_default()));
+        }
+
+        let mut reply = vec![];
+        wire::encode_socks6_auth_reply(status, &options, &mut reply);
+        stream.write_all(&reply).await?;
+
+        if status != SOCKS_AUTH_SUCCESS {
+            return Ok(false);
+        }
+
+        if self.challenges.is_empty() {
+            stream.read_exact(&mut initial_data).await?;
+        }
+        self.record(index, |r| r.initial_data = initial_data.clone());
+
+        let mut reply_options = self.reply_options.clone();
+        if self.report_dropped_initial_data {
+            reply_options.push(socks6::initial_data_option(0));
+        }
+        if self.drop_initial_data {
+            initial_data.clear();
+        }
+
+        self.delay(Phase::Reply).await;
+        let mut reply = vec![];
+        wire::encode_socks6_reply(self.reply, &self.binding, &reply_options, &mut reply);
+        stream.write_all(&reply).await?;
+
+        if self.reply == SOCKS_REP_SUCCEEDED {
+            // The initial data was meant for the destination, so it's echoed as well.
+            stream.write_all(&initial_data).await?;
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    // Echoes everything sent through the tunnel until the client closes it.
+    async fn echo<S>(
+        &self,
+        stream: &mut Tap<S>,
+        index: usize,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut buffer = [0; 4096];
+        loop {
+            let n = stream.inner.read(&mut buffer).await?;
+            if n == 0 {
+                return Ok(());
+            }
+
+            self.record(index, |r| {
+                r.bytes.extend_from_slice(&buffer[..n]);
+                r.tunnel.extend_from_slice(&buffer[..n]);
+            });
+            stream.write_all(&buffer[..n]).await?;
+        }
+    }
+
+    async fn delay(
+        &self,
+        phase: Phase,
+    ) {
+        if let Some(delay) = self.delays.get(&phase) {
+            tokio::time::sleep(*delay).await;
+        }
+    }
+
+    fn record<F: FnOnce(&mut Recording)>(
+        &self,
+        index: usize,
+        update: F,
+    ) {
+        update(&mut self.recordings.lock().unwrap()[index]);
+    }
+}
+
+/// A connection handed out by a `MockConnector`, and what was sent through it.
+#[derive(Clone, Debug)]
+pub struct MockDestination {
+    /// The address the handler connected to, as parsed from the request.
+    pub address: Address,
+    /// The bytes the handler delivered to the destination.
+    pub received: Vec<u8>,
+}
+
+/// A `Connector` whose connections lead to in-memory destinations.
+///
+/// The destinations echo and record everything they receive.
+#[derive(Clone, Default)]
+pub struct MockConnector {
+    destinations: Arc<Mutex<Vec<MockDestination>>>,
+    error: Option<io::ErrorKind>,
+    delay: Option<Duration>,
+}
+
+impl MockConnector {
+    /// Creates a connector whose connects always succeed.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fails every connect with the given kind of error, e.g., `ConnectionRefused`.
+    pub fn with_error(
+        mut self,
+        kind: io::ErrorKind,
+    ) -> Self {
+        self.error = Some(kind);
+        self
+    }
+
+    /// Delays every connect, whether it succeeds or fails, e.g., to simulate a slow destination.
+    pub fn with_delay(
+        mut self,
+        delay: Duration,
+    ) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+
+    /// Returns the destinations connected to so far, in order.
+    pub fn destinations(&self) -> Vec<MockDestination> {
+        self.destinations.lock().unwrap().clone()
+    }
+
+    /// Waits until the destination at `index` has received at least `length` bytes, and returns them.
+    ///
+    /// Panics if they aren't received within 5 seconds.
+    pub async fn received(
+        &self,
+        index: usize,
+        length: usize,
+    ) -> Vec<u8> {
+        let receiving = async {
+            loop {
+                let received = self.destinations.lock().unwrap().get(index).map(|d| d.received.clone());
+                match received {
+                    Some(received) if received.len() >= length => return received,
+                    _ => tokio::time::sleep(Duration::from_millis(1)).await,
+                }
+            }
+        };
+
+        match tokio::time::timeout(RECEIVE_TIMEOUT, receiving).await {
+            Ok(received) => received,
+            Err(_) => panic!("Destination {} didn't receive {} bytes: {:?}", index, length, self.destinations()),
+        }
+    }
+}
+
+/// Every connect gets a new connection to the server, so it can be the next link of a handler's chain.
+#[async_trait]
+impl Connector for MockSocksServer {
+    async fn connect(
+        &self,
+        _address: &Address,
+    ) -> Result<Box<dyn AsyncStream>> {
+        Ok(Box::new(self.duplex()))
+    }
+}
+
+#[async_trait]
+impl Connector for MockConnector {
+    async fn connect(
+        &self,
+        address: &Address,
+    ) -> Result<Box<dyn AsyncStream>> {
+        if let Some(delay) = self.delay {
+            tokio::time::sleep(delay).await;
+        }
+        if let Some(kind) = self.error {
+            return Err(io::Error::from(kind).into());
+        }
+
+        let index = {
+            let mut destinations = self.destinations.lock().unwrap();
+            destinations.push(MockDestination {
+                address: address.clone(),
+                received: vec![],
+            });
+            destinations.len() - 1
+        };
+
+        let (stream, mut destination) = tokio::io::duplex(64 * 1024);
+        let destinations = Arc::clone(&self.destinations);
+        tokio::spawn(async move {
+            let mut buffer = [0; 4096];
+            while let Ok(n) = destination.read(&mut buffer).await {
+                if n == 0 {
+                    break;
+                }
+
+                destinations.lock().unwrap()[index].received.extend_from_slice(&buffer[..n]);
+                if destination.write_all(&buffer[..n]).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Box::new(stream))
+    }
+}
+
+/// A `Resolver` that takes a minute to answer with an unroutable TEST-NET address, for testing the connect timeouts
+/// of handlers, typically on a paused clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StallingResolver;
+
+#[async_trait]
+impl Resolver for StallingResolver {
+    async fn resolve(
+        &self,
+        _host: &str,
+    ) -> Result<Vec<std::net::IpAddr>> {
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        Ok(vec!["192.0.2.1".parse()?])
+    }
+}
+
+/// Pairs a handler with clients over in-memory streams, without binding any ports.
+///
+/// The handler connects to the destinations of a `MockConnector`, so tests can check both the
+/// requests the handler parsed and the bytes it delivered.
+pub struct Harness {
+    handler: Arc<dyn SocksHandler + Send + Sync>,
+    connector: MockConnector,
+}
+
+impl Harness {
+    /// Creates a harness around a SOCKS5 handler, replacing its connector.
+    pub fn socks5(handler: Socks5Handler) -> Self {
+        Self::with_connector_socks5(handler, MockConnector::new())
+    }
+
+    /// Creates a harness around a SOCKS6 handler, replacing its connector.
+    pub fn socks6(handler: Socks6Handler) -> Self {
+        Self::with_connector_socks6(handler, MockConnector::new())
+    }
+
+    /// Creates a harness around a SOCKS5 handler that uses the given connector.
+    pub fn with_connector_socks5(
+        handler: Socks5Handler,
+        connector: MockConnector,
+    ) -> Self {
+        let handler = handler.with_connector(Arc::new(connector.clone()));
+        Self {
+            handler: Arc::new(handler),
+            connector,
+        }
+    }
+
+    /// Creates a harness around a SOCKS6 handler that uses the given connector.
+    pub fn with_connector_socks6(
+        handler: Socks6Handler,
+        connector: MockConnector,
+    ) -> Self {
+        let handler = handler.with_connector(Arc::new(connector.clone()));
+        Self {
+            handler: Arc::new(handler),
+            connector,
+        }
+    }
+
+    /// Returns the handler, e.g., to update its policy.
+    pub fn handler(&self) -> &Arc<dyn SocksHandler + Send + Sync> {
+        &self.handler
+    }
+
+    /// Returns the connector of the handler.
+    pub fn connector(&self) -> &MockConnector {
+        &self.connector
+    }
+
+    /// Returns a stream to the handler, which serves it in the background.
+    pub fn stream(&self) -> DuplexStream {
+        let (client, mut source) = tokio::io::duplex(64 * 1024);
+
+        let handler = Arc::clone(&self.handler);
+        tokio::spawn(async move {
+            if let Err(e) = handler.accept_request(&mut source).await {
+                debug!("Handler failed in harness: {}", e);
+            }
+        });
+
+        client
+    }
+
+    /// Connects the SOCKS6 client to a destination through the handler.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing a tuple of the tunnel to the destination and the bound `Address`, or an error.
+    pub async fn connect_socks6<A>(
+        &self,
+        client: &Socks6Client,
+        destination: A,
+        initial_data: Option<Vec<u8>>,
+        options: Option<Vec<SocksOption>>,
+    ) -> Result<(DuplexStream, Address)>
+    where
+        A: TryInto<Address>,
+        A::Error: Into<anyhow::Error>,
+    {
+        client
+            .connect_with_stream(self.stream(), destination, initial_data, options)
+            .await
+    }
+}
+
+/// Encoded SOCKS6 requests that every parser of requests should agree on, each with a name for assertion messages.
+///
+/// They cover a request without options, one with metadata, and one whose options come in an unusual order:
+/// an unrecognized option first, and the advertisement with the initial data length last.
+#[cfg(test)]
+pub(crate) fn request_vectors() -> Vec<(&'static str, Vec<u8>)> {
+    use crate::socks6::options::{AuthMethodAdvertisementOption, MetadataOption, UnrecognizedOption};
+    use crate::socks6::{Socks6Command, Socks6Request};
+
+    let request = |options, initial_data_length| {
+        let destination = Address::new("example.com", 443);
+        Socks6Request::new(Socks6Command::Connect, destination, initial_data_length, options, None).into_socks_bytes()
+    };
+
+    vec![
+        ("no options", request(vec![], 0)),
+        (
+            "metadata",
+            request(
+                vec![
+                    MetadataOption::new(7, String::from("seven")).wrap(),
+                    MetadataOption::new(1, String::from("one")).wrap(),
+                ],
+                0,
+            ),
+        ),
+        (
+            "unusual ordering",
+            request(
+                vec![
+                    UnrecognizedOption::new(0x1234, vec![1, 2, 3]).wrap(),
+                    MetadataOption::new(1, String::from("one")).wrap(),
+                    AuthMethodAdvertisementOption::new(3, vec![AuthMethod::NoAuthentication]).wrap(),
+                ],
+                3,
+            ),
+        ),
+    ]
+}
+
+// Stream wrapper that records every byte read from the client.
+struct Tap<S> {
+    inner: S,
+    recordings: Arc<Mutex<Vec<Recording>>>,
+    index: usize,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Tap<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+
+        if let Poll::Ready(Ok(())) = result {
+            let index = self.index;
+            self.recordings.lock().unwrap()[index]
+                .bytes
+                .extend_from_slice(&buf.filled()[before..]);
+        }
+
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Tap<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socks6::options::MetadataOption;
+
+    // Tests a tunnel from a SOCKS6 client, through the handler, to an in-memory destination.
+    #[tokio::test]
+    async fn test_harness_socks6() -> Result<()> {
+        let harness = Harness::socks6(Socks6Handler::default());
+        let client = Socks6Client::for_streams(None);
+
+        let options = vec![MetadataOption::new(1, String::from("value")).wrap()];
+        let (mut stream, _) = harness
+            .connect_socks6(&client, "example.com:443".to_string(), Some(b"hello".to_vec()), Some(options))
+            .await?;
+
+        stream.write_all(b" world").await?;
+        let mut echoed = [0; 11];
+        stream.read_exact(&mut echoed).await?;
+        assert_eq!(&echoed, b"hello world");
+
+        let destinations = harness.connector().destinations();
+        assert_eq!(destinations.len(), 1);
+        assert_eq!(destinations[0].address, Address::new("example.com", 443));
+        assert_eq!(harness.connector().received(0, 11).await, b"hello world".to_vec());
+        Ok(())
+    }
+
+    // Tests that a failing connect is answered with the matching reply.
+    #[tokio::test]
+    async fn test_harness_connect_error() -> Result<()> {
+        let connector = MockConnector::new().with_error(io::ErrorKind::ConnectionRefused);
+        let harness = Harness::with_connector_socks5(Socks5Handler::default(), connector);
+
+        let mut stream = harness.stream();
+        let mut handshake = vec![];
+        wire::encode_socks5_greeting(&[SOCKS_AUTH_NOT_REQUIRED], &mut handshake);
+        let request = crate::socks5::Socks5Request::new(SOCKS_CMD_CONNECT, Address::new("192.0.2.1", 80));
+        wire::encode_socks5_request(&request, &mut handshake);
+        stream.write_all(&handshake).await?;
+
+        let mut replies = [0; 12];
+        stream.read_exact(&mut replies).await?;
+        assert_eq!(replies[3], SOCKS_REP_CONNECTION_REFUSED);
+        Ok(())
+    }
+}
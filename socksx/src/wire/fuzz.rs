@@ -0,0 +1,295 @@
+// Arbitrary messages, and entry points for fuzzing the parsers, e.g., from the cargo-fuzz harnesses in `fuzz/`.
+//
+// The `Arbitrary` implementations only produce values that can be encoded, e.g., domain names of at most 255
+// bytes, and options that fit in an options block, so that every value they produce is worth a roundtrip.
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+
+use arbitrary::{Arbitrary, Unstructured};
+
+use crate::addresses::Address;
+use crate::constants::*;
+use crate::socks6::options::{
+    AuthDataOption, AuthMethod, AuthMethodAdvertisementOption, AuthMethodSelectionOption, MetadataOption, SocksOption,
+    SocksOptionKind, UnrecognizedOption,
+};
+use crate::socks6::{ParseMode, Socks6Draft, Socks6Request};
+use crate::wire::{self, Parsed};
+
+// The most bytes of data an arbitrary option carries, so that a request with several of them stays well within
+// the 65535 bytes of an options block.
+const MAX_DATA_LEN: usize = 255;
+
+// The most options, and metadata entries, an arbitrary request carries.
+const MAX_OPTIONS: usize = 8;
+
+// The characters of arbitrary domain names and Unix socket paths.
+const NAME_CHARACTERS: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789-.";
+
+/// Parses the bytes as a SOCKS6 request, of every draft revision and in every parse mode, and encodes the
+/// requests that parse. Never panics, whatever the bytes.
+pub fn fuzz_parse_request(bytes: &[u8]) {
+    for draft in [Socks6Draft::Draft11, Socks6Draft::Draft13] {
+        for mode in [ParseMode::Strict, ParseMode::Lenient, ParseMode::Recover] {
+            if let Ok(Parsed::Complete(request, _)) = wire::parse_socks6_request_with(bytes, draft, mode) {
+                let mut encoded = vec![];
+                request.write_socks_bytes_for(draft, &mut encoded);
+            }
+        }
+    }
+}
+
+/// Checks that options survive a roundtrip: parsing, encoding, and parsing them again yields the same options. The
+/// options are those of the bytes as an options block, if they parse strictly, and the option that the bytes
+/// describe as arbitrary input.
+///
+/// # Returns
+/// Whether the options survived, which a fuzz harness asserts. Never panics, whatever the bytes.
+pub fn fuzz_roundtrip_option(bytes: &[u8]) -> bool {
+    let parsed = wire::parse_options_with(bytes, Socks6Draft::default(), ParseMode::Strict);
+    if let Ok(Parsed::Complete((options, _), _)) = parsed {
+        if !options_roundtrip(&options) {
+            return false;
+        }
+    }
+
+    match SocksOption::arbitrary(&mut Unstructured::new(bytes)) {
+        Ok(option) => {
+            let mut encoded = vec![];
+            wire::encode_options(&[option], &mut encoded);
+            match wire::parse_options(&encoded) {
+                Ok(Parsed::Complete(options, _)) => options_roundtrip(&options),
+                _ => false,
+            }
+        }
+        Err(_) => true,
+    }
+}
+
+/// Checks that a request survives a roundtrip: parsing, encoding, and parsing it again yields the same request,
+/// apart from the order of metadata.
+///
+/// # Returns
+/// Whether the request survived, or `true` if the bytes aren't a request that parses strictly. Never panics,
+/// whatever the bytes.
+pub fn fuzz_roundtrip_request(bytes: &[u8]) -> bool {
+    let parsed = wire::parse_socks6_request_with(bytes, Socks6Draft::default(), ParseMode::Strict);
+    let Ok(Parsed::Complete(parsed, _)) = parsed else {
+        return true;
+    };
+
+    let mut encoded = vec![];
+    wire::encode_socks6_request(&parsed, &mut encoded);
+    match wire::parse_socks6_request(&encoded) {
+        Ok(Parsed::Complete(reparsed, _)) => same_request(&parsed, &reparsed),
+        _ => false,
+    }
+}
+
+// Returns whether the options parse into the same options once they are encoded.
+fn options_roundtrip(options: &[SocksOption]) -> bool {
+    let mut encoded = vec![];
+    wire::encode_options(options, &mut encoded);
+    match wire::parse_options(&encoded) {
+        Ok(Parsed::Complete(reparsed, _)) => options == reparsed,
+        _ => false,
+    }
+}
+
+// Returns whether the requests are the same, apart from the order of metadata, which the encoding sorts by key.
+fn same_request(
+    a: &Socks6Request,
+    b: &Socks6Request,
+) -> bool {
+    let others = |request: &Socks6Request| -> Vec<SocksOption> {
+        request.options.iter().filter(|o| !matches!(o, SocksOption::Metadata(_))).cloned().collect()
+    };
+
+    a.command == b.command
+        && a.destination == b.destination
+        && a.initial_data_length == b.initial_data_length
+        && a.metadata == b.metadata
+        && others(a) == others(b)
+}
+
+// Returns at most `max` arbitrary bytes.
+fn bounded_bytes(
+    u: &mut Unstructured<'_>,
+    max: usize,
+) -> arbitrary::Result<Vec<u8>> {
+    let length = u.int_in_range(0..=max)?;
+    Ok(u.bytes(length.min(u.len()))?.to_vec())
+}
+
+// Returns a name of 1 to `max` characters, starting with a letter, so that it doesn't parse as an IP address.
+fn name(
+    u: &mut Unstructured<'_>,
+    max: usize,
+) -> arbitrary::Result<String> {
+    let length = u.int_in_range(1..=max)?;
+    let mut name = String::with_capacity(length);
+    name.push(*u.choose(&NAME_CHARACTERS[..26])? as char);
+    for _ in 1..length {
+        name.push(*u.choose(NAME_CHARACTERS)? as char);
+    }
+
+    Ok(name)
+}
+
+impl<'a> Arbitrary<'a> for Address {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        let port = u.arbitrary()?;
+        let address = match u.int_in_range(0..=3)? {
+            0 => Address::Ip(SocketAddr::new(IpAddr::V4(u.arbitrary()?), port)),
+            1 => Address::Ip(SocketAddr::new(IpAddr::V6(u.arbitrary()?), port)),
+            2 => Address::Domainname {
+                host: name(u, u8::MAX as usize)?,
+                port,
+            },
+            _ => Address::Unix(PathBuf::from(format!("/{}", name(u, u8::MAX as usize - 1)?))),
+        };
+
+        Ok(address)
+    }
+}
+
+impl<'a> Arbitrary<'a> for AuthMethodAdvertisementOption {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        // Parsing drops the methods that are implied, or that aren't methods.
+        let methods: Vec<AuthMethod> = u.arbitrary()?;
+        let methods = methods
+            .into_iter()
+            .filter(|m| !matches!(m, AuthMethod::NoAuthentication | AuthMethod::NoAcceptableMethods))
+            .collect();
+
+        Ok(Self::new(u.arbitrary()?, methods))
+    }
+}
+
+impl<'a> Arbitrary<'a> for AuthMethodSelectionOption {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Self::new(u.arbitrary()?))
+    }
+}
+
+impl<'a> Arbitrary<'a> for AuthDataOption {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        // Zero bytes at the end of the data are taken for padding.
+        let mut data = bounded_bytes(u, MAX_DATA_LEN)?;
+        data.truncate(data.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1));
+
+        Ok(Self::new(u.arbitrary()?, data))
+    }
+}
+
+impl<'a> Arbitrary<'a> for MetadataOption {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        let value: String = u.arbitrary()?;
+        let end = (0..=value.len().min(MAX_DATA_LEN)).rev().find(|&i| value.is_char_boundary(i)).unwrap_or(0);
+
+        Ok(Self::new(u.arbitrary()?, value[..end].to_string()))
+    }
+}
+
+impl<'a> Arbitrary<'a> for UnrecognizedOption {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        // Kinds that parse into another option, or into metadata, aren't unrecognized.
+        let kind = match u.arbitrary()? {
+            SOCKS_OKIND_PACKED_METADATA => SOCKS_OKIND_PACKED_METADATA + 1,
+            kind => match SocksOptionKind::from(kind) {
+                SocksOptionKind::Stack | SocksOptionKind::Other(_) => kind,
+                _ => SocksOptionKind::Stack.into(),
+            },
+        };
+
+        // The data of an unrecognized option is read with its padding, up to the next multiple of four bytes.
+        let mut data = bounded_bytes(u, MAX_DATA_LEN)?;
+        data.resize(data.len().next_multiple_of(4), 0);
+
+        Ok(Self::new(kind, data))
+    }
+}
+
+impl<'a> Arbitrary<'a> for SocksOption {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        let option = match u.int_in_range(0..=4)? {
+            0 => AuthMethodAdvertisementOption::arbitrary(u)?.wrap(),
+            1 => AuthMethodSelectionOption::arbitrary(u)?.wrap(),
+            2 => AuthDataOption::arbitrary(u)?.wrap(),
+            3 => MetadataOption::arbitrary(u)?.wrap(),
+            _ => UnrecognizedOption::arbitrary(u)?.wrap(),
+        };
+
+        Ok(option)
+    }
+}
+
+impl<'a> Arbitrary<'a> for Socks6Request {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        let command = u.arbitrary()?;
+        let destination = u.arbitrary()?;
+        let initial_data_length = u.arbitrary()?;
+
+        let mut options = vec![];
+        for _ in 0..u.int_in_range(0..=MAX_OPTIONS)? {
+            options.push(u.arbitrary()?);
+        }
+        let mut metadata = HashMap::new();
+        for _ in 0..u.int_in_range(0..=MAX_OPTIONS)? {
+            let entry = MetadataOption::arbitrary(u)?;
+            metadata.insert(entry.key, entry.value);
+        }
+
+        Ok(Self::new(command, destination, initial_data_length, options, Some(metadata)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::collection::vec;
+    use proptest::prelude::{any, prop_assert, prop_assert_eq, proptest};
+
+    use super::*;
+    use crate::socks5::Socks5Request;
+
+    // Returns the value that the bytes describe as arbitrary input, if they suffice.
+    fn from_bytes<T: for<'a> Arbitrary<'a>>(bytes: &[u8]) -> Option<T> {
+        T::arbitrary(&mut Unstructured::new(bytes)).ok()
+    }
+
+    proptest! {
+        // Tests that arbitrary options, and options blocks, survive a roundtrip.
+        #[test]
+        fn test_roundtrip_option(bytes in vec(any::<u8>(), 0..1024)) {
+            prop_assert!(fuzz_roundtrip_option(&bytes));
+        }
+
+        // Tests that arbitrary SOCKS6 requests survive a roundtrip, and that parsing arbitrary bytes doesn't panic.
+        #[test]
+        fn test_roundtrip_request(bytes in vec(any::<u8>(), 0..4096)) {
+            fuzz_parse_request(&bytes);
+            if let Some(request) = from_bytes::<Socks6Request>(&bytes) {
+                let encoded = request.into_socks_bytes();
+                prop_assert!(matches!(wire::parse_socks6_request(&encoded), Ok(Parsed::Complete(..))));
+                prop_assert!(fuzz_roundtrip_request(&encoded));
+                fuzz_parse_request(&encoded);
+            }
+        }
+
+        // Tests that arbitrary SOCKS5 requests and addresses are parsed back into the same values.
+        #[test]
+        fn test_roundtrip_socks5_request(bytes in vec(any::<u8>(), 0..512)) {
+            if let Some(request) = from_bytes::<Socks5Request>(&bytes) {
+                let encoded = request.clone().into_socks_bytes();
+                match wire::parse_socks5_request(&encoded) {
+                    Ok(Parsed::Complete(parsed, length)) => {
+                        prop_assert_eq!(parsed, request);
+                        prop_assert_eq!(length, encoded.len());
+                    }
+                    parsed => prop_assert!(false, "{:?} didn't parse: {:?}", request, parsed),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,418 @@
+//! Sans-io parsing and encoding of SOCKS messages.
+//!
+//! The parsers work on byte slices and never perform I/O. When the input ends before the message
+//! does, they report how many more bytes are needed at least, so callers can read exactly that much.
+
+use std::net::IpAddr;
+
+use anyhow::Result;
+use bytes::BytesMut;
+use tokio::io::{self, AsyncRead, AsyncReadExt};
+
+pub use http::{encode_http_connect_request, parse_http_response, MAX_HTTP_HEAD_LEN};
+pub use socks5::{
+    encode_socks5_auth_status, encode_socks5_credentials, encode_socks5_greeting, encode_socks5_gssapi_message,
+    encode_socks5_method_selection, encode_socks5_reply, encode_socks5_request, encode_socks5_udp_header,
+    parse_socks5_auth_status, parse_socks5_auth_status_with, parse_socks5_credentials, parse_socks5_credentials_with,
+    parse_socks5_greeting, parse_socks5_gssapi_message, parse_socks5_method_selection, parse_socks5_negotiation,
+    parse_socks5_reply, parse_socks5_request, parse_socks5_udp_header,
+};
+pub use socks6::{
+    encode_options, encode_options_for, encode_socks6_auth_reply, encode_socks6_auth_reply_for, encode_socks6_reply,
+    encode_socks6_reply_for, encode_socks6_request, encode_socks6_version_mismatch, encode_socks6_version_mismatch_for,
+    parse_options, parse_options_for, parse_options_with, parse_socks6_auth_reply, parse_socks6_auth_reply_for,
+    parse_socks6_auth_reply_with, parse_socks6_reply, parse_socks6_reply_for, parse_socks6_reply_with,
+    parse_socks6_request, parse_socks6_request_for, parse_socks6_request_with, AuthReplyParts, Diagnosed, ReplyParts,
+};
+pub(crate) use socks6::{decode_options, split_options};
+
+use crate::addresses::{unix_path_from_bytes, Address};
+use crate::budget::Charge;
+use crate::constants::*;
+use crate::errors::SocksError;
+
+/// Takes a field from a `Reader`, or returns how many more bytes are needed for it.
+macro_rules! take {
+    ($field:expr) => {
+        match $field {
+            Ok(value) => value,
+            Err(needed) => return Ok(Parsed::Incomplete(needed)),
+        }
+    };
+}
+
+/// Parses a nested message at the position of a `Reader`, or returns how many more bytes are needed for it.
+macro_rules! nested {
+    ($reader:ident, $parse:expr) => {
+        match $parse($reader.rest())? {
+            Parsed::Complete(value, length) => {
+                $reader.advance(length);
+                value
+            }
+            Parsed::Incomplete(needed) => return Ok(Parsed::Incomplete(needed)),
+        }
+    };
+}
+
+/// Arbitrary messages and fuzz entry points for the parsers, enabled by the `arbitrary` feature.
+#[cfg(any(test, feature = "arbitrary"))]
+pub mod fuzz;
+mod http;
+mod socks5;
+mod socks6;
+/// Canonical byte sequences of handshakes, and the values they encode, enabled by the `test-util` feature.
+#[cfg(any(test, feature = "test-util"))]
+pub mod vectors;
+
+/// The outcome of parsing a message from the start of a byte slice.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Parsed<T> {
+    /// The message, and the number of bytes it occupies.
+    Complete(T, usize),
+    /// The input ends before the message does; at least this many more bytes are needed.
+    Incomplete(usize),
+}
+
+impl<T> Parsed<T> {
+    /// Maps the message, if it is complete.
+    pub fn map<U, F: FnOnce(T) -> U>(
+        self,
+        f: F,
+    ) -> Parsed<U> {
+        match self {
+            Parsed::Complete(value, length) => Parsed::Complete(f(value), length),
+            Parsed::Incomplete(needed) => Parsed::Incomplete(needed),
+        }
+    }
+}
+
+/// Cursor over the input of a parser.
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    /// Returns the number of bytes consumed so far.
+    pub(crate) fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Returns the bytes that have not been consumed yet.
+    pub(crate) fn rest(&self) -> &'a [u8] {
+        &self.bytes[self.position..]
+    }
+
+    pub(crate) fn advance(
+        &mut self,
+        length: usize,
+    ) {
+        self.position += length;
+    }
+
+    /// Consumes `length` bytes, or returns how many more bytes are needed for them.
+    pub(crate) fn take(
+        &mut self,
+        length: usize,
+    ) -> std::result::Result<&'a [u8], usize> {
+        let end = self.position + length;
+        if end > self.bytes.len() {
+            return Err(end - self.bytes.len());
+        }
+
+        let taken = &self.bytes[self.position..end];
+        self.position = end;
+
+        Ok(taken)
+    }
+
+    pub(crate) fn array<const N: usize>(&mut self) -> std::result::Result<[u8; N], usize> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+
+        Ok(array)
+    }
+
+    pub(crate) fn u8(&mut self) -> std::result::Result<u8, usize> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u16(&mut self) -> std::result::Result<u16, usize> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+}
+
+/// Parses a SOCKS address: its type, the address itself, and the port.
+pub fn parse_address(bytes: &[u8]) -> Result<Parsed<Address>> {
+    let mut reader = Reader::new(bytes);
+
+    let address = match take!(reader.u8()) {
+        SOCKS_ATYP_IPV4 => {
+            let host: [u8; 4] = take!(reader.array());
+            IpAddr::from(host).to_string()
+        }
+        SOCKS_ATYP_IPV6 => {
+            let host: [u8; 16] = take!(reader.array());
+            IpAddr::from(host).to_string()
+        }
+        SOCKS_ATYP_DOMAINNAME => {
+            let length = take!(reader.u8());
+            let host = take!(reader.take(length as usize));
+            String::from_utf8_lossy(host).into_owned()
+        }
+        SOCKS_ATYP_UNIX => {
+            // The extension carries a path instead of a host and a port.
+            let length = take!(reader.u8());
+            let path = take!(reader.take(length as usize));
+            ensure!(!path.is_empty(), "Empty Unix socket path");
+
+            return Ok(Parsed::Complete(Address::Unix(unix_path_from_bytes(path)), reader.position()));
+        }
+        address_type => return Err(SocksError::UnsupportedAddressType(address_type).into()),
+    };
+
+    let port = take!(reader.u16());
+
+    Ok(Parsed::Complete(Address::try_new(address, port)?, reader.position()))
+}
+
+/// Appends the SOCKS representation of an address to the buffer.
+pub fn encode_address(
+    address: &Address,
+    bytes: &mut Vec<u8>,
+) {
+    address.write_socks_bytes(bytes);
+}
+
+/// Reads a message from the stream, by feeding it to the parser until it is complete.
+///
+/// Only as many bytes as the parser asks for are read, so nothing after the message is consumed.
+/// This is not cancellation safe: use a `MessageReader` to resume a message after the future is dropped.
+pub(crate) async fn read_message<S, T, P>(
+    stream: &mut S,
+    scratch: &mut BytesMut,
+    parse: P,
+) -> Result<T>
+where
+    S: AsyncRead + Unpin + ?Sized,
+    P: Fn(&[u8]) -> Result<Parsed<T>>,
+{
+    scratch.clear();
+    fill_message(stream, scratch, parse).await
+}
+
+/// Reads a message from the stream like `read_message`, charging every byte read for it.
+pub(crate) async fn read_message_charged<S, T, P>(
+    stream: &mut S,
+    scratch: &mut BytesMut,
+    parse: P,
+    charge: &mut Charge,
+) -> Result<T>
+where
+    S: AsyncRead + Unpin + ?Sized,
+    P: Fn(&[u8]) -> Result<Parsed<T>>,
+{
+    scratch.clear();
+    fill_message_charged(stream, scratch, parse, charge).await
+}
+
+/// Reads into the buffer until the parser finds a complete message in it.
+///
+/// Every byte read is appended to the buffer right away, so dropping the future loses nothing.
+pub(crate) async fn fill_message<S, T, P>(
+    stream: &mut S,
+    buffer: &mut BytesMut,
+    parse: P,
+) -> Result<T>
+where
+    S: AsyncRead + Unpin + ?Sized,
+    P: Fn(&[u8]) -> Result<Parsed<T>>,
+{
+    fill_message_charged(stream, buffer, parse, &mut Charge::default()).await
+}
+
+/// Reads into the buffer like `fill_message`, charging the bytes the parser asks for before reading them.
+///
+/// Bytes that were charged but not read yet, as a read returned fewer bytes, are not charged again.
+pub(crate) async fn fill_message_charged<S, T, P>(
+    stream: &mut S,
+    buffer: &mut BytesMut,
+    parse: P,
+    charge: &mut Charge,
+) -> Result<T>
+where
+    S: AsyncRead + Unpin + ?Sized,
+    P: Fn(&[u8]) -> Result<Parsed<T>>,
+{
+    let mut charged_ahead = 0;
+    loop {
+        match parse(&buffer[..])? {
+            Parsed::Complete(message, _) => return Ok(message),
+            Parsed::Incomplete(needed) => {
+                if needed > charged_ahead {
+                    charge.add(needed - charged_ahead)?;
+                    charged_ahead = needed;
+                }
+                let read = (&mut *stream).take(needed as u64).read_buf(buffer).await?;
+                if read == 0 {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+                charged_ahead -= read;
+            }
+        }
+    }
+}
+
+/// Reads messages from a stream, resuming a partially read message when a previous read was cancelled.
+///
+/// The future returned by `read` is cancellation safe: when it is dropped, e.g., by a timeout or in a
+/// `select!`, the bytes read so far are kept, and the next call to `read` with the same parser continues
+/// the message where it left off. A malformed message fails the same way every time it is read again.
+#[derive(Debug, Default)]
+pub struct MessageReader {
+    buffer: BytesMut,
+    complete: bool,
+    charge: Charge,
+}
+
+impl MessageReader {
+    /// Creates a reader without any buffered bytes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Creates a reader that charges the bytes it reads.
+    pub(crate) fn charged(charge: Charge) -> Self {
+        Self {
+            charge,
+            ..Self::default()
+        }
+    }
+
+    // Charges bytes that are buffered beyond the messages, e.g., the initial data that follows a request.
+    pub(crate) fn charge(
+        &mut self,
+        bytes: usize,
+    ) -> Result<()> {
+        self.charge.add(bytes)
+    }
+
+    // Returns the charge for the bytes read so far.
+    pub(crate) fn into_charge(self) -> Charge {
+        self.charge
+    }
+
+    /// Reads a message from the stream, feeding it to the parser until it is complete.
+    ///
+    /// Only as many bytes as the parser asks for are read, so nothing after the message is consumed.
+    pub async fn read<S, T, P>(
+        &mut self,
+        stream: &mut S,
+        parse: P,
+    ) -> Result<T>
+    where
+        S: AsyncRead + Unpin + ?Sized,
+        P: Fn(&[u8]) -> Result<Parsed<T>>,
+    {
+        if self.complete {
+            self.buffer.clear();
+            self.complete = false;
+        }
+
+        let message = fill_message_charged(stream, &mut self.buffer, parse, &mut self.charge).await?;
+        self.complete = true;
+
+        Ok(message)
+    }
+
+    /// Returns the bytes of the last complete message, or those read so far of the current one.
+    pub fn buffered(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    /// Returns whether a message was partially read, i.e., a read was cancelled or failed halfway.
+    pub fn is_partial(&self) -> bool {
+        !self.complete && !self.buffer.is_empty()
+    }
+}
+
+/// Checks a parser against every truncation of a valid message, and against trailing bytes.
+#[cfg(any(test, feature = "test-util"))]
+pub(crate) fn assert_parses<T>(
+    bytes: &[u8],
+    parse: impl Fn(&[u8]) -> Result<Parsed<T>>,
+) -> T {
+    for length in 0..bytes.len() {
+        match parse(&bytes[..length]) {
+            Ok(Parsed::Incomplete(needed)) => {
+                assert!(needed > 0, "no bytes needed at {}", length);
+                assert!(length + needed <= bytes.len(), "over-read at {}: {} needed", length, needed);
+            }
+            Ok(Parsed::Complete(..)) => panic!("complete after {} of {} bytes", length, bytes.len()),
+            Err(e) => panic!("error after {} of {} bytes: {}", length, bytes.len(), e),
+        }
+    }
+
+    let mut trailing = bytes.to_vec();
+    trailing.extend([0xAA; 8]);
+    match parse(&trailing).unwrap() {
+        Parsed::Complete(_, length) => assert_eq!(length, bytes.len()),
+        Parsed::Incomplete(_) => panic!("incomplete with trailing bytes"),
+    }
+
+    match parse(bytes).unwrap() {
+        Parsed::Complete(message, length) => {
+            assert_eq!(length, bytes.len());
+            message
+        }
+        Parsed::Incomplete(_) => panic!("incomplete"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Tests every truncation of each address type.
+    #[test]
+    fn test_parse_address() {
+        for address in [
+            Address::new("192.168.1.1", 80),
+            Address::new("::1", 443),
+            Address::new("example.com", 8080),
+            Address::new("", 1),
+            Address::unix("/run/service.sock").unwrap(),
+        ] {
+            let mut bytes = vec![];
+            encode_address(&address, &mut bytes);
+            assert_eq!(assert_parses(&bytes, parse_address), address);
+        }
+    }
+
+    // Tests that unknown address types, and empty Unix socket paths, are rejected.
+    #[test]
+    fn test_parse_address_unknown_type() {
+        let error = parse_address(&[0x05, 127, 0, 0, 1, 0, 80]).unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(SocksError::UnsupportedAddressType(0x05))));
+        assert!(parse_address(&[SOCKS_ATYP_UNIX, 0x00]).is_err());
+    }
+
+    // Tests that a message is read without consuming the bytes after it.
+    #[tokio::test]
+    async fn test_read_message_exact() -> Result<()> {
+        let mut bytes = vec![];
+        encode_address(&Address::new("example.com", 80), &mut bytes);
+        bytes.extend(b"rest");
+
+        let mut stream = &bytes[..];
+        let address = read_message(&mut stream, &mut BytesMut::new(), parse_address).await?;
+
+        assert_eq!(address, Address::new("example.com", 80));
+        assert_eq!(stream, b"rest");
+        Ok(())
+    }
+}
@@ -0,0 +1,461 @@
+//! Sans-io parsing and encoding of SOCKS5 messages.
+
+use std::convert::TryFrom;
+
+use anyhow::Result;
+use num_traits::FromPrimitive;
+
+use super::{parse_address, Parsed, Reader};
+use crate::addresses::Address;
+use crate::constants::*;
+use crate::errors::{NegotiationError, SocksError};
+use crate::socks5::{AuthVersionPolicy, Socks5Command, Socks5Request};
+use crate::Credentials;
+
+/// Parses the greeting of a SOCKS5 client, returning the proposed authentication methods.
+pub fn parse_socks5_greeting(bytes: &[u8]) -> Result<Parsed<Vec<u8>>> {
+    let mut reader = Reader::new(bytes);
+
+    let version = take!(reader.u8());
+    ensure!(version == SOCKS_VER_5, "Client uses a different SOCKS version: {}.", version);
+
+    let nmethods = take!(reader.u8());
+    let methods = take!(reader.take(nmethods as usize));
+
+    Ok(Parsed::Complete(methods.to_vec(), reader.position()))
+}
+
+/// Parses the method negotiation of a SOCKS5 client strictly, returning the proposed authentication methods.
+///
+/// Unlike `parse_socks5_greeting`, this rejects a negotiation without methods, or with a method offered twice,
+/// with a `SocksError::MalformedNegotiation`, and another version with a `SocksError::VersionMismatch`. A
+/// negotiation with fewer methods than declared is incomplete: whether the rest is coming is up to the caller.
+pub fn parse_socks5_negotiation(bytes: &[u8]) -> Result<Parsed<Vec<u8>>> {
+    let mut reader = Reader::new(bytes);
+
+    let version = take!(reader.u8());
+    ensure!(version == SOCKS_VER_5, SocksError::VersionMismatch(version));
+
+    let nmethods = take!(reader.u8());
+    ensure!(nmethods > 0, SocksError::MalformedNegotiation(NegotiationError::NoMethods));
+
+    let methods = take!(reader.take(nmethods as usize));
+    for (i, method) in methods.iter().enumerate() {
+        ensure!(
+            !methods[..i].contains(method),
+            SocksError::MalformedNegotiation(NegotiationError::DuplicateMethod(*method))
+        );
+    }
+
+    Ok(Parsed::Complete(methods.to_vec(), reader.position()))
+}
+
+/// Appends the greeting of a SOCKS5 client to the buffer.
+pub fn encode_socks5_greeting(
+    methods: &[u8],
+    bytes: &mut Vec<u8>,
+) {
+    bytes.extend_from_slice(&[SOCKS_VER_5, methods.len() as u8]);
+    bytes.extend_from_slice(methods);
+}
+
+/// Parses the authentication method selected by a SOCKS5 proxy.
+pub fn parse_socks5_method_selection(bytes: &[u8]) -> Result<Parsed<u8>> {
+    let mut reader = Reader::new(bytes);
+
+    let version = take!(reader.u8());
+    ensure!(version == SOCKS_VER_5, "Proxy uses a different SOCKS version: {}.", version);
+
+    let method = take!(reader.u8());
+
+    Ok(Parsed::Complete(method, reader.position()))
+}
+
+/// Appends the authentication method selection of a SOCKS5 proxy to the buffer.
+pub fn encode_socks5_method_selection(
+    method: u8,
+    bytes: &mut Vec<u8>,
+) {
+    bytes.extend_from_slice(&[SOCKS_VER_5, method]);
+}
+
+/// Parses a username/password authentication request (RFC 1929), only accepting version 0x01.
+pub fn parse_socks5_credentials(bytes: &[u8]) -> Result<Parsed<Credentials>> {
+    parse_socks5_credentials_with(bytes, AuthVersionPolicy::Strict)
+}
+
+/// Parses a username/password authentication request (RFC 1929), accepting the version bytes of the policy.
+pub fn parse_socks5_credentials_with(
+    bytes: &[u8],
+    policy: AuthVersionPolicy,
+) -> Result<Parsed<Credentials>> {
+    let mut reader = Reader::new(bytes);
+
+    let version = take!(reader.u8());
+    ensure!(policy.accepts(version), SocksError::AuthVersionMismatch(version));
+
+    let ulen = take!(reader.u8());
+    let username = take!(reader.take(ulen as usize));
+    let plen = take!(reader.u8());
+    let password = take!(reader.take(plen as usize));
+
+    Ok(Parsed::Complete(Credentials::new(username, password), reader.position()))
+}
+
+/// Appends a username/password authentication request (RFC 1929) to the buffer.
+pub fn encode_socks5_credentials(
+    credentials: &Credentials,
+    bytes: &mut Vec<u8>,
+) {
+    bytes.push(SOCKS_AUTH_VER);
+    bytes.extend(credentials.as_socks_bytes());
+}
+
+/// Parses the status of a username/password authentication (RFC 1929), only accepting version 0x01.
+pub fn parse_socks5_auth_status(bytes: &[u8]) -> Result<Parsed<u8>> {
+    parse_socks5_auth_status_with(bytes, AuthVersionPolicy::Strict)
+}
+
+/// Parses the status of a username/password authentication (RFC 1929), accepting the version bytes of the policy.
+pub fn parse_socks5_auth_status_with(
+    bytes: &[u8],
+    policy: AuthVersionPolicy,
+) -> Result<Parsed<u8>> {
+    let mut reader = Reader::new(bytes);
+
+    let version = take!(reader.u8());
+    ensure!(policy.accepts(version), SocksError::AuthVersionMismatch(version));
+
+    let status = take!(reader.u8());
+
+    Ok(Parsed::Complete(status, reader.position()))
+}
+
+/// Appends the status of a username/password authentication (RFC 1929) to the buffer.
+pub fn encode_socks5_auth_status(
+    status: u8,
+    bytes: &mut Vec<u8>,
+) {
+    bytes.extend_from_slice(&[SOCKS_AUTH_VER, status]);
+}
+
+/// Parses a GSSAPI sub-negotiation message (RFC 1961), returning its type and its token.
+///
+/// An abort message carries no token, so it is returned with an empty one.
+pub fn parse_socks5_gssapi_message(bytes: &[u8]) -> Result<Parsed<(u8, Vec<u8>)>> {
+    let mut reader = Reader::new(bytes);
+
+    let version = take!(reader.u8());
+    ensure!(version == SOCKS_GSSAPI_VER, "Unexpected GSSAPI sub-negotiation version: {:#04x}", version);
+
+    let kind = take!(reader.u8());
+    if kind == SOCKS_GSSAPI_ABORT {
+        return Ok(Parsed::Complete((kind, vec![]), reader.position()));
+    }
+
+    let length = take!(reader.u16());
+    let token = take!(reader.take(length as usize));
+
+    Ok(Parsed::Complete((kind, token.to_vec()), reader.position()))
+}
+
+/// Appends a GSSAPI sub-negotiation message (RFC 1961) to the buffer, leaving out the token of an abort message.
+pub fn encode_socks5_gssapi_message(
+    kind: u8,
+    token: &[u8],
+    bytes: &mut Vec<u8>,
+) -> Result<()> {
+    bytes.extend_from_slice(&[SOCKS_GSSAPI_VER, kind]);
+    if kind != SOCKS_GSSAPI_ABORT {
+        let length = u16::try_from(token.len()).map_err(|_| anyhow!("GSSAPI token too long: {}", token.len()))?;
+        bytes.extend_from_slice(&length.to_be_bytes());
+        bytes.extend_from_slice(token);
+    }
+
+    Ok(())
+}
+
+/// Parses a SOCKS5 request.
+pub fn parse_socks5_request(bytes: &[u8]) -> Result<Parsed<Socks5Request>> {
+    let mut reader = Reader::new(bytes);
+
+    let version = take!(reader.u8());
+    ensure!(version == SOCKS_VER_5, "Client uses a different SOCKS version: {}.", version);
+
+    let command = take!(reader.u8());
+    let command = match Socks5Command::from_u8(command) {
+        Some(command) => command,
+        None => bail!("Unknown SOCKS5 command: {}.", command),
+    };
+
+    let _reserved = take!(reader.u8());
+    let destination = nested!(reader, parse_address);
+
+    Ok(Parsed::Complete(Socks5Request { command, destination }, reader.position()))
+}
+
+/// Appends a SOCKS5 request to the buffer.
+pub fn encode_socks5_request(
+    request: &Socks5Request,
+    bytes: &mut Vec<u8>,
+) {
+    bytes.extend_from_slice(&[SOCKS_VER_5, request.command.clone() as u8, SOCKS_RSV]);
+    request.destination.write_socks_bytes(bytes);
+}
+
+/// Parses a SOCKS5 reply, returning its reply code and binding.
+pub fn parse_socks5_reply(bytes: &[u8]) -> Result<Parsed<(u8, Address)>> {
+    let mut reader = Reader::new(bytes);
+
+    let version = take!(reader.u8());
+    ensure!(version == SOCKS_VER_5, "Proxy uses a different SOCKS version: {}.", version);
+
+    let reply_code = take!(reader.u8());
+    let _reserved = take!(reader.u8());
+    let binding = nested!(reader, parse_address);
+
+    Ok(Parsed::Complete((reply_code, binding), reader.position()))
+}
+
+/// Appends a SOCKS5 reply to the buffer.
+pub fn encode_socks5_reply(
+    reply_code: u8,
+    binding: &Address,
+    bytes: &mut Vec<u8>,
+) {
+    bytes.extend_from_slice(&[SOCKS_VER_5, reply_code, SOCKS_RSV]);
+    binding.write_socks_bytes(bytes);
+}
+
+/// Parses the header of a datagram relayed through a SOCKS5 UDP association (RFC 1928, section 7), returning its
+/// fragment number and destination. The payload follows the header.
+pub fn parse_socks5_udp_header(bytes: &[u8]) -> Result<Parsed<(u8, Address)>> {
+    let mut reader = Reader::new(bytes);
+
+    let _reserved = take!(reader.u16());
+    let fragment = take!(reader.u8());
+    let destination = nested!(reader, parse_address);
+    ensure!(!matches!(destination, Address::Unix(_)), SocksError::UnsupportedAddressType(SOCKS_ATYP_UNIX));
+
+    Ok(Parsed::Complete((fragment, destination), reader.position()))
+}
+
+/// Appends the header of a datagram relayed through a SOCKS5 UDP association to the buffer.
+pub fn encode_socks5_udp_header(
+    fragment: u8,
+    destination: &Address,
+    bytes: &mut Vec<u8>,
+) {
+    bytes.extend_from_slice(&[SOCKS_RSV, SOCKS_RSV, fragment]);
+    destination.write_socks_bytes(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::assert_parses;
+
+    // Tests every truncation of the authentication messages.
+    #[test]
+    fn test_parse_authentication() {
+        let mut bytes = vec![];
+        encode_socks5_greeting(&[SOCKS_AUTH_NOT_REQUIRED, SOCKS_AUTH_USERNAME_PASSWORD], &mut bytes);
+        assert_eq!(assert_parses(&bytes, parse_socks5_greeting), vec![0x00, 0x02]);
+
+        let mut bytes = vec![];
+        encode_socks5_method_selection(SOCKS_AUTH_USERNAME_PASSWORD, &mut bytes);
+        assert_eq!(assert_parses(&bytes, parse_socks5_method_selection), 0x02);
+
+        let credentials = Credentials::new("user", "secret");
+        let mut bytes = vec![];
+        encode_socks5_credentials(&credentials, &mut bytes);
+        assert_eq!(assert_parses(&bytes, parse_socks5_credentials), credentials);
+
+        let mut bytes = vec![];
+        encode_socks5_auth_status(SOCKS_AUTH_FAILED, &mut bytes);
+        assert_eq!(assert_parses(&bytes, parse_socks5_auth_status), SOCKS_AUTH_FAILED);
+
+        let mut bytes = vec![];
+        encode_socks5_gssapi_message(SOCKS_GSSAPI_AUTHENTICATION, b"token", &mut bytes).unwrap();
+        assert_eq!(bytes, [0x01, 0x01, 0x00, 0x05, b't', b'o', b'k', b'e', b'n']);
+        assert_eq!(
+            assert_parses(&bytes, parse_socks5_gssapi_message),
+            (SOCKS_GSSAPI_AUTHENTICATION, b"token".to_vec())
+        );
+
+        let mut bytes = vec![];
+        encode_socks5_gssapi_message(SOCKS_GSSAPI_ABORT, b"ignored", &mut bytes).unwrap();
+        assert_eq!(assert_parses(&bytes, parse_socks5_gssapi_message), (SOCKS_GSSAPI_ABORT, vec![]));
+    }
+
+    // Tests that the sub-negotiation version 0x05 is only accepted by the lenient policy, and named when rejected.
+    #[test]
+    fn test_parse_auth_version_policy() {
+        let credentials = [0x05, 0x04, b'u', b's', b'e', b'r', 0x00];
+        match parse_socks5_credentials_with(&credentials, AuthVersionPolicy::Lenient) {
+            Ok(Parsed::Complete(parsed, 7)) => assert_eq!(parsed, Credentials::new("user", "")),
+            _ => panic!("Lenient policy rejected version 0x05."),
+        }
+
+        let error = parse_socks5_credentials_with(&credentials, AuthVersionPolicy::Strict).unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(SocksError::AuthVersionMismatch(0x05))));
+        assert!(error.to_string().contains("0x05"));
+
+        let status = [SOCKS_VER_5, SOCKS_AUTH_SUCCESS];
+        assert!(matches!(
+            parse_socks5_auth_status_with(&status, AuthVersionPolicy::Lenient),
+            Ok(Parsed::Complete(SOCKS_AUTH_SUCCESS, 2))
+        ));
+        assert!(parse_socks5_auth_status_with(&status, AuthVersionPolicy::Strict).is_err());
+        assert!(parse_socks5_auth_status_with(&[0x02, SOCKS_AUTH_SUCCESS], AuthVersionPolicy::Lenient).is_err());
+    }
+
+    // Returns the malformation a negotiation was rejected with, if any.
+    fn negotiation_error(bytes: &[u8]) -> Option<NegotiationError> {
+        match parse_socks5_negotiation(bytes) {
+            Err(e) => match e.downcast_ref() {
+                Some(SocksError::MalformedNegotiation(error)) => Some(*error),
+                _ => panic!("unexpected error: {}", e),
+            },
+            Ok(_) => None,
+        }
+    }
+
+    // Tests the strict negotiation parser against random method lists, with a fixed seed to stay reproducible.
+    #[test]
+    fn test_parse_negotiation_random() {
+        let mut state = 0x2545_F491_4F6C_DD1Du64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        for _ in 0..1000 {
+            // Draw from a few methods only, so that duplicates are common.
+            let length = (next() % 12) as usize;
+            let pool = [0x00, 0x01, 0x02, 0x03, 0x80, 0xFE];
+            let methods: Vec<u8> = (0..length).map(|_| pool[next() as usize % pool.len()]).collect();
+            let mut bytes = vec![];
+            encode_socks5_greeting(&methods, &mut bytes);
+
+            let duplicate = (1..methods.len()).find(|&i| methods[..i].contains(&methods[i])).map(|i| methods[i]);
+            if methods.is_empty() {
+                assert_eq!(negotiation_error(&bytes), Some(NegotiationError::NoMethods));
+            } else if let Some(method) = duplicate {
+                assert_eq!(negotiation_error(&bytes), Some(NegotiationError::DuplicateMethod(method)));
+                for length in 0..bytes.len() {
+                    assert!(matches!(parse_socks5_negotiation(&bytes[..length]), Ok(Parsed::Incomplete(_))));
+                }
+            } else {
+                assert_eq!(assert_parses(&bytes, parse_socks5_negotiation), methods);
+            }
+        }
+    }
+
+    // Tests the malformed negotiations that the strict parser rejects, and the request glued to a negotiation.
+    #[test]
+    fn test_parse_negotiation_malformed() {
+        assert_eq!(negotiation_error(&[SOCKS_VER_5, 0x00]), Some(NegotiationError::NoMethods));
+        assert_eq!(
+            negotiation_error(&[SOCKS_VER_5, 0x03, 0x00, 0x02, 0x00]),
+            Some(NegotiationError::DuplicateMethod(0x00))
+        );
+        assert!(matches!(parse_socks5_negotiation(&[SOCKS_VER_5, 0x03, 0x00]), Ok(Parsed::Incomplete(2))));
+
+        let error = parse_socks5_negotiation(&[0x04, 0x01, 0x00]).unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(SocksError::VersionMismatch(0x04))));
+
+        // The lenient parser still accepts what the strict one rejects.
+        assert!(matches!(parse_socks5_greeting(&[SOCKS_VER_5, 0x00]), Ok(Parsed::Complete(_, 2))));
+
+        let mut bytes = vec![];
+        encode_socks5_greeting(&[SOCKS_AUTH_NOT_REQUIRED], &mut bytes);
+        let request = Socks5Request::new(SOCKS_CMD_CONNECT, Address::new("example.com", 80));
+        encode_socks5_request(&request, &mut bytes);
+        match parse_socks5_negotiation(&bytes) {
+            Ok(Parsed::Complete(methods, 3)) => assert_eq!(methods, vec![SOCKS_AUTH_NOT_REQUIRED]),
+            _ => panic!("Negotiation not parsed from the front of the bytes."),
+        }
+        assert_eq!(assert_parses(&bytes[3..], parse_socks5_request).destination, request.destination);
+    }
+
+    // Tests every truncation of a request and a reply.
+    #[test]
+    fn test_parse_request_and_reply() {
+        let request = Socks5Request::new(SOCKS_CMD_CONNECT, Address::new("example.com", 80));
+        let mut bytes = vec![];
+        encode_socks5_request(&request, &mut bytes);
+        assert_eq!(bytes, request.clone().into_socks_bytes());
+
+        let parsed = assert_parses(&bytes, parse_socks5_request);
+        assert_eq!(parsed.command, Socks5Command::Connect);
+        assert_eq!(parsed.destination, request.destination);
+
+        let mut bytes = vec![];
+        encode_socks5_reply(SOCKS_REP_SUCCEEDED, &Address::new("10.0.0.1", 1080), &mut bytes);
+        assert_eq!(
+            assert_parses(&bytes, parse_socks5_reply),
+            (SOCKS_REP_SUCCEEDED, Address::new("10.0.0.1", 1080))
+        );
+    }
+
+    // Tests every truncation of the header of a UDP datagram, and that Unix socket paths are refused in it.
+    #[test]
+    fn test_parse_udp_header() {
+        let mut bytes = vec![];
+        encode_socks5_udp_header(0, &Address::new("192.0.2.1", 53), &mut bytes);
+        assert_eq!(bytes, [0x00, 0x00, 0x00, SOCKS_ATYP_IPV4, 192, 0, 2, 1, 0x00, 0x35]);
+        assert_eq!(assert_parses(&bytes, parse_socks5_udp_header), (0, Address::new("192.0.2.1", 53)));
+
+        let mut bytes = vec![];
+        encode_socks5_udp_header(3, &Address::new("example.com", 53), &mut bytes);
+        assert_eq!(assert_parses(&bytes, parse_socks5_udp_header), (3, Address::new("example.com", 53)));
+
+        let unix = [0x00, 0x00, 0x00, SOCKS_ATYP_UNIX, 0x04, b'/', b't', b'm', b'p'];
+        assert!(parse_socks5_udp_header(&unix).is_err());
+    }
+
+    // Tests that malformed messages are rejected instead of waiting for more data.
+    #[test]
+    fn test_parse_malformed() {
+        assert!(parse_socks5_greeting(&[SOCKS_VER_6]).is_err());
+        assert!(parse_socks5_credentials(&[0x05]).is_err());
+        assert!(parse_socks5_request(&[SOCKS_VER_5, 0x09]).is_err());
+        assert!(parse_socks5_request(&[SOCKS_VER_5, SOCKS_CMD_CONNECT, SOCKS_RSV, 0x05]).is_err());
+        assert!(parse_socks5_reply(&[SOCKS_VER_6]).is_err());
+    }
+
+    // Tests that the SOCKS5 golden vectors encode to, and decode from, exactly their bytes.
+    #[test]
+    fn test_golden_vectors() {
+        use crate::wire::vectors;
+
+        for vector in [vectors::socks5_greeting_no_auth(), vectors::socks5_greeting_username_password()] {
+            vector.assert_encodes(|methods, bytes| encode_socks5_greeting(methods, bytes));
+            vector.assert_decodes(parse_socks5_greeting);
+        }
+        for vector in [vectors::socks5_selection_no_auth(), vectors::socks5_selection_username_password()] {
+            vector.assert_encodes(|method, bytes| encode_socks5_method_selection(*method, bytes));
+            vector.assert_decodes(parse_socks5_method_selection);
+        }
+
+        let credentials = vectors::socks5_credentials();
+        credentials.assert_encodes(encode_socks5_credentials);
+        credentials.assert_decodes(parse_socks5_credentials);
+
+        let status = vectors::socks5_auth_success();
+        status.assert_encodes(|status, bytes| encode_socks5_auth_status(*status, bytes));
+        status.assert_decodes(parse_socks5_auth_status);
+
+        for vector in [vectors::socks5_connect_ipv4(), vectors::socks5_connect_domain()] {
+            vector.assert_encodes(encode_socks5_request);
+            vector.assert_decodes(parse_socks5_request);
+        }
+        for vector in [vectors::socks5_reply_success(), vectors::socks5_reply_connection_not_allowed()] {
+            vector.assert_encodes(|(code, binding), bytes| encode_socks5_reply(*code, binding, bytes));
+            vector.assert_decodes(parse_socks5_reply);
+        }
+    }
+}
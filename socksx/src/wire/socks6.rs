@@ -0,0 +1,570 @@
+//! Sans-io parsing and encoding of SOCKS6 messages.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use anyhow::Result;
+
+use super::{parse_address, Parsed, Reader};
+use crate::addresses::Address;
+use crate::constants::*;
+use crate::errors::SocksError;
+use crate::socks6::options::{
+    self, AuthDataOption, AuthMethodAdvertisementOption, AuthMethodSelectionOption, MetadataOption, SocksOption,
+    SocksOptionKind, SocksOptions, UnrecognizedOption,
+};
+use crate::socks6::{Diagnostic, ParseMode, Socks6Command, Socks6Draft, Socks6Request};
+
+/// A parsed message, with the deviations from the draft that a lenient parse accepted.
+pub type Diagnosed<T> = (T, Vec<Diagnostic>);
+
+/// The status and options of an authentication reply.
+pub type AuthReplyParts = (u8, Vec<SocksOption>);
+
+/// The reply code, binding, and options of an operation reply.
+pub type ReplyParts = (u8, Address, Vec<SocksOption>);
+
+/// Parses a SOCKS6 request, including its options but not its initial data.
+pub fn parse_socks6_request(bytes: &[u8]) -> Result<Parsed<Socks6Request>> {
+    parse_socks6_request_for(bytes, Socks6Draft::default())
+}
+
+/// Parses a SOCKS6 request of the given draft revision.
+pub fn parse_socks6_request_for(
+    bytes: &[u8],
+    draft: Socks6Draft,
+) -> Result<Parsed<Socks6Request>> {
+    parse_socks6_request_with(bytes, draft, ParseMode::default())
+}
+
+/// Parses a SOCKS6 request of the given draft revision in the given mode.
+///
+/// The deviations a lenient parse accepted are available through `Socks6Request::diagnostics`.
+pub fn parse_socks6_request_with(
+    bytes: &[u8],
+    draft: Socks6Draft,
+    mode: ParseMode,
+) -> Result<Parsed<Socks6Request>> {
+    let mut diagnostics = vec![];
+    let mut reader = Reader::new(bytes);
+
+    let version = take!(reader.u8());
+    if version != draft.constants().version {
+        return Err(SocksError::VersionMismatch(version).into());
+    }
+
+    let command = take!(reader.u8());
+    let command = Socks6Command::try_from(command).map_err(|e| SocksError::UnknownCommand(e.value))?;
+
+    let destination = nested!(reader, parse_address);
+    check_padding(take!(reader.u8()), mode, &mut diagnostics)?;
+    let (options, found) = nested!(reader, |bytes| parse_options_with(bytes, draft, mode));
+    diagnostics.extend(found);
+
+    let initial_data_length = options.initial_data_length();
+    let metadata: HashMap<u16, String> = options
+        .metadata()
+        .into_iter()
+        .map(|(key, value)| (key, value.to_string()))
+        .collect();
+
+    let request = Socks6Request::new(command, destination, initial_data_length, options, Some(metadata))
+        .with_diagnostics(diagnostics);
+
+    Ok(Parsed::Complete(request, reader.position()))
+}
+
+/// Appends a SOCKS6 request to the buffer.
+pub fn encode_socks6_request(
+    request: &Socks6Request,
+    bytes: &mut Vec<u8>,
+) {
+    request.write_socks_bytes(bytes);
+}
+
+/// Appends the version mismatch reply, which is just the version the proxy speaks, to the buffer.
+pub fn encode_socks6_version_mismatch(bytes: &mut Vec<u8>) {
+    encode_socks6_version_mismatch_for(Socks6Draft::default(), bytes);
+}
+
+/// Appends the version mismatch reply of the given draft revision to the buffer.
+pub fn encode_socks6_version_mismatch_for(
+    draft: Socks6Draft,
+    bytes: &mut Vec<u8>,
+) {
+    bytes.push(draft.constants().version);
+}
+
+/// Parses a length-prefixed block of SOCKS6 options.
+pub fn parse_options(bytes: &[u8]) -> Result<Parsed<Vec<SocksOption>>> {
+    parse_options_for(bytes, Socks6Draft::default())
+}
+
+/// Parses a length-prefixed block of SOCKS6 options, with the option kinds of the given draft revision.
+pub fn parse_options_for(
+    bytes: &[u8],
+    draft: Socks6Draft,
+) -> Result<Parsed<Vec<SocksOption>>> {
+    Ok(match parse_options_with(bytes, draft, ParseMode::default())? {
+        Parsed::Complete((options, _), length) => Parsed::Complete(options, length),
+        Parsed::Incomplete(needed) => Parsed::Incomplete(needed),
+    })
+}
+
+/// Parses a length-prefixed block of SOCKS6 options of the given draft revision in the given mode.
+///
+/// # Returns
+/// The options, and the deviations from the draft that a lenient parse accepted.
+pub fn parse_options_with(
+    bytes: &[u8],
+    draft: Socks6Draft,
+    mode: ParseMode,
+) -> Result<Parsed<Diagnosed<Vec<SocksOption>>>> {
+    let mut reader = Reader::new(bytes);
+
+    let options_length = take!(reader.u16());
+    let options = take!(reader.take(options_length as usize));
+
+    let mut diagnostics = vec![];
+    let options = decode_options_with(options, draft, mode, &mut diagnostics)?;
+
+    Ok(Parsed::Complete((options, diagnostics), reader.position()))
+}
+
+/// Appends a length-prefixed block of SOCKS6 options to the buffer.
+pub fn encode_options(
+    options: &[SocksOption],
+    bytes: &mut Vec<u8>,
+) {
+    encode_options_for(options, Socks6Draft::default(), bytes);
+}
+
+/// Appends a length-prefixed block of SOCKS6 options, with the option kinds of the given draft revision.
+pub fn encode_options_for(
+    options: &[SocksOption],
+    draft: Socks6Draft,
+    bytes: &mut Vec<u8>,
+) {
+    let options_length: usize = options.iter().map(SocksOption::encoded_len).sum();
+
+    bytes.reserve(2 + options_length);
+    bytes.extend_from_slice(&(options_length as u16).to_be_bytes());
+    for option in options {
+        option.write_socks_bytes_for(draft, bytes);
+    }
+}
+
+/// Decodes the options in the body of an options block leniently.
+pub(crate) fn decode_options(
+    bytes: &[u8],
+    draft: Socks6Draft,
+) -> Result<Vec<SocksOption>> {
+    decode_options_with(bytes, draft, ParseMode::Lenient, &mut vec![])
+}
+
+/// Decodes the options in the body of an options block, adding the deviations it accepts to `diagnostics`.
+pub(crate) fn decode_options_with(
+    mut bytes: &[u8],
+    draft: Socks6Draft,
+    mode: ParseMode,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Result<Vec<SocksOption>> {
+    let mut options = Vec::new();
+    let mut inflate_limit = options::MAX_INFLATED_METADATA_LEN;
+
+    while !bytes.is_empty() {
+        if bytes.len() < 4 {
+            // Too short for an option header, so this can only be stray bytes after the last option.
+            mode.deviate(Diagnostic::TrailingBytes(bytes.len()), diagnostics)?;
+            break;
+        }
+
+        let kind = u16::from_be_bytes([bytes[0], bytes[1]]);
+        let length = u16::from_be_bytes([bytes[2], bytes[3]]) as usize;
+        ensure!(
+            length >= 4 && length <= bytes.len(),
+            "Invalid length for option {}: {}",
+            kind,
+            length
+        );
+        if !length.is_multiple_of(4) {
+            let diagnostic = Diagnostic::UnalignedOption {
+                kind,
+                length: length as u16,
+            };
+            mode.deviate(diagnostic, diagnostics)?;
+        }
+
+        // Remaining bytes of this option.
+        let options_data = &bytes[4..length];
+
+        let parsed = match SocksOptionKind::from_u16_for(kind, draft) {
+            SocksOptionKind::AuthMethodAdvertisement => {
+                AuthMethodAdvertisementOption::from_socks_bytes(options_data).map(|option| vec![option])
+            }
+            SocksOptionKind::AuthMethodSelection => {
+                AuthMethodSelectionOption::from_socks_bytes(options_data).map(|option| vec![option])
+            }
+            SocksOptionKind::AuthData => AuthDataOption::from_socks_bytes(options_data).map(|option| vec![option]),
+            SocksOptionKind::Metadata => MetadataOption::from_socks_bytes(options_data).map(|option| vec![option]),
+            // Packed metadata is expanded into an option per entry, as if each was sent on its own.
+            SocksOptionKind::Other(SOCKS_OKIND_PACKED_METADATA) => {
+                options::decode_packed_metadata_within(options_data, &mut inflate_limit)
+            }
+            SocksOptionKind::Stack | SocksOptionKind::Other(_) => {
+                Ok(vec![UnrecognizedOption::new(kind, options_data.to_vec()).wrap()])
+            }
+        };
+        match parsed {
+            Ok(parsed) => options.extend(parsed),
+            // The length is sane, so the next option starts where it says, regardless of the data.
+            Err(_) if mode == ParseMode::Recover => {
+                diagnostics.push(Diagnostic::MalformedOption {
+                    kind,
+                    length: length as u16,
+                });
+                options.push(SocksOption::Malformed(UnrecognizedOption::new(kind, options_data.to_vec())));
+            }
+            Err(e) => return Err(e),
+        }
+
+        bytes = &bytes[length..];
+    }
+
+    Ok(options)
+}
+
+// Checks that a padding byte is zero.
+fn check_padding(
+    padding: u8,
+    mode: ParseMode,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Result<()> {
+    if padding != SOCKS_PADDING {
+        mode.deviate(Diagnostic::NonzeroPadding(padding), diagnostics)?;
+    }
+
+    Ok(())
+}
+
+/// Splits the body of a decoded options block into the raw bytes of each option, including padding.
+pub(crate) fn split_options(mut bytes: &[u8]) -> Vec<&[u8]> {
+    let mut options = Vec::new();
+
+    while bytes.len() >= 4 {
+        let length = u16::from_be_bytes([bytes[2], bytes[3]]) as usize;
+        if length < 4 || length > bytes.len() {
+            break;
+        }
+
+        options.push(&bytes[..length]);
+        bytes = &bytes[length..];
+    }
+
+    options
+}
+
+/// Parses a SOCKS6 authentication reply, returning its status and options.
+pub fn parse_socks6_auth_reply(bytes: &[u8]) -> Result<Parsed<(u8, Vec<SocksOption>)>> {
+    parse_socks6_auth_reply_for(bytes, Socks6Draft::default())
+}
+
+/// Parses a SOCKS6 authentication reply of the given draft revision.
+pub fn parse_socks6_auth_reply_for(
+    bytes: &[u8],
+    draft: Socks6Draft,
+) -> Result<Parsed<(u8, Vec<SocksOption>)>> {
+    Ok(match parse_socks6_auth_reply_with(bytes, draft, ParseMode::default())? {
+        Parsed::Complete((reply, _), length) => Parsed::Complete(reply, length),
+        Parsed::Incomplete(needed) => Parsed::Incomplete(needed),
+    })
+}
+
+/// Parses a SOCKS6 authentication reply of the given draft revision in the given mode.
+///
+/// # Returns
+/// The status and options, and the deviations from the draft that a lenient parse accepted.
+pub fn parse_socks6_auth_reply_with(
+    bytes: &[u8],
+    draft: Socks6Draft,
+    mode: ParseMode,
+) -> Result<Parsed<Diagnosed<AuthReplyParts>>> {
+    let mut reader = Reader::new(bytes);
+
+    let version = take!(reader.u8());
+    ensure!(version == draft.constants().version, "Proxy uses a different SOCKS version: {}", version);
+
+    let status = take!(reader.u8());
+    let (options, diagnostics) = nested!(reader, |bytes| parse_options_with(bytes, draft, mode));
+
+    Ok(Parsed::Complete(((status, options), diagnostics), reader.position()))
+}
+
+/// Appends a SOCKS6 authentication reply to the buffer.
+pub fn encode_socks6_auth_reply(
+    status: u8,
+    options: &[SocksOption],
+    bytes: &mut Vec<u8>,
+) {
+    encode_socks6_auth_reply_for(status, options, Socks6Draft::default(), bytes);
+}
+
+/// Appends a SOCKS6 authentication reply of the given draft revision to the buffer.
+pub fn encode_socks6_auth_reply_for(
+    status: u8,
+    options: &[SocksOption],
+    draft: Socks6Draft,
+    bytes: &mut Vec<u8>,
+) {
+    bytes.extend_from_slice(&[draft.constants().version, status]);
+    encode_options_for(options, draft, bytes);
+}
+
+/// Parses a SOCKS6 operation reply, returning its reply code, binding, and options.
+pub fn parse_socks6_reply(bytes: &[u8]) -> Result<Parsed<(u8, Address, Vec<SocksOption>)>> {
+    parse_socks6_reply_for(bytes, Socks6Draft::default())
+}
+
+/// Parses a SOCKS6 operation reply of the given draft revision.
+pub fn parse_socks6_reply_for(
+    bytes: &[u8],
+    draft: Socks6Draft,
+) -> Result<Parsed<(u8, Address, Vec<SocksOption>)>> {
+    Ok(match parse_socks6_reply_with(bytes, draft, ParseMode::default())? {
+        Parsed::Complete((reply, _), length) => Parsed::Complete(reply, length),
+        Parsed::Incomplete(needed) => Parsed::Incomplete(needed),
+    })
+}
+
+/// Parses a SOCKS6 operation reply of the given draft revision in the given mode.
+///
+/// # Returns
+/// The reply code, binding, and options, and the deviations from the draft that a lenient parse accepted.
+pub fn parse_socks6_reply_with(
+    bytes: &[u8],
+    draft: Socks6Draft,
+    mode: ParseMode,
+) -> Result<Parsed<Diagnosed<ReplyParts>>> {
+    let mut diagnostics = vec![];
+    let mut reader = Reader::new(bytes);
+
+    let version = take!(reader.u8());
+    ensure!(version == draft.constants().version, "Proxy uses a different SOCKS version: {}", version);
+
+    let reply_code = take!(reader.u8());
+    check_padding(take!(reader.u8()), mode, &mut diagnostics)?;
+    let binding = nested!(reader, parse_address);
+    let (options, found) = nested!(reader, |bytes| parse_options_with(bytes, draft, mode));
+    diagnostics.extend(found);
+
+    Ok(Parsed::Complete(((reply_code, binding, options), diagnostics), reader.position()))
+}
+
+/// Appends a SOCKS6 operation reply to the buffer.
+pub fn encode_socks6_reply(
+    reply_code: u8,
+    binding: &Address,
+    options: &[SocksOption],
+    bytes: &mut Vec<u8>,
+) {
+    encode_socks6_reply_for(reply_code, binding, options, Socks6Draft::default(), bytes);
+}
+
+/// Appends a SOCKS6 operation reply of the given draft revision to the buffer.
+pub fn encode_socks6_reply_for(
+    reply_code: u8,
+    binding: &Address,
+    options: &[SocksOption],
+    draft: Socks6Draft,
+    bytes: &mut Vec<u8>,
+) {
+    bytes.extend_from_slice(&[draft.constants().version, reply_code, SOCKS_PADDING]);
+    binding.write_socks_bytes(bytes);
+    encode_options_for(options, draft, bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socks6::options::AuthMethod;
+    use crate::wire::assert_parses;
+
+    fn options() -> Vec<SocksOption> {
+        vec![
+            AuthMethodAdvertisementOption::new(5, vec![AuthMethod::UsernamePassword]).wrap(),
+            MetadataOption::new(1, String::from("value")).wrap(),
+            UnrecognizedOption::new(0x1234, vec![1, 2, 3]).wrap(),
+        ]
+    }
+
+    // Tests every truncation of a request with options.
+    #[test]
+    fn test_parse_request() {
+        let request = Socks6Request::new(
+            Socks6Command::Connect,
+            Address::new("example.com", 443),
+            5,
+            options(),
+            None,
+        );
+        let mut bytes = vec![];
+        encode_socks6_request(&request, &mut bytes);
+
+        let parsed = assert_parses(&bytes, parse_socks6_request);
+        assert_eq!(parsed.destination, request.destination);
+        assert_eq!(parsed.initial_data_length, 5);
+        assert_eq!(parsed.options.len(), 3);
+        assert_eq!(parsed.metadata.get(&1), Some(&String::from("value")));
+    }
+
+    // Tests that malformed requests are rejected as soon as the offending byte is seen.
+    #[test]
+    fn test_parse_request_malformed() {
+        assert!(parse_socks6_request(&[SOCKS_VER_5]).is_err());
+
+        let error = parse_socks6_request(&[SOCKS_VER_6, 0x09]).unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(SocksError::UnknownCommand(0x09))));
+        assert!(parse_socks6_request(&[SOCKS_VER_6, SOCKS_CMD_CONNECT, 0x07]).is_err());
+    }
+
+    // Tests that options are encoded with the kinds of the draft revision, and only recognized under those kinds.
+    #[test]
+    fn test_options_draft() {
+        let mut bytes = vec![];
+        encode_options_for(&options(), Socks6Draft::Draft13, &mut bytes);
+        assert_eq!(&bytes[2..4], &SOCKS6_DRAFT_13.okind_auth_meth_adv.to_be_bytes());
+
+        let parsed = assert_parses(&bytes, |bytes| parse_options_for(bytes, Socks6Draft::Draft13));
+        assert!(matches!(parsed[0], SocksOption::AuthMethodAdvertisement(_)));
+        assert!(matches!(parsed[1], SocksOption::Metadata(_)));
+
+        let parsed = assert_parses(&bytes, parse_options);
+        assert!(matches!(parsed[0], SocksOption::Unrecognized(_)));
+    }
+
+    // Tests every truncation of the replies.
+    #[test]
+    fn test_parse_replies() {
+        let mut bytes = vec![];
+        encode_socks6_auth_reply(SOCKS_AUTH_SUCCESS, &options(), &mut bytes);
+        let (status, parsed) = assert_parses(&bytes, parse_socks6_auth_reply);
+        assert_eq!(status, SOCKS_AUTH_SUCCESS);
+        assert_eq!(parsed.len(), 3);
+
+        let mut bytes = vec![];
+        encode_socks6_reply(SOCKS_REP_HOST_UNREACHABLE, &Address::new("::1", 80), &[], &mut bytes);
+        let (reply_code, binding, parsed) = assert_parses(&bytes, parse_socks6_reply);
+        assert_eq!(reply_code, SOCKS_REP_HOST_UNREACHABLE);
+        assert_eq!(binding, Address::new("::1", 80));
+        assert!(parsed.is_empty());
+    }
+
+    // Tests that options with impossible lengths are rejected instead of panicking.
+    #[test]
+    fn test_parse_options_invalid_length() {
+        assert!(decode_options(&[0x00, 0x02, 0x00, 0x02], Socks6Draft::default()).is_err());
+        assert!(decode_options(&[0x00, 0x02, 0x00, 0x10, 0x00], Socks6Draft::default()).is_err());
+        assert!(parse_options_with(&[0x00, 0x02, 0x00, 0x02], Socks6Draft::default(), ParseMode::Strict).is_err());
+    }
+
+    // Tests that strict parsing rejects each deviation from the draft, and lenient parsing records it.
+    #[test]
+    fn test_parse_modes() -> Result<()> {
+        let draft = Socks6Draft::default();
+
+        let mut request = vec![];
+        let connect = Socks6Request::new(Socks6Command::Connect, Address::new("192.0.2.1", 80), 0, vec![], None);
+        encode_socks6_request(&connect, &mut request);
+        request[2 + connect.destination.encoded_len()] = 0x01;
+
+        let mut reply = vec![];
+        encode_socks6_reply(SOCKS_REP_SUCCEEDED, &Address::new("192.0.2.1", 80), &[], &mut reply);
+        reply[2] = 0x01;
+
+        // An unaligned option of 5 bytes, followed by 2 bytes that don't form an option.
+        let options = [0x00, 0x07, 0x12, 0x34, 0x00, 0x05, 0x01, 0xAA, 0xBB];
+        let unaligned = Diagnostic::UnalignedOption {
+            kind: 0x1234,
+            length: 5,
+        };
+
+        let strict = |error: anyhow::Error| error.downcast::<Diagnostic>().unwrap();
+        let parse = |bytes: &[u8]| parse_socks6_request_with(bytes, draft, ParseMode::Strict);
+        assert_eq!(strict(parse(&request).unwrap_err()), Diagnostic::NonzeroPadding(0x01));
+        let parse = |bytes: &[u8]| parse_socks6_reply_with(bytes, draft, ParseMode::Strict);
+        assert_eq!(strict(parse(&reply).unwrap_err()), Diagnostic::NonzeroPadding(0x01));
+        let parse = |bytes: &[u8]| parse_options_with(bytes, draft, ParseMode::Strict);
+        assert_eq!(strict(parse(&options).unwrap_err()), unaligned);
+        assert_eq!(strict(parse(&[0x00, 0x02, 0xAA, 0xBB]).unwrap_err()), Diagnostic::TrailingBytes(2));
+
+        let parsed = assert_parses(&request, |bytes| parse_socks6_request_with(bytes, draft, ParseMode::Lenient));
+        assert_eq!(parsed.diagnostics(), &[Diagnostic::NonzeroPadding(0x01)]);
+        let (_, diagnostics) = assert_parses(&reply, |bytes| parse_socks6_reply_with(bytes, draft, ParseMode::Lenient));
+        assert_eq!(diagnostics, vec![Diagnostic::NonzeroPadding(0x01)]);
+        let parse = |bytes: &[u8]| parse_options_with(bytes, draft, ParseMode::Lenient);
+        let (parsed, diagnostics) = assert_parses(&options, parse);
+        assert!(matches!(&parsed[..], [SocksOption::Unrecognized(_)]));
+        assert_eq!(diagnostics, vec![unaligned, Diagnostic::TrailingBytes(2)]);
+
+        // The lenient mode is the default, while impossible lengths are rejected in either mode.
+        assert!(matches!(parse_options(&options)?, Parsed::Complete(parsed, 9) if parsed.len() == 1));
+        assert!(parse(&[0x00, 0x04, 0x12, 0x34, 0x00, 0x02]).is_err());
+
+        Ok(())
+    }
+
+    // Tests that recovering keeps an option whose data doesn't parse as malformed, and parses the options after it,
+    // while the other modes reject it.
+    #[test]
+    fn test_parse_options_recover() -> Result<()> {
+        let draft = Socks6Draft::default();
+        let metadata = draft.constants().okind_metadata;
+
+        // Metadata with a value length beyond the option, followed by valid metadata.
+        let mut bytes = vec![0x00, 0x00];
+        bytes.extend_from_slice(&metadata.to_be_bytes());
+        bytes.extend_from_slice(&[0x00, 0x08, 0x00, 0x01, 0x00, 0x10]);
+        bytes.extend_from_slice(&MetadataOption::new(2, String::from("two")).wrap().as_socks_bytes());
+        let length = (bytes.len() - 2) as u16;
+        bytes[..2].copy_from_slice(&length.to_be_bytes());
+
+        assert!(parse_options_with(&bytes, draft, ParseMode::Strict).is_err());
+        assert!(parse_options_with(&bytes, draft, ParseMode::Lenient).is_err());
+
+        let (parsed, diagnostics) = assert_parses(&bytes, |bytes| parse_options_with(bytes, draft, ParseMode::Recover));
+        assert_eq!(diagnostics, vec![Diagnostic::MalformedOption { kind: metadata, length: 8 }]);
+        match &parsed[..] {
+            [SocksOption::Malformed(malformed), SocksOption::Metadata(valid)] => {
+                assert_eq!((malformed.kind(), malformed.data()), (metadata, &[0x00, 0x01, 0x00, 0x10][..]));
+                assert_eq!(valid.value, "two");
+            }
+            parsed => panic!("Unexpected options: {:?}", parsed),
+        }
+
+        // An impossible length still aborts.
+        assert!(parse_options_with(&[0x00, 0x04, 0x12, 0x34, 0x00, 0x02], draft, ParseMode::Recover).is_err());
+
+        Ok(())
+    }
+
+    // Tests that the SOCKS6 golden vectors encode to, and decode from, exactly their bytes.
+    #[test]
+    fn test_golden_vectors() {
+        use crate::wire::vectors;
+
+        let request = vectors::socks6_connect_with_metadata();
+        request.assert_encodes(encode_socks6_request);
+        request.assert_decodes(parse_socks6_request);
+
+        for vector in [vectors::socks6_auth_success(), vectors::socks6_auth_failure()] {
+            vector.assert_encodes(|(status, options), bytes| encode_socks6_auth_reply(*status, options, bytes));
+            vector.assert_decodes(parse_socks6_auth_reply);
+        }
+        for vector in [vectors::socks6_reply_success(), vectors::socks6_reply_connection_refused()] {
+            vector.assert_encodes(|(code, binding, options), bytes| {
+                encode_socks6_reply(*code, binding, options, bytes)
+            });
+            vector.assert_decodes(parse_socks6_reply);
+        }
+    }
+}